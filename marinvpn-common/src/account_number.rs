@@ -0,0 +1,53 @@
+//! Account numbers are 16 characters from an alphabet that leaves out the
+//! look-alikes 0/O and 1/I, shown as four groups of four. The server mints
+//! them and the client reads typed or pasted input through here, so both
+//! agree on what a well-formed number is.
+
+pub const ALPHABET: &str = "ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+pub const LENGTH: usize = 16;
+const GROUP: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccountNumberError {
+    TooShort { missing: usize },
+    TooLong,
+    InvalidCharacter(char),
+}
+
+/// Uppercases `input` and drops whitespace and the dashes, dots and
+/// underscores that password managers and notes tend to add. Anything else
+/// is kept so [`validate`] can point at it.
+pub fn normalize(input: &str) -> String {
+    input
+        .chars()
+        .filter(|c| !c.is_whitespace() && !matches!(c, '-' | '.' | '_'))
+        .flat_map(char::to_uppercase)
+        .collect()
+}
+
+/// Formats possibly partial input in groups of four for display as it is
+/// typed. Characters past the sixteenth are dropped.
+pub fn format_groups(input: &str) -> String {
+    let chars: Vec<char> = normalize(input).chars().take(LENGTH).collect();
+    chars
+        .chunks(GROUP)
+        .map(|group| group.iter().collect::<String>())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Checks `input` and returns it normalized. A character outside the
+/// alphabet is reported before the length, so it shows up while typing.
+pub fn validate(input: &str) -> Result<String, AccountNumberError> {
+    let normalized = normalize(input);
+    if let Some(bad) = normalized.chars().find(|c| !ALPHABET.contains(*c)) {
+        return Err(AccountNumberError::InvalidCharacter(bad));
+    }
+    match normalized.chars().count() {
+        n if n < LENGTH => Err(AccountNumberError::TooShort {
+            missing: LENGTH - n,
+        }),
+        LENGTH => Ok(normalized),
+        _ => Err(AccountNumberError::TooLong),
+    }
+}
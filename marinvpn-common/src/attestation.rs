@@ -0,0 +1,33 @@
+//! Wire format of the per-request device attestation. The client signs and
+//! the server verifies the exact same message, so both build it here.
+
+use sha2::{Digest, Sha256};
+
+/// `timestamp:nonce:signature_b64`
+pub const ATTESTATION_HEADER: &str = "X-Marin-Attestation";
+/// Hex SHA-256 of the request body (empty body hashes too).
+pub const ATTESTATION_BODY_HEADER: &str = "X-Marin-Attestation-Body";
+/// Base64 Ed25519 device public key.
+pub const ATTESTATION_PUB_HEADER: &str = "X-Marin-Attestation-Pub";
+/// Error `code` for a request signed with a different key than the one the
+/// server holds for the device, e.g. after a reinstall. Logging in again
+/// registers the current key.
+pub const DEVICE_KEY_MISMATCH_CODE: &str = "device_key_mismatch";
+
+pub fn body_hash_hex(body: &[u8]) -> String {
+    hex::encode(Sha256::digest(body))
+}
+
+pub fn signing_message(
+    timestamp: &str,
+    nonce: &str,
+    method: &str,
+    path: &str,
+    body_hash: &str,
+) -> String {
+    format!("{}:{}:{}:{}:{}", timestamp, nonce, method, path, body_hash)
+}
+
+pub fn header_value(timestamp: &str, nonce: &str, signature_b64: &str) -> String {
+    format!("{}:{}:{}", timestamp, nonce, signature_b64)
+}
@@ -10,6 +10,11 @@ use validator::Validate;
 #[cfg(feature = "db")]
 use sqlx::FromRow;
 
+pub mod account_number;
+pub mod attestation;
+pub mod pow;
+pub mod version;
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default, Zeroize, ZeroizeOnDrop)]
 #[cfg_attr(feature = "openapi", derive(ToSchema))]
 pub struct DnsBlockingState {
@@ -51,6 +56,25 @@ pub struct AnonymousConfigRequest {
     pub dns_blocking: Option<DnsBlockingState>,
     pub quantum_resistant: bool,
     pub pqc_public_key: Option<String>,
+    /// Required only when the server has proof of work enabled.
+    #[serde(default)]
+    pub pow: Option<PowSolution>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Zeroize, ZeroizeOnDrop)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct PowSolution {
+    pub challenge: String,
+    pub nonce: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct PowChallenge {
+    pub enabled: bool,
+    pub challenge: String,
+    pub difficulty: u8,
+    pub expires_at: i64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Zeroize, ZeroizeOnDrop)]
@@ -85,6 +109,11 @@ pub struct WireGuardConfig {
     pub pqc_provider: Option<String>,
     pub pqc_ciphertext: Option<String>,
     pub obfuscation_key: Option<String>,
+    /// Port the relay runs Shadowsocks on, when it is not the endpoint's.
+    pub obfuscation_port: Option<u16>,
+    /// Shadowsocks cipher the relay expects, such as
+    /// `chacha20-ietf-poly1305`. Unset means `aes-256-gcm`.
+    pub obfuscation_cipher: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -127,6 +156,15 @@ pub struct LoginResponse {
     pub error: Option<String>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default, Zeroize, ZeroizeOnDrop)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+#[cfg_attr(feature = "validation", derive(Validate))]
+pub struct GenerateRequest {
+    #[serde(default)]
+    #[cfg_attr(feature = "validation", validate(length(min = 1, max = 64)))]
+    pub invite_code: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[cfg_attr(feature = "openapi", derive(ToSchema))]
 pub struct GenerateResponse {
@@ -161,6 +199,41 @@ pub struct RemoveDeviceRequest {
     pub device_name: String,
 }
 
+/// Outcome of a device removal. A request that removed nothing is not an
+/// error, but the client needs to know why so it can say so.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct RemoveDeviceResponse {
+    pub removed: bool,
+    #[serde(default)]
+    pub reason: Option<RemoveDeviceReason>,
+}
+
+impl RemoveDeviceResponse {
+    pub fn removed() -> Self {
+        Self {
+            removed: true,
+            reason: None,
+        }
+    }
+
+    pub fn not_removed(reason: RemoveDeviceReason) -> Self {
+        Self {
+            removed: false,
+            reason: Some(reason),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum RemoveDeviceReason {
+    /// No device with that name is registered to the account, usually
+    /// because it was already removed from another device.
+    NotFound,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Zeroize, ZeroizeOnDrop)]
 #[cfg_attr(feature = "openapi", derive(ToSchema))]
 #[cfg_attr(feature = "validation", derive(Validate))]
@@ -173,12 +246,200 @@ pub struct ReportRequest {
     #[cfg_attr(feature = "validation", validate(length(min = 1, max = 2000)))]
     pub message: String,
     pub is_encrypted: bool,
+    /// Plaintext triage metadata. Optional so older clients keep working,
+    /// and deliberately coarse so it cannot identify the reporter.
+    #[serde(default)]
+    #[zeroize(skip)]
+    pub category: Option<ReportCategory>,
+    #[serde(default)]
+    #[cfg_attr(
+        feature = "validation",
+        validate(custom(function = "validate_app_version"))
+    )]
+    pub app_version: Option<String>,
+    #[serde(default)]
+    #[zeroize(skip)]
+    pub os: Option<ReportOs>,
+    #[serde(default)]
+    #[zeroize(skip)]
+    pub last_error_code: Option<ReportErrorCode>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ReportCategory {
+    Connection,
+    Performance,
+    Account,
+    Crash,
+    Abuse,
+    Other,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ReportOs {
+    Windows,
+    Linux,
+    Macos,
+    Other,
+}
+
+impl ReportOs {
+    pub fn current() -> Self {
+        if cfg!(target_os = "windows") {
+            ReportOs::Windows
+        } else if cfg!(target_os = "linux") {
+            ReportOs::Linux
+        } else if cfg!(target_os = "macos") {
+            ReportOs::Macos
+        } else {
+            ReportOs::Other
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ReportErrorCode {
+    ConnectionFailed,
+    HandshakeTimeout,
+    AuthFailed,
+    ConfigFetchFailed,
+    DnsFailure,
+    KillSwitchFailed,
+    Network,
+    Unknown,
+}
+
+/// Acknowledgement for a problem report. `reference` is logged next to the
+/// report server-side so support can find it when the user quotes it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct ReportResponse {
+    pub accepted: bool,
+    #[serde(default)]
+    pub reference: Option<String>,
+}
+
+/// Phrase an operator has to type into a panic request. A stray POST with
+/// the key alone, from a script or a mis-click, wipes nothing.
+pub const PANIC_CONFIRMATION: &str = "WIPE ALL SESSIONS";
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct PanicRequest {
+    pub confirm: String,
+}
+
+impl PanicRequest {
+    pub fn is_confirmed(&self) -> bool {
+        self.confirm == PANIC_CONFIRMATION
+    }
+}
+
+/// `wiped` is false when an earlier wipe is still inside the cooldown; the
+/// request is then a no-op and `wiped_at` is when that earlier wipe ran.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct PanicResponse {
+    pub wiped: bool,
+    pub wiped_at: i64,
+}
+
+/// `directives` uses the `RUST_LOG` syntax, e.g. `marinvpn_server=debug,tower_http=info`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct LogLevelRequest {
+    pub directives: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct LogLevelResponse {
+    pub directives: String,
+    pub previous: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Zeroize, ZeroizeOnDrop)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+#[cfg_attr(feature = "validation", derive(Validate))]
+pub struct AccountPanicRequest {
+    #[cfg_attr(
+        feature = "validation",
+        validate(custom(function = "validate_account_number"))
+    )]
+    pub account_number: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct AccountPanicResponse {
+    pub devices_removed: u64,
+    pub peers_removed: u64,
+}
+
+/// What `GET /api/v1/version` answers: the server's release, the
+/// [`version::features`] it supports and the oldest client release it still
+/// works with.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct ServerVersion {
+    pub version: String,
+    pub api_features: Vec<String>,
+    pub min_client_version: String,
+}
+
+/// Sessions held by the calling device.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct SessionsResponse {
+    pub active_sessions: u64,
+    pub max_sessions: u64,
+}
+
+/// The device stays on the account; only its sessions end.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct LogoutResponse {
+    pub sessions_revoked: u64,
+}
+
+/// A single-use code another device can claim instead of typing the
+/// account number.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Zeroize, ZeroizeOnDrop)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct TransferTokenResponse {
+    pub transfer_token: String,
+    pub expires_at: i64,
+}
+
+/// A login that proves the account with a transfer code. Answered with a
+/// [`LoginResponse`]; a code that stops at the device limit can be claimed
+/// again with `kick_device` set.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Zeroize, ZeroizeOnDrop)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+#[cfg_attr(feature = "validation", derive(Validate))]
+pub struct ClaimTransferRequest {
+    #[cfg_attr(feature = "validation", validate(length(min = 1, max = 64)))]
+    pub transfer_token: String,
+    #[cfg_attr(feature = "validation", validate(length(min = 40, max = 80)))]
+    pub device_pubkey: Option<String>,
+    #[cfg_attr(feature = "validation", validate(length(min = 1, max = 50)))]
+    pub kick_device: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[cfg_attr(feature = "openapi", derive(ToSchema))]
 pub struct ErrorResponse {
     pub error: String,
+    /// Machine-readable kind of error, e.g. `rate_limited`. Absent from
+    /// servers that predate it.
+    #[serde(default)]
+    pub code: Option<String>,
     pub success: bool,
 }
 
@@ -224,9 +485,36 @@ pub enum IpVersion {
 #[cfg(test)]
 mod tests;
 
+#[cfg(feature = "validation")]
+fn validate_app_version(value: &str) -> Result<(), validator::ValidationError> {
+    // Plain `MAJOR.MINOR.PATCH` with an optional short pre-release tag; anything
+    // longer could be used to smuggle identifying data past the encryption.
+    let (core, pre) = match value.split_once('-') {
+        Some((core, pre)) => (core, Some(pre)),
+        None => (value, None),
+    };
+    let parts: Vec<&str> = core.split('.').collect();
+    if parts.len() != 3
+        || parts
+            .iter()
+            .any(|p| p.is_empty() || p.len() > 4 || !p.chars().all(|c| c.is_ascii_digit()))
+    {
+        return Err(validator::ValidationError::new("app_version_format"));
+    }
+    if let Some(pre) = pre {
+        if pre.is_empty()
+            || pre.len() > 12
+            || !pre.chars().all(|c| c.is_ascii_alphanumeric() || c == '.')
+        {
+            return Err(validator::ValidationError::new("app_version_format"));
+        }
+    }
+    Ok(())
+}
+
 #[cfg(feature = "validation")]
 fn validate_account_number(value: &str) -> Result<(), validator::ValidationError> {
-    const ALLOWED: &str = "ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+    use account_number::ALPHABET;
     let cleaned: String = value
         .chars()
         .filter(|c| !c.is_whitespace())
@@ -235,7 +523,7 @@ fn validate_account_number(value: &str) -> Result<(), validator::ValidationError
     if cleaned.len() != 16 {
         return Err(validator::ValidationError::new("account_length"));
     }
-    if !cleaned.chars().all(|c| ALLOWED.contains(c)) {
+    if !cleaned.chars().all(|c| ALPHABET.contains(c)) {
         return Err(validator::ValidationError::new("account_charset"));
     }
 
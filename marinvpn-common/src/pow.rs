@@ -0,0 +1,50 @@
+//! Hashcash-style proof of work for anonymous config issuance. The server
+//! only verifies; the client does the search. Both sides share this module
+//! so the digest layout cannot drift.
+
+use sha2::{Digest, Sha256};
+
+const POW_DOMAIN: &[u8] = b"MARIN_VPN_POW_V1";
+
+/// Upper bound the server will ever ask for. Anything above this would take
+/// a desktop client minutes, which defeats "lightweight".
+pub const MAX_POW_DIFFICULTY: u8 = 28;
+
+/// The solution is bound to the blind-signed token, so one solved challenge
+/// cannot be replayed for a second config.
+pub fn pow_digest(challenge: &str, token: &str, nonce: u64) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(POW_DOMAIN);
+    hasher.update(challenge.as_bytes());
+    hasher.update(b":");
+    hasher.update(token.as_bytes());
+    hasher.update(b":");
+    hasher.update(nonce.to_be_bytes());
+    hasher.finalize().into()
+}
+
+pub fn leading_zero_bits(digest: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in digest {
+        if *byte == 0 {
+            bits += 8;
+        } else {
+            bits += byte.leading_zeros();
+            break;
+        }
+    }
+    bits
+}
+
+pub fn meets_difficulty(challenge: &str, token: &str, nonce: u64, difficulty: u8) -> bool {
+    leading_zero_bits(&pow_digest(challenge, token, nonce)) >= u32::from(difficulty)
+}
+
+/// Finds the first nonce that satisfies `difficulty`. Expected work is
+/// `2^difficulty` hashes; callers should run this off the UI thread.
+pub fn solve(challenge: &str, token: &str, difficulty: u8) -> u64 {
+    let difficulty = difficulty.min(MAX_POW_DIFFICULTY);
+    (0..=u64::MAX)
+        .find(|nonce| meets_difficulty(challenge, token, *nonce, difficulty))
+        .expect("nonce space exhausted")
+}
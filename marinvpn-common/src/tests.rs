@@ -35,4 +35,169 @@ mod tests {
             assert!(req.validate().is_ok());
         }
     }
+
+    #[test]
+    fn test_report_request_without_triage_fields_is_unchanged() {
+        let legacy = r#"{
+            "account_number": "ABCD E2GH JK7M NPQR",
+            "message": "b64chunk1|b64chunk2",
+            "is_encrypted": true
+        }"#;
+        let req: ReportRequest = serde_json::from_str(legacy).unwrap();
+        assert_eq!(req.message, "b64chunk1|b64chunk2");
+        assert!(req.is_encrypted);
+        assert_eq!(req.category, None);
+        assert_eq!(req.app_version, None);
+        assert_eq!(req.os, None);
+        assert_eq!(req.last_error_code, None);
+
+        #[cfg(feature = "validation")]
+        {
+            use validator::Validate;
+            assert!(req.validate().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_report_request_accepts_triage_fields() {
+        let raw = r#"{
+            "account_number": "ABCD E2GH JK7M NPQR",
+            "message": "b64chunk1",
+            "is_encrypted": true,
+            "category": "connection",
+            "app_version": "0.1.0",
+            "os": "linux",
+            "last_error_code": "handshake_timeout"
+        }"#;
+        let req: ReportRequest = serde_json::from_str(raw).unwrap();
+        assert_eq!(req.category, Some(ReportCategory::Connection));
+        assert_eq!(req.os, Some(ReportOs::Linux));
+        assert_eq!(req.last_error_code, Some(ReportErrorCode::HandshakeTimeout));
+        assert_eq!(req.message, "b64chunk1");
+
+        #[cfg(feature = "validation")]
+        {
+            use validator::Validate;
+            assert!(req.validate().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_report_request_rejects_unknown_triage_values() {
+        let raw = r#"{
+            "account_number": "ABCD E2GH JK7M NPQR",
+            "message": "b64chunk1",
+            "is_encrypted": true,
+            "category": "my-name-is-alice"
+        }"#;
+        assert!(serde_json::from_str::<ReportRequest>(raw).is_err());
+
+        let raw = raw.replace(r#""category": "my-name-is-alice""#, r#""os": "templeos""#);
+        assert!(serde_json::from_str::<ReportRequest>(&raw).is_err());
+    }
+
+    #[test]
+    fn test_report_request_app_version_format() {
+        #[cfg(feature = "validation")]
+        {
+            use validator::Validate;
+
+            let mut req = ReportRequest {
+                account_number: "ABCD E2GH JK7M NPQR".to_string(),
+                message: "b64chunk1".to_string(),
+                is_encrypted: true,
+                category: Some(ReportCategory::Other),
+                app_version: Some("1.2.3-beta.1".to_string()),
+                os: Some(ReportOs::current()),
+                last_error_code: None,
+            };
+            assert!(req.validate().is_ok());
+
+            for bad in [
+                "1.2",
+                "1.2.3.4",
+                "v1.2.3",
+                "1.2.3-",
+                "1.2.3 alice@example.com",
+            ] {
+                req.app_version = Some(bad.to_string());
+                assert!(req.validate().is_err(), "{} should be rejected", bad);
+            }
+        }
+    }
+
+    #[test]
+    fn test_pow_leading_zero_bits() {
+        use crate::pow::leading_zero_bits;
+        assert_eq!(leading_zero_bits(&[0xff]), 0);
+        assert_eq!(leading_zero_bits(&[0x00, 0x10]), 11);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+    }
+
+    #[test]
+    fn test_pow_solve_meets_difficulty() {
+        use crate::pow::{meets_difficulty, solve};
+        let nonce = solve("challenge", "token", 10);
+        assert!(meets_difficulty("challenge", "token", nonce, 10));
+        assert!((0..nonce).all(|n| !meets_difficulty("challenge", "token", n, 10)));
+    }
+
+    #[test]
+    fn test_panic_request_requires_exact_phrase() {
+        let request = |confirm: &str| PanicRequest {
+            confirm: confirm.to_string(),
+        };
+        assert!(request(PANIC_CONFIRMATION).is_confirmed());
+        for typo in ["", "yes", "wipe all sessions", "WIPE ALL SESSIONS ", "WIPE"] {
+            assert!(!request(typo).is_confirmed(), "{:?}", typo);
+        }
+    }
+
+    #[test]
+    fn account_number_input_is_normalized_and_grouped() {
+        use crate::account_number::{format_groups, normalize};
+
+        assert_eq!(normalize(" abcd-e2gh\tjk7m.npqr\n"), "ABCDE2GHJK7MNPQR");
+        assert_eq!(format_groups("abcde2ghjk7mnpqr"), "ABCD E2GH JK7M NPQR");
+        // Partial input is grouped as it is typed, with no trailing space.
+        assert_eq!(format_groups("abcd"), "ABCD");
+        assert_eq!(format_groups("ABCD E"), "ABCD E");
+        assert_eq!(format_groups("abcde2ghjk7mnpqrst"), "ABCD E2GH JK7M NPQR");
+        assert_eq!(format_groups(""), "");
+    }
+
+    #[test]
+    fn account_number_validation_reports_what_is_wrong() {
+        use crate::account_number::{validate, AccountNumberError};
+
+        assert_eq!(validate("ABCD-E2GH-JK7M-NPQR").unwrap(), "ABCDE2GHJK7MNPQR");
+        assert_eq!(
+            validate("ABCD E2"),
+            Err(AccountNumberError::TooShort { missing: 10 })
+        );
+        assert_eq!(
+            validate("ABCD E2GH JK7M NPQR S"),
+            Err(AccountNumberError::TooLong)
+        );
+        // 0 and O are both left out of the alphabet; flagged before the length.
+        assert_eq!(
+            validate("ABC0"),
+            Err(AccountNumberError::InvalidCharacter('0'))
+        );
+    }
+
+    #[test]
+    fn versions_compare_by_release() {
+        use crate::version::Version;
+
+        let parse = |v| Version::parse(v).unwrap();
+        assert!(parse("0.1.0") < parse("0.1.1"));
+        assert!(parse("0.9.9") < parse("0.10.0"));
+        assert!(parse("1.0.0") > parse("0.99.99"));
+        assert_eq!(parse("1.2.3-beta.1"), parse("1.2.3"));
+        assert_eq!(parse(" 1.2.3 ").to_string(), "1.2.3");
+        for bad in ["", "1.2", "1.2.3.4", "1.x.3", "v1.2.3"] {
+            assert_eq!(Version::parse(bad), None, "{}", bad);
+        }
+    }
 }
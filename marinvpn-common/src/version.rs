@@ -0,0 +1,60 @@
+//! Release versions and the API features a server advertises at
+//! `GET /api/v1/version`. The client compares its own version with the
+//! server's minimum through here, so both read version strings the same way.
+
+use std::fmt;
+
+/// The app's own version, sent with every API request so the server can turn
+/// away releases it no longer supports. A request without it counts as
+/// `0.0.0`.
+pub const CLIENT_VERSION_HEADER: &str = "X-Marin-Client-Version";
+/// Error `code` for a request from a client older than the server's
+/// minimum. Only an update helps.
+pub const CLIENT_TOO_OLD_CODE: &str = "client_too_old";
+
+/// Names in [`crate::ServerVersion::api_features`]. A client checks for a
+/// name before relying on the endpoints behind it; unknown names are
+/// ignored.
+pub mod features {
+    /// `/vpn/config-anonymous` with blind-signed tokens.
+    pub const ANONYMOUS_CONFIG: &str = "anonymous-config";
+    /// Configs for more than one relay at a time, for nested tunnels.
+    pub const MULTIHOP: &str = "multihop";
+    /// ML-KEM-768 key exchange for the preshared key.
+    pub const POST_QUANTUM: &str = "post-quantum";
+    /// `/vpn/pow-challenge` issues challenges that config requests must solve.
+    pub const PROOF_OF_WORK: &str = "proof-of-work";
+    /// `/account/transfer-token` and its claim.
+    pub const TRANSFER_TOKENS: &str = "transfer-tokens";
+    /// Problem reports encrypted to `/auth/support-key`.
+    pub const ENCRYPTED_REPORTS: &str = "encrypted-reports";
+}
+
+/// A `MAJOR.MINOR.PATCH` release. A pre-release tag is read past and
+/// compares equal to its release. The default, `0.0.0`, is older than any
+/// release.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl Version {
+    pub fn parse(value: &str) -> Option<Self> {
+        let core = value.trim().split(['-', '+']).next()?;
+        let mut parts = core.split('.').map(|p| p.parse::<u32>().ok());
+        let version = Self {
+            major: parts.next()??,
+            minor: parts.next()??,
+            patch: parts.next()??,
+        };
+        parts.next().is_none().then_some(version)
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
@@ -0,0 +1,385 @@
+//! Golden wire-format fixtures. The client and server both (de)serialize these
+//! types, so a renamed field or changed enum spelling must fail here before it
+//! ships to one side only.
+
+use marinvpn_common::*;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::fmt::Debug;
+
+const KEY: &str = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+const ACCOUNT: &str = "ABCD E2GH JK7M NPQR";
+
+fn fixture(name: &str) -> String {
+    let path = format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+    std::fs::read_to_string(&path).unwrap_or_else(|e| panic!("{}: {}", path, e))
+}
+
+fn strip_nulls(value: Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .filter(|(_, v)| !v.is_null())
+                .map(|(k, v)| (k, strip_nulls(v)))
+                .collect(),
+        ),
+        Value::Array(items) => Value::Array(items.into_iter().map(strip_nulls).collect()),
+        other => other,
+    }
+}
+
+/// Every field present: serialization must match the fixture exactly.
+fn assert_golden<T>(expected: &T, name: &str)
+where
+    T: Serialize + DeserializeOwned + PartialEq + Debug,
+{
+    let raw = fixture(name);
+    let golden: Value = serde_json::from_str(&raw).unwrap();
+    assert_eq!(serde_json::to_value(expected).unwrap(), golden, "{}", name);
+    assert_eq!(
+        &serde_json::from_str::<T>(&raw).unwrap(),
+        expected,
+        "{}",
+        name
+    );
+}
+
+/// Optional fields omitted, as an older peer would send them. They must still
+/// parse, and we must not emit anything beyond the fixture except `null`s.
+fn assert_minimal<T>(expected: &T, name: &str)
+where
+    T: Serialize + DeserializeOwned + PartialEq + Debug,
+{
+    let raw = fixture(name);
+    let golden: Value = serde_json::from_str(&raw).unwrap();
+    assert_eq!(
+        strip_nulls(serde_json::to_value(expected).unwrap()),
+        golden,
+        "{}",
+        name
+    );
+    assert_eq!(
+        &serde_json::from_str::<T>(&raw).unwrap(),
+        expected,
+        "{}",
+        name
+    );
+}
+
+fn dns_blocking(ads: bool, trackers: bool, adult: bool, social: bool) -> DnsBlockingState {
+    DnsBlockingState {
+        ads,
+        trackers,
+        malware: true,
+        gambling: false,
+        adult_content: adult,
+        social_media: social,
+    }
+}
+
+#[test]
+fn anonymous_config_request_contract() {
+    assert_golden(
+        &AnonymousConfigRequest {
+            message: "dG9rZW4tbWVzc2FnZQ==".to_string(),
+            signature: "c2lnbmF0dXJl".to_string(),
+            location: "Sweden, Stockholm".to_string(),
+            pub_key: KEY.to_string(),
+            dns_blocking: Some(dns_blocking(true, true, false, true)),
+            quantum_resistant: true,
+            pqc_public_key: Some("cHFjLXB1YmxpYy1rZXk=".to_string()),
+            pow: Some(PowSolution {
+                challenge: "1767225600.00112233.aabbccdd".to_string(),
+                nonce: 48213,
+            }),
+        },
+        "anonymous_config_request_full.json",
+    );
+    assert_minimal(
+        &AnonymousConfigRequest {
+            message: "dG9rZW4tbWVzc2FnZQ==".to_string(),
+            signature: "c2lnbmF0dXJl".to_string(),
+            location: "Sweden".to_string(),
+            pub_key: KEY.to_string(),
+            dns_blocking: None,
+            quantum_resistant: false,
+            pqc_public_key: None,
+            pow: None,
+        },
+        "anonymous_config_request_minimal.json",
+    );
+}
+
+#[test]
+fn config_request_contract() {
+    assert_golden(
+        &ConfigRequest {
+            account_number: ACCOUNT.to_string(),
+            location: "Germany, Frankfurt".to_string(),
+            pub_key: KEY.to_string(),
+            dns_blocking: Some(dns_blocking(true, false, true, false)),
+            quantum_resistant: true,
+            pqc_public_key: Some("cHFjLXB1YmxpYy1rZXk=".to_string()),
+        },
+        "config_request_full.json",
+    );
+    assert_minimal(
+        &ConfigRequest {
+            account_number: ACCOUNT.to_string(),
+            location: "Germany".to_string(),
+            pub_key: KEY.to_string(),
+            dns_blocking: None,
+            quantum_resistant: false,
+            pqc_public_key: None,
+        },
+        "config_request_minimal.json",
+    );
+}
+
+fn wireguard_config(full: bool) -> WireGuardConfig {
+    let optional = |value: &str| full.then(|| value.to_string());
+    WireGuardConfig {
+        private_key: String::new(),
+        public_key: "c2VydmVyLXB1YmxpYy1rZXk=".to_string(),
+        preshared_key: optional("cHJlc2hhcmVkLWtleQ=="),
+        endpoint: "se-sto.marinvpn.net:51820".to_string(),
+        allowed_ips: "0.0.0.0/0, ::/0".to_string(),
+        address: "10.0.0.2/32".to_string(),
+        dns: optional("10.64.0.1"),
+        pqc_handshake: optional("aGFuZHNoYWtl"),
+        pqc_provider: optional("ml-kem-768"),
+        pqc_ciphertext: optional("Y2lwaGVydGV4dA=="),
+        obfuscation_key: optional("b2JmdXNjYXRpb24="),
+        obfuscation_port: full.then_some(8443),
+        obfuscation_cipher: optional("chacha20-ietf-poly1305"),
+    }
+}
+
+#[test]
+fn wireguard_config_contract() {
+    assert_golden(&wireguard_config(true), "wireguard_config_full.json");
+    assert_minimal(&wireguard_config(false), "wireguard_config_minimal.json");
+}
+
+#[test]
+fn login_contract() {
+    assert_golden(
+        &LoginRequest {
+            account_number: ACCOUNT.to_string(),
+            device_pubkey: Some(KEY.to_string()),
+            kick_device: Some("Swift Falcon".to_string()),
+        },
+        "login_request_full.json",
+    );
+    assert_minimal(
+        &LoginRequest {
+            account_number: ACCOUNT.to_string(),
+            device_pubkey: None,
+            kick_device: None,
+        },
+        "login_request_minimal.json",
+    );
+
+    let device = |name: &str, created: &str| Device {
+        name: name.to_string(),
+        created_date: created.to_string(),
+    };
+    assert_golden(
+        &LoginResponse {
+            success: true,
+            auth_token: Some("eyJhbGciOiJIUzI1NiJ9.e30.c2ln".to_string()),
+            refresh_token: Some("cmVmcmVzaA".to_string()),
+            account_info: Some(Account {
+                account_number: ACCOUNT.to_string(),
+                expiry_date: 1769904000,
+                created_at: 1767225600,
+            }),
+            current_device: Some("Swift Falcon".to_string()),
+            devices: Some(vec![
+                device("Swift Falcon", "2026-01-01"),
+                device("Quiet Otter", "2026-01-15"),
+            ]),
+            error_code: None,
+            error: None,
+        },
+        "login_response_full.json",
+    );
+    assert_minimal(
+        &LoginResponse {
+            success: false,
+            auth_token: None,
+            refresh_token: None,
+            account_info: None,
+            current_device: None,
+            devices: None,
+            error_code: Some("DEVICE_LIMIT".to_string()),
+            error: Some("Device limit reached".to_string()),
+        },
+        "login_response_minimal.json",
+    );
+    assert_golden(&device("Swift Falcon", "2026-01-01"), "device.json");
+}
+
+#[test]
+fn report_request_contract() {
+    assert_golden(
+        &ReportRequest {
+            account_number: ACCOUNT.to_string(),
+            message: "b64chunk1|b64chunk2".to_string(),
+            is_encrypted: true,
+            category: Some(ReportCategory::Connection),
+            app_version: Some("0.1.0".to_string()),
+            os: Some(ReportOs::Macos),
+            last_error_code: Some(ReportErrorCode::DnsFailure),
+        },
+        "report_request_full.json",
+    );
+    assert_minimal(
+        &ReportRequest {
+            account_number: ACCOUNT.to_string(),
+            message: "b64chunk1".to_string(),
+            is_encrypted: true,
+            category: None,
+            app_version: None,
+            os: None,
+            last_error_code: None,
+        },
+        "report_request_minimal.json",
+    );
+    assert_golden(
+        &ReportResponse {
+            accepted: true,
+            reference: Some("3f9a1c07".to_string()),
+        },
+        "report_response_full.json",
+    );
+    assert_minimal(
+        &ReportResponse {
+            accepted: true,
+            reference: None,
+        },
+        "report_response_minimal.json",
+    );
+}
+
+#[test]
+fn remove_device_response_contract() {
+    assert_minimal(
+        &RemoveDeviceResponse::removed(),
+        "remove_device_response_removed.json",
+    );
+    assert_golden(
+        &RemoveDeviceResponse::not_removed(RemoveDeviceReason::NotFound),
+        "remove_device_response_not_found.json",
+    );
+}
+
+#[test]
+fn small_payload_contracts() {
+    assert_golden(
+        &GenerateRequest {
+            invite_code: Some("spring-2026".to_string()),
+        },
+        "generate_request_full.json",
+    );
+    assert_minimal(&GenerateRequest::default(), "generate_request_minimal.json");
+    assert_golden(
+        &PowChallenge {
+            enabled: true,
+            challenge: "1767225600.00112233.aabbccdd".to_string(),
+            difficulty: 18,
+            expires_at: 1767225600,
+        },
+        "pow_challenge.json",
+    );
+    assert_golden(
+        &VpnServer {
+            country: "Sweden".to_string(),
+            city: "Stockholm".to_string(),
+            endpoint: "se-sto.marinvpn.net:51820".to_string(),
+            public_key: "se_pub_key".to_string(),
+            current_load: 12,
+            avg_latency: 15,
+        },
+        "vpn_server.json",
+    );
+    assert_golden(
+        &PanicRequest {
+            confirm: PANIC_CONFIRMATION.to_string(),
+        },
+        "panic_request.json",
+    );
+    assert_golden(
+        &PanicResponse {
+            wiped: false,
+            wiped_at: 1767225600,
+        },
+        "panic_response.json",
+    );
+    assert_golden(
+        &LogLevelRequest {
+            directives: "marinvpn_server=debug,tower_http=info".to_string(),
+        },
+        "log_level_request.json",
+    );
+    assert_golden(
+        &LogLevelResponse {
+            directives: "marinvpn_server=debug,tower_http=info".to_string(),
+            previous: "marinvpn_server=info,tower_http=info".to_string(),
+        },
+        "log_level_response.json",
+    );
+    assert_golden(
+        &AccountPanicRequest {
+            account_number: ACCOUNT.to_string(),
+        },
+        "account_panic_request.json",
+    );
+    assert_golden(
+        &AccountPanicResponse {
+            devices_removed: 2,
+            peers_removed: 1,
+        },
+        "account_panic_response.json",
+    );
+    assert_golden(
+        &LogoutResponse {
+            sessions_revoked: 1,
+        },
+        "logout_response.json",
+    );
+    assert_golden(
+        &SessionsResponse {
+            active_sessions: 2,
+            max_sessions: 3,
+        },
+        "sessions_response.json",
+    );
+    assert_golden(
+        &ServerVersion {
+            version: "0.1.0".to_string(),
+            api_features: vec![
+                version::features::ANONYMOUS_CONFIG.to_string(),
+                version::features::MULTIHOP.to_string(),
+            ],
+            min_client_version: "0.1.0".to_string(),
+        },
+        "server_version.json",
+    );
+    assert_golden(
+        &ErrorResponse {
+            error: "Unauthorized".to_string(),
+            code: Some("unauthorized".to_string()),
+            success: false,
+        },
+        "error_response.json",
+    );
+    assert_minimal(
+        &ErrorResponse {
+            error: "Unauthorized".to_string(),
+            code: None,
+            success: false,
+        },
+        "error_response_minimal.json",
+    );
+}
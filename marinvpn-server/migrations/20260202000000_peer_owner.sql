@@ -0,0 +1,6 @@
+-- Tie each peer to the account or blind token that registered it so the same
+-- public key submitted from another context is rejected instead of sharing an IP.
+-- Rows from before this migration have no owner and cannot be re-claimed; they
+-- age out through the stale session cleanup.
+
+ALTER TABLE peers ADD COLUMN IF NOT EXISTS owner_tag TEXT;
@@ -0,0 +1,14 @@
+-- Reclaim tunnel IPs from expired peers instead of deriving them from the
+-- ever-growing peer row id. Fresh addresses come from their own sequence and
+-- are only drawn once the free list is empty.
+
+CREATE TABLE IF NOT EXISTS free_ips (
+    ip TEXT PRIMARY KEY,
+    freed_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_free_ips_freed_at ON free_ips(freed_at, ip);
+
+CREATE SEQUENCE IF NOT EXISTS peer_ip_seq;
+
+SELECT setval('peer_ip_seq', COALESCE((SELECT MAX(id) FROM peers), 0) + 1, false);
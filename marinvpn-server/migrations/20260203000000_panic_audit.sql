@@ -0,0 +1,9 @@
+-- One row per panic wipe, holding nothing but when it ran. Not touched by the
+-- wipe itself, so it also backs the cooldown that turns a double-submit into
+-- a no-op.
+
+CREATE TABLE IF NOT EXISTS panic_audit (
+    wiped_at BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_panic_audit_wiped_at ON panic_audit(wiped_at);
@@ -0,0 +1,8 @@
+-- A device may now hold several refresh tokens at once, one per session, up
+-- to auth.max_sessions_per_device. Each row is a session keyed by its token
+-- hash; rotation rewrites the hash in place so the session keeps its slot.
+
+ALTER TABLE refresh_tokens DROP CONSTRAINT IF EXISTS refresh_tokens_pkey;
+ALTER TABLE refresh_tokens ADD PRIMARY KEY (token_hash);
+
+CREATE INDEX IF NOT EXISTS idx_refresh_tokens_device ON refresh_tokens(account_id, device_name);
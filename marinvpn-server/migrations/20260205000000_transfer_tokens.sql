@@ -0,0 +1,11 @@
+-- Single-use codes that hand an account to a new device. The code itself is
+-- only stored hashed, and the account number is sealed with a key derived
+-- from it, so a row on its own does not say which account it is for.
+
+CREATE TABLE IF NOT EXISTS transfer_tokens (
+    token_hash TEXT PRIMARY KEY,
+    sealed_account TEXT NOT NULL,
+    expires_at BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_transfer_tokens_expires_at ON transfer_tokens(expires_at);
@@ -0,0 +1,6 @@
+-- Per-relay Shadowsocks settings, handed to clients with each config. Unset
+-- means the endpoint's own port and aes-256-gcm.
+
+ALTER TABLE vpn_servers ADD COLUMN IF NOT EXISTS obfuscation_port INTEGER
+    CHECK (obfuscation_port BETWEEN 1 AND 65535);
+ALTER TABLE vpn_servers ADD COLUMN IF NOT EXISTS obfuscation_cipher TEXT;
@@ -0,0 +1,82 @@
+//! Local control plane. The admin routes are served on a Unix socket with no
+//! token check: whoever can open the socket file is an operator, so the
+//! file's owner and mode are the whole access policy. The socket is created
+//! owner-only (0600); widen it with a group if several accounts need it.
+//! It is bound inside a private directory and only then moved into place,
+//! so it is never reachable at its final path with looser permissions.
+
+use crate::{handlers, AppState};
+use axum::{routing::post, Router};
+use std::sync::Arc;
+
+/// Routes available on the admin socket. Paths are relative to the socket,
+/// e.g. `curl --unix-socket /run/marinvpn/admin.sock -X POST
+/// http://localhost/log-level`.
+pub fn routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/maintenance", post(handlers::admin::set_maintenance))
+        .route("/drain", post(handlers::admin::set_draining))
+        .route("/log-level", post(handlers::admin::set_log_level))
+        // Still needs the panic key and confirmation phrase; only the admin
+        // token is waived.
+        .route("/panic", post(handlers::vpn::trigger_panic))
+}
+
+/// Serves `app` on `path` until the listener fails. A stale socket file left
+/// by a previous run is replaced.
+#[cfg(unix)]
+pub async fn serve(path: &std::path::Path, app: Router) -> std::io::Result<()> {
+    use hyper_util::rt::TokioIo;
+    use hyper_util::service::TowerToHyperService;
+
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let listener = bind_private(path)?;
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let service = TowerToHyperService::new(app.clone());
+        tokio::spawn(async move {
+            if let Err(e) = hyper::server::conn::http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                tracing::debug!("Admin socket connection failed: {}", e);
+            }
+        });
+    }
+}
+
+/// Binds the socket in a fresh 0700 directory next to `path`, narrows it to
+/// 0600 there and renames it into place. Binding at `path` directly would
+/// leave it open under the umask until the mode is changed.
+#[cfg(unix)]
+fn bind_private(path: &std::path::Path) -> std::io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    let name = path
+        .file_name()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "no file name"))?;
+    let parent = path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(std::path::Path::new("."));
+    let private = parent.join(format!(
+        ".{}.{}.tmp",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&private);
+    std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+
+    let staged = private.join(name);
+    let bound = tokio::net::UnixListener::bind(&staged).and_then(|listener| {
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_dir_all(&private);
+    bound
+}
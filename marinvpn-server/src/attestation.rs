@@ -0,0 +1,110 @@
+use crate::error::AppError;
+use axum::http::HeaderMap;
+use base64::Engine;
+use marinvpn_common::attestation::{
+    body_hash_hex, signing_message, ATTESTATION_BODY_HEADER, ATTESTATION_HEADER,
+    ATTESTATION_PUB_HEADER,
+};
+use ring::signature::{UnparsedPublicKey, ED25519};
+
+/// How far a request timestamp may drift from the server clock.
+pub const MAX_CLOCK_SKEW_SECS: i64 = 60;
+
+/// The parts of a request's attestation headers that can be checked without
+/// touching the database. Replay and device-key lookups stay in the
+/// middleware.
+#[derive(Debug)]
+pub struct Attestation {
+    pub timestamp: String,
+    pub nonce: String,
+    pub provided_pubkey: Option<String>,
+    signature_b64: String,
+    body_hash: String,
+}
+
+impl Attestation {
+    /// Parses the headers and checks freshness and the body hash.
+    pub fn from_request(
+        headers: &HeaderMap,
+        body: &[u8],
+        path: &str,
+        now: i64,
+        require_body_hash: bool,
+    ) -> Result<Self, AppError> {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|h| h.to_str().ok())
+                .map(|s| s.to_string())
+        };
+
+        let attestation = header(ATTESTATION_HEADER).ok_or(AppError::Unauthorized)?;
+        let att_parts: Vec<&str> = attestation.split(':').collect();
+        if att_parts.len() != 3 {
+            tracing::warn!(
+                "Blocked request with invalid attestation format from {}",
+                path
+            );
+            return Err(AppError::Unauthorized);
+        }
+
+        let timestamp = att_parts[0]
+            .parse::<i64>()
+            .map_err(|_| AppError::Unauthorized)?;
+        if (now - timestamp).abs() > MAX_CLOCK_SKEW_SECS {
+            tracing::warn!(
+                "Blocked request with expired attestation (diff: {}s) to {}",
+                now - timestamp,
+                path
+            );
+            return Err(AppError::Unauthorized);
+        }
+
+        let body_hash = body_hash_hex(body);
+        match header(ATTESTATION_BODY_HEADER) {
+            Some(provided) if provided != body_hash => {
+                tracing::warn!("Blocked request with body hash mismatch to {}", path);
+                return Err(AppError::Unauthorized);
+            }
+            None if require_body_hash => {
+                tracing::warn!("Blocked request missing attestation body hash to {}", path);
+                return Err(AppError::Unauthorized);
+            }
+            _ => {}
+        }
+
+        Ok(Self {
+            timestamp: att_parts[0].to_string(),
+            nonce: att_parts[1].to_string(),
+            provided_pubkey: header(ATTESTATION_PUB_HEADER),
+            signature_b64: att_parts[2].to_string(),
+            body_hash,
+        })
+    }
+
+    pub fn verify_signature(
+        &self,
+        method: &str,
+        path: &str,
+        pubkey_b64: &str,
+    ) -> Result<(), AppError> {
+        let pubkey_bytes = base64::engine::general_purpose::STANDARD
+            .decode(pubkey_b64)
+            .map_err(|_| AppError::Unauthorized)?;
+        let sig_bytes = base64::engine::general_purpose::STANDARD
+            .decode(&self.signature_b64)
+            .map_err(|_| AppError::Unauthorized)?;
+
+        let message = signing_message(&self.timestamp, &self.nonce, method, path, &self.body_hash);
+
+        let verifier = UnparsedPublicKey::new(&ED25519, pubkey_bytes);
+        if verifier.verify(message.as_bytes(), &sig_bytes).is_err() {
+            tracing::warn!(
+                "Blocked unauthorized client request to {} (Signature mismatch)",
+                path
+            );
+            return Err(AppError::Unauthorized);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,105 @@
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts, http::HeaderMap};
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::error::{AppError, AppResult};
+
+/// Lets a caller ask for less time than the server's request timeout, in
+/// milliseconds. It can only shorten the budget.
+pub const DEADLINE_HEADER: &str = "x-request-deadline-ms";
+
+/// Longest stretch of the request timeout kept back from handlers, so a
+/// step that runs out of budget can still roll back and answer before
+/// `enforce_timeout` drops the request.
+const MAX_HEADROOM: Duration = Duration::from_secs(1);
+
+/// When a request has to be finished by. Set per request by
+/// `runtime::enforce_timeout` and passed down to each step of a long
+/// handler chain, which checks it before starting work it cannot finish.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Deadline {
+    at: Option<Instant>,
+}
+
+impl Deadline {
+    pub fn after(budget: Duration) -> Self {
+        Self {
+            at: Some(Instant::now() + budget),
+        }
+    }
+
+    /// For work outside a request, such as tests and maintenance tasks.
+    pub fn unbounded() -> Self {
+        Self { at: None }
+    }
+
+    /// The deadline for a request under `timeout`: headroom is taken off the
+    /// end, and a shorter budget in [`DEADLINE_HEADER`] wins.
+    pub fn for_request(timeout: Duration, headers: &HeaderMap) -> Self {
+        let budget = timeout.saturating_sub(MAX_HEADROOM.min(timeout / 10));
+        let requested = headers
+            .get(DEADLINE_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .map(Duration::from_millis);
+        match requested {
+            Some(requested) => Self::after(budget.min(requested)),
+            None => Self::after(budget),
+        }
+    }
+
+    /// Time left, or `None` when there is no deadline.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.at
+            .map(|at| at.saturating_duration_since(Instant::now()))
+    }
+
+    /// Fails with `DeadlineExceeded` once the budget is spent, so `step` is
+    /// never started.
+    pub fn check(&self, step: &str) -> AppResult<()> {
+        match self.remaining() {
+            Some(left) if left.is_zero() => {
+                tracing::warn!("Request deadline passed before {}", step);
+                Err(AppError::DeadlineExceeded)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Runs `step` until the deadline. A step cut short is dropped, so a
+    /// transaction it holds is rolled back rather than committed.
+    pub async fn run<T>(
+        &self,
+        step: &str,
+        fut: impl Future<Output = AppResult<T>>,
+    ) -> AppResult<T> {
+        self.check(step)?;
+        let Some(at) = self.at else {
+            return fut.await;
+        };
+        match tokio::time::timeout_at(at, fut).await {
+            Ok(result) => result,
+            Err(_) => {
+                tracing::warn!("Request deadline passed during {}", step);
+                Err(AppError::DeadlineExceeded)
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Deadline
+where
+    S: Send + Sync,
+{
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .extensions
+            .get::<Deadline>()
+            .copied()
+            .unwrap_or_else(Deadline::unbounded))
+    }
+}
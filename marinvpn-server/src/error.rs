@@ -4,6 +4,8 @@ use axum::{
     response::{IntoResponse, Response},
     Json,
 };
+use marinvpn_common::attestation::DEVICE_KEY_MISMATCH_CODE;
+use marinvpn_common::version::CLIENT_TOO_OLD_CODE;
 use serde_json::json;
 use thiserror::Error;
 
@@ -27,50 +29,148 @@ pub enum AppError {
     #[error("Unauthorized")]
     Unauthorized,
 
+    /// Signed with a valid key, but not the one registered for the device.
+    #[error("This device's key does not match the one registered for it")]
+    DeviceKeyMismatch,
+
     #[error("Invalid request: {0}")]
     BadRequest(String),
 
+    /// Unknown, already claimed or expired; the three are not told apart.
+    #[error("This transfer code is not valid. Create a new one on your other device.")]
+    TransferTokenInvalid,
+
     #[error("Device already exists")]
     DeviceConflict,
+
+    #[error("Account generation limit reached, try again later")]
+    GenerationThrottled { global: bool, retry_after_secs: u64 },
+
+    #[error("A valid invite code is required to create an account")]
+    InviteRequired,
+
+    #[error("This WireGuard public key is already in use; generate a new key")]
+    PubKeyInUse,
+
+    #[error("Too many requests")]
+    TooManyRequests { retry_after_secs: u64 },
+
+    /// The server is up but cannot take the request right now, e.g. during
+    /// maintenance or with the address pool exhausted.
+    #[error("{0}")]
+    ServiceBusy(String),
+
+    #[error("Request body is too large")]
+    PayloadTooLarge,
+
+    /// The request raced another one that changed the same resource.
+    #[error("{0}")]
+    Conflict(String),
+
+    /// The request ran out of time and gave up before its next step.
+    #[error("Request could not be completed in time")]
+    DeadlineExceeded,
+
+    /// The app is older than `server.min_client_version`.
+    #[error("This version of the app is no longer supported. Update to {min_version} or later.")]
+    ClientTooOld { min_version: String },
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::Internal(_) | AppError::Database(_) | AppError::Migration(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            AppError::AccountNotFound
+            | AppError::Unauthorized
+            | AppError::DeviceKeyMismatch
+            | AppError::TransferTokenInvalid => StatusCode::UNAUTHORIZED,
+            AppError::AccountExpired | AppError::InviteRequired => StatusCode::FORBIDDEN,
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::DeviceConflict | AppError::PubKeyInUse | AppError::Conflict(_) => {
+                StatusCode::CONFLICT
+            }
+            AppError::GenerationThrottled { .. } | AppError::TooManyRequests { .. } => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            AppError::ServiceBusy(_) | AppError::DeadlineExceeded => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            AppError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::ClientTooOld { .. } => StatusCode::UPGRADE_REQUIRED,
+        }
+    }
+
+    /// The `code` field of the error body, stable for clients to match on.
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::Internal(_) | AppError::Database(_) | AppError::Migration(_) => "internal",
+            AppError::AccountNotFound => "account_not_found",
+            AppError::AccountExpired => "account_expired",
+            AppError::Unauthorized => "unauthorized",
+            AppError::DeviceKeyMismatch => DEVICE_KEY_MISMATCH_CODE,
+            AppError::BadRequest(_) => "bad_request",
+            AppError::TransferTokenInvalid => "transfer_token_invalid",
+            AppError::DeviceConflict => "device_conflict",
+            AppError::GenerationThrottled { global: false, .. } => "generation_ip_limited",
+            AppError::GenerationThrottled { global: true, .. } => "generation_global_limited",
+            AppError::InviteRequired => "invite_required",
+            AppError::PubKeyInUse => "pubkey_in_use",
+            AppError::TooManyRequests { .. } => "rate_limited",
+            AppError::ServiceBusy(_) => "service_busy",
+            AppError::PayloadTooLarge => "payload_too_large",
+            AppError::Conflict(_) => "conflict",
+            AppError::DeadlineExceeded => "deadline_exceeded",
+            AppError::ClientTooOld { .. } => CLIENT_TOO_OLD_CODE,
+        }
+    }
+
+    fn retry_after_secs(&self) -> Option<u64> {
+        match self {
+            AppError::GenerationThrottled {
+                retry_after_secs, ..
+            }
+            | AppError::TooManyRequests { retry_after_secs } => Some(*retry_after_secs),
+            _ => None,
+        }
+    }
 }
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        let (status, error_message) = match &self {
+        // Server-side failures are logged in full but described to the
+        // client only in general terms.
+        let error_message = match &self {
             AppError::Internal(e) => {
                 tracing::error!("Internal error: {:?}", e);
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "Internal server error".to_string(),
-                )
+                "Internal server error".to_string()
             }
             AppError::Database(e) => {
                 tracing::error!("Database error: {:?}", e);
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "Database operation failed".to_string(),
-                )
+                "Database operation failed".to_string()
             }
             AppError::Migration(e) => {
                 tracing::error!("Migration error: {:?}", e);
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "Migration failed".to_string(),
-                )
+                "Migration failed".to_string()
             }
-            AppError::AccountNotFound => (StatusCode::UNAUTHORIZED, self.to_string()),
-            AppError::AccountExpired => (StatusCode::FORBIDDEN, self.to_string()),
-            AppError::Unauthorized => (StatusCode::UNAUTHORIZED, self.to_string()),
-            AppError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg.clone()),
-            AppError::DeviceConflict => (StatusCode::CONFLICT, self.to_string()),
+            AppError::BadRequest(msg) => msg.clone(),
+            other => other.to_string(),
         };
 
         let body = Json(json!({
             "error": error_message,
+            "code": self.code(),
             "success": false,
         }));
-
-        (status, body).into_response()
+        let mut response = (self.status(), body).into_response();
+        if let Some(secs) = self.retry_after_secs() {
+            response.headers_mut().insert(
+                axum::http::header::RETRY_AFTER,
+                axum::http::HeaderValue::from(secs),
+            );
+        }
+        response
     }
 }
 
@@ -0,0 +1,98 @@
+use crate::error::{AppError, AppResult};
+use crate::log_filter::{LogFilter, LOG_FILTER};
+use crate::AppState;
+use axum::{extract::State, Json};
+use marinvpn_common::{ErrorResponse, LogLevelRequest, LogLevelResponse};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Maintenance mode answers app requests with 503. The HTTP admin routes and
+/// public paths stay open during it; this switch is on the admin socket.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MaintenanceRequest {
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MaintenanceResponse {
+    pub enabled: bool,
+    pub previous: bool,
+}
+
+pub async fn set_maintenance(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<MaintenanceRequest>,
+) -> Json<MaintenanceResponse> {
+    let mut next = (*state.runtime.current()).clone();
+    let previous = next.maintenance_mode;
+    next.maintenance_mode = payload.enabled;
+    state.runtime.apply(next);
+    tracing::warn!(enabled = payload.enabled, "Maintenance mode changed");
+    Json(MaintenanceResponse {
+        enabled: payload.enabled,
+        previous,
+    })
+}
+
+/// Draining turns away requests for new tunnels so the server can be taken
+/// out of rotation; connected clients keep their peers until they leave.
+/// Like maintenance, it is switched on the admin socket.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct DrainRequest {
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct DrainResponse {
+    pub enabled: bool,
+    pub previous: bool,
+}
+
+pub async fn set_draining(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<DrainRequest>,
+) -> Json<DrainResponse> {
+    let previous = state.runtime.set_draining(payload.enabled);
+    tracing::warn!(enabled = payload.enabled, "Draining changed");
+    Json(DrainResponse {
+        enabled: payload.enabled,
+        previous,
+    })
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/admin/log-level",
+    request_body = LogLevelRequest,
+    responses(
+        (status = 200, description = "Filter replaced; `previous` is what to send to revert", body = LogLevelResponse),
+        (status = 400, description = "Directives could not be parsed", body = ErrorResponse),
+        (status = 401, description = "Missing or wrong admin token", body = ErrorResponse)
+    )
+)]
+pub async fn set_log_level(
+    Json(payload): Json<LogLevelRequest>,
+) -> AppResult<Json<LogLevelResponse>> {
+    let filter = LOG_FILTER
+        .get()
+        .ok_or_else(|| AppError::Internal(anyhow::anyhow!("Log filter is not installed")))?;
+    apply_log_level(filter, payload)
+}
+
+pub fn apply_log_level(
+    filter: &LogFilter,
+    payload: LogLevelRequest,
+) -> AppResult<Json<LogLevelResponse>> {
+    let previous = filter
+        .set(&payload.directives)
+        .map_err(AppError::BadRequest)?;
+    tracing::warn!(
+        previous = %previous,
+        directives = %payload.directives,
+        "Log filter changed at runtime"
+    );
+    Ok(Json(LogLevelResponse {
+        directives: payload.directives,
+        previous,
+    }))
+}
@@ -1,5 +1,8 @@
 use crate::error::{AppError, AppResult};
 use crate::models::Device;
+use crate::runtime::{retry_after_secs, GenerationThrottle};
+use crate::services::auth::TokenIssuer;
+use crate::services::transfer::AccountTransfer;
 use crate::AppState;
 use axum::{
     async_trait, extract::FromRef, extract::FromRequestParts, http::request::Parts, http::HeaderMap,
@@ -7,11 +10,16 @@ use axum::{
 use axum::{extract::State, Json};
 use base64::Engine;
 use chrono::Utc;
+use marinvpn_common::account_number;
+use marinvpn_common::attestation::ATTESTATION_PUB_HEADER;
 use marinvpn_common::{
-    BlindTokenRequest, BlindTokenResponse, ErrorResponse, GenerateResponse, LoginRequest,
-    LoginResponse, RefreshRequest, RefreshResponse, RemoveDeviceRequest,
+    AccountPanicRequest, AccountPanicResponse, BlindTokenRequest, BlindTokenResponse,
+    ClaimTransferRequest, ErrorResponse, GenerateRequest, GenerateResponse, LoginRequest,
+    LoginResponse, LogoutResponse, RefreshRequest, RefreshResponse, RemoveDeviceReason,
+    RemoveDeviceRequest, RemoveDeviceResponse, SessionsResponse, TransferTokenResponse,
 };
 use rand::Rng;
+use std::net::{IpAddr, Ipv4Addr};
 use std::sync::Arc;
 use validator::Validate;
 
@@ -20,6 +28,10 @@ pub struct AuthUser {
     pub device_name: String,
 }
 
+/// Caller address resolved through the trusted proxy settings. `None` when
+/// the connection info is unavailable (e.g. in-process tests).
+pub struct ClientIp(pub Option<IpAddr>);
+
 #[utoipa::path(
     get,
     path = "/api/v1/auth/blind-key",
@@ -102,8 +114,7 @@ where
         }
 
         let token = &auth_header[7..];
-        let claims =
-            crate::services::auth::decode_access_token(token, &state.settings.auth.jwt_secret)?;
+        let claims = TokenIssuer::from_settings(&state.settings.auth).decode_access_token(token)?;
 
         Ok(AuthUser {
             account_number: claims.sub,
@@ -112,16 +123,56 @@ where
     }
 }
 
+#[async_trait]
+impl<S> FromRequestParts<S> for ClientIp
+where
+    S: Send + Sync,
+{
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(ClientIp(crate::client_ip(parts)))
+    }
+}
+
 #[utoipa::path(
     post,
     path = "/api/v1/account/generate",
+    request_body(content = Option<GenerateRequest>, description = "Invite code, when the server requires one"),
     responses(
-        (status = 200, description = "Account generated successfully", body = GenerateResponse)
+        (status = 200, description = "Account generated successfully", body = GenerateResponse),
+        (status = 403, description = "Invite code required", body = ErrorResponse),
+        (status = 429, description = "Account generation throttled", body = ErrorResponse)
     )
 )]
 pub async fn generate_account(
     State(state): State<Arc<AppState>>,
+    ClientIp(ip): ClientIp,
+    payload: Option<Json<GenerateRequest>>,
 ) -> AppResult<Json<GenerateResponse>> {
+    // Throttle before looking at the invite code so the limit also caps
+    // invite guessing.
+    let ip = ip.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    state
+        .runtime
+        .check_generation(ip)
+        .map_err(|throttle| match throttle {
+            GenerationThrottle::PerIp(wait) => AppError::GenerationThrottled {
+                global: false,
+                retry_after_secs: retry_after_secs(wait),
+            },
+            GenerationThrottle::Global(wait) => {
+                tracing::warn!("Global account generation limit reached");
+                AppError::GenerationThrottled {
+                    global: true,
+                    retry_after_secs: retry_after_secs(wait),
+                }
+            }
+        })?;
+
+    let invite_code = payload.and_then(|Json(req)| req.invite_code.clone());
+    check_invite_code(&state.settings.auth.invite_codes, invite_code.as_deref())?;
+
     let mut attempts = 0;
     let account = loop {
         let account_number = generate_account_number();
@@ -154,7 +205,8 @@ pub async fn generate_account(
     responses(
         (status = 200, description = "Login successful", body = LoginResponse),
         (status = 401, description = "Account not found", body = ErrorResponse),
-        (status = 400, description = "Invalid request or device limit reached", body = ErrorResponse)
+        (status = 400, description = "Invalid request or device limit reached", body = ErrorResponse),
+        (status = 409, description = "Device claimed by a concurrent login", body = ErrorResponse)
     )
 )]
 pub async fn login(
@@ -165,14 +217,27 @@ pub async fn login(
     payload
         .validate()
         .map_err(|e| AppError::BadRequest(e.to_string()))?;
+    check_device_pubkey(&headers, payload.device_pubkey.as_deref())?;
+
+    sign_in(
+        &state,
+        &payload.account_number,
+        payload.device_pubkey.as_deref(),
+        payload.kick_device.as_deref(),
+    )
+    .await
+    .map(Json)
+}
 
-    if is_production() && payload.device_pubkey.is_none() {
+/// The key a login registers must be the one the request is signed with.
+fn check_device_pubkey(headers: &HeaderMap, device_pubkey: Option<&str>) -> AppResult<()> {
+    if is_production() && device_pubkey.is_none() {
         return Err(AppError::BadRequest(
             "device_pubkey required in production".to_string(),
         ));
     }
 
-    if let Some(ref device_pubkey) = payload.device_pubkey {
+    if let Some(device_pubkey) = device_pubkey {
         let decoded = base64::engine::general_purpose::STANDARD
             .decode(device_pubkey)
             .map_err(|_| AppError::BadRequest("invalid device_pubkey".to_string()))?;
@@ -180,18 +245,27 @@ pub async fn login(
             return Err(AppError::BadRequest("invalid device_pubkey".to_string()));
         }
         let provided_pubkey = headers
-            .get("X-Marin-Attestation-Pub")
+            .get(ATTESTATION_PUB_HEADER)
             .and_then(|h| h.to_str().ok())
-            .map(|s| s.to_string())
             .ok_or(AppError::Unauthorized)?;
-        if provided_pubkey != *device_pubkey {
+        if provided_pubkey != device_pubkey {
             return Err(AppError::Unauthorized);
         }
     }
+    Ok(())
+}
 
+/// Everything a login does once the caller has shown it holds the account:
+/// picks or registers the device and opens a session for it.
+async fn sign_in(
+    state: &AppState,
+    account_number: &str,
+    device_pubkey: Option<&str>,
+    kick_device: Option<&str>,
+) -> AppResult<LoginResponse> {
     let account = state
         .db
-        .get_account(&payload.account_number)
+        .get_account(account_number)
         .await?
         .ok_or(AppError::AccountNotFound)?;
 
@@ -201,7 +275,7 @@ pub async fn login(
 
     let devices = state.db.get_devices(&account.account_number).await?;
 
-    let existing_device = if let Some(ref pubkey) = payload.device_pubkey {
+    let existing_device = if let Some(pubkey) = device_pubkey {
         state
             .db
             .get_device_by_pubkey(&account.account_number, pubkey)
@@ -212,7 +286,7 @@ pub async fn login(
 
     let device_name = if let Some(existing) = existing_device {
         existing.name
-    } else if let Some(pubkey) = payload.device_pubkey.as_deref() {
+    } else if let Some(pubkey) = device_pubkey {
         if let Some(placeholder) = devices.iter().find(|d| d.attestation_pubkey.is_none()) {
             let updated = state
                 .db
@@ -221,12 +295,12 @@ pub async fn login(
             if updated {
                 placeholder.name.clone()
             } else {
-                return Err(AppError::Internal(anyhow::anyhow!(
-                    "Failed to claim placeholder device"
-                )));
+                return Err(AppError::Conflict(
+                    "Device was claimed by another login".to_string(),
+                ));
             }
         } else if devices.len() >= 5 {
-            if let Some(ref kick) = payload.kick_device {
+            if let Some(kick) = kick_device {
                 let removed = state
                     .db
                     .remove_device(&account.account_number, kick)
@@ -239,7 +313,7 @@ pub async fn login(
                             created_date: format_utc_date(d.added_at),
                         })
                         .collect();
-                    return Ok(Json(LoginResponse {
+                    return Ok(LoginResponse {
                         success: false,
                         auth_token: None,
                         refresh_token: None,
@@ -248,7 +322,7 @@ pub async fn login(
                         devices: Some(common_devices),
                         error_code: Some("DEVICE_NOT_FOUND".to_string()),
                         error: Some("Device not found".to_string()),
-                    }));
+                    });
                 }
 
                 let name = generate_device_name();
@@ -265,7 +339,7 @@ pub async fn login(
                         created_date: format_utc_date(d.added_at),
                     })
                     .collect();
-                return Ok(Json(LoginResponse {
+                return Ok(LoginResponse {
                     success: false,
                     auth_token: None,
                     refresh_token: None,
@@ -276,7 +350,7 @@ pub async fn login(
                     error: Some(
                         "Device limit reached (max 5). Remove a device to continue.".to_string(),
                     ),
-                }));
+                });
             }
         } else {
             let name = generate_device_name();
@@ -295,27 +369,39 @@ pub async fn login(
         name
     };
 
-    let token = crate::services::auth::create_token(
-        &account.account_number,
-        &device_name,
-        &state.settings.auth.jwt_secret,
-    )?;
-    let (refresh_token, refresh_exp) = crate::services::auth::create_refresh_token(
-        &account.account_number,
-        &device_name,
-        &state.settings.auth.jwt_secret,
-    )?;
-    state
+    let tokens = TokenIssuer::from_settings(&state.settings.auth);
+    let token = tokens.access_token(&account.account_number, &device_name, device_pubkey)?;
+    let (refresh_token, refresh_exp) =
+        tokens.refresh_token(&account.account_number, &device_name, device_pubkey)?;
+    let max_sessions = state.settings.auth.max_sessions_per_device;
+    let created = state
         .db
-        .upsert_refresh_token(
+        .create_session(
             &account.account_number,
             &device_name,
             &refresh_token,
             refresh_exp,
+            max_sessions,
         )
         .await?;
+    if !created {
+        return Ok(LoginResponse {
+            success: false,
+            auth_token: None,
+            refresh_token: None,
+            account_info: None,
+            current_device: Some(device_name),
+            devices: None,
+            error_code: Some("SESSION_LIMIT".to_string()),
+            error: Some(format!(
+                "This device already has {} active sessions. Log out elsewhere or wait for one \
+                 to expire.",
+                max_sessions
+            )),
+        });
+    }
 
-    Ok(Json(LoginResponse {
+    Ok(LoginResponse {
         success: true,
         auth_token: Some(token),
         refresh_token: Some(refresh_token),
@@ -324,9 +410,83 @@ pub async fn login(
         devices: None,
         error_code: None,
         error: None,
+    })
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/account/transfer-token",
+    responses(
+        (status = 200, description = "A single-use code for signing in another device", body = TransferTokenResponse),
+        (status = 401, description = "Missing or invalid token", body = ErrorResponse),
+        (status = 403, description = "Account expired", body = ErrorResponse)
+    )
+)]
+pub async fn issue_transfer_token(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+) -> AppResult<Json<TransferTokenResponse>> {
+    let account = state
+        .db
+        .get_account(&auth.account_number)
+        .await?
+        .ok_or(AppError::AccountNotFound)?;
+
+    // The new device could not log in with it anyway.
+    if account.expiry_date < Utc::now().timestamp() {
+        return Err(AppError::AccountExpired);
+    }
+
+    let (transfer_token, expires_at) = AccountTransfer::from_settings(&state.settings.auth)
+        .issue(&state.db, &account.account_number)
+        .await?;
+    Ok(Json(TransferTokenResponse {
+        transfer_token,
+        expires_at,
     }))
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/v1/account/transfer-token/claim",
+    request_body = ClaimTransferRequest,
+    responses(
+        (status = 200, description = "Login successful, or stopped at the device limit", body = LoginResponse),
+        (status = 401, description = "Transfer code unknown, used or expired", body = ErrorResponse)
+    )
+)]
+pub async fn claim_transfer_token(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<ClaimTransferRequest>,
+) -> AppResult<Json<LoginResponse>> {
+    payload
+        .validate()
+        .map_err(|e| AppError::BadRequest(e.to_string()))?;
+    check_device_pubkey(&headers, payload.device_pubkey.as_deref())?;
+
+    let claimed = AccountTransfer::from_settings(&state.settings.auth)
+        .claim(&state.db, &payload.transfer_token)
+        .await?
+        .ok_or(AppError::TransferTokenInvalid)?;
+    let result = sign_in(
+        &state,
+        &claimed.account_number,
+        payload.device_pubkey.as_deref(),
+        payload.kick_device.as_deref(),
+    )
+    .await;
+    match result {
+        Ok(response) if response.success => Ok(Json(response)),
+        // No session was opened, e.g. at the device limit, so the code
+        // stays good for the retry.
+        other => {
+            claimed.give_back(&state.db).await?;
+            other.map(Json)
+        }
+    }
+}
+
 fn generate_device_name() -> String {
     let mut rng = rand::thread_rng();
     let adjectives = [
@@ -342,21 +502,33 @@ fn generate_device_name() -> String {
     )
 }
 
+/// An empty list disables the requirement.
+pub fn check_invite_code(allowed: &[String], provided: Option<&str>) -> AppResult<()> {
+    if allowed.is_empty() {
+        return Ok(());
+    }
+    let provided = provided.map(str::trim).unwrap_or_default();
+    if provided.is_empty() {
+        return Err(AppError::InviteRequired);
+    }
+
+    use subtle::ConstantTimeEq;
+    let matched = allowed.iter().fold(0u8, |acc, code| {
+        acc | code.as_bytes().ct_eq(provided.as_bytes()).unwrap_u8()
+    });
+    if matched == 0 {
+        return Err(AppError::InviteRequired);
+    }
+    Ok(())
+}
+
 fn generate_account_number() -> String {
-    const ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+    let alphabet = account_number::ALPHABET.as_bytes();
     let mut rng = rand::thread_rng();
-    let mut raw = String::with_capacity(16);
-    for _ in 0..16 {
-        let idx = rng.gen_range(0..ALPHABET.len());
-        raw.push(ALPHABET[idx] as char);
-    }
-    format!(
-        "{} {} {} {}",
-        &raw[0..4],
-        &raw[4..8],
-        &raw[8..12],
-        &raw[12..16]
-    )
+    let raw: String = (0..account_number::LENGTH)
+        .map(|_| alphabet[rng.gen_range(0..alphabet.len())] as char)
+        .collect();
+    account_number::format_groups(&raw)
 }
 
 #[utoipa::path(
@@ -377,13 +549,11 @@ pub async fn refresh_token(
         .validate()
         .map_err(|e| AppError::BadRequest(e.to_string()))?;
 
-    let claims = crate::services::auth::decode_refresh_token(
-        &payload.refresh_token,
-        &state.settings.auth.jwt_secret,
-    )?;
+    let tokens = TokenIssuer::from_settings(&state.settings.auth);
+    let claims = tokens.decode_refresh_token(&payload.refresh_token)?;
 
     let provided_pubkey = headers
-        .get("X-Marin-Attestation-Pub")
+        .get(ATTESTATION_PUB_HEADER)
         .and_then(|h| h.to_str().ok())
         .map(|s| s.to_string())
         .ok_or(AppError::Unauthorized)?;
@@ -392,20 +562,20 @@ pub async fn refresh_token(
         .db
         .get_device_pubkey(&claims.sub, &claims.device)
         .await?;
-    if stored_pubkey.as_deref() != Some(provided_pubkey.as_str()) {
-        return Err(AppError::Unauthorized);
+    match stored_pubkey {
+        None => return Err(AppError::Unauthorized),
+        Some(stored)
+            if stored != provided_pubkey || !claims.is_bound_to(Some(&provided_pubkey)) =>
+        {
+            return Err(AppError::DeviceKeyMismatch)
+        }
+        Some(_) => {}
     }
 
-    let new_access = crate::services::auth::create_token(
-        &claims.sub,
-        &claims.device,
-        &state.settings.auth.jwt_secret,
-    )?;
-    let (new_refresh, refresh_exp) = crate::services::auth::create_refresh_token(
-        &claims.sub,
-        &claims.device,
-        &state.settings.auth.jwt_secret,
-    )?;
+    let device_pubkey = Some(provided_pubkey.as_str());
+    let new_access = tokens.access_token(&claims.sub, &claims.device, device_pubkey)?;
+    let (new_refresh, refresh_exp) =
+        tokens.refresh_token(&claims.sub, &claims.device, device_pubkey)?;
 
     let success = state
         .db
@@ -485,7 +655,7 @@ fn format_utc_date(ts: i64) -> String {
     path = "/api/v1/account/devices/remove",
     request_body = RemoveDeviceRequest,
     responses(
-        (status = 200, description = "Device removed successfully", body = bool),
+        (status = 200, description = "Whether the device was removed, and why not", body = RemoveDeviceResponse),
         (status = 401, description = "Account not found", body = ErrorResponse)
     )
 )]
@@ -493,7 +663,7 @@ pub async fn remove_device(
     State(state): State<Arc<AppState>>,
     auth: AuthUser,
     Json(payload): Json<RemoveDeviceRequest>,
-) -> AppResult<Json<bool>> {
+) -> AppResult<Json<RemoveDeviceResponse>> {
     payload
         .validate()
         .map_err(|e| AppError::BadRequest(e.to_string()))?;
@@ -508,9 +678,93 @@ pub async fn remove_device(
         .await?
         .ok_or(AppError::AccountNotFound)?;
 
-    let success = state
+    let removed = state
         .db
         .remove_device(&account.account_number, &payload.device_name)
         .await?;
-    Ok(Json(success))
+    Ok(Json(if removed {
+        RemoveDeviceResponse::removed()
+    } else {
+        RemoveDeviceResponse::not_removed(RemoveDeviceReason::NotFound)
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/account/sessions",
+    responses(
+        (status = 200, description = "Unexpired sessions held by this device", body = SessionsResponse),
+        (status = 401, description = "Missing or invalid token", body = ErrorResponse)
+    )
+)]
+pub async fn get_sessions(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+) -> AppResult<Json<SessionsResponse>> {
+    let active_sessions = state
+        .db
+        .count_active_sessions(&auth.account_number, &auth.device_name)
+        .await?;
+    Ok(Json(SessionsResponse {
+        active_sessions,
+        max_sessions: state.settings.auth.max_sessions_per_device,
+    }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/account/logout",
+    responses(
+        (status = 200, description = "The device's refresh tokens were revoked; the device stays listed", body = LogoutResponse),
+        (status = 401, description = "Missing or invalid token", body = ErrorResponse)
+    )
+)]
+pub async fn logout(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+) -> AppResult<Json<LogoutResponse>> {
+    // Access tokens are not tracked, so the presented one lives out its
+    // short lifetime; revoking the refresh token is what ends the session.
+    let sessions_revoked = state
+        .db
+        .revoke_refresh_tokens(&auth.account_number, &auth.device_name)
+        .await?;
+    Ok(Json(LogoutResponse { sessions_revoked }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/account/panic",
+    request_body = AccountPanicRequest,
+    responses(
+        (status = 200, description = "The account's devices, sessions and peers were removed", body = AccountPanicResponse),
+        (status = 401, description = "Token does not match the account", body = ErrorResponse)
+    )
+)]
+pub async fn panic_account(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+    Json(payload): Json<AccountPanicRequest>,
+) -> AppResult<Json<AccountPanicResponse>> {
+    payload
+        .validate()
+        .map_err(|e| AppError::BadRequest(e.to_string()))?;
+
+    if auth.account_number != payload.account_number {
+        return Err(AppError::Unauthorized);
+    }
+
+    // The token already proves the account; an unknown one simply has
+    // nothing to wipe.
+    let (devices_removed, pub_keys) = state.db.panic_wipe_account(&payload.account_number).await?;
+    for pub_key in &pub_keys {
+        if let Err(e) = state.vpn.remove_peer(pub_key).await {
+            tracing::error!("Failed to remove peer during account panic wipe: {}", e);
+        }
+    }
+
+    Ok(Json(AccountPanicResponse {
+        devices_removed,
+        peers_removed: pub_keys.len() as u64,
+    }))
 }
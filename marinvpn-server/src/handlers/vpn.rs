@@ -1,12 +1,20 @@
+use crate::config::Settings;
+use crate::deadline::Deadline;
 use crate::error::{AppError, AppResult};
 use crate::handlers::auth::AuthUser;
 use crate::models::CommonVpnServer;
+use crate::runtime::RuntimeSettings;
+use crate::services::db::{PanicWipe, PeerOwner};
+use crate::services::pow::{disabled_challenge, PowVerifier};
 use crate::AppState;
 use axum::{extract::State, Json};
 use base64::Engine;
 use chrono::Utc;
+use marinvpn_common::version::features;
 use marinvpn_common::{
-    AnonymousConfigRequest, ConfigRequest, ErrorResponse, ReportRequest, WireGuardConfig,
+    AnonymousConfigRequest, ConfigRequest, ErrorResponse, PanicRequest, PanicResponse,
+    PowChallenge, ReportRequest, ReportResponse, ServerVersion, WireGuardConfig,
+    PANIC_CONFIRMATION,
 };
 use ml_kem::kem::Encapsulate;
 use ml_kem::{EncodedSizeUser, MlKem768Params};
@@ -42,40 +50,65 @@ pub async fn get_servers(
     Ok(Json(common_servers))
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/v1/vpn/pow-challenge",
+    responses(
+        (status = 200, description = "Proof-of-work challenge for anonymous config", body = PowChallenge)
+    )
+)]
+pub async fn get_pow_challenge(State(state): State<Arc<AppState>>) -> Json<PowChallenge> {
+    match PowVerifier::from_settings(&state.settings.auth) {
+        Some(verifier) => Json(verifier.issue(Utc::now().timestamp())),
+        None => Json(disabled_challenge()),
+    }
+}
+
 #[utoipa::path(
     post,
     path = "/api/v1/vpn/config-anonymous",
     request_body = AnonymousConfigRequest,
     responses(
         (status = 200, description = "Configuration retrieved successfully", body = WireGuardConfig),
-        (status = 401, description = "Invalid token or signature", body = ErrorResponse)
+        (status = 400, description = "Missing or invalid proof of work", body = ErrorResponse),
+        (status = 401, description = "Invalid token or signature", body = ErrorResponse),
+        (status = 409, description = "Token already used", body = ErrorResponse),
+        (status = 503, description = "No tunnel address available", body = ErrorResponse)
     )
 )]
 pub async fn get_anonymous_config(
     State(state): State<Arc<AppState>>,
+    deadline: Deadline,
     Json(payload): Json<AnonymousConfigRequest>,
 ) -> AppResult<Json<WireGuardConfig>> {
     payload
         .validate()
         .map_err(|e: validator::ValidationErrors| AppError::BadRequest(e.to_string()))?;
 
+    // Cheaper than the RSA check below, so it goes first.
+    if let Some(verifier) = PowVerifier::from_settings(&state.settings.auth) {
+        let solution = payload
+            .pow
+            .as_ref()
+            .ok_or_else(|| AppError::BadRequest("Proof of work required".to_string()))?;
+        verifier.verify(solution, &payload.message, Utc::now().timestamp())?;
+    }
+
     if !state.signer.verify(&payload.message, &payload.signature) {
         return Err(AppError::Unauthorized);
     }
 
     if state.db.is_token_used(&payload.message).await? {
-        return Err(AppError::BadRequest("Token already used".to_string()));
+        return Err(AppError::Conflict("Token already used".to_string()));
     }
 
-    if let Err(e) = state.db.mark_token_used(&payload.message).await {
-        if let AppError::Database(sqlx::Error::Database(db_err)) = &e {
-            if db_err.is_unique_violation() {
-                return Err(AppError::BadRequest(
-                    "Token already used (concurrent)".to_string(),
-                ));
-            }
-        }
-        return Err(e);
+    // Once marked, the token is spent whether or not a config comes back.
+    deadline.check("token redemption")?;
+
+    if !state.db.claim_token(&payload.message).await? {
+        return Err(AppError::Conflict(
+            "Token already used (concurrent)".to_string(),
+        ));
     }
 
     let country = payload
@@ -84,7 +117,9 @@ pub async fn get_anonymous_config(
         .next()
         .unwrap_or("Sweden")
         .trim();
-    let servers = state.db.get_servers_by_location(country).await?;
+    let servers = deadline
+        .run("server lookup", state.db.get_servers_by_location(country))
+        .await?;
     let server = servers
         .into_iter()
         .min_by(|a, b| {
@@ -96,10 +131,19 @@ pub async fn get_anonymous_config(
             "No active servers in this location".to_string(),
         ))?;
 
-    let assigned_ip = state.db.get_or_create_peer(&payload.pub_key).await?;
-    state
-        .vpn
-        .register_peer(&payload.pub_key, &assigned_ip)
+    let assigned_ip = state
+        .db
+        .get_or_create_peer(
+            &payload.pub_key,
+            PeerOwner::Anonymous(&payload.message),
+            deadline,
+        )
+        .await?;
+    deadline
+        .run(
+            "peer registration",
+            state.vpn.register_peer(&payload.pub_key, &assigned_ip),
+        )
         .await?;
 
     let dns_servers = "1.1.1.1, 8.8.8.8".to_string();
@@ -147,6 +191,10 @@ pub async fn get_anonymous_config(
         },
         pqc_ciphertext: pqc_ct,
         obfuscation_key: Some(obfuscation_key),
+        obfuscation_port: server
+            .obfuscation_port
+            .and_then(|port| u16::try_from(port).ok()),
+        obfuscation_cipher: server.obfuscation_cipher,
     };
 
     Ok(Json(config))
@@ -159,12 +207,14 @@ pub async fn get_anonymous_config(
     responses(
         (status = 200, description = "Configuration retrieved successfully", body = WireGuardConfig),
         (status = 401, description = "Account not found", body = ErrorResponse),
-        (status = 403, description = "Account expired", body = ErrorResponse)
+        (status = 403, description = "Account expired", body = ErrorResponse),
+        (status = 503, description = "No tunnel address available", body = ErrorResponse)
     )
 )]
 pub async fn get_vpn_config(
     State(state): State<Arc<AppState>>,
     auth: AuthUser,
+    deadline: Deadline,
     Json(payload): Json<ConfigRequest>,
 ) -> AppResult<Json<WireGuardConfig>> {
     payload
@@ -175,9 +225,11 @@ pub async fn get_vpn_config(
         return Err(AppError::Unauthorized);
     }
 
-    let account = state
-        .db
-        .get_account(&payload.account_number)
+    let account = deadline
+        .run(
+            "account lookup",
+            state.db.get_account(&payload.account_number),
+        )
         .await?
         .ok_or(AppError::AccountNotFound)?;
 
@@ -191,7 +243,9 @@ pub async fn get_vpn_config(
         .next()
         .unwrap_or("Sweden")
         .trim();
-    let servers = state.db.get_servers_by_location(country).await?;
+    let servers = deadline
+        .run("server lookup", state.db.get_servers_by_location(country))
+        .await?;
     let server = servers
         .into_iter()
         .min_by(|a, b| {
@@ -203,10 +257,19 @@ pub async fn get_vpn_config(
             "No active servers in this location".to_string(),
         ))?;
 
-    let assigned_ip = state.db.get_or_create_peer(&payload.pub_key).await?;
-    state
-        .vpn
-        .register_peer(&payload.pub_key, &assigned_ip)
+    let assigned_ip = state
+        .db
+        .get_or_create_peer(
+            &payload.pub_key,
+            PeerOwner::Account(&payload.account_number),
+            deadline,
+        )
+        .await?;
+    deadline
+        .run(
+            "peer registration",
+            state.vpn.register_peer(&payload.pub_key, &assigned_ip),
+        )
         .await?;
 
     let dns_servers = if let Some(ref prefs) = payload.dns_blocking {
@@ -264,6 +327,10 @@ pub async fn get_vpn_config(
         },
         pqc_ciphertext: pqc_ct,
         obfuscation_key: Some(obfuscation_key),
+        obfuscation_port: server
+            .obfuscation_port
+            .and_then(|port| u16::try_from(port).ok()),
+        obfuscation_cipher: server.obfuscation_cipher,
     };
 
     Ok(Json(config))
@@ -274,7 +341,7 @@ pub async fn get_vpn_config(
     path = "/api/v1/vpn/report",
     request_body = ReportRequest,
     responses(
-        (status = 200, description = "Report received", body = bool),
+        (status = 200, description = "Report received", body = ReportResponse),
         (status = 401, description = "Account not found", body = ErrorResponse)
     )
 )]
@@ -282,7 +349,7 @@ pub async fn report_problem(
     State(_state): State<Arc<AppState>>,
     auth: AuthUser,
     Json(payload): Json<ReportRequest>,
-) -> AppResult<Json<bool>> {
+) -> AppResult<Json<ReportResponse>> {
     payload
         .validate()
         .map_err(|e: validator::ValidationErrors| AppError::BadRequest(e.to_string()))?;
@@ -308,14 +375,23 @@ pub async fn report_problem(
         "****".to_string()
     };
 
+    let reference = hex::encode(rand::thread_rng().gen::<[u8; 4]>());
     tracing::info!(
+        reference = %reference,
+        category = ?payload.category,
+        app_version = payload.app_version.as_deref().unwrap_or("unknown"),
+        os = ?payload.os,
+        last_error_code = ?payload.last_error_code,
         "PROBLEM REPORTED from {}: (Encrypted: {}, length: {} bytes)",
         masked_account,
         payload.is_encrypted,
         payload.message.len()
     );
 
-    Ok(Json(true))
+    Ok(Json(ReportResponse {
+        accepted: true,
+        reference: Some(reference),
+    }))
 }
 
 #[utoipa::path(
@@ -337,10 +413,53 @@ pub async fn get_canary() -> Json<String> {
     ))
 }
 
+/// This build's version and features under `settings`. Proof of work is
+/// only advertised while it is enforced. The minimum client version is
+/// reloadable, so it comes from `runtime`.
+pub fn server_version(settings: &Settings, runtime: &RuntimeSettings) -> ServerVersion {
+    let mut api_features = vec![
+        features::ANONYMOUS_CONFIG,
+        features::MULTIHOP,
+        features::POST_QUANTUM,
+        features::TRANSFER_TOKENS,
+        features::ENCRYPTED_REPORTS,
+    ];
+    if settings.auth.pow_enabled {
+        api_features.push(features::PROOF_OF_WORK);
+    }
+    ServerVersion {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        api_features: api_features.into_iter().map(str::to_string).collect(),
+        min_client_version: runtime.min_client_version.to_string(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/version",
+    responses(
+        (status = 200, description = "Server version, API features and the oldest supported client", body = ServerVersion)
+    )
+)]
+pub async fn get_version(State(state): State<Arc<AppState>>) -> Json<ServerVersion> {
+    Json(server_version(&state.settings, &state.runtime.current()))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/vpn/panic",
+    request_body = PanicRequest,
+    responses(
+        (status = 200, description = "Wiped, or already wiped within the cooldown", body = PanicResponse),
+        (status = 400, description = "Confirmation phrase missing or wrong", body = ErrorResponse),
+        (status = 401, description = "Missing or wrong X-Panic-Key", body = ErrorResponse)
+    )
+)]
 pub async fn trigger_panic(
     State(state): State<Arc<AppState>>,
     headers: axum::http::HeaderMap,
-) -> AppResult<Json<bool>> {
+    payload: Option<Json<PanicRequest>>,
+) -> AppResult<Json<PanicResponse>> {
     let panic_key = &state.settings.auth.panic_key;
     let provided_key = headers
         .get("X-Panic-Key")
@@ -358,11 +477,39 @@ pub async fn trigger_panic(
         return Err(AppError::Unauthorized);
     }
 
-    state.db.panic_wipe().await?;
-    state.vpn.remove_all_peers().await?;
+    if !payload.is_some_and(|Json(request)| request.is_confirmed()) {
+        return Err(AppError::BadRequest(format!(
+            "Panic wipe requires {{\"confirm\": \"{}\"}}",
+            PANIC_CONFIRMATION
+        )));
+    }
 
-    tracing::error!(
-        "EMERGENCY PANIC WIPE COMPLETED. All ephemeral session data and peers removed."
-    );
-    Ok(Json(true))
+    let cooldown = state.settings.auth.panic_cooldown_secs as i64;
+    match state
+        .db
+        .panic_wipe(Utc::now().timestamp(), cooldown)
+        .await?
+    {
+        PanicWipe::Wiped(at) => {
+            state.vpn.remove_all_peers().await?;
+            tracing::error!(
+                wiped_at = at,
+                "EMERGENCY PANIC WIPE COMPLETED. All ephemeral session data and peers removed."
+            );
+            Ok(Json(PanicResponse {
+                wiped: true,
+                wiped_at: at,
+            }))
+        }
+        PanicWipe::Skipped(at) => {
+            tracing::warn!(
+                wiped_at = at,
+                "Panic wipe repeated within cooldown; ignored"
+            );
+            Ok(Json(PanicResponse {
+                wiped: false,
+                wiped_at: at,
+            }))
+        }
+    }
 }
@@ -5,35 +5,36 @@ use axum::{
     Router,
 };
 use axum_prometheus::PrometheusMetricLayer;
-use base64::Engine;
 use once_cell::sync::Lazy;
-use ring::signature::{UnparsedPublicKey, ED25519};
 use std::net::IpAddr;
 use std::sync::Arc;
 use std::sync::RwLock;
 use std::time::Duration;
-use tower_governor::{governor::GovernorConfigBuilder, GovernorLayer};
-use tower_http::{
-    compression::CompressionLayer,
-    cors::{Any, CorsLayer},
-    timeout::TimeoutLayer,
-    trace::TraceLayer,
-};
+use tower_http::{compression::CompressionLayer, trace::TraceLayer};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use utoipa::OpenApi;
 use utoipa_swagger_ui::SwaggerUi;
 
+pub mod admin_socket;
+pub mod attestation;
 pub mod config;
+pub mod deadline;
 pub mod error;
 pub mod handlers;
+pub mod log_filter;
 pub mod models;
+pub mod runtime;
 pub mod services;
 pub mod vpn_config;
 
 use marinvpn_common::{
-    Account, AnonymousConfigRequest, BlindTokenRequest, BlindTokenResponse, ConfigRequest, Device,
-    ErrorResponse, GenerateResponse, LoginRequest, LoginResponse, RefreshRequest, RefreshResponse,
-    RemoveDeviceRequest, ReportRequest, VpnServer, WireGuardConfig,
+    Account, AccountPanicRequest, AccountPanicResponse, AnonymousConfigRequest, BlindTokenRequest,
+    BlindTokenResponse, ClaimTransferRequest, ConfigRequest, Device, ErrorResponse,
+    GenerateRequest, GenerateResponse, LogLevelRequest, LogLevelResponse, LoginRequest,
+    LoginResponse, LogoutResponse, PanicRequest, PanicResponse, PowChallenge, PowSolution,
+    RefreshRequest, RefreshResponse, RemoveDeviceReason, RemoveDeviceRequest, RemoveDeviceResponse,
+    ReportCategory, ReportErrorCode, ReportOs, ReportRequest, ReportResponse, ServerVersion,
+    SessionsResponse, TransferTokenResponse, VpnServer, WireGuardConfig,
 };
 
 pub struct AppState {
@@ -42,6 +43,7 @@ pub struct AppState {
     pub vpn: services::vpn::VpnOrchestrator,
     pub signer: services::auth::BlindSigner,
     pub support_key: services::auth::SupportKey,
+    pub runtime: Arc<runtime::RuntimeConfig>,
 }
 
 #[derive(Clone, Debug)]
@@ -68,14 +70,23 @@ static ADMIN_GUARD: Lazy<RwLock<AdminGuardConfig>> = Lazy::new(|| {
         handlers::auth::login,
         handlers::auth::get_devices,
         handlers::auth::remove_device,
+        handlers::auth::get_sessions,
+        handlers::auth::logout,
+        handlers::auth::panic_account,
+        handlers::auth::issue_transfer_token,
+        handlers::auth::claim_transfer_token,
         handlers::auth::get_blind_public_key,
         handlers::auth::get_support_public_key,
         handlers::auth::issue_blind_token,
         handlers::auth::refresh_token,
         handlers::vpn::get_vpn_config,
         handlers::vpn::get_anonymous_config,
+        handlers::vpn::get_pow_challenge,
         handlers::vpn::report_problem,
+        handlers::vpn::trigger_panic,
+        handlers::admin::set_log_level,
         handlers::vpn::get_canary,
+        handlers::vpn::get_version,
     ),
     components(
         schemas(
@@ -85,16 +96,36 @@ static ADMIN_GUARD: Lazy<RwLock<AdminGuardConfig>> = Lazy::new(|| {
             LoginRequest,
             ConfigRequest,
             AnonymousConfigRequest,
+            PowChallenge,
+            PowSolution,
             BlindTokenRequest,
             RemoveDeviceRequest,
+            RemoveDeviceResponse,
+            RemoveDeviceReason,
             ReportRequest,
+            ReportResponse,
+            ReportCategory,
+            ReportOs,
+            ReportErrorCode,
+            PanicRequest,
+            PanicResponse,
+            AccountPanicRequest,
+            AccountPanicResponse,
+            LogoutResponse,
+            SessionsResponse,
+            TransferTokenResponse,
+            ClaimTransferRequest,
+            LogLevelRequest,
+            LogLevelResponse,
             LoginResponse,
+            GenerateRequest,
             GenerateResponse,
             BlindTokenResponse,
             RefreshRequest,
             RefreshResponse,
             ErrorResponse,
             WireGuardConfig,
+            ServerVersion,
         )
     ),
     tags(
@@ -106,35 +137,55 @@ struct ApiDoc;
 pub async fn run() {
     dotenvy::dotenv().ok();
 
-    let settings = config::Settings::new().expect("Failed to load configuration");
+    let settings = match config::Settings::new() {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
 
+    let default_directives = format!(
+        "marinvpn_server={},tower_http=info",
+        settings.server.log_level
+    );
+    // An unparsable RUST_LOG falls back to server.log_level.
+    let (filter_layer, log_filter) = std::env::var(tracing_subscriber::EnvFilter::DEFAULT_ENV)
+        .ok()
+        .and_then(|directives| log_filter::LogFilter::new(&directives).ok())
+        .or_else(|| log_filter::LogFilter::new(&default_directives).ok())
+        .unwrap_or_else(|| {
+            eprintln!("Invalid configuration: server.log_level is not a valid log level");
+            std::process::exit(1);
+        });
     tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
-                format!(
-                    "marinvpn_server={},tower_http=info",
-                    settings.server.log_level
-                )
-                .into()
-            }),
-        )
+        .with(filter_layer)
         .with(tracing_subscriber::fmt::layer())
         .init();
+    let _ = log_filter::LOG_FILTER.set(log_filter);
 
+    let peer_pool = services::peer_pool::PeerPool::from_settings(&settings.vpn)
+        .expect("vpn.peer_cidr was validated at startup");
     let db = services::db::Database::new(&settings.database.url, &settings.auth.account_salt)
         .await
-        .expect("Failed to initialize database");
+        .expect("Failed to initialize database")
+        .with_peer_pool(peer_pool);
     let vpn_iface = std::env::var("WG_INTERFACE").unwrap_or_else(|_| "marinvpn0".to_string());
     let vpn_orchestrator = services::vpn::VpnOrchestrator::new(vpn_iface);
     let signer = services::auth::BlindSigner::new();
     let support_key = services::auth::SupportKey::new();
 
+    let runtime = Arc::new(runtime::RuntimeConfig::new(
+        runtime::RuntimeSettings::from_server(&settings.server),
+    ));
+
     let state = Arc::new(AppState {
         db,
         settings: settings.clone(),
         vpn: vpn_orchestrator,
         signer,
         support_key,
+        runtime: runtime.clone(),
     });
 
     {
@@ -147,6 +198,7 @@ pub async fn run() {
 
     #[cfg(unix)]
     {
+        let runtime = runtime.clone();
         tokio::spawn(async move {
             use tokio::signal::unix::{signal, SignalKind};
             if let Ok(mut hup) = signal(SignalKind::hangup()) {
@@ -157,18 +209,26 @@ pub async fn run() {
                         *guard = new_cfg;
                         tracing::info!("Reloaded admin guard configuration from env");
                     }
+                    let next = runtime.current().reload_from_env();
+                    tracing::info!(?next, "Reloaded runtime settings from env");
+                    runtime.apply(next);
                 }
             }
         });
     }
 
     let cleanup_state = state.clone();
+    let cleanup_runtime = runtime.clone();
     tokio::spawn(async move {
-        let mut interval = tokio::time::interval(Duration::from_secs(3600));
         loop {
-            interval.tick().await;
+            // Re-read every round so a reload takes effect after the current wait.
+            let current = cleanup_runtime.current();
             tracing::info!("Starting periodic cleanup of stale VPN sessions...");
-            match cleanup_state.db.cleanup_stale_sessions(86400).await {
+            match cleanup_state
+                .db
+                .cleanup_stale_sessions(current.session_max_age_secs as i64)
+                .await
+            {
                 Ok(stale_keys) => {
                     for key in stale_keys {
                         let _ = cleanup_state.vpn.remove_peer(&key).await;
@@ -176,17 +236,23 @@ pub async fn run() {
                 }
                 Err(e) => tracing::error!("Failed to cleanup stale sessions: {}", e),
             }
+            cleanup_runtime.retain_recent();
+            tokio::time::sleep(current.cleanup_interval()).await;
         }
     });
 
+    #[cfg(unix)]
+    if let Some(path) = settings.server.admin_socket.clone() {
+        let app = admin_socket::routes().with_state(state.clone());
+        tokio::spawn(async move {
+            tracing::info!("Admin socket listening on {}", path);
+            if let Err(e) = admin_socket::serve(std::path::Path::new(&path), app).await {
+                tracing::error!("Admin socket {} stopped: {}", path, e);
+            }
+        });
+    }
+
     let (prometheus_layer, metric_handle) = PrometheusMetricLayer::pair();
-    let governor_config = Arc::new(
-        GovernorConfigBuilder::default()
-            .per_second(6)
-            .burst_size(10)
-            .finish()
-            .unwrap(),
-    );
 
     let app = Router::new()
         .merge(SwaggerUi::new("/swagger-ui").url("/api-docs/openapi.json", ApiDoc::openapi()))
@@ -200,9 +266,14 @@ pub async fn run() {
         )
         .nest("/api/v1", api_routes())
         .layer(prometheus_layer)
-        .layer(GovernorLayer {
-            config: governor_config,
-        })
+        .layer(axum::middleware::from_fn_with_state(
+            runtime.clone(),
+            runtime::enforce_maintenance,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            runtime.clone(),
+            runtime::enforce_rate_limit,
+        ))
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(|request: &axum::http::Request<_>| {
@@ -231,14 +302,16 @@ pub async fn run() {
             state.clone(),
             verify_client_attestation,
         ))
-        .layer(
-            CorsLayer::new()
-                .allow_origin(Any)
-                .allow_methods(Any)
-                .allow_headers(Any),
-        )
+        .layer(axum::middleware::from_fn_with_state(
+            runtime.clone(),
+            runtime::enforce_min_client_version,
+        ))
+        .layer(runtime::cors_layer(runtime.clone()))
         .layer(CompressionLayer::new())
-        .layer(TimeoutLayer::new(Duration::from_secs(30)))
+        .layer(axum::middleware::from_fn_with_state(
+            runtime,
+            runtime::enforce_timeout,
+        ))
         .with_state(state);
 
     let addr = format!("{}:{}", settings.server.host, settings.server.port);
@@ -267,6 +340,17 @@ pub fn api_routes() -> Router<Arc<AppState>> {
             "/account/devices/remove",
             post(handlers::auth::remove_device),
         )
+        .route("/account/sessions", get(handlers::auth::get_sessions))
+        .route("/account/logout", post(handlers::auth::logout))
+        .route("/account/panic", post(handlers::auth::panic_account))
+        .route(
+            "/account/transfer-token",
+            post(handlers::auth::issue_transfer_token),
+        )
+        .route(
+            "/account/transfer-token/claim",
+            post(handlers::auth::claim_transfer_token),
+        )
         .route("/auth/blind-key", get(handlers::auth::get_blind_public_key))
         .route(
             "/auth/support-key",
@@ -280,28 +364,58 @@ pub fn api_routes() -> Router<Arc<AppState>> {
             "/vpn/config-anonymous",
             post(handlers::vpn::get_anonymous_config),
         )
+        .route("/vpn/pow-challenge", get(handlers::vpn::get_pow_challenge))
         .route("/vpn/report", post(handlers::vpn::report_problem))
         .route("/vpn/panic", post(handlers::vpn::trigger_panic))
         .route("/canary", get(handlers::vpn::get_canary))
+        .route("/version", get(handlers::vpn::get_version))
+        .route("/admin/log-level", post(handlers::admin::set_log_level))
 }
 
 async fn health_check() -> &'static str {
     "OK"
 }
 
-async fn verify_client_attestation(
+/// Wipes every account's sessions, so it is an operator action rather than a
+/// client one.
+const GLOBAL_PANIC_PATH: &str = "/api/v1/vpn/panic";
+
+/// Operator endpoints, all behind the admin token.
+const ADMIN_PATH_PREFIX: &str = "/api/v1/admin/";
+
+/// Open to anyone, without attestation or a token, so they stay cheap and
+/// can be asked before the client has a device key.
+const PUBLIC_PATHS: &[&str] = &["/health", "/api/v1/version"];
+
+/// Paths operators call with the admin token rather than the app.
+fn is_admin_path(path: &str) -> bool {
+    path == "/metrics"
+        || path == GLOBAL_PANIC_PATH
+        || path.starts_with(ADMIN_PATH_PREFIX)
+        || path.starts_with("/swagger-ui")
+        || path.starts_with("/api-docs")
+}
+
+/// Whether `path` is an API endpoint the app calls with device attestation.
+pub(crate) fn is_app_path(path: &str) -> bool {
+    path.starts_with("/api/") && !PUBLIC_PATHS.contains(&path) && !is_admin_path(path)
+}
+
+/// Checks the device attestation on every API request. Admin paths use the
+/// admin token instead and [`PUBLIC_PATHS`] are always open.
+pub async fn verify_client_attestation(
     State(state): State<Arc<AppState>>,
     req: axum::extract::Request,
     next: axum::middleware::Next,
 ) -> Result<axum::response::Response, error::AppError> {
     let (req_parts, body) = req.into_parts();
     let path = req_parts.uri.path();
-    if path == "/health" {
+    if PUBLIC_PATHS.contains(&path) {
         let req = axum::extract::Request::from_parts(req_parts, body);
         return Ok(next.run(req).await);
     }
 
-    if path == "/metrics" || path.starts_with("/swagger-ui") || path.starts_with("/api-docs") {
+    if is_admin_path(path) {
         let (admin_token, allowlist, trusted_proxy_hops, trusted_proxy_cidrs) = {
             let guard = ADMIN_GUARD.read().expect("admin guard lock poisoned");
             (
@@ -355,65 +469,15 @@ async fn verify_client_attestation(
     let body_bytes = to_bytes(body, state.settings.server.max_body_bytes)
         .await
         .map_err(|_| error::AppError::Unauthorized)?;
-    let body_hash = {
-        use sha2::Digest;
-        hex::encode(sha2::Sha256::digest(&body_bytes))
-    };
-
-    let attestation = req_parts
-        .headers
-        .get("X-Marin-Attestation")
-        .and_then(|h| h.to_str().ok())
-        .ok_or(error::AppError::Unauthorized)?;
-    let provided_body_hash = req_parts
-        .headers
-        .get("X-Marin-Attestation-Body")
-        .and_then(|h| h.to_str().ok())
-        .map(|s| s.to_string());
-    let provided_pubkey = req_parts
-        .headers
-        .get("X-Marin-Attestation-Pub")
-        .and_then(|h| h.to_str().ok())
-        .map(|s| s.to_string());
-
-    let att_parts: Vec<&str> = attestation.split(':').collect();
-    if att_parts.len() != 3 {
-        tracing::warn!(
-            "Blocked request with invalid attestation format from {}",
-            path
-        );
-        return Err(error::AppError::Unauthorized);
-    }
-
-    let timestamp_str = att_parts[0];
-    let nonce = att_parts[1];
-    let provided_sig = att_parts[2];
-
-    let timestamp = timestamp_str
-        .parse::<i64>()
-        .map_err(|_| error::AppError::Unauthorized)?;
-    let now = chrono::Utc::now().timestamp();
-
-    if (now - timestamp).abs() > 60 {
-        tracing::warn!(
-            "Blocked request with expired attestation (diff: {}s) to {}",
-            now - timestamp,
-            path
-        );
-        return Err(error::AppError::Unauthorized);
-    }
-
-    if is_production() && provided_body_hash.is_none() {
-        tracing::warn!("Blocked request missing attestation body hash to {}", path);
-        return Err(error::AppError::Unauthorized);
-    }
-
-    if let Some(ref provided) = provided_body_hash {
-        if provided != &body_hash {
-            tracing::warn!("Blocked request with body hash mismatch to {}", path);
-            return Err(error::AppError::Unauthorized);
-        }
-    }
+    let attestation = attestation::Attestation::from_request(
+        &req_parts.headers,
+        &body_bytes,
+        path,
+        chrono::Utc::now().timestamp(),
+        is_production(),
+    )?;
+    let nonce = attestation.nonce.as_str();
+    let provided_pubkey = attestation.provided_pubkey.clone();
 
     if state.db.is_attestation_id_used(nonce).await? {
         tracing::warn!(
@@ -432,8 +496,25 @@ async fn verify_client_attestation(
     {
         if let Some(token) = auth_header.strip_prefix("Bearer ") {
             if let Ok(claims) =
-                crate::services::auth::decode_access_token(token, &state.settings.auth.jwt_secret)
+                crate::services::auth::TokenIssuer::from_settings(&state.settings.auth)
+                    .decode_access_token(token)
             {
+                // Checked before the lookup so a token stays tied to the key
+                // it was minted for even when the device row has none.
+                if is_production() && claims.dpk.is_none() {
+                    tracing::warn!("Blocked token with no device key for {}", claims.sub);
+                    return Err(error::AppError::Unauthorized);
+                }
+                if !claims.is_bound_to(provided_pubkey.as_deref()) {
+                    tracing::warn!(
+                        "Blocked token presented with another device's key for {}",
+                        claims.sub
+                    );
+                    return Err(match provided_pubkey {
+                        Some(_) => error::AppError::DeviceKeyMismatch,
+                        None => error::AppError::Unauthorized,
+                    });
+                }
                 device_pubkey = state
                     .db
                     .get_device_pubkey(&claims.sub, &claims.device)
@@ -451,7 +532,7 @@ async fn verify_client_attestation(
                             "Blocked request with mismatched device pubkey for {}",
                             claims.sub
                         );
-                        return Err(error::AppError::Unauthorized);
+                        return Err(error::AppError::DeviceKeyMismatch);
                     }
                 }
             }
@@ -468,42 +549,14 @@ async fn verify_client_attestation(
     }
 
     if let Some(ref pubkey_b64) = device_pubkey {
-        let pubkey_bytes = base64::engine::general_purpose::STANDARD
-            .decode(pubkey_b64)
-            .map_err(|_| error::AppError::Unauthorized)?;
-        let sig_bytes = base64::engine::general_purpose::STANDARD
-            .decode(provided_sig)
-            .map_err(|_| error::AppError::Unauthorized)?;
-
-        let message = format!(
-            "{}:{}:{}:{}:{}",
-            timestamp_str,
-            nonce,
-            req_parts.method.as_str(),
-            path,
-            body_hash
-        );
-
-        let verifier = UnparsedPublicKey::new(&ED25519, pubkey_bytes);
-        if verifier.verify(message.as_bytes(), &sig_bytes).is_err() {
-            tracing::warn!(
-                "Blocked unauthorized client request to {} (Signature mismatch)",
-                path
-            );
-            return Err(error::AppError::Unauthorized);
-        }
+        attestation.verify_signature(req_parts.method.as_str(), path, pubkey_b64)?;
     } else {
         tracing::warn!("Blocked request missing device pubkey to {}", path);
         return Err(error::AppError::Unauthorized);
     }
 
-    if let Err(e) = state.db.mark_attestation_id_used(nonce).await {
-        if let error::AppError::Database(sqlx::Error::Database(db_err)) = &e {
-            if db_err.is_unique_violation() {
-                return Err(error::AppError::Unauthorized);
-            }
-        }
-        return Err(e);
+    if !state.db.claim_attestation_id(nonce).await? {
+        return Err(error::AppError::Unauthorized);
     }
 
     let req = axum::extract::Request::from_parts(req_parts, Body::from(body_bytes));
@@ -569,6 +622,15 @@ fn extract_client_ip(
     peer_ip
 }
 
+/// Client address as seen through the configured trusted proxies.
+pub(crate) fn client_ip(req_parts: &axum::http::request::Parts) -> Option<IpAddr> {
+    let (hops, cidrs) = {
+        let guard = ADMIN_GUARD.read().expect("admin guard lock poisoned");
+        (guard.trusted_proxy_hops, guard.trusted_proxy_cidrs.clone())
+    };
+    extract_client_ip(req_parts, hops, &cidrs)
+}
+
 fn is_ip_in_cidrs(ip: IpAddr, cidrs: &[String]) -> bool {
     if cidrs.is_empty() {
         return false;
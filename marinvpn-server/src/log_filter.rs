@@ -0,0 +1,57 @@
+use once_cell::sync::OnceCell;
+use std::sync::RwLock;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+pub type FilterLayer = reload::Layer<EnvFilter, Registry>;
+
+/// The process-wide filter, set once by `run` when the subscriber is built.
+pub static LOG_FILTER: OnceCell<LogFilter> = OnceCell::new();
+
+/// The tracing filter, swappable at runtime so an operator can turn on debug
+/// logging for one module during an incident and turn it off again without a
+/// restart. The directive string is kept alongside the filter because
+/// `EnvFilter` cannot be turned back into the text it was parsed from.
+pub struct LogFilter {
+    handle: reload::Handle<EnvFilter, Registry>,
+    directives: RwLock<String>,
+}
+
+impl LogFilter {
+    /// Returns the layer to install on the registry and the handle that
+    /// controls it.
+    pub fn new(directives: &str) -> Result<(FilterLayer, Self), String> {
+        let (layer, handle) = reload::Layer::new(parse(directives)?);
+        let filter = Self {
+            handle,
+            directives: RwLock::new(directives.to_string()),
+        };
+        Ok((layer, filter))
+    }
+
+    pub fn current(&self) -> String {
+        self.directives
+            .read()
+            .expect("log filter lock poisoned")
+            .clone()
+    }
+
+    /// Replaces the filter and returns the directives it replaced. An invalid
+    /// string leaves the current filter untouched.
+    pub fn set(&self, directives: &str) -> Result<String, String> {
+        let filter = parse(directives)?;
+        let mut current = self.directives.write().expect("log filter lock poisoned");
+        self.handle
+            .reload(filter)
+            .map_err(|e| format!("failed to apply log filter: {}", e))?;
+        Ok(std::mem::replace(&mut *current, directives.to_string()))
+    }
+}
+
+fn parse(directives: &str) -> Result<EnvFilter, String> {
+    if directives.trim().is_empty() {
+        return Err("log directives must not be empty".to_string());
+    }
+    EnvFilter::builder()
+        .parse(directives)
+        .map_err(|e| format!("invalid log directives: {}", e))
+}
@@ -41,6 +41,8 @@ pub struct VpnServer {
     pub is_active: bool,
     pub current_load: i64,
     pub avg_latency: i64,
+    pub obfuscation_port: Option<i32>,
+    pub obfuscation_cipher: Option<String>,
 }
 
 impl VpnServer {
@@ -0,0 +1,461 @@
+use axum::{
+    extract::{Request, State},
+    http::{HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use governor::{
+    clock::{Clock, DefaultClock},
+    middleware::{StateInformationMiddleware, StateSnapshot},
+    DefaultDirectRateLimiter, DefaultKeyedRateLimiter, Quota,
+};
+use std::net::{IpAddr, Ipv4Addr};
+use std::num::NonZeroU32;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+
+use crate::config::ServerSettings;
+use crate::deadline::Deadline;
+use crate::error::AppError;
+use marinvpn_common::version::{Version, CLIENT_VERSION_HEADER};
+
+/// The subset of server settings that can change on SIGHUP. Everything else
+/// (bind address, database, secrets) still needs a restart.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RuntimeSettings {
+    pub rate_limit_replenish_secs: u64,
+    pub rate_limit_burst: u32,
+    pub maintenance_mode: bool,
+    pub cors_origins: Vec<String>,
+    pub request_timeout_secs: u64,
+    pub cleanup_interval_secs: u64,
+    pub session_max_age_secs: u64,
+    pub generate_ip_replenish_secs: u64,
+    pub generate_ip_burst: u32,
+    pub generate_global_replenish_secs: u64,
+    pub generate_global_burst: u32,
+    pub min_client_version: Version,
+}
+
+impl RuntimeSettings {
+    pub fn from_server(server: &ServerSettings) -> Self {
+        Self {
+            rate_limit_replenish_secs: server.rate_limit_replenish_secs,
+            rate_limit_burst: server.rate_limit_burst,
+            maintenance_mode: server.maintenance_mode,
+            cors_origins: server.cors_origins.clone(),
+            request_timeout_secs: server.request_timeout_secs,
+            cleanup_interval_secs: server.cleanup_interval_secs,
+            session_max_age_secs: server.session_max_age_secs,
+            generate_ip_replenish_secs: server.generate_ip_replenish_secs,
+            generate_ip_burst: server.generate_ip_burst,
+            generate_global_replenish_secs: server.generate_global_replenish_secs,
+            generate_global_burst: server.generate_global_burst,
+            // Checked by `Settings::validate`.
+            min_client_version: Version::parse(&server.min_client_version).unwrap_or_default(),
+        }
+    }
+
+    /// Re-reads the `APP__SERVER__*` variables for the reloadable fields.
+    /// Unset or unparsable values keep their current setting so a typo in one
+    /// variable does not reset the others to defaults.
+    pub fn reload_from_env(&self) -> Self {
+        Self {
+            rate_limit_replenish_secs: env_positive(
+                "APP__SERVER__RATE_LIMIT_REPLENISH_SECS",
+                self.rate_limit_replenish_secs,
+            ),
+            rate_limit_burst: env_positive("APP__SERVER__RATE_LIMIT_BURST", self.rate_limit_burst),
+            maintenance_mode: env_value("APP__SERVER__MAINTENANCE_MODE", self.maintenance_mode),
+            cors_origins: match std::env::var("APP__SERVER__CORS_ORIGINS") {
+                Ok(raw) => raw
+                    .split(',')
+                    .map(|s| s.trim())
+                    .filter(|s| !s.is_empty())
+                    .map(|s| s.to_string())
+                    .collect(),
+                Err(_) => self.cors_origins.clone(),
+            },
+            request_timeout_secs: env_positive(
+                "APP__SERVER__REQUEST_TIMEOUT_SECS",
+                self.request_timeout_secs,
+            ),
+            cleanup_interval_secs: env_positive(
+                "APP__SERVER__CLEANUP_INTERVAL_SECS",
+                self.cleanup_interval_secs,
+            ),
+            session_max_age_secs: env_positive(
+                "APP__SERVER__SESSION_MAX_AGE_SECS",
+                self.session_max_age_secs,
+            ),
+            generate_ip_replenish_secs: env_positive(
+                "APP__SERVER__GENERATE_IP_REPLENISH_SECS",
+                self.generate_ip_replenish_secs,
+            ),
+            generate_ip_burst: env_positive(
+                "APP__SERVER__GENERATE_IP_BURST",
+                self.generate_ip_burst,
+            ),
+            generate_global_replenish_secs: env_positive(
+                "APP__SERVER__GENERATE_GLOBAL_REPLENISH_SECS",
+                self.generate_global_replenish_secs,
+            ),
+            generate_global_burst: env_positive(
+                "APP__SERVER__GENERATE_GLOBAL_BURST",
+                self.generate_global_burst,
+            ),
+            min_client_version: std::env::var("APP__SERVER__MIN_CLIENT_VERSION")
+                .ok()
+                .and_then(|raw| Version::parse(&raw))
+                .unwrap_or(self.min_client_version),
+        }
+    }
+
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.request_timeout_secs)
+    }
+
+    pub fn cleanup_interval(&self) -> Duration {
+        Duration::from_secs(self.cleanup_interval_secs)
+    }
+
+    /// Whether a client reporting `version` is still supported. One that
+    /// reports nothing, or nothing readable, predates the header.
+    pub fn admits_client(&self, version: Option<&str>) -> bool {
+        version.and_then(Version::parse).unwrap_or_default() >= self.min_client_version
+    }
+
+    /// An empty list keeps the historical allow-any behaviour.
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.cors_origins.is_empty() || self.cors_origins.iter().any(|o| o == origin)
+    }
+
+    fn quota(&self) -> Quota {
+        quota(self.rate_limit_replenish_secs, self.rate_limit_burst)
+    }
+
+    fn generate_ip_quota(&self) -> Quota {
+        quota(self.generate_ip_replenish_secs, self.generate_ip_burst)
+    }
+
+    fn generate_global_quota(&self) -> Quota {
+        quota(
+            self.generate_global_replenish_secs,
+            self.generate_global_burst,
+        )
+    }
+}
+
+fn quota(replenish_secs: u64, burst: u32) -> Quota {
+    let period = Duration::from_secs(replenish_secs.max(1));
+    let burst = NonZeroU32::new(burst).unwrap_or(NonZeroU32::MIN);
+    Quota::with_period(period)
+        .expect("rate limit period is non-zero")
+        .allow_burst(burst)
+}
+
+fn env_value<T: FromStr>(key: &str, current: T) -> T {
+    match std::env::var(key) {
+        Ok(raw) => match raw.trim().parse::<T>() {
+            Ok(value) => value,
+            Err(_) => {
+                tracing::warn!("Ignoring unparsable {} on reload", key);
+                current
+            }
+        },
+        Err(_) => current,
+    }
+}
+
+fn env_positive<T: FromStr + PartialEq + Default + Copy>(key: &str, current: T) -> T {
+    let value = env_value(key, current);
+    if value == T::default() {
+        tracing::warn!("Ignoring zero {} on reload", key);
+        return current;
+    }
+    value
+}
+
+/// Which account generation budget ran out. The per-IP budget is checked
+/// first so one looping client is stopped before it eats the shared pool.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GenerationThrottle {
+    PerIp(Duration),
+    Global(Duration),
+}
+
+/// What is left of a client's request budget after an allowed request,
+/// sent back as `X-RateLimit-Limit` and `X-RateLimit-Remaining`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateBudget {
+    pub limit: u32,
+    pub remaining: u32,
+}
+
+impl RateBudget {
+    fn from_snapshot(snapshot: StateSnapshot) -> Self {
+        Self {
+            limit: snapshot.quota().burst_size().get(),
+            remaining: snapshot.remaining_burst_capacity(),
+        }
+    }
+}
+
+/// A request over its budget: the burst size, and how long until the next
+/// request is allowed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimited {
+    pub limit: u32,
+    pub wait: Duration,
+}
+
+type ApiRateLimiter = DefaultKeyedRateLimiter<IpAddr, StateInformationMiddleware>;
+
+fn api_limiter(quota: Quota) -> Arc<ApiRateLimiter> {
+    Arc::new(DefaultKeyedRateLimiter::keyed(quota).with_middleware::<StateInformationMiddleware>())
+}
+
+struct Limiters {
+    api: Arc<ApiRateLimiter>,
+    generate_ip: Arc<DefaultKeyedRateLimiter<IpAddr>>,
+    generate_global: Arc<DefaultDirectRateLimiter>,
+}
+
+/// Shared, swappable view of [`RuntimeSettings`] plus the limiters built from
+/// them. Readers take a cheap `Arc` snapshot so a reload never blocks a
+/// request for longer than the pointer swap.
+pub struct RuntimeConfig {
+    settings: RwLock<Arc<RuntimeSettings>>,
+    limiters: RwLock<Limiters>,
+    /// Kept apart from the settings so a `SIGHUP` reload does not undo it.
+    draining: AtomicBool,
+}
+
+impl RuntimeConfig {
+    pub fn new(settings: RuntimeSettings) -> Self {
+        let limiters = Limiters {
+            api: api_limiter(settings.quota()),
+            generate_ip: Arc::new(DefaultKeyedRateLimiter::keyed(settings.generate_ip_quota())),
+            generate_global: Arc::new(DefaultDirectRateLimiter::direct(
+                settings.generate_global_quota(),
+            )),
+        };
+        Self {
+            settings: RwLock::new(Arc::new(settings)),
+            limiters: RwLock::new(limiters),
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether new tunnels are being turned away so the server can be taken
+    /// out of rotation.
+    pub fn draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Starts or stops draining and returns whether it was on before.
+    pub fn set_draining(&self, enabled: bool) -> bool {
+        self.draining.swap(enabled, Ordering::Relaxed)
+    }
+
+    pub fn current(&self) -> Arc<RuntimeSettings> {
+        self.settings
+            .read()
+            .expect("runtime config lock poisoned")
+            .clone()
+    }
+
+    /// Swaps in new settings. A limiter is only rebuilt when its quota
+    /// changed; doing so forgets existing per-IP state, which is acceptable
+    /// for an operator-initiated change.
+    pub fn apply(&self, next: RuntimeSettings) {
+        let previous = self.current();
+        {
+            let mut limiters = self.limiters.write().expect("rate limiter lock poisoned");
+            if previous.quota() != next.quota() {
+                limiters.api = api_limiter(next.quota());
+            }
+            if previous.generate_ip_quota() != next.generate_ip_quota() {
+                limiters.generate_ip =
+                    Arc::new(DefaultKeyedRateLimiter::keyed(next.generate_ip_quota()));
+            }
+            if previous.generate_global_quota() != next.generate_global_quota() {
+                limiters.generate_global = Arc::new(DefaultDirectRateLimiter::direct(
+                    next.generate_global_quota(),
+                ));
+            }
+        }
+        *self.settings.write().expect("runtime config lock poisoned") = Arc::new(next);
+    }
+
+    /// Takes one request from the budget of `ip`, returning what is left of
+    /// it, or how long the caller has to wait when it is used up.
+    pub fn check_rate_limit(&self, ip: IpAddr) -> Result<RateBudget, RateLimited> {
+        let limiter = self
+            .limiters
+            .read()
+            .expect("rate limiter lock poisoned")
+            .api
+            .clone();
+        limiter
+            .check_key(&ip)
+            .map(RateBudget::from_snapshot)
+            .map_err(|not_until| RateLimited {
+                limit: not_until.quota().burst_size().get(),
+                wait: wait_time(not_until),
+            })
+    }
+
+    /// Stricter budget for `/account/generate`, on top of [`Self::check_rate_limit`].
+    pub fn check_generation(&self, ip: IpAddr) -> Result<(), GenerationThrottle> {
+        let (per_ip, global) = {
+            let limiters = self.limiters.read().expect("rate limiter lock poisoned");
+            (
+                limiters.generate_ip.clone(),
+                limiters.generate_global.clone(),
+            )
+        };
+        per_ip
+            .check_key(&ip)
+            .map_err(|n| GenerationThrottle::PerIp(wait_time(n)))?;
+        global
+            .check()
+            .map_err(|n| GenerationThrottle::Global(wait_time(n)))
+    }
+
+    /// Drops limiter state for clients that are back to a full bucket.
+    pub fn retain_recent(&self) {
+        let (api, generate_ip) = {
+            let limiters = self.limiters.read().expect("rate limiter lock poisoned");
+            (limiters.api.clone(), limiters.generate_ip.clone())
+        };
+        api.retain_recent();
+        api.shrink_to_fit();
+        generate_ip.retain_recent();
+        generate_ip.shrink_to_fit();
+    }
+}
+
+fn wait_time(not_until: governor::NotUntil<governor::clock::QuantaInstant>) -> Duration {
+    not_until.wait_time_from(DefaultClock::default().now())
+}
+
+/// Whole seconds for a `Retry-After` header, rounded up so clients never
+/// retry a moment too early.
+pub fn retry_after_secs(wait: Duration) -> u64 {
+    wait.as_secs() + u64::from(wait.subsec_nanos() > 0)
+}
+
+pub const RATE_LIMIT_LIMIT: &str = "x-ratelimit-limit";
+pub const RATE_LIMIT_REMAINING: &str = "x-ratelimit-remaining";
+
+/// Applies the per-IP request budget. Every response says how large the
+/// budget is and how much of it is left; a throttled one also carries
+/// `Retry-After`.
+pub async fn enforce_rate_limit(
+    State(runtime): State<Arc<RuntimeConfig>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let (parts, body) = req.into_parts();
+    // Without a peer address every such request shares one bucket rather
+    // than bypassing the limit.
+    let ip = crate::client_ip(&parts).unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+
+    let (mut response, budget) = match runtime.check_rate_limit(ip) {
+        Ok(budget) => (next.run(Request::from_parts(parts, body)).await, budget),
+        Err(limited) => {
+            let response = AppError::TooManyRequests {
+                retry_after_secs: retry_after_secs(limited.wait),
+            }
+            .into_response();
+            let budget = RateBudget {
+                limit: limited.limit,
+                remaining: 0,
+            };
+            (response, budget)
+        }
+    };
+    let headers = response.headers_mut();
+    headers.insert(RATE_LIMIT_LIMIT, HeaderValue::from(budget.limit));
+    headers.insert(RATE_LIMIT_REMAINING, HeaderValue::from(budget.remaining));
+    response
+}
+
+/// Endpoints that hand out a new peer. These are the ones refused while the
+/// server drains; peers already handed out keep working.
+const NEW_TUNNEL_PATHS: &[&str] = &["/api/v1/vpn/config", "/api/v1/vpn/config-anonymous"];
+
+/// Turns away app requests during maintenance, and requests for a new
+/// tunnel while draining. Public and operator paths stay open, so the
+/// version can still be asked and an operator can turn maintenance off
+/// again over HTTP.
+pub async fn enforce_maintenance(
+    State(runtime): State<Arc<RuntimeConfig>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let path = req.uri().path();
+    if runtime.current().maintenance_mode && crate::is_app_path(path) {
+        return AppError::ServiceBusy("Service is under maintenance".to_string()).into_response();
+    }
+    if runtime.draining() && NEW_TUNNEL_PATHS.contains(&path) {
+        return AppError::ServiceBusy("Server is draining".to_string()).into_response();
+    }
+    next.run(req).await
+}
+
+/// Turns away app requests from clients below the minimum version. Public
+/// and operator paths stay open, so an outdated app can still learn the
+/// minimum from `/api/v1/version`.
+pub async fn enforce_min_client_version(
+    State(runtime): State<Arc<RuntimeConfig>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let settings = runtime.current();
+    if crate::is_app_path(req.uri().path()) {
+        let version = req
+            .headers()
+            .get(CLIENT_VERSION_HEADER)
+            .and_then(|v| v.to_str().ok());
+        if !settings.admits_client(version) {
+            return AppError::ClientTooOld {
+                min_version: settings.min_client_version.to_string(),
+            }
+            .into_response();
+        }
+    }
+    next.run(req).await
+}
+
+/// Equivalent of `TimeoutLayer`, but the deadline is read per request. The
+/// handler sees a slightly earlier [`Deadline`] so it can stop on its own.
+pub async fn enforce_timeout(
+    State(runtime): State<Arc<RuntimeConfig>>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let timeout = runtime.current().request_timeout();
+    let deadline = Deadline::for_request(timeout, req.headers());
+    req.extensions_mut().insert(deadline);
+    match tokio::time::timeout(timeout, next.run(req)).await {
+        Ok(response) => response,
+        Err(_) => StatusCode::REQUEST_TIMEOUT.into_response(),
+    }
+}
+
+pub fn cors_layer(runtime: Arc<RuntimeConfig>) -> CorsLayer {
+    CorsLayer::new()
+        .allow_origin(AllowOrigin::predicate(move |origin: &HeaderValue, _| {
+            origin
+                .to_str()
+                .map(|o| runtime.current().allows_origin(o))
+                .unwrap_or(false)
+        }))
+        .allow_methods(Any)
+        .allow_headers(Any)
+}
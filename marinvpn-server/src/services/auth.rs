@@ -1,8 +1,10 @@
+use crate::config::AuthSettings;
 use crate::error::{AppError, AppResult};
 use base64::Engine;
 use blake2::{Blake2s, Digest};
 use chrono::{Duration, Utc};
 use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rand::Rng;
 use rsa::{
     pkcs8::{DecodePrivateKey, EncodePrivateKey, EncodePublicKey, LineEnding},
     traits::{PrivateKeyParts, PublicKeyParts},
@@ -11,13 +13,45 @@ use rsa::{
 use serde::{Deserialize, Serialize};
 use std::fs;
 use std::path::{Path, PathBuf};
+use subtle::ConstantTimeEq;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
     pub exp: usize,
+    pub iss: String,
+    pub aud: String,
     pub device: String,
     pub kind: String,
+    /// Random per token, so two sessions minted in the same second for the
+    /// same device never share a refresh token hash.
+    #[serde(default)]
+    pub jti: String,
+    /// Hash of the attestation pubkey of the device the token was minted
+    /// for. `None` only for devices that never registered a key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dpk: Option<String>,
+}
+
+impl Claims {
+    /// Whether a request signed with `pubkey_b64` may use this token.
+    pub fn is_bound_to(&self, pubkey_b64: Option<&str>) -> bool {
+        match (&self.dpk, pubkey_b64) {
+            (Some(dpk), Some(pubkey)) => dpk
+                .as_bytes()
+                .ct_eq(device_key_hash(pubkey).as_bytes())
+                .into(),
+            (Some(_), None) => false,
+            (None, _) => true,
+        }
+    }
+}
+
+pub fn device_key_hash(pubkey_b64: &str) -> String {
+    let mut hasher = Blake2s::new();
+    hasher.update(b"MARIN_VPN_DEVICE_KEY_V1");
+    hasher.update(pubkey_b64.as_bytes());
+    hex::encode(hasher.finalize())
 }
 
 pub struct BlindSigner {
@@ -192,80 +226,132 @@ fn write_private_key(path: &Path, data: &[u8]) -> std::io::Result<()> {
     }
 }
 
-pub fn create_token(account_number: &str, device: &str, secret: &str) -> AppResult<String> {
-    let expiration = Utc::now()
-        .checked_add_signed(Duration::minutes(15))
-        .ok_or_else(|| AppError::Internal(anyhow::anyhow!("Invalid timestamp")))?
-        .timestamp();
-
-    create_token_with_exp(account_number, device, secret, expiration, "access")
+/// Mints and checks the API's access and refresh tokens.
+pub struct TokenIssuer {
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    validation: Validation,
+    issuer: String,
+    audience: String,
+    access_ttl_secs: u64,
+    refresh_ttl_secs: u64,
 }
 
-pub fn create_refresh_token(
-    account_number: &str,
-    device: &str,
-    secret: &str,
-) -> AppResult<(String, i64)> {
-    let expiration = Utc::now()
-        .checked_add_signed(Duration::days(30))
-        .ok_or_else(|| AppError::Internal(anyhow::anyhow!("Invalid timestamp")))?
-        .timestamp();
-
-    let token = create_token_with_exp(account_number, device, secret, expiration, "refresh")?;
-    Ok((token, expiration))
-}
+impl TokenIssuer {
+    pub fn new(
+        secret: &str,
+        issuer: &str,
+        audience: &str,
+        access_ttl_secs: u64,
+        refresh_ttl_secs: u64,
+    ) -> Self {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_issuer(&[issuer]);
+        validation.set_audience(&[audience]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+        Self {
+            encoding_key: EncodingKey::from_secret(secret.as_bytes()),
+            decoding_key: DecodingKey::from_secret(secret.as_bytes()),
+            validation,
+            issuer: issuer.to_string(),
+            audience: audience.to_string(),
+            access_ttl_secs,
+            refresh_ttl_secs,
+        }
+    }
 
-fn create_token_with_exp(
-    account_number: &str,
-    device: &str,
-    secret: &str,
-    exp: i64,
-    kind: &str,
-) -> AppResult<String> {
-    let normalized: String = account_number
-        .chars()
-        .filter(|c| !c.is_whitespace())
-        .collect::<String>()
-        .to_uppercase();
-
-    let claims = Claims {
-        sub: normalized,
-        exp: exp as usize,
-        device: device.to_string(),
-        kind: kind.to_string(),
-    };
-
-    let header = Header::new(Algorithm::HS256);
-    encode(
-        &header,
-        &claims,
-        &EncodingKey::from_secret(secret.as_bytes()),
-    )
-    .map_err(|_| AppError::Internal(anyhow::anyhow!("Failed to create token")))
-}
+    pub fn from_settings(auth: &AuthSettings) -> Self {
+        Self::new(
+            &auth.jwt_secret,
+            &auth.jwt_issuer,
+            &auth.jwt_audience,
+            auth.access_token_ttl_secs,
+            auth.refresh_token_ttl_secs,
+        )
+    }
 
-pub fn decode_token(token: &str, secret: &str) -> AppResult<Claims> {
-    decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(secret.as_bytes()),
-        &Validation::new(Algorithm::HS256),
-    )
-    .map(|data| data.claims)
-    .map_err(|_| AppError::Unauthorized)
-}
+    /// `device_pubkey` is the device's base64 attestation key, which the
+    /// token is then bound to.
+    pub fn access_token(
+        &self,
+        account_number: &str,
+        device: &str,
+        device_pubkey: Option<&str>,
+    ) -> AppResult<String> {
+        let expiration = expires_in(self.access_ttl_secs)?;
+        self.sign(account_number, device, device_pubkey, expiration, "access")
+    }
 
-pub fn decode_access_token(token: &str, secret: &str) -> AppResult<Claims> {
-    let claims = decode_token(token, secret)?;
-    if claims.kind != "access" {
-        return Err(AppError::Unauthorized);
+    /// Returns the token together with its expiry, which the caller stores
+    /// on the refresh token row.
+    pub fn refresh_token(
+        &self,
+        account_number: &str,
+        device: &str,
+        device_pubkey: Option<&str>,
+    ) -> AppResult<(String, i64)> {
+        let expiration = expires_in(self.refresh_ttl_secs)?;
+        let token = self.sign(account_number, device, device_pubkey, expiration, "refresh")?;
+        Ok((token, expiration))
     }
-    Ok(claims)
-}
 
-pub fn decode_refresh_token(token: &str, secret: &str) -> AppResult<Claims> {
-    let claims = decode_token(token, secret)?;
-    if claims.kind != "refresh" {
-        return Err(AppError::Unauthorized);
+    fn sign(
+        &self,
+        account_number: &str,
+        device: &str,
+        device_pubkey: Option<&str>,
+        exp: i64,
+        kind: &str,
+    ) -> AppResult<String> {
+        let normalized: String = account_number
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect::<String>()
+            .to_uppercase();
+
+        let claims = Claims {
+            sub: normalized,
+            exp: exp as usize,
+            iss: self.issuer.clone(),
+            aud: self.audience.clone(),
+            device: device.to_string(),
+            kind: kind.to_string(),
+            jti: hex::encode(rand::thread_rng().gen::<[u8; 16]>()),
+            dpk: device_pubkey.map(device_key_hash),
+        };
+
+        encode(&Header::new(Algorithm::HS256), &claims, &self.encoding_key)
+            .map_err(|_| AppError::Internal(anyhow::anyhow!("Failed to create token")))
     }
-    Ok(claims)
+
+    pub fn decode(&self, token: &str) -> AppResult<Claims> {
+        decode::<Claims>(token, &self.decoding_key, &self.validation)
+            .map(|data| data.claims)
+            .map_err(|_| AppError::Unauthorized)
+    }
+
+    pub fn decode_access_token(&self, token: &str) -> AppResult<Claims> {
+        let claims = self.decode(token)?;
+        if claims.kind != "access" {
+            return Err(AppError::Unauthorized);
+        }
+        Ok(claims)
+    }
+
+    pub fn decode_refresh_token(&self, token: &str) -> AppResult<Claims> {
+        let claims = self.decode(token)?;
+        if claims.kind != "refresh" {
+            return Err(AppError::Unauthorized);
+        }
+        Ok(claims)
+    }
+}
+
+fn expires_in(ttl_secs: u64) -> AppResult<i64> {
+    i64::try_from(ttl_secs)
+        .ok()
+        .and_then(Duration::try_seconds)
+        .and_then(|ttl| Utc::now().checked_add_signed(ttl))
+        .map(|at| at.timestamp())
+        .ok_or_else(|| AppError::Internal(anyhow::anyhow!("Invalid timestamp")))
 }
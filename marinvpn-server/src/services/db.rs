@@ -1,19 +1,64 @@
+use crate::deadline::Deadline;
 use crate::error::{AppError, AppResult};
 use crate::models::{Account, Device, VpnServer};
+use crate::services::peer_pool::PeerPool;
 use argon2::{
     password_hash::{PasswordHasher, SaltString},
     Algorithm, Argon2, Params, Version,
 };
 use blake2::{Blake2s, Digest};
 use chrono::{TimeZone, Utc};
-use rand::Rng;
-use sqlx::{postgres::PgPoolOptions, Error, PgPool};
+use sqlx::{postgres::PgPoolOptions, Acquire, Error, PgPool, Postgres, Transaction};
 use tracing::info;
 
+/// Who a peer row was issued to. Authenticated configs belong to an account;
+/// anonymous configs belong to the single-use blind token that paid for them,
+/// so two anonymous issuances are never linked to each other.
+#[derive(Clone, Copy, Debug)]
+pub enum PeerOwner<'a> {
+    Account(&'a str),
+    Anonymous(&'a str),
+}
+
+/// Opaque tag stored on the peer row. It is keyed with the server salt and
+/// bound to the public key, so tags from the same account never repeat across
+/// rows and the table cannot be grouped by owner.
+pub fn peer_owner_tag(salt: &str, pub_key: &str, owner: PeerOwner<'_>) -> String {
+    let mut hasher = Blake2s::new();
+    hasher.update(b"MARIN_VPN_PEER_OWNER_V1");
+    hasher.update(salt.as_bytes());
+    hasher.update(pub_key.as_bytes());
+    match owner {
+        PeerOwner::Account(account_number) => {
+            let normalized: String = account_number
+                .chars()
+                .filter(|c| !c.is_whitespace())
+                .collect::<String>()
+                .to_uppercase();
+            hasher.update(b"account:");
+            hasher.update(normalized.as_bytes());
+        }
+        PeerOwner::Anonymous(token) => {
+            hasher.update(b"anonymous:");
+            hasher.update(token.as_bytes());
+        }
+    }
+    hex::encode(hasher.finalize())
+}
+
+/// Result of [`Database::panic_wipe`], carrying the time of the wipe in effect.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PanicWipe {
+    Wiped(i64),
+    /// An earlier wipe is still inside the cooldown; nothing was done.
+    Skipped(i64),
+}
+
 #[derive(Clone)]
 pub struct Database {
     pool: PgPool,
     salt: String,
+    peer_pool: PeerPool,
 }
 
 impl Database {
@@ -110,22 +155,24 @@ impl Database {
         Ok(Self {
             pool,
             salt: salt.to_string(),
+            peer_pool: PeerPool::default(),
         })
     }
 
-    fn map_id_to_ip(id: i64) -> String {
-        const POOL_SIZE: u32 = 16_580_608;
-        let id_wrapped = (id as u32).wrapping_rem(POOL_SIZE);
-
-        let z = (id_wrapped % 253) + 2; // 2 to 254
-        let y = (id_wrapped / 253) % 256; // 0 to 255
-        let x = (id_wrapped / (253 * 256)) % 256; // 0 to 255
+    pub fn with_peer_pool(mut self, peer_pool: PeerPool) -> Self {
+        self.peer_pool = peer_pool;
+        self
+    }
 
-        format!("10.{}.{}.{}/32", x, y, z)
+    pub(crate) fn hash_refresh_token(token: &str) -> String {
+        let mut hasher = Blake2s::new();
+        hasher.update(token.as_bytes());
+        hex::encode(hasher.finalize())
     }
 
-    fn hash_refresh_token(token: &str) -> String {
+    pub(crate) fn hash_transfer_token(token: &str) -> String {
         let mut hasher = Blake2s::new();
+        hasher.update(b"MARIN_VPN_TRANSFER_ID_V1");
         hasher.update(token.as_bytes());
         hex::encode(hasher.finalize())
     }
@@ -134,23 +181,24 @@ impl Database {
         let cutoff = Utc::now().timestamp() - max_age_secs;
         let now = Utc::now().timestamp();
 
-        let stale_peers: Vec<(String,)> =
-            sqlx::query_as("SELECT pub_key FROM peers WHERE registered_at < $1")
-                .bind(cutoff)
-                .fetch_all(&self.pool)
-                .await?;
+        let mut tx = self.pool.begin().await?;
+        let stale_peers: Vec<(String, Option<String>)> = sqlx::query_as(
+            "DELETE FROM peers WHERE registered_at < $1 RETURNING pub_key, assigned_ip",
+        )
+        .bind(cutoff)
+        .fetch_all(&mut *tx)
+        .await?;
 
-        let pub_keys: Vec<String> = stale_peers.into_iter().map(|(pk,)| pk).collect();
+        let (pub_keys, freed_ips): (Vec<String>, Vec<Option<String>>) =
+            stale_peers.into_iter().unzip();
+        Self::reclaim_ips(&mut tx, freed_ips.into_iter().flatten().collect(), now).await?;
+        tx.commit().await?;
 
         if !pub_keys.is_empty() {
             info!(
-                "Cleaning up {} stale VPN sessions from shared session store",
+                "Cleaned up {} stale VPN sessions from shared session store",
                 pub_keys.len()
             );
-            sqlx::query("DELETE FROM peers WHERE registered_at < $1")
-                .bind(cutoff)
-                .execute(&self.pool)
-                .await?;
         }
 
         sqlx::query("DELETE FROM used_tokens WHERE used_at < $1")
@@ -168,9 +216,50 @@ impl Database {
             .execute(&self.pool)
             .await?;
 
+        sqlx::query("DELETE FROM transfer_tokens WHERE expires_at < $1")
+            .bind(now)
+            .execute(&self.pool)
+            .await?;
+
         Ok(pub_keys)
     }
 
+    /// Removes a single peer and returns its IP to the free list.
+    pub async fn release_peer(&self, pub_key: &str) -> AppResult<bool> {
+        let mut tx = self.pool.begin().await?;
+        let released: Option<Option<String>> =
+            sqlx::query_scalar("DELETE FROM peers WHERE pub_key = $1 RETURNING assigned_ip")
+                .bind(pub_key)
+                .fetch_optional(&mut *tx)
+                .await?;
+
+        let Some(ip) = released else {
+            tx.rollback().await?;
+            return Ok(false);
+        };
+        Self::reclaim_ips(&mut tx, ip.into_iter().collect(), Utc::now().timestamp()).await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn reclaim_ips(
+        tx: &mut Transaction<'_, Postgres>,
+        ips: Vec<String>,
+        now: i64,
+    ) -> AppResult<()> {
+        if ips.is_empty() {
+            return Ok(());
+        }
+        sqlx::query(
+            "INSERT INTO free_ips (ip, freed_at) SELECT ip, $2 FROM UNNEST($1::TEXT[]) AS ip ON CONFLICT (ip) DO NOTHING",
+        )
+        .bind(ips)
+        .bind(now)
+        .execute(&mut **tx)
+        .await?;
+        Ok(())
+    }
+
     pub async fn is_attestation_id_used(&self, id: &str) -> AppResult<bool> {
         let row: Option<(String,)> = sqlx::query_as("SELECT id FROM attestation_ids WHERE id = $1")
             .bind(id)
@@ -179,14 +268,17 @@ impl Database {
         Ok(row.is_some())
     }
 
-    pub async fn mark_attestation_id_used(&self, id: &str) -> AppResult<()> {
+    /// Records `id` as used. `false` when another request got there first.
+    pub async fn claim_attestation_id(&self, id: &str) -> AppResult<bool> {
         let now = Utc::now().timestamp();
-        sqlx::query("INSERT INTO attestation_ids (id, used_at) VALUES ($1, $2)")
-            .bind(id)
-            .bind(now)
-            .execute(&self.pool)
-            .await?;
-        Ok(())
+        let res = sqlx::query(
+            "INSERT INTO attestation_ids (id, used_at) VALUES ($1, $2) ON CONFLICT (id) DO NOTHING",
+        )
+        .bind(id)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
     }
 
     pub async fn is_token_used(&self, message: &str) -> AppResult<bool> {
@@ -198,14 +290,17 @@ impl Database {
         Ok(row.is_some())
     }
 
-    pub async fn mark_token_used(&self, message: &str) -> AppResult<()> {
+    /// Records `message` as redeemed. `false` when it already was.
+    pub async fn claim_token(&self, message: &str) -> AppResult<bool> {
         let now = Utc::now().timestamp();
-        sqlx::query("INSERT INTO used_tokens (message, used_at) VALUES ($1, $2)")
-            .bind(message)
-            .bind(now)
-            .execute(&self.pool)
-            .await?;
-        Ok(())
+        let res = sqlx::query(
+            "INSERT INTO used_tokens (message, used_at) VALUES ($1, $2) ON CONFLICT (message) DO NOTHING",
+        )
+        .bind(message)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
     }
 
     pub async fn create_account(
@@ -322,30 +417,79 @@ impl Database {
         }))
     }
 
-    pub async fn upsert_refresh_token(
+    /// Stores `refresh_token` as a new session for the device unless it
+    /// already has `max_sessions` unexpired ones, in which case nothing is
+    /// written and `false` is returned. The device row is locked while
+    /// counting so concurrent logins cannot both take the last slot.
+    pub async fn create_session(
         &self,
         account_id: &str,
         device_name: &str,
         refresh_token: &str,
         expires_at: i64,
-    ) -> AppResult<()> {
+        max_sessions: u64,
+    ) -> AppResult<bool> {
         let now = Utc::now().timestamp();
         let hashed_account = self.resolve_account_pk(account_id).await?;
         let token_hash = Self::hash_refresh_token(refresh_token);
 
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("SELECT 1 FROM devices WHERE account_id = $1 AND name = $2 FOR UPDATE")
+            .bind(&hashed_account)
+            .bind(device_name)
+            .fetch_optional(&mut *tx)
+            .await?;
+        sqlx::query(
+            "DELETE FROM refresh_tokens WHERE account_id = $1 AND device_name = $2 AND expires_at < $3",
+        )
+        .bind(&hashed_account)
+        .bind(device_name)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+        let active: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM refresh_tokens WHERE account_id = $1 AND device_name = $2",
+        )
+        .bind(&hashed_account)
+        .bind(device_name)
+        .fetch_one(&mut *tx)
+        .await?;
+        if active as u64 >= max_sessions {
+            tx.rollback().await?;
+            return Ok(false);
+        }
+
         sqlx::query(
             "INSERT INTO refresh_tokens (account_id, device_name, token_hash, issued_at, expires_at) \
-             VALUES ($1, $2, $3, $4, $5) \
-             ON CONFLICT (account_id, device_name) DO UPDATE SET token_hash = $3, issued_at = $4, expires_at = $5",
+             VALUES ($1, $2, $3, $4, $5)",
         )
         .bind(&hashed_account)
         .bind(device_name)
         .bind(token_hash)
         .bind(now)
         .bind(expires_at)
-        .execute(&self.pool)
+        .execute(&mut *tx)
         .await?;
-        Ok(())
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    pub async fn count_active_sessions(
+        &self,
+        account_id: &str,
+        device_name: &str,
+    ) -> AppResult<u64> {
+        let hashed_account = self.resolve_account_pk(account_id).await?;
+        let active: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM refresh_tokens \
+             WHERE account_id = $1 AND device_name = $2 AND expires_at >= $3",
+        )
+        .bind(&hashed_account)
+        .bind(device_name)
+        .bind(Utc::now().timestamp())
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(active as u64)
     }
 
     pub async fn rotate_refresh_token(
@@ -387,36 +531,68 @@ impl Database {
     ) -> AppResult<bool> {
         let hashed_account = self.resolve_account_pk(account_id).await?;
         let token_hash = Self::hash_refresh_token(refresh_token);
-        let row: Option<(i64,)> = sqlx::query_as(
-            "SELECT expires_at FROM refresh_tokens WHERE account_id = $1 AND device_name = $2 AND token_hash = $3",
+        // `expires_at` is an INTEGER column, so it is compared in SQL rather
+        // than decoded.
+        let valid: bool = sqlx::query_scalar(
+            "SELECT EXISTS (SELECT 1 FROM refresh_tokens WHERE account_id = $1 AND device_name = $2 AND token_hash = $3 AND expires_at >= $4)",
         )
         .bind(&hashed_account)
         .bind(device_name)
         .bind(token_hash)
-        .fetch_optional(&self.pool)
+        .bind(Utc::now().timestamp())
+        .fetch_one(&self.pool)
         .await?;
-
-        if let Some((expires_at,)) = row {
-            Ok(expires_at >= Utc::now().timestamp())
-        } else {
-            Ok(false)
-        }
+        Ok(valid)
     }
 
+    /// Returns how many refresh tokens were deleted.
     pub async fn revoke_refresh_tokens(
         &self,
         account_id: &str,
         device_name: &str,
-    ) -> AppResult<()> {
+    ) -> AppResult<u64> {
         let hashed_account = self.resolve_account_pk(account_id).await?;
-        sqlx::query("DELETE FROM refresh_tokens WHERE account_id = $1 AND device_name = $2")
-            .bind(&hashed_account)
-            .bind(device_name)
-            .execute(&self.pool)
-            .await?;
+        let res =
+            sqlx::query("DELETE FROM refresh_tokens WHERE account_id = $1 AND device_name = $2")
+                .bind(&hashed_account)
+                .bind(device_name)
+                .execute(&self.pool)
+                .await?;
+        Ok(res.rows_affected())
+    }
+
+    pub async fn create_transfer_token(
+        &self,
+        token: &str,
+        sealed_account: &str,
+        expires_at: i64,
+    ) -> AppResult<()> {
+        sqlx::query(
+            "INSERT INTO transfer_tokens (token_hash, sealed_account, expires_at) VALUES ($1, $2, $3)",
+        )
+        .bind(Self::hash_transfer_token(token))
+        .bind(sealed_account)
+        .bind(expires_at)
+        .execute(&self.pool)
+        .await?;
         Ok(())
     }
 
+    /// Deleting the row is what spends the token, so of two concurrent
+    /// claims only one gets it back.
+    pub async fn take_transfer_token(&self, token: &str) -> AppResult<Option<(String, i64)>> {
+        let now = Utc::now().timestamp();
+        let taken: Option<(String, i64)> = sqlx::query_as(
+            "DELETE FROM transfer_tokens WHERE token_hash = $1 AND expires_at >= $2 \
+             RETURNING sealed_account, expires_at",
+        )
+        .bind(Self::hash_transfer_token(token))
+        .bind(now)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(taken)
+    }
+
     pub async fn get_devices(&self, account_id: &str) -> AppResult<Vec<Device>> {
         let hashed = self.resolve_account_pk(account_id).await?;
         let rows: Vec<(String, i64, Option<String>)> = sqlx::query_as(
@@ -517,27 +693,55 @@ impl Database {
         )
     }
 
-    pub async fn get_or_create_peer(&self, pub_key: &str) -> AppResult<String> {
+    /// Returns the tunnel IP for `pub_key`, allocating one on first use. A key
+    /// is only ever shared with the owner that registered it; the same key
+    /// from anyone else is rejected rather than handed the existing IP.
+    ///
+    /// An allocation still running at `deadline` is abandoned and its
+    /// transaction rolled back, so a request that times out never leaves a
+    /// peer row behind without the client having received its address.
+    pub async fn get_or_create_peer(
+        &self,
+        pub_key: &str,
+        owner: PeerOwner<'_>,
+        deadline: Deadline,
+    ) -> AppResult<String> {
+        deadline
+            .run(
+                "peer allocation",
+                self.allocate_peer(pub_key, owner, deadline),
+            )
+            .await
+    }
+
+    async fn allocate_peer(
+        &self,
+        pub_key: &str,
+        owner: PeerOwner<'_>,
+        deadline: Deadline,
+    ) -> AppResult<String> {
+        let owner_tag = peer_owner_tag(&self.salt, pub_key, owner);
         let mut tx = self.pool.begin().await?;
 
-        let existing: Option<(String,)> =
-            sqlx::query_as("SELECT assigned_ip FROM peers WHERE pub_key = $1")
+        let existing: Option<(Option<String>, Option<String>)> =
+            sqlx::query_as("SELECT assigned_ip, owner_tag FROM peers WHERE pub_key = $1")
                 .bind(pub_key)
                 .fetch_optional(&mut *tx)
                 .await?;
 
-        if let Some((ip,)) = existing {
+        if let Some(existing) = existing {
             tx.commit().await?;
-            return Ok(ip);
+            return Self::claim_existing_peer(existing, &owner_tag);
         }
 
         let now = Utc::now().timestamp();
 
         let insert_result = sqlx::query_scalar::<_, i64>(
-            "INSERT INTO peers (pub_key, registered_at) VALUES ($1, $2) RETURNING id",
+            "INSERT INTO peers (pub_key, registered_at, owner_tag) VALUES ($1, $2, $3) RETURNING id",
         )
         .bind(pub_key)
         .bind(now)
+        .bind(&owner_tag)
         .fetch_one(&mut *tx)
         .await;
 
@@ -545,12 +749,12 @@ impl Database {
             Ok(id) => id,
             Err(Error::Database(db_err)) if db_err.is_unique_violation() => {
                 tx.rollback().await?;
-                let existing: (String,) =
-                    sqlx::query_as("SELECT assigned_ip FROM peers WHERE pub_key = $1")
+                let existing: (Option<String>, Option<String>) =
+                    sqlx::query_as("SELECT assigned_ip, owner_tag FROM peers WHERE pub_key = $1")
                         .bind(pub_key)
                         .fetch_one(&self.pool)
                         .await?;
-                return Ok(existing.0);
+                return Self::claim_existing_peer(existing, &owner_tag);
             }
             Err(e) => {
                 tx.rollback().await?;
@@ -558,66 +762,169 @@ impl Database {
             }
         };
 
-        let mut allocated = false;
-        let mut assigned_ip = String::new();
+        // Reclaimed addresses are handed out oldest-freed first, so an IP that
+        // was just released is not immediately visible under a new key.
+        let mut reclaimed: Option<String> = sqlx::query_scalar(
+            "DELETE FROM free_ips WHERE ip = (SELECT ip FROM free_ips ORDER BY freed_at, ip LIMIT 1 FOR UPDATE SKIP LOCKED) RETURNING ip",
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let mut assigned_ip = None;
 
-        for offset in 0..10 {
-            let candidate_id = row_id.wrapping_add(offset);
-            let candidate_ip = Self::map_id_to_ip(candidate_id);
+        for _ in 0..10 {
+            let candidate_ip = match reclaimed.take() {
+                Some(ip) => ip,
+                None => {
+                    let index: i64 = sqlx::query_scalar("SELECT nextval('peer_ip_seq')")
+                        .fetch_one(&mut *tx)
+                        .await?;
+                    self.peer_pool.address(index)
+                }
+            };
 
+            let mut attempt = (&mut *tx).begin().await?;
             match sqlx::query("UPDATE peers SET assigned_ip = $1 WHERE id = $2")
                 .bind(&candidate_ip)
                 .bind(row_id)
-                .execute(&mut *tx)
+                .execute(&mut *attempt)
                 .await
             {
                 Ok(_) => {
-                    allocated = true;
-                    assigned_ip = candidate_ip;
-                    if offset > 0 {
-                        info!(
-                            "Allocated IP {} with offset {} due to collision",
-                            assigned_ip, offset
-                        );
-                    } else {
-                        info!("Allocating anonymous IP {} for public key", assigned_ip);
-                    }
+                    attempt.commit().await?;
+                    info!("Allocating anonymous IP {} for public key", candidate_ip);
+                    assigned_ip = Some(candidate_ip);
                     break;
                 }
                 Err(Error::Database(db_err)) if db_err.is_unique_violation() => {
+                    attempt.rollback().await?;
                     tracing::warn!("IP collision for {}, retrying...", candidate_ip);
                     continue;
                 }
                 Err(e) => {
+                    attempt.rollback().await?;
                     tx.rollback().await?;
                     return Err(e.into());
                 }
             }
         }
 
-        if !allocated {
+        let Some(assigned_ip) = assigned_ip else {
             tx.rollback().await?;
-            return Err(AppError::BadRequest(
+            return Err(AppError::ServiceBusy(
                 "Failed to allocate IP address: Pool saturated or high collision rate".to_string(),
             ));
-        }
+        };
 
+        if let Err(e) = deadline.check("peer commit") {
+            tx.rollback().await?;
+            return Err(e);
+        }
         tx.commit().await?;
         Ok(assigned_ip)
     }
 
-    pub async fn panic_wipe(&self) -> AppResult<()> {
-        info!("CRITICAL: Panic wipe triggered. Clearing all ephemeral session data.");
-        sqlx::query("DELETE FROM peers").execute(&self.pool).await?;
-        sqlx::query("DELETE FROM used_tokens")
-            .execute(&self.pool)
+    pub(crate) fn claim_existing_peer(
+        (assigned_ip, existing_owner): (Option<String>, Option<String>),
+        owner_tag: &str,
+    ) -> AppResult<String> {
+        use subtle::ConstantTimeEq;
+        let same_owner = existing_owner
+            .as_deref()
+            .is_some_and(|tag| tag.as_bytes().ct_eq(owner_tag.as_bytes()).into());
+        match assigned_ip {
+            Some(ip) if same_owner => Ok(ip),
+            _ => {
+                tracing::warn!("Rejected WireGuard public key already registered elsewhere");
+                Err(AppError::PubKeyInUse)
+            }
+        }
+    }
+
+    /// Clears all ephemeral session data unless a wipe already ran within
+    /// `cooldown_secs` of `now`. The audit table is locked for the whole
+    /// transaction, so two concurrent requests cannot both wipe.
+    pub async fn panic_wipe(&self, now: i64, cooldown_secs: i64) -> AppResult<PanicWipe> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("LOCK TABLE panic_audit IN EXCLUSIVE MODE")
+            .execute(&mut *tx)
             .await?;
-        sqlx::query("DELETE FROM attestation_ids")
-            .execute(&self.pool)
+        let last: Option<i64> = sqlx::query_scalar("SELECT MAX(wiped_at) FROM panic_audit")
+            .fetch_one(&mut *tx)
             .await?;
-        sqlx::query("DELETE FROM refresh_tokens")
-            .execute(&self.pool)
+        if let Some(at) = last.filter(|at| now - at < cooldown_secs) {
+            tx.rollback().await?;
+            return Ok(PanicWipe::Skipped(at));
+        }
+
+        info!("CRITICAL: Panic wipe triggered. Clearing all ephemeral session data.");
+        for statement in [
+            "DELETE FROM peers",
+            "DELETE FROM free_ips",
+            "ALTER SEQUENCE peer_ip_seq RESTART",
+            "DELETE FROM used_tokens",
+            "DELETE FROM attestation_ids",
+            "DELETE FROM refresh_tokens",
+        ] {
+            sqlx::query(statement).execute(&mut *tx).await?;
+        }
+        sqlx::query("INSERT INTO panic_audit (wiped_at) VALUES ($1)")
+            .bind(now)
+            .execute(&mut *tx)
             .await?;
-        Ok(())
+        tx.commit().await?;
+        Ok(PanicWipe::Wiped(now))
+    }
+
+    /// Removes one account's devices, refresh tokens and peers, leaving the
+    /// account itself and everyone else alone. Peer rows only carry a keyed
+    /// per-key owner tag, so the account's peers are found by recomputing the
+    /// tag for every owned row. Returns the removed device count and the
+    /// public keys to drop from the interface.
+    pub async fn panic_wipe_account(&self, account_number: &str) -> AppResult<(u64, Vec<String>)> {
+        let hashed = self.resolve_account_pk(account_number).await?;
+        let owned: Vec<(String, String)> =
+            sqlx::query_as("SELECT pub_key, owner_tag FROM peers WHERE owner_tag IS NOT NULL")
+                .fetch_all(&self.pool)
+                .await?;
+        let pub_keys: Vec<String> = owned
+            .into_iter()
+            .filter(|(pub_key, tag)| {
+                *tag == peer_owner_tag(&self.salt, pub_key, PeerOwner::Account(account_number))
+            })
+            .map(|(pub_key, _)| pub_key)
+            .collect();
+
+        let mut tx = self.pool.begin().await?;
+        let devices = sqlx::query("DELETE FROM devices WHERE account_id = $1")
+            .bind(&hashed)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        sqlx::query("DELETE FROM refresh_tokens WHERE account_id = $1")
+            .bind(&hashed)
+            .execute(&mut *tx)
+            .await?;
+        let removed: Vec<(String, Option<String>)> = sqlx::query_as(
+            "DELETE FROM peers WHERE pub_key = ANY($1) RETURNING pub_key, assigned_ip",
+        )
+        .bind(&pub_keys)
+        .fetch_all(&mut *tx)
+        .await?;
+        let (pub_keys, freed_ips): (Vec<String>, Vec<Option<String>>) = removed.into_iter().unzip();
+        Self::reclaim_ips(
+            &mut tx,
+            freed_ips.into_iter().flatten().collect(),
+            Utc::now().timestamp(),
+        )
+        .await?;
+        tx.commit().await?;
+
+        info!(
+            "Account panic wipe removed {} devices and {} peers",
+            devices,
+            pub_keys.len()
+        );
+        Ok((devices, pub_keys))
     }
 }
@@ -0,0 +1,306 @@
+use async_trait::async_trait;
+use chrono::Utc;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+
+use crate::deadline::Deadline;
+use crate::error::{AppError, AppResult};
+use crate::services::db::{peer_owner_tag, Database, PeerOwner};
+use crate::services::peer_pool::PeerPool;
+use crate::services::store::{PeerStore, ReplayStore, SessionStore, TransferStore};
+
+/// Candidate addresses tried per allocation, as in [`Database`].
+const ALLOCATION_ATTEMPTS: usize = 10;
+
+/// An in-process stand-in for [`Database`] that implements the storage
+/// traits with the same rules, so they can be tested without Postgres.
+/// Every call holds one lock for its whole duration, which plays the part of
+/// the transactions and row locks the Postgres implementation relies on.
+pub struct MemoryStore {
+    salt: String,
+    peer_pool: PeerPool,
+    state: Mutex<MemoryState>,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    /// Public key to `(assigned_ip, owner_tag)`.
+    peers: HashMap<String, (String, String)>,
+    assigned_ips: HashSet<String>,
+    /// Released addresses, oldest first.
+    free_ips: VecDeque<String>,
+    /// Like `peer_ip_seq`, never rolled back.
+    next_index: i64,
+    sessions: Vec<Session>,
+    attestation_ids: HashSet<String>,
+    used_tokens: HashSet<String>,
+    /// Transfer token hash to `(sealed_account, expires_at)`.
+    transfer_tokens: HashMap<String, (String, i64)>,
+}
+
+struct Session {
+    account: String,
+    device_name: String,
+    token_hash: String,
+    expires_at: i64,
+}
+
+impl MemoryStore {
+    pub fn new(salt: &str) -> Self {
+        Self {
+            salt: salt.to_string(),
+            peer_pool: PeerPool::default(),
+            state: Mutex::new(MemoryState::default()),
+        }
+    }
+
+    pub fn with_peer_pool(mut self, peer_pool: PeerPool) -> Self {
+        self.peer_pool = peer_pool;
+        self
+    }
+
+    /// Adds a peer holding `ip` without going through allocation, the way
+    /// rows from an older allocator look, so tests can set up collisions.
+    pub fn insert_peer(&self, pub_key: &str, ip: &str, owner: PeerOwner<'_>) {
+        let tag = peer_owner_tag(&self.salt, pub_key, owner);
+        let mut state = self.lock();
+        state.assigned_ips.insert(ip.to_string());
+        state
+            .peers
+            .insert(pub_key.to_string(), (ip.to_string(), tag));
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MemoryState> {
+        self.state.lock().expect("memory store lock poisoned")
+    }
+}
+
+/// Accounts are keyed as typed, without the hashing `Database` applies.
+fn account_key(account_number: &str) -> String {
+    account_number
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_uppercase()
+}
+
+#[async_trait]
+impl PeerStore for MemoryStore {
+    async fn get_or_create_peer(
+        &self,
+        pub_key: &str,
+        owner: PeerOwner<'_>,
+        deadline: Deadline,
+    ) -> AppResult<String> {
+        deadline.check("peer allocation")?;
+        let owner_tag = peer_owner_tag(&self.salt, pub_key, owner);
+        let mut state = self.lock();
+
+        if let Some((ip, tag)) = state.peers.get(pub_key) {
+            return Database::claim_existing_peer(
+                (Some(ip.clone()), Some(tag.clone())),
+                &owner_tag,
+            );
+        }
+
+        // The free-list head is only removed once an address sticks, which
+        // is what rolling back the Postgres transaction amounts to.
+        let mut reclaimed = state.free_ips.front().cloned();
+        let used_free_list = reclaimed.is_some();
+        let mut assigned = None;
+        for _ in 0..ALLOCATION_ATTEMPTS {
+            let candidate = match reclaimed.take() {
+                Some(ip) => ip,
+                None => {
+                    let index = state.next_index;
+                    state.next_index += 1;
+                    self.peer_pool.address(index)
+                }
+            };
+            if !state.assigned_ips.contains(&candidate) {
+                assigned = Some(candidate);
+                break;
+            }
+        }
+
+        let Some(ip) = assigned else {
+            return Err(AppError::ServiceBusy(
+                "Failed to allocate IP address: Pool saturated or high collision rate".to_string(),
+            ));
+        };
+        deadline.check("peer commit")?;
+        if used_free_list {
+            state.free_ips.pop_front();
+        }
+        state.assigned_ips.insert(ip.clone());
+        state
+            .peers
+            .insert(pub_key.to_string(), (ip.clone(), owner_tag));
+        Ok(ip)
+    }
+
+    async fn release_peer(&self, pub_key: &str) -> AppResult<bool> {
+        let mut state = self.lock();
+        let Some((ip, _)) = state.peers.remove(pub_key) else {
+            return Ok(false);
+        };
+        state.assigned_ips.remove(&ip);
+        if !state.free_ips.contains(&ip) {
+            state.free_ips.push_back(ip);
+        }
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl SessionStore for MemoryStore {
+    async fn create_session(
+        &self,
+        account_id: &str,
+        device_name: &str,
+        refresh_token: &str,
+        expires_at: i64,
+        max_sessions: u64,
+    ) -> AppResult<bool> {
+        let now = Utc::now().timestamp();
+        let account = account_key(account_id);
+        let mut state = self.lock();
+        state.sessions.retain(|s| {
+            !(s.account == account && s.device_name == device_name && s.expires_at < now)
+        });
+        let active = state
+            .sessions
+            .iter()
+            .filter(|s| s.account == account && s.device_name == device_name)
+            .count();
+        if active as u64 >= max_sessions {
+            return Ok(false);
+        }
+        state.sessions.push(Session {
+            account,
+            device_name: device_name.to_string(),
+            token_hash: Database::hash_refresh_token(refresh_token),
+            expires_at,
+        });
+        Ok(true)
+    }
+
+    async fn count_active_sessions(&self, account_id: &str, device_name: &str) -> AppResult<u64> {
+        let now = Utc::now().timestamp();
+        let account = account_key(account_id);
+        let state = self.lock();
+        Ok(state
+            .sessions
+            .iter()
+            .filter(|s| s.account == account && s.device_name == device_name)
+            .filter(|s| s.expires_at >= now)
+            .count() as u64)
+    }
+
+    async fn rotate_refresh_token(
+        &self,
+        account_id: &str,
+        device_name: &str,
+        old_token: &str,
+        new_token: &str,
+        new_expires_at: i64,
+    ) -> AppResult<bool> {
+        let now = Utc::now().timestamp();
+        let account = account_key(account_id);
+        let old_hash = Database::hash_refresh_token(old_token);
+        let mut state = self.lock();
+        let session = state.sessions.iter_mut().find(|s| {
+            s.account == account
+                && s.device_name == device_name
+                && s.token_hash == old_hash
+                && s.expires_at >= now
+        });
+        match session {
+            Some(session) => {
+                session.token_hash = Database::hash_refresh_token(new_token);
+                session.expires_at = new_expires_at;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn validate_refresh_token(
+        &self,
+        account_id: &str,
+        device_name: &str,
+        refresh_token: &str,
+    ) -> AppResult<bool> {
+        let now = Utc::now().timestamp();
+        let account = account_key(account_id);
+        let token_hash = Database::hash_refresh_token(refresh_token);
+        let state = self.lock();
+        Ok(state.sessions.iter().any(|s| {
+            s.account == account
+                && s.device_name == device_name
+                && s.token_hash == token_hash
+                && s.expires_at >= now
+        }))
+    }
+
+    async fn revoke_refresh_tokens(&self, account_id: &str, device_name: &str) -> AppResult<u64> {
+        let account = account_key(account_id);
+        let mut state = self.lock();
+        let before = state.sessions.len();
+        state
+            .sessions
+            .retain(|s| !(s.account == account && s.device_name == device_name));
+        Ok((before - state.sessions.len()) as u64)
+    }
+}
+
+#[async_trait]
+impl ReplayStore for MemoryStore {
+    async fn is_attestation_id_used(&self, id: &str) -> AppResult<bool> {
+        Ok(self.lock().attestation_ids.contains(id))
+    }
+
+    async fn claim_attestation_id(&self, id: &str) -> AppResult<bool> {
+        Ok(self.lock().attestation_ids.insert(id.to_string()))
+    }
+
+    async fn is_token_used(&self, message: &str) -> AppResult<bool> {
+        Ok(self.lock().used_tokens.contains(message))
+    }
+
+    async fn claim_token(&self, message: &str) -> AppResult<bool> {
+        Ok(self.lock().used_tokens.insert(message.to_string()))
+    }
+}
+
+#[async_trait]
+impl TransferStore for MemoryStore {
+    async fn create_transfer_token(
+        &self,
+        token: &str,
+        sealed_account: &str,
+        expires_at: i64,
+    ) -> AppResult<()> {
+        self.lock().transfer_tokens.insert(
+            Database::hash_transfer_token(token),
+            (sealed_account.to_string(), expires_at),
+        );
+        Ok(())
+    }
+
+    async fn take_transfer_token(&self, token: &str) -> AppResult<Option<(String, i64)>> {
+        let now = Utc::now().timestamp();
+        let mut state = self.lock();
+        let hash = Database::hash_transfer_token(token);
+        // Like the Postgres delete, an expired token is left for cleanup.
+        if state
+            .transfer_tokens
+            .get(&hash)
+            .is_some_and(|(_, expires_at)| *expires_at >= now)
+        {
+            Ok(state.transfer_tokens.remove(&hash))
+        } else {
+            Ok(None)
+        }
+    }
+}
@@ -1,3 +1,8 @@
 pub mod auth;
 pub mod db;
+pub mod memory_store;
+pub mod peer_pool;
+pub mod pow;
+pub mod store;
+pub mod transfer;
 pub mod vpn;
@@ -0,0 +1,71 @@
+use crate::config::VpnSettings;
+use ipnet::Ipv4Net;
+use std::net::Ipv4Addr;
+
+/// Addresses handed to peers inside `vpn.peer_cidr`.
+///
+/// Prefixes of /24 or wider are split into /24 blocks that each skip `.0`,
+/// `.1` (the gateway) and `.255`, which keeps the default `10.0.0.0/8` mapping
+/// identical to what older servers allocated. Narrower prefixes skip only the
+/// network, gateway and broadcast addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerPool {
+    network: Ipv4Net,
+    capacity: u32,
+}
+
+const BLOCK_HOSTS: u32 = 253;
+
+impl PeerPool {
+    pub fn new(cidr: &str, expected_peers: u32) -> Result<Self, String> {
+        let network: Ipv4Net = cidr
+            .trim()
+            .parse()
+            .map_err(|_| format!("is not an IPv4 CIDR: {}", cidr))?;
+        let network = network.trunc();
+
+        let capacity = Self::capacity_of(network.prefix_len());
+        if capacity < expected_peers.max(1) {
+            return Err(format!(
+                "{} only holds {} peers (need {})",
+                network, capacity, expected_peers
+            ));
+        }
+
+        Ok(Self { network, capacity })
+    }
+
+    pub fn from_settings(settings: &VpnSettings) -> Result<Self, String> {
+        Self::new(&settings.peer_cidr, settings.expected_peers)
+    }
+
+    fn capacity_of(prefix_len: u8) -> u32 {
+        if prefix_len <= 24 {
+            (1u32 << (24 - prefix_len)) * BLOCK_HOSTS
+        } else {
+            (1u32 << (32 - prefix_len)).saturating_sub(3)
+        }
+    }
+
+    pub fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    /// Maps a pool index to a `/32` address, wrapping once the pool is full.
+    pub fn address(&self, index: i64) -> String {
+        let index = index.rem_euclid(self.capacity as i64) as u32;
+        let offset = if self.network.prefix_len() <= 24 {
+            (index / BLOCK_HOSTS) * 256 + (index % BLOCK_HOSTS) + 2
+        } else {
+            index + 2
+        };
+        let ip = Ipv4Addr::from(u32::from(self.network.network()) + offset);
+        format!("{}/32", ip)
+    }
+}
+
+impl Default for PeerPool {
+    fn default() -> Self {
+        Self::new("10.0.0.0/8", 1).expect("default peer pool is valid")
+    }
+}
@@ -0,0 +1,84 @@
+use crate::config::AuthSettings;
+use crate::error::{AppError, AppResult};
+use marinvpn_common::pow::meets_difficulty;
+use marinvpn_common::{PowChallenge, PowSolution};
+use rand::Rng;
+use ring::hmac;
+
+/// Issues and checks stateless proof-of-work challenges. A challenge is
+/// `expires_at.salt.mac`, where the MAC proves the server minted it, so no
+/// challenge table is needed. Replays are already covered by the single-use
+/// blind token the solution is bound to.
+pub struct PowVerifier {
+    key: hmac::Key,
+    difficulty: u8,
+    ttl_secs: i64,
+}
+
+impl PowVerifier {
+    pub fn new(secret: &str, difficulty: u8, ttl_secs: u64) -> Self {
+        Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()),
+            difficulty,
+            ttl_secs: ttl_secs as i64,
+        }
+    }
+
+    /// `None` when proof of work is switched off.
+    pub fn from_settings(auth: &AuthSettings) -> Option<Self> {
+        auth.pow_enabled.then(|| {
+            Self::new(
+                &auth.attestation_secret,
+                auth.pow_difficulty,
+                auth.pow_challenge_ttl_secs,
+            )
+        })
+    }
+
+    pub fn issue(&self, now: i64) -> PowChallenge {
+        let expires_at = now + self.ttl_secs;
+        let salt = hex::encode(rand::thread_rng().gen::<[u8; 16]>());
+        let body = format!("{}.{}", expires_at, salt);
+        let mac = hex::encode(hmac::sign(&self.key, body.as_bytes()));
+        PowChallenge {
+            enabled: true,
+            challenge: format!("{}.{}", body, mac),
+            difficulty: self.difficulty,
+            expires_at,
+        }
+    }
+
+    pub fn verify(&self, solution: &PowSolution, token: &str, now: i64) -> AppResult<()> {
+        let invalid = || AppError::BadRequest("Invalid proof of work".to_string());
+
+        let (body, mac_hex) = solution.challenge.rsplit_once('.').ok_or_else(invalid)?;
+        let mac = hex::decode(mac_hex).map_err(|_| invalid())?;
+        hmac::verify(&self.key, body.as_bytes(), &mac).map_err(|_| invalid())?;
+
+        let expires_at = body
+            .split_once('.')
+            .and_then(|(ts, _)| ts.parse::<i64>().ok())
+            .ok_or_else(invalid)?;
+        if expires_at < now {
+            return Err(AppError::BadRequest(
+                "Proof of work challenge expired".to_string(),
+            ));
+        }
+
+        if !meets_difficulty(&solution.challenge, token, solution.nonce, self.difficulty) {
+            return Err(invalid());
+        }
+        Ok(())
+    }
+}
+
+/// Placeholder handed out when the feature is off so clients can skip the
+/// solve step without a second request.
+pub fn disabled_challenge() -> PowChallenge {
+    PowChallenge {
+        enabled: false,
+        challenge: String::new(),
+        difficulty: 0,
+        expires_at: 0,
+    }
+}
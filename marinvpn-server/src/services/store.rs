@@ -0,0 +1,200 @@
+use async_trait::async_trait;
+
+use crate::deadline::Deadline;
+use crate::error::AppResult;
+use crate::services::db::{Database, PeerOwner};
+
+// The parts of the database whose rules are worth testing on their own.
+// `Database` is the Postgres implementation used in production;
+// `MemoryStore` keeps the same rules in process so tests can run without a
+// server. Everything else is only reachable through `Database` directly.
+
+/// Tunnel address allocation.
+#[async_trait]
+pub trait PeerStore: Send + Sync {
+    /// See [`Database::get_or_create_peer`].
+    async fn get_or_create_peer(
+        &self,
+        pub_key: &str,
+        owner: PeerOwner<'_>,
+        deadline: Deadline,
+    ) -> AppResult<String>;
+
+    /// Removes the peer and puts its address on the free list. `false` when
+    /// there was no such peer.
+    async fn release_peer(&self, pub_key: &str) -> AppResult<bool>;
+}
+
+/// Refresh tokens, stored hashed, one row per device session.
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    /// See [`Database::create_session`].
+    async fn create_session(
+        &self,
+        account_id: &str,
+        device_name: &str,
+        refresh_token: &str,
+        expires_at: i64,
+        max_sessions: u64,
+    ) -> AppResult<bool>;
+
+    async fn count_active_sessions(&self, account_id: &str, device_name: &str) -> AppResult<u64>;
+
+    /// Swaps `old_token` for `new_token` if it is an unexpired session of
+    /// this device. A token can be rotated once; `false` afterwards.
+    async fn rotate_refresh_token(
+        &self,
+        account_id: &str,
+        device_name: &str,
+        old_token: &str,
+        new_token: &str,
+        new_expires_at: i64,
+    ) -> AppResult<bool>;
+
+    async fn validate_refresh_token(
+        &self,
+        account_id: &str,
+        device_name: &str,
+        refresh_token: &str,
+    ) -> AppResult<bool>;
+
+    async fn revoke_refresh_tokens(&self, account_id: &str, device_name: &str) -> AppResult<u64>;
+}
+
+/// Single-use values: attestation nonces and redeemed blind tokens.
+#[async_trait]
+pub trait ReplayStore: Send + Sync {
+    async fn is_attestation_id_used(&self, id: &str) -> AppResult<bool>;
+
+    /// Records `id` as used. `false` when it already was, i.e. a replay.
+    async fn claim_attestation_id(&self, id: &str) -> AppResult<bool>;
+
+    async fn is_token_used(&self, message: &str) -> AppResult<bool>;
+
+    /// Records `message` as redeemed. `false` when it already was.
+    async fn claim_token(&self, message: &str) -> AppResult<bool>;
+}
+
+/// Codes that hand an account to a new device, stored hashed.
+#[async_trait]
+pub trait TransferStore: Send + Sync {
+    async fn create_transfer_token(
+        &self,
+        token: &str,
+        sealed_account: &str,
+        expires_at: i64,
+    ) -> AppResult<()>;
+
+    /// Removes an unexpired token and returns `(sealed_account, expires_at)`
+    /// as stored. `None` when it is unknown, already taken or expired.
+    async fn take_transfer_token(&self, token: &str) -> AppResult<Option<(String, i64)>>;
+}
+
+#[async_trait]
+impl PeerStore for Database {
+    async fn get_or_create_peer(
+        &self,
+        pub_key: &str,
+        owner: PeerOwner<'_>,
+        deadline: Deadline,
+    ) -> AppResult<String> {
+        Database::get_or_create_peer(self, pub_key, owner, deadline).await
+    }
+
+    async fn release_peer(&self, pub_key: &str) -> AppResult<bool> {
+        Database::release_peer(self, pub_key).await
+    }
+}
+
+#[async_trait]
+impl SessionStore for Database {
+    async fn create_session(
+        &self,
+        account_id: &str,
+        device_name: &str,
+        refresh_token: &str,
+        expires_at: i64,
+        max_sessions: u64,
+    ) -> AppResult<bool> {
+        Database::create_session(
+            self,
+            account_id,
+            device_name,
+            refresh_token,
+            expires_at,
+            max_sessions,
+        )
+        .await
+    }
+
+    async fn count_active_sessions(&self, account_id: &str, device_name: &str) -> AppResult<u64> {
+        Database::count_active_sessions(self, account_id, device_name).await
+    }
+
+    async fn rotate_refresh_token(
+        &self,
+        account_id: &str,
+        device_name: &str,
+        old_token: &str,
+        new_token: &str,
+        new_expires_at: i64,
+    ) -> AppResult<bool> {
+        Database::rotate_refresh_token(
+            self,
+            account_id,
+            device_name,
+            old_token,
+            new_token,
+            new_expires_at,
+        )
+        .await
+    }
+
+    async fn validate_refresh_token(
+        &self,
+        account_id: &str,
+        device_name: &str,
+        refresh_token: &str,
+    ) -> AppResult<bool> {
+        Database::validate_refresh_token(self, account_id, device_name, refresh_token).await
+    }
+
+    async fn revoke_refresh_tokens(&self, account_id: &str, device_name: &str) -> AppResult<u64> {
+        Database::revoke_refresh_tokens(self, account_id, device_name).await
+    }
+}
+
+#[async_trait]
+impl ReplayStore for Database {
+    async fn is_attestation_id_used(&self, id: &str) -> AppResult<bool> {
+        Database::is_attestation_id_used(self, id).await
+    }
+
+    async fn claim_attestation_id(&self, id: &str) -> AppResult<bool> {
+        Database::claim_attestation_id(self, id).await
+    }
+
+    async fn is_token_used(&self, message: &str) -> AppResult<bool> {
+        Database::is_token_used(self, message).await
+    }
+
+    async fn claim_token(&self, message: &str) -> AppResult<bool> {
+        Database::claim_token(self, message).await
+    }
+}
+
+#[async_trait]
+impl TransferStore for Database {
+    async fn create_transfer_token(
+        &self,
+        token: &str,
+        sealed_account: &str,
+        expires_at: i64,
+    ) -> AppResult<()> {
+        Database::create_transfer_token(self, token, sealed_account, expires_at).await
+    }
+
+    async fn take_transfer_token(&self, token: &str) -> AppResult<Option<(String, i64)>> {
+        Database::take_transfer_token(self, token).await
+    }
+}
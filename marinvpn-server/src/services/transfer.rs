@@ -0,0 +1,138 @@
+use crate::config::AuthSettings;
+use crate::error::{AppError, AppResult};
+use crate::services::store::TransferStore;
+use base64::Engine;
+use blake2::{Blake2s, Digest};
+use chrono::Utc;
+use marinvpn_common::account_number;
+use rand::Rng;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+
+/// Characters in a transfer code, from the account number alphabet so it
+/// reads back as easily. Shown as three groups of four.
+pub const TRANSFER_TOKEN_LENGTH: usize = 12;
+
+/// Issues and claims the single-use codes that sign a new device in to an
+/// account. Only a hash of the code is stored; the account number is stored
+/// next to it sealed with a key derived from the code and the account salt,
+/// so the table on its own does not link a code to an account.
+pub struct AccountTransfer {
+    salt: String,
+    ttl_secs: i64,
+}
+
+/// A transfer code that has been spent, and the account it was for.
+pub struct ClaimedTransfer {
+    pub account_number: String,
+    token: String,
+    sealed_account: String,
+    expires_at: i64,
+}
+
+impl AccountTransfer {
+    pub fn new(salt: &str, ttl_secs: u64) -> Self {
+        Self {
+            salt: salt.to_string(),
+            ttl_secs: ttl_secs as i64,
+        }
+    }
+
+    pub fn from_settings(auth: &AuthSettings) -> Self {
+        Self::new(&auth.account_salt, auth.transfer_token_ttl_secs)
+    }
+
+    /// Returns the code, grouped for display, and when it expires.
+    pub async fn issue(
+        &self,
+        store: &impl TransferStore,
+        account_number: &str,
+    ) -> AppResult<(String, i64)> {
+        let token = new_token();
+        let expires_at = Utc::now().timestamp() + self.ttl_secs;
+        let sealed = self.seal(&token, &account_number::normalize(account_number))?;
+        store
+            .create_transfer_token(&token, &sealed, expires_at)
+            .await?;
+        Ok((account_number::format_groups(&token), expires_at))
+    }
+
+    /// Spends `token`, typed with or without its spaces. `None` when it is
+    /// unknown, already claimed or expired.
+    pub async fn claim(
+        &self,
+        store: &impl TransferStore,
+        token: &str,
+    ) -> AppResult<Option<ClaimedTransfer>> {
+        let token = account_number::normalize(token);
+        let Some((sealed_account, expires_at)) = store.take_transfer_token(&token).await? else {
+            return Ok(None);
+        };
+        // Only a bug or a changed salt gets here; the code is spent either way.
+        let account_number = self
+            .open(&token, &sealed_account)
+            .ok_or_else(|| AppError::Internal(anyhow::anyhow!("Unreadable transfer token")))?;
+        Ok(Some(ClaimedTransfer {
+            account_number,
+            token,
+            sealed_account,
+            expires_at,
+        }))
+    }
+
+    fn key(&self, token: &str) -> LessSafeKey {
+        let mut hasher = Blake2s::new();
+        hasher.update(b"MARIN_VPN_TRANSFER_KEY_V1");
+        hasher.update(self.salt.as_bytes());
+        hasher.update(token.as_bytes());
+        let key = UnboundKey::new(&CHACHA20_POLY1305, &hasher.finalize())
+            .expect("a Blake2s digest is a valid ChaCha20-Poly1305 key");
+        LessSafeKey::new(key)
+    }
+
+    // Every code is fresh, so each key seals exactly one message and a
+    // fixed nonce never repeats under the same key.
+    fn seal(&self, token: &str, account_number: &str) -> AppResult<String> {
+        let mut sealed = account_number.as_bytes().to_vec();
+        self.key(token)
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key([0; 12]),
+                Aad::empty(),
+                &mut sealed,
+            )
+            .map_err(|_| AppError::Internal(anyhow::anyhow!("Failed to seal transfer token")))?;
+        Ok(base64::engine::general_purpose::STANDARD.encode(sealed))
+    }
+
+    fn open(&self, token: &str, sealed_account: &str) -> Option<String> {
+        let mut sealed = base64::engine::general_purpose::STANDARD
+            .decode(sealed_account)
+            .ok()?;
+        let opened = self
+            .key(token)
+            .open_in_place(
+                Nonce::assume_unique_for_key([0; 12]),
+                Aad::empty(),
+                &mut sealed,
+            )
+            .ok()?;
+        String::from_utf8(opened.to_vec()).ok()
+    }
+}
+
+fn new_token() -> String {
+    let alphabet = account_number::ALPHABET.as_bytes();
+    let mut rng = rand::thread_rng();
+    (0..TRANSFER_TOKEN_LENGTH)
+        .map(|_| alphabet[rng.gen_range(0..alphabet.len())] as char)
+        .collect()
+}
+
+impl ClaimedTransfer {
+    /// Makes the code claimable again until its original expiry, for a claim
+    /// that stopped short of signing the device in.
+    pub async fn give_back(self, store: &impl TransferStore) -> AppResult<()> {
+        store
+            .create_transfer_token(&self.token, &self.sealed_account, self.expires_at)
+            .await
+    }
+}
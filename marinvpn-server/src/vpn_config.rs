@@ -24,5 +24,7 @@ pub fn get_config_for_location(location: &str) -> WireGuardConfig {
         pqc_provider: None,
         pqc_ciphertext: None,
         obfuscation_key: None,
+        obfuscation_port: None,
+        obfuscation_cipher: None,
     }
 }
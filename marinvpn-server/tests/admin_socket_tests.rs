@@ -0,0 +1,166 @@
+#![cfg(unix)]
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+    Router,
+};
+use marinvpn_server::handlers::admin::{DrainResponse, MaintenanceResponse};
+use marinvpn_server::log_filter::{LogFilter, LOG_FILTER};
+use marinvpn_server::services::db::Database;
+use marinvpn_server::{admin_socket, api_routes, verify_client_attestation, AppState};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tower::util::ServiceExt;
+
+async fn setup_state() -> Option<Arc<AppState>> {
+    let db_url = match std::env::var("TEST_DATABASE_URL") {
+        Ok(url) => url,
+        Err(_) => {
+            eprintln!("TEST_DATABASE_URL not set; skipping integration test.");
+            return None;
+        }
+    };
+    let db = Database::new(&db_url, "test_salt")
+        .await
+        .expect("Failed to create test DB");
+
+    let mut settings = marinvpn_server::config::Settings::new().unwrap();
+    settings.database.url = db_url.to_string();
+
+    let runtime = Arc::new(marinvpn_server::runtime::RuntimeConfig::new(
+        marinvpn_server::runtime::RuntimeSettings::from_server(&settings.server),
+    ));
+    Some(Arc::new(AppState {
+        db,
+        settings,
+        vpn: marinvpn_server::services::vpn::VpnOrchestrator::new("wg0".to_string()),
+        signer: marinvpn_server::services::auth::BlindSigner::new(),
+        support_key: marinvpn_server::services::auth::SupportKey::new(),
+        runtime,
+    }))
+}
+
+fn socket_path() -> PathBuf {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    std::env::temp_dir().join(format!("marinvpn-admin-{}.sock", nanos))
+}
+
+/// Sends one HTTP/1.1 request over the socket and returns the status and body.
+async fn post(path: &std::path::Path, uri: &str, body: &str) -> (u16, String) {
+    let mut stream = tokio::net::UnixStream::connect(path).await.unwrap();
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        uri,
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+
+    let status = response.split(' ').nth(1).unwrap().parse().unwrap();
+    let body = response.split("\r\n\r\n").nth(1).unwrap_or("").to_string();
+    (status, body)
+}
+
+async fn wait_for(path: &std::path::Path) {
+    for _ in 0..100 {
+        let bound = std::fs::metadata(path).map(|m| m.file_type().is_socket());
+        if bound.unwrap_or(false) {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    panic!("admin socket {} never appeared", path.display());
+}
+
+#[tokio::test]
+async fn socket_serves_admin_routes_without_a_token() {
+    let Some(state) = setup_state().await else {
+        return;
+    };
+    let path = socket_path();
+    // A leftover file from a crashed run must not stop the socket binding.
+    std::fs::write(&path, b"stale").unwrap();
+
+    let app = admin_socket::routes().with_state(state.clone());
+    let server_path = path.clone();
+    let server = tokio::spawn(async move { admin_socket::serve(&server_path, app).await });
+    wait_for(&path).await;
+
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    // The private directory it was bound in is gone again.
+    let name = path.file_name().unwrap().to_string_lossy().into_owned();
+    let leftovers = std::fs::read_dir(path.parent().unwrap())
+        .unwrap()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            let other = entry.file_name().to_string_lossy().into_owned();
+            other != name && other.contains(&name)
+        })
+        .count();
+    assert_eq!(leftovers, 0);
+
+    let (status, body) = post(&path, "/maintenance", r#"{"enabled":true}"#).await;
+    assert_eq!(status, 200, "{}", body);
+    let response: MaintenanceResponse = serde_json::from_str(&body).unwrap();
+    assert_eq!(
+        response,
+        MaintenanceResponse {
+            enabled: true,
+            previous: false,
+        }
+    );
+    assert!(state.runtime.current().maintenance_mode);
+
+    let (status, body) = post(&path, "/drain", r#"{"enabled":true}"#).await;
+    assert_eq!(status, 200, "{}", body);
+    let response: DrainResponse = serde_json::from_str(&body).unwrap();
+    assert_eq!(
+        response,
+        DrainResponse {
+            enabled: true,
+            previous: false,
+        }
+    );
+    assert!(state.runtime.draining());
+
+    let (_layer, filter) = LogFilter::new("info").unwrap();
+    let _ = LOG_FILTER.set(filter);
+    let (status, _) = post(&path, "/log-level", r#"{"directives":"  "}"#).await;
+    assert_eq!(status, 400);
+
+    server.abort();
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn http_admin_routes_still_need_the_admin_token() {
+    let Some(state) = setup_state().await else {
+        return;
+    };
+    let app = Router::new()
+        .nest("/api/v1", api_routes())
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            verify_client_attestation,
+        ))
+        .with_state(state);
+
+    let request = Request::builder()
+        .method("POST")
+        .uri("/api/v1/admin/log-level")
+        .header("Content-Type", "application/json")
+        .body(Body::from(r#"{"directives":"debug"}"#))
+        .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
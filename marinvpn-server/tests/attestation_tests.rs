@@ -0,0 +1,327 @@
+use axum::{
+    body::Body,
+    http::{HeaderMap, HeaderValue, Request, StatusCode},
+    middleware::from_fn_with_state,
+    Router,
+};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use marinvpn_common::attestation::{
+    body_hash_hex, header_value, signing_message, ATTESTATION_BODY_HEADER, ATTESTATION_HEADER,
+    ATTESTATION_PUB_HEADER,
+};
+use marinvpn_server::attestation::{Attestation, MAX_CLOCK_SKEW_SECS};
+use marinvpn_server::error::AppError;
+use marinvpn_server::services::auth::TokenIssuer;
+use marinvpn_server::{api_routes, verify_client_attestation, AppState};
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use std::sync::Arc;
+use tower::util::ServiceExt;
+
+fn device_key() -> Ed25519KeyPair {
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+    Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+}
+
+fn fresh_nonce() -> String {
+    hex::encode(rand::random::<[u8; 16]>())
+}
+
+/// Mirrors the client's `request_with_attestation`.
+struct Signed {
+    timestamp: i64,
+    nonce: String,
+    method: &'static str,
+    path: &'static str,
+    body: Vec<u8>,
+}
+
+impl Signed {
+    fn new(method: &'static str, path: &'static str, body: &[u8]) -> Self {
+        Self {
+            timestamp: chrono::Utc::now().timestamp(),
+            nonce: fresh_nonce(),
+            method,
+            path,
+            body: body.to_vec(),
+        }
+    }
+
+    fn headers(&self, key: &Ed25519KeyPair) -> HeaderMap {
+        let timestamp = self.timestamp.to_string();
+        let hash = body_hash_hex(&self.body);
+        let message = signing_message(&timestamp, &self.nonce, self.method, self.path, &hash);
+        let signature = STANDARD.encode(key.sign(message.as_bytes()).as_ref());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            ATTESTATION_HEADER,
+            HeaderValue::from_str(&header_value(&timestamp, &self.nonce, &signature)).unwrap(),
+        );
+        headers.insert(
+            ATTESTATION_BODY_HEADER,
+            HeaderValue::from_str(&hash).unwrap(),
+        );
+        headers.insert(
+            ATTESTATION_PUB_HEADER,
+            HeaderValue::from_str(&STANDARD.encode(key.public_key().as_ref())).unwrap(),
+        );
+        headers
+    }
+
+    fn request(&self, headers: HeaderMap, body: &[u8]) -> Request<Body> {
+        let mut builder = Request::builder()
+            .method(self.method)
+            .uri(self.path)
+            .header("Content-Type", "application/json");
+        for (name, value) in headers.iter() {
+            builder = builder.header(name, value);
+        }
+        builder.body(Body::from(body.to_vec())).unwrap()
+    }
+}
+
+fn pubkey_b64(key: &Ed25519KeyPair) -> String {
+    STANDARD.encode(key.public_key().as_ref())
+}
+
+fn check(signed: &Signed, headers: &HeaderMap, body: &[u8]) -> Result<Attestation, AppError> {
+    Attestation::from_request(
+        headers,
+        body,
+        signed.path,
+        chrono::Utc::now().timestamp(),
+        true,
+    )
+}
+
+#[test]
+fn client_signature_verifies_on_server() {
+    let key = device_key();
+    let signed = Signed::new("POST", "/vpn/config", br#"{"location":"Sweden"}"#);
+    let headers = signed.headers(&key);
+
+    let attestation = check(&signed, &headers, &signed.body).unwrap();
+    assert_eq!(attestation.nonce, signed.nonce);
+    assert_eq!(attestation.provided_pubkey, Some(pubkey_b64(&key)));
+    assert!(attestation
+        .verify_signature(signed.method, signed.path, &pubkey_b64(&key))
+        .is_ok());
+}
+
+#[test]
+fn signature_from_another_key_is_rejected() {
+    let key = device_key();
+    let signed = Signed::new("GET", "/vpn/servers", b"");
+    let attestation = check(&signed, &signed.headers(&key), b"").unwrap();
+
+    assert!(matches!(
+        attestation.verify_signature("GET", "/vpn/servers", &pubkey_b64(&device_key())),
+        Err(AppError::Unauthorized)
+    ));
+    assert!(matches!(
+        attestation.verify_signature("POST", "/vpn/servers", &pubkey_b64(&key)),
+        Err(AppError::Unauthorized)
+    ));
+    assert!(matches!(
+        attestation.verify_signature("GET", "/vpn/config", &pubkey_b64(&key)),
+        Err(AppError::Unauthorized)
+    ));
+}
+
+#[test]
+fn stale_and_future_timestamps_are_rejected() {
+    let key = device_key();
+    for skew in [-(MAX_CLOCK_SKEW_SECS + 5), MAX_CLOCK_SKEW_SECS + 5] {
+        let mut signed = Signed::new("GET", "/vpn/servers", b"");
+        signed.timestamp += skew;
+        assert!(matches!(
+            check(&signed, &signed.headers(&key), b""),
+            Err(AppError::Unauthorized)
+        ));
+    }
+}
+
+#[test]
+fn body_mismatch_and_missing_hash_are_rejected() {
+    let key = device_key();
+    let signed = Signed::new("POST", "/vpn/config", br#"{"location":"Sweden"}"#);
+    let mut headers = signed.headers(&key);
+
+    assert!(matches!(
+        check(&signed, &headers, br#"{"location":"Germany"}"#),
+        Err(AppError::Unauthorized)
+    ));
+
+    headers.remove(ATTESTATION_BODY_HEADER);
+    assert!(matches!(
+        check(&signed, &headers, &signed.body),
+        Err(AppError::Unauthorized)
+    ));
+    assert!(Attestation::from_request(
+        &headers,
+        &signed.body,
+        signed.path,
+        chrono::Utc::now().timestamp(),
+        false,
+    )
+    .is_ok());
+}
+
+#[test]
+fn malformed_header_is_rejected() {
+    let key = device_key();
+    let signed = Signed::new("GET", "/vpn/servers", b"");
+    let mut headers = signed.headers(&key);
+    headers.insert(ATTESTATION_HEADER, HeaderValue::from_static("12345:nonce"));
+    assert!(matches!(
+        check(&signed, &headers, b""),
+        Err(AppError::Unauthorized)
+    ));
+
+    headers.remove(ATTESTATION_HEADER);
+    assert!(matches!(
+        check(&signed, &headers, b""),
+        Err(AppError::Unauthorized)
+    ));
+}
+
+async fn setup_app() -> Option<Router> {
+    setup_app_with_tokens().await.map(|(app, _)| app)
+}
+
+async fn setup_app_with_tokens() -> Option<(Router, TokenIssuer)> {
+    let db_url = match std::env::var("TEST_DATABASE_URL") {
+        Ok(url) => url,
+        Err(_) => {
+            eprintln!("TEST_DATABASE_URL not set; skipping integration test.");
+            return None;
+        }
+    };
+    let db = marinvpn_server::services::db::Database::new(&db_url, "test_salt")
+        .await
+        .expect("Failed to create test DB");
+
+    let mut settings = marinvpn_server::config::Settings::new().unwrap();
+    settings.database.url = db_url.to_string();
+
+    let runtime = Arc::new(marinvpn_server::runtime::RuntimeConfig::new(
+        marinvpn_server::runtime::RuntimeSettings::from_server(&settings.server),
+    ));
+    let tokens = TokenIssuer::from_settings(&settings.auth);
+    let state = Arc::new(AppState {
+        db,
+        settings,
+        vpn: marinvpn_server::services::vpn::VpnOrchestrator::new("wg0".to_string()),
+        signer: marinvpn_server::services::auth::BlindSigner::new(),
+        support_key: marinvpn_server::services::auth::SupportKey::new(),
+        runtime,
+    });
+
+    let app = api_routes()
+        .layer(from_fn_with_state(state.clone(), verify_client_attestation))
+        .with_state(state);
+    Some((app, tokens))
+}
+
+async fn status(app: &Router, request: Request<Body>) -> StatusCode {
+    app.clone().oneshot(request).await.unwrap().status()
+}
+
+#[tokio::test]
+async fn router_accepts_signed_request_once() {
+    let Some(app) = setup_app().await else {
+        return;
+    };
+    let key = device_key();
+    let signed = Signed::new("GET", "/vpn/pow-challenge", b"");
+    let headers = signed.headers(&key);
+
+    assert_eq!(
+        status(&app, signed.request(headers.clone(), b"")).await,
+        StatusCode::OK
+    );
+    assert_eq!(
+        status(&app, signed.request(headers, b"")).await,
+        StatusCode::UNAUTHORIZED
+    );
+}
+
+#[tokio::test]
+async fn router_rejects_tampered_requests() {
+    let Some(app) = setup_app().await else {
+        return;
+    };
+    let key = device_key();
+
+    let unsigned = Signed::new("GET", "/vpn/pow-challenge", b"");
+    assert_eq!(
+        status(&app, unsigned.request(HeaderMap::new(), b"")).await,
+        StatusCode::UNAUTHORIZED
+    );
+
+    let wrong_key = Signed::new("GET", "/vpn/pow-challenge", b"");
+    let mut headers = wrong_key.headers(&device_key());
+    headers.insert(
+        ATTESTATION_PUB_HEADER,
+        HeaderValue::from_str(&pubkey_b64(&key)).unwrap(),
+    );
+    assert_eq!(
+        status(&app, wrong_key.request(headers, b"")).await,
+        StatusCode::UNAUTHORIZED
+    );
+
+    let mut expired = Signed::new("GET", "/vpn/pow-challenge", b"");
+    expired.timestamp -= MAX_CLOCK_SKEW_SECS + 5;
+    assert_eq!(
+        status(&app, expired.request(expired.headers(&key), b"")).await,
+        StatusCode::UNAUTHORIZED
+    );
+
+    let body = br#"{"account_number":"ABCD E2GH JK7M NPQR"}"#;
+    let swapped = Signed::new("POST", "/account/devices", body);
+    assert_eq!(
+        status(
+            &app,
+            swapped.request(
+                swapped.headers(&key),
+                br#"{"account_number":"WXYZ E2GH JK7M NPQR"}"#
+            )
+        )
+        .await,
+        StatusCode::UNAUTHORIZED
+    );
+}
+
+#[tokio::test]
+async fn token_is_rejected_with_another_devices_key() {
+    let Some((app, tokens)) = setup_app_with_tokens().await else {
+        return;
+    };
+    let key = device_key();
+    let token = tokens
+        .access_token(
+            "ABCD E2GH JK7M NPQR",
+            "Swift Falcon",
+            Some(&pubkey_b64(&key)),
+        )
+        .unwrap();
+    let bearer = HeaderValue::from_str(&format!("Bearer {}", token)).unwrap();
+
+    let own = Signed::new("GET", "/vpn/pow-challenge", b"");
+    let mut headers = own.headers(&key);
+    headers.insert(axum::http::header::AUTHORIZATION, bearer.clone());
+    assert_eq!(
+        status(&app, own.request(headers, b"")).await,
+        StatusCode::OK
+    );
+
+    // Correctly signed, but by a device the token was not minted for.
+    let other = Signed::new("GET", "/vpn/pow-challenge", b"");
+    let mut headers = other.headers(&device_key());
+    headers.insert(axum::http::header::AUTHORIZATION, bearer);
+    assert_eq!(
+        status(&app, other.request(headers, b"")).await,
+        StatusCode::UNAUTHORIZED
+    );
+}
@@ -2,12 +2,27 @@ use axum::{
     body::Body,
     http::{Request, StatusCode},
 };
-use marinvpn_common::{GenerateResponse, LoginRequest, LoginResponse};
+use axum::{extract::State, http::HeaderMap, Json};
+use marinvpn_common::attestation::ATTESTATION_PUB_HEADER;
+use marinvpn_common::{
+    ClaimTransferRequest, GenerateResponse, LoginRequest, LoginResponse, RefreshRequest,
+    RemoveDeviceReason, RemoveDeviceRequest, RemoveDeviceResponse,
+};
+use marinvpn_server::error::AppError;
+use marinvpn_server::handlers::auth::{
+    claim_transfer_token, get_sessions, issue_transfer_token, logout, refresh_token, remove_device,
+    AuthUser,
+};
+use marinvpn_server::services::auth::TokenIssuer;
 use marinvpn_server::{api_routes, AppState};
+use rand::Rng;
 use std::sync::Arc;
 use tower::util::ServiceExt;
 
-async fn setup_app() -> Option<axum::Router> {
+/// Any 32 bytes, base64, the way a client sends its attestation key.
+const NEW_DEVICE_KEY: &str = "BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc=";
+
+async fn setup_state() -> Option<Arc<AppState>> {
     let db_url = match std::env::var("TEST_DATABASE_URL") {
         Ok(url) => url,
         Err(_) => {
@@ -25,15 +40,34 @@ async fn setup_app() -> Option<axum::Router> {
     let vpn = marinvpn_server::services::vpn::VpnOrchestrator::new("wg0".to_string());
     let signer = marinvpn_server::services::auth::BlindSigner::new();
     let support_key = marinvpn_server::services::auth::SupportKey::new();
-    let state = Arc::new(AppState {
+    let runtime = Arc::new(marinvpn_server::runtime::RuntimeConfig::new(
+        marinvpn_server::runtime::RuntimeSettings::from_server(&settings.server),
+    ));
+    Some(Arc::new(AppState {
         db,
         settings,
         vpn,
         signer,
         support_key,
-    });
+        runtime,
+    }))
+}
 
-    Some(api_routes().with_state(state))
+async fn setup_app() -> Option<axum::Router> {
+    Some(api_routes().with_state(setup_state().await?))
+}
+
+fn random_account_number() -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+    let mut rng = rand::thread_rng();
+    let groups: Vec<String> = (0..4)
+        .map(|_| {
+            (0..4)
+                .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char)
+                .collect()
+        })
+        .collect();
+    groups.join(" ")
 }
 
 #[tokio::test]
@@ -91,7 +125,313 @@ async fn test_generate_and_login() {
         login_res.account_info.unwrap().account_number,
         gen_res.account_number
     );
-    assert!(login_res.current_device.unwrap_or_default().len() > 0);
+    assert!(!login_res.current_device.unwrap_or_default().is_empty());
     assert!(login_res.auth_token.unwrap_or_default().len() > 10);
     assert!(login_res.refresh_token.unwrap_or_default().len() > 10);
 }
+
+#[tokio::test]
+async fn remove_device_reports_not_found() {
+    let Some(state) = setup_state().await else {
+        return;
+    };
+    let account_number = random_account_number();
+    state.db.create_account(&account_number, 30).await.unwrap();
+    state
+        .db
+        .add_device(&account_number, "Swift Falcon", None)
+        .await
+        .unwrap();
+    state
+        .db
+        .add_device(&account_number, "Quiet Otter", None)
+        .await
+        .unwrap();
+
+    let remove = |device_name: &str| {
+        remove_device(
+            State(state.clone()),
+            AuthUser {
+                account_number: account_number.clone(),
+                device_name: "Swift Falcon".to_string(),
+            },
+            Json(RemoveDeviceRequest {
+                account_number: account_number.clone(),
+                device_name: device_name.to_string(),
+            }),
+        )
+    };
+
+    let Json(first) = remove("Quiet Otter").await.unwrap();
+    assert_eq!(first, RemoveDeviceResponse::removed());
+
+    let Json(again) = remove("Quiet Otter").await.unwrap();
+    assert!(!again.removed);
+    assert_eq!(again.reason, Some(RemoveDeviceReason::NotFound));
+
+    let Json(unknown) = remove("Never Registered").await.unwrap();
+    assert_eq!(
+        unknown,
+        RemoveDeviceResponse::not_removed(RemoveDeviceReason::NotFound)
+    );
+}
+
+#[tokio::test]
+async fn logout_revokes_refresh_token_but_keeps_device() {
+    let Some(state) = setup_state().await else {
+        return;
+    };
+    let account_number = random_account_number();
+    let device = "Swift Falcon";
+    state.db.create_account(&account_number, 30).await.unwrap();
+    state
+        .db
+        .add_device(&account_number, device, Some("attestation-key"))
+        .await
+        .unwrap();
+    let (refresh, expires_at) = TokenIssuer::from_settings(&state.settings.auth)
+        .refresh_token(&account_number, device, Some("attestation-key"))
+        .unwrap();
+    state
+        .db
+        .create_session(&account_number, device, &refresh, expires_at, 3)
+        .await
+        .unwrap();
+
+    let Json(response) = logout(
+        State(state.clone()),
+        AuthUser {
+            account_number: account_number.clone(),
+            device_name: device.to_string(),
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(response.sessions_revoked, 1);
+
+    let mut headers = HeaderMap::new();
+    headers.insert(ATTESTATION_PUB_HEADER, "attestation-key".parse().unwrap());
+    let refreshed = refresh_token(
+        State(state.clone()),
+        headers,
+        Json(RefreshRequest {
+            refresh_token: refresh,
+        }),
+    )
+    .await;
+    assert!(matches!(refreshed, Err(AppError::Unauthorized)));
+
+    let kept = state
+        .db
+        .get_device_pubkey(&account_number, device)
+        .await
+        .unwrap();
+    assert_eq!(kept.as_deref(), Some("attestation-key"));
+}
+
+#[tokio::test]
+async fn refresh_signed_with_a_new_device_key_reports_the_mismatch() {
+    let Some(state) = setup_state().await else {
+        return;
+    };
+    let account_number = random_account_number();
+    let device = "Calm Otter";
+    state.db.create_account(&account_number, 30).await.unwrap();
+    state
+        .db
+        .add_device(&account_number, device, Some("old-attestation-key"))
+        .await
+        .unwrap();
+    let (refresh, expires_at) = TokenIssuer::from_settings(&state.settings.auth)
+        .refresh_token(&account_number, device, Some("old-attestation-key"))
+        .unwrap();
+    state
+        .db
+        .create_session(&account_number, device, &refresh, expires_at, 3)
+        .await
+        .unwrap();
+
+    // What a reinstalled client sends: its old token, signed by a new key.
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        ATTESTATION_PUB_HEADER,
+        "new-attestation-key".parse().unwrap(),
+    );
+    let refreshed = refresh_token(
+        State(state.clone()),
+        headers,
+        Json(RefreshRequest {
+            refresh_token: refresh,
+        }),
+    )
+    .await;
+    assert!(matches!(refreshed, Err(AppError::DeviceKeyMismatch)));
+}
+
+#[tokio::test]
+async fn session_cap_refuses_logins_until_one_is_revoked() {
+    let Some(state) = setup_state().await else {
+        return;
+    };
+    let account_number = random_account_number();
+    let device = "Swift Falcon";
+    state.db.create_account(&account_number, 30).await.unwrap();
+    state
+        .db
+        .add_device(&account_number, device, None)
+        .await
+        .unwrap();
+    let expires_at = chrono::Utc::now().timestamp() + 3600;
+    let session = |n: u32| {
+        let db = &state.db;
+        let account_number = account_number.clone();
+        async move {
+            let token = format!("refresh-{}-{}", account_number, n);
+            db.create_session(&account_number, device, &token, expires_at, 2)
+                .await
+                .unwrap()
+        }
+    };
+
+    assert!(session(1).await);
+    assert!(session(2).await);
+    assert!(!session(3).await, "third session is over the cap");
+
+    let Json(sessions) = get_sessions(
+        State(state.clone()),
+        AuthUser {
+            account_number: account_number.clone(),
+            device_name: device.to_string(),
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(sessions.active_sessions, 2);
+    assert_eq!(
+        sessions.max_sessions,
+        state.settings.auth.max_sessions_per_device
+    );
+
+    let revoked = state
+        .db
+        .revoke_refresh_tokens(&account_number, device)
+        .await
+        .unwrap();
+    assert_eq!(revoked, 2);
+    assert!(session(4).await);
+}
+
+#[tokio::test]
+async fn expired_session_frees_its_slot() {
+    let Some(state) = setup_state().await else {
+        return;
+    };
+    let account_number = random_account_number();
+    let device = "Swift Falcon";
+    state.db.create_account(&account_number, 30).await.unwrap();
+    state
+        .db
+        .add_device(&account_number, device, None)
+        .await
+        .unwrap();
+    let create = |token: &'static str, expires_at: i64| {
+        let db = &state.db;
+        let account_number = account_number.clone();
+        async move {
+            let token = format!("{}-{}", token, account_number);
+            db.create_session(&account_number, device, &token, expires_at, 1)
+                .await
+                .unwrap()
+        }
+    };
+
+    let now = chrono::Utc::now().timestamp();
+    assert!(create("short", now + 1).await);
+    assert!(!create("blocked", now + 3600).await);
+
+    tokio::time::sleep(std::time::Duration::from_secs(3)).await;
+    assert_eq!(
+        state
+            .db
+            .count_active_sessions(&account_number, device)
+            .await
+            .unwrap(),
+        0
+    );
+    assert!(create("after-expiry", now + 3600).await);
+}
+
+#[tokio::test]
+async fn transfer_token_signs_in_one_new_device() {
+    let Some(state) = setup_state().await else {
+        return;
+    };
+    let account_number = random_account_number();
+    state.db.create_account(&account_number, 30).await.unwrap();
+    state
+        .db
+        .add_device(&account_number, "Swift Falcon", None)
+        .await
+        .unwrap();
+    let issue = || {
+        issue_transfer_token(
+            State(state.clone()),
+            AuthUser {
+                account_number: account_number.clone(),
+                device_name: "Swift Falcon".to_string(),
+            },
+        )
+    };
+    let claim = |token: &str, kick_device: Option<&str>| {
+        let mut headers = HeaderMap::new();
+        headers.insert(ATTESTATION_PUB_HEADER, NEW_DEVICE_KEY.parse().unwrap());
+        claim_transfer_token(
+            State(state.clone()),
+            headers,
+            Json(ClaimTransferRequest {
+                transfer_token: token.to_string(),
+                device_pubkey: Some(NEW_DEVICE_KEY.to_string()),
+                kick_device: kick_device.map(str::to_string),
+            }),
+        )
+    };
+
+    let Json(issued) = issue().await.unwrap();
+    let Json(login) = claim(&issued.transfer_token, None).await.unwrap();
+    assert!(login.success);
+    // It took over the device row that had no key yet.
+    assert_eq!(login.current_device.as_deref(), Some("Swift Falcon"));
+    assert!(login.auth_token.is_some() && login.refresh_token.is_some());
+    assert_eq!(
+        login.account_info.unwrap().account_number.replace(' ', ""),
+        account_number.replace(' ', "")
+    );
+    assert!(matches!(
+        claim(&issued.transfer_token, None).await,
+        Err(AppError::TransferTokenInvalid)
+    ));
+
+    // At the device limit the code survives for the retry that kicks one.
+    state
+        .db
+        .remove_device(&account_number, "Swift Falcon")
+        .await
+        .unwrap();
+    for n in 0..5 {
+        let name = format!("device {}", n);
+        let key = format!("key-{}", n);
+        state
+            .db
+            .add_device(&account_number, &name, Some(&key))
+            .await
+            .unwrap();
+    }
+    let Json(issued) = issue().await.unwrap();
+    let Json(limited) = claim(&issued.transfer_token, None).await.unwrap();
+    assert!(!limited.success);
+    assert_eq!(limited.error_code.as_deref(), Some("DEVICE_LIMIT"));
+    let Json(kicked) = claim(&issued.transfer_token, Some("device 0"))
+        .await
+        .unwrap();
+    assert!(kicked.success);
+}
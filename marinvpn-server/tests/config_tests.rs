@@ -0,0 +1,294 @@
+use marinvpn_common::version::features;
+use marinvpn_server::config::{
+    AuthSettings, DatabaseSettings, ServerSettings, Settings, VpnSettings,
+};
+use marinvpn_server::handlers::vpn::server_version;
+use marinvpn_server::runtime::RuntimeSettings;
+
+const JWT_SECRET: &str = "k3J9vQ2xLm8Zp4Rt7Wn1Yc6Hb0Fd5Gs2Ue9Ia3Oj8Kl4Mq7Nv1Px6Tz0Ry5Sw2";
+const ATTESTATION_SECRET: &str = "Vb7Nc2Xz9Lk4Jh1Gf6Ds3Aq8Wp5Eo0Ri7Tu2Yy9Mm4Nn1Bb6Vv3Cc8Xx5Zz0";
+const ACCOUNT_SALT: &str = "Qw3Er8Ty1Ui6Op9As4Df7Gh2Jk5Lz0Xc";
+const PANIC_KEY: &str = "Pz4Ox9Ly2Kw7Jv0Iu5Ht8Gs3Fr6Eq1Dp4Co9Bn2Am7Zl0Yk5Xj8Wi3Vh6Ug1";
+const ADMIN_TOKEN: &str = "a8F3k2M9q7W1z5X0c4V6b2N8m1L7p3K9j5H0g6D4s2A8";
+
+fn production_settings() -> Settings {
+    Settings {
+        server: ServerSettings {
+            port: 3000,
+            host: "0.0.0.0".to_string(),
+            log_level: "info".to_string(),
+            max_body_bytes: 262_144,
+            admin_token: ADMIN_TOKEN.to_string(),
+            admin_socket: None,
+            metrics_allowlist: Vec::new(),
+            trusted_proxy_hops: 1,
+            trusted_proxy_cidrs: vec!["10.0.0.0/24".to_string()],
+            rate_limit_replenish_secs: 6,
+            rate_limit_burst: 10,
+            maintenance_mode: false,
+            cors_origins: Vec::new(),
+            request_timeout_secs: 30,
+            cleanup_interval_secs: 3600,
+            session_max_age_secs: 86_400,
+            generate_ip_replenish_secs: 1200,
+            generate_ip_burst: 3,
+            generate_global_replenish_secs: 2,
+            generate_global_burst: 60,
+            min_client_version: "0.1.0".to_string(),
+        },
+        database: DatabaseSettings {
+            url: "postgres://marinvpn:marinvpn@db:5432/marinvpn".to_string(),
+        },
+        auth: AuthSettings {
+            jwt_secret: JWT_SECRET.to_string(),
+            jwt_issuer: "marinvpn".to_string(),
+            jwt_audience: "marinvpn-api".to_string(),
+            access_token_ttl_secs: 900,
+            refresh_token_ttl_secs: 2_592_000,
+            max_sessions_per_device: 3,
+            transfer_token_ttl_secs: 300,
+            attestation_secret: ATTESTATION_SECRET.to_string(),
+            account_salt: ACCOUNT_SALT.to_string(),
+            panic_key: PANIC_KEY.to_string(),
+            panic_cooldown_secs: 600,
+            invite_codes: Vec::new(),
+            pow_enabled: false,
+            pow_difficulty: 18,
+            pow_challenge_ttl_secs: 120,
+        },
+        vpn: VpnSettings {
+            peer_cidr: "10.0.0.0/8".to_string(),
+            expected_peers: 65_536,
+        },
+    }
+}
+
+fn rejection(settings: &Settings) -> String {
+    settings
+        .validate("production")
+        .expect_err("config should be rejected")
+        .to_string()
+}
+
+#[test]
+fn valid_production_config_is_accepted() {
+    assert!(production_settings().validate("production").is_ok());
+}
+
+#[test]
+fn localhost_host_is_rejected_in_production() {
+    let mut settings = production_settings();
+    settings.server.host = "127.0.0.1".to_string();
+    assert!(rejection(&settings).contains("server.host"));
+}
+
+#[test]
+fn short_admin_token_is_rejected() {
+    let mut settings = production_settings();
+    settings.server.admin_token = "short-token".to_string();
+    let err = rejection(&settings);
+    assert!(err.contains("server.admin_token is too short"), "{}", err);
+}
+
+#[test]
+fn missing_admin_token_is_rejected() {
+    let mut settings = production_settings();
+    settings.server.admin_token = "  ".to_string();
+    assert!(rejection(&settings).contains("server.admin_token is missing"));
+}
+
+#[test]
+fn default_jwt_secret_is_rejected() {
+    let mut settings = production_settings();
+    settings.auth.jwt_secret = "replace-with-a-real-secret-in-production".to_string();
+    assert!(rejection(&settings).contains("auth.jwt_secret still uses the shipped default"));
+}
+
+#[test]
+fn low_entropy_jwt_secret_is_rejected() {
+    let mut settings = production_settings();
+    settings.auth.jwt_secret = "ab".repeat(32);
+    let err = rejection(&settings);
+    assert!(
+        err.contains("auth.jwt_secret has too little entropy"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn weak_attestation_secret_and_panic_key_are_both_reported() {
+    let mut settings = production_settings();
+    settings.auth.attestation_secret = "marinvpn_secure_attestation_2026_top_tier".to_string();
+    settings.auth.panic_key = "emergency".to_string();
+    let err = rejection(&settings);
+    assert!(err.contains("auth.attestation_secret"), "{}", err);
+    assert!(err.contains("auth.panic_key"), "{}", err);
+}
+
+#[test]
+fn oversized_account_salt_is_rejected_in_every_mode() {
+    let mut settings = production_settings();
+    settings.auth.account_salt = "x".repeat(64);
+    let err = settings.validate("development").unwrap_err().to_string();
+    assert!(
+        err.contains("auth.account_salt must be 4-48 bytes"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn account_salt_with_whitespace_is_rejected() {
+    let mut settings = production_settings();
+    settings.auth.account_salt = "Qw3Er8Ty1Ui6Op9A s4Df7Gh2Jk5Lz0Xc".to_string();
+    assert!(rejection(&settings).contains("auth.account_salt must not contain whitespace"));
+}
+
+#[test]
+fn short_account_salt_is_rejected_in_production() {
+    let mut settings = production_settings();
+    settings.auth.account_salt = "Qw3Er8Ty1Ui6".to_string();
+    assert!(rejection(&settings).contains("auth.account_salt is too short"));
+}
+
+#[test]
+fn direct_exposure_without_tls_proxy_is_rejected() {
+    let mut settings = production_settings();
+    settings.server.trusted_proxy_hops = 0;
+    settings.server.trusted_proxy_cidrs.clear();
+    assert!(rejection(&settings).contains("TLS-terminating reverse proxy"));
+}
+
+#[test]
+fn proxy_hops_without_cidrs_are_rejected() {
+    let mut settings = production_settings();
+    settings.server.trusted_proxy_cidrs.clear();
+    assert!(rejection(&settings).contains("server.trusted_proxy_cidrs"));
+}
+
+#[test]
+fn development_mode_tolerates_default_secrets() {
+    let mut settings = production_settings();
+    settings.server.host = "127.0.0.1".to_string();
+    settings.server.admin_token.clear();
+    settings.server.trusted_proxy_hops = 0;
+    settings.auth.jwt_secret = "replace-with-a-real-secret-in-production".to_string();
+    assert!(settings.validate("development").is_ok());
+}
+
+#[test]
+fn zero_rate_limit_burst_is_rejected_in_every_mode() {
+    let mut settings = production_settings();
+    settings.server.rate_limit_burst = 0;
+    let err = settings.validate("development").unwrap_err().to_string();
+    assert!(
+        err.contains("server.rate_limit_burst must be at least 1"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn access_token_must_expire_before_refresh_token() {
+    let mut settings = production_settings();
+    settings.auth.access_token_ttl_secs = settings.auth.refresh_token_ttl_secs;
+    let err = rejection(&settings);
+    assert!(
+        err.contains("auth.access_token_ttl_secs must be shorter"),
+        "{}",
+        err
+    );
+
+    settings.auth.access_token_ttl_secs = 0;
+    let err = rejection(&settings);
+    assert!(
+        err.contains("auth.access_token_ttl_secs must be at least 1"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn empty_jwt_audience_is_rejected() {
+    let mut settings = production_settings();
+    settings.auth.jwt_audience = " ".to_string();
+    let err = rejection(&settings);
+    assert!(
+        err.contains("auth.jwt_audience must not be empty"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn comma_separated_env_lists_are_parsed() {
+    std::env::set_var(
+        "APP__SERVER__CORS_ORIGINS",
+        "https://a.example,https://b.example",
+    );
+    std::env::set_var("APP__AUTH__INVITE_CODES", "spring-2026");
+    std::env::set_var("APP__SERVER__RATE_LIMIT_BURST", "12");
+
+    let settings = Settings::new().expect("env lists should load");
+    assert_eq!(
+        settings.server.cors_origins,
+        vec!["https://a.example", "https://b.example"]
+    );
+    assert_eq!(settings.auth.invite_codes, vec!["spring-2026"]);
+    assert_eq!(settings.server.rate_limit_burst, 12);
+}
+
+#[test]
+fn undersized_peer_cidr_is_rejected_in_every_mode() {
+    let mut settings = production_settings();
+    settings.vpn.peer_cidr = "100.64.0.0/24".to_string();
+    settings.vpn.expected_peers = 1000;
+    let err = settings.validate("development").unwrap_err().to_string();
+    assert!(err.contains("vpn.peer_cidr"), "{}", err);
+
+    settings.vpn.expected_peers = 253;
+    assert!(settings.validate("development").is_ok());
+
+    settings.vpn.peer_cidr = "fd00::/64".to_string();
+    assert!(settings.validate("development").is_err());
+}
+
+#[test]
+fn malformed_min_client_version_is_rejected_in_every_mode() {
+    let mut settings = production_settings();
+    settings.server.min_client_version = "1.2".to_string();
+    let err = settings.validate("development").unwrap_err().to_string();
+    assert!(
+        err.contains("server.min_client_version must be a MAJOR.MINOR.PATCH version"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn version_advertises_features_and_minimum_client() {
+    let mut settings = production_settings();
+    settings.server.min_client_version = "0.3.0".to_string();
+    settings.auth.pow_enabled = false;
+
+    let runtime = RuntimeSettings::from_server(&settings.server);
+    let advertised = server_version(&settings, &runtime);
+    assert_eq!(advertised.version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(advertised.min_client_version, "0.3.0");
+    assert!(advertised
+        .api_features
+        .iter()
+        .any(|f| f == features::ANONYMOUS_CONFIG));
+    assert!(!advertised
+        .api_features
+        .iter()
+        .any(|f| f == features::PROOF_OF_WORK));
+
+    settings.auth.pow_enabled = true;
+    let advertised = server_version(&settings, &runtime);
+    assert!(advertised
+        .api_features
+        .iter()
+        .any(|f| f == features::PROOF_OF_WORK));
+}
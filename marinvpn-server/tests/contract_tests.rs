@@ -0,0 +1,40 @@
+use axum::response::IntoResponse;
+use marinvpn_server::error::AppError;
+use marinvpn_server::models::Device;
+use serde_json::Value;
+
+/// The golden fixtures live with the shared types in `marinvpn-common`.
+fn common_fixture(name: &str) -> Value {
+    let path = format!(
+        "{}/../marinvpn-common/tests/fixtures/{}",
+        env!("CARGO_MANIFEST_DIR"),
+        name
+    );
+    let raw = std::fs::read_to_string(&path).unwrap_or_else(|e| panic!("{}: {}", path, e));
+    serde_json::from_str(&raw).unwrap()
+}
+
+#[test]
+fn device_row_maps_to_common_device_fixture() {
+    let row = Device {
+        id: Some(7),
+        account_id: "hashed-account".to_string(),
+        name: "Swift Falcon".to_string(),
+        added_at: 1767225600,
+        attestation_pubkey: Some("AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=".to_string()),
+    };
+    assert_eq!(
+        serde_json::to_value(row.into_common()).unwrap(),
+        common_fixture("device.json")
+    );
+}
+
+#[tokio::test]
+async fn error_body_matches_common_error_response() {
+    let response = AppError::Unauthorized.into_response();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json, common_fixture("error_response.json"));
+}
@@ -0,0 +1,143 @@
+use axum::{
+    body::Body,
+    http::{HeaderMap, HeaderValue, Request, StatusCode},
+    middleware::from_fn_with_state,
+    routing::get,
+    Json, Router,
+};
+use marinvpn_common::version::Version;
+use marinvpn_server::deadline::{Deadline, DEADLINE_HEADER};
+use marinvpn_server::error::{AppError, AppResult};
+use marinvpn_server::runtime::{self, RuntimeConfig, RuntimeSettings};
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::Duration;
+use tower::util::ServiceExt;
+
+fn settings(request_timeout_secs: u64) -> RuntimeSettings {
+    RuntimeSettings {
+        rate_limit_replenish_secs: 60,
+        rate_limit_burst: 10,
+        maintenance_mode: false,
+        cors_origins: Vec::new(),
+        request_timeout_secs,
+        cleanup_interval_secs: 3600,
+        session_max_age_secs: 86_400,
+        generate_ip_replenish_secs: 3600,
+        generate_ip_burst: 2,
+        generate_global_replenish_secs: 3600,
+        generate_global_burst: 5,
+        min_client_version: Version::default(),
+    }
+}
+
+/// A handler chain whose second step outlives any reasonable budget.
+async fn slow_chain(deadline: Deadline) -> AppResult<&'static str> {
+    deadline.run("fast step", async { Ok(()) }).await?;
+    deadline
+        .run("slow step", async {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            Ok("late")
+        })
+        .await
+}
+
+fn app(request_timeout_secs: u64) -> Router {
+    let runtime = Arc::new(RuntimeConfig::new(settings(request_timeout_secs)));
+    Router::new()
+        .route("/api/v1/slow", get(slow_chain))
+        .route(
+            "/api/v1/budget",
+            get(|deadline: Deadline| async move {
+                Json(deadline.remaining().map(|left| left.as_millis() as u64))
+            }),
+        )
+        .layer(from_fn_with_state(runtime, runtime::enforce_timeout))
+}
+
+async fn call(app: Router, path: &str, deadline_ms: Option<&str>) -> axum::response::Response {
+    let mut req = Request::builder().uri(path);
+    if let Some(ms) = deadline_ms {
+        req = req.header(DEADLINE_HEADER, ms);
+    }
+    app.oneshot(req.body(Body::empty()).unwrap()).await.unwrap()
+}
+
+async fn json(response: axum::response::Response) -> Value {
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+#[tokio::test]
+async fn handler_gives_up_before_the_hard_timeout() {
+    let started = std::time::Instant::now();
+    let response = call(app(1), "/api/v1/slow", None).await;
+
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(json(response).await["code"], "deadline_exceeded");
+    assert!(started.elapsed() < Duration::from_secs(1));
+}
+
+#[tokio::test]
+async fn handler_budget_leaves_headroom() {
+    let left = json(call(app(30), "/api/v1/budget", None).await).await;
+    let left = left.as_u64().unwrap();
+    assert!(left <= 29_000, "{}", left);
+    assert!(left > 28_000, "{}", left);
+}
+
+#[tokio::test]
+async fn header_can_shorten_but_not_extend_the_budget() {
+    let shorter = json(call(app(30), "/api/v1/budget", Some("500")).await).await;
+    assert!(shorter.as_u64().unwrap() <= 500);
+
+    let longer = json(call(app(30), "/api/v1/budget", Some("600000")).await).await;
+    assert!(longer.as_u64().unwrap() <= 29_000);
+
+    let garbage = json(call(app(30), "/api/v1/budget", Some("soon")).await).await;
+    assert!(garbage.as_u64().unwrap() > 28_000);
+
+    let cut_short = call(app(30), "/api/v1/slow", Some("50")).await;
+    assert_eq!(cut_short.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[tokio::test]
+async fn spent_deadline_never_starts_the_step() {
+    let deadline = Deadline::after(Duration::ZERO);
+    assert!(matches!(
+        deadline.check("anything"),
+        Err(AppError::DeadlineExceeded)
+    ));
+
+    let mut started = false;
+    let result = deadline
+        .run("step", async {
+            started = true;
+            Ok(())
+        })
+        .await;
+    assert!(matches!(result, Err(AppError::DeadlineExceeded)));
+    assert!(!started);
+}
+
+#[tokio::test]
+async fn unbounded_deadline_never_expires() {
+    let deadline = Deadline::unbounded();
+    assert_eq!(deadline.remaining(), None);
+    assert!(deadline.check("anything").is_ok());
+    assert_eq!(deadline.run("step", async { Ok(7) }).await.unwrap(), 7);
+}
+
+#[test]
+fn short_timeouts_keep_most_of_their_budget() {
+    let deadline = Deadline::for_request(Duration::from_secs(2), &HeaderMap::new());
+    let left = deadline.remaining().unwrap();
+    assert!(left > Duration::from_millis(1_700) && left <= Duration::from_millis(1_800));
+
+    let mut headers = HeaderMap::new();
+    headers.insert(DEADLINE_HEADER, HeaderValue::from_static("0"));
+    let spent = Deadline::for_request(Duration::from_secs(2), &headers);
+    assert!(spent.check("anything").is_err());
+}
@@ -0,0 +1,129 @@
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use marinvpn_server::error::AppError;
+use serde_json::Value;
+
+async fn respond(error: AppError) -> (StatusCode, Option<String>, Value) {
+    let response = error.into_response();
+    let status = response.status();
+    let retry_after = response
+        .headers()
+        .get(header::RETRY_AFTER)
+        .map(|v| v.to_str().unwrap().to_string());
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    (status, retry_after, serde_json::from_slice(&body).unwrap())
+}
+
+async fn assert_maps_to(error: AppError, status: StatusCode, code: &str) {
+    let (actual, _, body) = respond(error).await;
+    assert_eq!(actual, status, "{}", code);
+    assert_eq!(body["code"], code);
+    assert_eq!(body["success"], false);
+}
+
+#[tokio::test]
+async fn too_many_requests_is_429_with_retry_after() {
+    let (status, retry_after, body) = respond(AppError::TooManyRequests {
+        retry_after_secs: 7,
+    })
+    .await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(retry_after.as_deref(), Some("7"));
+    assert_eq!(body["code"], "rate_limited");
+    assert_eq!(body["error"], "Too many requests");
+}
+
+#[tokio::test]
+async fn service_busy_is_503_and_keeps_its_message() {
+    let (status, retry_after, body) = respond(AppError::ServiceBusy(
+        "Service is under maintenance".to_string(),
+    ))
+    .await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(retry_after, None);
+    assert_eq!(body["code"], "service_busy");
+    assert_eq!(body["error"], "Service is under maintenance");
+}
+
+#[tokio::test]
+async fn payload_too_large_is_413() {
+    assert_maps_to(
+        AppError::PayloadTooLarge,
+        StatusCode::PAYLOAD_TOO_LARGE,
+        "payload_too_large",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn conflict_is_409_and_keeps_its_message() {
+    let (status, _, body) = respond(AppError::Conflict("Token already used".to_string())).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(body["code"], "conflict");
+    assert_eq!(body["error"], "Token already used");
+}
+
+#[tokio::test]
+async fn existing_variants_keep_their_status_and_gain_a_code() {
+    let cases = [
+        (
+            AppError::AccountNotFound,
+            StatusCode::UNAUTHORIZED,
+            "account_not_found",
+        ),
+        (
+            AppError::AccountExpired,
+            StatusCode::FORBIDDEN,
+            "account_expired",
+        ),
+        (
+            AppError::Unauthorized,
+            StatusCode::UNAUTHORIZED,
+            "unauthorized",
+        ),
+        (
+            AppError::DeviceKeyMismatch,
+            StatusCode::UNAUTHORIZED,
+            "device_key_mismatch",
+        ),
+        (
+            AppError::BadRequest("Invalid location".to_string()),
+            StatusCode::BAD_REQUEST,
+            "bad_request",
+        ),
+        (
+            AppError::DeviceConflict,
+            StatusCode::CONFLICT,
+            "device_conflict",
+        ),
+        (
+            AppError::InviteRequired,
+            StatusCode::FORBIDDEN,
+            "invite_required",
+        ),
+        (AppError::PubKeyInUse, StatusCode::CONFLICT, "pubkey_in_use"),
+        (
+            AppError::GenerationThrottled {
+                global: true,
+                retry_after_secs: 60,
+            },
+            StatusCode::TOO_MANY_REQUESTS,
+            "generation_global_limited",
+        ),
+    ];
+    for (error, status, code) in cases {
+        assert_eq!(error.status(), status, "{}", code);
+        assert_eq!(error.code(), code);
+        assert_maps_to(error, status, code).await;
+    }
+}
+
+#[tokio::test]
+async fn internal_errors_hide_their_cause() {
+    let (status, _, body) = respond(AppError::Internal(anyhow::anyhow!("disk on fire"))).await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(body["code"], "internal");
+    assert_eq!(body["error"], "Internal server error");
+}
@@ -0,0 +1,108 @@
+use axum::{
+    http::{header, StatusCode},
+    response::IntoResponse,
+};
+use marinvpn_common::version::Version;
+use marinvpn_server::error::AppError;
+use marinvpn_server::handlers::auth::check_invite_code;
+use marinvpn_server::runtime::{GenerationThrottle, RuntimeConfig, RuntimeSettings};
+use std::net::IpAddr;
+
+fn settings(ip_burst: u32, global_burst: u32) -> RuntimeSettings {
+    RuntimeSettings {
+        rate_limit_replenish_secs: 6,
+        rate_limit_burst: 10,
+        maintenance_mode: false,
+        cors_origins: Vec::new(),
+        request_timeout_secs: 30,
+        cleanup_interval_secs: 3600,
+        session_max_age_secs: 86_400,
+        generate_ip_replenish_secs: 3600,
+        generate_ip_burst: ip_burst,
+        generate_global_replenish_secs: 3600,
+        generate_global_burst: global_burst,
+        min_client_version: Version::default(),
+    }
+}
+
+fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+#[test]
+fn per_ip_generation_limit_fires_before_global() {
+    let runtime = RuntimeConfig::new(settings(2, 5));
+
+    assert!(runtime.check_generation(ip("198.51.100.1")).is_ok());
+    assert!(runtime.check_generation(ip("198.51.100.1")).is_ok());
+    assert!(matches!(
+        runtime.check_generation(ip("198.51.100.1")),
+        Err(GenerationThrottle::PerIp(_))
+    ));
+
+    assert!(runtime.check_generation(ip("198.51.100.2")).is_ok());
+    assert!(runtime.check_generation(ip("198.51.100.2")).is_ok());
+    assert!(runtime.check_generation(ip("198.51.100.3")).is_ok());
+    assert!(matches!(
+        runtime.check_generation(ip("198.51.100.4")),
+        Err(GenerationThrottle::Global(_))
+    ));
+}
+
+#[test]
+fn per_ip_rejections_do_not_spend_global_budget() {
+    let runtime = RuntimeConfig::new(settings(1, 2));
+
+    assert!(runtime.check_generation(ip("198.51.100.10")).is_ok());
+    for _ in 0..10 {
+        assert!(matches!(
+            runtime.check_generation(ip("198.51.100.10")),
+            Err(GenerationThrottle::PerIp(_))
+        ));
+    }
+    assert!(runtime.check_generation(ip("198.51.100.11")).is_ok());
+}
+
+#[test]
+fn generation_limit_is_separate_from_api_limit() {
+    let runtime = RuntimeConfig::new(settings(1, 5));
+
+    assert!(runtime.check_generation(ip("198.51.100.20")).is_ok());
+    assert!(runtime.check_generation(ip("198.51.100.20")).is_err());
+    assert!(runtime.check_rate_limit(ip("198.51.100.20")).is_ok());
+}
+
+#[tokio::test]
+async fn throttled_generation_returns_code_and_retry_after() {
+    let response = AppError::GenerationThrottled {
+        global: false,
+        retry_after_secs: 42,
+    }
+    .into_response();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()[header::RETRY_AFTER], "42");
+
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["code"], "generation_ip_limited");
+    assert_eq!(json["success"], false);
+}
+
+#[test]
+fn invite_code_is_only_required_when_configured() {
+    assert!(check_invite_code(&[], None).is_ok());
+
+    let codes = vec!["spring-2026".to_string(), "friends".to_string()];
+    assert!(check_invite_code(&codes, Some("friends")).is_ok());
+    assert!(check_invite_code(&codes, Some(" spring-2026 ")).is_ok());
+    assert!(matches!(
+        check_invite_code(&codes, None),
+        Err(AppError::InviteRequired)
+    ));
+    assert!(matches!(
+        check_invite_code(&codes, Some("friend")),
+        Err(AppError::InviteRequired)
+    ));
+}
@@ -0,0 +1,51 @@
+use axum::Json;
+use marinvpn_common::LogLevelRequest;
+use marinvpn_server::error::AppError;
+use marinvpn_server::handlers::admin::apply_log_level;
+use marinvpn_server::log_filter::LogFilter;
+use tracing::Level;
+use tracing_subscriber::layer::SubscriberExt;
+
+fn request(directives: &str) -> LogLevelRequest {
+    LogLevelRequest {
+        directives: directives.to_string(),
+    }
+}
+
+#[test]
+fn valid_directive_is_applied_and_can_be_reverted() {
+    let (layer, filter) = LogFilter::new("marinvpn_server=info").unwrap();
+    let subscriber = tracing_subscriber::registry().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        assert!(!tracing::enabled!(target: "marinvpn_server::db", Level::DEBUG));
+
+        let Json(response) = apply_log_level(&filter, request("marinvpn_server=debug")).unwrap();
+        assert_eq!(response.previous, "marinvpn_server=info");
+        assert_eq!(filter.current(), "marinvpn_server=debug");
+        assert!(tracing::enabled!(target: "marinvpn_server::db", Level::DEBUG));
+
+        let Json(reverted) = apply_log_level(&filter, request(&response.previous)).unwrap();
+        assert_eq!(reverted.directives, "marinvpn_server=info");
+        assert!(!tracing::enabled!(target: "marinvpn_server::db", Level::DEBUG));
+    });
+}
+
+#[test]
+fn invalid_directive_is_rejected_and_filter_kept() {
+    let (layer, filter) = LogFilter::new("marinvpn_server=info").unwrap();
+    let subscriber = tracing_subscriber::registry().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        for bad in ["marinvpn_server=loud", "", "  "] {
+            assert!(
+                matches!(
+                    apply_log_level(&filter, request(bad)),
+                    Err(AppError::BadRequest(_))
+                ),
+                "{:?} should be rejected",
+                bad
+            );
+        }
+        assert_eq!(filter.current(), "marinvpn_server=info");
+        assert!(tracing::enabled!(target: "marinvpn_server", Level::INFO));
+    });
+}
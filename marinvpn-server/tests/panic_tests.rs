@@ -0,0 +1,252 @@
+use axum::{
+    body::Body,
+    extract::State,
+    http::{Request, StatusCode},
+    Json, Router,
+};
+use marinvpn_common::{AccountPanicRequest, PanicRequest, PanicResponse, PANIC_CONFIRMATION};
+use marinvpn_server::deadline::Deadline;
+use marinvpn_server::handlers::auth::{panic_account, AuthUser};
+use marinvpn_server::services::db::{Database, PeerOwner};
+use marinvpn_server::{api_routes, AppState};
+use std::sync::Arc;
+use tower::util::ServiceExt;
+
+const PANIC_KEY: &str = "panic-test-key";
+
+// Both wipes touch the shared peer table, so they must not overlap.
+static DB_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+async fn setup_state() -> Option<Arc<AppState>> {
+    let db_url = match std::env::var("TEST_DATABASE_URL") {
+        Ok(url) => url,
+        Err(_) => {
+            eprintln!("TEST_DATABASE_URL not set; skipping integration test.");
+            return None;
+        }
+    };
+    let db = Database::new(&db_url, "test_salt")
+        .await
+        .expect("Failed to create test DB");
+
+    let mut settings = marinvpn_server::config::Settings::new().unwrap();
+    settings.database.url = db_url.to_string();
+    settings.auth.panic_key = PANIC_KEY.to_string();
+    // Short enough that an earlier run of this test has expired.
+    settings.auth.panic_cooldown_secs = 2;
+
+    let runtime = Arc::new(marinvpn_server::runtime::RuntimeConfig::new(
+        marinvpn_server::runtime::RuntimeSettings::from_server(&settings.server),
+    ));
+    Some(Arc::new(AppState {
+        db,
+        settings,
+        vpn: marinvpn_server::services::vpn::VpnOrchestrator::new("wg0".to_string()),
+        signer: marinvpn_server::services::auth::BlindSigner::new(),
+        support_key: marinvpn_server::services::auth::SupportKey::new(),
+        runtime,
+    }))
+}
+
+async fn panic(app: &Router, key: Option<&str>, confirm: Option<&str>) -> (StatusCode, Vec<u8>) {
+    let mut request = Request::builder().method("POST").uri("/vpn/panic");
+    if let Some(key) = key {
+        request = request.header("X-Panic-Key", key);
+    }
+    let body = match confirm {
+        Some(confirm) => {
+            request = request.header("Content-Type", "application/json");
+            let payload = PanicRequest {
+                confirm: confirm.to_string(),
+            };
+            Body::from(serde_json::to_vec(&payload).unwrap())
+        }
+        None => Body::empty(),
+    };
+
+    let response = app
+        .clone()
+        .oneshot(request.body(body).unwrap())
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    (status, body.to_vec())
+}
+
+#[tokio::test]
+async fn panic_requires_key_and_confirmation_then_cools_down() {
+    let Some(state) = setup_state().await else {
+        return;
+    };
+    let _guard = DB_LOCK.lock().await;
+    let app = api_routes().with_state(state);
+
+    let (status, _) = panic(&app, None, Some(PANIC_CONFIRMATION)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = panic(&app, Some("wrong-key"), Some(PANIC_CONFIRMATION)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, _) = panic(&app, Some(PANIC_KEY), None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = panic(&app, Some(PANIC_KEY), Some("yes")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (status, body) = panic(&app, Some(PANIC_KEY), Some(PANIC_CONFIRMATION)).await;
+    assert_eq!(status, StatusCode::OK);
+    let first: PanicResponse = serde_json::from_slice(&body).unwrap();
+    assert!(first.wiped);
+
+    // A double-submit is acknowledged but does not wipe again.
+    let (status, body) = panic(&app, Some(PANIC_KEY), Some(PANIC_CONFIRMATION)).await;
+    assert_eq!(status, StatusCode::OK);
+    let repeat: PanicResponse = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        repeat,
+        PanicResponse {
+            wiped: false,
+            wiped_at: first.wiped_at,
+        }
+    );
+}
+
+const DEVICES: [&str; 2] = ["Swift Falcon", "Quiet Otter"];
+
+async fn has_device(db: &Database, account: &str, name: &str) -> bool {
+    db.get_device_pubkey(account, name).await.unwrap().is_some()
+}
+
+fn session_token(account: &str) -> String {
+    format!("refresh-{}", account)
+}
+
+/// Whether the seeded refresh token still works, by rotating it in place.
+async fn has_session(db: &Database, account: &str) -> bool {
+    let expires_at = chrono::Utc::now().timestamp() + 3600;
+    let token = session_token(account);
+    db.rotate_refresh_token(account, DEVICES[0], &token, &token, expires_at)
+        .await
+        .unwrap()
+}
+
+fn unique(prefix: &str, len: usize) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+    let mut n = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let mut out = prefix.to_string();
+    while out.len() < len {
+        out.push(ALPHABET[(n % 32) as usize] as char);
+        n = n / 32 + 7;
+    }
+    out
+}
+
+/// Sets up an account with two devices, a refresh token and a peer.
+/// Returns the account number and the peer's public key.
+async fn seeded_account(state: &AppState, prefix: &str) -> (String, String) {
+    let raw = unique(prefix, 16);
+    let account = format!(
+        "{} {} {} {}",
+        &raw[0..4],
+        &raw[4..8],
+        &raw[8..12],
+        &raw[12..16]
+    );
+    state.db.create_account(&account, 30).await.unwrap();
+    for device in DEVICES {
+        state
+            .db
+            .add_device(&account, device, Some("attestation-key"))
+            .await
+            .unwrap();
+    }
+    state
+        .db
+        .create_session(
+            &account,
+            DEVICES[0],
+            &session_token(&account),
+            chrono::Utc::now().timestamp() + 3600,
+            1,
+        )
+        .await
+        .unwrap();
+
+    let pub_key = format!("{}=", unique(prefix, 43));
+    state
+        .db
+        .get_or_create_peer(
+            &pub_key,
+            PeerOwner::Account(&account),
+            Deadline::unbounded(),
+        )
+        .await
+        .unwrap();
+    (account, pub_key)
+}
+
+#[tokio::test]
+async fn account_panic_leaves_other_accounts_intact() {
+    let Some(state) = setup_state().await else {
+        return;
+    };
+    let _guard = DB_LOCK.lock().await;
+
+    let (victim, victim_key) = seeded_account(&state, "VV").await;
+    let (other, other_key) = seeded_account(&state, "WW").await;
+
+    let Json(response) = panic_account(
+        State(state.clone()),
+        AuthUser {
+            account_number: victim.clone(),
+            device_name: "Swift Falcon".to_string(),
+        },
+        Json(AccountPanicRequest {
+            account_number: victim.clone(),
+        }),
+    )
+    .await
+    .unwrap();
+    assert_eq!(response.devices_removed, 2);
+    assert_eq!(response.peers_removed, 1);
+
+    let db = &state.db;
+    for device in DEVICES {
+        assert!(!has_device(db, &victim, device).await, "{}", device);
+    }
+    assert!(!has_session(db, &victim).await);
+    assert!(!db.release_peer(&victim_key).await.unwrap());
+
+    for device in DEVICES {
+        assert!(has_device(db, &other, device).await, "{}", device);
+    }
+    assert!(has_session(db, &other).await);
+    assert!(db.release_peer(&other_key).await.unwrap());
+}
+
+#[tokio::test]
+async fn account_panic_rejects_another_accounts_token() {
+    let Some(state) = setup_state().await else {
+        return;
+    };
+
+    let result = panic_account(
+        State(state),
+        AuthUser {
+            account_number: "ABCD E2GH JK7M NPQR".to_string(),
+            device_name: "Swift Falcon".to_string(),
+        },
+        Json(AccountPanicRequest {
+            account_number: "ABCD E2GH JK7M NPQS".to_string(),
+        }),
+    )
+    .await;
+    assert!(matches!(
+        result,
+        Err(marinvpn_server::error::AppError::Unauthorized)
+    ));
+}
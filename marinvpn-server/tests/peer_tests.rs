@@ -0,0 +1,297 @@
+use marinvpn_server::deadline::Deadline;
+use marinvpn_server::error::AppError;
+use marinvpn_server::services::db::{peer_owner_tag, Database, PeerOwner};
+use marinvpn_server::services::peer_pool::PeerPool;
+
+const KEY: &str = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+const OTHER_KEY: &str = "BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBA=";
+
+// The free list is shared, so allocation tests run one at a time.
+static DB_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+async fn setup_db() -> Option<Database> {
+    let db_url = match std::env::var("TEST_DATABASE_URL") {
+        Ok(url) => url,
+        Err(_) => {
+            eprintln!("TEST_DATABASE_URL not set; skipping integration test.");
+            return None;
+        }
+    };
+    Some(
+        Database::new(&db_url, "test_salt")
+            .await
+            .expect("Failed to create test DB"),
+    )
+}
+
+fn unique_key(tag: &str) -> String {
+    use base64::{engine::general_purpose, Engine as _};
+    let mut bytes = [0u8; 32];
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos()
+        .to_le_bytes();
+    bytes[..16].copy_from_slice(&nanos);
+    bytes[16..16 + tag.len().min(16)].copy_from_slice(&tag.as_bytes()[..tag.len().min(16)]);
+    general_purpose::STANDARD.encode(bytes)
+}
+
+#[test]
+fn owner_tags_separate_accounts_and_tokens() {
+    let account = peer_owner_tag("salt", KEY, PeerOwner::Account("ABCD E2GH JK7M NPQR"));
+    assert_eq!(
+        account,
+        peer_owner_tag("salt", KEY, PeerOwner::Account("abcde2ghjk7mnpqr"))
+    );
+    assert_ne!(
+        account,
+        peer_owner_tag("salt", KEY, PeerOwner::Account("ABCD E2GH JK7M NPQS"))
+    );
+    assert_ne!(
+        account,
+        peer_owner_tag("salt", KEY, PeerOwner::Anonymous("ABCDE2GHJK7MNPQR"))
+    );
+    assert_ne!(
+        account,
+        peer_owner_tag("other", KEY, PeerOwner::Account("ABCD E2GH JK7M NPQR"))
+    );
+    assert_ne!(
+        account,
+        peer_owner_tag("salt", OTHER_KEY, PeerOwner::Account("ABCD E2GH JK7M NPQR"))
+    );
+}
+
+#[test]
+fn default_pool_matches_legacy_mapping() {
+    let pool = PeerPool::default();
+    assert_eq!(pool.capacity(), 16_580_608);
+    assert_eq!(pool.address(0), "10.0.0.2/32");
+    assert_eq!(pool.address(252), "10.0.0.254/32");
+    assert_eq!(pool.address(253), "10.0.1.2/32");
+    assert_eq!(pool.address(253 * 256), "10.1.0.2/32");
+    assert_eq!(pool.address(16_580_608), "10.0.0.2/32");
+}
+
+#[test]
+fn custom_prefix_stays_inside_the_network() {
+    let pool = PeerPool::new("100.64.0.0/22", 1000).unwrap();
+    assert_eq!(pool.capacity(), 4 * 253);
+    assert_eq!(pool.address(0), "100.64.0.2/32");
+    assert_eq!(pool.address(253), "100.64.1.2/32");
+    assert_eq!(pool.address(4 * 253 - 1), "100.64.3.254/32");
+    assert_eq!(pool.address(4 * 253), "100.64.0.2/32");
+
+    let small = PeerPool::new("172.16.5.16/28", 10).unwrap();
+    assert_eq!(small.capacity(), 13);
+    assert_eq!(small.address(0), "172.16.5.18/32");
+    assert_eq!(small.address(12), "172.16.5.30/32");
+    assert_eq!(small.address(13), "172.16.5.18/32");
+}
+
+#[test]
+fn undersized_prefix_is_rejected() {
+    assert!(PeerPool::new("172.16.5.16/28", 14).is_err());
+    assert!(PeerPool::new("100.64.0.0/24", 254).is_err());
+    assert!(PeerPool::new("192.168.1.1/32", 1).is_err());
+    assert!(PeerPool::new("not-a-network", 1).is_err());
+}
+
+#[tokio::test]
+async fn same_owner_gets_same_ip_for_repeat_key() {
+    let Some(db) = setup_db().await else {
+        return;
+    };
+    let _guard = DB_LOCK.lock().await;
+    let key = unique_key("repeat");
+    let owner = PeerOwner::Account("ABCD E2GH JK7M NPQR");
+
+    let first = db
+        .get_or_create_peer(&key, owner, Deadline::unbounded())
+        .await
+        .unwrap();
+    let second = db
+        .get_or_create_peer(&key, owner, Deadline::unbounded())
+        .await
+        .unwrap();
+    assert_eq!(first, second);
+}
+
+#[tokio::test]
+async fn reused_key_from_another_owner_is_rejected() {
+    let Some(db) = setup_db().await else {
+        return;
+    };
+    let _guard = DB_LOCK.lock().await;
+    let key = unique_key("reuse");
+
+    db.get_or_create_peer(
+        &key,
+        PeerOwner::Account("ABCD E2GH JK7M NPQR"),
+        Deadline::unbounded(),
+    )
+    .await
+    .unwrap();
+
+    for other in [
+        PeerOwner::Account("WXYZ E2GH JK7M NPQR"),
+        PeerOwner::Anonymous("blind-token-message"),
+    ] {
+        assert!(matches!(
+            db.get_or_create_peer(&key, other, Deadline::unbounded())
+                .await,
+            Err(AppError::PubKeyInUse)
+        ));
+    }
+}
+
+#[tokio::test]
+async fn distinct_keys_never_share_an_ip() {
+    let Some(db) = setup_db().await else {
+        return;
+    };
+    let _guard = DB_LOCK.lock().await;
+    let a = db
+        .get_or_create_peer(
+            &unique_key("a"),
+            PeerOwner::Anonymous("token-a"),
+            Deadline::unbounded(),
+        )
+        .await
+        .unwrap();
+    let b = db
+        .get_or_create_peer(
+            &unique_key("b"),
+            PeerOwner::Anonymous("token-b"),
+            Deadline::unbounded(),
+        )
+        .await
+        .unwrap();
+    assert_ne!(a, b);
+}
+
+#[tokio::test]
+async fn released_ips_are_reallocated_before_fresh_ones() {
+    let Some(db) = setup_db().await else {
+        return;
+    };
+    let _guard = DB_LOCK.lock().await;
+
+    let key_a = unique_key("cycle-a");
+    let key_b = unique_key("cycle-b");
+    let ip_a = db
+        .get_or_create_peer(
+            &key_a,
+            PeerOwner::Anonymous("cycle-a"),
+            Deadline::unbounded(),
+        )
+        .await
+        .unwrap();
+    let ip_b = db
+        .get_or_create_peer(
+            &key_b,
+            PeerOwner::Anonymous("cycle-b"),
+            Deadline::unbounded(),
+        )
+        .await
+        .unwrap();
+
+    assert!(db.release_peer(&key_a).await.unwrap());
+    assert!(db.release_peer(&key_b).await.unwrap());
+    assert!(!db.release_peer(&key_a).await.unwrap());
+
+    let mut pending = vec![ip_a, ip_b];
+    for round in 0..64 {
+        let ip = db
+            .get_or_create_peer(
+                &unique_key(&format!("cycle-{}", round)),
+                PeerOwner::Anonymous("cycle-next"),
+                Deadline::unbounded(),
+            )
+            .await
+            .unwrap();
+        pending.retain(|freed| freed != &ip);
+        if pending.is_empty() {
+            break;
+        }
+    }
+    assert!(pending.is_empty(), "never reallocated {:?}", pending);
+}
+
+#[tokio::test]
+async fn allocation_cut_off_by_the_deadline_is_rolled_back() {
+    let Some(db) = setup_db().await else {
+        return;
+    };
+    let _guard = DB_LOCK.lock().await;
+    let raw = sqlx::PgPool::connect(&std::env::var("TEST_DATABASE_URL").unwrap())
+        .await
+        .unwrap();
+
+    // Put an address at the head of the free list, then have another
+    // transaction hold it so the allocation stalls after inserting its row.
+    let ip = "10.255.255.254/32";
+    sqlx::query("INSERT INTO free_ips (ip, freed_at) VALUES ($1, 0) ON CONFLICT (ip) DO UPDATE SET freed_at = 0")
+        .bind(ip)
+        .execute(&raw)
+        .await
+        .unwrap();
+    let mut blocker = raw.begin().await.unwrap();
+    sqlx::query("INSERT INTO peers (pub_key, assigned_ip, registered_at) VALUES ($1, $2, 0)")
+        .bind(unique_key("blocker"))
+        .bind(ip)
+        .execute(&mut *blocker)
+        .await
+        .unwrap();
+
+    let key = unique_key("deadline");
+    let started = std::time::Instant::now();
+    let result = db
+        .get_or_create_peer(
+            &key,
+            PeerOwner::Anonymous("deadline"),
+            Deadline::after(std::time::Duration::from_millis(300)),
+        )
+        .await;
+    assert!(matches!(result, Err(AppError::DeadlineExceeded)));
+    assert!(started.elapsed() < std::time::Duration::from_secs(2));
+    blocker.rollback().await.unwrap();
+
+    // Locking the address waits for the abandoned transaction to finish; it
+    // is only still listed if that transaction rolled back.
+    let mut check = raw.begin().await.unwrap();
+    let freed: Option<String> = tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        sqlx::query_scalar("SELECT ip FROM free_ips WHERE ip = $1 FOR UPDATE")
+            .bind(ip)
+            .fetch_optional(&mut *check),
+    )
+    .await
+    .expect("abandoned allocation never finished")
+    .unwrap();
+    check.rollback().await.unwrap();
+    assert_eq!(freed.as_deref(), Some(ip));
+    let rows: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM peers WHERE pub_key = $1")
+        .bind(&key)
+        .fetch_one(&raw)
+        .await
+        .unwrap();
+    assert_eq!(rows, 0);
+
+    // With time to spare the same key gets the address that was put back.
+    let assigned = db
+        .get_or_create_peer(
+            &key,
+            PeerOwner::Anonymous("deadline"),
+            Deadline::unbounded(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(assigned, ip);
+    assert!(db.release_peer(&key).await.unwrap());
+    sqlx::query("DELETE FROM free_ips WHERE ip = $1")
+        .bind(ip)
+        .execute(&raw)
+        .await
+        .unwrap();
+}
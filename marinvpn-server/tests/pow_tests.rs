@@ -0,0 +1,102 @@
+use marinvpn_common::pow::{meets_difficulty, solve};
+use marinvpn_common::PowSolution;
+use marinvpn_server::error::AppError;
+use marinvpn_server::services::pow::PowVerifier;
+
+const SECRET: &str = "pow-test-secret-with-enough-length-0123456789";
+const TOKEN: &str = "c29tZS1ibGluZC1zaWduZWQtdG9rZW4=";
+const NOW: i64 = 1_760_000_000;
+
+fn solved(verifier: &PowVerifier, token: &str) -> PowSolution {
+    let challenge = verifier.issue(NOW);
+    PowSolution {
+        nonce: solve(&challenge.challenge, token, challenge.difficulty),
+        challenge: challenge.challenge,
+    }
+}
+
+fn is_bad_request(result: Result<(), AppError>, needle: &str) -> bool {
+    matches!(result, Err(AppError::BadRequest(msg)) if msg.contains(needle))
+}
+
+#[test]
+fn valid_solution_is_accepted() {
+    let verifier = PowVerifier::new(SECRET, 8, 120);
+    let solution = solved(&verifier, TOKEN);
+    assert!(verifier.verify(&solution, TOKEN, NOW + 10).is_ok());
+}
+
+#[test]
+fn insufficient_work_is_rejected() {
+    let verifier = PowVerifier::new(SECRET, 12, 120);
+    let challenge = verifier.issue(NOW);
+    let nonce = (0..u64::MAX)
+        .find(|n| !meets_difficulty(&challenge.challenge, TOKEN, *n, 12))
+        .unwrap();
+    let solution = PowSolution {
+        challenge: challenge.challenge,
+        nonce,
+    };
+    assert!(is_bad_request(
+        verifier.verify(&solution, TOKEN, NOW),
+        "Invalid proof of work"
+    ));
+}
+
+#[test]
+fn solution_is_bound_to_the_token() {
+    let verifier = PowVerifier::new(SECRET, 12, 120);
+    let solution = solved(&verifier, TOKEN);
+    // One in 4096 chance the same nonce also fits another token; pick a
+    // token where it does not so the test is deterministic.
+    let other = (0..)
+        .map(|i| format!("other-token-{}", i))
+        .find(|t| !meets_difficulty(&solution.challenge, t, solution.nonce, 12))
+        .unwrap();
+    assert!(verifier.verify(&solution, &other, NOW).is_err());
+}
+
+#[test]
+fn forged_challenge_is_rejected() {
+    let verifier = PowVerifier::new(SECRET, 4, 120);
+    let other_server = PowVerifier::new("a-different-secret-entirely-0123456789", 4, 120);
+    let solution = solved(&other_server, TOKEN);
+    assert!(is_bad_request(
+        verifier.verify(&solution, TOKEN, NOW),
+        "Invalid proof of work"
+    ));
+
+    // Extending the expiry invalidates the MAC.
+    let mut solution = solved(&verifier, TOKEN);
+    solution.challenge =
+        solution
+            .challenge
+            .replacen(&(NOW + 120).to_string(), &(NOW + 99_999).to_string(), 1);
+    assert!(verifier.verify(&solution, TOKEN, NOW).is_err());
+}
+
+#[test]
+fn expired_challenge_is_rejected() {
+    let verifier = PowVerifier::new(SECRET, 4, 120);
+    let solution = solved(&verifier, TOKEN);
+    assert!(is_bad_request(
+        verifier.verify(&solution, TOKEN, NOW + 121),
+        "expired"
+    ));
+}
+
+#[test]
+fn malformed_challenge_is_rejected() {
+    let verifier = PowVerifier::new(SECRET, 4, 120);
+    for challenge in ["", "no-dots", "1.2.zz", "..."] {
+        let solution = PowSolution {
+            challenge: challenge.to_string(),
+            nonce: 0,
+        };
+        assert!(
+            verifier.verify(&solution, TOKEN, NOW).is_err(),
+            "{}",
+            challenge
+        );
+    }
+}
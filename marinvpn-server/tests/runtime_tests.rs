@@ -0,0 +1,353 @@
+use axum::{
+    body::Body,
+    extract::connect_info::ConnectInfo,
+    http::{header, Request, StatusCode},
+    middleware::from_fn_with_state,
+    routing::get,
+    Router,
+};
+use marinvpn_common::version::{Version, CLIENT_VERSION_HEADER};
+use marinvpn_server::runtime::{self, RuntimeConfig, RuntimeSettings};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tower::util::ServiceExt;
+
+fn settings(burst: u32) -> RuntimeSettings {
+    RuntimeSettings {
+        rate_limit_replenish_secs: 60,
+        rate_limit_burst: burst,
+        maintenance_mode: false,
+        cors_origins: Vec::new(),
+        request_timeout_secs: 30,
+        cleanup_interval_secs: 3600,
+        session_max_age_secs: 86_400,
+        generate_ip_replenish_secs: 3600,
+        generate_ip_burst: 2,
+        generate_global_replenish_secs: 3600,
+        generate_global_burst: 5,
+        min_client_version: Version::default(),
+    }
+}
+
+fn app(runtime: &Arc<RuntimeConfig>) -> Router {
+    Router::new()
+        .route("/api/v1/ping", get(|| async { "pong" }))
+        .route("/api/v1/version", get(|| async { "0.1.0" }))
+        .route("/api/v1/admin/maintenance", get(|| async { "off" }))
+        .route("/api/v1/vpn/panic", get(|| async { "panicked" }))
+        .route("/api/v1/vpn/config", get(|| async { "config" }))
+        .route(
+            "/api/v1/slow",
+            get(|| async {
+                tokio::time::sleep(Duration::from_secs(3)).await;
+                "late"
+            }),
+        )
+        .layer(from_fn_with_state(
+            runtime.clone(),
+            runtime::enforce_maintenance,
+        ))
+        .layer(from_fn_with_state(
+            runtime.clone(),
+            runtime::enforce_rate_limit,
+        ))
+        .layer(from_fn_with_state(
+            runtime.clone(),
+            runtime::enforce_min_client_version,
+        ))
+        .layer(from_fn_with_state(
+            runtime.clone(),
+            runtime::enforce_timeout,
+        ))
+}
+
+async fn call(app: &Router, path: &str, peer: &str) -> axum::response::Response {
+    let addr: SocketAddr = peer.parse().unwrap();
+    let mut req = Request::builder().uri(path).body(Body::empty()).unwrap();
+    req.extensions_mut().insert(ConnectInfo(addr));
+    app.clone().oneshot(req).await.unwrap()
+}
+
+async fn allowed_before_throttle(app: &Router, peer: &str) -> usize {
+    let mut allowed = 0;
+    for _ in 0..50 {
+        if call(app, "/api/v1/ping", peer).await.status() == StatusCode::TOO_MANY_REQUESTS {
+            break;
+        }
+        allowed += 1;
+    }
+    allowed
+}
+
+#[tokio::test]
+async fn reload_changes_effective_rate_limit() {
+    let runtime = Arc::new(RuntimeConfig::new(settings(2)));
+    let app = app(&runtime);
+    assert_eq!(allowed_before_throttle(&app, "198.51.100.1:4000").await, 2);
+
+    runtime.apply(settings(5));
+    assert_eq!(allowed_before_throttle(&app, "198.51.100.1:4000").await, 5);
+
+    runtime.apply(settings(1));
+    assert_eq!(allowed_before_throttle(&app, "198.51.100.1:4000").await, 1);
+}
+
+#[tokio::test]
+async fn throttled_response_carries_retry_after() {
+    let runtime = Arc::new(RuntimeConfig::new(settings(1)));
+    let app = app(&runtime);
+    assert_eq!(
+        call(&app, "/api/v1/ping", "198.51.100.2:4000")
+            .await
+            .status(),
+        StatusCode::OK
+    );
+
+    let throttled = call(&app, "/api/v1/ping", "198.51.100.2:4000").await;
+    assert_eq!(throttled.status(), StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: u64 = throttled.headers()[header::RETRY_AFTER]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!((1..=60).contains(&retry_after), "{}", retry_after);
+}
+
+fn header(response: &axum::response::Response, name: &str) -> u64 {
+    response.headers()[name].to_str().unwrap().parse().unwrap()
+}
+
+#[tokio::test]
+async fn responses_report_the_remaining_budget() {
+    let runtime = Arc::new(RuntimeConfig::new(settings(3)));
+    let app = app(&runtime);
+    for remaining in [2, 1, 0] {
+        let response = call(&app, "/api/v1/ping", "198.51.100.6:4000").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header(&response, runtime::RATE_LIMIT_LIMIT), 3);
+        assert_eq!(header(&response, runtime::RATE_LIMIT_REMAINING), remaining);
+        assert!(response.headers().get(header::RETRY_AFTER).is_none());
+    }
+
+    let throttled = call(&app, "/api/v1/ping", "198.51.100.6:4000").await;
+    assert_eq!(throttled.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(header(&throttled, runtime::RATE_LIMIT_LIMIT), 3);
+    assert_eq!(header(&throttled, runtime::RATE_LIMIT_REMAINING), 0);
+    assert!((1..=60).contains(&header(&throttled, header::RETRY_AFTER.as_str())));
+}
+
+#[tokio::test]
+async fn rate_limit_is_tracked_per_client_ip() {
+    let runtime = Arc::new(RuntimeConfig::new(settings(1)));
+    let app = app(&runtime);
+    assert_eq!(allowed_before_throttle(&app, "198.51.100.3:4000").await, 1);
+    assert_eq!(allowed_before_throttle(&app, "198.51.100.4:4000").await, 1);
+}
+
+#[tokio::test]
+async fn unrelated_reload_keeps_limiter_state() {
+    let runtime = Arc::new(RuntimeConfig::new(settings(1)));
+    let app = app(&runtime);
+    assert_eq!(allowed_before_throttle(&app, "198.51.100.5:4000").await, 1);
+
+    let mut next = settings(1);
+    next.cors_origins = vec!["https://marinvpn.example".to_string()];
+    runtime.apply(next);
+    assert_eq!(allowed_before_throttle(&app, "198.51.100.5:4000").await, 0);
+}
+
+#[tokio::test]
+async fn maintenance_flag_toggles_without_rebuilding_router() {
+    let runtime = Arc::new(RuntimeConfig::new(settings(10)));
+    let app = app(&runtime);
+
+    let mut next = settings(10);
+    next.maintenance_mode = true;
+    runtime.apply(next);
+    assert_eq!(
+        call(&app, "/api/v1/ping", "198.51.100.6:4000")
+            .await
+            .status(),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+
+    runtime.apply(settings(10));
+    assert_eq!(
+        call(&app, "/api/v1/ping", "198.51.100.6:4000")
+            .await
+            .status(),
+        StatusCode::OK
+    );
+}
+
+#[tokio::test]
+async fn maintenance_leaves_public_and_operator_paths_open() {
+    let mut current = settings(10);
+    current.maintenance_mode = true;
+    let runtime = Arc::new(RuntimeConfig::new(current));
+    let app = app(&runtime);
+
+    for path in [
+        "/api/v1/version",
+        "/api/v1/admin/maintenance",
+        "/api/v1/vpn/panic",
+    ] {
+        assert_eq!(
+            call(&app, path, "198.51.100.9:4000").await.status(),
+            StatusCode::OK,
+            "{}",
+            path
+        );
+    }
+    assert_eq!(
+        call(&app, "/api/v1/ping", "198.51.100.9:4000")
+            .await
+            .status(),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+}
+
+#[tokio::test]
+async fn draining_refuses_new_tunnels_only() {
+    let runtime = Arc::new(RuntimeConfig::new(settings(10)));
+    let app = app(&runtime);
+
+    assert!(!runtime.set_draining(true));
+    assert_eq!(
+        call(&app, "/api/v1/vpn/config", "198.51.100.10:4000")
+            .await
+            .status(),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+    assert_eq!(
+        call(&app, "/api/v1/ping", "198.51.100.10:4000")
+            .await
+            .status(),
+        StatusCode::OK
+    );
+
+    // A reload replaces the settings but must not end the drain.
+    runtime.apply(settings(10));
+    assert!(runtime.draining());
+
+    assert!(runtime.set_draining(false));
+    assert_eq!(
+        call(&app, "/api/v1/vpn/config", "198.51.100.10:4000")
+            .await
+            .status(),
+        StatusCode::OK
+    );
+}
+
+#[tokio::test]
+async fn reloaded_timeout_applies_to_next_request() {
+    let runtime = Arc::new(RuntimeConfig::new(settings(10)));
+    let app = app(&runtime);
+
+    let mut next = settings(10);
+    next.request_timeout_secs = 1;
+    runtime.apply(next);
+    assert_eq!(
+        call(&app, "/api/v1/slow", "198.51.100.7:4000")
+            .await
+            .status(),
+        StatusCode::REQUEST_TIMEOUT
+    );
+}
+
+async fn call_as(app: &Router, path: &str, version: Option<&str>) -> axum::response::Response {
+    let mut req = Request::builder().uri(path);
+    if let Some(version) = version {
+        req = req.header(CLIENT_VERSION_HEADER, version);
+    }
+    let mut req = req.body(Body::empty()).unwrap();
+    req.extensions_mut().insert(ConnectInfo(
+        "198.51.100.8:4000".parse::<SocketAddr>().unwrap(),
+    ));
+    app.clone().oneshot(req).await.unwrap()
+}
+
+#[tokio::test]
+async fn clients_below_the_minimum_version_are_turned_away() {
+    let mut current = settings(50);
+    current.min_client_version = Version::parse("0.3.0").unwrap();
+    let runtime = Arc::new(RuntimeConfig::new(current));
+    let app = app(&runtime);
+
+    for old in [Some("0.2.9"), Some("not a version"), None] {
+        let response = call_as(&app, "/api/v1/ping", old).await;
+        assert_eq!(response.status(), StatusCode::UPGRADE_REQUIRED, "{:?}", old);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["code"], "client_too_old");
+        assert!(body["error"].as_str().unwrap().contains("0.3.0"));
+    }
+    for current in ["0.3.0", "0.3.1-beta.2", "1.0.0"] {
+        let response = call_as(&app, "/api/v1/ping", Some(current)).await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", current);
+    }
+
+    // An outdated app can still find out what it has to update to.
+    let response = call_as(&app, "/api/v1/version", Some("0.1.0")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn lowered_minimum_version_applies_to_next_request() {
+    let mut current = settings(50);
+    current.min_client_version = Version::parse("0.3.0").unwrap();
+    let runtime = Arc::new(RuntimeConfig::new(current));
+    let app = app(&runtime);
+    assert_eq!(
+        call_as(&app, "/api/v1/ping", Some("0.2.0")).await.status(),
+        StatusCode::UPGRADE_REQUIRED
+    );
+
+    runtime.apply(settings(50));
+    assert_eq!(
+        call_as(&app, "/api/v1/ping", Some("0.2.0")).await.status(),
+        StatusCode::OK
+    );
+}
+
+#[test]
+fn cors_allow_list_is_exact_and_empty_means_any() {
+    let mut current = settings(10);
+    assert!(current.allows_origin("https://anything.example"));
+
+    current.cors_origins = vec!["https://marinvpn.example".to_string()];
+    assert!(current.allows_origin("https://marinvpn.example"));
+    assert!(!current.allows_origin("https://marinvpn.example.evil"));
+}
+
+#[test]
+fn reload_from_env_overrides_set_values_and_ignores_bad_ones() {
+    std::env::set_var("APP__SERVER__RATE_LIMIT_BURST", "25");
+    std::env::set_var("APP__SERVER__MAINTENANCE_MODE", "true");
+    std::env::set_var(
+        "APP__SERVER__CORS_ORIGINS",
+        "https://a.example, https://b.example",
+    );
+    std::env::set_var("APP__SERVER__CLEANUP_INTERVAL_SECS", "0");
+    std::env::set_var("APP__SERVER__REQUEST_TIMEOUT_SECS", "soon");
+    std::env::set_var("APP__SERVER__MIN_CLIENT_VERSION", "0.4.0");
+
+    let reloaded = settings(10).reload_from_env();
+
+    assert_eq!(reloaded.rate_limit_burst, 25);
+    assert!(reloaded.maintenance_mode);
+    assert_eq!(
+        reloaded.cors_origins,
+        vec!["https://a.example", "https://b.example"]
+    );
+    assert_eq!(reloaded.cleanup_interval_secs, 3600);
+    assert_eq!(reloaded.request_timeout_secs, 30);
+    assert_eq!(reloaded.rate_limit_replenish_secs, 60);
+    assert_eq!(
+        reloaded.min_client_version,
+        Version::parse("0.4.0").unwrap()
+    );
+}
@@ -0,0 +1,282 @@
+use chrono::Utc;
+use marinvpn_server::deadline::Deadline;
+use marinvpn_server::error::AppError;
+use marinvpn_server::services::db::{Database, PeerOwner};
+use marinvpn_server::services::memory_store::MemoryStore;
+use marinvpn_server::services::peer_pool::PeerPool;
+use marinvpn_server::services::store::{PeerStore, ReplayStore, SessionStore, TransferStore};
+use marinvpn_server::services::transfer::AccountTransfer;
+use std::time::Duration;
+
+const ACCOUNT: &str = "ABCD E2GH JK7M NPQR";
+
+// The contracts below run against the in-memory store always, and against
+// Postgres as well when TEST_DATABASE_URL is set, so the two cannot drift.
+
+async fn postgres() -> Option<Database> {
+    let url = std::env::var("TEST_DATABASE_URL").ok()?;
+    Some(
+        Database::new(&url, "test_salt")
+            .await
+            .expect("Failed to create test DB"),
+    )
+}
+
+fn unique(tag: &str) -> String {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    format!("{}-{}", tag, nanos)
+}
+
+async fn rotation_contract(store: &impl SessionStore) {
+    let account = unique("rotate");
+    // Token hashes are unique across all accounts.
+    let [first, second, third] = ["first", "second", "third"].map(|t| format!("{}-{}", account, t));
+    let later = Utc::now().timestamp() + 3600;
+    assert!(store
+        .create_session(&account, "Swift Falcon", &first, later, 3)
+        .await
+        .unwrap());
+
+    assert!(store
+        .rotate_refresh_token(&account, "Swift Falcon", &first, &second, later)
+        .await
+        .unwrap());
+    assert!(!store
+        .validate_refresh_token(&account, "Swift Falcon", &first)
+        .await
+        .unwrap());
+    assert!(store
+        .validate_refresh_token(&account, "Swift Falcon", &second)
+        .await
+        .unwrap());
+
+    // A rotated token is spent, and a token only rotates for its own device.
+    assert!(!store
+        .rotate_refresh_token(&account, "Swift Falcon", &first, &third, later)
+        .await
+        .unwrap());
+    assert!(!store
+        .rotate_refresh_token(&account, "Calm Otter", &second, &third, later)
+        .await
+        .unwrap());
+    assert_eq!(
+        store
+            .count_active_sessions(&account, "Swift Falcon")
+            .await
+            .unwrap(),
+        1
+    );
+}
+
+async fn expired_rotation_contract(store: &impl SessionStore) {
+    let account = unique("expired");
+    let [stale, fresh] = ["stale", "fresh"].map(|t| format!("{}-{}", account, t));
+    let past = Utc::now().timestamp() - 10;
+    let later = Utc::now().timestamp() + 3600;
+    assert!(store
+        .create_session(&account, "Swift Falcon", &stale, past, 3)
+        .await
+        .unwrap());
+    assert!(!store
+        .rotate_refresh_token(&account, "Swift Falcon", &stale, &fresh, later)
+        .await
+        .unwrap());
+    assert_eq!(
+        store
+            .revoke_refresh_tokens(&account, "Swift Falcon")
+            .await
+            .unwrap(),
+        1
+    );
+}
+
+async fn replay_contract(store: &impl ReplayStore) {
+    let nonce = unique("nonce");
+    assert!(!store.is_attestation_id_used(&nonce).await.unwrap());
+    assert!(store.claim_attestation_id(&nonce).await.unwrap());
+    assert!(store.is_attestation_id_used(&nonce).await.unwrap());
+    assert!(!store.claim_attestation_id(&nonce).await.unwrap());
+
+    // Nonces and blind tokens are tracked apart.
+    assert!(!store.is_token_used(&nonce).await.unwrap());
+    assert!(store.claim_token(&nonce).await.unwrap());
+    assert!(!store.claim_token(&nonce).await.unwrap());
+}
+
+async fn peer_ownership_contract(store: &impl PeerStore) {
+    let key = unique("peer");
+    let owner = PeerOwner::Account(ACCOUNT);
+    let ip = store
+        .get_or_create_peer(&key, owner, Deadline::unbounded())
+        .await
+        .unwrap();
+    assert_eq!(
+        store
+            .get_or_create_peer(&key, owner, Deadline::unbounded())
+            .await
+            .unwrap(),
+        ip
+    );
+    assert!(matches!(
+        store
+            .get_or_create_peer(&key, PeerOwner::Anonymous("token"), Deadline::unbounded())
+            .await,
+        Err(AppError::PubKeyInUse)
+    ));
+    assert!(store.release_peer(&key).await.unwrap());
+    assert!(!store.release_peer(&key).await.unwrap());
+}
+
+async fn transfer_contract(store: &impl TransferStore) {
+    let transfer = AccountTransfer::new("test_salt", 300);
+    let (token, expires_at) = transfer.issue(store, ACCOUNT).await.unwrap();
+    assert_eq!(token.len(), 14, "three groups of four: {}", token);
+    assert!(expires_at > Utc::now().timestamp());
+
+    // Typed without the spaces and in lower case.
+    let typed = token.replace(' ', "").to_lowercase();
+    let claimed = transfer.claim(store, &typed).await.unwrap().unwrap();
+    assert_eq!(claimed.account_number, "ABCDE2GHJK7MNPQR");
+    assert!(transfer.claim(store, &token).await.unwrap().is_none());
+
+    // Handed back after a claim that did not sign in, it works once more.
+    claimed.give_back(store).await.unwrap();
+    assert!(transfer.claim(store, &token).await.unwrap().is_some());
+    assert!(transfer.claim(store, &token).await.unwrap().is_none());
+
+    // The code alone is not enough; the salt is part of the key.
+    let (token, _) = transfer.issue(store, ACCOUNT).await.unwrap();
+    let other_server = AccountTransfer::new("other_salt", 300);
+    assert!(other_server.claim(store, &token).await.is_err());
+}
+
+async fn expired_transfer_contract(store: &impl TransferStore) {
+    let token = unique("TRANSFER");
+    let past = Utc::now().timestamp() - 10;
+    store
+        .create_transfer_token(&token, "sealed", past)
+        .await
+        .unwrap();
+    assert!(store.take_transfer_token(&token).await.unwrap().is_none());
+
+    let token = unique("TRANSFER");
+    let later = Utc::now().timestamp() + 60;
+    store
+        .create_transfer_token(&token, "sealed", later)
+        .await
+        .unwrap();
+    assert_eq!(
+        store.take_transfer_token(&token).await.unwrap(),
+        Some(("sealed".to_string(), later))
+    );
+}
+
+#[tokio::test]
+async fn refresh_token_rotation() {
+    rotation_contract(&MemoryStore::new("test_salt")).await;
+    expired_rotation_contract(&MemoryStore::new("test_salt")).await;
+    if let Some(db) = postgres().await {
+        rotation_contract(&db).await;
+        expired_rotation_contract(&db).await;
+    }
+}
+
+#[tokio::test]
+async fn transfer_tokens_work_once_and_expire() {
+    transfer_contract(&MemoryStore::new("test_salt")).await;
+    expired_transfer_contract(&MemoryStore::new("test_salt")).await;
+    if let Some(db) = postgres().await {
+        transfer_contract(&db).await;
+        expired_transfer_contract(&db).await;
+    }
+}
+
+#[tokio::test]
+async fn attestation_and_token_replays_are_rejected() {
+    replay_contract(&MemoryStore::new("test_salt")).await;
+    if let Some(db) = postgres().await {
+        replay_contract(&db).await;
+    }
+}
+
+#[tokio::test]
+async fn peer_keys_stay_with_their_owner() {
+    peer_ownership_contract(&MemoryStore::new("test_salt")).await;
+    if let Some(db) = postgres().await {
+        peer_ownership_contract(&db).await;
+    }
+}
+
+fn small_store() -> (MemoryStore, PeerPool) {
+    let pool = PeerPool::new("172.16.5.16/28", 10).unwrap();
+    (MemoryStore::new("test_salt").with_peer_pool(pool), pool)
+}
+
+#[tokio::test]
+async fn allocation_skips_addresses_already_taken() {
+    let (store, pool) = small_store();
+    store.insert_peer("legacy-a", &pool.address(0), PeerOwner::Anonymous("a"));
+    store.insert_peer("legacy-b", &pool.address(1), PeerOwner::Anonymous("b"));
+
+    let ip = store
+        .get_or_create_peer("new", PeerOwner::Anonymous("new"), Deadline::unbounded())
+        .await
+        .unwrap();
+    assert_eq!(ip, pool.address(2));
+}
+
+#[tokio::test]
+async fn saturated_pool_fails_without_leaving_a_peer() {
+    let (store, pool) = small_store();
+    for index in 0..pool.capacity() as i64 {
+        store
+            .get_or_create_peer(
+                &format!("peer-{}", index),
+                PeerOwner::Anonymous("fill"),
+                Deadline::unbounded(),
+            )
+            .await
+            .unwrap();
+    }
+
+    let owner = PeerOwner::Anonymous("late");
+    assert!(matches!(
+        store
+            .get_or_create_peer("late", owner, Deadline::unbounded())
+            .await,
+        Err(AppError::ServiceBusy(_))
+    ));
+    assert!(!store.release_peer("late").await.unwrap());
+
+    // A released address goes to the next key ahead of the sequence.
+    assert!(store.release_peer("peer-7").await.unwrap());
+    assert_eq!(
+        store
+            .get_or_create_peer("late", owner, Deadline::unbounded())
+            .await
+            .unwrap(),
+        pool.address(7)
+    );
+}
+
+#[tokio::test]
+async fn spent_deadline_allocates_nothing() {
+    let (store, pool) = small_store();
+    let owner = PeerOwner::Anonymous("slow");
+    assert!(matches!(
+        store
+            .get_or_create_peer("slow", owner, Deadline::after(Duration::ZERO))
+            .await,
+        Err(AppError::DeadlineExceeded)
+    ));
+    assert_eq!(
+        store
+            .get_or_create_peer("slow", owner, Deadline::unbounded())
+            .await
+            .unwrap(),
+        pool.address(0)
+    );
+}
@@ -0,0 +1,222 @@
+use axum::{extract::State, http::HeaderMap, Json};
+use marinvpn_common::attestation::ATTESTATION_PUB_HEADER;
+use marinvpn_common::RefreshRequest;
+use marinvpn_server::handlers::auth::refresh_token;
+use marinvpn_server::services::auth::TokenIssuer;
+use marinvpn_server::services::db::Database;
+use marinvpn_server::AppState;
+use std::sync::Arc;
+
+const SECRET: &str = "token-test-secret";
+const ISSUER: &str = "marinvpn";
+const AUDIENCE: &str = "marinvpn-api";
+const ACCOUNT: &str = "ABCD E2GH JK7M NPQR";
+const ACCESS_TTL: u64 = 1;
+// Short enough to watch the stored row expire.
+const REFRESH_TTL: u64 = 2;
+
+fn issuer(issuer: &str, audience: &str) -> TokenIssuer {
+    TokenIssuer::new(SECRET, issuer, audience, 900, 86_400)
+}
+
+fn assert_expires_in(exp: i64, ttl: u64) {
+    let expected = chrono::Utc::now().timestamp() + ttl as i64;
+    assert!(
+        (expected - exp).abs() <= 2,
+        "exp {} is not about {} seconds from now",
+        exp,
+        ttl
+    );
+}
+
+#[test]
+fn minted_tokens_carry_the_configured_lifetime() {
+    let tokens = issuer(ISSUER, AUDIENCE);
+    let access = tokens.access_token(ACCOUNT, "Swift Falcon", None).unwrap();
+    let claims = tokens.decode_access_token(&access).unwrap();
+    assert_expires_in(claims.exp as i64, 900);
+
+    let (refresh, exp) = tokens.refresh_token(ACCOUNT, "Swift Falcon", None).unwrap();
+    let claims = tokens.decode_refresh_token(&refresh).unwrap();
+    assert_eq!(claims.exp as i64, exp);
+    assert_expires_in(exp, 86_400);
+}
+
+#[test]
+fn out_of_range_lifetime_is_an_error() {
+    for ttl in [u64::MAX, i64::MAX as u64] {
+        let tokens = TokenIssuer::new(SECRET, ISSUER, AUDIENCE, ttl, ttl);
+        assert!(tokens.access_token(ACCOUNT, "Swift Falcon", None).is_err());
+    }
+}
+
+#[test]
+fn tokens_name_their_issuer_and_audience() {
+    let tokens = issuer(ISSUER, AUDIENCE);
+    let access = tokens.access_token(ACCOUNT, "Swift Falcon", None).unwrap();
+    let claims = tokens.decode_access_token(&access).unwrap();
+    assert_eq!(claims.iss, ISSUER);
+    assert_eq!(claims.aud, AUDIENCE);
+}
+
+#[test]
+fn tokens_for_another_audience_or_issuer_are_rejected() {
+    let ours = issuer(ISSUER, AUDIENCE);
+    for other in [
+        issuer(ISSUER, "other-api"),
+        issuer("other-tenant", AUDIENCE),
+    ] {
+        let access = other.access_token(ACCOUNT, "Swift Falcon", None).unwrap();
+        assert!(ours.decode_access_token(&access).is_err());
+        let (refresh, _) = other.refresh_token(ACCOUNT, "Swift Falcon", None).unwrap();
+        assert!(ours.decode_refresh_token(&refresh).is_err());
+    }
+}
+
+#[test]
+fn bound_tokens_only_match_their_device_key() {
+    let tokens = issuer(ISSUER, AUDIENCE);
+    let access = tokens
+        .access_token(ACCOUNT, "Swift Falcon", Some("device-a-key"))
+        .unwrap();
+    let claims = tokens.decode_access_token(&access).unwrap();
+    assert!(claims.is_bound_to(Some("device-a-key")));
+    assert!(!claims.is_bound_to(Some("device-b-key")));
+    assert!(!claims.is_bound_to(None));
+
+    let access = tokens.access_token(ACCOUNT, "Swift Falcon", None).unwrap();
+    let claims = tokens.decode_access_token(&access).unwrap();
+    assert_eq!(claims.dpk, None);
+    assert!(claims.is_bound_to(Some("device-b-key")));
+}
+
+#[test]
+fn tokens_without_issuer_or_audience_are_rejected() {
+    #[derive(serde::Serialize)]
+    struct LegacyClaims<'a> {
+        sub: &'a str,
+        exp: usize,
+        device: &'a str,
+        kind: &'a str,
+    }
+    let legacy = jsonwebtoken::encode(
+        &jsonwebtoken::Header::default(),
+        &LegacyClaims {
+            sub: "ABCDE2GHJK7MNPQR",
+            exp: (chrono::Utc::now().timestamp() + 900) as usize,
+            device: "Swift Falcon",
+            kind: "access",
+        },
+        &jsonwebtoken::EncodingKey::from_secret(SECRET.as_bytes()),
+    )
+    .unwrap();
+    assert!(issuer(ISSUER, AUDIENCE)
+        .decode_access_token(&legacy)
+        .is_err());
+}
+
+async fn setup_state() -> Option<Arc<AppState>> {
+    let db_url = match std::env::var("TEST_DATABASE_URL") {
+        Ok(url) => url,
+        Err(_) => {
+            eprintln!("TEST_DATABASE_URL not set; skipping integration test.");
+            return None;
+        }
+    };
+    let db = Database::new(&db_url, "test_salt")
+        .await
+        .expect("Failed to create test DB");
+
+    let mut settings = marinvpn_server::config::Settings::new().unwrap();
+    settings.database.url = db_url.to_string();
+    settings.auth.jwt_secret = SECRET.to_string();
+    settings.auth.access_token_ttl_secs = ACCESS_TTL;
+    settings.auth.refresh_token_ttl_secs = REFRESH_TTL;
+
+    let runtime = Arc::new(marinvpn_server::runtime::RuntimeConfig::new(
+        marinvpn_server::runtime::RuntimeSettings::from_server(&settings.server),
+    ));
+    Some(Arc::new(AppState {
+        db,
+        settings,
+        vpn: marinvpn_server::services::vpn::VpnOrchestrator::new("wg0".to_string()),
+        signer: marinvpn_server::services::auth::BlindSigner::new(),
+        support_key: marinvpn_server::services::auth::SupportKey::new(),
+        runtime,
+    }))
+}
+
+fn random_account_number() -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+    let mut n = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let groups: Vec<String> = (0..4)
+        .map(|_| {
+            (0..4)
+                .map(|_| {
+                    let c = ALPHABET[(n % 32) as usize] as char;
+                    n = n / 32 + 11;
+                    c
+                })
+                .collect()
+        })
+        .collect();
+    groups.join(" ")
+}
+
+#[tokio::test]
+async fn refresh_uses_the_configured_lifetimes() {
+    let Some(state) = setup_state().await else {
+        return;
+    };
+    let account = random_account_number();
+    let device = "Swift Falcon";
+    state.db.create_account(&account, 30).await.unwrap();
+    state
+        .db
+        .add_device(&account, device, Some("attestation-key"))
+        .await
+        .unwrap();
+    let (old_refresh, old_exp) = issuer(ISSUER, AUDIENCE)
+        .refresh_token(&account, device, Some("attestation-key"))
+        .unwrap();
+    state
+        .db
+        .create_session(&account, device, &old_refresh, old_exp, 3)
+        .await
+        .unwrap();
+
+    let mut headers = HeaderMap::new();
+    headers.insert(ATTESTATION_PUB_HEADER, "attestation-key".parse().unwrap());
+    let Json(response) = refresh_token(
+        State(state.clone()),
+        headers,
+        Json(RefreshRequest {
+            refresh_token: old_refresh,
+        }),
+    )
+    .await
+    .unwrap();
+
+    let tokens = TokenIssuer::from_settings(&state.settings.auth);
+    let access = tokens.decode_access_token(&response.auth_token).unwrap();
+    assert_expires_in(access.exp as i64, ACCESS_TTL);
+    let refresh = tokens
+        .decode_refresh_token(&response.refresh_token)
+        .unwrap();
+    assert!(access.is_bound_to(Some("attestation-key")));
+    assert!(refresh.is_bound_to(Some("attestation-key")));
+    assert_expires_in(refresh.exp as i64, REFRESH_TTL);
+
+    // The stored row expires with the token, so it can no longer be rotated.
+    tokio::time::sleep(std::time::Duration::from_secs(REFRESH_TTL + 2)).await;
+    let later = chrono::Utc::now().timestamp() + 3600;
+    let rotated = state
+        .db
+        .rotate_refresh_token(&account, device, &response.refresh_token, "next", later)
+        .await
+        .unwrap();
+    assert!(!rotated);
+}
@@ -1,6 +1,6 @@
-use crate::hooks::use_vpn_client;
-use crate::icons::{Loader, RefreshCw};
-use crate::models::{ConnectionStatus, SettingsState};
+use crate::hooks::{use_controls_enabled, use_vpn_client};
+use crate::icons::{Loader, Pin, RefreshCw};
+use crate::models::{ConnectionStatus, Location, LocationSelection, SettingsState};
 use crate::state::ConnectionState;
 use crate::window::WINDOW_WIDTH;
 use crate::Route;
@@ -13,9 +13,10 @@ struct ConnectionDetails {
 }
 
 fn get_connection_details(location_text: &str, settings: &SettingsState) -> ConnectionDetails {
-    let location = crate::models::LocationInfo::from_string(location_text);
+    let Location { country, city } = Location::parse(location_text)
+        .unwrap_or_else(|_| Location::new("Unknown", "Unknown"));
 
-    let server_code = match location.country.as_str() {
+    let server_code = match country.as_str() {
         "Sweden" => "se",
         "United States" => "us",
         "Germany" => "de",
@@ -23,7 +24,7 @@ fn get_connection_details(location_text: &str, settings: &SettingsState) -> Conn
         "Netherlands" => "nl",
         _ => "un",
     };
-    let city_lower = location.city.to_lowercase();
+    let city_lower = city.to_lowercase();
     let city_code = city_lower.get(0..3).unwrap_or("unk");
 
     let server_name = if settings.multi_hop {
@@ -33,8 +34,8 @@ fn get_connection_details(location_text: &str, settings: &SettingsState) -> Conn
     };
 
     ConnectionDetails {
-        country: location.country,
-        city: location.city,
+        country,
+        city,
         server_name,
     }
 }
@@ -94,8 +95,14 @@ pub fn ConnectionOverlay() -> Element {
     let status = (state.status)();
     let settings = (state.settings)();
     let location_text = (state.current_location)();
+    let enabled = use_controls_enabled();
+    let transitioning = matches!(
+        status,
+        ConnectionStatus::Connecting | ConnectionStatus::Disconnecting
+    );
 
     let details = get_connection_details(&location_text, &settings);
+    let pinned = settings.is_pinned(&Location::new(&details.country, &details.city));
     let features = get_active_features(&settings);
 
     let button_color_class = match status {
@@ -147,8 +154,13 @@ pub fn ConnectionOverlay() -> Element {
                             }
                         }
                     } else {
-                        div { class: "font-bold text-lg leading-tight",
+                        div { class: "font-bold text-lg leading-tight flex items-center gap-1.5",
                             "{details.country}, {details.city}"
+                            if pinned {
+                                span { class: "text-primary", title: "Pinned server",
+                                    Pin { size: 14, fill: Some("currentColor".to_string()) }
+                                }
+                            }
                         }
                         div { class: "text-[10px] text-muted-foreground font-medium uppercase tracking-widest",
                             "{details.server_name}"
@@ -212,7 +224,8 @@ pub fn ConnectionOverlay() -> Element {
                 // Switch location and reload
                 div { class: "flex items-center w-full mb-3 border border-white/10 rounded overflow-hidden bg-white/5",
                     button {
-                        class: "flex-1 h-8 flex items-center justify-center hover:bg-white/5 text-foreground transition-all active:scale-[0.98] no-drag text-xs font-semibold",
+                        class: "flex-1 h-8 flex items-center justify-center hover:bg-white/5 text-foreground transition-all active:scale-[0.98] disabled:opacity-50 disabled:cursor-not-allowed no-drag text-xs font-semibold",
+                        disabled: !enabled,
                         onclick: move |_| {
                             nav.push(Route::Locations {});
                         },
@@ -221,12 +234,13 @@ pub fn ConnectionOverlay() -> Element {
                     // Vertical divider
                     div { class: "w-[1px] h-4 bg-white/10" }
                     button {
-                        class: "w-10 h-8 flex items-center justify-center hover:bg-white/5 text-muted-foreground transition-all active:scale-[0.98] no-drag",
+                        class: "w-10 h-8 flex items-center justify-center hover:bg-white/5 text-muted-foreground transition-all active:scale-[0.98] disabled:opacity-50 disabled:cursor-not-allowed no-drag",
+                        disabled: !enabled,
                         onclick: move |_| {
                             if status == ConnectionStatus::Connected {
                                 let loc = (state.current_location)();
                                 vpn.disconnect();
-                                vpn.connect(loc);
+                                vpn.connect(LocationSelection::parse_or_automatic(&loc));
                             }
                         },
                         RefreshCw { size: 14 }
@@ -235,10 +249,20 @@ pub fn ConnectionOverlay() -> Element {
 
                 // Main connect button
                 button {
-                    onclick: move |_| vpn.toggle(),
-                    disabled: matches!(status, ConnectionStatus::Connecting | ConnectionStatus::Disconnecting),
+                    // Only reachable mid-transition once the lock has run
+                    // out: give up on the attempt.
+                    onclick: move |_| {
+                        if transitioning {
+                            vpn.disconnect();
+                        } else {
+                            vpn.toggle();
+                        }
+                    },
+                    disabled: !enabled,
                     class: "group relative h-8 flex items-center justify-center w-full rounded shadow-xl hover:brightness-110 transition-all duration-300 cursor-pointer disabled:opacity-80 disabled:cursor-not-allowed text-sm font-bold {button_color_class} no-drag",
-                    if status == ConnectionStatus::Connecting {
+                    if transitioning && enabled {
+                        {i18n.tr("cancel")}
+                    } else if status == ConnectionStatus::Connecting {
                         Loader {
                             size: 16,
                             class: Some("animate-spin mr-2".to_string()),
@@ -85,14 +85,10 @@ pub fn DashboardMap(regions: Vec<Region>, country: String, status: ConnectionSta
                     if let Some(region) = active_region {
                         if settings.multi_hop {
                             {
-                                let entry_info = crate::models::LocationInfo::from_string(
-                                    &settings.entry_location,
-                                );
-                                let exit_info = crate::models::LocationInfo::from_string(
-                                    &settings.exit_location,
-                                );
-                                let entry_region = regions.iter().find(|r| r.name == entry_info.country);
-                                let exit_region = regions.iter().find(|r| r.name == exit_info.country);
+                                let entry_country = settings.entry_location.country();
+                                let exit_country = settings.exit_location.country();
+                                let entry_region = regions.iter().find(|r| Some(r.name.as_str()) == entry_country);
+                                let exit_region = regions.iter().find(|r| Some(r.name.as_str()) == exit_country);
                                 if let (Some(en), Some(ex)) = (entry_region, exit_region) {
                                     rsx! {
                                         line {
@@ -154,7 +150,7 @@ pub fn DashboardMap(regions: Vec<Region>, country: String, status: ConnectionSta
                                 class: "cursor-pointer transition-all duration-300 pointer-events-auto",
                                 class: if country == region.name { "opacity-100 scale-125" } else { "opacity-40 hover:opacity-100" },
                                 onclick: move |_| {
-                                    vpn.connect(format!("{}, Auto", click_name.clone()));
+                                    vpn.connect(crate::models::LocationSelection::BestIn(click_name.clone()));
                                 },
                                 circle {
                                     cx: "{region.map_x}",
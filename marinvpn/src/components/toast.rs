@@ -1,7 +1,7 @@
 use crate::icons::*;
 use dioxus::prelude::*;
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum ToastType {
     Info,
     Success,
@@ -52,6 +52,15 @@ impl ToastManager {
             toasts.write().retain(|t| t.id != id);
         });
     }
+
+    /// Messages currently on screen, oldest first.
+    pub fn messages(&self) -> Vec<(String, ToastType)> {
+        self.toasts
+            .read()
+            .iter()
+            .map(|t| (t.message.clone(), t.type_))
+            .collect()
+    }
 }
 
 pub fn use_toast() -> ToastManager {
@@ -191,7 +191,12 @@ pub fn SettingCollapsible(
 }
 
 #[component]
-pub fn MenuRow(label: String, icon: Element, onclick: EventHandler<MouseEvent>) -> Element {
+pub fn MenuRow(
+    label: String,
+    icon: Element,
+    onclick: EventHandler<MouseEvent>,
+    value: Option<String>,
+) -> Element {
     rsx! {
         div {
             class: "flex items-center justify-between px-4 hover:bg-accent/30 cursor-pointer transition-colors group shrink-0",
@@ -201,7 +206,12 @@ pub fn MenuRow(label: String, icon: Element, onclick: EventHandler<MouseEvent>)
                 div { class: "text-muted-foreground group-hover:text-primary transition-colors flex items-center", {icon} }
                 span { class: "font-bold text-sm text-foreground", "{label}" }
             }
-            ChevronRight { size: 16, class: Some("text-muted-foreground".to_string()) }
+            div { class: "flex items-center gap-2",
+                if let Some(v) = value {
+                    span { class: "text-xs text-muted-foreground", "{v}" }
+                }
+                ChevronRight { size: 16, class: Some("text-muted-foreground".to_string()) }
+            }
         }
     }
 }
@@ -12,16 +12,19 @@ pub fn get_default_regions() -> Vec<Region> {
                     name: "Stockholm".to_string(),
                     load: 45,
                     ping: 12,
+                    available: true,
                 },
                 City {
                     name: "Gothenburg".to_string(),
                     load: 22,
                     ping: 14,
+                    available: true,
                 },
                 City {
                     name: "Malmö".to_string(),
                     load: 89,
                     ping: 15,
+                    available: true,
                 },
             ],
         },
@@ -35,26 +38,31 @@ pub fn get_default_regions() -> Vec<Region> {
                     name: "New York".to_string(),
                     load: 92,
                     ping: 110,
+                    available: true,
                 },
                 City {
                     name: "Los Angeles".to_string(),
                     load: 65,
                     ping: 150,
+                    available: true,
                 },
                 City {
                     name: "Chicago".to_string(),
                     load: 30,
                     ping: 130,
+                    available: true,
                 },
                 City {
                     name: "Dallas".to_string(),
                     load: 12,
                     ping: 140,
+                    available: true,
                 },
                 City {
                     name: "Miami".to_string(),
                     load: 45,
                     ping: 120,
+                    available: true,
                 },
             ],
         },
@@ -68,16 +76,19 @@ pub fn get_default_regions() -> Vec<Region> {
                     name: "Frankfurt".to_string(),
                     load: 78,
                     ping: 25,
+                    available: true,
                 },
                 City {
                     name: "Berlin".to_string(),
                     load: 55,
                     ping: 28,
+                    available: true,
                 },
                 City {
                     name: "Munich".to_string(),
                     load: 33,
                     ping: 30,
+                    available: true,
                 },
             ],
         },
@@ -91,11 +102,13 @@ pub fn get_default_regions() -> Vec<Region> {
                     name: "London".to_string(),
                     load: 95,
                     ping: 35,
+                    available: true,
                 },
                 City {
                     name: "Manchester".to_string(),
                     load: 40,
                     ping: 38,
+                    available: true,
                 },
             ],
         },
@@ -109,11 +122,13 @@ pub fn get_default_regions() -> Vec<Region> {
                     name: "Amsterdam".to_string(),
                     load: 82,
                     ping: 18,
+                    available: true,
                 },
                 City {
                     name: "Rotterdam".to_string(),
                     load: 25,
                     ping: 20,
+                    available: true,
                 },
             ],
         },
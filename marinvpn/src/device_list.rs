@@ -0,0 +1,160 @@
+use crate::models::Device;
+use std::collections::HashSet;
+
+/// The Devices view's copy of the account's device list.
+///
+/// Removals are applied optimistically: a device disappears as soon as the
+/// user removes it and only comes back if the server call fails. The server's
+/// list is kept intact underneath and in-flight removals are tracked by name,
+/// so several removals can overlap and a rollback restores the device in its
+/// original position no matter how the others resolve.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeviceList {
+    devices: Vec<Device>,
+    pending: HashSet<String>,
+}
+
+impl DeviceList {
+    pub fn new(devices: Vec<Device>) -> Self {
+        Self {
+            devices,
+            pending: HashSet::new(),
+        }
+    }
+
+    /// Devices to show, in server order, without removals in flight.
+    pub fn visible(&self) -> Vec<Device> {
+        self.devices
+            .iter()
+            .filter(|d| !self.pending.contains(&d.name))
+            .cloned()
+            .collect()
+    }
+
+    pub fn is_pending(&self, name: &str) -> bool {
+        self.pending.contains(name)
+    }
+
+    /// Hides `name` right away. Returns `false` when there is nothing to
+    /// remove, including a second click while the first is still in flight.
+    pub fn begin_remove(&mut self, name: &str) -> bool {
+        if !self.devices.iter().any(|d| d.name == name) {
+            return false;
+        }
+        self.pending.insert(name.to_string())
+    }
+
+    /// The server removed the device, or no longer had it.
+    pub fn confirm_remove(&mut self, name: &str) {
+        if self.pending.remove(name) {
+            self.devices.retain(|d| d.name != name);
+        }
+    }
+
+    /// The server call failed; show the device again.
+    pub fn rollback_remove(&mut self, name: &str) {
+        self.pending.remove(name);
+    }
+
+    /// Takes a freshly fetched list. Removals still in flight stay hidden as
+    /// long as the server reports the device; ones it no longer has are done.
+    pub fn refresh(&mut self, devices: Vec<Device>) {
+        self.pending
+            .retain(|name| devices.iter().any(|d| &d.name == name));
+        self.devices = devices;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(name: &str) -> Device {
+        Device {
+            name: name.to_string(),
+            created_date: "2026-01-01".to_string(),
+        }
+    }
+
+    fn list(names: &[&str]) -> DeviceList {
+        DeviceList::new(names.iter().map(|n| device(n)).collect())
+    }
+
+    fn names(list: &DeviceList) -> Vec<String> {
+        list.visible().into_iter().map(|d| d.name.clone()).collect()
+    }
+
+    #[test]
+    fn removal_is_applied_before_the_server_answers() {
+        let mut devices = list(&["Swift Falcon", "Quiet Otter", "Brave Lynx"]);
+
+        assert!(devices.begin_remove("Quiet Otter"));
+        assert!(devices.is_pending("Quiet Otter"));
+        assert_eq!(names(&devices), ["Swift Falcon", "Brave Lynx"]);
+
+        assert!(!devices.begin_remove("Quiet Otter"), "already in flight");
+        assert!(!devices.begin_remove("Unknown"));
+
+        devices.confirm_remove("Quiet Otter");
+        assert!(!devices.is_pending("Quiet Otter"));
+        assert_eq!(names(&devices), ["Swift Falcon", "Brave Lynx"]);
+        assert!(!devices.begin_remove("Quiet Otter"));
+    }
+
+    #[test]
+    fn failed_removal_restores_the_original_position() {
+        let mut devices = list(&["Swift Falcon", "Quiet Otter", "Brave Lynx"]);
+
+        devices.begin_remove("Quiet Otter");
+        devices.rollback_remove("Quiet Otter");
+        assert!(!devices.is_pending("Quiet Otter"));
+        assert_eq!(
+            names(&devices),
+            ["Swift Falcon", "Quiet Otter", "Brave Lynx"]
+        );
+    }
+
+    #[test]
+    fn overlapping_removals_resolve_independently() {
+        let mut devices = list(&["Swift Falcon", "Quiet Otter", "Brave Lynx", "Calm Heron"]);
+
+        devices.begin_remove("Quiet Otter");
+        devices.begin_remove("Brave Lynx");
+        devices.begin_remove("Calm Heron");
+        assert_eq!(names(&devices), ["Swift Falcon"]);
+
+        // Answers arrive out of order.
+        devices.rollback_remove("Brave Lynx");
+        devices.confirm_remove("Calm Heron");
+        assert_eq!(names(&devices), ["Swift Falcon", "Brave Lynx"]);
+
+        devices.rollback_remove("Quiet Otter");
+        assert_eq!(
+            names(&devices),
+            ["Swift Falcon", "Quiet Otter", "Brave Lynx"]
+        );
+    }
+
+    #[test]
+    fn refresh_keeps_in_flight_removals_hidden() {
+        let mut devices = list(&["Swift Falcon", "Quiet Otter", "Brave Lynx"]);
+        devices.begin_remove("Quiet Otter");
+        devices.begin_remove("Brave Lynx");
+
+        // The server already dropped Brave Lynx but not yet Quiet Otter.
+        devices.refresh(vec![
+            device("Swift Falcon"),
+            device("Quiet Otter"),
+            device("Calm Heron"),
+        ]);
+        assert_eq!(names(&devices), ["Swift Falcon", "Calm Heron"]);
+        assert!(devices.is_pending("Quiet Otter"));
+        assert!(!devices.is_pending("Brave Lynx"));
+
+        devices.rollback_remove("Quiet Otter");
+        assert_eq!(
+            names(&devices),
+            ["Swift Falcon", "Quiet Otter", "Calm Heron"]
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use marinvpn_common::version::CLIENT_TOO_OLD_CODE;
 use reqwest::StatusCode;
 use thiserror::Error;
 
@@ -18,6 +19,12 @@ pub enum AppError {
     #[error("Session expired")]
     SessionExpired,
 
+    #[error("Device attestation key was replaced")]
+    DeviceKeyReplaced,
+
+    #[error("Device key does not match the one registered")]
+    DeviceKeyMismatch,
+
     #[error("Validation error: {0}")]
     Validation(String),
 
@@ -36,14 +43,38 @@ pub enum AppError {
     #[error("Crypto error: {0}")]
     Crypto(String),
 
+    #[error("Captive portal detected")]
+    CaptivePortal,
+
     #[error("Unknown error: {0}")]
     Unknown(String),
 }
 
 impl AppError {
+    /// Whether the server turned the request away because this build is
+    /// older than it supports.
+    pub fn is_client_too_old(&self) -> bool {
+        match self {
+            AppError::Api { status, message } => {
+                *status == StatusCode::UPGRADE_REQUIRED
+                    || crate::services::auth::error_code(message).as_deref()
+                        == Some(CLIENT_TOO_OLD_CODE)
+            }
+            _ => false,
+        }
+    }
+
     pub fn user_friendly_message(&self) -> String {
         match self {
+            AppError::Network(_) if crate::services::auth::api_proxy_in_use() => {
+                "Could not reach the server through your API proxy. Check that it is running."
+                    .to_string()
+            }
             AppError::Network(_) => "Check your internet connection.".to_string(),
+            _ if self.is_client_too_old() => {
+                "This version of MarinVPN is no longer supported. Please update the app."
+                    .to_string()
+            }
             AppError::Api { status, .. } => match *status {
                 StatusCode::TOO_MANY_REQUESTS => "Too many requests. Please try again later.".to_string(),
                 StatusCode::SERVICE_UNAVAILABLE => "Server is currently unavailable.".to_string(),
@@ -51,6 +82,17 @@ impl AppError {
             },
             AppError::Auth(msg) => format!("Login failed: {}", msg),
             AppError::SessionExpired => "Your session has expired. Please log in again.".to_string(),
+            AppError::DeviceKeyReplaced => {
+                "This device's security key was damaged and has been replaced. Please log in again."
+                    .to_string()
+            }
+            AppError::DeviceKeyMismatch => {
+                "This device needs to be registered again. Please log in again.".to_string()
+            }
+            AppError::CaptivePortal => {
+                "This network requires you to sign in. Open a browser to log in, then try again."
+                    .to_string()
+            }
             AppError::Vpn(msg) => format!("VPN Connection Error: {}", msg),
             _ => self.to_string(),
         }
@@ -1,11 +1,15 @@
 pub mod use_account;
 pub mod use_connection;
+pub mod use_persist;
+pub mod use_request_log;
 pub mod use_servers;
 pub mod tests;
 
-use crate::models::{ConnectionStatus, VpnAction};
+use crate::models::{ConnectionStatus, LocationSelection, VpnAction};
 use crate::state::ConnectionState;
 use dioxus::prelude::*;
+use std::future::Future;
+use std::time::{Duration, Instant};
 
 #[derive(Clone, Copy)]
 pub struct VpnClient {
@@ -13,7 +17,7 @@ pub struct VpnClient {
 }
 
 impl VpnClient {
-    pub fn connect(&self, location: String) {
+    pub fn connect(&self, location: LocationSelection) {
         self.state.vpn_action.send(VpnAction::Connect(location));
     }
 
@@ -26,7 +30,7 @@ impl VpnClient {
         match (state.status)() {
             ConnectionStatus::Disconnected => {
                 let loc = (state.current_location)();
-                self.connect(loc);
+                self.connect(LocationSelection::parse_or_automatic(&loc));
             }
             ConnectionStatus::Connected => {
                 self.disconnect();
@@ -47,11 +51,57 @@ impl VpnClient {
     }
 }
 
+/// Resolves once `offline` is off. Background loops await this before each
+/// round, so offline mode holds them without tearing them down.
+pub async fn wait_until_online(offline: ReadOnlySignal<bool>) {
+    while *offline.peek() {
+        tokio::time::sleep(std::time::Duration::from_millis(250)).await;
+    }
+}
+
 pub fn use_vpn_client() -> VpnClient {
     let state = use_context::<ConnectionState>();
     VpnClient { state }
 }
 
+/// How long connection controls stay locked while connecting or
+/// disconnecting. A connect normally finishes well inside this, including
+/// the UDP handshake wait and the TCP fallback; past it the controls come
+/// back so an attempt that hangs can be cancelled.
+pub const TRANSITION_LOCK: Duration = Duration::from_secs(45);
+
+/// Whether connect, disconnect and location controls take input, given the
+/// status and how long it has been held.
+pub fn controls_enabled(status: ConnectionStatus, held_for: Duration) -> bool {
+    match status {
+        ConnectionStatus::Connected | ConnectionStatus::Disconnected => true,
+        ConnectionStatus::Connecting | ConnectionStatus::Disconnecting => {
+            held_for >= TRANSITION_LOCK
+        }
+    }
+}
+
+/// [`controls_enabled`] for the current status, re-evaluated when the
+/// status changes and once more when a transition outlasts
+/// [`TRANSITION_LOCK`].
+pub fn use_controls_enabled() -> bool {
+    let state = use_context::<ConnectionState>();
+    let mut changed_at = use_signal(Instant::now);
+    let mut lock_checks = use_signal(|| 0u32);
+
+    use_effect(move || {
+        (state.status)();
+        changed_at.set(Instant::now());
+        spawn(async move {
+            tokio::time::sleep(TRANSITION_LOCK).await;
+            lock_checks += 1;
+        });
+    });
+
+    lock_checks();
+    controls_enabled((state.status)(), changed_at().elapsed())
+}
+
 #[derive(Clone, Copy)]
 pub struct I18n {
     lang: crate::models::Language,
@@ -99,3 +149,26 @@ pub fn use_scroll_handler(dns_expanded: Option<Signal<bool>>) {
         }
     });
 }
+
+/// `use_future` for loops that only make sense with a session. The task is
+/// cancelled when `account` is cleared, so nothing keeps running on the
+/// tokens of an account that just logged out, and started afresh once an
+/// account is set again.
+pub fn use_signed_in_future<F>(
+    account: ReadOnlySignal<Option<String>>,
+    future: impl FnMut() -> F + 'static,
+) -> UseFuture
+where
+    F: Future + 'static,
+{
+    let mut task = use_future(future);
+    use_effect(move || {
+        let signed_in = account.read().as_deref().is_some_and(|a| !a.is_empty());
+        match (signed_in, task.finished()) {
+            (false, false) => task.cancel(),
+            (true, true) => task.restart(),
+            _ => {}
+        }
+    });
+    task
+}
@@ -1,85 +1,32 @@
 #[cfg(test)]
 mod tests {
+    use crate::components::toast::{use_toast, ToastType};
     use crate::error::AppError;
     use crate::hooks::use_account::AccountState;
-    use crate::hooks::use_connection::use_connection_with_service;
+    use crate::hooks::use_connection::{use_connection, use_connection_with_service};
+    use crate::hooks::use_persist::use_persist;
+    use crate::hooks::use_servers::use_servers_with_service;
+    use crate::hooks::{controls_enabled, use_signed_in_future, TRANSITION_LOCK};
     use crate::models::{
-        CommonVpnServer, ConnectionStatus, SettingsState, VpnAction, WireGuardConfig,
+        CommonVpnServer, ConnectionStatus, Hop, LastConnection, Location, LocationSelection,
+        ServerSettings, SettingsState, StartupBehavior, StealthMode, VpnAction, WireGuardConfig,
     };
-    use crate::services::vpn::{VpnEvent, VpnService};
-    use crate::services::AppService;
+    use crate::services::connect_timing::{ConnectTiming, Phase};
+    use crate::services::connection_stats::ConnectionStats;
+    use crate::services::vpn::{
+        RecoveryCause, SharedVpnService, StealthPort, Transport, VpnError, VpnEvent, VpnService,
+        VpnStats,
+    };
+    use crate::services::vpn_mock::{MockCall, MockVpnService};
+    use crate::services::servers::ServersService;
+    use crate::services::{AppService, SharedAppService};
     use async_trait::async_trait;
+    use dioxus::dioxus_core::NoOpMutations;
     use dioxus::prelude::*;
-    use marinvpn_common::DnsBlockingState;
+    use std::cell::Cell;
+    use std::rc::Rc;
     use std::sync::{Arc, Mutex};
-    use tokio::sync::broadcast;
-
-    #[derive(Clone)]
-    struct MockVpnService {
-        tx: broadcast::Sender<VpnEvent>,
-        connected: Arc<Mutex<bool>>,
-        connect_calls: Arc<Mutex<usize>>,
-    }
-
-    impl PartialEq for MockVpnService {
-        fn eq(&self, other: &Self) -> bool {
-            Arc::ptr_eq(&self.connected, &other.connected)
-        }
-    }
-
-    impl MockVpnService {
-        fn new() -> Self {
-            let (tx, _) = broadcast::channel(10);
-            Self {
-                tx,
-                connected: Arc::new(Mutex::new(false)),
-                connect_calls: Arc::new(Mutex::new(0)),
-            }
-        }
-    }
-
-    #[async_trait]
-    impl VpnService for MockVpnService {
-        fn subscribe(&self) -> broadcast::Receiver<VpnEvent> {
-            self.tx.subscribe()
-        }
-        async fn connect(
-            &self,
-            _entry: String,
-            _entry_config: WireGuardConfig,
-            _exit: Option<(String, WireGuardConfig)>,
-            _settings: SettingsState,
-            _auth: Option<(String, String)>,
-        ) {
-            {
-                let mut calls = self.connect_calls.lock().unwrap();
-                *calls += 1;
-                let mut lock = self.connected.lock().unwrap();
-                *lock = true;
-            }
-            let _ = self.tx.send(VpnEvent::StatusChanged(ConnectionStatus::Connected));
-        }
-        async fn disconnect(&self) {
-            {
-                let mut lock = self.connected.lock().unwrap();
-                *lock = false;
-            }
-            let _ = self.tx.send(VpnEvent::StatusChanged(ConnectionStatus::Disconnected));
-        }
-        async fn get_status(&self) -> ConnectionStatus {
-            let lock = self.connected.lock().unwrap();
-            if *lock {
-                ConnectionStatus::Connected
-            } else {
-                ConnectionStatus::Disconnected
-            }
-        }
-        async fn enable_captive_portal(&self, _duration_secs: u64) {}
-        async fn apply_lockdown(&self, _settings: &SettingsState) -> Result<(), crate::services::vpn::VpnError> {
-            Ok(())
-        }
-        async fn disable_kill_switch(&self) {}
-    }
+    use std::time::Duration;
 
     #[derive(Clone, PartialEq)]
     struct MockAppService;
@@ -107,8 +54,7 @@ mod tests {
             &self,
             _location: &str,
             _token: &str,
-            _dns: Option<DnsBlockingState>,
-            _qr: bool,
+            _settings: &ServerSettings,
         ) -> Result<WireGuardConfig, AppError> {
             Ok(WireGuardConfig {
                 private_key: "priv".to_string(),
@@ -119,6 +65,8 @@ mod tests {
                 dns: None,
                 preshared_key: None,
                 obfuscation_key: None,
+                obfuscation_port: None,
+                obfuscation_cipher: None,
                 pqc_ciphertext: None,
                 pqc_handshake: None,
                 pqc_provider: None,
@@ -218,7 +166,7 @@ mod tests {
             let vpn_state = use_connection_with_service(account, settings, vpn_service, app_service);
             
             use_effect(move || {
-                vpn_state.vpn_action.send(VpnAction::Connect("Automatic".to_string()));
+                vpn_state.vpn_action.send(VpnAction::Connect(LocationSelection::Automatic));
             });
 
             rsx! {
@@ -238,7 +186,11 @@ mod tests {
         for _ in 0..40 {
             tokio::time::sleep(std::time::Duration::from_millis(50)).await;
             dom.wait_for_work().await;
-            if *vpn_service.connect_calls.lock().unwrap() > 0 {
+            if vpn_service
+                .calls()
+                .iter()
+                .any(|call| matches!(call, MockCall::Connect { .. }))
+            {
                 called = true;
                 break;
             }
@@ -246,4 +198,1135 @@ mod tests {
         
         assert!(called, "Expected VpnService::connect to be called");
     }
-}
\ No newline at end of file
+
+    #[derive(Clone, Debug, Default)]
+    struct Snapshot {
+        status: Option<ConnectionStatus>,
+        current_location: String,
+        connected_since: bool,
+        download_speed: f64,
+        active_port: Option<StealthPort>,
+        stealth_mode: StealthMode,
+        udp_blocked_networks: Vec<String>,
+        probe_results: usize,
+        exit_ready: bool,
+        toasts: Vec<(String, ToastType)>,
+        connection_stats: ConnectionStats,
+        last_connect: Option<ConnectTiming>,
+        single_hop_offer: Option<(Hop, String)>,
+        last_connection: Option<LastConnection>,
+    }
+
+    #[derive(Clone, Default)]
+    struct Probe(Arc<Mutex<Snapshot>>);
+
+    impl PartialEq for Probe {
+        fn eq(&self, other: &Self) -> bool {
+            Arc::ptr_eq(&self.0, &other.0)
+        }
+    }
+
+    impl Probe {
+        fn get(&self) -> Snapshot {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    #[component]
+    fn ProbeApp(
+        vpn_service: MockVpnService,
+        app_service: SharedAppService,
+        actions: Vec<VpnAction>,
+        settings: SettingsState,
+        probe: Probe,
+    ) -> Element {
+        rsx! {
+            crate::components::toast::ToastProvider {
+                ProbedConnection { vpn_service, app_service, actions, settings, probe }
+            }
+        }
+    }
+
+    /// Goes through `use_connection` with injected services, the same path
+    /// `AppStateProvider` takes, and copies what the UI would show into `probe`.
+    /// `actions` are sent once on mount, in order, and `settings` are what
+    /// the app starts with.
+    #[component]
+    fn ProbedConnection(
+        vpn_service: MockVpnService,
+        app_service: SharedAppService,
+        actions: Vec<VpnAction>,
+        settings: SettingsState,
+        probe: Probe,
+    ) -> Element {
+        let settings = use_signal(move || settings);
+        let account = AccountState {
+            account_number: use_signal(|| Some("1234".to_string())),
+            auth_token: use_signal(|| Some("token".to_string())),
+            refresh_token: use_signal(|| None),
+            account_expiry: use_signal(|| None),
+            device_name: use_signal(|| "test-device".to_string()),
+        };
+        let vpn_state = use_connection(
+            account,
+            settings,
+            Some(SharedVpnService::new(vpn_service)),
+            Some(app_service),
+        );
+        crate::state::use_startup(settings, account.account_number, vpn_state);
+        let toasts = use_toast();
+        use_hook(move || {
+            for action in actions {
+                vpn_state.vpn_action.send(action);
+            }
+        });
+
+        *probe.0.lock().unwrap() = Snapshot {
+            status: Some((vpn_state.status)()),
+            current_location: (vpn_state.current_location)(),
+            connected_since: (vpn_state.connected_since)().is_some(),
+            download_speed: (vpn_state.download_speed)(),
+            active_port: (vpn_state.active_port)(),
+            stealth_mode: settings().stealth_mode,
+            udp_blocked_networks: settings().udp_blocked_networks,
+            probe_results: (vpn_state.stealth_probe)().results.len(),
+            exit_ready: (vpn_state.exit_ready)(),
+            toasts: toasts.messages(),
+            connection_stats: (vpn_state.connection_stats)(),
+            last_connect: (vpn_state.last_connect)(),
+            single_hop_offer: (vpn_state.single_hop_offer)()
+                .map(|offer| (offer.failed, offer.location)),
+            last_connection: settings().last_connection,
+        };
+
+        rsx! { div {} }
+    }
+
+    async fn settle(dom: &mut VirtualDom) {
+        for _ in 0..20 {
+            match tokio::time::timeout(Duration::from_millis(100), dom.wait_for_work()).await {
+                Ok(()) => dom.render_immediate(&mut NoOpMutations),
+                Err(_) => break,
+            }
+        }
+    }
+
+    async fn mounted(vpn_service: &MockVpnService, probe: &Probe) -> VirtualDom {
+        mounted_with(vpn_service, SharedAppService::new(MockAppService), Vec::new(), probe).await
+    }
+
+    async fn mounted_with(
+        vpn_service: &MockVpnService,
+        app_service: SharedAppService,
+        actions: Vec<VpnAction>,
+        probe: &Probe,
+    ) -> VirtualDom {
+        let settings = SettingsState::default();
+        launched(vpn_service, app_service, actions, settings, probe).await
+    }
+
+    async fn launched(
+        vpn_service: &MockVpnService,
+        app_service: SharedAppService,
+        actions: Vec<VpnAction>,
+        settings: SettingsState,
+        probe: &Probe,
+    ) -> VirtualDom {
+        let mut dom = VirtualDom::new_with_props(
+            ProbeApp,
+            ProbeAppProps {
+                vpn_service: vpn_service.clone(),
+                app_service,
+                actions,
+                settings,
+                probe: probe.clone(),
+            },
+        );
+        dom.rebuild_in_place();
+        settle(&mut dom).await;
+        assert_eq!(vpn_service.subscribers(), 1, "UI should listen for events");
+        dom
+    }
+
+    async fn emit(dom: &mut VirtualDom, vpn_service: &MockVpnService, event: VpnEvent) {
+        vpn_service.emit(event);
+        settle(dom).await;
+    }
+
+    #[tokio::test]
+    async fn status_transitions_drive_ui_state_and_toasts() {
+        let vpn_service = MockVpnService::new();
+        let probe = Probe::default();
+        let mut dom = mounted(&vpn_service, &probe).await;
+
+        let snap = probe.get();
+        assert_eq!(snap.status, Some(ConnectionStatus::Disconnected));
+        assert!(snap.toasts.is_empty());
+
+        emit(&mut dom, &vpn_service, VpnEvent::StatusChanged(ConnectionStatus::Connecting)).await;
+        let snap = probe.get();
+        assert_eq!(snap.status, Some(ConnectionStatus::Connecting));
+        assert!(!snap.connected_since);
+        assert!(snap.toasts.is_empty());
+
+        emit(&mut dom, &vpn_service, VpnEvent::StatusChanged(ConnectionStatus::Connected)).await;
+        let snap = probe.get();
+        assert_eq!(snap.status, Some(ConnectionStatus::Connected));
+        assert!(snap.connected_since);
+        assert_eq!(
+            snap.toasts,
+            vec![("Connected securely".to_string(), ToastType::Success)]
+        );
+
+        emit(&mut dom, &vpn_service, VpnEvent::StatusChanged(ConnectionStatus::Disconnected)).await;
+        let snap = probe.get();
+        assert_eq!(snap.status, Some(ConnectionStatus::Disconnected));
+        assert!(!snap.connected_since);
+        assert_eq!(
+            snap.toasts.last(),
+            Some(&("Disconnected".to_string(), ToastType::Info))
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_attempt_does_not_announce_disconnect() {
+        let vpn_service = MockVpnService::new();
+        let probe = Probe::default();
+        let mut dom = mounted(&vpn_service, &probe).await;
+
+        emit(&mut dom, &vpn_service, VpnEvent::StatusChanged(ConnectionStatus::Connecting)).await;
+        emit(
+            &mut dom,
+            &vpn_service,
+            VpnEvent::Error(VpnError::ConnectionFailed("handshake".to_string())),
+        )
+        .await;
+        emit(&mut dom, &vpn_service, VpnEvent::StatusChanged(ConnectionStatus::Disconnected)).await;
+
+        let snap = probe.get();
+        assert_eq!(snap.status, Some(ConnectionStatus::Disconnected));
+        assert_eq!(
+            snap.toasts,
+            vec![(
+                "Connection failed: handshake".to_string(),
+                ToastType::Error
+            )]
+        );
+        assert_eq!(snap.connection_stats.attempts, 1);
+        assert_eq!(snap.connection_stats.failures["connection_failed"], 1);
+    }
+
+    #[tokio::test]
+    async fn self_healing_reconnect_is_announced_once() {
+        let vpn_service = MockVpnService::new();
+        let probe = Probe::default();
+        let mut dom = mounted(&vpn_service, &probe).await;
+        emit(&mut dom, &vpn_service, VpnEvent::StatusChanged(ConnectionStatus::Connected)).await;
+
+        emit(&mut dom, &vpn_service, VpnEvent::Recovering(RecoveryCause::HealthCheck)).await;
+        for status in [
+            ConnectionStatus::Disconnecting,
+            ConnectionStatus::Disconnected,
+            ConnectionStatus::Connecting,
+            ConnectionStatus::Connected,
+        ] {
+            emit(&mut dom, &vpn_service, VpnEvent::StatusChanged(status)).await;
+        }
+        emit(
+            &mut dom,
+            &vpn_service,
+            VpnEvent::Recovered {
+                cause: RecoveryCause::HealthCheck,
+                new_server: Some("Oslo".to_string()),
+            },
+        )
+        .await;
+
+        // No "Disconnected" or second "Connected securely" in between.
+        assert_eq!(
+            probe.get().toasts,
+            vec![
+                ("Connected securely".to_string(), ToastType::Success),
+                (
+                    "Connection recovered automatically. Switched to Oslo.".to_string(),
+                    ToastType::Success
+                ),
+            ]
+        );
+
+        // Back to normal once the recovery is over.
+        emit(&mut dom, &vpn_service, VpnEvent::StatusChanged(ConnectionStatus::Disconnected)).await;
+        assert_eq!(
+            probe.get().toasts.last(),
+            Some(&("Disconnected".to_string(), ToastType::Info))
+        );
+    }
+
+    #[tokio::test]
+    async fn obfuscation_switch_is_not_a_new_connection() {
+        let vpn_service = MockVpnService::new();
+        let probe = Probe::default();
+        let mut dom = mounted(&vpn_service, &probe).await;
+        for status in [ConnectionStatus::Connecting, ConnectionStatus::Connected] {
+            emit(&mut dom, &vpn_service, VpnEvent::StatusChanged(status)).await;
+        }
+
+        let quic = StealthPort {
+            transport: Transport::Udp,
+            port: 443,
+        };
+        emit(&mut dom, &vpn_service, VpnEvent::StatusChanged(ConnectionStatus::Connecting)).await;
+        assert_eq!(probe.get().status, Some(ConnectionStatus::Connecting));
+        emit(&mut dom, &vpn_service, VpnEvent::TransportChanged(Some(quic))).await;
+        emit(&mut dom, &vpn_service, VpnEvent::StatusChanged(ConnectionStatus::Connected)).await;
+
+        let snap = probe.get();
+        assert_eq!(snap.status, Some(ConnectionStatus::Connected));
+        assert_eq!(snap.active_port, Some(quic));
+        assert!(snap.connected_since);
+        assert_eq!(
+            snap.toasts,
+            vec![("Connected securely".to_string(), ToastType::Success)]
+        );
+        assert_eq!(snap.connection_stats.attempts, 1);
+
+        // The next connect after a disconnect counts again.
+        for status in [
+            ConnectionStatus::Disconnecting,
+            ConnectionStatus::Disconnected,
+            ConnectionStatus::Connecting,
+            ConnectionStatus::Connected,
+        ] {
+            emit(&mut dom, &vpn_service, VpnEvent::StatusChanged(status)).await;
+        }
+        assert_eq!(probe.get().connection_stats.attempts, 2);
+    }
+
+    #[tokio::test]
+    async fn stats_events_update_speeds() {
+        let vpn_service = MockVpnService::new();
+        let probe = Probe::default();
+        let mut dom = mounted(&vpn_service, &probe).await;
+
+        emit(
+            &mut dom,
+            &vpn_service,
+            VpnEvent::StatsUpdated(VpnStats {
+                download_speed: 12.5,
+                upload_speed: 3.0,
+                total_download: 0,
+                total_upload: 0,
+                latest_handshake: 0,
+            }),
+        )
+        .await;
+        assert_eq!(probe.get().download_speed, 12.5);
+    }
+
+    #[tokio::test]
+    async fn transport_events_track_the_active_port() {
+        let vpn_service = MockVpnService::new();
+        let probe = Probe::default();
+        let mut dom = mounted(&vpn_service, &probe).await;
+        assert_eq!(probe.get().active_port, None);
+
+        let port = StealthPort {
+            transport: Transport::Tcp,
+            port: 443,
+        };
+        emit(&mut dom, &vpn_service, VpnEvent::TransportChanged(Some(port))).await;
+        assert_eq!(probe.get().active_port, Some(port));
+
+        emit(&mut dom, &vpn_service, VpnEvent::TransportChanged(None)).await;
+        assert_eq!(probe.get().active_port, None);
+    }
+
+    #[tokio::test]
+    async fn udp_blocked_networks_are_remembered() {
+        let vpn_service = MockVpnService::new();
+        let probe = Probe::default();
+        let mut dom = mounted(&vpn_service, &probe).await;
+
+        let network = Some("4f1c9a7d20be63e5".to_string());
+        emit(&mut dom, &vpn_service, VpnEvent::UdpBlocked(network.clone())).await;
+        // Unidentified networks switch for this connection only.
+        emit(&mut dom, &vpn_service, VpnEvent::UdpBlocked(None)).await;
+
+        let snap = probe.get();
+        assert_eq!(snap.udp_blocked_networks, ["4f1c9a7d20be63e5"]);
+        assert_eq!(snap.toasts.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn mock_records_calls_without_changing_status() {
+        let vpn_service = MockVpnService::new();
+        let probe = Probe::default();
+        let _dom = mounted(&vpn_service, &probe).await;
+
+        assert_eq!(vpn_service.calls(), vec![MockCall::ApplyLockdown]);
+        vpn_service.disconnect().await;
+        assert_eq!(
+            vpn_service.get_status().await,
+            ConnectionStatus::Disconnected
+        );
+        assert_eq!(vpn_service.calls().last(), Some(&MockCall::Disconnect));
+    }
+
+    #[tokio::test]
+    async fn quit_tears_down_before_the_window_closes() {
+        let vpn_service = MockVpnService::new();
+        let probe = Probe::default();
+        let app_service = SharedAppService::new(MockAppService);
+        let _dom = mounted_with(&vpn_service, app_service, vec![VpnAction::Quit], &probe).await;
+
+        assert!(probe.get().exit_ready);
+        let teardown: Vec<MockCall> = vpn_service
+            .calls()
+            .into_iter()
+            .filter(|call| *call != MockCall::ApplyLockdown)
+            .collect();
+        assert_eq!(teardown, [MockCall::Disconnect, MockCall::DisableKillSwitch]);
+    }
+
+    #[tokio::test]
+    async fn finding_a_stealth_mode_switches_to_one_that_works() {
+        let vpn_service = MockVpnService::new();
+        vpn_service.on_connect(|settings| match settings.stealth_mode {
+            StealthMode::Quic => vec![
+                VpnEvent::StatusChanged(ConnectionStatus::Connected),
+                VpnEvent::StatsUpdated(VpnStats {
+                    download_speed: 0.0,
+                    upload_speed: 0.0,
+                    total_download: 0,
+                    total_upload: 0,
+                    latest_handshake: 1_700_000_000,
+                }),
+            ],
+            _ => vec![
+                VpnEvent::Error(VpnError::ConnectionFailed("blocked".to_string())),
+                VpnEvent::StatusChanged(ConnectionStatus::Disconnected),
+            ],
+        });
+        let probe = Probe::default();
+        let app_service = SharedAppService::new(MockAppService);
+        let actions = vec![VpnAction::FindStealthMode];
+        let _dom = mounted_with(&vpn_service, app_service, actions, &probe).await;
+
+        let snap = probe.get();
+        assert_eq!(snap.stealth_mode, StealthMode::Quic);
+        assert_eq!(snap.probe_results, 7);
+        assert!(snap.toasts.contains(&(
+            "Switched to the fastest working protocol".to_string(),
+            ToastType::Success
+        )));
+        let attempts = vpn_service
+            .calls()
+            .iter()
+            .filter(|call| matches!(call, MockCall::Connect { .. }))
+            .count();
+        assert_eq!(attempts, 7);
+    }
+
+    fn relay(country: &str, city: &str, host: &str, latency: u32) -> CommonVpnServer {
+        CommonVpnServer {
+            country: country.to_string(),
+            city: city.to_string(),
+            endpoint: format!("{}:51820", host),
+            public_key: format!("{}-key", host),
+            current_load: 0,
+            avg_latency: latency,
+        }
+    }
+
+    /// Answers from a fixed relay list: the lowest `avg_latency` wins, and
+    /// configs carry the endpoint/key of the relay serving that location.
+    #[derive(Clone)]
+    struct RelayListAppService(Vec<CommonVpnServer>);
+
+    impl RelayListAppService {
+        fn best(candidates: Vec<CommonVpnServer>) -> Result<CommonVpnServer, AppError> {
+            candidates
+                .into_iter()
+                .min_by_key(|s| s.avg_latency)
+                .ok_or_else(|| AppError::Vpn("No servers found".to_string()))
+        }
+
+        fn in_country(&self, country: Option<&str>) -> Vec<CommonVpnServer> {
+            self.0
+                .iter()
+                .filter(|s| country.is_none_or(|c| s.country == c))
+                .cloned()
+                .collect()
+        }
+    }
+
+    #[async_trait]
+    impl AppService for RelayListAppService {
+        async fn find_best_server(
+            &self,
+            country: Option<&str>,
+        ) -> Result<CommonVpnServer, AppError> {
+            Self::best(self.in_country(country))
+        }
+
+        async fn find_best_server_excluding(
+            &self,
+            country: Option<&str>,
+            exclude: &[String],
+        ) -> Result<CommonVpnServer, AppError> {
+            Self::best(ServersService::exclude_servers(
+                &self.in_country(country),
+                exclude,
+                true,
+            ))
+        }
+
+        async fn get_anonymous_config(
+            &self,
+            location: &str,
+            _token: &str,
+            _settings: &ServerSettings,
+        ) -> Result<WireGuardConfig, AppError> {
+            let server = self
+                .0
+                .iter()
+                .find(|s| format!("{}, {}", s.country, s.city) == location)
+                .ok_or_else(|| AppError::Vpn(format!("Unknown location {}", location)))?;
+            Ok(WireGuardConfig {
+                private_key: "priv".to_string(),
+                public_key: server.public_key.clone(),
+                endpoint: server.endpoint.clone(),
+                allowed_ips: "0.0.0.0/0".to_string(),
+                address: "10.0.0.2/32".to_string(),
+                dns: None,
+                preshared_key: None,
+                obfuscation_key: None,
+                obfuscation_port: None,
+                obfuscation_cipher: None,
+                pqc_ciphertext: None,
+                pqc_handshake: None,
+                pqc_provider: None,
+            })
+        }
+
+        async fn get_servers(&self) -> Result<Vec<CommonVpnServer>, AppError> {
+            Ok(self.0.clone())
+        }
+
+        async fn measure_latency(&self, _endpoint: &str) -> Option<u32> {
+            None
+        }
+    }
+
+    async fn run_action(
+        relays: Vec<CommonVpnServer>,
+        action: VpnAction,
+    ) -> (MockVpnService, Snapshot) {
+        run_actions(relays, vec![action]).await
+    }
+
+    async fn run_actions(
+        relays: Vec<CommonVpnServer>,
+        actions: Vec<VpnAction>,
+    ) -> (MockVpnService, Snapshot) {
+        let vpn_service = MockVpnService::new();
+        let probe = Probe::default();
+        let app_service = SharedAppService::new(RelayListAppService(relays));
+        let _dom = mounted_with(&vpn_service, app_service, actions, &probe).await;
+        (vpn_service, probe.get())
+    }
+
+    fn connects(vpn_service: &MockVpnService) -> Vec<MockCall> {
+        vpn_service
+            .calls()
+            .into_iter()
+            .filter(|call| matches!(call, MockCall::Connect { .. }))
+            .collect()
+    }
+
+    fn connect_call(entry: &str, exit: Option<&str>) -> MockCall {
+        MockCall::Connect {
+            entry: entry.to_string(),
+            exit: exit.map(str::to_string),
+        }
+    }
+
+    #[tokio::test]
+    async fn automatic_connect_picks_best_server() {
+        let relays = vec![
+            relay("Sweden", "Stockholm", "10.1.0.1", 40),
+            relay("Germany", "Frankfurt", "10.2.0.1", 10),
+            relay("Sweden", "Gothenburg", "10.1.0.2", 25),
+        ];
+
+        let (vpn_service, snap) =
+            run_action(relays.clone(), VpnAction::Connect(LocationSelection::Automatic)).await;
+        assert_eq!(connects(&vpn_service), vec![connect_call("Germany, Frankfurt", None)]);
+        assert_eq!(snap.current_location, "Germany, Frankfurt");
+
+        let sweden = LocationSelection::BestIn("Sweden".to_string());
+        let (vpn_service, snap) = run_action(relays, VpnAction::Connect(sweden)).await;
+        assert_eq!(connects(&vpn_service), vec![connect_call("Sweden, Gothenburg", None)]);
+        assert_eq!(snap.current_location, "Sweden, Gothenburg");
+    }
+
+    #[tokio::test]
+    async fn automatic_connect_stays_on_the_pinned_server() {
+        let relays = vec![
+            relay("Sweden", "Stockholm", "10.1.0.1", 40),
+            relay("Germany", "Frankfurt", "10.2.0.1", 10),
+        ];
+        let settings = SettingsState {
+            pinned_server: Some("Sweden, Stockholm".to_string()),
+            ..SettingsState::default()
+        };
+        let vpn_service = MockVpnService::new();
+        let probe = Probe::default();
+        let _dom = launched(
+            &vpn_service,
+            SharedAppService::new(RelayListAppService(relays)),
+            vec![VpnAction::Connect(LocationSelection::Automatic)],
+            settings,
+            &probe,
+        )
+        .await;
+        assert_eq!(connects(&vpn_service), vec![connect_call("Sweden, Stockholm", None)]);
+    }
+
+    #[tokio::test]
+    async fn city_named_like_auto_connects_directly() {
+        // Anything but the lowest-latency relay proves no auto-selection ran.
+        let relays = vec![
+            relay("Germany", "Frankfurt", "10.2.0.1", 10),
+            relay("Spain", "Autol", "10.3.0.1", 90),
+        ];
+        let (vpn_service, snap) = run_action(
+            relays,
+            VpnAction::Connect(Location::new("Spain", "Autol").into()),
+        )
+        .await;
+        assert_eq!(connects(&vpn_service), vec![connect_call("Spain, Autol", None)]);
+        assert!(snap.toasts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn automatic_connect_reports_lookup_failure() {
+        let (vpn_service, snap) =
+            run_action(Vec::new(), VpnAction::Connect(LocationSelection::Automatic)).await;
+        assert!(connects(&vpn_service).is_empty());
+        assert!(snap.toasts.iter().any(|(_, kind)| *kind == ToastType::Error));
+    }
+
+    #[tokio::test]
+    async fn successful_connect_keeps_its_timing() {
+        let vpn_service = MockVpnService::new();
+        vpn_service.on_connect(|_| vec![VpnEvent::StatusChanged(ConnectionStatus::Connected)]);
+        let probe = Probe::default();
+        let app_service = SharedAppService::new(RelayListAppService(vec![relay(
+            "Germany",
+            "Frankfurt",
+            "10.2.0.1",
+            10,
+        )]));
+        let action = VpnAction::Connect(LocationSelection::Automatic);
+        let _dom = mounted_with(&vpn_service, app_service, vec![action], &probe).await;
+
+        let timing = probe.get().last_connect.expect("connect was timed");
+        assert!(timing.phase(Phase::FindServer).is_some());
+        assert!(timing.summary().starts_with("connect took "));
+
+        // An attempt that never connects records nothing.
+        let (_, snap) =
+            run_action(Vec::new(), VpnAction::Connect(LocationSelection::Automatic)).await;
+        assert_eq!(snap.last_connect, None);
+    }
+
+    fn remembering() -> SettingsState {
+        SettingsState {
+            startup_behavior: StartupBehavior::ConnectLast,
+            ..SettingsState::default()
+        }
+    }
+
+    /// Starts the app with `settings` against a backend that connects
+    /// whenever asked, and runs `actions`.
+    async fn session(
+        relays: Vec<CommonVpnServer>,
+        actions: Vec<VpnAction>,
+        settings: SettingsState,
+    ) -> (MockVpnService, Snapshot) {
+        let vpn_service = MockVpnService::new();
+        vpn_service.on_connect(|_| vec![VpnEvent::StatusChanged(ConnectionStatus::Connected)]);
+        let probe = Probe::default();
+        let app_service = SharedAppService::new(RelayListAppService(relays));
+        let _dom = launched(&vpn_service, app_service, actions, settings, &probe).await;
+        (vpn_service, probe.get())
+    }
+
+    /// A launch with saved `settings` and no clicks; whatever connects comes
+    /// from the startup behavior.
+    async fn relaunched(
+        relays: Vec<CommonVpnServer>,
+        settings: SettingsState,
+    ) -> (MockVpnService, Snapshot) {
+        session(relays, Vec::new(), settings).await
+    }
+
+    #[tokio::test]
+    async fn connection_open_at_quit_is_resumed_on_the_same_server() {
+        let frankfurt = relay("Germany", "Frankfurt", "10.2.0.1", 10);
+        let actions = vec![
+            VpnAction::Connect(LocationSelection::Automatic),
+            VpnAction::Quit,
+        ];
+        let (_, snap) = session(vec![frankfurt.clone()], actions, remembering()).await;
+        let last = snap.last_connection.expect("connection remembered");
+        assert_eq!(last.entry, "Germany, Frankfurt");
+        assert!(last.connected, "quitting is not a disconnect");
+
+        // An automatic pick would take the faster relay.
+        let relays = vec![relay("Sweden", "Stockholm", "10.1.0.1", 5), frankfurt];
+        let relaunch = SettingsState {
+            last_connection: Some(last),
+            ..remembering()
+        };
+        let (vpn_service, _) = relaunched(relays, relaunch).await;
+        assert_eq!(
+            connects(&vpn_service),
+            vec![connect_call("Germany, Frankfurt", None)]
+        );
+    }
+
+    #[tokio::test]
+    async fn disconnecting_before_quit_skips_the_resume() {
+        let relays = vec![relay("Germany", "Frankfurt", "10.2.0.1", 10)];
+        let actions = vec![
+            VpnAction::Connect(LocationSelection::Automatic),
+            VpnAction::Disconnect,
+            VpnAction::Quit,
+        ];
+        let (_, snap) = session(relays.clone(), actions, remembering()).await;
+        let last = snap.last_connection.expect("servers kept");
+        assert!(!last.connected);
+
+        let relaunch = SettingsState {
+            last_connection: Some(last),
+            ..remembering()
+        };
+        let (vpn_service, _) = relaunched(relays, relaunch).await;
+        assert!(connects(&vpn_service).is_empty());
+    }
+
+    #[tokio::test]
+    async fn nothing_is_remembered_unless_asked() {
+        let relays = vec![relay("Germany", "Frankfurt", "10.2.0.1", 10)];
+        let actions = vec![VpnAction::Connect(LocationSelection::Automatic)];
+        let (_, snap) = session(relays, actions, SettingsState::default()).await;
+        assert_eq!(snap.last_connection, None);
+    }
+
+    #[tokio::test]
+    async fn resume_under_lockdown_keeps_the_firewall_up() {
+        let relaunch = SettingsState {
+            lockdown_mode: true,
+            last_connection: Some(LastConnection {
+                entry: "Spain, Autol".to_string(),
+                exit: Some("Germany, Frankfurt".to_string()),
+                connected: true,
+            }),
+            ..remembering()
+        };
+        let relays = vec![
+            relay("Germany", "Frankfurt", "10.2.0.1", 10),
+            relay("Spain", "Autol", "10.3.0.1", 90),
+        ];
+        let (vpn_service, _) = relaunched(relays, relaunch).await;
+
+        let calls = vpn_service.calls();
+        assert_eq!(calls.first(), Some(&MockCall::ApplyLockdown));
+        assert_eq!(
+            connects(&vpn_service),
+            vec![connect_call("Spain, Autol", Some("Germany, Frankfurt"))]
+        );
+        assert!(!calls.contains(&MockCall::DisableKillSwitch));
+    }
+
+    #[tokio::test]
+    async fn automatic_multihop_uses_distinct_servers() {
+        let relays = vec![
+            relay("Sweden", "Stockholm", "10.1.0.1", 40),
+            relay("Germany", "Frankfurt", "10.2.0.1", 10),
+        ];
+        let (vpn_service, _) = run_action(
+            relays,
+            VpnAction::MultiHopConnect(LocationSelection::Automatic, LocationSelection::Automatic),
+        )
+        .await;
+        assert_eq!(
+            connects(&vpn_service),
+            vec![connect_call("Germany, Frankfurt", Some("Sweden, Stockholm"))]
+        );
+    }
+
+    #[tokio::test]
+    async fn multihop_replaces_exit_on_the_same_relay() {
+        // Two locations served by the same box: the exit must move elsewhere.
+        let relays = vec![
+            relay("Germany", "Frankfurt", "10.2.0.1", 10),
+            relay("Germany", "Berlin", "10.2.0.1", 15),
+            relay("Sweden", "Stockholm", "10.1.0.1", 40),
+        ];
+        let (vpn_service, _) = run_action(
+            relays,
+            VpnAction::MultiHopConnect(
+                Location::new("Germany", "Frankfurt").into(),
+                Location::new("Germany", "Berlin").into(),
+            ),
+        )
+        .await;
+        assert_eq!(
+            connects(&vpn_service),
+            vec![connect_call("Germany, Frankfurt", Some("Sweden, Stockholm"))]
+        );
+    }
+
+    #[tokio::test]
+    async fn multihop_without_a_second_relay_does_not_connect() {
+        let relays = vec![
+            relay("Germany", "Frankfurt", "10.2.0.1", 10),
+            relay("Germany", "Berlin", "10.2.0.1", 15),
+        ];
+        let (vpn_service, snap) = run_action(
+            relays,
+            VpnAction::MultiHopConnect(
+                Location::new("Germany", "Frankfurt").into(),
+                Location::new("Germany", "Berlin").into(),
+            ),
+        )
+        .await;
+        assert!(connects(&vpn_service).is_empty());
+        assert!(snap.toasts.iter().any(|(_, kind)| *kind == ToastType::Error));
+    }
+
+    fn multihop(entry: (&str, &str), exit: (&str, &str)) -> VpnAction {
+        VpnAction::MultiHopConnect(
+            Location::new(entry.0, entry.1).into(),
+            Location::new(exit.0, exit.1).into(),
+        )
+    }
+
+    #[tokio::test]
+    async fn multihop_entry_failure_names_the_hop_and_offers_the_exit() {
+        // The relay list has no server for the entry location.
+        let relays = vec![relay("Sweden", "Stockholm", "10.1.0.1", 40)];
+        let (vpn_service, snap) =
+            run_action(relays, multihop(("Atlantis", "Poseidonia"), ("Sweden", "Stockholm")))
+                .await;
+
+        assert!(connects(&vpn_service).is_empty());
+        assert_eq!(
+            snap.toasts,
+            vec![(
+                "Entry server Atlantis, Poseidonia failed: VPN Connection Error: Unknown location Atlantis, Poseidonia"
+                    .to_string(),
+                ToastType::Error
+            )]
+        );
+        assert_eq!(
+            snap.single_hop_offer,
+            Some((Hop::Entry, "Sweden, Stockholm".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn multihop_exit_failure_falls_back_only_once_accepted() {
+        let relays = vec![relay("Sweden", "Stockholm", "10.1.0.1", 40)];
+        let failing = multihop(("Sweden", "Stockholm"), ("Atlantis", "Poseidonia"));
+
+        let (vpn_service, snap) = run_action(relays.clone(), failing.clone()).await;
+        assert!(connects(&vpn_service).is_empty());
+        assert_eq!(snap.single_hop_offer, Some((Hop::Exit, "Sweden, Stockholm".to_string())));
+        assert!(snap.toasts[0].0.starts_with("Exit server Atlantis, Poseidonia failed:"));
+
+        let (vpn_service, snap) =
+            run_actions(relays, vec![failing, VpnAction::AcceptSingleHop]).await;
+        assert_eq!(connects(&vpn_service), vec![connect_call("Sweden, Stockholm", None)]);
+        assert_eq!(snap.single_hop_offer, None);
+        assert_eq!(snap.current_location, "Sweden, Stockholm");
+    }
+
+    #[tokio::test]
+    async fn multihop_failing_on_both_hops_reports_each_and_offers_nothing() {
+        let relays = vec![relay("Sweden", "Stockholm", "10.1.0.1", 40)];
+        let (vpn_service, snap) = run_actions(
+            relays,
+            vec![
+                multihop(("Atlantis", "Poseidonia"), ("Lemuria", "Kumari")),
+                VpnAction::AcceptSingleHop,
+            ],
+        )
+        .await;
+
+        assert!(connects(&vpn_service).is_empty());
+        assert_eq!(snap.single_hop_offer, None);
+        let (message, kind) = &snap.toasts[0];
+        assert_eq!(*kind, ToastType::Error);
+        assert!(message.starts_with("Multi-hop failed on both servers."));
+        assert!(message.contains("Entry server Atlantis, Poseidonia failed"));
+        assert!(message.contains("Exit server Lemuria, Kumari failed"));
+    }
+
+    /// Records which network calls the server loop makes.
+    #[derive(Clone)]
+    struct CountingAppService {
+        relays: RelayListAppService,
+        calls: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    impl PartialEq for CountingAppService {
+        fn eq(&self, other: &Self) -> bool {
+            Arc::ptr_eq(&self.calls, &other.calls)
+        }
+    }
+
+    impl CountingAppService {
+        fn calls(&self) -> Vec<&'static str> {
+            self.calls.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl AppService for CountingAppService {
+        async fn find_best_server(
+            &self,
+            country: Option<&str>,
+        ) -> Result<CommonVpnServer, AppError> {
+            self.relays.find_best_server(country).await
+        }
+
+        async fn find_best_server_excluding(
+            &self,
+            country: Option<&str>,
+            exclude: &[String],
+        ) -> Result<CommonVpnServer, AppError> {
+            self.relays.find_best_server_excluding(country, exclude).await
+        }
+
+        async fn get_anonymous_config(
+            &self,
+            location: &str,
+            token: &str,
+            settings: &ServerSettings,
+        ) -> Result<WireGuardConfig, AppError> {
+            self.relays
+                .get_anonymous_config(location, token, settings)
+                .await
+        }
+
+        async fn get_servers(&self) -> Result<Vec<CommonVpnServer>, AppError> {
+            self.calls.lock().unwrap().push("get_servers");
+            self.relays.get_servers().await
+        }
+
+        async fn measure_latency(&self, endpoint: &str) -> Option<u32> {
+            self.calls.lock().unwrap().push("measure_latency");
+            self.relays.measure_latency(endpoint).await
+        }
+    }
+
+    /// Hands the offline signal of a mounted [`OfflineServers`] to the test.
+    #[derive(Clone, Default)]
+    struct OfflineSwitch(Rc<Cell<Option<Signal<bool>>>>);
+
+    impl PartialEq for OfflineSwitch {
+        fn eq(&self, other: &Self) -> bool {
+            Rc::ptr_eq(&self.0, &other.0)
+        }
+    }
+
+    impl OfflineSwitch {
+        fn set(&self, dom: &VirtualDom, offline: bool) {
+            let mut signal = self.0.get().expect("component not mounted");
+            dom.in_runtime(|| signal.set(offline));
+        }
+    }
+
+    #[component]
+    fn OfflineServers(service: CountingAppService, switch: OfflineSwitch) -> Element {
+        let offline = use_signal(|| true);
+        use_hook(|| switch.0.set(Some(offline)));
+        let battery_saver = use_signal(|| false);
+        use_servers_with_service(service, offline.into(), battery_saver.into());
+        rsx! { div {} }
+    }
+
+    #[tokio::test]
+    async fn server_sync_and_latency_probes_hold_while_offline() {
+        let service = CountingAppService {
+            relays: RelayListAppService(vec![relay("Sweden", "Stockholm", "10.1.0.1", 40)]),
+            calls: Arc::default(),
+        };
+        let switch = OfflineSwitch::default();
+        let mut dom = VirtualDom::new_with_props(
+            OfflineServers,
+            OfflineServersProps {
+                service: service.clone(),
+                switch: switch.clone(),
+            },
+        );
+        dom.rebuild_in_place();
+        settle(&mut dom).await;
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        settle(&mut dom).await;
+        assert!(service.calls().is_empty(), "offline mode let {:?} out", service.calls());
+
+        switch.set(&dom, false);
+        for _ in 0..40 {
+            if service.calls().len() >= 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            settle(&mut dom).await;
+        }
+        assert_eq!(service.calls(), vec!["get_servers", "measure_latency"]);
+    }
+
+    #[test]
+    fn controls_lock_during_transitions_until_the_lock_runs_out() {
+        let settled = [ConnectionStatus::Connected, ConnectionStatus::Disconnected];
+        let busy = [
+            ConnectionStatus::Connecting,
+            ConnectionStatus::Disconnecting,
+        ];
+        for status in settled {
+            assert!(controls_enabled(status, Duration::ZERO), "{:?}", status);
+        }
+        for status in busy {
+            assert!(!controls_enabled(status, Duration::ZERO), "{:?}", status);
+            let almost = TRANSITION_LOCK - Duration::from_millis(1);
+            assert!(!controls_enabled(status, almost), "{:?}", status);
+            // A hung attempt does not lock the UI for good.
+            assert!(controls_enabled(status, TRANSITION_LOCK), "{:?}", status);
+        }
+    }
+
+    /// Hands the account signal of a mounted [`SessionLoop`] to the test and
+    /// counts the rounds its loop has run.
+    #[derive(Clone, Default)]
+    struct Session {
+        account: Rc<Cell<Option<Signal<Option<String>>>>>,
+        rounds: Arc<Mutex<u32>>,
+    }
+
+    impl PartialEq for Session {
+        fn eq(&self, other: &Self) -> bool {
+            Rc::ptr_eq(&self.account, &other.account)
+        }
+    }
+
+    impl Session {
+        fn set(&self, dom: &VirtualDom, account: Option<&str>) {
+            let mut signal = self.account.get().expect("component not mounted");
+            dom.in_runtime(|| signal.set(account.map(str::to_string)));
+        }
+
+        fn rounds(&self) -> u32 {
+            *self.rounds.lock().unwrap()
+        }
+
+        /// Rounds run over the next 200ms.
+        async fn rounds_soon(&self, dom: &mut VirtualDom) -> u32 {
+            let before = self.rounds();
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            settle(dom).await;
+            self.rounds() - before
+        }
+    }
+
+    #[component]
+    fn SessionLoop(session: Session) -> Element {
+        let account = use_signal(|| Some("1234567890123456".to_string()));
+        use_hook(|| session.account.set(Some(account)));
+        let rounds = session.rounds.clone();
+        use_signed_in_future(account.into(), move || {
+            let rounds = rounds.clone();
+            async move {
+                loop {
+                    *rounds.lock().unwrap() += 1;
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+            }
+        });
+        rsx! { div {} }
+    }
+
+    #[tokio::test]
+    async fn clearing_the_account_stops_session_loops_until_next_login() {
+        let session = Session::default();
+        let mut dom = VirtualDom::new_with_props(
+            SessionLoop,
+            SessionLoopProps {
+                session: session.clone(),
+            },
+        );
+        dom.rebuild_in_place();
+        settle(&mut dom).await;
+        assert!(session.rounds_soon(&mut dom).await > 0);
+
+        session.set(&dom, None);
+        settle(&mut dom).await;
+        assert_eq!(session.rounds_soon(&mut dom).await, 0);
+
+        session.set(&dom, Some("6543210987654321"));
+        settle(&mut dom).await;
+        assert!(session.rounds_soon(&mut dom).await > 0);
+    }
+
+    #[derive(Clone, Default)]
+    struct Writes(Arc<Mutex<Vec<u32>>>);
+
+    impl PartialEq for Writes {
+        fn eq(&self, other: &Self) -> bool {
+            Arc::ptr_eq(&self.0, &other.0)
+        }
+    }
+
+    /// Persists a counter that a task bumps five times in quick succession,
+    /// then sets to the same final value again once it has been written.
+    #[component]
+    fn RapidChanges(writes: Writes) -> Element {
+        let mut value = use_signal(|| 0u32);
+        use_persist(move || value(), Duration::from_millis(100), move |v| {
+            writes.0.lock().unwrap().push(v);
+            Ok(())
+        });
+        use_hook(|| {
+            spawn(async move {
+                for v in 1..=5 {
+                    value.set(v);
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                value.set(5);
+            })
+        });
+        rsx! { div {} }
+    }
+
+    #[tokio::test]
+    async fn rapid_changes_are_written_once_and_repeats_skipped() {
+        let writes = Writes::default();
+        let mut dom = VirtualDom::new_with_props(
+            RapidChanges,
+            RapidChangesProps {
+                writes: writes.clone(),
+            },
+        );
+        dom.rebuild_in_place();
+        for _ in 0..16 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            settle(&mut dom).await;
+        }
+        assert_eq!(*writes.0.lock().unwrap(), vec![5]);
+    }
+}
@@ -1,3 +1,5 @@
+use crate::hooks::use_persist::{use_persist, PERSIST_DELAY};
+use crate::hooks::{use_signed_in_future, wait_until_online};
 use crate::storage::{load_config, AppConfig};
 use dioxus::prelude::*;
 use std::time::Duration;
@@ -11,12 +13,12 @@ pub struct AccountState {
     pub device_name: Signal<String>,
 }
 
-pub fn use_account(initial_config: &AppConfig) -> AccountState {
+pub fn use_account(initial_config: &AppConfig, offline: ReadOnlySignal<bool>) -> AccountState {
     let account_number = use_signal(|| initial_config.account_number.clone());
     let mut auth_token = use_signal(|| initial_config.auth_token.clone());
     let mut refresh_token = use_signal(|| initial_config.refresh_token.clone());
     let account_expiry = use_signal(|| initial_config.account_expiry);
-    let device_name = use_signal(|| {
+    let mut device_name = use_signal(|| {
         initial_config
             .device_name
             .clone()
@@ -24,25 +26,28 @@ pub fn use_account(initial_config: &AppConfig) -> AccountState {
     });
 
     // Auto-save auth info
-    use_effect(move || {
-        let acc = account_number();
-        let auth = auth_token();
-        let refresh = refresh_token();
-        let exp = account_expiry();
-        let dev = device_name();
-        spawn(async move {
-            tokio::time::sleep(Duration::from_secs(1)).await;
-            let _ = tokio::task::spawn_blocking(move || {
-                crate::storage::save_auth_info(acc, auth, refresh, exp, Some(dev))
-            })
-            .await;
-        });
-    });
+    use_persist(
+        move || {
+            (
+                account_number(),
+                auth_token(),
+                refresh_token(),
+                account_expiry(),
+                device_name(),
+            )
+        },
+        PERSIST_DELAY,
+        |(acc, auth, refresh, exp, dev)| {
+            crate::storage::save_auth_info(acc, auth, refresh, exp, Some(dev))
+        },
+    );
 
-    // Sync tokens from disk (in case another window/process updates them)
-    use_future(move || async move {
+    // Sync tokens from disk (in case another window/process updates them).
+    // Stopped while logged out, so a logout is not undone by a stale read.
+    use_signed_in_future(account_number.into(), move || async move {
         loop {
             tokio::time::sleep(Duration::from_secs(15)).await;
+            wait_until_online(offline).await;
             let cfg = tokio::task::spawn_blocking(load_config)
                 .await
                 .unwrap_or_default();
@@ -55,6 +60,13 @@ pub fn use_account(initial_config: &AppConfig) -> AccountState {
             if current_refresh != cfg.refresh_token {
                 refresh_token.set(cfg.refresh_token.clone());
             }
+            // Logging in again to register a new device key can move the
+            // account to a new device.
+            if let Some(name) = cfg.device_name {
+                if *device_name.peek() != name {
+                    device_name.set(name);
+                }
+            }
         }
     });
 
@@ -1,34 +1,69 @@
 use crate::components::toast::{ToastManager, ToastType};
 use crate::hooks::use_account::AccountState;
-use crate::models::{ConnectionStatus, SettingsState, VpnAction};
-use crate::services::vpn::{VpnEvent, VpnService, WireGuardService};
-use crate::services::{AppService, ProductionAppService};
+use crate::error::AppError;
+use crate::models::{
+    ConnectionStatus, Hop, Location, LocationSelection, SettingsState, SingleHopOffer,
+    StartupBehavior, VpnAction,
+};
+use crate::services::connect_timing::{self, timed, ConnectTiming, Phase};
+use crate::services::connection_stats::{AttemptTracker, ConnectionStats};
+use crate::services::servers::ServersService;
+use crate::services::stealth_probe::{
+    fastest, probe_stealth_modes, ProbeTarget, StealthProbe, PROBE_ORDER, PROBE_TIMEOUT,
+};
+use crate::services::vpn::{
+    shut_down, SharedVpnService, StealthPort, VpnEvent, VpnService, WireGuardService,
+};
+use crate::services::{AppService, SharedAppService};
 use chrono::Utc;
 use dioxus::prelude::*;
 use futures_util::StreamExt;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 #[derive(Clone, Copy)]
 pub struct VpnState {
     pub status: Signal<ConnectionStatus>,
     pub current_location: Signal<String>,
+    /// Unix time the tunnel came up, for display. Durations must not be
+    /// derived from it; the wall clock can step while connected.
     pub connected_since: Signal<Option<f64>>,
     pub download_speed: Signal<f64>,
     pub upload_speed: Signal<f64>,
+    /// Port the entry hop is using while connected.
+    pub active_port: Signal<Option<StealthPort>>,
+    /// Progress and results of [`VpnAction::FindStealthMode`].
+    pub stealth_probe: Signal<StealthProbe>,
+    /// Outcomes of connection attempts, excluding protocol finder runs.
+    pub connection_stats: Signal<ConnectionStats>,
+    /// Where the time of the last successful connect went.
+    pub last_connect: Signal<Option<ConnectTiming>>,
+    /// Set when a multihop connect failed on one hop only.
+    pub single_hop_offer: Signal<Option<SingleHopOffer>>,
     pub vpn_action: Coroutine<VpnAction>,
+    /// Set once [`VpnAction::Quit`] has torn the connection down.
+    pub exit_ready: Signal<bool>,
+    /// Set once the lockdown setting the app launched with has been applied,
+    /// or has failed to apply.
+    pub lockdown_applied: Signal<bool>,
 }
 
+/// Runs the connection hooks against the injected services, falling back to
+/// the real WireGuard backend and API client for whichever is `None`.
 pub fn use_connection(
     account_state: AccountState,
     settings: Signal<SettingsState>,
+    vpn_service: Option<SharedVpnService>,
+    app_service: Option<SharedAppService>,
 ) -> VpnState {
-    let vpn_service = use_hook(WireGuardService::new);
-    let app_service = use_hook(|| ProductionAppService);
+    let vpn_service = use_hook(move || {
+        vpn_service.unwrap_or_else(|| SharedVpnService::new(WireGuardService::new()))
+    });
+    let app_service = use_hook(move || app_service.unwrap_or_default());
     
     use_connection_internal(account_state, settings, vpn_service, app_service)
 }
 
-pub fn use_connection_with_service<S: AppService, V: VpnService + Clone + 'static>(
+pub fn use_connection_with_service<S: AppService + Clone, V: VpnService + Clone + 'static>(
     account_state: AccountState,
     settings: Signal<SettingsState>,
     vpn_service: V,
@@ -37,9 +72,9 @@ pub fn use_connection_with_service<S: AppService, V: VpnService + Clone + 'stati
     use_connection_internal(account_state, settings, vpn_service, app_service)
 }
 
-fn use_connection_internal<S: AppService, V: VpnService + Clone + 'static>(
+fn use_connection_internal<S: AppService + Clone, V: VpnService + Clone + 'static>(
     account_state: AccountState,
-    settings: Signal<SettingsState>,
+    mut settings: Signal<SettingsState>,
     vpn_service: V,
     app_service: S,
 ) -> VpnState {
@@ -48,7 +83,13 @@ fn use_connection_internal<S: AppService, V: VpnService + Clone + 'static>(
     let mut connected_since = use_signal(|| None);
     let mut download_speed = use_signal(|| 0.0);
     let mut upload_speed = use_signal(|| 0.0);
-    let mut auto_connect_started = use_signal(|| false);
+    let mut active_port = use_signal(|| None);
+    let mut stealth_probe = use_signal(StealthProbe::default);
+    let mut connection_stats = use_signal(ConnectionStats::default);
+    let mut last_connect = use_signal(|| None);
+    let mut single_hop_offer = use_signal(|| None);
+    let mut exit_ready = use_signal(|| false);
+    let mut lockdown_applied = use_signal(|| false);
 
     let toast_manager = use_context::<ToastManager>();
 
@@ -64,137 +105,361 @@ fn use_connection_internal<S: AppService, V: VpnService + Clone + 'static>(
         let mut toasts = toast_manager;
         async move {
             while let Some(msg) = rx.next().await {
-                match msg {
-                    VpnAction::Connect(mut location) => {
-                        let acc_num = account_number.peek().clone().unwrap_or_default();
-                        let token = auth_token.peek().clone().unwrap_or_default();
-                        if acc_num.is_empty() {
-                            toasts.show("Please log in first", ToastType::Error);
-                            continue;
-                        }
+                if !matches!(msg, VpnAction::AcceptSingleHop) {
+                    single_hop_offer.set(None);
+                }
+                let connecting = matches!(
+                    msg,
+                    VpnAction::Connect(_)
+                        | VpnAction::MultiHopConnect(..)
+                        | VpnAction::AcceptSingleHop
+                );
+                let was_connected =
+                    vpn_service.get_status().await == ConnectionStatus::Connected;
+                // The entry and exit handed to the backend, to remember once
+                // the tunnel is up.
+                let mut target: Option<(String, Option<String>)> = None;
+                let ((), timing) = connect_timing::measure(async {
+                    match msg {
+                        VpnAction::Connect(selection) => {
+                            let acc_num = account_number.peek().clone().unwrap_or_default();
+                            let token = auth_token.peek().clone().unwrap_or_default();
+                            if acc_num.is_empty() {
+                                toasts.show("Please log in first", ToastType::Error);
+                                return;
+                            }
 
-                        if location == "Automatic" || location.contains("Auto") {
-                            toasts.show("Finding best server...", ToastType::Info);
-                            let country = if location.contains(",") {
-                                location.split(',').next().map(|s| s.trim())
-                            } else {
-                                None
+                            let location = match settings.peek().with_pin(selection) {
+                                LocationSelection::Specific(location) => location,
+                                other => {
+                                    toasts.show("Finding best server...", ToastType::Info);
+                                    let best = app_service.find_best_server(other.country());
+                                    match timed(Phase::FindServer, best).await {
+                                        Ok(best) => {
+                                            let location = Location::from(&best);
+                                            current_location.set(location.to_string());
+                                            location
+                                        }
+                                        Err(e) => {
+                                            toasts.show(
+                                                &e.user_friendly_message(),
+                                                ToastType::Error,
+                                            );
+                                            return;
+                                        }
+                                    }
+                                }
                             };
 
-                            match app_service.find_best_server(country).await {
-                                Ok(best) => {
-                                    location = format!("{}, {}", best.country, best.city);
-                                    current_location.set(location.clone());
+                            let location = location.to_string();
+                            let s = settings.peek().clone();
+                            let auth = Some((acc_num.clone(), token.clone()));
+                            match app_service.get_anonymous_config(
+                                &location,
+                                &token,
+                                &s.server_settings(),
+                            )
+                            .await
+                            {
+                                Ok(config) => {
+                                    target = Some((location.clone(), None));
+                                    vpn_service.connect(location, config, None, s, auth).await
                                 }
                                 Err(e) => {
-                                    toasts.show(
-                                        &e.user_friendly_message(),
-                                        ToastType::Error,
-                                    );
-                                    continue;
+                                    toasts.show(&e.user_friendly_message(), ToastType::Error)
                                 }
                             }
                         }
-
-                        let s = settings.peek().clone();
-                        let auth = Some((acc_num.clone(), token.clone()));
-                        match app_service.get_anonymous_config(
-                            &location,
-                            &token,
-                            Some(s.dns_blocking.clone()),
-                            s.quantum_resistant,
-                        )
-                        .await
-                        {
-                            Ok(config) => {
-                                vpn_service.connect(location, config, None, s, auth).await
+                        VpnAction::MultiHopConnect(entry, exit) => {
+                            let acc_num = account_number.peek().clone().unwrap_or_default();
+                            let token = auth_token.peek().clone().unwrap_or_default();
+                            if acc_num.is_empty() {
+                                toasts.show("Please log in first", ToastType::Error);
+                                return;
                             }
-                            Err(e) => {
-                                toasts.show(&e.user_friendly_message(), ToastType::Error)
+                            let s = settings.peek().clone();
+                            let auth = Some((acc_num.clone(), token.clone()));
+                            let entry_loc = match s.with_pin(entry) {
+                                LocationSelection::Specific(location) => location,
+                                other => match timed(
+                                    Phase::FindServer,
+                                    app_service.find_best_server(other.country()),
+                                )
+                                .await
+                                {
+                                    Ok(best) => Location::from(&best),
+                                    Err(e) => {
+                                        toasts.show(
+                                            &e.user_friendly_message(),
+                                            ToastType::Error,
+                                        );
+                                        return;
+                                    }
+                                },
+                            };
+                            let mut exit_loc = match exit {
+                                LocationSelection::Specific(location) => location,
+                                other => {
+                                    let exclude_entry = vec![entry_loc.to_string()];
+                                    let best = app_service
+                                        .find_best_server_excluding(other.country(), &exclude_entry);
+                                    match timed(Phase::FindServer, best).await {
+                                        Ok(best) => Location::from(&best),
+                                        Err(e) => {
+                                            toasts.show(
+                                                &e.user_friendly_message(),
+                                                ToastType::Error,
+                                            );
+                                            return;
+                                        }
+                                    }
+                                }
+                            };
+                            if entry_loc == exit_loc {
+                                let exclude_entry = vec![entry_loc.to_string()];
+                                if let Ok(best) =
+                                    app_service.find_best_server_excluding(None, &exclude_entry)
+                                        .await
+                                {
+                                    let candidate = Location::from(&best);
+                                    if candidate != entry_loc {
+                                        exit_loc = candidate;
+                                    }
+                                }
                             }
-                        }
-                    }
-                    VpnAction::MultiHopConnect(entry, exit) => {
-                        let acc_num = account_number.peek().clone().unwrap_or_default();
-                        let token = auth_token.peek().clone().unwrap_or_default();
-                        if acc_num.is_empty() {
-                            toasts.show("Please log in first", ToastType::Error);
-                            continue;
-                        }
-                        let s = settings.peek().clone();
-                        let auth = Some((acc_num.clone(), token.clone()));
-                        let mut entry_loc = entry;
-                        let mut exit_loc = exit;
-                        if entry_loc == "Automatic" || entry_loc.contains("Auto") {
-                            match app_service.find_best_server(None).await {
-                                Ok(best) => {
-                                    entry_loc = format!("{}, {}", best.country, best.city);
+                            let entry_loc = entry_loc.to_string();
+                            let mut exit_loc = exit_loc.to_string();
+
+                            let server_settings = s.server_settings();
+                            let entry_fut = app_service.get_anonymous_config(
+                                &entry_loc,
+                                &token,
+                                &server_settings,
+                            );
+                            let exit_fut = app_service.get_anonymous_config(
+                                &exit_loc,
+                                &token,
+                                &server_settings,
+                            );
+                            match tokio::join!(entry_fut, exit_fut) {
+                                (Ok(e_cfg), Ok(mut x_cfg)) => {
+                                    if ServersService::is_same_server(
+                                        &e_cfg.endpoint,
+                                        &e_cfg.public_key,
+                                        &x_cfg.endpoint,
+                                        &x_cfg.public_key,
+                                    ) {
+                                        let exclude = vec![
+                                            entry_loc.clone(),
+                                            e_cfg.endpoint.clone(),
+                                            e_cfg.public_key.clone(),
+                                        ];
+                                        let replacement = match app_service
+                                            .find_best_server_excluding(None, &exclude)
+                                            .await
+                                        {
+                                            Ok(best) => {
+                                                exit_loc = Location::from(&best).to_string();
+                                                app_service
+                                                    .get_anonymous_config(
+                                                        &exit_loc,
+                                                        &token,
+                                                        &server_settings,
+                                                    )
+                                                    .await
+                                            }
+                                            Err(e) => Err(e),
+                                        };
+                                        match replacement {
+                                            Ok(cfg)
+                                                if !ServersService::is_same_server(
+                                                    &e_cfg.endpoint,
+                                                    &e_cfg.public_key,
+                                                    &cfg.endpoint,
+                                                    &cfg.public_key,
+                                                ) =>
+                                            {
+                                                x_cfg = cfg;
+                                            }
+                                            Ok(_) => {
+                                                toasts.show(
+                                                    "Multi-hop needs two different servers. Pick another exit.",
+                                                    ToastType::Error,
+                                                );
+                                                return;
+                                            }
+                                            Err(e) => {
+                                                toasts.show(
+                                                    &e.user_friendly_message(),
+                                                    ToastType::Error,
+                                                );
+                                                return;
+                                            }
+                                        }
+                                    }
+                                    if entry_loc == exit_loc {
+                                        toasts.show(
+                                            "Entry and exit servers share a location; multi-hop adds less privacy.",
+                                            ToastType::Info,
+                                        );
+                                    }
+                                    target = Some((entry_loc.clone(), Some(exit_loc.clone())));
+                                    vpn_service
+                                        .connect(entry_loc, e_cfg, Some((exit_loc, x_cfg)), s, auth)
+                                        .await
                                 }
-                                Err(e) => {
-                                    toasts.show(
-                                        &e.user_friendly_message(),
-                                        ToastType::Error,
-                                    );
-                                    continue;
+                                (Err(entry_err), Err(exit_err)) => toasts.show(
+                                    &format!(
+                                        "Multi-hop failed on both servers. {}; {}",
+                                        hop_failure(Hop::Entry, &entry_loc, &entry_err),
+                                        hop_failure(Hop::Exit, &exit_loc, &exit_err),
+                                    ),
+                                    ToastType::Error,
+                                ),
+                                (Err(e), Ok(x_cfg)) => {
+                                    toasts.show(&hop_failure(Hop::Entry, &entry_loc, &e), ToastType::Error);
+                                    single_hop_offer.set(Some(SingleHopOffer {
+                                        failed: Hop::Entry,
+                                        reason: e.user_friendly_message(),
+                                        location: exit_loc,
+                                        config: x_cfg,
+                                    }));
                                 }
+                                (Ok(e_cfg), Err(e)) => {
+                                    toasts.show(&hop_failure(Hop::Exit, &exit_loc, &e), ToastType::Error);
+                                    single_hop_offer.set(Some(SingleHopOffer {
+                                        failed: Hop::Exit,
+                                        reason: e.user_friendly_message(),
+                                        location: entry_loc,
+                                        config: e_cfg,
+                                    }));
+                                }
+                            }
+                        }
+                        VpnAction::AcceptSingleHop => {
+                            let Some(offer) = single_hop_offer.take() else {
+                                return;
+                            };
+                            let acc_num = account_number.peek().clone().unwrap_or_default();
+                            let token = auth_token.peek().clone().unwrap_or_default();
+                            current_location.set(offer.location.clone());
+                            target = Some((offer.location.clone(), None));
+                            vpn_service
+                                .connect(
+                                    offer.location,
+                                    offer.config,
+                                    None,
+                                    settings.peek().clone(),
+                                    Some((acc_num, token)),
+                                )
+                                .await
+                        }
+                        VpnAction::Disconnect => {
+                            let remembered = settings.peek().last_connection.clone();
+                            if remembered.is_some_and(|last| last.connected) {
+                                settings.with_mut(|s| s.remember_disconnected());
                             }
+                            vpn_service.disconnect().await
+                        }
+                        VpnAction::Reconnect => {
+                            let _ = vpn_service.disconnect().await;
+                            tokio::time::sleep(Duration::from_millis(500)).await;
                         }
-                        if exit_loc == "Automatic" || exit_loc.contains("Auto") {
-                            let exclude_entry = vec![entry_loc.clone()];
-                            match app_service.find_best_server_excluding(None, &exclude_entry)
+                        VpnAction::Quit => {
+                            shut_down(&vpn_service, &settings.peek()).await;
+                            exit_ready.set(true);
+                        }
+                        VpnAction::FindStealthMode => {
+                            let acc_num = account_number.peek().clone().unwrap_or_default();
+                            let token = auth_token.peek().clone().unwrap_or_default();
+                            if acc_num.is_empty() {
+                                toasts.show("Please log in first", ToastType::Error);
+                                return;
+                            }
+                            if vpn_service.get_status().await != ConnectionStatus::Disconnected {
+                                toasts.show("Disconnect before testing protocols", ToastType::Error);
+                                return;
+                            }
+                            let s = settings.peek().clone();
+                            let location = match s.with_pin(s.entry_location.clone()) {
+                                LocationSelection::Specific(location) => location,
+                                other => match timed(
+                                    Phase::FindServer,
+                                    app_service.find_best_server(other.country()),
+                                )
+                                .await
+                                {
+                                    Ok(best) => Location::from(&best),
+                                    Err(e) => {
+                                        toasts.show(&e.user_friendly_message(), ToastType::Error);
+                                        return;
+                                    }
+                                },
+                            };
+                            let location = location.to_string();
+                            let config = match app_service
+                                .get_anonymous_config(
+                                    &location,
+                                    &token,
+                                    &s.server_settings(),
+                                )
                                 .await
                             {
-                                Ok(best) => {
-                                    exit_loc = format!("{}, {}", best.country, best.city);
-                                }
+                                Ok(config) => config,
                                 Err(e) => {
+                                    toasts.show(&e.user_friendly_message(), ToastType::Error);
+                                    return;
+                                }
+                            };
+                            let target = ProbeTarget {
+                                location,
+                                config,
+                                auth: Some((acc_num, token)),
+                            };
+
+                            stealth_probe.set(StealthProbe {
+                                running: true,
+                                results: Vec::new(),
+                            });
+                            let results = probe_stealth_modes(
+                                &vpn_service,
+                                &target,
+                                &s,
+                                &PROBE_ORDER,
+                                PROBE_TIMEOUT,
+                                |result| stealth_probe.with_mut(|p| p.results.push(result.clone())),
+                            )
+                            .await;
+                            stealth_probe.with_mut(|p| p.running = false);
+
+                            match results.as_deref().and_then(fastest) {
+                                Some(mode) => {
+                                    settings.with_mut(|s| s.stealth_mode = mode);
                                     toasts.show(
-                                        &e.user_friendly_message(),
-                                        ToastType::Error,
+                                        "Switched to the fastest working protocol",
+                                        ToastType::Success,
                                     );
-                                    continue;
                                 }
-                            }
-                        }
-                        if entry_loc == exit_loc {
-                            let exclude_entry = vec![entry_loc.clone()];
-                            if let Ok(best) =
-                                app_service.find_best_server_excluding(None, &exclude_entry)
-                                    .await
-                            {
-                                let candidate = format!("{}, {}", best.country, best.city);
-                                if candidate != entry_loc {
-                                    exit_loc = candidate;
+                                None if results.is_none() => {
+                                    toasts.show("Disconnect before testing protocols", ToastType::Error)
                                 }
+                                None => toasts.show("No protocol reached the server", ToastType::Error),
                             }
                         }
-
-                        let entry_fut = app_service.get_anonymous_config(
-                            &entry_loc,
-                            &token,
-                            Some(s.dns_blocking.clone()),
-                            s.quantum_resistant,
-                        );
-                        let exit_fut = app_service.get_anonymous_config(
-                            &exit_loc,
-                            &token,
-                            Some(s.dns_blocking.clone()),
-                            s.quantum_resistant,
-                        );
-                        match tokio::join!(entry_fut, exit_fut) {
-                            (Ok(e_cfg), Ok(x_cfg)) => {
-                                vpn_service
-                                    .connect(entry_loc, e_cfg, Some((exit_loc, x_cfg)), s, auth)
-                                    .await
-                            }
-                            (Err(e), _) => toasts.show(&e.user_friendly_message(), ToastType::Error),
-                            (_, Err(e)) => toasts.show(&e.user_friendly_message(), ToastType::Error),
-                        }
                     }
-                    VpnAction::Disconnect => vpn_service.disconnect().await,
-                    VpnAction::Reconnect => {
-                        let _ = vpn_service.disconnect().await;
-                        tokio::time::sleep(Duration::from_millis(500)).await;
+                })
+                .await;
+                if connecting
+                    && !was_connected
+                    && vpn_service.get_status().await == ConnectionStatus::Connected
+                {
+                    tracing::info!("{}", timing.summary());
+                    last_connect.set(Some(timing));
+                }
+                if let Some((entry, exit)) = target {
+                    let remember = settings.peek().startup_behavior == StartupBehavior::ConnectLast;
+                    if remember && vpn_service.get_status().await == ConnectionStatus::Connected {
+                        settings.with_mut(|s| s.remember_connected(&entry, exit.as_deref()));
                     }
                 }
             }
@@ -207,18 +472,48 @@ fn use_connection_internal<S: AppService, V: VpnService + Clone + 'static>(
         let mut rx = vpn_service_listener.subscribe();
         let mut toasts = toast_manager;
         let mut prev_status = ConnectionStatus::Disconnected;
+        let mut attempt = AttemptTracker::default();
+        // Set while a self-healing reconnect runs, so its disconnect and
+        // connect read as one recovery rather than a blip.
+        let mut recovering = false;
+        // Set while a live obfuscation switch runs. It goes from Connected
+        // to Connecting and back without a new session.
+        let mut switching = false;
         spawn(async move {
             while let Ok(event) = rx.recv().await {
+                // Probe attempts connect and disconnect repeatedly; their
+                // results are shown on the anti-censorship page instead.
+                let quiet = stealth_probe.peek().running;
+                let switch_step = match event {
+                    VpnEvent::StatusChanged(ConnectionStatus::Connecting) => {
+                        prev_status == ConnectionStatus::Connected
+                    }
+                    VpnEvent::StatusChanged(_) => switching,
+                    _ => false,
+                };
+                if !quiet && !switch_step {
+                    if let Some(outcome) = attempt.observe(&event, Instant::now()) {
+                        connection_stats.with_mut(|s| s.record(&outcome));
+                    }
+                }
                 match event {
                     VpnEvent::StatusChanged(new_status) => {
                         status.set(new_status);
+                        switching = switch_step && new_status == ConnectionStatus::Connecting;
                         if new_status == ConnectionStatus::Connected {
-                            connected_since.set(Some(Utc::now().timestamp() as f64));
-                            toasts.show("Connected securely", ToastType::Success);
+                            // A switch keeps the session, and with it the uptime.
+                            if !switch_step {
+                                connected_since.set(Some(Utc::now().timestamp() as f64));
+                            }
+                            if !quiet && !recovering && !switch_step {
+                                toasts.show("Connected securely", ToastType::Success);
+                            }
                         } else if new_status == ConnectionStatus::Disconnected {
                             connected_since.set(None);
-                            if prev_status == ConnectionStatus::Connected
-                                || prev_status == ConnectionStatus::Disconnecting
+                            if !quiet
+                                && !recovering
+                                && (prev_status == ConnectionStatus::Connected
+                                    || prev_status == ConnectionStatus::Disconnecting)
                             {
                                 toasts.show("Disconnected", ToastType::Info);
                             }
@@ -230,7 +525,33 @@ fn use_connection_internal<S: AppService, V: VpnService + Clone + 'static>(
                         download_speed.set(stats.download_speed);
                         upload_speed.set(stats.upload_speed);
                     }
-                    VpnEvent::Error(err) => toasts.show(&err.to_string(), ToastType::Error),
+                    VpnEvent::Error(err) if !quiet => {
+                        recovering = false;
+                        toasts.show(&err.to_string(), ToastType::Error)
+                    }
+                    VpnEvent::Error(_) => {}
+                    VpnEvent::TransportChanged(port) => active_port.set(port),
+                    VpnEvent::UdpBlocked(network) => {
+                        if let Some(id) = network {
+                            settings.with_mut(|s| s.remember_udp_blocked(&id));
+                        }
+                        toasts.show(
+                            "UDP is blocked on this network. Switched to UDP-over-TCP.",
+                            ToastType::Info,
+                        );
+                    }
+                    VpnEvent::Recovering(_) => recovering = true,
+                    VpnEvent::Recovered { new_server, .. } => {
+                        recovering = false;
+                        let message = match new_server {
+                            Some(server) => format!(
+                                "Connection recovered automatically. Switched to {}.",
+                                server
+                            ),
+                            None => "Connection recovered automatically".to_string(),
+                        };
+                        toasts.show(&message, ToastType::Success);
+                    }
                     VpnEvent::CaptivePortalActive(active) => {
                         if active {
                             toasts.show(
@@ -249,43 +570,51 @@ fn use_connection_internal<S: AppService, V: VpnService + Clone + 'static>(
     use_effect(move || {
         let s = settings();
         let svc = vpn_service_lockdown.clone();
+        let mut toasts = toast_manager;
         spawn(async move {
-            let _ = svc.apply_lockdown(&s).await;
+            // A lockdown that could not be put in place must not look like one.
+            if let Err(e) = svc.apply_lockdown(&s).await {
+                toasts.show(&e.to_string(), ToastType::Error);
+            }
+            if !*lockdown_applied.peek() {
+                lockdown_applied.set(true);
+            }
         });
     });
 
-    // Auto Connect
-    let vpn_action_auto = vpn_action;
+    // A new stealth mode takes effect on a live tunnel straight away.
+    let stealth_mode = use_memo(move || settings().stealth_mode);
+    let vpn_service_stealth = vpn_service.clone();
     use_effect(move || {
+        let mode = stealth_mode();
+        let svc = vpn_service_stealth.clone();
+        spawn(async move {
+            if svc.get_status().await != ConnectionStatus::Connected {
+                return;
+            }
+            // A failure is reported through the error event.
+            if let Err(e) = svc.switch_obfuscation(mode).await {
+                tracing::warn!("Switching obfuscation failed: {}", e);
+            }
+        });
+    });
+
+    // So do changes to the split tunneling apps.
+    let split_apps = use_memo(move || {
         let s = settings();
-        let account = account_number();
-        let has_account = account
-            .as_ref()
-            .map(|value| !value.is_empty())
-            .unwrap_or(false);
-        if has_account && !auto_connect_started() && s.auto_connect {
-            auto_connect_started.set(true);
-            if s.multi_hop {
-                let entry = if s.entry_location.is_empty() {
-                    "Automatic".to_string()
-                } else {
-                    s.entry_location.clone()
-                };
-                let exit = if s.exit_location.is_empty() {
-                    "Automatic".to_string()
-                } else {
-                    s.exit_location.clone()
-                };
-                vpn_action_auto.send(VpnAction::MultiHopConnect(entry, exit));
-            } else {
-                let loc = if s.entry_location.is_empty() {
-                    "Automatic".to_string()
-                } else {
-                    s.entry_location.clone()
-                };
-                vpn_action_auto.send(VpnAction::Connect(loc));
+        (s.split_tunneling, s.split_mode, s.split_apps().to_vec())
+    });
+    let vpn_service_split = vpn_service.clone();
+    use_effect(move || {
+        split_apps();
+        let current = settings.peek().clone();
+        let svc = vpn_service_split.clone();
+        spawn(async move {
+            if svc.get_status().await != ConnectionStatus::Connected {
+                return;
             }
-        }
+            svc.update_split_apps(&current).await;
+        });
     });
 
     VpnState {
@@ -294,6 +623,23 @@ fn use_connection_internal<S: AppService, V: VpnService + Clone + 'static>(
         connected_since,
         download_speed,
         upload_speed,
+        active_port,
+        stealth_probe,
+        connection_stats,
+        last_connect,
+        single_hop_offer,
         vpn_action,
+        exit_ready,
+        lockdown_applied,
     }
 }
+
+/// Which hop of a multihop connect failed, where, and why.
+fn hop_failure(hop: Hop, location: &str, error: &AppError) -> String {
+    format!(
+        "{} server {} failed: {}",
+        hop.label(),
+        location,
+        error.user_friendly_message()
+    )
+}
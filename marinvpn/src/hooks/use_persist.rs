@@ -0,0 +1,106 @@
+use dioxus::prelude::*;
+use std::time::Duration;
+
+/// How long a value has to stay unchanged before it is written to disk.
+pub const PERSIST_DELAY: Duration = Duration::from_secs(1);
+
+/// Bookkeeping behind [`use_persist`]: which change is the latest, and what
+/// is already on disk.
+#[derive(Debug)]
+pub struct Debounce<T> {
+    saved: Option<T>,
+    generation: u64,
+}
+
+impl<T: PartialEq> Debounce<T> {
+    /// `saved` is what the value was loaded as, so it is not written back.
+    pub fn new(saved: T) -> Self {
+        Self {
+            saved: Some(saved),
+            generation: 0,
+        }
+    }
+
+    /// Notes a change to `value`. Any write still waiting is superseded; the
+    /// returned ticket is for a new one, or `None` when `value` is already
+    /// what was last written.
+    pub fn changed(&mut self, value: &T) -> Option<u64> {
+        self.generation += 1;
+        (self.saved.as_ref() != Some(value)).then_some(self.generation)
+    }
+
+    /// Whether the write for `ticket` should still happen once its delay has
+    /// passed, i.e. nothing changed in the meantime.
+    pub fn is_current(&self, ticket: u64) -> bool {
+        ticket == self.generation
+    }
+
+    pub fn written(&mut self, value: T) {
+        self.saved = Some(value);
+    }
+}
+
+/// Writes what `read` returns with `save` once it has been stable for
+/// `delay`. Changes within the delay coalesce into one write of the final
+/// value, and a value that matches the last write is skipped. `read` is
+/// tracked like any effect, so it should read the signals to persist.
+pub fn use_persist<T, R, S>(mut read: R, delay: Duration, save: S)
+where
+    T: Clone + PartialEq + Send + 'static,
+    R: FnMut() -> T + 'static,
+    S: Fn(T) -> std::io::Result<()> + Clone + Send + 'static,
+{
+    let mut debounce: Option<CopyValue<Debounce<T>>> = None;
+
+    use_effect(move || {
+        let value = read();
+        let mut state =
+            *debounce.get_or_insert_with(|| CopyValue::new(Debounce::new(value.clone())));
+        let Some(ticket) = state.write().changed(&value) else {
+            return;
+        };
+        let save = save.clone();
+        spawn(async move {
+            tokio::time::sleep(delay).await;
+            if !state.peek().is_current(ticket) {
+                return;
+            }
+            let written = value.clone();
+            match tokio::task::spawn_blocking(move || save(written)).await {
+                Ok(Ok(())) => state.write().written(value),
+                Ok(Err(e)) => tracing::warn!("Failed to save config: {}", e),
+                Err(e) => tracing::warn!("Config save task failed: {}", e),
+            }
+        });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rapid_changes_leave_one_current_ticket() {
+        let mut debounce = Debounce::new(false);
+        let first = debounce.changed(&true).unwrap();
+        let second = debounce.changed(&false);
+        let third = debounce.changed(&true).unwrap();
+
+        // Toggling back to the saved value needs no write at all.
+        assert_eq!(second, None);
+        assert!(!debounce.is_current(first));
+        assert!(debounce.is_current(third));
+    }
+
+    #[test]
+    fn unchanged_values_are_not_written_again() {
+        let mut debounce = Debounce::new(1420);
+        assert_eq!(debounce.changed(&1420), None);
+
+        let ticket = debounce.changed(&1280).unwrap();
+        assert!(debounce.is_current(ticket));
+        debounce.written(1280);
+        assert_eq!(debounce.changed(&1280), None);
+        assert!(debounce.changed(&1420).is_some());
+    }
+}
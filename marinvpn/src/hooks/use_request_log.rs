@@ -0,0 +1,21 @@
+use crate::services::request_log::{self, RequestRecord};
+use dioxus::prelude::*;
+use std::time::Duration;
+
+/// The control-plane request log, re-read every second while the calling
+/// component is mounted.
+pub fn use_request_log() -> Signal<Vec<RequestRecord>> {
+    let mut records = use_signal(request_log::snapshot);
+
+    use_future(move || async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            let latest = request_log::snapshot();
+            if *records.peek() != latest {
+                records.set(latest);
+            }
+        }
+    });
+
+    records
+}
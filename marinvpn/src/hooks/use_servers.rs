@@ -1,25 +1,153 @@
+use crate::hooks::wait_until_online;
 use crate::models::{City, Region};
-use crate::services::{AppService, ProductionAppService};
+use crate::services::power::{self, PowerSource};
+use crate::services::{AppService, SharedAppService};
 use dioxus::prelude::*;
 use futures_util::future;
 use std::time::Duration;
 
-pub fn use_servers() -> Signal<Vec<Region>> {
-    let app_service = use_hook(|| ProductionAppService);
-    use_servers_internal(app_service)
+/// Time between syncs while they succeed.
+pub const SYNC_INTERVAL: Duration = Duration::from_secs(60);
+/// First retry after a failed sync. It doubles with each failure in a row,
+/// up to `SYNC_INTERVAL`.
+pub const SYNC_RETRY: Duration = Duration::from_secs(5);
+/// Each wait is stretched or shortened by up to this fraction, so clients
+/// started together do not keep syncing in lockstep.
+pub const SYNC_JITTER: f64 = 0.2;
+/// With battery saver on and no charger, latency is measured on only one
+/// sync in this many.
+pub const BATTERY_PROBE_EVERY: u32 = 10;
+
+/// When the next server sync is due.
+#[derive(Debug, Default)]
+pub struct SyncBackoff {
+    failures: u32,
+}
+
+impl SyncBackoff {
+    pub fn succeeded(&mut self) {
+        self.failures = 0;
+    }
+
+    pub fn failed(&mut self) {
+        self.failures = self.failures.saturating_add(1);
+    }
+
+    /// The wait before the next sync, before jitter.
+    pub fn base_delay(&self) -> Duration {
+        match self.failures {
+            0 => SYNC_INTERVAL,
+            n => SYNC_RETRY
+                .saturating_mul(2u32.saturating_pow(n - 1))
+                .min(SYNC_INTERVAL),
+        }
+    }
+
+    /// The base delay with jitter applied. `roll` is a random number in
+    /// `0.0..1.0`.
+    pub fn next_delay(&self, roll: f64) -> Duration {
+        let spread = 1.0 - SYNC_JITTER + 2.0 * SYNC_JITTER * roll.clamp(0.0, 1.0);
+        self.base_delay().mul_f64(spread)
+    }
 }
 
-pub fn use_servers_with_service<S: AppService>(service: S) -> Signal<Vec<Region>> {
-    use_servers_internal(service)
+/// Which syncs also measure latency to every server.
+#[derive(Debug, Default)]
+pub struct ProbeSchedule {
+    since_probe: Option<u32>,
 }
 
-fn use_servers_internal<S: AppService>(app_service: S) -> Signal<Vec<Region>> {
+impl ProbeSchedule {
+    /// Called once per sync. Every sync probes unless battery saver is on and
+    /// the machine runs on battery; then only every `BATTERY_PROBE_EVERY`th
+    /// does. The first sync always probes so the list has latencies at all,
+    /// and plugging in resumes probing on the next sync.
+    pub fn should_probe(&mut self, battery_saver: bool, power: PowerSource) -> bool {
+        let due = match self.since_probe {
+            None => true,
+            Some(rounds) => {
+                !battery_saver || power != PowerSource::Battery || rounds + 1 >= BATTERY_PROBE_EVERY
+            }
+        };
+        self.since_probe = match self.since_probe {
+            Some(rounds) if !due => Some(rounds + 1),
+            _ => Some(0),
+        };
+        due
+    }
+}
+
+/// Keeps the server list and its latencies fresh. Nothing is fetched or
+/// probed while `offline` is set, and probes are rare on battery while
+/// `battery_saver` is set.
+pub fn use_servers(
+    app_service: Option<SharedAppService>,
+    offline: ReadOnlySignal<bool>,
+    battery_saver: ReadOnlySignal<bool>,
+) -> Signal<Vec<Region>> {
+    let app_service = use_hook(move || app_service.unwrap_or_default());
+    use_servers_internal(app_service, offline, battery_saver)
+}
+
+pub fn use_servers_with_service<S: AppService + Clone>(
+    service: S,
+    offline: ReadOnlySignal<bool>,
+    battery_saver: ReadOnlySignal<bool>,
+) -> Signal<Vec<Region>> {
+    use_servers_internal(service, offline, battery_saver)
+}
+
+/// The latency last measured for a city, for syncs that skip probing.
+fn last_ping(regions: &[Region], country: &str, city: &str) -> u32 {
+    regions
+        .iter()
+        .find(|r| r.name == country)
+        .and_then(|r| r.cities.iter().find(|c| c.name == city))
+        .map_or(999, |c| c.ping as u32)
+}
+
+/// Folds a freshly synced list into the one on screen. Known regions and
+/// cities keep their place and take the new load and latency, new ones are
+/// appended, and ones the sync left out stay where they are, marked
+/// unavailable.
+pub fn merge_regions(current: &mut Vec<Region>, fresh: Vec<Region>) {
+    for region in current.iter_mut() {
+        let listed = fresh.iter().find(|r| r.name == region.name);
+        for city in &mut region.cities {
+            city.available = listed.is_some_and(|r| r.cities.iter().any(|c| c.name == city.name));
+        }
+    }
+
+    for fresh_region in fresh {
+        let Some(region) = current.iter_mut().find(|r| r.name == fresh_region.name) else {
+            current.push(fresh_region);
+            continue;
+        };
+        for fresh_city in fresh_region.cities {
+            match region.cities.iter_mut().find(|c| c.name == fresh_city.name) {
+                Some(city) => *city = fresh_city,
+                None => region.cities.push(fresh_city),
+            }
+        }
+    }
+}
+
+fn use_servers_internal<S: AppService + Clone>(
+    app_service: S,
+    offline: ReadOnlySignal<bool>,
+    battery_saver: ReadOnlySignal<bool>,
+) -> Signal<Vec<Region>> {
     let mut regions = use_signal(crate::data::get_default_regions);
 
     let service = app_service.clone();
     use_future(move || {
         let service = service.clone();
         async move {
+            let mut backoff = SyncBackoff::default();
+            let mut probes = ProbeSchedule::default();
+            // The bundled list is a placeholder until the first sync
+            // replaces it; after that, syncs are merged in.
+            let mut synced = false;
             loop {
                 // service is safe to use here because we cloned it into the outer closure
                 // and it's moved into this async block.
@@ -28,19 +156,31 @@ fn use_servers_internal<S: AppService>(app_service: S) -> Signal<Vec<Region>> {
                 // The error was because `service.clone()` inside the loop for `ping_tasks`
                 // was trying to move out of `service` which was already moved into the async block.
                 // We need to clone it for `ping_tasks` properly.
-                
+
+                wait_until_online(offline).await;
                 match service.get_servers().await {
+                    // Offline mode came on while the list was in flight.
+                    Ok(_) if *offline.peek() => continue,
                     Ok(api_servers) => {
-                        let mut ping_tasks = Vec::new();
-                        for s in &api_servers {
-                            let endpoint = s.endpoint.clone();
-                            let svc = service.clone(); // Clone for each task
-                            ping_tasks.push(async move {
-                                svc.measure_latency(&endpoint).await.unwrap_or(999)
-                            });
-                        }
-
-                        let pings = future::join_all(ping_tasks).await;
+                        backoff.succeeded();
+                        let saver = *battery_saver.peek();
+                        let pings = if probes.should_probe(saver, power::current().await) {
+                            let mut ping_tasks = Vec::new();
+                            for s in &api_servers {
+                                let endpoint = s.endpoint.clone();
+                                let svc = service.clone(); // Clone for each task
+                                ping_tasks.push(async move {
+                                    svc.measure_latency(&endpoint).await.unwrap_or(999)
+                                });
+                            }
+                            future::join_all(ping_tasks).await
+                        } else {
+                            let current = regions.peek();
+                            api_servers
+                                .iter()
+                                .map(|s| last_ping(&current, &s.country, &s.city))
+                                .collect()
+                        };
 
                         let mut new_regions: Vec<Region> = Vec::new();
                         for (i, s) in api_servers.into_iter().enumerate() {
@@ -52,6 +192,7 @@ fn use_servers_internal<S: AppService>(app_service: S) -> Signal<Vec<Region>> {
                                         name: s.city,
                                         load: 0,
                                         ping: ping as u8,
+                                        available: true,
                                     });
                                 }
                             } else {
@@ -71,20 +212,210 @@ fn use_servers_internal<S: AppService>(app_service: S) -> Signal<Vec<Region>> {
                                         name: s.city,
                                         load: 0,
                                         ping: ping as u8,
+                                        available: true,
                                     }],
                                 });
                             }
                         }
                         if !new_regions.is_empty() {
-                            regions.set(new_regions);
+                            if synced {
+                                regions.with_mut(|current| merge_regions(current, new_regions));
+                            } else {
+                                regions.set(new_regions);
+                                synced = true;
+                            }
                         }
                     }
-                    Err(e) => tracing::error!("Failed to sync server list: {}", e),
+                    Err(e) => {
+                        backoff.failed();
+                        tracing::error!("Failed to sync server list: {}", e);
+                    }
                 }
-                tokio::time::sleep(Duration::from_secs(60)).await;
+                let wait = backoff.next_delay(rand::random());
+                tokio::time::sleep(wait).await;
             }
         }
     });
 
     regions
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn city(name: &str, ping: u8) -> City {
+        City {
+            name: name.to_string(),
+            load: 0,
+            ping,
+            available: true,
+        }
+    }
+
+    fn region(name: &str, cities: Vec<City>) -> Region {
+        Region {
+            name: name.to_string(),
+            flag: String::new(),
+            map_x: 0.0,
+            map_y: 0.0,
+            cities,
+        }
+    }
+
+    fn listing(regions: &[Region]) -> Vec<(String, u8, bool)> {
+        regions
+            .iter()
+            .flat_map(|r| &r.cities)
+            .map(|c| (c.name.clone(), c.ping, c.available))
+            .collect()
+    }
+
+    fn entry(name: &str, ping: u8, available: bool) -> (String, u8, bool) {
+        (name.to_string(), ping, available)
+    }
+
+    #[test]
+    fn merge_updates_in_place_and_keeps_order() {
+        let mut current = vec![
+            region(
+                "Sweden",
+                vec![city("Stockholm", 20), city("Gothenburg", 25)],
+            ),
+            region("Germany", vec![city("Frankfurt", 30)]),
+        ];
+        // The API lists them in another order, with a new city and country.
+        let fresh = vec![
+            region("Norway", vec![city("Oslo", 18)]),
+            region("Germany", vec![city("Berlin", 35), city("Frankfurt", 28)]),
+            region(
+                "Sweden",
+                vec![city("Gothenburg", 22), city("Stockholm", 19)],
+            ),
+        ];
+
+        merge_regions(&mut current, fresh);
+
+        let names: Vec<_> = current.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, ["Sweden", "Germany", "Norway"]);
+        assert_eq!(
+            listing(&current),
+            [
+                entry("Stockholm", 19, true),
+                entry("Gothenburg", 22, true),
+                entry("Frankfurt", 28, true),
+                entry("Berlin", 35, true),
+                entry("Oslo", 18, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn servers_left_out_are_kept_as_unavailable() {
+        let mut current = vec![
+            region(
+                "Sweden",
+                vec![city("Stockholm", 20), city("Gothenburg", 25)],
+            ),
+            region("Germany", vec![city("Frankfurt", 30)]),
+        ];
+
+        merge_regions(
+            &mut current,
+            vec![region("Sweden", vec![city("Gothenburg", 24)])],
+        );
+        assert_eq!(
+            listing(&current),
+            [
+                entry("Stockholm", 20, false),
+                entry("Gothenburg", 24, true),
+                entry("Frankfurt", 30, false),
+            ]
+        );
+
+        // Listed again, in the same place as before.
+        merge_regions(
+            &mut current,
+            vec![
+                region("Germany", vec![city("Frankfurt", 31)]),
+                region(
+                    "Sweden",
+                    vec![city("Stockholm", 21), city("Gothenburg", 24)],
+                ),
+            ],
+        );
+        assert_eq!(
+            listing(&current),
+            [
+                entry("Stockholm", 21, true),
+                entry("Gothenburg", 24, true),
+                entry("Frankfurt", 31, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn failures_retry_sooner_and_back_off_to_the_interval() {
+        let mut backoff = SyncBackoff::default();
+        assert_eq!(backoff.base_delay(), SYNC_INTERVAL);
+
+        let mut delays = Vec::new();
+        for _ in 0..6 {
+            backoff.failed();
+            delays.push(backoff.base_delay().as_secs());
+        }
+        assert_eq!(delays, [5, 10, 20, 40, 60, 60]);
+
+        // Many failures in a row never overflow past the cap.
+        for _ in 0..100 {
+            backoff.failed();
+        }
+        assert_eq!(backoff.base_delay(), SYNC_INTERVAL);
+
+        backoff.succeeded();
+        assert_eq!(backoff.base_delay(), SYNC_INTERVAL);
+    }
+
+    #[test]
+    fn jitter_stays_within_its_bounds() {
+        let mut backoff = SyncBackoff::default();
+        assert_eq!(backoff.next_delay(0.0), Duration::from_secs(48));
+        assert_eq!(backoff.next_delay(0.5), SYNC_INTERVAL);
+        assert_eq!(backoff.next_delay(1.0), Duration::from_secs(72));
+
+        backoff.failed();
+        assert_eq!(backoff.next_delay(0.0), Duration::from_secs(4));
+        assert_eq!(backoff.next_delay(1.0), Duration::from_secs(6));
+        // Out-of-range rolls are clamped.
+        assert_eq!(backoff.next_delay(7.0), Duration::from_secs(6));
+    }
+
+    #[test]
+    fn battery_saver_probes_rarely_on_battery_only() {
+        let mut schedule = ProbeSchedule::default();
+        let mut run = |saver, power, rounds| {
+            (0..rounds)
+                .filter(|_| schedule.should_probe(saver, power))
+                .count()
+        };
+
+        // The first sync probes even on battery; after that one in ten.
+        assert_eq!(run(true, PowerSource::Battery, 1), 1);
+        assert_eq!(run(true, PowerSource::Battery, 9), 0);
+        assert_eq!(run(true, PowerSource::Battery, 20), 2);
+
+        // Plugging in, or an unknown source, probes every sync again.
+        assert_eq!(run(true, PowerSource::Mains, 3), 3);
+        assert_eq!(run(true, PowerSource::Unknown, 3), 3);
+        // So does battery with the saver off.
+        assert_eq!(run(false, PowerSource::Battery, 3), 3);
+    }
+
+    #[test]
+    fn skipped_probes_keep_the_last_latency() {
+        let regions = vec![region("Sweden", vec![city("Stockholm", 32)])];
+        assert_eq!(last_ping(&regions, "Sweden", "Stockholm"), 32);
+        assert_eq!(last_ping(&regions, "Sweden", "Malmo"), 999);
+        assert_eq!(last_ping(&regions, "Norway", "Oslo"), 999);
+    }
+}
@@ -57,6 +57,13 @@ fn match_english(key: &str) -> &'static str {
         "buy_credit" => "Buy more credit",
         "redeem_voucher" => "Redeem voucher",
         "log_out" => "Log out",
+        "active_sessions" => "Active sessions",
+        "logged_out" => "Logged out. This device stays on your account until you remove it.",
+        "logged_out_offline" => "Logged out on this device, but the server could not be reached.",
+        "emergency_wipe" => "Emergency wipe",
+        "emergency_wipe_desc" => "Removes every device, session and tunnel on this account. This cannot be undone.",
+        "wipe_now" => "Wipe now",
+        "cancel" => "Cancel",
         "copy_account" => "Account number copied",
         "secure_private" => "Secure & Private",
         "login" => "Log In",
@@ -84,8 +91,12 @@ fn match_english(key: &str) -> &'static str {
         "adult_content" => "Adult Content",
         "social_media" => "Social Media",
         "desc_auto_connect" => "Automatically connect to a server when the app launches.",
-        "desc_dns_blocking" => "Disable all DNS content blockers above to activate this setting.",
         "desc_ipv6" => "Enable to allow IPv6 traffic through the tunnel.",
+        "desc_custom_dns_exclusive" => {
+            "Content blockers work through MarinVPN's DNS. Turning on a custom DNS server turns them off, and turning on a blocker turns custom DNS off."
+        }
+        "keep_lockdown_after_exit" => "Keep blocking after quit",
+        "desc_keep_lockdown_after_exit" => "The Internet stays blocked after you quit until MarinVPN connects again. See the README to remove the block without the app.",
         "desc_mtu" => "Set WireGuard MTU value. Valid range: 1280 - 1420.",
         "title_local_sharing" => "Local network sharing",
         "title_dns_blocking" => "DNS content blockers",
@@ -183,9 +194,6 @@ fn match_swedish(key: &str) -> &'static str {
         "adult_content" => "Vuxet innehåll",
         "social_media" => "Sociala medier",
         "desc_auto_connect" => "Anslut automatiskt till en server när appen startar.",
-        "desc_dns_blocking" => {
-            "Inaktivera alla innehållsblockerare ovan för att aktivera denna inställning."
-        }
         "desc_ipv6" => "Aktivera för att tillåta IPv6-trafik genom tunneln.",
         "desc_mtu" => "Ställ in MTU-värde för WireGuard. Giltigt intervall: 1280 - 1420.",
         "title_local_sharing" => "Delning i lokalt nätverk",
@@ -268,7 +276,6 @@ fn match_german(key: &str) -> &'static str {
         "adult_content" => "Erwachseneninhalt",
         "social_media" => "Soziale Medien",
         "desc_auto_connect" => "Verbindet beim App-Start automatisch mit einem Server.",
-        "desc_dns_blocking" => "Deaktivieren Sie alle Filter, um dies zu nutzen.",
         "desc_ipv6" => "Erlaubt IPv6-Verkehr durch den Tunnel.",
         "desc_mtu" => "MTU-Wert einstellen. Bereich: 1280 - 1420.",
         "title_local_sharing" => "Lokale Netzwerkfreigabe",
@@ -351,7 +358,6 @@ fn match_french(key: &str) -> &'static str {
         "adult_content" => "Contenu adulte",
         "social_media" => "Réseaux sociaux",
         "desc_auto_connect" => "Se connecte automatiquement au démarrage.",
-        "desc_dns_blocking" => "Désactivez les bloqueurs ci-dessus pour activer ceci.",
         "desc_ipv6" => "Autorise le trafic IPv6 dans le tunnel.",
         "desc_mtu" => "Régler le MTU. Plage : 1280 - 1420.",
         "title_local_sharing" => "Partage réseau local",
@@ -434,7 +440,6 @@ fn match_spanish(key: &str) -> &'static str {
         "adult_content" => "Contenido adulto",
         "social_media" => "Redes sociales",
         "desc_auto_connect" => "Conexión automática al iniciar.",
-        "desc_dns_blocking" => "Desactiva bloqueadores para activar esto.",
         "desc_ipv6" => "Permitir tráfico IPv6 por el túnel.",
         "desc_mtu" => "Establecer MTU. Rango: 1280 - 1420.",
         "title_local_sharing" => "Compartir en red local",
@@ -517,7 +522,6 @@ fn match_italian(key: &str) -> &'static str {
         "adult_content" => "Contenuti adulti",
         "social_media" => "Social Media",
         "desc_auto_connect" => "Connessione automatica all'avvio.",
-        "desc_dns_blocking" => "Disattiva blocchi sopra per attivare.",
         "desc_ipv6" => "Consenti traffico IPv6 nel tunnel.",
         "desc_mtu" => "Imposta MTU. Range: 1280 - 1420.",
         "title_local_sharing" => "Condivisione rete locale",
@@ -600,7 +604,6 @@ fn match_dutch(key: &str) -> &'static str {
         "adult_content" => "Volwassen inhoud",
         "social_media" => "Sociale media",
         "desc_auto_connect" => "Verbind automatisch bij opstarten.",
-        "desc_dns_blocking" => "Schakel filters hierboven uit om dit te gebruiken.",
         "desc_ipv6" => "Sta IPv6-verkeer in de tunnel toe.",
         "desc_mtu" => "Stel MTU in. Bereik: 1280 - 1420.",
         "title_local_sharing" => "Lokaal netwerk delen",
@@ -683,9 +686,6 @@ fn match_portuguese_brazilian(key: &str) -> &'static str {
         "adult_content" => "Conteúdo adulto",
         "social_media" => "Redes Sociais",
         "desc_auto_connect" => "Conecta automaticamente a um servidor quando o app inicia.",
-        "desc_dns_blocking" => {
-            "Desative todos os bloqueadores acima para ativar esta configuração."
-        }
         "desc_ipv6" => "Ative para permitir tráfego IPv6 pelo túnel.",
         "desc_mtu" => "Definir valor MTU do WireGuard. Intervalo: 1280 - 1420.",
         "title_local_sharing" => "Compartilhamento na rede local",
@@ -768,7 +768,6 @@ fn match_polish(key: &str) -> &'static str {
         "adult_content" => "Treści dla dorosłych",
         "social_media" => "Social Media",
         "desc_auto_connect" => "Łącz automatycznie po starcie.",
-        "desc_dns_blocking" => "Wyłącz blokady powyżej, by to włączyć.",
         "desc_ipv6" => "Zezwól na ruch IPv6 w tunelu.",
         "desc_mtu" => "Ustaw MTU. Zakres: 1280 - 1420.",
         "title_local_sharing" => "Dostęp w sieci lokalnej",
@@ -851,7 +850,6 @@ fn match_norwegian(key: &str) -> &'static str {
         "adult_content" => "Voksent innhold",
         "social_media" => "Sosiale medier",
         "desc_auto_connect" => "Koble til automatisk ved start.",
-        "desc_dns_blocking" => "Slå av blokkeringer over for å bruke.",
         "desc_ipv6" => "Tillat IPv6-trafikk i tunellen.",
         "desc_mtu" => "Sett MTU. Område: 1280 - 1420.",
         "title_local_sharing" => "Deling på lokalt nett",
@@ -934,7 +932,6 @@ fn match_danish(key: &str) -> &'static str {
         "adult_content" => "Voksenindhold",
         "social_media" => "Sociale medier",
         "desc_auto_connect" => "Forbind automatisk ved start.",
-        "desc_dns_blocking" => "Slå blokeringer fra for at bruge dette.",
         "desc_ipv6" => "Tillad IPv6-trafik i tunnelen.",
         "desc_mtu" => "Indstil MTU. Område: 1280 - 1420.",
         "title_local_sharing" => "Deling på lokalt net",
@@ -1017,7 +1014,6 @@ fn match_finnish(key: &str) -> &'static str {
         "adult_content" => "Aikuisviihde",
         "social_media" => "Somet",
         "desc_auto_connect" => "Yhdistä automaattisesti käynnistyksessä.",
-        "desc_dns_blocking" => "Poista estot ylhäältä käyttääksesi.",
         "desc_ipv6" => "Salli IPv6-liikenne tunnelissa.",
         "desc_mtu" => "Aseta MTU. Alue: 1280 - 1420.",
         "title_local_sharing" => "Paikallisverkon jako",
@@ -1100,7 +1096,6 @@ fn match_russian(key: &str) -> &'static str {
         "adult_content" => "Контент для взрослых",
         "social_media" => "Социальные сети",
         "desc_auto_connect" => "Автоматически подключаться к серверу при запуске приложения.",
-        "desc_dns_blocking" => "Отключите все фильтры выше, чтобы активировать эту настройку.",
         "desc_ipv6" => "Включите, чтобы разрешить трафик IPv6 через туннель.",
         "desc_mtu" => "Установите значение MTU WireGuard. Допустимый диапазон: 1280 - 1420.",
         "title_local_sharing" => "Доступ в локальной сети",
@@ -1183,7 +1178,6 @@ fn match_chinese_simplified(key: &str) -> &'static str {
         "adult_content" => "成人内容",
         "social_media" => "社交媒体",
         "desc_auto_connect" => "应用启动时自动连接到服务器。",
-        "desc_dns_blocking" => "禁用上方所有内容阻断器以激活此设置。",
         "desc_ipv6" => "开启以允许 IPv6 流量通过隧道。",
         "desc_mtu" => "设置 WireGuard MTU 值。有效范围：1280 - 1420。",
         "title_local_sharing" => "局域网共享",
@@ -1266,7 +1260,6 @@ fn match_chinese_traditional(key: &str) -> &'static str {
         "adult_content" => "成人內容",
         "social_media" => "社交媒體",
         "desc_auto_connect" => "應用啟動時自動連接到服務器。",
-        "desc_dns_blocking" => "禁用上方所有內容阻斷器以激活此設置。",
         "desc_ipv6" => "開啟以允許 IPv6 流量通過隧道。",
         "desc_mtu" => "設置 WireGuard MTU 值。有效範圍：1280 - 1420。",
         "title_local_sharing" => "區域網共享",
@@ -1349,7 +1342,6 @@ fn match_arabic(key: &str) -> &'static str {
         "adult_content" => "محتوى للبالغين",
         "social_media" => "وسائل التواصل الاجتماعي",
         "desc_auto_connect" => "الاتصال تلقائياً بخادم عند تشغيل التطبيق.",
-        "desc_dns_blocking" => "قم بتعطيل جميع أدوات حظر المحتوى أعلاه لتنشيط هذا الإعداد.",
         "desc_ipv6" => "مكّن للسماح بمرور حركة مرور IPv6 عبر النفق.",
         "desc_mtu" => "اضبط قيمة MTU لـ WireGuard. النطاق الصالح: 1280 - 1420.",
         "title_local_sharing" => "مشاركة الشبكة المحلية",
@@ -1432,7 +1424,6 @@ fn match_turkish(key: &str) -> &'static str {
         "adult_content" => "Yetişkin İçeriği",
         "social_media" => "Sosyal Medya",
         "desc_auto_connect" => "Uygulama başladığında otomatik olarak bir sunucuya bağlan.",
-        "desc_dns_blocking" => "Bu ayarı etkinleştirmek için yukarıdaki tüm DNS içerik engelleyicileri devre dışı bırakın.",
         "desc_ipv6" => "Tünel üzerinden IPv6 trafiğine izin vermek için etkinleştirin.",
         "desc_mtu" => "WireGuard MTU değerini ayarlayın. Geçerli aralık: 1280 - 1420.",
         "title_local_sharing" => "Yerel ağ paylaşımı",
@@ -1515,9 +1506,6 @@ fn match_persian(key: &str) -> &'static str {
         "adult_content" => "محتوای بزرگسالان",
         "social_media" => "شبکه‌های اجتماعی",
         "desc_auto_connect" => "اتصال خودکار به یک سرور هنگام اجرای برنامه.",
-        "desc_dns_blocking" => {
-            "تمام مسدودکننده‌های محتوای DNS بالا را غیرفعال کنید تا این تنظیم فعال شود."
-        }
         "desc_ipv6" => "برای اجازه عبور ترافیک IPv6 از طریق تونل فعال کنید.",
         "desc_mtu" => "مقدار MTU WireGuard را تنظیم کنید. محدوده مجاز: ۱۲۸۰ - ۱۴۲۰.",
         "title_local_sharing" => "اشتراک‌گذاری شبکه محلی",
@@ -1600,7 +1588,6 @@ fn match_thai(key: &str) -> &'static str {
         "adult_content" => "เนื้อหาสำหรับผู้ใหญ่",
         "social_media" => "โซเชียลมีเดีย",
         "desc_auto_connect" => "เชื่อมต่อกับเซิร์ฟเวอร์โดยอัตโนมัติเมื่อเปิดแอป",
-        "desc_dns_blocking" => "ปิดใช้งานตัวบล็อกเนื้อหา DNS ด้านบนทั้งหมดเพื่อเปิดใช้งานการตั้งค่านี้",
         "desc_ipv6" => "เปิดใช้งานเพื่ออนุญาตให้ทราฟฟิก IPv6 ผ่านอุโมงค์",
         "desc_mtu" => "ตั้งค่า MTU ของ WireGuard ช่วงที่ใช้งานได้: 1280 - 1420",
         "title_local_sharing" => "การแชร์เครือข่ายท้องถิ่น",
@@ -1683,9 +1670,6 @@ fn match_japanese(key: &str) -> &'static str {
         "adult_content" => "成人向けコンテンツ",
         "social_media" => "ソーシャルメディア",
         "desc_auto_connect" => "アプリの起動時に自動的にサーバーに接続します。",
-        "desc_dns_blocking" => {
-            "この設定を有効にするには、上記のすべてのDNSコンテンツブロッカーを無効にしてください。"
-        }
         "desc_ipv6" => "有効にすると、トンネルを介したIPv6トラフィックを許可します。",
         "desc_mtu" => "WireGuardのMTU値を設定します。有効な範囲：1280 - 1420。",
         "title_local_sharing" => "ローカルネットワーク共有",
@@ -1768,9 +1752,6 @@ fn match_korean(key: &str) -> &'static str {
         "adult_content" => "성인용 콘텐츠",
         "social_media" => "소셜 미디어",
         "desc_auto_connect" => "앱이 실행될 때 자동으로 서버에 연결합니다.",
-        "desc_dns_blocking" => {
-            "이 설정을 활성화하려면 위의 모든 DNS 콘텐츠 차단기를 비활성화하십시오."
-        }
         "desc_ipv6" => "터널을 통해 IPv6 트래픽을 허용하려면 활성화하십시오.",
         "desc_mtu" => "WireGuard MTU 값을 설정합니다. 유효 범위: 1280 - 1420.",
         "title_local_sharing" => "로컬 네트워크 공유",
@@ -1853,9 +1834,6 @@ fn match_indonesian(key: &str) -> &'static str {
         "adult_content" => "Konten Dewasa",
         "social_media" => "Media Sosial",
         "desc_auto_connect" => "Secara otomatis terhubung ke server saat aplikasi diluncurkan.",
-        "desc_dns_blocking" => {
-            "Nonaktifkan semua pemblokir konten DNS di atas untuk mengaktifkan pengaturan ini."
-        }
         "desc_ipv6" => "Aktifkan untuk mengizinkan lalu lintas IPv6 melalui terowongan.",
         "desc_mtu" => "Atur nilai MTU WireGuard. Rentang valid: 1280 - 1420.",
         "title_local_sharing" => "Berbagi jaringan lokal",
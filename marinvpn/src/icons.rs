@@ -136,6 +136,20 @@ pub fn Star(
     }
 }
 
+#[component]
+pub fn Pin(
+    size: u32,
+    #[props(default)] class: Option<String>,
+    #[props(default)] fill: Option<String>,
+) -> Element {
+    rsx! {
+        IconBase { size, class, fill,
+            path { d: "M12 17v5" }
+            path { d: "M9 10.76a2 2 0 0 1-1.11 1.79l-1.78.9A2 2 0 0 0 5 15.24V16a1 1 0 0 0 1 1h12a1 1 0 0 0 1-1v-.76a2 2 0 0 0-1.11-1.79l-1.78-.9A2 2 0 0 1 15 10.76V7a1 1 0 0 1 1-1 2 2 0 0 0 0-4H8a2 2 0 0 0 0 4 1 1 0 0 1 1 1z" }
+        }
+    }
+}
+
 #[component]
 pub fn Loader(size: u32, #[props(default)] class: Option<String>) -> Element {
     rsx! {
@@ -367,3 +381,23 @@ pub fn Copy(size: u32, #[props(default)] class: Option<String>) -> Element {
         }
     }
 }
+
+#[component]
+pub fn QrCode(size: u32, #[props(default)] class: Option<String>) -> Element {
+    rsx! {
+        IconBase { size, class,
+            rect { width: "5", height: "5", x: "3", y: "3", rx: "1" }
+            rect { width: "5", height: "5", x: "16", y: "3", rx: "1" }
+            rect { width: "5", height: "5", x: "3", y: "16", rx: "1" }
+            path { d: "M21 16h-3a2 2 0 0 0-2 2v3" }
+            path { d: "M21 21v.01" }
+            path { d: "M12 7v3a2 2 0 0 1-2 2H7" }
+            path { d: "M3 12h.01" }
+            path { d: "M12 3h.01" }
+            path { d: "M12 16v.01" }
+            path { d: "M16 12h1" }
+            path { d: "M21 12v.01" }
+            path { d: "M12 21v-1" }
+        }
+    }
+}
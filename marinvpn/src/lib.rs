@@ -2,13 +2,16 @@
 
 pub mod components;
 pub mod data;
+pub mod device_list;
 pub mod error;
 pub mod hooks;
 pub mod i18n;
 pub mod icons;
 pub mod layouts;
+pub mod location_index;
 pub mod models;
 pub mod services;
+pub mod single_instance;
 pub mod state;
 pub mod storage;
 pub mod views;
@@ -122,18 +125,27 @@ fn AppContent() -> Element {
 
     use_effect(move || {
         if status == ConnectionStatus::Connected {
-            let location_info = models::LocationInfo::from_string(&location);
-            update_tray_tooltip(&format!(
-                "Connected. {}, {}",
-                location_info.city, location_info.country
-            ));
+            let tooltip = match models::Location::parse(&location) {
+                Ok(models::Location { country, city }) => {
+                    format!("Connected. {}, {}", city, country)
+                }
+                Err(_) => "Connected".to_string(),
+            };
+            update_tray_tooltip(&tooltip);
         } else {
             update_tray_tooltip(&branding_name);
         }
     });
 
+    let title_window = window.clone();
     use_effect(move || {
-        window.window.set_title(&branding_name_title);
+        title_window.window.set_title(&branding_name_title);
+    });
+
+    use_effect(move || {
+        if (state.exit_ready)() {
+            window.close();
+        }
     });
 
     use_effect(move || {
@@ -164,6 +176,21 @@ fn AppContent() -> Element {
 pub fn run_app() {
     tracing_subscriber::fmt::init();
 
+    // A second instance would fight the first over the config file and the
+    // tunnel, so it only asks the first to show itself.
+    match single_instance::acquire(&single_instance::lock_dir()) {
+        Ok(single_instance::Acquired::Primary(lock)) => lock.listen(window::request_show),
+        Ok(single_instance::Acquired::Secondary { notified }) => {
+            if notified {
+                tracing::info!("MarinVPN is already running; showing its window");
+            } else {
+                tracing::warn!("MarinVPN is already running but did not respond");
+            }
+            return;
+        }
+        Err(e) => tracing::warn!("Could not take the single-instance lock: {}", e),
+    }
+
     let config = Config::new()
         .with_window(
             WindowBuilder::new()
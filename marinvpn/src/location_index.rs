@@ -0,0 +1,231 @@
+use crate::models::{City, Location, Region};
+use std::collections::HashSet;
+
+#[derive(PartialEq)]
+struct IndexedCity {
+    city: City,
+    key: String,
+    name_lower: String,
+}
+
+#[derive(PartialEq)]
+struct IndexedRegion {
+    region: Region,
+    name_lower: String,
+    cities: Vec<IndexedCity>,
+}
+
+/// Search index over the server list. Lowercased names and favorite keys are
+/// computed once when the list changes, so a keystroke only walks the index
+/// instead of re-formatting and re-lowercasing every city.
+#[derive(PartialEq)]
+pub struct LocationIndex {
+    regions: Vec<IndexedRegion>,
+}
+
+impl LocationIndex {
+    pub fn build(regions: &[Region]) -> Self {
+        let regions = regions
+            .iter()
+            .map(|region| IndexedRegion {
+                name_lower: region.name.to_lowercase(),
+                cities: region
+                    .cities
+                    .iter()
+                    .map(|city| IndexedCity {
+                        key: Location::new(&region.name, &city.name).to_string(),
+                        name_lower: city.name.to_lowercase(),
+                        city: city.clone(),
+                    })
+                    .collect(),
+                region: region.clone(),
+            })
+            .collect();
+        Self { regions }
+    }
+
+    pub fn len(&self) -> usize {
+        self.regions.iter().map(|r| r.cities.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Regions matching `query`, each with only the cities to show.
+    ///
+    /// A query matching a country keeps all of its cities; otherwise only the
+    /// matching cities are kept. Regions and cities holding a favorite sort
+    /// first, preserving the list order otherwise. With `favorites_only`
+    /// every non-favorite city is dropped.
+    pub fn search(
+        &self,
+        query: &str,
+        favorites: &HashSet<String>,
+        favorites_only: bool,
+    ) -> Vec<Region> {
+        let query = query.trim().to_lowercase();
+
+        let mut results: Vec<(bool, Region)> = Vec::new();
+        for indexed in &self.regions {
+            let country_matches = query.is_empty() || indexed.name_lower.contains(&query);
+
+            let mut cities: Vec<(bool, &City)> = indexed
+                .cities
+                .iter()
+                .filter(|c| country_matches || c.name_lower.contains(&query))
+                .map(|c| (favorites.contains(&c.key), &c.city))
+                .filter(|(is_fav, _)| *is_fav || !favorites_only)
+                .collect();
+            if cities.is_empty() {
+                continue;
+            }
+            cities.sort_by_key(|(is_fav, _)| !is_fav);
+
+            let has_favorite = cities.first().is_some_and(|(is_fav, _)| *is_fav);
+            let region = Region {
+                cities: cities.into_iter().map(|(_, c)| c.clone()).collect(),
+                ..indexed.region.clone()
+            };
+            results.push((has_favorite, region));
+        }
+
+        results.sort_by_key(|(has_favorite, _)| !has_favorite);
+        results.into_iter().map(|(_, region)| region).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(name: &str, cities: &[&str]) -> Region {
+        Region {
+            name: name.to_string(),
+            flag: String::new(),
+            map_x: 0.0,
+            map_y: 0.0,
+            cities: cities
+                .iter()
+                .map(|c| City {
+                    name: c.to_string(),
+                    load: 0,
+                    ping: 0,
+                    available: true,
+                })
+                .collect(),
+        }
+    }
+
+    fn index() -> LocationIndex {
+        LocationIndex::build(&[
+            region("Sweden", &["Stockholm", "Gothenburg", "Malmo"]),
+            region("Germany", &["Frankfurt", "Berlin"]),
+            region("United States", &["New York", "Los Angeles"]),
+        ])
+    }
+
+    fn names(results: &[Region]) -> Vec<(String, Vec<String>)> {
+        results
+            .iter()
+            .map(|r| {
+                (
+                    r.name.clone(),
+                    r.cities.iter().map(|c| c.name.clone()).collect(),
+                )
+            })
+            .collect()
+    }
+
+    fn favs(keys: &[&str]) -> HashSet<String> {
+        keys.iter().map(|k| k.to_string()).collect()
+    }
+
+    #[test]
+    fn build_indexes_every_city() {
+        let index = index();
+        assert_eq!(index.len(), 7);
+        assert!(!index.is_empty());
+        assert!(LocationIndex::build(&[]).is_empty());
+
+        let all = index.search("", &HashSet::new(), false);
+        assert_eq!(
+            names(&all),
+            vec![
+                (
+                    "Sweden".to_string(),
+                    vec!["Stockholm".into(), "Gothenburg".into(), "Malmo".into()]
+                ),
+                (
+                    "Germany".to_string(),
+                    vec!["Frankfurt".into(), "Berlin".into()]
+                ),
+                (
+                    "United States".to_string(),
+                    vec!["New York".into(), "Los Angeles".into()]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn search_matches_country_or_city() {
+        let index = index();
+        let none = HashSet::new();
+
+        let by_country = index.search("  GER ", &none, false);
+        assert_eq!(
+            names(&by_country),
+            vec![(
+                "Germany".to_string(),
+                vec!["Frankfurt".into(), "Berlin".into()]
+            )]
+        );
+
+        let by_city = index.search("york", &none, false);
+        assert_eq!(
+            names(&by_city),
+            vec![("United States".to_string(), vec!["New York".into()])]
+        );
+
+        assert!(index.search("atlantis", &none, false).is_empty());
+    }
+
+    #[test]
+    fn favorites_sort_first() {
+        let index = index();
+        let favorites = favs(&["Germany, Berlin", "Sweden, Malmo"]);
+
+        let results = index.search("", &favorites, false);
+        assert_eq!(
+            names(&results)
+                .iter()
+                .map(|(region, cities)| (region.as_str(), cities[0].as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("Sweden", "Malmo"),
+                ("Germany", "Berlin"),
+                ("United States", "New York"),
+            ]
+        );
+    }
+
+    #[test]
+    fn favorites_only_drops_everything_else() {
+        let index = index();
+        let favorites = favs(&["Germany, Berlin", "Sweden, Malmo", "Nowhere, Gone"]);
+
+        assert_eq!(
+            names(&index.search("", &favorites, true)),
+            vec![
+                ("Sweden".to_string(), vec!["Malmo".into()]),
+                ("Germany".to_string(), vec!["Berlin".into()]),
+            ]
+        );
+        assert_eq!(
+            names(&index.search("ber", &favorites, true)),
+            vec![("Germany".to_string(), vec!["Berlin".into()])]
+        );
+        assert!(index.search("", &HashSet::new(), true).is_empty());
+    }
+}
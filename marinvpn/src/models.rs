@@ -1,10 +1,12 @@
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
 
 pub use marinvpn_common::{
-    Account, ConfigRequest, ConnectionStatus, Device, DnsBlockingState, ErrorResponse,
-    GenerateResponse, IpVersion, LoginRequest, LoginResponse, Protocol, RefreshRequest,
-    RefreshResponse, RemoveDeviceRequest, ReportRequest, VpnServer as CommonVpnServer,
-    WireGuardConfig,
+    Account, AccountPanicRequest, AccountPanicResponse, ConfigRequest, ConnectionStatus, Device,
+    DnsBlockingState, ErrorResponse, GenerateResponse, IpVersion, LoginRequest, LoginResponse,
+    LogoutResponse, PowChallenge, PowSolution, Protocol, RefreshRequest, RefreshResponse,
+    RemoveDeviceReason, RemoveDeviceRequest, RemoveDeviceResponse, ReportOs, ReportRequest,
+    ReportResponse, ServerVersion, SessionsResponse, VpnServer as CommonVpnServer, WireGuardConfig,
 };
 
 #[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize, Default)]
@@ -102,6 +104,7 @@ pub enum StealthMode {
     #[default]
     Automatic,
     WireGuardPort,
+    WireGuard443,
     Lwo,
     Quic,
     Shadowsocks,
@@ -109,11 +112,157 @@ pub enum StealthMode {
     None,
 }
 
+/// A DNS-over-HTTPS endpoint that speaks the JSON API
+/// (`application/dns-json`), used to find the API host without trusting the
+/// local resolver.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct DohProvider {
+    pub name: String,
+    pub url: String,
+}
+
+impl DohProvider {
+    pub fn new(name: &str, url: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            url: url.to_string(),
+        }
+    }
+
+    /// Tried in this order. They run on separate infrastructure, so a censor
+    /// has to block all three.
+    pub fn defaults() -> Vec<Self> {
+        vec![
+            Self::new("Cloudflare", "https://cloudflare-dns.com/dns-query"),
+            Self::new("Google", "https://dns.google/resolve"),
+            Self::new("Quad9", "https://dns.quad9.net:5053/dns-query"),
+        ]
+    }
+
+    /// Only `https` endpoints with a host; the point is that the local
+    /// network cannot read or rewrite the query.
+    pub fn is_valid(&self) -> bool {
+        self.url
+            .strip_prefix("https://")
+            .and_then(|rest| rest.split(['/', '?']).next())
+            .is_some_and(|host| !host.is_empty() && !host.contains(char::is_whitespace))
+    }
+}
+
+/// How the client reaches the MarinVPN API. The tunnel itself is not
+/// affected; this is for networks that block the API but not WireGuard, or
+/// users who want the control plane kept off their own connection.
+#[derive(Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub enum ApiProxy {
+    #[default]
+    Direct,
+    /// An `http://` or `https://` proxy URL.
+    Http(String),
+    /// `host:port` of a SOCKS5 proxy. Names are resolved by the proxy.
+    Socks5(String),
+    /// The local Tor client's SOCKS port.
+    Tor,
+}
+
+/// What the client does by itself when a connected tunnel stops working.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub enum ReconnectPolicy {
+    /// Leave the tunnel alone; the user reconnects.
+    Never,
+    /// Rebuild a tunnel whose handshake went stale or that came back from
+    /// sleep without one, and fail over once health checks keep failing.
+    #[default]
+    OnFailure,
+    /// As `OnFailure`, but checks more often, gives up on a server sooner
+    /// and fails over to another server in the chosen country as well.
+    Aggressive,
+}
+
+/// What the client connects to by itself at launch.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub enum StartupBehavior {
+    /// Stay disconnected until the user connects.
+    #[default]
+    DoNothing,
+    /// Reconnect to the exact servers that were connected when the app
+    /// closed. Stays disconnected if the user had disconnected.
+    ConnectLast,
+    /// Connect to the lowest-latency server anywhere, whatever location is
+    /// selected.
+    ConnectFastest,
+    /// Connect to the selected entry, and exit with multihop.
+    ConnectConfigured,
+}
+
+/// Which side of [`SettingsState::split_tunneling`] the listed ranges and
+/// apps are on.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub enum SplitTunnelMode {
+    /// Everything goes through the tunnel except `excluded_ips` and
+    /// `excluded_apps`.
+    #[default]
+    Exclude,
+    /// Only `included_ips` and `included_apps` go through the tunnel.
+    IncludeOnly,
+}
+
+/// Which resolver answers for a [`DnsRoute`]'s domain.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub enum DnsRouteVia {
+    /// The DNS server used inside the tunnel.
+    #[default]
+    Tunnel,
+    /// The resolver of the network the machine is on, outside the tunnel.
+    Local,
+}
+
+/// Names under `domain` sent to a chosen resolver, whatever the rest of
+/// DNS does.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct DnsRoute {
+    /// Lowercase, without leading or trailing dots. Covers its subdomains.
+    pub domain: String,
+    pub via: DnsRouteVia,
+    /// Also try single-label names under `domain`, so `intranet` looks up
+    /// `intranet.corp.example`.
+    #[serde(default)]
+    pub search: bool,
+}
+
+/// Whether a reconnect may present the WireGuard key of an earlier one.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub enum KeyReuse {
+    /// A new keypair for every config, so no two sessions share a key.
+    #[default]
+    Fresh,
+    /// Keep using a location's keypair for this many seconds after it was
+    /// made, so a quick reconnect gets the same peer and address back.
+    Within(u64),
+}
+
+impl KeyReuse {
+    pub fn window(self) -> Option<Duration> {
+        match self {
+            KeyReuse::Fresh => None,
+            KeyReuse::Within(secs) => Some(Duration::from_secs(secs)),
+        }
+    }
+}
+
 #[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
 pub struct SettingsState {
     pub dark_mode: bool,
     pub launch_on_startup: bool,
-    pub auto_connect: bool,
+    /// What to connect to at launch, once signed in.
+    #[serde(default)]
+    pub startup_behavior: StartupBehavior,
+    /// The on/off switch that `startup_behavior` replaced. Only read from
+    /// older settings files, by [`normalized`](Self::normalized).
+    #[serde(rename = "auto_connect", default, skip_serializing)]
+    pub legacy_auto_connect: bool,
+    /// Kept while `startup_behavior` is [`StartupBehavior::ConnectLast`].
+    #[serde(default)]
+    pub last_connection: Option<LastConnection>,
     pub local_sharing: bool,
     pub language: Language,
     pub branding_preset: String,
@@ -122,22 +271,79 @@ pub struct SettingsState {
     pub branding_logo_path: String,
     pub protocol: Protocol,
     pub stealth_mode: StealthMode,
+    /// Networks where Automatic found UDP blocked, by
+    /// [`network_id`](crate::services::network_id). Automatic goes straight
+    /// to UDP-over-TCP on them.
+    #[serde(default)]
+    pub udp_blocked_networks: Vec<String>,
     pub ipv6_support: bool,
     pub quantum_resistant: bool,
     pub split_tunneling: bool,
     pub multi_hop: bool,
-    pub entry_location: String,
-    pub exit_location: String,
+    pub entry_location: LocationSelection,
+    pub exit_location: LocationSelection,
+    /// The server to stay on, as `"Country, City"`, for users whose IP has
+    /// to stay the same. It stands in for automatic picks, and failover
+    /// reconnects to it rather than moving elsewhere.
+    #[serde(default)]
+    pub pinned_server: Option<String>,
     pub lockdown_mode: bool,
+    /// Leave the lockdown firewall up after the app quits.
+    #[serde(default)]
+    pub keep_lockdown_after_exit: bool,
     pub obfuscation: bool,
     pub daita_enabled: bool,
     pub dns_blocking: DnsBlockingState,
     pub custom_dns: bool,
     pub custom_dns_server: String,
+    /// Domains resolved by a different resolver than other names.
+    #[serde(default)]
+    pub dns_routes: Vec<DnsRoute>,
+    /// Resolve names no route matches on the local network instead of
+    /// through the tunnel.
+    #[serde(default)]
+    pub dns_unmatched_local: bool,
     pub ip_version: IpVersion,
     pub mtu: u32,
     pub excluded_ips: Vec<String>,
+    #[serde(default)]
+    pub split_mode: SplitTunnelMode,
+    #[serde(default)]
+    pub included_ips: Vec<String>,
     pub excluded_apps: Vec<AppInfo>,
+    #[serde(default)]
+    pub included_apps: Vec<AppInfo>,
+    /// Where the API host is looked up, first to last.
+    #[serde(default = "DohProvider::defaults")]
+    pub doh_providers: Vec<DohProvider>,
+    /// Hide the API hostname with Encrypted Client Hello where the host
+    /// offers it.
+    #[serde(default)]
+    pub encrypted_client_hello: bool,
+    #[serde(default)]
+    pub api_proxy: ApiProxy,
+    /// Hold the server sync, token sync and latency probes while
+    /// disconnected, so the app only talks to the network when asked to.
+    #[serde(default)]
+    pub offline_mode: bool,
+    #[serde(default)]
+    pub reconnect_policy: ReconnectPolicy,
+    /// Measure server latency far less often while running on battery.
+    #[serde(default)]
+    pub battery_saver: bool,
+    #[serde(default)]
+    pub key_reuse: KeyReuse,
+}
+
+/// The settings that leave the device, in config requests. Requests are
+/// built from this alone, so the rest of [`SettingsState`] (appearance,
+/// split tunneling, custom DNS, locations and so on) stays local.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct ServerSettings {
+    /// Asks the relay's resolver to block these categories.
+    pub dns_blocking: Option<DnsBlockingState>,
+    /// Asks for a post-quantum key exchange on top of WireGuard's.
+    pub quantum_resistant: bool,
 }
 
 impl Default for SettingsState {
@@ -145,7 +351,9 @@ impl Default for SettingsState {
         Self {
             dark_mode: true,
             launch_on_startup: false,
-            auto_connect: false,
+            startup_behavior: StartupBehavior::DoNothing,
+            legacy_auto_connect: false,
+            last_connection: None,
             local_sharing: false,
             language: Language::English,
             branding_preset: "custom".to_string(),
@@ -154,31 +362,283 @@ impl Default for SettingsState {
             branding_logo_path: "".to_string(),
             protocol: Protocol::WireGuard,
             stealth_mode: StealthMode::None,
+            udp_blocked_networks: vec![],
             ipv6_support: true,
             quantum_resistant: false,
             split_tunneling: false,
             multi_hop: false,
-            entry_location: "Automatic".to_string(),
-            exit_location: "Automatic".to_string(),
+            entry_location: LocationSelection::Automatic,
+            exit_location: LocationSelection::Automatic,
+            pinned_server: None,
             lockdown_mode: false,
+            keep_lockdown_after_exit: false,
             obfuscation: false,
             daita_enabled: false,
             dns_blocking: DnsBlockingState::default(),
             custom_dns: false,
             custom_dns_server: "1.1.1.1".to_string(),
+            dns_routes: vec![],
+            dns_unmatched_local: false,
             ip_version: IpVersion::Automatic,
             mtu: 1420,
             excluded_ips: vec![],
+            split_mode: SplitTunnelMode::Exclude,
+            included_ips: vec![],
             excluded_apps: vec![],
+            included_apps: vec![],
+            doh_providers: DohProvider::defaults(),
+            encrypted_client_hello: false,
+            api_proxy: ApiProxy::Direct,
+            offline_mode: false,
+            reconnect_policy: ReconnectPolicy::OnFailure,
+            battery_saver: false,
+            key_reuse: KeyReuse::Fresh,
+        }
+    }
+}
+
+/// Content blocking is done by the relay's resolver, so a custom DNS server
+/// bypasses it. The two are kept mutually exclusive: switching one on
+/// switches the other off.
+impl SettingsState {
+    pub fn set_custom_dns(&mut self, enabled: bool) {
+        self.custom_dns = enabled;
+        if enabled {
+            self.dns_blocking = DnsBlockingState::default();
+        }
+    }
+
+    /// Applies `change` to the blockers and turns custom DNS off if any
+    /// blocker ends up enabled.
+    pub fn update_dns_blocking(&mut self, change: impl FnOnce(&mut DnsBlockingState)) {
+        change(&mut self.dns_blocking);
+        if self.dns_blocking != DnsBlockingState::default() {
+            self.custom_dns = false;
+        }
+    }
+
+    /// The ranges the current [`SplitTunnelMode`] applies to.
+    pub fn split_ranges_mut(&mut self) -> &mut Vec<String> {
+        match self.split_mode {
+            SplitTunnelMode::Exclude => &mut self.excluded_ips,
+            SplitTunnelMode::IncludeOnly => &mut self.included_ips,
+        }
+    }
+
+    /// The apps the current [`SplitTunnelMode`] applies to.
+    pub fn split_apps(&self) -> &[AppInfo] {
+        match self.split_mode {
+            SplitTunnelMode::Exclude => &self.excluded_apps,
+            SplitTunnelMode::IncludeOnly => &self.included_apps,
+        }
+    }
+
+    pub fn split_apps_mut(&mut self) -> &mut Vec<AppInfo> {
+        match self.split_mode {
+            SplitTunnelMode::Exclude => &mut self.excluded_apps,
+            SplitTunnelMode::IncludeOnly => &mut self.included_apps,
+        }
+    }
+
+    /// What the split tunneling menu entry shows: off, or how many apps the
+    /// current mode lists.
+    pub fn split_tunneling_summary(&self) -> String {
+        if !self.split_tunneling {
+            return "Off".to_string();
+        }
+        let count = self.split_apps().len();
+        let apps = if count == 1 { "app" } else { "apps" };
+        match (self.split_mode, count) {
+            (SplitTunnelMode::Exclude, 0) => "No apps excluded".to_string(),
+            (SplitTunnelMode::Exclude, n) => format!("{} {} excluded", n, apps),
+            (SplitTunnelMode::IncludeOnly, 0) => "No apps listed".to_string(),
+            (SplitTunnelMode::IncludeOnly, n) => format!("Only {} {} tunneled", n, apps),
+        }
+    }
+
+    /// Whether only some apps use the tunnel and the rest leave directly.
+    pub fn tunnels_only_listed_apps(&self) -> bool {
+        self.split_tunneling
+            && self.split_mode == SplitTunnelMode::IncludeOnly
+            && !self.included_apps.is_empty()
+    }
+
+    pub fn uses_custom_dns(&self) -> bool {
+        self.custom_dns && !self.custom_dns_server.trim().is_empty()
+    }
+
+    /// Blocking preferences to send with a config request, or `None` when a
+    /// custom DNS server would ignore them anyway.
+    pub fn requested_dns_blocking(&self) -> Option<DnsBlockingState> {
+        (!self.uses_custom_dns()).then(|| self.dns_blocking.clone())
+    }
+
+    /// Everything a config request says about these settings.
+    pub fn server_settings(&self) -> ServerSettings {
+        ServerSettings {
+            dns_blocking: self.requested_dns_blocking(),
+            quantum_resistant: self.quantum_resistant,
+        }
+    }
+
+    /// Resolves settings saved before the rule was enforced. Custom DNS wins,
+    /// as it always did when the tunnel was configured. Auto-connect from
+    /// before [`StartupBehavior`] becomes `ConnectConfigured`.
+    pub fn normalized(mut self) -> Self {
+        if self.custom_dns {
+            self.set_custom_dns(true);
+        }
+        if std::mem::take(&mut self.legacy_auto_connect) {
+            self.startup_behavior = StartupBehavior::ConnectConfigured;
+        }
+        self
+    }
+}
+
+/// Oldest entries are dropped past this so the list cannot grow without
+/// bound on a laptop that roams a lot.
+const MAX_UDP_BLOCKED_NETWORKS: usize = 32;
+
+impl SettingsState {
+    /// The mode to connect with on `network`: Automatic skips the UDP
+    /// attempt where UDP has been found blocked before.
+    pub fn stealth_mode_on(&self, network: Option<&str>) -> StealthMode {
+        let blocked = network.is_some_and(|id| self.udp_blocked_networks.iter().any(|n| n == id));
+        match self.stealth_mode {
+            StealthMode::Automatic if blocked => StealthMode::Tcp,
+            mode => mode,
+        }
+    }
+
+    pub fn remember_udp_blocked(&mut self, network: &str) {
+        if self.udp_blocked_networks.iter().any(|n| n == network) {
+            return;
+        }
+        self.udp_blocked_networks.push(network.to_string());
+        let excess = self
+            .udp_blocked_networks
+            .len()
+            .saturating_sub(MAX_UDP_BLOCKED_NETWORKS);
+        self.udp_blocked_networks.drain(..excess);
+    }
+
+    /// What [`startup_behavior`](Self::startup_behavior) connects to. Only
+    /// a connection that was still up when the app closed is resumed.
+    pub fn startup_action(&self) -> Option<VpnAction> {
+        let pair = |entry: &LocationSelection, exit: &LocationSelection| {
+            if self.multi_hop {
+                VpnAction::MultiHopConnect(entry.clone(), exit.clone())
+            } else {
+                VpnAction::Connect(entry.clone())
+            }
+        };
+        match self.startup_behavior {
+            StartupBehavior::DoNothing => None,
+            StartupBehavior::ConnectLast => self.last_connection.as_ref()?.resume(),
+            StartupBehavior::ConnectFastest => {
+                let fastest = LocationSelection::Automatic;
+                Some(pair(&fastest, &fastest))
+            }
+            StartupBehavior::ConnectConfigured => {
+                Some(pair(&self.entry_location, &self.exit_location))
+            }
+        }
+    }
+
+    /// The pinned server, unless none is pinned or it no longer parses.
+    pub fn pinned_location(&self) -> Option<Location> {
+        Location::parse(self.pinned_server.as_deref()?).ok()
+    }
+
+    pub fn is_pinned(&self, location: &Location) -> bool {
+        self.pinned_location().as_ref() == Some(location)
+    }
+
+    /// Pins `location`, or unpins it if it is the pinned server.
+    pub fn toggle_pin(&mut self, location: &Location) {
+        self.pinned_server = if self.is_pinned(location) {
+            None
+        } else {
+            Some(location.to_string())
+        };
+    }
+
+    /// What to connect to for `selection`. A pinned server replaces the
+    /// automatic picks, which would choose by load and latency; a server
+    /// picked by hand is still used.
+    pub fn with_pin(&self, selection: LocationSelection) -> LocationSelection {
+        match (selection, self.pinned_location()) {
+            (LocationSelection::Specific(location), _) => location.into(),
+            (_, Some(pinned)) => pinned.into(),
+            (selection, None) => selection,
+        }
+    }
+
+    pub fn set_startup_behavior(&mut self, behavior: StartupBehavior) {
+        self.startup_behavior = behavior;
+        if behavior != StartupBehavior::ConnectLast {
+            self.last_connection = None;
+        }
+    }
+
+    /// Records the servers a connect came up on, for
+    /// [`StartupBehavior::ConnectLast`] only.
+    pub fn remember_connected(&mut self, entry: &str, exit: Option<&str>) {
+        if self.startup_behavior == StartupBehavior::ConnectLast {
+            self.last_connection = Some(LastConnection {
+                entry: entry.to_string(),
+                exit: exit.map(str::to_string),
+                connected: true,
+            });
+        }
+    }
+
+    /// Keeps the servers but stops the next launch from reconnecting to
+    /// them. Only a disconnect the user asked for does this; quitting while
+    /// connected does not.
+    pub fn remember_disconnected(&mut self) {
+        if let Some(last) = &mut self.last_connection {
+            last.connected = false;
         }
     }
 }
 
+/// The servers the tunnel last came up on, as `"Country, City"`.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct LastConnection {
+    pub entry: String,
+    #[serde(default)]
+    pub exit: Option<String>,
+    /// Whether the tunnel was still up when the app closed.
+    pub connected: bool,
+}
+
+impl LastConnection {
+    /// The connect that brings the same servers back, or `None` if the
+    /// tunnel was down or a location no longer parses.
+    fn resume(&self) -> Option<VpnAction> {
+        if !self.connected {
+            return None;
+        }
+        let entry = Location::parse(&self.entry).ok()?;
+        Some(match &self.exit {
+            Some(exit) => {
+                let exit = Location::parse(exit).ok()?;
+                VpnAction::MultiHopConnect(entry.into(), exit.into())
+            }
+            None => VpnAction::Connect(entry.into()),
+        })
+    }
+}
+
 #[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
 pub struct City {
     pub name: String,
     pub load: u8,
     pub ping: u8,
+    /// Cleared when a sync no longer lists the city. It stays on screen so
+    /// a selected or favorite city does not vanish.
+    pub available: bool,
 }
 
 #[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
@@ -190,25 +650,592 @@ pub struct Region {
     pub cities: Vec<City>,
 }
 
-#[derive(Clone, Debug, PartialEq)]
-pub struct LocationInfo {
+/// A concrete server location, written `"Country, City"`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Location {
     pub country: String,
     pub city: String,
 }
 
-impl LocationInfo {
-    pub fn from_string(s: &str) -> Self {
-        let parts: Vec<&str> = s.split(',').collect();
-        let country = parts.first().unwrap_or(&"Unknown").trim().to_string();
-        let city = parts.get(1).unwrap_or(&"Unknown").trim().to_string();
-        Self { country, city }
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum LocationParseError {
+    #[error("location is empty")]
+    Empty,
+    #[error("location {0:?} is not in \"Country, City\" form")]
+    Malformed(String),
+}
+
+impl Location {
+    pub fn new(country: impl Into<String>, city: impl Into<String>) -> Self {
+        Self {
+            country: country.into(),
+            city: city.into(),
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self, LocationParseError> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err(LocationParseError::Empty);
+        }
+
+        let malformed = || LocationParseError::Malformed(s.to_string());
+        let (country, city) = s.split_once(',').ok_or_else(malformed)?;
+        let (country, city) = (country.trim(), city.trim());
+        if country.is_empty() || city.is_empty() || city.contains(',') {
+            return Err(malformed());
+        }
+        Ok(Self::new(country, city))
+    }
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}, {}", self.country, self.city)
+    }
+}
+
+impl std::str::FromStr for Location {
+    type Err = LocationParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl From<&CommonVpnServer> for Location {
+    fn from(server: &CommonVpnServer) -> Self {
+        Self::new(&server.country, &server.city)
+    }
+}
+
+/// What the user asked to connect to. Everything but `Specific` still needs
+/// a server picked before connecting.
+///
+/// Persisted as the strings older versions stored: `"Automatic"`,
+/// `"Country, Auto"` and `"Country, City"`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Default)]
+pub enum LocationSelection {
+    #[default]
+    Automatic,
+    BestIn(String),
+    Specific(Location),
+}
+
+impl LocationSelection {
+    pub const AUTOMATIC: &'static str = "Automatic";
+    const BEST_IN_CITY: &'static str = "Auto";
+
+    pub fn parse(s: &str) -> Result<Self, LocationParseError> {
+        let s = s.trim();
+        if s.eq_ignore_ascii_case(Self::AUTOMATIC) {
+            return Ok(Self::Automatic);
+        }
+
+        let location = Location::parse(s)?;
+        if location.city.eq_ignore_ascii_case(Self::BEST_IN_CITY)
+            || location.city.eq_ignore_ascii_case(Self::AUTOMATIC)
+        {
+            Ok(Self::BestIn(location.country))
+        } else {
+            Ok(Self::Specific(location))
+        }
+    }
+
+    /// Like `parse`, but anything unreadable falls back to `Automatic`.
+    /// Settings written by older versions could hold an empty string here.
+    pub fn parse_or_automatic(s: &str) -> Self {
+        Self::parse(s).unwrap_or_default()
+    }
+
+    pub fn country(&self) -> Option<&str> {
+        match self {
+            Self::Automatic => None,
+            Self::BestIn(country) => Some(country),
+            Self::Specific(location) => Some(&location.country),
+        }
+    }
+
+    pub fn specific(&self) -> Option<&Location> {
+        match self {
+            Self::Specific(location) => Some(location),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for LocationSelection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Automatic => f.write_str(Self::AUTOMATIC),
+            Self::BestIn(country) => write!(f, "{}, {}", country, Self::BEST_IN_CITY),
+            Self::Specific(location) => location.fmt(f),
+        }
+    }
+}
+
+impl std::str::FromStr for LocationSelection {
+    type Err = LocationParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl From<Location> for LocationSelection {
+    fn from(location: Location) -> Self {
+        Self::Specific(location)
+    }
+}
+
+impl Serialize for LocationSelection {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for LocationSelection {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = String::deserialize(deserializer)?;
+        Ok(Self::parse_or_automatic(&raw))
     }
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum VpnAction {
-    Connect(String),
-    MultiHopConnect(String, String),
+    Connect(LocationSelection),
+    MultiHopConnect(LocationSelection, LocationSelection),
     Disconnect,
     Reconnect,
+    /// Tear down for exit, then flag the window to close.
+    Quit,
+    /// Try every stealth mode against the entry location and switch to the
+    /// fastest one that works.
+    FindStealthMode,
+    /// Take up the pending [`SingleHopOffer`]. Nothing happens without one.
+    AcceptSingleHop,
+}
+
+/// One end of a multihop pair.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hop {
+    Entry,
+    Exit,
+}
+
+impl Hop {
+    pub fn label(self) -> &'static str {
+        match self {
+            Hop::Entry => "Entry",
+            Hop::Exit => "Exit",
+        }
+    }
+}
+
+/// Left behind when only one hop of a multihop connect got a config. The
+/// other hop's config is kept so the user can connect through it alone, which
+/// is never done without asking: a single server then sees both who they are
+/// and where their traffic goes.
+#[derive(Clone, PartialEq)]
+pub struct SingleHopOffer {
+    pub failed: Hop,
+    pub reason: String,
+    pub location: String,
+    pub config: WireGuardConfig,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        DnsBlockingState, DohProvider, LastConnection, Location, LocationParseError,
+        LocationSelection, SettingsState, StartupBehavior, StealthMode, VpnAction,
+    };
+
+    fn specific(country: &str, city: &str) -> LocationSelection {
+        LocationSelection::Specific(Location::new(country, city))
+    }
+
+    #[test]
+    fn location_round_trips() {
+        for text in ["Sweden, Stockholm", "United States, New York"] {
+            let location = Location::parse(text).unwrap();
+            assert_eq!(location.to_string(), text);
+            assert_eq!(text.parse::<Location>().unwrap(), location);
+        }
+        assert_eq!(
+            Location::parse("Sweden,Stockholm").unwrap(),
+            Location::new("Sweden", "Stockholm")
+        );
+    }
+
+    #[test]
+    fn location_parse_rejects_malformed_input() {
+        assert_eq!(Location::parse(""), Err(LocationParseError::Empty));
+        assert_eq!(Location::parse("   "), Err(LocationParseError::Empty));
+        for text in ["Sweden", "Sweden,", ", Stockholm", "a, b, c", "Automatic"] {
+            assert!(
+                matches!(Location::parse(text), Err(LocationParseError::Malformed(_))),
+                "{text:?} should not parse"
+            );
+        }
+    }
+
+    #[test]
+    fn selection_round_trips() {
+        for text in ["Automatic", "Sweden, Auto", "United States, New York"] {
+            let selection = LocationSelection::parse(text).unwrap();
+            assert_eq!(selection.to_string(), text);
+            assert_eq!(text.parse::<LocationSelection>().unwrap(), selection);
+        }
+        assert_eq!(
+            LocationSelection::parse("  automatic ").unwrap(),
+            LocationSelection::Automatic
+        );
+        assert_eq!(
+            LocationSelection::parse("Sweden, Automatic").unwrap(),
+            LocationSelection::BestIn("Sweden".to_string())
+        );
+        assert!(LocationSelection::parse("Auto").is_err());
+        assert!(LocationSelection::parse("").is_err());
+    }
+
+    #[test]
+    fn city_containing_auto_is_never_automatic() {
+        for (country, city) in [
+            ("Spain", "Autol"),
+            ("Italy", "Automobile City"),
+            ("Mexico", "Autlán"),
+            ("Automatica", "Somewhere"),
+            ("Sweden", "Stockholm Auto"),
+        ] {
+            let text = format!("{}, {}", country, city);
+            let selection = LocationSelection::parse(&text).unwrap();
+            assert_eq!(selection, specific(country, city), "{text}");
+            assert_eq!(selection.specific(), Some(&Location::new(country, city)));
+            assert_eq!(LocationSelection::parse_or_automatic(&text), selection);
+        }
+    }
+
+    #[test]
+    fn selection_accessors() {
+        let city = specific("Sweden", "Stockholm");
+        assert_eq!(city.country(), Some("Sweden"));
+        assert!(city.specific().is_some());
+
+        let best = LocationSelection::BestIn("Sweden".to_string());
+        assert_eq!(best.country(), Some("Sweden"));
+        assert_eq!(best.specific(), None);
+
+        assert_eq!(LocationSelection::Automatic.country(), None);
+        assert_eq!(LocationSelection::Automatic.specific(), None);
+    }
+
+    #[test]
+    fn persisted_selections_migrate() {
+        let read = |raw: &str| -> LocationSelection {
+            serde_json::from_value(serde_json::Value::String(raw.to_string())).unwrap()
+        };
+        assert_eq!(read(""), LocationSelection::Automatic);
+        assert_eq!(read("not a location"), LocationSelection::Automatic);
+        assert_eq!(read("Automatic"), LocationSelection::Automatic);
+        assert_eq!(read("Germany, Auto"), LocationSelection::BestIn("Germany".into()));
+        assert_eq!(read("Germany, Frankfurt"), specific("Germany", "Frankfurt"));
+
+        let settings = SettingsState {
+            entry_location: specific("Germany", "Frankfurt"),
+            ..SettingsState::default()
+        };
+        let json = serde_json::to_value(&settings).unwrap();
+        assert_eq!(json["entry_location"], "Germany, Frankfurt");
+        assert_eq!(json["exit_location"], "Automatic");
+        assert_eq!(
+            serde_json::from_value::<SettingsState>(json).unwrap(),
+            settings
+        );
+    }
+
+    #[test]
+    fn doh_providers_default_in_older_settings() {
+        let mut json = serde_json::to_value(SettingsState::default()).unwrap();
+        json.as_object_mut().unwrap().remove("doh_providers");
+        let settings = serde_json::from_value::<SettingsState>(json).unwrap();
+        let names: Vec<_> = settings.doh_providers.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["Cloudflare", "Google", "Quad9"]);
+        assert!(settings.doh_providers.iter().all(DohProvider::is_valid));
+    }
+
+    #[test]
+    fn doh_providers_must_be_https() {
+        let valid = |url: &str| DohProvider::new("Test", url).is_valid();
+        assert!(valid("https://doh.example/dns-query"));
+        assert!(valid("https://doh.example:8443"));
+        assert!(!valid("http://doh.example/dns-query"));
+        assert!(!valid("https:///dns-query"));
+        assert!(!valid("https://doh .example/"));
+        assert!(!valid("doh.example"));
+    }
+
+    fn blocking_ads() -> DnsBlockingState {
+        DnsBlockingState {
+            ads: true,
+            ..DnsBlockingState::default()
+        }
+    }
+
+    #[test]
+    fn custom_dns_turns_content_blocking_off() {
+        let mut settings = SettingsState {
+            dns_blocking: blocking_ads(),
+            ..SettingsState::default()
+        };
+        settings.set_custom_dns(true);
+        assert!(settings.custom_dns);
+        assert_eq!(settings.dns_blocking, DnsBlockingState::default());
+    }
+
+    #[test]
+    fn content_blocking_turns_custom_dns_off() {
+        let mut settings = SettingsState::default();
+        settings.set_custom_dns(true);
+        settings.update_dns_blocking(|b| b.trackers = true);
+        assert!(!settings.custom_dns);
+        assert!(settings.dns_blocking.trackers);
+
+        // Switching the last blocker off leaves custom DNS alone.
+        settings.set_custom_dns(false);
+        settings.update_dns_blocking(|b| b.trackers = false);
+        settings.set_custom_dns(true);
+        settings.update_dns_blocking(|b| b.ads = false);
+        assert!(settings.custom_dns);
+    }
+
+    #[test]
+    fn blocking_is_only_requested_without_custom_dns() {
+        let mut settings = SettingsState {
+            dns_blocking: blocking_ads(),
+            ..SettingsState::default()
+        };
+        assert_eq!(settings.requested_dns_blocking(), Some(blocking_ads()));
+
+        settings.set_custom_dns(true);
+        assert_eq!(settings.requested_dns_blocking(), None);
+
+        // An empty server field means the relay's resolver is used.
+        settings.custom_dns_server = "  ".to_string();
+        assert!(settings.requested_dns_blocking().is_some());
+    }
+
+    #[test]
+    fn saved_conflicting_dns_settings_keep_custom_dns() {
+        let saved = SettingsState {
+            custom_dns: true,
+            dns_blocking: blocking_ads(),
+            ..SettingsState::default()
+        };
+        let settings = saved.normalized();
+        assert!(settings.custom_dns);
+        assert_eq!(settings.dns_blocking, DnsBlockingState::default());
+    }
+
+    #[test]
+    fn automatic_skips_udp_on_networks_that_block_it() {
+        let mut settings = SettingsState {
+            stealth_mode: StealthMode::Automatic,
+            ..SettingsState::default()
+        };
+        settings.remember_udp_blocked("cafe");
+        settings.remember_udp_blocked("cafe");
+        assert_eq!(settings.udp_blocked_networks, ["cafe"]);
+
+        assert_eq!(settings.stealth_mode_on(Some("cafe")), StealthMode::Tcp);
+        assert_eq!(
+            settings.stealth_mode_on(Some("home")),
+            StealthMode::Automatic
+        );
+        assert_eq!(settings.stealth_mode_on(None), StealthMode::Automatic);
+
+        // An explicit choice is never overridden.
+        settings.stealth_mode = StealthMode::Quic;
+        assert_eq!(settings.stealth_mode_on(Some("cafe")), StealthMode::Quic);
+    }
+
+    #[test]
+    fn udp_blocked_networks_keep_the_most_recent() {
+        let mut settings = SettingsState::default();
+        for i in 0..40 {
+            settings.remember_udp_blocked(&format!("net-{}", i));
+        }
+        assert_eq!(settings.udp_blocked_networks.len(), 32);
+        assert_eq!(settings.udp_blocked_networks[0], "net-8");
+        assert_eq!(settings.udp_blocked_networks[31], "net-39");
+    }
+
+    fn starting_with(behavior: StartupBehavior) -> SettingsState {
+        SettingsState {
+            startup_behavior: behavior,
+            entry_location: specific("Spain", "Madrid"),
+            exit_location: specific("Sweden", "Malmo"),
+            ..SettingsState::default()
+        }
+    }
+
+    #[test]
+    fn a_pinned_server_replaces_automatic_picks() {
+        let mut settings = SettingsState::default();
+        let berlin = Location::new("Germany", "Berlin");
+        assert_eq!(
+            settings.with_pin(LocationSelection::Automatic),
+            LocationSelection::Automatic
+        );
+
+        settings.toggle_pin(&berlin);
+        assert!(settings.is_pinned(&berlin));
+        assert_eq!(
+            settings.with_pin(LocationSelection::Automatic),
+            specific("Germany", "Berlin")
+        );
+        assert_eq!(
+            settings.with_pin(LocationSelection::BestIn("Spain".to_string())),
+            specific("Germany", "Berlin")
+        );
+        assert_eq!(
+            settings.with_pin(specific("Spain", "Madrid")),
+            specific("Spain", "Madrid"),
+            "a server picked by hand is kept"
+        );
+
+        settings.toggle_pin(&berlin);
+        assert_eq!(settings.pinned_server, None);
+    }
+
+    #[test]
+    fn doing_nothing_at_startup_stays_disconnected() {
+        let mut settings = starting_with(StartupBehavior::DoNothing);
+        settings.remember_connected("Spain, Madrid", None);
+        assert_eq!(settings.last_connection, None, "nothing is kept");
+        assert_eq!(settings.startup_action(), None);
+    }
+
+    #[test]
+    fn connecting_configured_uses_the_selected_locations() {
+        let mut settings = starting_with(StartupBehavior::ConnectConfigured);
+        assert_eq!(
+            settings.startup_action(),
+            Some(VpnAction::Connect(specific("Spain", "Madrid")))
+        );
+        settings.multi_hop = true;
+        assert_eq!(
+            settings.startup_action(),
+            Some(VpnAction::MultiHopConnect(
+                specific("Spain", "Madrid"),
+                specific("Sweden", "Malmo")
+            ))
+        );
+    }
+
+    #[test]
+    fn connecting_fastest_ignores_the_selected_locations() {
+        let mut settings = starting_with(StartupBehavior::ConnectFastest);
+        assert_eq!(
+            settings.startup_action(),
+            Some(VpnAction::Connect(LocationSelection::Automatic))
+        );
+        settings.multi_hop = true;
+        assert_eq!(
+            settings.startup_action(),
+            Some(VpnAction::MultiHopConnect(
+                LocationSelection::Automatic,
+                LocationSelection::Automatic
+            ))
+        );
+    }
+
+    #[test]
+    fn connecting_last_resumes_only_a_connection_left_up() {
+        let mut settings = starting_with(StartupBehavior::ConnectLast);
+        assert_eq!(settings.startup_action(), None, "never connected");
+
+        settings.remember_connected("Germany, Berlin", Some("Norway, Oslo"));
+        assert_eq!(
+            settings.startup_action(),
+            Some(VpnAction::MultiHopConnect(
+                specific("Germany", "Berlin"),
+                specific("Norway", "Oslo")
+            ))
+        );
+
+        settings.remember_disconnected();
+        assert!(settings.last_connection.is_some());
+        assert_eq!(settings.startup_action(), None);
+
+        settings.set_startup_behavior(StartupBehavior::ConnectConfigured);
+        assert_eq!(settings.last_connection, None);
+    }
+
+    #[test]
+    fn remembered_connection_survives_a_settings_round_trip() {
+        let mut settings = starting_with(StartupBehavior::ConnectLast);
+        settings.remember_connected("Germany, Frankfurt", None);
+
+        let saved: SettingsState =
+            serde_json::from_str(&serde_json::to_string(&settings).unwrap()).unwrap();
+        assert_eq!(
+            saved.startup_action(),
+            Some(VpnAction::Connect(specific("Germany", "Frankfurt")))
+        );
+
+        let unreadable = LastConnection {
+            entry: "Frankfurt".to_string(),
+            exit: None,
+            connected: true,
+        };
+        let settings = SettingsState {
+            last_connection: Some(unreadable),
+            ..saved
+        };
+        assert_eq!(settings.startup_action(), None);
+    }
+
+    #[test]
+    fn saved_auto_connect_becomes_connect_configured() {
+        let mut json = serde_json::to_value(SettingsState::default()).unwrap();
+        let fields = json.as_object_mut().unwrap();
+        assert!(!fields.contains_key("auto_connect"), "never written back");
+        fields.remove("startup_behavior");
+        fields.insert("auto_connect".to_string(), true.into());
+
+        let settings = serde_json::from_value::<SettingsState>(json)
+            .unwrap()
+            .normalized();
+        assert_eq!(
+            settings.startup_behavior,
+            StartupBehavior::ConnectConfigured
+        );
+        assert!(!settings.legacy_auto_connect);
+    }
+
+    #[test]
+    fn split_tunneling_summary_counts_the_listed_apps() {
+        use super::{AppInfo, SplitTunnelMode};
+        let app = |path: &str| AppInfo {
+            name: path.to_string(),
+            path: path.to_string(),
+            icon: None,
+        };
+        let mut settings = SettingsState {
+            excluded_apps: vec![app("/usr/bin/steam")],
+            ..SettingsState::default()
+        };
+        assert_eq!(settings.split_tunneling_summary(), "Off");
+
+        settings.split_tunneling = true;
+        assert_eq!(settings.split_tunneling_summary(), "1 app excluded");
+        settings.excluded_apps.push(app("/usr/bin/discord"));
+        assert_eq!(settings.split_tunneling_summary(), "2 apps excluded");
+
+        settings.split_mode = SplitTunnelMode::IncludeOnly;
+        assert_eq!(settings.split_tunneling_summary(), "No apps listed");
+        settings.included_apps.push(app("/usr/bin/firefox"));
+        assert_eq!(settings.split_tunneling_summary(), "Only 1 app tunneled");
+    }
 }
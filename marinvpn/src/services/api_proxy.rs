@@ -0,0 +1,601 @@
+use crate::models::ApiProxy;
+use std::net::{IpAddr, SocketAddr};
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+/// Where a local Tor client listens for SOCKS connections by default.
+pub const TOR_SOCKS_ADDR: &str = "127.0.0.1:9050";
+
+/// Longest request head the bridge reads before giving up on a client.
+const MAX_HEAD: usize = 16 * 1024;
+
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum ProxyError {
+    #[error("Invalid proxy address {0}")]
+    InvalidAddress(String),
+
+    #[error("Proxy {addr} could not be reached: {message}")]
+    Unreachable { addr: String, message: String },
+
+    #[error("{addr} is not a SOCKS5 proxy")]
+    NotSocks { addr: String },
+
+    #[error("Proxy {addr} requires a login, which is not supported")]
+    AuthRequired { addr: String },
+
+    #[error("Proxy refused to connect to {target}: {reason}")]
+    Refused {
+        target: String,
+        reason: &'static str,
+    },
+}
+
+/// The HTTP clients for API calls and DoH lookups together with whatever
+/// keeps their proxy running.
+pub struct ApiTransport {
+    pub client: reqwest::Client,
+    /// For DoH lookups. They go to other hosts than the API, so this client
+    /// never carries the API host's ECH config.
+    pub lookup: reqwest::Client,
+    proxied: bool,
+    _bridge: Option<Socks5Bridge>,
+}
+
+impl ApiTransport {
+    /// Talks to the API directly.
+    pub fn direct(builder: reqwest::ClientBuilder, lookup: reqwest::ClientBuilder) -> Self {
+        let build = |builder: reqwest::ClientBuilder| {
+            builder
+                .build()
+                .expect("Failed to build secure reqwest client")
+        };
+        Self {
+            client: build(builder),
+            lookup: build(lookup),
+            proxied: false,
+            _bridge: None,
+        }
+    }
+
+    pub fn proxied(&self) -> bool {
+        self.proxied
+    }
+
+    /// Routes every request of `builder` and `lookup` through `proxy`.
+    /// SOCKS5 (and so Tor) goes through a local [`Socks5Bridge`], which
+    /// leaves name resolution to the proxy. Only the address is checked
+    /// here; see [`check`] for whether the proxy actually answers.
+    pub async fn new(
+        proxy: &ApiProxy,
+        builder: reqwest::ClientBuilder,
+        lookup: reqwest::ClientBuilder,
+    ) -> Result<Self, ProxyError> {
+        let (proxy_url, bridge) = match proxy {
+            ApiProxy::Direct => return Ok(Self::direct(builder, lookup)),
+            ApiProxy::Http(url) => (http_proxy_url(url)?, None),
+            ApiProxy::Socks5(_) | ApiProxy::Tor => {
+                let addr = socks_addr(proxy)?;
+                let bridge =
+                    Socks5Bridge::start(addr)
+                        .await
+                        .map_err(|e| ProxyError::Unreachable {
+                            addr: "local bridge".to_string(),
+                            message: e.to_string(),
+                        })?;
+                (format!("http://{}", bridge.addr()), Some(bridge))
+            }
+        };
+        let proxy = reqwest::Proxy::all(&proxy_url)
+            .map_err(|_| ProxyError::InvalidAddress(proxy_url.clone()))?;
+        let build = |builder: reqwest::ClientBuilder| {
+            builder
+                .proxy(proxy.clone())
+                .build()
+                .map_err(|e| ProxyError::InvalidAddress(e.to_string()))
+        };
+        Ok(Self {
+            client: build(builder)?,
+            lookup: build(lookup)?,
+            proxied: true,
+            _bridge: bridge,
+        })
+    }
+}
+
+fn http_proxy_url(url: &str) -> Result<String, ProxyError> {
+    let invalid = || ProxyError::InvalidAddress(url.to_string());
+    let parsed = reqwest::Url::parse(url.trim()).map_err(|_| invalid())?;
+    if !matches!(parsed.scheme(), "http" | "https") || parsed.host_str().is_none() {
+        return Err(invalid());
+    }
+    Ok(parsed.to_string())
+}
+
+fn socks_addr(proxy: &ApiProxy) -> Result<String, ProxyError> {
+    let addr = match proxy {
+        ApiProxy::Socks5(addr) => addr.trim(),
+        ApiProxy::Tor => TOR_SOCKS_ADDR,
+        _ => return Err(ProxyError::InvalidAddress(format!("{:?}", proxy))),
+    };
+    match split_host_port(addr) {
+        Some((host, _)) if !host.is_empty() => Ok(addr.to_string()),
+        _ => Err(ProxyError::InvalidAddress(addr.to_string())),
+    }
+}
+
+/// Confirms the configured proxy is there: a TCP connect for HTTP proxies,
+/// and the SOCKS5 greeting for SOCKS ones.
+pub async fn check(proxy: &ApiProxy) -> Result<(), ProxyError> {
+    match proxy {
+        ApiProxy::Direct => Ok(()),
+        ApiProxy::Http(url) => {
+            let parsed = reqwest::Url::parse(&http_proxy_url(url)?)
+                .map_err(|_| ProxyError::InvalidAddress(url.clone()))?;
+            let host = parsed.host_str().unwrap_or_default();
+            let port = parsed.port_or_known_default().unwrap_or(80);
+            let addr = format!("{}:{}", host, port);
+            connect(&addr).await.map(drop)
+        }
+        ApiProxy::Socks5(_) | ApiProxy::Tor => {
+            let addr = socks_addr(proxy)?;
+            let mut stream = connect(&addr).await?;
+            greet(&mut stream, &addr).await
+        }
+    }
+}
+
+async fn connect(addr: &str) -> Result<TcpStream, ProxyError> {
+    TcpStream::connect(addr)
+        .await
+        .map_err(|e| ProxyError::Unreachable {
+            addr: addr.to_string(),
+            message: e.to_string(),
+        })
+}
+
+/// Offers "no authentication", the only method supported.
+async fn greet(stream: &mut TcpStream, addr: &str) -> Result<(), ProxyError> {
+    let unreachable = |e: std::io::Error| ProxyError::Unreachable {
+        addr: addr.to_string(),
+        message: e.to_string(),
+    };
+    stream.write_all(&[5, 1, 0]).await.map_err(unreachable)?;
+    let mut reply = [0u8; 2];
+    stream
+        .read_exact(&mut reply)
+        .await
+        .map_err(|_| ProxyError::NotSocks {
+            addr: addr.to_string(),
+        })?;
+    match reply {
+        [5, 0] => Ok(()),
+        [5, _] => Err(ProxyError::AuthRequired {
+            addr: addr.to_string(),
+        }),
+        _ => Err(ProxyError::NotSocks {
+            addr: addr.to_string(),
+        }),
+    }
+}
+
+/// Opens a SOCKS5 tunnel to `host:port` through the proxy at `proxy_addr`.
+/// Host names are passed on unresolved, which Tor requires and which keeps
+/// the lookup off the local network.
+pub async fn socks5_connect(
+    proxy_addr: &str,
+    host: &str,
+    port: u16,
+) -> Result<TcpStream, ProxyError> {
+    let mut stream = connect(proxy_addr).await?;
+    greet(&mut stream, proxy_addr).await?;
+
+    let target = format!("{}:{}", host, port);
+    let mut request = vec![5, 1, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let name = u8::try_from(host.len())
+                .map_err(|_| ProxyError::InvalidAddress(host.to_string()))?;
+            request.push(3);
+            request.push(name);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+
+    let broken = |_| ProxyError::NotSocks {
+        addr: proxy_addr.to_string(),
+    };
+    stream.write_all(&request).await.map_err(broken)?;
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await.map_err(broken)?;
+    if head[0] != 5 {
+        return Err(ProxyError::NotSocks {
+            addr: proxy_addr.to_string(),
+        });
+    }
+    if head[1] != 0 {
+        return Err(ProxyError::Refused {
+            target,
+            reason: reply_reason(head[1]),
+        });
+    }
+    // The bound address is of no use here, but has to be read past.
+    let bound = match head[3] {
+        1 => 4,
+        4 => 16,
+        3 => stream.read_u8().await.map_err(broken)? as usize,
+        _ => {
+            return Err(ProxyError::NotSocks {
+                addr: proxy_addr.to_string(),
+            })
+        }
+    };
+    let mut rest = vec![0u8; bound + 2];
+    stream.read_exact(&mut rest).await.map_err(broken)?;
+    Ok(stream)
+}
+
+fn reply_reason(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "not allowed by the proxy's rules",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "timed out",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+/// A local HTTP proxy that forwards everything over SOCKS5, since the HTTP
+/// client only speaks to HTTP proxies. It handles `CONNECT` tunnels (HTTPS)
+/// and plain forwarded requests (an `http://` API in development). Stops
+/// when dropped.
+pub struct Socks5Bridge {
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl Socks5Bridge {
+    pub async fn start(socks_addr: String) -> std::io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let task = tokio::spawn(async move {
+            while let Ok((client, _)) = listener.accept().await {
+                let socks_addr = socks_addr.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = bridge(client, &socks_addr).await {
+                        tracing::warn!("API proxy: {}", e);
+                    }
+                });
+            }
+        });
+        Ok(Self { addr, task })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for Socks5Bridge {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn bridge(mut client: TcpStream, socks_addr: &str) -> Result<(), ProxyError> {
+    let Some((head, rest)) = read_head(&mut client).await else {
+        return Ok(());
+    };
+    let mut parts = head.lines().next().unwrap_or_default().split_whitespace();
+    let (method, target, version) = (
+        parts.next().unwrap_or_default(),
+        parts.next().unwrap_or_default(),
+        parts.next().unwrap_or("HTTP/1.1"),
+    );
+
+    let forward = if method.eq_ignore_ascii_case("CONNECT") {
+        split_host_port(target).map(|(host, port)| (host, port, None))
+    } else {
+        target.strip_prefix("http://").map(|absolute| {
+            let (authority, path) = match absolute.find('/') {
+                Some(i) => absolute.split_at(i),
+                None => (absolute, "/"),
+            };
+            let (host, port) = split_host_port(authority).unwrap_or((authority.to_string(), 80));
+            // Origin-form request line, then the headers as they came.
+            let headers = head.split_once("\r\n").map_or("\r\n", |(_, h)| h);
+            let head = format!("{} {} {}\r\n{}", method, path, version, headers);
+            (host, port, Some(head))
+        })
+    };
+    let Some((host, port, forwarded_head)) = forward else {
+        let _ = client
+            .write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")
+            .await;
+        return Err(ProxyError::InvalidAddress(target.to_string()));
+    };
+
+    let mut upstream = match socks5_connect(socks_addr, &host, port).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            let _ = client
+                .write_all(b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n")
+                .await;
+            return Err(e);
+        }
+    };
+    let io = |e: std::io::Error| ProxyError::Unreachable {
+        addr: socks_addr.to_string(),
+        message: e.to_string(),
+    };
+    match forwarded_head {
+        Some(head) => upstream.write_all(head.as_bytes()).await.map_err(io)?,
+        None => client
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await
+            .map_err(io)?,
+    }
+    upstream.write_all(&rest).await.map_err(io)?;
+    let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+    Ok(())
+}
+
+/// Reads up to the blank line ending the request head. Returns the head
+/// (with its final CRLF pair) and whatever body bytes came along with it.
+async fn read_head(stream: &mut TcpStream) -> Option<(String, Vec<u8>)> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 2048];
+    loop {
+        let n = stream.read(&mut chunk).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let rest = buf.split_off(end + 4);
+            return Some((String::from_utf8(buf).ok()?, rest));
+        }
+        if buf.len() > MAX_HEAD {
+            return None;
+        }
+    }
+}
+
+/// `host:port` or `[v6]:port`.
+fn split_host_port(authority: &str) -> Option<(String, u16)> {
+    let (host, port) = authority.rsplit_once(':')?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    Some((host.to_string(), port.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    const API: &str = "api.marinvpn.example";
+
+    /// Replies `body` to one plain HTTP request and keeps its head.
+    async fn http_server(body: &'static str) -> (SocketAddr, Arc<Mutex<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let seen = Arc::new(Mutex::new(String::new()));
+        let record = seen.clone();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let (head, _) = read_head(&mut socket).await.unwrap();
+            *record.lock().unwrap() = head;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+        });
+        (addr, seen)
+    }
+
+    /// A SOCKS5 server that answers CONNECT with `reply` and, on success,
+    /// relays to `backend` whatever the requested target was.
+    async fn socks_server(
+        reply: u8,
+        backend: Option<SocketAddr>,
+    ) -> (String, Arc<Mutex<Vec<(String, u16)>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let targets = Arc::new(Mutex::new(Vec::new()));
+        let record = targets.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let record = record.clone();
+                tokio::spawn(async move {
+                    let mut greeting = [0u8; 3];
+                    socket.read_exact(&mut greeting).await.unwrap();
+                    socket.write_all(&[5, 0]).await.unwrap();
+                    let mut head = [0u8; 4];
+                    if socket.read_exact(&mut head).await.is_err() {
+                        return;
+                    }
+                    assert_eq!(head[3], 3, "names must reach the proxy unresolved");
+                    let len = socket.read_u8().await.unwrap() as usize;
+                    let mut name = vec![0u8; len];
+                    socket.read_exact(&mut name).await.unwrap();
+                    let port = socket.read_u16().await.unwrap();
+                    record
+                        .lock()
+                        .unwrap()
+                        .push((String::from_utf8(name).unwrap(), port));
+                    socket
+                        .write_all(&[5, reply, 0, 1, 0, 0, 0, 0, 0, 0])
+                        .await
+                        .unwrap();
+                    if let (0, Some(backend)) = (reply, backend) {
+                        let mut upstream = TcpStream::connect(backend).await.unwrap();
+                        let _ = tokio::io::copy_bidirectional(&mut socket, &mut upstream).await;
+                    }
+                });
+            }
+        });
+        (addr, targets)
+    }
+
+    fn closed_port() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn http_proxy_is_applied_to_the_client() {
+        let (proxy, seen) = http_server("via proxy").await;
+        let transport = ApiTransport::new(
+            &ApiProxy::Http(format!("http://{}", proxy)),
+            reqwest::Client::builder(),
+            reqwest::Client::builder(),
+        )
+        .await
+        .unwrap();
+        assert!(transport.proxied());
+
+        let url = format!("http://{}/api/v1/ping", API);
+        let body = transport.client.get(&url).send().await.unwrap();
+        assert_eq!(body.text().await.unwrap(), "via proxy");
+        assert!(seen.lock().unwrap().starts_with(&format!("GET {} ", url)));
+    }
+
+    #[tokio::test]
+    async fn requests_route_through_the_socks_server() {
+        let (backend, seen) = http_server("hello from the api").await;
+        let (socks, targets) = socks_server(0, Some(backend)).await;
+        let transport = ApiTransport::new(
+            &ApiProxy::Socks5(socks),
+            reqwest::Client::builder(),
+            reqwest::Client::builder(),
+        )
+        .await
+        .unwrap();
+
+        let res = transport
+            .client
+            .get(format!("http://{}:8080/api/v1/servers", API))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.text().await.unwrap(), "hello from the api");
+        assert_eq!(*targets.lock().unwrap(), [(API.to_string(), 8080)]);
+        // The API sees an ordinary origin-form request.
+        assert!(seen
+            .lock()
+            .unwrap()
+            .starts_with("GET /api/v1/servers HTTP/1.1\r\n"));
+    }
+
+    #[tokio::test]
+    async fn connect_tunnels_pass_bytes_both_ways() {
+        let (backend, seen) = http_server("tunnelled").await;
+        let (socks, targets) = socks_server(0, Some(backend)).await;
+        let bridge = Socks5Bridge::start(socks).await.unwrap();
+
+        let mut client = TcpStream::connect(bridge.addr()).await.unwrap();
+        let connect = format!("CONNECT {}:443 HTTP/1.1\r\nHost: {}:443\r\n\r\n", API, API);
+        client.write_all(connect.as_bytes()).await.unwrap();
+        let (reply, _) = read_head(&mut client).await.unwrap();
+        assert!(reply.starts_with("HTTP/1.1 200"));
+
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.ends_with("tunnelled"));
+        assert_eq!(*targets.lock().unwrap(), [(API.to_string(), 443)]);
+        assert_eq!(*seen.lock().unwrap(), "GET / HTTP/1.1\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn proxy_failures_are_reported_by_cause() {
+        let (socks, _) = socks_server(5, None).await;
+        assert_eq!(
+            socks5_connect(&socks, API, 443).await.unwrap_err(),
+            ProxyError::Refused {
+                target: format!("{}:443", API),
+                reason: "connection refused",
+            }
+        );
+        // Through the bridge the client just sees a failed tunnel.
+        let bridge = Socks5Bridge::start(socks).await.unwrap();
+        let mut client = TcpStream::connect(bridge.addr()).await.unwrap();
+        client
+            .write_all(format!("CONNECT {}:443 HTTP/1.1\r\n\r\n", API).as_bytes())
+            .await
+            .unwrap();
+        let (reply, _) = read_head(&mut client).await.unwrap();
+        assert!(reply.starts_with("HTTP/1.1 502"));
+
+        let gone = closed_port();
+        assert!(matches!(
+            check(&ApiProxy::Socks5(gone.clone())).await,
+            Err(ProxyError::Unreachable { addr, .. }) if addr == gone
+        ));
+
+        // A web server answering the greeting with its own protocol.
+        let web = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let web_addr = web.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut socket, _) = web.accept().await.unwrap();
+            let _ = socket.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await;
+        });
+        assert!(matches!(
+            check(&ApiProxy::Socks5(web_addr)).await,
+            Err(ProxyError::NotSocks { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn socks_servers_demanding_a_login_are_named() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            socket.read_exact(&mut greeting).await.unwrap();
+            socket.write_all(&[5, 0xff]).await.unwrap();
+        });
+        assert_eq!(
+            check(&ApiProxy::Socks5(addr.clone())).await,
+            Err(ProxyError::AuthRequired { addr })
+        );
+    }
+
+    #[tokio::test]
+    async fn malformed_addresses_are_rejected_up_front() {
+        for proxy in [
+            ApiProxy::Http("not a url".to_string()),
+            ApiProxy::Http("socks5://127.0.0.1:1080".to_string()),
+            ApiProxy::Socks5("127.0.0.1".to_string()),
+            ApiProxy::Socks5(":1080".to_string()),
+        ] {
+            assert!(matches!(
+                ApiTransport::new(
+                    &proxy,
+                    reqwest::Client::builder(),
+                    reqwest::Client::builder()
+                )
+                .await,
+                Err(ProxyError::InvalidAddress(_))
+            ));
+        }
+        assert_eq!(socks_addr(&ApiProxy::Tor).unwrap(), TOR_SOCKS_ADDR);
+    }
+}
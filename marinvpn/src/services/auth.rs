@@ -1,11 +1,27 @@
 use crate::error::AppError;
+use crate::services::api_proxy::{self, ApiTransport, ProxyError};
+use crate::services::connect_timing::{timed, Phase};
+use crate::services::doh::{self, DohError, ResolvedHost};
+use crate::services::ech::{self, EchPlan};
+use crate::services::key_cache::{PublicKeyCache, PUBLIC_KEY_TTL};
+use crate::services::report_crypto::{encrypt_report, ReportEncryption};
+use crate::services::request_log;
+use crate::services::wg_conf::validate_server_config;
+use crate::services::wg_keys::KeyPairCache;
 use crate::models::{
-    ConfigRequest, Device, GenerateResponse, LoginRequest, LoginResponse, RefreshRequest,
-    RefreshResponse, RemoveDeviceRequest, ReportRequest, WireGuardConfig,
+    Account, AccountPanicRequest, AccountPanicResponse, ApiProxy, ConfigRequest, Device, DohProvider, GenerateResponse, KeyReuse,
+    LoginRequest, LoginResponse, LogoutResponse, PowChallenge, SessionsResponse, PowSolution, RefreshRequest, RefreshResponse,
+    RemoveDeviceReason, RemoveDeviceRequest, RemoveDeviceResponse, ReportOs, ReportRequest,
+    ReportResponse, ServerSettings, ServerVersion, WireGuardConfig,
 };
 use base64::{prelude::BASE64_STANDARD, Engine};
 use blake2::{Blake2s, Digest as BlakeDigest};
 use boringtun::x25519::{PublicKey, StaticSecret};
+use marinvpn_common::attestation::{
+    body_hash_hex, header_value, signing_message, ATTESTATION_BODY_HEADER, ATTESTATION_HEADER,
+    ATTESTATION_PUB_HEADER, DEVICE_KEY_MISMATCH_CODE,
+};
+use marinvpn_common::version::{Version, CLIENT_VERSION_HEADER};
 use marinvpn_common::{AnonymousConfigRequest, BlindTokenRequest, BlindTokenResponse};
 use ml_kem::kem::Decapsulate;
 use ml_kem::{EncodedSizeUser, KemCore, MlKem768};
@@ -18,12 +34,20 @@ use ring::rand::SystemRandom;
 use ring::signature::{Ed25519KeyPair, KeyPair};
 use rsa::traits::PublicKeyParts;
 use rsa::{pkcs8::DecodePublicKey, BigUint, RsaPublicKey};
-use serde::Serialize;
-use sha2::{Digest, Sha256};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
 
 pub struct AuthService;
 
-static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+static BLIND_KEY: PublicKeyCache = PublicKeyCache::new(PUBLIC_KEY_TTL);
+static SUPPORT_KEY: PublicKeyCache = PublicKeyCache::new(PUBLIC_KEY_TTL);
+static WG_KEYS: KeyPairCache = KeyPairCache::new();
+static DEVICE_KEY_REPLACED: AtomicBool = AtomicBool::new(false);
+static REBIND_ATTEMPTED: AtomicBool = AtomicBool::new(false);
+
+fn client_builder() -> reqwest::ClientBuilder {
     let mut headers = reqwest::header::HeaderMap::new();
     headers.insert(
         "User-Agent",
@@ -34,9 +58,55 @@ static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
         .default_headers(headers)
         .timeout(std::time::Duration::from_secs(10))
         .tcp_keepalive(std::time::Duration::from_secs(60))
-        .build()
-        .expect("Failed to build secure reqwest client")
-});
+}
+
+/// TLS settings carrying the API host's ECH config, while one is in use.
+static ECH_TLS: RwLock<Option<rustls::ClientConfig>> = RwLock::new(None);
+/// The proxy `TRANSPORT` was last built for.
+static API_PROXY: RwLock<ApiProxy> = RwLock::new(ApiProxy::Direct);
+
+static TRANSPORT: Lazy<RwLock<ApiTransport>> =
+    Lazy::new(|| RwLock::new(ApiTransport::direct(api_client_builder(), client_builder())));
+
+fn api_client_builder() -> reqwest::ClientBuilder {
+    match ECH_TLS.read().unwrap().clone() {
+        Some(tls) => client_builder().use_preconfigured_tls(tls),
+        None => client_builder(),
+    }
+}
+
+async fn install_transport(proxy: &ApiProxy) -> Result<(), ProxyError> {
+    let transport = ApiTransport::new(proxy, api_client_builder(), client_builder()).await?;
+    *TRANSPORT.write().unwrap() = transport;
+    *API_PROXY.write().unwrap() = proxy.clone();
+    Ok(())
+}
+
+/// The API client, routed through `settings.api_proxy`.
+pub(crate) fn client() -> reqwest::Client {
+    TRANSPORT.read().unwrap().client.clone()
+}
+
+/// The DoH client, routed like [`client`] but without ECH.
+fn lookup_client() -> reqwest::Client {
+    TRANSPORT.read().unwrap().lookup.clone()
+}
+
+/// Stops sending an Encrypted Client Hello after the API host turned one
+/// down, which it does once the config looked up over DoH is rotated out.
+/// API requests then share the DoH client, which never had ECH, until the
+/// next lookup. Returns that client.
+pub(crate) fn drop_rejected_ech() -> reqwest::Client {
+    *ECH_TLS.write().unwrap() = None;
+    let mut transport = TRANSPORT.write().unwrap();
+    transport.client = transport.lookup.clone();
+    transport.client.clone()
+}
+
+/// Whether API requests currently go through `settings.api_proxy`.
+pub fn api_proxy_in_use() -> bool {
+    TRANSPORT.read().unwrap().proxied()
+}
 
 fn api_base() -> Result<String, AppError> {
     let base = std::env::var("MARIN_API_URL");
@@ -67,6 +137,20 @@ fn api_url(path: &str) -> Result<String, AppError> {
     Ok(format!("{}/{}", base, path))
 }
 
+/// Whether `own` is older than the oldest client a server still supports.
+/// A minimum that can't be read is not held against the client.
+fn older_than(own: &str, min_client_version: &str) -> bool {
+    match (Version::parse(own), Version::parse(min_client_version)) {
+        (Some(own), Some(min)) => own < min,
+        _ => false,
+    }
+}
+
+/// Whether the server no longer supports this build of the app.
+pub fn client_too_old(server: &ServerVersion) -> bool {
+    older_than(env!("CARGO_PKG_VERSION"), &server.min_client_version)
+}
+
 fn is_production() -> bool {
     let run_mode = std::env::var("RUN_MODE").unwrap_or_else(|_| "development".to_string());
     let app_env = std::env::var("APP_ENV").unwrap_or_else(|_| "".to_string());
@@ -75,20 +159,56 @@ fn is_production() -> bool {
 }
 
 fn device_keypair() -> Result<Ed25519KeyPair, AppError> {
-    if let Some(encoded) = crate::storage::load_device_attestation_key() {
-        let raw = BASE64_STANDARD
-            .decode(encoded)
-            .map_err(|_| AppError::Crypto("Invalid device attestation key in storage".to_string()))?;
-        return Ed25519KeyPair::from_pkcs8(raw.as_slice())
-            .map_err(|_| AppError::Crypto("Invalid device attestation key in storage".to_string()));
+    let stored = crate::storage::load_device_attestation_key();
+    let (key, replaced) = load_or_create_device_key(
+        stored.as_deref(),
+        crate::storage::save_device_attestation_key,
+    )?;
+    if replaced {
+        tracing::warn!(
+            "Stored device attestation key could not be loaded; generated a new one. \
+             The device has to log in again to bind it."
+        );
+        // Both tokens are bound to the old key and would only be refused.
+        let _ = crate::storage::update_auth_tokens(None, None);
+        DEVICE_KEY_REPLACED.store(true, Ordering::Relaxed);
     }
+    Ok(key)
+}
+
+/// The stored attestation key, or a new one passed to `save` when there is
+/// none or the stored one is unreadable. The flag is set in the second case.
+fn load_or_create_device_key(
+    stored: Option<&str>,
+    save: impl FnOnce(&str),
+) -> Result<(Ed25519KeyPair, bool), AppError> {
+    let replaced = match stored {
+        None => false,
+        Some(encoded) => {
+            let key = BASE64_STANDARD
+                .decode(encoded)
+                .ok()
+                .and_then(|raw| Ed25519KeyPair::from_pkcs8(&raw).ok());
+            match key {
+                Some(key) => return Ok((key, false)),
+                None => true,
+            }
+        }
+    };
 
     let rng = SystemRandom::new();
     let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng)
         .map_err(|_| AppError::Crypto("Failed to generate device attestation key".to_string()))?;
-    crate::storage::save_device_attestation_key(&BASE64_STANDARD.encode(pkcs8.as_ref()));
-    Ed25519KeyPair::from_pkcs8(pkcs8.as_ref())
-        .map_err(|_| AppError::Crypto("Failed to load generated device attestation key".to_string()))
+    save(&BASE64_STANDARD.encode(pkcs8.as_ref()));
+    let key = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref())
+        .map_err(|_| AppError::Crypto("Failed to load generated device attestation key".to_string()))?;
+    Ok((key, replaced))
+}
+
+/// Whether this run replaced a corrupt device attestation key and the device
+/// still has to log in again to bind the new one.
+pub fn device_key_replaced() -> bool {
+    DEVICE_KEY_REPLACED.load(Ordering::Relaxed)
 }
 
 fn device_pubkey_b64() -> Result<String, AppError> {
@@ -96,21 +216,13 @@ fn device_pubkey_b64() -> Result<String, AppError> {
     Ok(BASE64_STANDARD.encode(key.public_key().as_ref()))
 }
 
-fn body_hash_hex(bytes: &[u8]) -> String {
-    let hash = Sha256::digest(bytes);
-    hex::encode(hash)
-}
-
 fn request_with_attestation(
     method: &str,
     path: &str,
     body: Option<Vec<u8>>,
 ) -> Result<reqwest::RequestBuilder, AppError> {
     let url = api_url(path)?;
-    let hash = match body.as_ref() {
-        Some(bytes) => body_hash_hex(bytes),
-        None => body_hash_hex(&[]),
-    };
+    let hash = body_hash_hex(body.as_deref().unwrap_or_default());
 
     let timestamp = chrono::Utc::now().timestamp().to_string();
     let nonce: String = {
@@ -120,8 +232,8 @@ fn request_with_attestation(
     };
 
     let mut rb = match method {
-        "GET" => CLIENT.get(url),
-        "POST" => CLIENT.post(url),
+        "GET" => client().get(url),
+        "POST" => client().post(url),
         _ => return Err(AppError::Config(format!("Unsupported HTTP method: {}", method))),
     };
 
@@ -131,7 +243,7 @@ fn request_with_attestation(
             .body(bytes);
     }
 
-    let message = format!("{}:{}:{}:{}:{}", timestamp, nonce, method, path, hash);
+    let message = signing_message(&timestamp, &nonce, method, path, &hash);
     let key = device_keypair()?;
     let signature = key.sign(message.as_bytes());
     let signature_b64 = BASE64_STANDARD.encode(signature.as_ref());
@@ -139,17 +251,267 @@ fn request_with_attestation(
 
     Ok(rb
         .header(
-            "X-Marin-Attestation",
-            format!("{}:{}:{}", timestamp, nonce, signature_b64),
+            ATTESTATION_HEADER,
+            header_value(&timestamp, &nonce, &signature_b64),
         )
-        .header("X-Marin-Attestation-Body", hash)
-        .header("X-Marin-Attestation-Pub", pubkey_b64))
+        .header(ATTESTATION_BODY_HEADER, hash)
+        .header(ATTESTATION_PUB_HEADER, pubkey_b64)
+        .header(CLIENT_VERSION_HEADER, env!("CARGO_PKG_VERSION")))
+}
+
+/// Validates a PEM public key response before handing it to the RSA parser.
+/// A captive portal answers our HTTPS request with its own login page, which
+/// would otherwise surface as an opaque PEM decoding error.
+fn parse_public_key_response(
+    what: &str,
+    status: StatusCode,
+    content_type: Option<&str>,
+    body: &str,
+) -> Result<RsaPublicKey, AppError> {
+    if status == StatusCode::NETWORK_AUTHENTICATION_REQUIRED {
+        return Err(AppError::CaptivePortal);
+    }
+    if !status.is_success() {
+        return Err(AppError::Api {
+            status,
+            message: format!("Failed to fetch {}", what),
+        });
+    }
+
+    let mime = content_type
+        .and_then(|ct| ct.split(';').next())
+        .map(|ct| ct.trim().to_ascii_lowercase());
+    let looks_like_html = body.trim_start().starts_with('<');
+    if mime.as_deref() == Some("text/html") || looks_like_html {
+        return Err(AppError::CaptivePortal);
+    }
+    if let Some(mime) = mime {
+        if !matches!(
+            mime.as_str(),
+            "text/plain" | "application/x-pem-file" | "application/octet-stream"
+        ) {
+            return Err(AppError::Api {
+                status,
+                message: format!("Unexpected content type {} for {}", mime, what),
+            });
+        }
+    }
+
+    RsaPublicKey::from_public_key_pem(body.trim())
+        .map_err(|e| AppError::Crypto(format!("Invalid {}: {}", what, e)))
+}
+
+/// Servers before the structured responses answered device removal and
+/// problem reports with a bare `true`/`false`; both shapes are accepted.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Acknowledgement<T> {
+    Structured(T),
+    Legacy(bool),
+}
+
+fn parse_remove_device_response(body: &str) -> Result<RemoveDeviceResponse, AppError> {
+    Ok(match serde_json::from_str(body)? {
+        Acknowledgement::Structured(response) => response,
+        Acknowledgement::Legacy(true) => RemoveDeviceResponse::removed(),
+        // The old server only returned false when no row matched.
+        Acknowledgement::Legacy(false) => {
+            RemoveDeviceResponse::not_removed(RemoveDeviceReason::NotFound)
+        }
+    })
+}
+
+fn parse_report_response(body: &str) -> Result<ReportResponse, AppError> {
+    Ok(match serde_json::from_str(body)? {
+        Acknowledgement::Structured(response) => response,
+        Acknowledgement::Legacy(accepted) => ReportResponse {
+            accepted,
+            reference: None,
+        },
+    })
+}
+
+async fn fetch_public_key(path: &str, what: &str) -> Result<RsaPublicKey, AppError> {
+    let res = request_log::send(request_with_attestation("GET", path, None)?).await?;
+    let status = res.status();
+    let content_type = res
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let body = res.text().await.map_err(AppError::Network)?;
+    parse_public_key_response(what, status, content_type.as_deref(), &body)
 }
 
 fn json_body<T: Serialize>(payload: &T) -> Result<Vec<u8>, AppError> {
     serde_json::to_vec(payload).map_err(|e| AppError::Serialization(e))
 }
 
+/// Body of `/api/v1/vpn/config-anonymous`. Of the user's settings only
+/// `settings` goes in.
+fn anonymous_config_request(
+    location: &str,
+    pub_key: String,
+    pqc_public_key: Option<String>,
+    settings: &ServerSettings,
+    message: String,
+    signature: String,
+    pow: Option<PowSolution>,
+) -> AnonymousConfigRequest {
+    AnonymousConfigRequest {
+        message,
+        signature,
+        location: location.to_string(),
+        pub_key,
+        dns_blocking: settings.dns_blocking.clone(),
+        quantum_resistant: settings.quantum_resistant,
+        pqc_public_key,
+        pow,
+    }
+}
+
+/// Body of `/api/v1/vpn/config`, the same as the anonymous one but tied to
+/// the account instead of a blind token.
+fn account_config_request(
+    account_number: &str,
+    location: &str,
+    pub_key: String,
+    pqc_public_key: Option<String>,
+    settings: &ServerSettings,
+) -> ConfigRequest {
+    ConfigRequest {
+        account_number: account_number.to_string(),
+        location: location.to_string(),
+        pub_key,
+        dns_blocking: settings.dns_blocking.clone(),
+        quantum_resistant: settings.quantum_resistant,
+        pqc_public_key,
+    }
+}
+
+/// Longest `Retry-After` a request waits out before trying again. A longer
+/// wait is passed back to the caller as the 429 it is.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+/// How long a 429 asks to wait, if it says and the wait is short enough to
+/// sit out.
+fn retry_after(res: &reqwest::Response) -> Option<Duration> {
+    if res.status() != StatusCode::TOO_MANY_REQUESTS {
+        return None;
+    }
+    let secs = res
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(Duration::from_secs(secs)).filter(|wait| *wait <= MAX_RETRY_AFTER)
+}
+
+/// What to do about a 401 before retrying the request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Recovery {
+    /// The access token expired; the refresh token gets new ones.
+    Refresh,
+    /// The server holds another key for this device, e.g. after a
+    /// reinstall, so no token this key signs for is accepted until it is
+    /// registered by logging in again.
+    Rebind,
+}
+
+/// The `code` field of an API error body.
+pub(crate) fn error_code(body: &str) -> Option<String> {
+    serde_json::from_str::<serde_json::Value>(body)
+        .ok()
+        .and_then(|body| body.get("code")?.as_str().map(str::to_string))
+}
+
+impl Recovery {
+    fn for_rejection(body: &str) -> Self {
+        match error_code(body).as_deref() {
+            Some(DEVICE_KEY_MISMATCH_CODE) => Self::Rebind,
+            _ => Self::Refresh,
+        }
+    }
+}
+
+/// What a login attempt came to. The server answers some refusals in the
+/// body of a 200 and others with an error status; both end up here.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoginOutcome {
+    Success(LoginSession),
+    /// The account is at its device limit and one of `devices` has to be
+    /// removed first.
+    DeviceLimit {
+        devices: Vec<Device>,
+        message: Option<String>,
+    },
+    Expired,
+    /// No account has this number.
+    Invalid,
+    RateLimited,
+    /// Refused for another reason, such as too many sessions on this device.
+    Refused(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoginSession {
+    pub account: Account,
+    pub device_name: String,
+    pub auth_token: String,
+    pub refresh_token: String,
+}
+
+impl LoginOutcome {
+    /// Reads the server's answer to a login. Errors that are not a verdict
+    /// on the login, such as a network failure, are passed back.
+    pub fn from_result(result: Result<LoginResponse, AppError>) -> Result<Self, AppError> {
+        let resp = match result {
+            Ok(resp) => resp,
+            Err(AppError::Api { status, message }) => {
+                return match error_code(&message).as_deref() {
+                    Some("account_not_found") => Ok(Self::Invalid),
+                    Some("account_expired") => Ok(Self::Expired),
+                    Some("rate_limited") => Ok(Self::RateLimited),
+                    _ if status == StatusCode::TOO_MANY_REQUESTS => Ok(Self::RateLimited),
+                    _ => Err(AppError::Api { status, message }),
+                };
+            }
+            Err(e) => return Err(e),
+        };
+
+        if resp.success {
+            return match (
+                resp.account_info,
+                resp.current_device,
+                resp.auth_token,
+                resp.refresh_token,
+            ) {
+                (Some(account), Some(device_name), Some(auth_token), Some(refresh_token)) => {
+                    Ok(Self::Success(LoginSession {
+                        account,
+                        device_name,
+                        auth_token,
+                        refresh_token,
+                    }))
+                }
+                _ => Err(AppError::Auth("Invalid login response".to_string())),
+            };
+        }
+        match resp.devices {
+            Some(devices) => Ok(Self::DeviceLimit {
+                devices,
+                message: resp.error,
+            }),
+            None => Ok(Self::Refused(
+                resp.error.unwrap_or_else(|| "Login failed".to_string()),
+            )),
+        }
+    }
+}
+
 impl AuthService {
     async fn send_authed_with_refresh<F>(
         token: &str,
@@ -158,19 +520,76 @@ impl AuthService {
     where
         F: Fn(&str) -> Result<reqwest::RequestBuilder, AppError>,
     {
-        let res = make_req(token)?.send().await?;
+        Self::send_authed_with(token, make_req, |recovery| async move {
+            match recovery {
+                Recovery::Refresh => Self::refresh_session().await,
+                Recovery::Rebind => Self::rebind_device().await,
+            }
+        })
+        .await
+    }
+
+    /// Sends the request built by `make_req`. On a 401 it lets `recover`
+    /// fetch a new access token for the kind of rejection it was and sends
+    /// the request once more with that. Each send is throttled as in
+    /// [`Self::send_throttled`].
+    async fn send_authed_with<F, R, Fut>(
+        token: &str,
+        make_req: F,
+        recover: R,
+    ) -> Result<reqwest::Response, AppError>
+    where
+        F: Fn(&str) -> Result<reqwest::RequestBuilder, AppError>,
+        R: FnOnce(Recovery) -> Fut,
+        Fut: std::future::Future<Output = Result<String, AppError>>,
+    {
+        let res = Self::send_throttled(|| make_req(token)).await?;
 
         if res.status() != StatusCode::UNAUTHORIZED {
             return Ok(res);
         }
 
+        let body = res.text().await.unwrap_or_default();
+        let token = recover(Recovery::for_rejection(&body)).await?;
+
+        Self::send_throttled(|| make_req(&token)).await
+    }
+
+    /// Sends the request built by `make_req`, and once more after the wait
+    /// a 429 asks for. The request is built again so that it carries a
+    /// fresh attestation nonce.
+    async fn send_throttled<F>(make_req: F) -> Result<reqwest::Response, AppError>
+    where
+        F: Fn() -> Result<reqwest::RequestBuilder, AppError>,
+    {
+        let res = request_log::send(make_req()?).await?;
+        let Some(wait) = retry_after(&res) else {
+            return Ok(res);
+        };
+        tracing::info!("Rate limited, retrying in {}s", wait.as_secs());
+        tokio::time::sleep(wait).await;
+        request_log::send(make_req()?).await.map_err(AppError::from)
+    }
+
+    /// Trades the saved refresh token for new tokens and saves them.
+    async fn refresh_session() -> Result<String, AppError> {
         let refresh = crate::storage::load_config().refresh_token;
         let Some(refresh_token) = refresh else {
+            if device_key_replaced() {
+                return Err(AppError::DeviceKeyReplaced);
+            }
             return Err(AppError::SessionExpired);
         };
 
         let refreshed = match Self::refresh_auth(&refresh_token).await {
             Ok(tokens) => tokens,
+            // The refresh token is bound to the old key as well.
+            Err(AppError::Api { status, message })
+                if status == StatusCode::UNAUTHORIZED
+                    && Recovery::for_rejection(&message) == Recovery::Rebind =>
+            {
+                return Self::rebind_device().await;
+            }
             Err(err) => {
                 let _ = crate::storage::update_auth_tokens(None, None);
                 return Err(err);
@@ -180,55 +599,237 @@ impl AuthService {
             Some(refreshed.auth_token.clone()),
             Some(refreshed.refresh_token.clone()),
         );
-
-        make_req(&refreshed.auth_token)?.send().await.map_err(AppError::from)
+        Ok(refreshed.auth_token)
     }
 
-    pub async fn secure_resolve(hostname: &str) -> Option<String> {
-        let doh_url = "https://cloudflare-dns.com/dns-query";
-
-        let rb = CLIENT
-            .get(doh_url)
-            .header("Accept", "application/dns-json")
-            .query(&[("name", hostname), ("type", "A")]);
+    /// Logs in again with the saved account number, which registers this
+    /// device's current key in place of the one the server has. Tried once
+    /// per run; if that does not work, the user is asked to log in.
+    async fn rebind_device() -> Result<String, AppError> {
+        let config = crate::storage::load_config();
+        let rebound = match config.account_number.as_deref() {
+            Some(account) if !REBIND_ATTEMPTED.swap(true, Ordering::Relaxed) => {
+                tracing::warn!("Server holds another key for this device; logging in again");
+                Self::send_login(account, None).await
+            }
+            _ => Err(AppError::DeviceKeyMismatch),
+        };
 
-        let res = rb.send().await.ok()?;
-        if !res.status().is_success() {
-            return None;
+        match rebound {
+            Ok(LoginResponse {
+                success: true,
+                auth_token: Some(auth_token),
+                refresh_token,
+                current_device,
+                ..
+            }) => {
+                let _ = crate::storage::save_auth_info(
+                    config.account_number,
+                    Some(auth_token.clone()),
+                    refresh_token,
+                    config.account_expiry,
+                    current_device.or(config.device_name),
+                );
+                DEVICE_KEY_REPLACED.store(false, Ordering::Relaxed);
+                Ok(auth_token)
+            }
+            other => {
+                if let Err(e) = other {
+                    tracing::warn!("Could not register the new device key: {}", e);
+                }
+                let _ = crate::storage::update_auth_tokens(None, None);
+                Err(AppError::DeviceKeyMismatch)
+            }
         }
+    }
 
-        let json: serde_json::Value = res.json().await.ok()?;
-        let answers = json.get("Answer")?.as_array()?;
+    /// Sends API traffic through `proxy` from now on. Attestation signs the
+    /// method, path and body but never the host, so the server accepts
+    /// requests whichever way they arrive. An invalid address leaves the
+    /// current route in place; a proxy that is set but not answering is
+    /// still used, so requests fail rather than quietly going direct.
+    pub async fn set_api_proxy(proxy: &ApiProxy) -> Result<(), ProxyError> {
+        install_transport(proxy).await?;
+        api_proxy::check(proxy).await
+    }
 
-        for answer in answers {
-            if let Some(data) = answer.get("data").and_then(|d| d.as_str()) {
-                if data.split('.').count() == 4 {
-                    tracing::info!("DoH: Resolved {} to {}", hostname, data);
-                    return Some(data.to_string());
-                }
-            }
+    /// Looks up the IPv4 and IPv6 addresses of `hostname` over
+    /// DNS-over-HTTPS, moving to the next of `providers` (what
+    /// `doh::active_providers` picks from the settings) when one is blocked,
+    /// broken or slow.
+    pub async fn secure_resolve(
+        hostname: &str,
+        providers: &[DohProvider],
+    ) -> Result<ResolvedHost, DohError> {
+        doh::resolve_with(
+            providers,
+            hostname,
+            |provider| async move { doh::query_host(&lookup_client(), &provider, hostname).await },
+            |e| tracing::warn!("DoH blocked ({}), trying alternate", e),
+        )
+        .await
+        .inspect(|host| {
+            tracing::info!(
+                "DoH: Resolved {} to {:?} and {:?}",
+                hostname,
+                host.v4,
+                host.v6
+            )
+        })
+    }
+
+    /// Hides the API hostname with Encrypted Client Hello from now on when
+    /// `enabled` and the host publishes an ECH config, looked up through
+    /// `providers`. Otherwise, or when the lookup fails or the config cannot
+    /// be used, API requests go out with a normal handshake.
+    pub async fn set_encrypted_client_hello(enabled: bool, providers: &[DohProvider]) {
+        let tls = match Self::ech_plan(enabled, providers).await {
+            EchPlan::Use(list) => ech::tls_config(&list)
+                .inspect_err(|e| tracing::warn!("API host's ECH config is unusable: {}", e))
+                .ok(),
+            EchPlan::Disabled | EchPlan::NotOffered => None,
+        };
+        if tls.is_none() && ECH_TLS.read().unwrap().is_none() {
+            return;
         }
+        tracing::info!(
+            "ECH {} for API requests",
+            if tls.is_some() { "enabled" } else { "disabled" }
+        );
+        *ECH_TLS.write().unwrap() = tls;
+        let proxy = API_PROXY.read().unwrap().clone();
+        if let Err(e) = install_transport(&proxy).await {
+            tracing::warn!("Failed to rebuild the API client: {}", e);
+        }
+    }
 
-        None
+    /// How the API connection should use Encrypted Client Hello. A failed
+    /// lookup is treated as no ECH on offer.
+    async fn ech_plan(enabled: bool, providers: &[DohProvider]) -> EchPlan {
+        if !enabled {
+            return EchPlan::Disabled;
+        }
+        // An IP address has no HTTPS record to look up.
+        let host = api_base()
+            .ok()
+            .and_then(|base| Some(reqwest::Url::parse(&base).ok()?.domain()?.to_string()));
+        let Some(host) = host else {
+            return EchPlan::NotOffered;
+        };
+        let offered = match ech::discover(&lookup_client(), providers, &host).await {
+            Ok(offered) => offered,
+            Err(e) => {
+                tracing::warn!("ECH lookup for {} failed: {}", host, e);
+                None
+            }
+        };
+        ech::plan(true, offered)
     }
 
     pub async fn get_anonymous_config(
         location: &str,
         token: &str,
-        dns_blocking: Option<crate::models::DnsBlockingState>,
-        quantum_resistant: bool,
+        settings: &ServerSettings,
     ) -> Result<WireGuardConfig, AppError> {
-        let rb = request_with_attestation("GET", "/api/v1/auth/blind-key", None)?;
-        let key_pem = rb
-            .send()
-            .await?
-            .text()
-            .await
-            .map_err(|e| AppError::Network(e))?;
+        let fetch_blind_key = || fetch_public_key("/api/v1/auth/blind-key", "server public key");
+        let (m_bytes, s) = timed(Phase::BlindSign, async {
+            let server_pub_key = BLIND_KEY.get(fetch_blind_key).await?;
+            match Self::blind_sign(&server_pub_key, token).await? {
+                Some(signed) => Ok(signed),
+                None => {
+                    // Most likely the server rotated its key since we cached it.
+                    BLIND_KEY.invalidate();
+                    let server_pub_key = BLIND_KEY.get(fetch_blind_key).await?;
+                    Self::blind_sign(&server_pub_key, token)
+                        .await?
+                        .ok_or_else(|| {
+                            AppError::Crypto(
+                                "Blind signature verification failed locally!".to_string(),
+                            )
+                        })
+                }
+            }
+        })
+        .await?;
+
+        // Always a new key: the server ties an anonymous peer to the blind
+        // token that paid for it, so a reused key would be refused as in use
+        // and would link the two sessions if it were not.
+        let (private_key, public_key, pqc_sk, pqc_pk_b64) = timed(Phase::Keys, async {
+            let private_key = StaticSecret::random_from_rng(thread_rng());
+            let public_key = PublicKey::from(&private_key);
+            let (pqc_sk, pqc_pk_b64) = if settings.quantum_resistant {
+                let mut rng = thread_rng();
+                let (sk, pk) = MlKem768::generate(&mut rng);
+                (Some(sk), Some(BASE64_STANDARD.encode(pk.as_bytes())))
+            } else {
+                (None, None)
+            };
+            (private_key, public_key, pqc_sk, pqc_pk_b64)
+        })
+        .await;
+        let priv_base64 = BASE64_STANDARD.encode(private_key.to_bytes());
+        let pub_base64 = BASE64_STANDARD.encode(public_key.as_bytes());
 
-        let server_pub_key = RsaPublicKey::from_public_key_pem(&key_pem)
-            .map_err(|e| AppError::Crypto(format!("Invalid server public key: {}", e)))?;
+        let mut config = timed(Phase::Config, async {
+            let message = BASE64_STANDARD.encode(m_bytes);
+            let pow = Self::solve_pow_challenge(&message).await?;
+
+            let anon_req = anonymous_config_request(
+                location,
+                pub_base64,
+                pqc_pk_b64,
+                settings,
+                message,
+                BASE64_STANDARD.encode(s.to_bytes_be()),
+                pow,
+            );
+
+            let rb = request_with_attestation(
+                "POST",
+                "/api/v1/vpn/config-anonymous",
+                Some(json_body(&anon_req)?),
+            )?;
+
+            let res = request_log::send(rb).await?;
+
+            if !res.status().is_success() {
+                return Err(AppError::Api {
+                    status: res.status(),
+                    message: res.text().await.unwrap_or_default(),
+                });
+            }
+
+            let mut config = res.json::<WireGuardConfig>().await?;
+            validate_server_config(&config)?;
+
+            if let (Some(sk), Some(ct_b64)) = (pqc_sk, &config.pqc_ciphertext) {
+                let ct_bytes = BASE64_STANDARD
+                    .decode(ct_b64)
+                    .map_err(|_| AppError::Crypto("Invalid PQC ciphertext".to_string()))?;
+                let ct = ml_kem::Ciphertext::<MlKem768>::try_from(ct_bytes.as_slice())
+                    .map_err(|_| AppError::Crypto("Invalid PQC CT length".to_string()))?;
+                let ss = sk
+                    .decapsulate(&ct)
+                    .map_err(|_| AppError::Crypto("PQC Decapsulation failed".to_string()))?;
+                config.preshared_key = Some(BASE64_STANDARD.encode(ss.as_slice()));
+            }
+            Ok::<_, AppError>(config)
+        })
+        .await?;
+
+        config.private_key = priv_base64;
+
+        Ok(config)
+    }
 
+    /// Gets a random message blind-signed by the server under
+    /// `server_pub_key`. `None` means the signature did not verify against
+    /// that key.
+    async fn blind_sign(
+        server_pub_key: &RsaPublicKey,
+        token: &str,
+    ) -> Result<Option<([u8; 32], BigUint)>, AppError> {
         // Scope RNG usage
         let m_bytes: [u8; 32] = {
             let mut rng = thread_rng();
@@ -290,47 +891,20 @@ impl AuthService {
         let s = (s_prime * r_inv) % n;
 
         if s.modpow(e, n) != hashed_m {
-            return Err(AppError::Crypto("Blind signature verification failed locally!".to_string()));
+            return Ok(None);
         }
+        Ok(Some((m_bytes, s)))
+    }
 
-        let private_key = StaticSecret::random_from_rng(thread_rng());
-        let public_key = PublicKey::from(&private_key);
-        let priv_base64 = BASE64_STANDARD.encode(private_key.to_bytes());
-        let pub_base64 = BASE64_STANDARD.encode(public_key.as_bytes());
-
-        let (pqc_sk, pqc_pk_b64) = if quantum_resistant {
-            let mut rng = thread_rng();
-            let (sk, pk) = MlKem768::generate(&mut rng);
-            (Some(sk), Some(BASE64_STANDARD.encode(pk.as_bytes())))
-        } else {
-            (None, None)
-        };
-
-        let anon_req = AnonymousConfigRequest {
-            message: BASE64_STANDARD.encode(m_bytes),
-            signature: BASE64_STANDARD.encode(s.to_bytes_be()),
-            location: location.to_string(),
-            pub_key: pub_base64,
-            dns_blocking: dns_blocking.map(|d| marinvpn_common::DnsBlockingState {
-                ads: d.ads,
-                trackers: d.trackers,
-                malware: d.malware,
-                gambling: d.gambling,
-                adult_content: d.adult_content,
-                social_media: d.social_media,
-            }),
-            quantum_resistant,
-            pqc_public_key: pqc_pk_b64,
-        };
-
-        let rb = request_with_attestation(
-            "POST",
-            "/api/v1/vpn/config-anonymous",
-            Some(json_body(&anon_req)?),
-        )?;
-
-        let res = rb.send().await?;
-
+    /// Fetches the server's proof-of-work challenge and solves it for
+    /// `message`. Servers without the endpoint, or with the feature off, get
+    /// no solution.
+    async fn solve_pow_challenge(message: &str) -> Result<Option<PowSolution>, AppError> {
+        let rb = request_with_attestation("GET", "/api/v1/vpn/pow-challenge", None)?;
+        let res = request_log::send(rb).await?;
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
         if !res.status().is_success() {
             return Err(AppError::Api {
                 status: res.status(),
@@ -338,28 +912,80 @@ impl AuthService {
             });
         }
 
-        let mut config = res.json::<WireGuardConfig>().await?;
-
-        if let (Some(sk), Some(ct_b64)) = (pqc_sk, &config.pqc_ciphertext) {
-            let ct_bytes = BASE64_STANDARD
-                .decode(ct_b64)
-                .map_err(|_| AppError::Crypto("Invalid PQC ciphertext".to_string()))?;
-            let ct = ml_kem::Ciphertext::<MlKem768>::try_from(ct_bytes.as_slice())
-                .map_err(|_| AppError::Crypto("Invalid PQC CT length".to_string()))?;
-            let ss = sk
-                .decapsulate(&ct)
-                .map_err(|_| AppError::Crypto("PQC Decapsulation failed".to_string()))?;
-            config.preshared_key = Some(BASE64_STANDARD.encode(ss.as_slice()));
+        let challenge = res.json::<PowChallenge>().await?;
+        if !challenge.enabled {
+            return Ok(None);
         }
 
-        config.private_key = priv_base64;
+        let token = message.to_string();
+        let solution = tokio::task::spawn_blocking(move || {
+            let nonce =
+                marinvpn_common::pow::solve(&challenge.challenge, &token, challenge.difficulty);
+            PowSolution {
+                challenge: challenge.challenge.clone(),
+                nonce,
+            }
+        })
+        .await
+        .map_err(|e| AppError::Crypto(format!("Proof of work failed: {}", e)))?;
 
-        Ok(config)
+        Ok(Some(solution))
     }
 
     pub async fn login(
         account_number: &str,
         kick_device: Option<String>,
+    ) -> Result<LoginOutcome, AppError> {
+        let outcome =
+            LoginOutcome::from_result(Self::send_login(account_number, kick_device).await)?;
+        if matches!(outcome, LoginOutcome::Success(_)) {
+            DEVICE_KEY_REPLACED.store(false, Ordering::Relaxed);
+            REBIND_ATTEMPTED.store(false, Ordering::Relaxed);
+        }
+
+        Ok(outcome)
+    }
+
+    /// Logs in, and whenever the account is at its device limit asks `pick`
+    /// which device to remove, then tries again with that device kicked.
+    /// `pick` gets the devices and the server's message and returns `None`
+    /// to give up, which ends the flow with the `DeviceLimit` outcome. A
+    /// retry that is refused with a fresh list, e.g. because the device was
+    /// removed elsewhere in the meantime, goes back to `pick`.
+    pub async fn login_with_kick<P, Fut>(
+        account_number: &str,
+        pick: P,
+    ) -> Result<LoginOutcome, AppError>
+    where
+        P: FnMut(Vec<Device>, Option<String>) -> Fut,
+        Fut: std::future::Future<Output = Option<String>>,
+    {
+        Self::login_kicking(|kick| Self::login(account_number, kick), pick).await
+    }
+
+    async fn login_kicking<L, LoginFut, P, PickFut>(
+        mut login: L,
+        mut pick: P,
+    ) -> Result<LoginOutcome, AppError>
+    where
+        L: FnMut(Option<String>) -> LoginFut,
+        LoginFut: std::future::Future<Output = Result<LoginOutcome, AppError>>,
+        P: FnMut(Vec<Device>, Option<String>) -> PickFut,
+        PickFut: std::future::Future<Output = Option<String>>,
+    {
+        let mut outcome = login(None).await?;
+        while let LoginOutcome::DeviceLimit { devices, message } = &outcome {
+            let Some(kick) = pick(devices.clone(), message.clone()).await else {
+                break;
+            };
+            outcome = login(Some(kick)).await?;
+        }
+        Ok(outcome)
+    }
+
+    async fn send_login(
+        account_number: &str,
+        kick_device: Option<String>,
     ) -> Result<LoginResponse, AppError> {
         let login_req = LoginRequest {
             account_number: account_number.to_string(),
@@ -372,7 +998,7 @@ impl AuthService {
             Some(json_body(&login_req)?),
         )?;
 
-        let res = rb.send().await?;
+        let res = request_log::send(rb).await?;
 
         if !res.status().is_success() {
              return Err(AppError::Api {
@@ -381,9 +1007,7 @@ impl AuthService {
             });
         }
 
-        let data = res.json::<LoginResponse>().await?;
-
-        Ok(data)
+        Ok(res.json::<LoginResponse>().await?)
     }
 
     pub async fn refresh_auth(refresh_token: &str) -> Result<RefreshResponse, AppError> {
@@ -392,7 +1016,7 @@ impl AuthService {
         };
         let rb = request_with_attestation("POST", "/api/v1/auth/refresh", Some(json_body(&req)?))?;
 
-        let res = rb.send().await?;
+        let res = request_log::send(rb).await?;
 
         if !res.status().is_success() {
             return Err(AppError::Api {
@@ -436,7 +1060,7 @@ impl AuthService {
         account_number: &str,
         device_name: &str,
         token: &str,
-    ) -> Result<bool, AppError> {
+    ) -> Result<RemoveDeviceResponse, AppError> {
         let remove_req = RemoveDeviceRequest {
             account_number: account_number.to_string(),
             device_name: device_name.to_string(),
@@ -458,40 +1082,94 @@ impl AuthService {
             });
         }
 
-        let success = res.json::<bool>().await?;
+        parse_remove_device_response(&res.text().await?)
+    }
+
+    /// Wipes this account's devices, sessions and peers on the server. The
+    /// caller is expected to log out locally afterwards.
+    pub async fn get_sessions(token: &str) -> Result<SessionsResponse, AppError> {
+        let res = Self::send_authed_with_refresh(token, |t| {
+            request_with_attestation("GET", "/api/v1/account/sessions", None)
+                .map(|rb| rb.header("Authorization", format!("Bearer {}", t)))
+        })
+        .await?;
+
+        if !res.status().is_success() {
+            return Err(AppError::Api {
+                status: res.status(),
+                message: res.text().await.unwrap_or_default(),
+            });
+        }
+
+        Ok(res.json::<SessionsResponse>().await?)
+    }
+
+    /// Ends this device's session on the server without removing the
+    /// device. The saved tokens are cleared even if the server can't be
+    /// reached, so the app is logged out either way.
+    pub async fn logout(token: &str) -> Result<LogoutResponse, AppError> {
+        let result = Self::send_logout(token).await;
+        let _ = crate::storage::update_auth_tokens(None, None);
+        result
+    }
+
+    async fn send_logout(token: &str) -> Result<LogoutResponse, AppError> {
+        let res = Self::send_authed_with_refresh(token, |t| {
+            request_with_attestation("POST", "/api/v1/account/logout", None)
+                .map(|rb| rb.header("Authorization", format!("Bearer {}", t)))
+        })
+        .await?;
+
+        if !res.status().is_success() {
+            return Err(AppError::Api {
+                status: res.status(),
+                message: res.text().await.unwrap_or_default(),
+            });
+        }
+
+        Ok(res.json::<LogoutResponse>().await?)
+    }
+
+    pub async fn panic_account(
+        account_number: &str,
+        token: &str,
+    ) -> Result<AccountPanicResponse, AppError> {
+        let panic_req = AccountPanicRequest {
+            account_number: account_number.to_string(),
+        };
+        let res = Self::send_authed_with_refresh(token, |t| {
+            request_with_attestation("POST", "/api/v1/account/panic", Some(json_body(&panic_req)?))
+                .map(|rb| rb.header("Authorization", format!("Bearer {}", t)))
+        })
+        .await?;
+
+        if !res.status().is_success() {
+             return Err(AppError::Api {
+                status: res.status(),
+                message: res.text().await.unwrap_or_default(),
+            });
+        }
 
-        Ok(success)
+        Ok(res.json::<AccountPanicResponse>().await?)
     }
 
     pub async fn report_problem(
         account_number: &str,
         message: &str,
         token: &str,
-    ) -> Result<bool, AppError> {
-        let rb = request_with_attestation("GET", "/api/v1/auth/support-key", None)?;
-        let key_pem = rb
-            .send()
-            .await?
-            .text()
-            .await
-            .map_err(|e| AppError::Network(e))?;
-
-        let pub_key = RsaPublicKey::from_public_key_pem(&key_pem)
-            .map_err(|e| AppError::Crypto(format!("Invalid support public key: {}", e)))?;
-
-        let mut rng = thread_rng();
-        let enc_data = if !message.is_empty() {
-            let msg_bytes = message.as_bytes();
-            let max_chunk = 400;
-            let mut encrypted_chunks = Vec::new();
-
-            for chunk in msg_bytes.chunks(max_chunk) {
-                let enc = pub_key
-                    .encrypt(&mut rng, rsa::Oaep::new::<Sha256>(), chunk)
-                    .map_err(|e| AppError::Crypto(format!("Encryption failed: {}", e)))?;
-                encrypted_chunks.push(BASE64_STANDARD.encode(enc));
-            }
-            encrypted_chunks.join("|")
+    ) -> Result<ReportResponse, AppError> {
+        let pub_key = SUPPORT_KEY
+            .get(|| fetch_public_key("/api/v1/auth/support-key", "support public key"))
+            .await?;
+
+        let is_encrypted = !message.is_empty();
+        let enc_data = if is_encrypted {
+            encrypt_report(
+                &pub_key,
+                message.as_bytes(),
+                ReportEncryption::default(),
+                &mut thread_rng(),
+            )?
         } else {
             String::new()
         };
@@ -499,7 +1177,11 @@ impl AuthService {
         let report_req = ReportRequest {
             account_number: account_number.to_string(),
             message: enc_data,
-            is_encrypted: true,
+            is_encrypted,
+            category: None,
+            app_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            os: Some(ReportOs::current()),
+            last_error_code: None,
         };
         let res = Self::send_authed_with_refresh(token, |t| {
             request_with_attestation("POST", "/api/v1/vpn/report", Some(json_body(&report_req)?))
@@ -514,14 +1196,27 @@ impl AuthService {
             });
         }
 
-        let success = res.json::<bool>().await?;
+        parse_report_response(&res.text().await?)
+    }
+
+    /// What the server runs and supports. Open to anyone, so it goes out
+    /// without attestation and can be asked before signing in.
+    pub async fn get_server_version() -> Result<ServerVersion, AppError> {
+        let res = request_log::send(client().get(api_url("/version")?)).await?;
 
-        Ok(success)
+        if !res.status().is_success() {
+            return Err(AppError::Api {
+                status: res.status(),
+                message: res.text().await.unwrap_or_default(),
+            });
+        }
+
+        Ok(res.json::<ServerVersion>().await?)
     }
 
     pub async fn generate_account_number() -> Result<String, AppError> {
         let rb = request_with_attestation("POST", "/api/v1/account/generate", None)?;
-        let res = rb.send().await?;
+        let res = request_log::send(rb).await?;
 
         if !res.status().is_success() {
              return Err(AppError::Api {
@@ -539,16 +1234,16 @@ impl AuthService {
         account_number: &str,
         location: &str,
         token: &str,
-        dns_blocking: Option<crate::models::DnsBlockingState>,
-        quantum_resistant: bool,
+        settings: &ServerSettings,
+        key_reuse: KeyReuse,
     ) -> Result<WireGuardConfig, AppError> {
-        let private_key = StaticSecret::random_from_rng(thread_rng());
+        let private_key = WG_KEYS.get(location, key_reuse);
         let public_key = PublicKey::from(&private_key);
 
         let priv_base64 = BASE64_STANDARD.encode(private_key.to_bytes());
         let pub_base64 = BASE64_STANDARD.encode(public_key.as_bytes());
 
-        let (pqc_sk, pqc_pk_b64) = if quantum_resistant {
+        let (pqc_sk, pqc_pk_b64) = if settings.quantum_resistant {
             let mut rng = thread_rng();
             let (sk, pk) = MlKem768::generate(&mut rng);
             (Some(sk), Some(BASE64_STANDARD.encode(pk.as_bytes())))
@@ -556,21 +1251,8 @@ impl AuthService {
             (None, None)
         };
 
-        let cfg_req = ConfigRequest {
-            account_number: account_number.to_string(),
-            location: location.to_string(),
-            pub_key: pub_base64,
-            dns_blocking: dns_blocking.map(|d| marinvpn_common::DnsBlockingState {
-                ads: d.ads,
-                trackers: d.trackers,
-                malware: d.malware,
-                gambling: d.gambling,
-                adult_content: d.adult_content,
-                social_media: d.social_media,
-            }),
-            quantum_resistant,
-            pqc_public_key: pqc_pk_b64,
-        };
+        let cfg_req =
+            account_config_request(account_number, location, pub_base64, pqc_pk_b64, settings);
         let res = Self::send_authed_with_refresh(token, |t| {
             request_with_attestation("POST", "/api/v1/vpn/config", Some(json_body(&cfg_req)?))
                 .map(|rb| rb.header("Authorization", format!("Bearer {}", t)))
@@ -578,6 +1260,9 @@ impl AuthService {
         .await?;
 
         if !res.status().is_success() {
+            // A held key the server refuses, e.g. one registered before a
+            // logout, would be refused again on the next try.
+            WG_KEYS.forget(location);
             let status = res.status();
             let err_body = res.text().await.unwrap_or_default();
             return Err(AppError::Api {
@@ -587,6 +1272,7 @@ impl AuthService {
         }
 
         let mut config = res.json::<WireGuardConfig>().await?;
+        validate_server_config(&config)?;
 
         if let (Some(sk), Some(ct_b64)) = (pqc_sk, &config.pqc_ciphertext) {
             let ct_bytes = BASE64_STANDARD
@@ -605,3 +1291,678 @@ impl AuthService {
         Ok(config)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rsa::pkcs8::{EncodePublicKey, LineEnding};
+    use rsa::RsaPrivateKey;
+
+    // Key generation is slow in debug builds; share one across tests.
+    static KEY: Lazy<(RsaPublicKey, String)> = Lazy::new(|| {
+        let key = RsaPrivateKey::new(&mut thread_rng(), 1024).unwrap();
+        let public = key.to_public_key();
+        let pem = public.to_public_key_pem(LineEnding::LF).unwrap();
+        (public, pem)
+    });
+
+    /// Settings with every local field set to something that would stand
+    /// out in a request body.
+    fn distinctive_settings() -> crate::models::SettingsState {
+        use crate::models::{AppInfo, DnsRoute, DnsRouteVia, SettingsState};
+        SettingsState {
+            branding_name: "Local Brand".to_string(),
+            custom_dns_server: "192.0.2.53".to_string(),
+            excluded_ips: vec!["198.51.100.0/24".to_string()],
+            excluded_apps: vec![AppInfo {
+                name: "Local App".to_string(),
+                path: "/opt/local-app".to_string(),
+                icon: None,
+            }],
+            dns_routes: vec![DnsRoute {
+                domain: "corp.example".to_string(),
+                via: DnsRouteVia::Local,
+                search: true,
+            }],
+            udp_blocked_networks: vec!["home-network-id".to_string()],
+            quantum_resistant: true,
+            ..SettingsState::default()
+        }
+    }
+
+    fn keys(value: &serde_json::Value) -> Vec<&str> {
+        let mut keys: Vec<&str> = value
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    #[test]
+    fn config_requests_carry_only_server_settings() {
+        let settings = distinctive_settings();
+        let server = settings.server_settings();
+        assert_eq!(server.dns_blocking, Some(settings.dns_blocking.clone()));
+        assert!(server.quantum_resistant);
+
+        let anonymous = serde_json::to_value(anonymous_config_request(
+            "Sweden, Stockholm",
+            "pub".to_string(),
+            Some("pqc".to_string()),
+            &server,
+            "message".to_string(),
+            "signature".to_string(),
+            None,
+        ))
+        .unwrap();
+        assert_eq!(
+            keys(&anonymous),
+            [
+                "dns_blocking",
+                "location",
+                "message",
+                "pow",
+                "pqc_public_key",
+                "pub_key",
+                "quantum_resistant",
+                "signature",
+            ]
+        );
+
+        let account = serde_json::to_value(account_config_request(
+            "1234123412341234",
+            "Sweden, Stockholm",
+            "pub".to_string(),
+            None,
+            &server,
+        ))
+        .unwrap();
+        assert_eq!(
+            keys(&account),
+            [
+                "account_number",
+                "dns_blocking",
+                "location",
+                "pqc_public_key",
+                "pub_key",
+                "quantum_resistant",
+            ]
+        );
+
+        for body in [anonymous.to_string(), account.to_string()] {
+            for local in [
+                "Local Brand",
+                "192.0.2.53",
+                "198.51.100.0/24",
+                "Local App",
+                "corp.example",
+                "home-network-id",
+            ] {
+                assert!(!body.contains(local), "{} leaked into {}", local, body);
+            }
+        }
+    }
+
+    #[test]
+    fn anonymous_config_request_passes_the_privacy_audit() {
+        let body = anonymous_config_request(
+            "Sweden, Stockholm",
+            BASE64_STANDARD.encode([7u8; 32]),
+            Some(BASE64_STANDARD.encode([9u8; 1184])),
+            &distinctive_settings().server_settings(),
+            BASE64_STANDARD.encode([3u8; 32]),
+            BASE64_STANDARD.encode([5u8; 128]),
+            None,
+        );
+        let request = reqwest::Client::new()
+            .post("https://api.marinvpn.example/api/v1/vpn/config-anonymous")
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(json_body(&body).unwrap())
+            .build()
+            .unwrap();
+        assert_eq!(crate::services::privacy_audit::audit(&request), []);
+    }
+
+    #[test]
+    fn custom_dns_keeps_blocking_preferences_local() {
+        let mut settings = distinctive_settings();
+        settings.set_custom_dns(true);
+        let request = anonymous_config_request(
+            "Sweden, Stockholm",
+            "pub".to_string(),
+            None,
+            &settings.server_settings(),
+            "message".to_string(),
+            "signature".to_string(),
+            None,
+        );
+        assert_eq!(request.dns_blocking, None);
+    }
+
+    #[test]
+    fn stored_device_key_is_reused() {
+        let mut saved = None;
+        let (original, _) =
+            load_or_create_device_key(None, |k| saved = Some(k.to_string())).unwrap();
+        let saved = saved.expect("a new key is persisted");
+
+        let (loaded, replaced) =
+            load_or_create_device_key(Some(&saved), |_| panic!("nothing to save")).unwrap();
+        assert!(!replaced);
+        assert_eq!(loaded.public_key().as_ref(), original.public_key().as_ref());
+    }
+
+    #[test]
+    fn corrupt_device_key_is_replaced() {
+        let valid = {
+            let mut saved = String::new();
+            load_or_create_device_key(None, |k| saved = k.to_string()).unwrap();
+            saved
+        };
+        let truncated = BASE64_STANDARD.encode(&BASE64_STANDARD.decode(&valid).unwrap()[..40]);
+
+        for corrupt in ["not base64!", "", truncated.as_str()] {
+            let mut saved = None;
+            let (key, replaced) =
+                load_or_create_device_key(Some(corrupt), |k| saved = Some(k.to_string())).unwrap();
+            assert!(replaced, "{:?} should be replaced", corrupt);
+
+            // The replacement is persisted and loads back as the same key.
+            let (reloaded, replaced) =
+                load_or_create_device_key(saved.as_deref(), |_| panic!("nothing to save"))
+                    .unwrap();
+            assert!(!replaced);
+            assert_eq!(reloaded.public_key().as_ref(), key.public_key().as_ref());
+        }
+    }
+
+    /// Answers one request per entry in `responses`, in order, and passes
+    /// on the Authorization header each one carried. Header lines can follow
+    /// the status, separated by `\r\n`.
+    async fn serve(
+        responses: Vec<(&'static str, &'static str)>,
+    ) -> (String, tokio::sync::mpsc::UnboundedReceiver<String>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/api/v1/account/sessions",
+            listener.local_addr().unwrap()
+        );
+        let (seen, authorizations) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            for (status, body) in responses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = [0u8; 2048];
+                let read = socket.read(&mut request).await.unwrap();
+                let head = String::from_utf8_lossy(&request[..read]);
+                let authorization = head
+                    .lines()
+                    .filter_map(|line| line.split_once(':'))
+                    .find(|(name, _)| name.eq_ignore_ascii_case("authorization"))
+                    .map(|(_, value)| value.trim().to_string())
+                    .unwrap_or_default();
+                let _ = seen.send(authorization);
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (url, authorizations)
+    }
+
+    fn bearer(url: &str) -> impl Fn(&str) -> Result<reqwest::RequestBuilder, AppError> + '_ {
+        move |token| Ok(reqwest::Client::new().get(url).bearer_auth(token))
+    }
+
+    const MISMATCH: &str = r#"{"code":"device_key_mismatch","success":false}"#;
+
+    #[tokio::test]
+    async fn key_mismatch_registers_the_device_again_and_retries() {
+        let (url, mut authorizations) =
+            serve(vec![("401 Unauthorized", MISMATCH), ("200 OK", "{}")]).await;
+
+        let mut chosen = None;
+        let res = AuthService::send_authed_with("old-token", bearer(&url), |recovery| {
+            chosen = Some(recovery);
+            async { Ok("rebound-token".to_string()) }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(chosen, Some(Recovery::Rebind));
+        assert_eq!(authorizations.recv().await.unwrap(), "Bearer old-token");
+        assert_eq!(authorizations.recv().await.unwrap(), "Bearer rebound-token");
+    }
+
+    #[tokio::test]
+    async fn other_rejections_refresh_the_session() {
+        for body in [
+            r#"{"error":"Unauthorized","code":"unauthorized","success":false}"#,
+            "Unauthorized",
+            "",
+        ] {
+            let (url, _) = serve(vec![("401 Unauthorized", body), ("200 OK", "{}")]).await;
+            let mut chosen = None;
+            AuthService::send_authed_with("old-token", bearer(&url), |recovery| {
+                chosen = Some(recovery);
+                async { Ok("refreshed-token".to_string()) }
+            })
+            .await
+            .unwrap();
+            assert_eq!(chosen, Some(Recovery::Refresh), "{:?}", body);
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_recovery_is_reported_without_a_retry() {
+        let (url, mut authorizations) = serve(vec![("401 Unauthorized", MISMATCH)]).await;
+
+        let result = AuthService::send_authed_with("old-token", bearer(&url), |_| async {
+            Err::<String, _>(AppError::DeviceKeyMismatch)
+        })
+        .await;
+
+        assert!(matches!(result, Err(AppError::DeviceKeyMismatch)));
+        assert_eq!(authorizations.recv().await.unwrap(), "Bearer old-token");
+        assert!(authorizations.recv().await.is_none());
+    }
+
+    const RATE_LIMITED: &str = r#"{"code":"rate_limited","success":false}"#;
+
+    #[tokio::test]
+    async fn throttled_requests_wait_out_retry_after() {
+        let (url, mut authorizations) = serve(vec![
+            ("429 Too Many Requests\r\nRetry-After: 1", RATE_LIMITED),
+            ("200 OK", "{}"),
+        ])
+        .await;
+
+        let started = std::time::Instant::now();
+        let res = AuthService::send_authed_with("token", bearer(&url), |_| async {
+            panic!("a 429 is not a rejected token")
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert_eq!(authorizations.recv().await.unwrap(), "Bearer token");
+        assert_eq!(authorizations.recv().await.unwrap(), "Bearer token");
+    }
+
+    #[tokio::test]
+    async fn long_or_missing_retry_after_is_left_to_the_caller() {
+        for status in [
+            "429 Too Many Requests\r\nRetry-After: 3600",
+            "429 Too Many Requests",
+        ] {
+            let (url, mut authorizations) = serve(vec![(status, RATE_LIMITED)]).await;
+            let res = AuthService::send_authed_with("token", bearer(&url), |_| async {
+                panic!("a 429 is not a rejected token")
+            })
+            .await
+            .unwrap();
+
+            assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS, "{}", status);
+            assert_eq!(authorizations.recv().await.unwrap(), "Bearer token");
+            assert!(authorizations.recv().await.is_none());
+        }
+    }
+
+    fn public_key_pem() -> (RsaPublicKey, String) {
+        KEY.clone()
+    }
+
+    fn parse(
+        status: StatusCode,
+        content_type: Option<&str>,
+        body: &str,
+    ) -> Result<RsaPublicKey, AppError> {
+        parse_public_key_response("server public key", status, content_type, body)
+    }
+
+    #[test]
+    fn accepts_pem_from_the_api() {
+        let (public, pem) = public_key_pem();
+        for content_type in [
+            Some("text/plain; charset=utf-8"),
+            Some("application/x-pem-file"),
+            None,
+        ] {
+            assert_eq!(parse(StatusCode::OK, content_type, &pem).unwrap(), public);
+        }
+    }
+
+    #[test]
+    fn non_success_status_is_an_api_error() {
+        let (_, pem) = public_key_pem();
+        match parse(StatusCode::SERVICE_UNAVAILABLE, Some("text/plain"), &pem) {
+            Err(AppError::Api { status, .. }) => {
+                assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE)
+            }
+            other => panic!("expected API error, got {:?}", other),
+        }
+        assert!(matches!(
+            parse(StatusCode::NETWORK_AUTHENTICATION_REQUIRED, None, ""),
+            Err(AppError::CaptivePortal)
+        ));
+    }
+
+    #[test]
+    fn html_login_page_is_a_captive_portal() {
+        let page = "<!DOCTYPE html><html><body>Sign in to Airport WiFi</body></html>";
+        assert!(matches!(
+            parse(StatusCode::OK, Some("text/html; charset=UTF-8"), page),
+            Err(AppError::CaptivePortal)
+        ));
+        // Some portals mislabel their page.
+        assert!(matches!(
+            parse(StatusCode::OK, Some("text/plain"), page),
+            Err(AppError::CaptivePortal)
+        ));
+    }
+
+    #[test]
+    fn wrong_content_type_is_an_api_error() {
+        let (_, pem) = public_key_pem();
+        match parse(StatusCode::OK, Some("application/json"), &pem) {
+            Err(AppError::Api { status, message }) => {
+                assert_eq!(status, StatusCode::OK);
+                assert!(message.contains("application/json"), "{}", message);
+            }
+            other => panic!("expected API error, got {:?}", other),
+        }
+    }
+
+    fn login_outcome(body: &str) -> Result<LoginOutcome, AppError> {
+        LoginOutcome::from_result(Ok(serde_json::from_str(body).unwrap()))
+    }
+
+    fn login_rejection(status: StatusCode, body: &str) -> Result<LoginOutcome, AppError> {
+        LoginOutcome::from_result(Err(AppError::Api {
+            status,
+            message: body.to_string(),
+        }))
+    }
+
+    #[test]
+    fn login_answers_in_the_body_map_to_outcomes() {
+        let success = login_outcome(
+            r#"{"success":true,"auth_token":"a","refresh_token":"r",
+                "account_info":{"account_number":"1234","expiry_date":10,"created_at":1},
+                "current_device":"brave tiger","devices":null,"error_code":null,"error":null}"#,
+        );
+        match success.unwrap() {
+            LoginOutcome::Success(session) => {
+                assert_eq!(session.account.account_number, "1234");
+                assert_eq!(session.device_name, "brave tiger");
+                assert_eq!(session.auth_token, "a");
+                assert_eq!(session.refresh_token, "r");
+            }
+            other => panic!("expected success, got {:?}", other),
+        }
+
+        let limit = login_outcome(
+            r#"{"success":false,"auth_token":null,"refresh_token":null,"account_info":null,
+                "current_device":null,"devices":[{"name":"cold river","created_date":"2026-01-02"}],
+                "error_code":"DEVICE_LIMIT","error":"Device limit reached (max 5)."}"#,
+        );
+        assert_eq!(
+            limit.unwrap(),
+            LoginOutcome::DeviceLimit {
+                devices: vec![Device {
+                    name: "cold river".to_string(),
+                    created_date: "2026-01-02".to_string(),
+                }],
+                message: Some("Device limit reached (max 5).".to_string()),
+            }
+        );
+
+        let sessions = login_outcome(
+            r#"{"success":false,"auth_token":null,"refresh_token":null,"account_info":null,
+                "current_device":"brave tiger","devices":null,
+                "error_code":"SESSION_LIMIT","error":"Too many sessions"}"#,
+        );
+        assert_eq!(
+            sessions.unwrap(),
+            LoginOutcome::Refused("Too many sessions".to_string())
+        );
+
+        // Claims success but leaves out the tokens.
+        let broken = login_outcome(
+            r#"{"success":true,"auth_token":null,"refresh_token":null,"account_info":null,
+                "current_device":null,"devices":null,"error_code":null,"error":null}"#,
+        );
+        assert!(matches!(broken, Err(AppError::Auth(_))));
+    }
+
+    #[test]
+    fn login_error_statuses_map_to_outcomes() {
+        let body = |code: &str| format!(r#"{{"error":"x","code":"{}","success":false}}"#, code);
+        let verdict = |status, code| login_rejection(status, &body(code)).unwrap();
+        let expired = verdict(StatusCode::FORBIDDEN, "account_expired");
+        assert_eq!(expired, LoginOutcome::Expired);
+        let unknown = verdict(StatusCode::UNAUTHORIZED, "account_not_found");
+        assert_eq!(unknown, LoginOutcome::Invalid);
+        let throttled = verdict(StatusCode::TOO_MANY_REQUESTS, "rate_limited");
+        assert_eq!(throttled, LoginOutcome::RateLimited);
+        // A 429 from a proxy in front of the API carries no code.
+        assert_eq!(
+            login_rejection(StatusCode::TOO_MANY_REQUESTS, "").unwrap(),
+            LoginOutcome::RateLimited
+        );
+
+        // Anything else is not a verdict on the login.
+        let busy = login_rejection(StatusCode::SERVICE_UNAVAILABLE, &body("service_busy"));
+        assert!(matches!(busy, Err(AppError::Api { .. })));
+        let offline = LoginOutcome::from_result(Err(AppError::Validation("x".to_string())));
+        assert!(matches!(offline, Err(AppError::Validation(_))));
+    }
+
+    fn device(name: &str) -> Device {
+        Device {
+            name: name.to_string(),
+            created_date: "2026-01-02".to_string(),
+        }
+    }
+
+    /// The `kick_device` of each login attempt.
+    type Kicks = std::sync::Arc<std::sync::Mutex<Vec<Option<String>>>>;
+
+    /// A server holding `devices` at the limit. Logging in needs one kicked;
+    /// kicking a device it does not have is refused with the current list.
+    fn full_account(
+        devices: &[&str],
+    ) -> (
+        Kicks,
+        impl FnMut(Option<String>) -> std::future::Ready<Result<LoginOutcome, AppError>>,
+    ) {
+        let calls = Kicks::default();
+        let seen = calls.clone();
+        let mut devices: Vec<Device> = devices.iter().map(|d| device(d)).collect();
+        let login = move |kick: Option<String>| {
+            seen.lock().unwrap().push(kick.clone());
+            let at = kick.and_then(|k| devices.iter().position(|d| d.name == k));
+            let outcome = match at {
+                Some(at) => {
+                    let removed = devices.remove(at);
+                    Ok(LoginOutcome::Success(LoginSession {
+                        account: Account {
+                            account_number: "1234".to_string(),
+                            expiry_date: 10,
+                            created_at: 1,
+                        },
+                        device_name: format!("after {}", removed.name),
+                        auth_token: "a".to_string(),
+                        refresh_token: "r".to_string(),
+                    }))
+                }
+                None => Ok(LoginOutcome::DeviceLimit {
+                    devices: devices.clone(),
+                    message: None,
+                }),
+            };
+            std::future::ready(outcome)
+        };
+        (calls, login)
+    }
+
+    #[tokio::test]
+    async fn device_limit_kicks_the_picked_device_and_retries() {
+        let (calls, login) = full_account(&["cold river", "wild storm"]);
+        let outcome = AuthService::login_kicking(login, |devices, _| {
+            assert_eq!(devices.len(), 2);
+            std::future::ready(Some("wild storm".to_string()))
+        })
+        .await
+        .unwrap();
+
+        match outcome {
+            LoginOutcome::Success(session) => assert_eq!(session.device_name, "after wild storm"),
+            other => panic!("expected success, got {:?}", other),
+        }
+        let kicked = Some("wild storm".to_string());
+        assert_eq!(*calls.lock().unwrap(), [None, kicked]);
+    }
+
+    #[tokio::test]
+    async fn device_already_gone_is_picked_again_from_the_fresh_list() {
+        let (calls, login) = full_account(&["cold river", "wild storm"]);
+        let mut picks = vec!["cold river", "removed elsewhere"];
+        let outcome = AuthService::login_kicking(login, |_, _| {
+            std::future::ready(picks.pop().map(str::to_string))
+        })
+        .await
+        .unwrap();
+
+        assert!(matches!(outcome, LoginOutcome::Success(_)));
+        assert_eq!(calls.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn closing_the_device_picker_ends_at_the_limit() {
+        let (calls, login) = full_account(&["cold river"]);
+        let outcome = AuthService::login_kicking(login, |_, _| std::future::ready(None))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            outcome,
+            LoginOutcome::DeviceLimit {
+                devices: vec![device("cold river")],
+                message: None,
+            }
+        );
+        assert_eq!(*calls.lock().unwrap(), [None]);
+    }
+
+    #[tokio::test]
+    async fn failed_retry_is_reported() {
+        let mut attempts = 0;
+        let login = |_: Option<String>| {
+            attempts += 1;
+            std::future::ready(match attempts {
+                1 => Ok(LoginOutcome::DeviceLimit {
+                    devices: vec![device("cold river")],
+                    message: None,
+                }),
+                _ => Err(AppError::Validation("offline".to_string())),
+            })
+        };
+        let picked = |_, _| std::future::ready(Some("cold river".to_string()));
+        let result = AuthService::login_kicking(login, picked).await;
+        assert!(matches!(result, Err(AppError::Validation(_))));
+    }
+
+    #[test]
+    fn remove_device_response_distinguishes_not_found() {
+        assert_eq!(
+            parse_remove_device_response(r#"{"removed":true,"reason":null}"#).unwrap(),
+            RemoveDeviceResponse::removed()
+        );
+        let not_found =
+            parse_remove_device_response(r#"{"removed":false,"reason":"not_found"}"#).unwrap();
+        assert!(!not_found.removed);
+        assert_eq!(not_found.reason, Some(RemoveDeviceReason::NotFound));
+
+        assert_eq!(
+            parse_remove_device_response("true").unwrap(),
+            RemoveDeviceResponse::removed()
+        );
+        assert_eq!(parse_remove_device_response("false").unwrap(), not_found);
+        assert!(matches!(
+            parse_remove_device_response(r#"{"ok":1}"#),
+            Err(AppError::Serialization(_))
+        ));
+    }
+
+    #[test]
+    fn report_response_accepts_both_shapes() {
+        let response = parse_report_response(r#"{"accepted":true,"reference":"3f9a1c07"}"#);
+        assert_eq!(response.unwrap().reference.as_deref(), Some("3f9a1c07"));
+
+        let legacy = parse_report_response("true").unwrap();
+        assert!(legacy.accepted);
+        assert_eq!(legacy.reference, None);
+    }
+
+    #[test]
+    fn malformed_pem_is_a_crypto_error() {
+        let (_, pem) = public_key_pem();
+        let truncated = &pem[..pem.len() / 2];
+        for body in ["", "not a key", truncated] {
+            match parse(StatusCode::OK, Some("text/plain"), body) {
+                Err(AppError::Crypto(message)) => {
+                    assert!(message.starts_with("Invalid server public key"), "{}", message)
+                }
+                other => panic!("expected crypto error, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn client_is_too_old_only_below_the_minimum() {
+        assert!(older_than("0.1.0", "0.2.0"));
+        assert!(older_than("0.9.9", "1.0.0"));
+        assert!(!older_than("0.2.0", "0.2.0"));
+        assert!(!older_than("0.2.0-beta.1", "0.2.0"));
+        assert!(!older_than("0.10.0", "0.9.0"));
+        assert!(!older_than("0.1.0", "latest"));
+        assert!(!older_than("0.1.0", ""));
+    }
+
+    #[test]
+    fn client_too_old_rejection_asks_for_an_update() {
+        let rejection = AppError::Api {
+            status: StatusCode::UPGRADE_REQUIRED,
+            message: r#"{"error":"This version of the app is no longer supported. Update to 0.3.0 or later.","code":"client_too_old","success":false}"#.to_string(),
+        };
+        let error = LoginOutcome::from_result(Err(rejection)).unwrap_err();
+        assert!(error.is_client_too_old());
+        assert_eq!(
+            error.user_friendly_message(),
+            "This version of MarinVPN is no longer supported. Please update the app."
+        );
+
+        // The code is enough on its own, whatever the status.
+        let relayed = AppError::Api {
+            status: StatusCode::BAD_REQUEST,
+            message: r#"{"code":"client_too_old"}"#.to_string(),
+        };
+        assert!(relayed.is_client_too_old());
+        let other = AppError::Api {
+            status: StatusCode::BAD_REQUEST,
+            message: r#"{"code":"bad_request"}"#.to_string(),
+        };
+        assert!(!other.is_client_too_old());
+    }
+}
@@ -0,0 +1,360 @@
+//! Starting the app at login: a value under the user's `Run` key on
+//! Windows, an XDG autostart entry on Linux.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Value name under [`RUN_KEY`] and `Name` of the desktop entry.
+pub const APP_NAME: &str = "MarinVPN";
+pub const RUN_KEY: &str = r"HKCU\Software\Microsoft\Windows\CurrentVersion\Run";
+pub const DESKTOP_FILE: &str = "marinvpn.desktop";
+
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum AutostartError {
+    #[error("Launch at startup is not supported on this system")]
+    Unsupported,
+
+    #[error("Could not find the app's executable: {0}")]
+    NoExecutable(String),
+
+    #[error("Not allowed to change the startup entry: {0}")]
+    PermissionDenied(String),
+
+    #[error("Could not change the startup entry: {0}")]
+    Failed(String),
+}
+
+impl From<io::Error> for AutostartError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::PermissionDenied => Self::PermissionDenied(e.to_string()),
+            _ => Self::Failed(e.to_string()),
+        }
+    }
+}
+
+/// What the OS currently starts at login for this app.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Registration {
+    /// Starts this executable.
+    Registered,
+    NotRegistered,
+    /// An entry exists but starts something else, e.g. a copy of the app
+    /// that has since been moved or replaced.
+    Stale(String),
+}
+
+impl Registration {
+    #[cfg_attr(not(any(target_os = "linux", target_os = "windows")), allow(dead_code))]
+    fn of(command: Option<&str>, expected: &str) -> Self {
+        match command {
+            None => Self::NotRegistered,
+            Some(command) if command == expected => Self::Registered,
+            Some(command) => Self::Stale(command.to_string()),
+        }
+    }
+}
+
+/// The file the OS should start: the AppImage rather than the binary
+/// unpacked from it, which lives in a temporary mount.
+pub fn launch_path() -> Result<PathBuf, AutostartError> {
+    if let Some(appimage) = std::env::var_os("APPIMAGE") {
+        return Ok(PathBuf::from(appimage));
+    }
+    std::env::current_exe().map_err(|e| AutostartError::NoExecutable(e.to_string()))
+}
+
+/// Where the desktop entry goes under the user's config directory.
+pub fn linux_autostart_path(config_dir: &Path) -> PathBuf {
+    config_dir.join("autostart").join(DESKTOP_FILE)
+}
+
+/// `Exec` value for `exe`, quoted as the desktop entry spec asks when the
+/// path holds spaces or reserved characters.
+pub fn linux_exec(exe: &Path) -> String {
+    let exe = exe.to_string_lossy();
+    let reserved = |c: char| c.is_whitespace() || "\"'\\><~|&;$*?#()`".contains(c);
+    if !exe.contains(reserved) {
+        return exe.into_owned();
+    }
+    let mut quoted = String::from("\"");
+    for c in exe.chars() {
+        if matches!(c, '"' | '`' | '$' | '\\') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+pub fn linux_desktop_entry(exe: &Path) -> String {
+    format!(
+        "[Desktop Entry]\n\
+         Type=Application\n\
+         Name={APP_NAME}\n\
+         Exec={}\n\
+         Terminal=false\n\
+         X-GNOME-Autostart-enabled=true\n",
+        linux_exec(exe)
+    )
+}
+
+/// The `Exec` line of a desktop entry, if it has one.
+pub fn linux_registered_command(entry: &str) -> Option<&str> {
+    entry
+        .lines()
+        .find_map(|line| line.strip_prefix("Exec="))
+        .map(str::trim)
+}
+
+/// How the Run key value starts `exe`, quoted so a path with spaces is not
+/// split.
+pub fn windows_command(exe: &Path) -> String {
+    format!("\"{}\"", exe.display())
+}
+
+pub fn windows_register_args(exe: &Path) -> Vec<String> {
+    [
+        "add",
+        RUN_KEY,
+        "/v",
+        APP_NAME,
+        "/t",
+        "REG_SZ",
+        "/d",
+        &windows_command(exe),
+        "/f",
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect()
+}
+
+pub fn windows_unregister_args() -> Vec<String> {
+    ["delete", RUN_KEY, "/v", APP_NAME, "/f"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect()
+}
+
+pub fn windows_query_args() -> Vec<String> {
+    ["query", RUN_KEY, "/v", APP_NAME]
+        .iter()
+        .map(|s| s.to_string())
+        .collect()
+}
+
+/// The data of the [`APP_NAME`] value in `reg query` output, e.g.
+/// `    MarinVPN    REG_SZ    "C:\Program Files\MarinVPN\marinvpn.exe"`.
+pub fn windows_registered_command(output: &str) -> Option<&str> {
+    output.lines().find_map(|line| {
+        let (name, rest) = line.trim().split_once("REG_SZ")?;
+        (name.trim() == APP_NAME).then(|| rest.trim())
+    })
+}
+
+/// Whether `reg` failed for lack of rights rather than anything else.
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+fn windows_denied(stderr: &str) -> bool {
+    stderr.contains("Access is denied")
+}
+
+/// Reads what the OS starts at login for this app.
+pub async fn registration() -> Result<Registration, AutostartError> {
+    let exe = launch_path()?;
+    #[cfg(target_os = "linux")]
+    {
+        let path = linux_config_path()?;
+        match tokio::fs::read_to_string(&path).await {
+            Ok(entry) => Ok(Registration::of(
+                linux_registered_command(&entry),
+                &linux_exec(&exe),
+            )),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Registration::NotRegistered),
+            Err(e) => Err(e.into()),
+        }
+    }
+    #[cfg(target_os = "windows")]
+    {
+        // `reg query` fails when the value does not exist.
+        let output = reg(&windows_query_args()).await;
+        let command = output.as_deref().ok().and_then(windows_registered_command);
+        Ok(Registration::of(command, &windows_command(&exe)))
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "windows")))]
+    {
+        let _ = exe;
+        Ok(Registration::NotRegistered)
+    }
+}
+
+/// Makes the OS start the app at login, or stop doing so, unless it already
+/// matches. An entry that starts another copy of the app is rewritten.
+pub async fn sync(enabled: bool) -> Result<Registration, AutostartError> {
+    let current = registration().await?;
+    match (enabled, &current) {
+        (true, Registration::Registered) | (false, Registration::NotRegistered) => {
+            return Ok(current)
+        }
+        (true, Registration::Stale(command)) => {
+            tracing::warn!("Startup entry points at {}; replacing it", command);
+        }
+        _ => {}
+    }
+    if enabled {
+        register().await?;
+        Ok(Registration::Registered)
+    } else {
+        unregister().await?;
+        Ok(Registration::NotRegistered)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn linux_config_path() -> Result<PathBuf, AutostartError> {
+    let dirs = directories::BaseDirs::new().ok_or(AutostartError::Unsupported)?;
+    Ok(linux_autostart_path(dirs.config_dir()))
+}
+
+#[cfg(target_os = "windows")]
+async fn reg(args: &[String]) -> Result<String, AutostartError> {
+    let output = tokio::process::Command::new("reg")
+        .args(args)
+        .output()
+        .await?;
+    if output.status.success() {
+        return Ok(String::from_utf8_lossy(&output.stdout).into_owned());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+    Err(if windows_denied(&stderr) {
+        AutostartError::PermissionDenied(stderr)
+    } else {
+        AutostartError::Failed(stderr)
+    })
+}
+
+async fn register() -> Result<(), AutostartError> {
+    let exe = launch_path()?;
+    #[cfg(target_os = "linux")]
+    {
+        let path = linux_config_path()?;
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        tokio::fs::write(&path, linux_desktop_entry(&exe)).await?;
+        Ok(())
+    }
+    #[cfg(target_os = "windows")]
+    {
+        reg(&windows_register_args(&exe)).await.map(drop)
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "windows")))]
+    {
+        let _ = exe;
+        Err(AutostartError::Unsupported)
+    }
+}
+
+async fn unregister() -> Result<(), AutostartError> {
+    #[cfg(target_os = "linux")]
+    {
+        match tokio::fs::remove_file(linux_config_path()?).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+    #[cfg(target_os = "windows")]
+    {
+        reg(&windows_unregister_args()).await.map(drop)
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "windows")))]
+    {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn desktop_entry_starts_the_app_with_a_quoted_path() {
+        let entry = linux_desktop_entry(Path::new("/opt/MarinVPN/marinvpn"));
+        assert_eq!(
+            entry,
+            "[Desktop Entry]\nType=Application\nName=MarinVPN\nExec=/opt/MarinVPN/marinvpn\n\
+             Terminal=false\nX-GNOME-Autostart-enabled=true\n"
+        );
+        assert_eq!(
+            linux_registered_command(&entry),
+            Some("/opt/MarinVPN/marinvpn")
+        );
+
+        assert_eq!(
+            linux_exec(Path::new("/home/a b/My $App/marinvpn")),
+            "\"/home/a b/My \\$App/marinvpn\""
+        );
+        assert_eq!(
+            linux_autostart_path(Path::new("/home/a/.config")),
+            Path::new("/home/a/.config/autostart/marinvpn.desktop")
+        );
+    }
+
+    #[test]
+    fn run_key_commands_write_query_and_delete_one_value() {
+        let exe = Path::new(r"C:\Program Files\MarinVPN\marinvpn.exe");
+        assert_eq!(
+            windows_register_args(exe).join(" "),
+            r#"add HKCU\Software\Microsoft\Windows\CurrentVersion\Run /v MarinVPN /t REG_SZ /d "C:\Program Files\MarinVPN\marinvpn.exe" /f"#
+        );
+        assert_eq!(
+            windows_unregister_args().join(" "),
+            r"delete HKCU\Software\Microsoft\Windows\CurrentVersion\Run /v MarinVPN /f"
+        );
+        assert_eq!(
+            windows_query_args().join(" "),
+            r"query HKCU\Software\Microsoft\Windows\CurrentVersion\Run /v MarinVPN"
+        );
+    }
+
+    #[test]
+    fn registration_is_read_back_and_drift_detected() {
+        let output =
+            "\r\nHKEY_CURRENT_USER\\Software\\Microsoft\\Windows\\CurrentVersion\\Run\r\n    \
+                      MarinVPN    REG_SZ    \"C:\\Apps\\marinvpn.exe\"\r\n\r\n";
+        let command = windows_registered_command(output);
+        assert_eq!(command, Some("\"C:\\Apps\\marinvpn.exe\""));
+
+        let here = windows_command(Path::new(r"C:\Apps\marinvpn.exe"));
+        assert_eq!(Registration::of(command, &here), Registration::Registered);
+        let moved = windows_command(Path::new(r"D:\MarinVPN\marinvpn.exe"));
+        assert_eq!(
+            Registration::of(command, &moved),
+            Registration::Stale("\"C:\\Apps\\marinvpn.exe\"".to_string())
+        );
+        assert_eq!(Registration::of(None, &here), Registration::NotRegistered);
+        assert_eq!(
+            windows_registered_command("MarinVPNOld    REG_SZ    x"),
+            None
+        );
+    }
+
+    #[test]
+    fn permission_errors_are_told_apart() {
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert!(matches!(
+            AutostartError::from(denied),
+            AutostartError::PermissionDenied(_)
+        ));
+        assert!(matches!(
+            AutostartError::from(io::Error::from(io::ErrorKind::Other)),
+            AutostartError::Failed(_)
+        ));
+        assert!(windows_denied("ERROR: Access is denied."));
+        assert!(!windows_denied(
+            "ERROR: The system was unable to find the specified registry key or value."
+        ));
+    }
+}
@@ -0,0 +1,312 @@
+//! IP ranges and the set arithmetic split tunneling needs to turn "everything
+//! except these" or "only these" into a WireGuard AllowedIPs list.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// An address block, always stored with its host bits cleared.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// `None` when `prefix` is longer than the address.
+    pub fn new(addr: IpAddr, prefix: u8) -> Option<Self> {
+        (prefix <= max_prefix(addr)).then(|| Self {
+            addr: mask(addr, prefix),
+            prefix,
+        })
+    }
+
+    /// The single address `addr`.
+    pub fn host(addr: IpAddr) -> Self {
+        Self {
+            addr,
+            prefix: max_prefix(addr),
+        }
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    /// Whether every address in `other` is also in `self`.
+    pub fn contains(&self, other: &Cidr) -> bool {
+        self.addr.is_ipv4() == other.addr.is_ipv4()
+            && self.prefix <= other.prefix
+            && mask(other.addr, self.prefix) == self.addr
+    }
+
+    /// The two blocks one bit longer that make up `self`, or `None` for a
+    /// single address.
+    fn halves(&self) -> Option<(Cidr, Cidr)> {
+        if self.prefix == max_prefix(self.addr) {
+            return None;
+        }
+        let prefix = self.prefix + 1;
+        let low = Cidr {
+            addr: self.addr,
+            prefix,
+        };
+        let high_bit = 1u128 << (max_prefix(self.addr) - prefix);
+        let high = Cidr {
+            addr: from_bits(self.addr, to_bits(self.addr) | high_bit),
+            prefix,
+        };
+        Some((low, high))
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidCidr(pub String);
+
+impl fmt::Display for InvalidCidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "not an IP range: {:?}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidCidr {}
+
+/// `ip` or `ip/prefix`. Host bits are cleared, so `10.1.2.3/8` is `10.0.0.0/8`.
+impl FromStr for Cidr {
+    type Err = InvalidCidr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidCidr(s.to_string());
+        let s = s.trim();
+        match s.split_once('/') {
+            Some((addr, prefix)) => {
+                let addr = addr.parse::<IpAddr>().map_err(|_| invalid())?;
+                let prefix = prefix.parse::<u8>().map_err(|_| invalid())?;
+                Cidr::new(addr, prefix).ok_or_else(invalid)
+            }
+            None => s.parse::<IpAddr>().map(Cidr::host).map_err(|_| invalid()),
+        }
+    }
+}
+
+/// A comma separated list such as an AllowedIPs value, with at least one
+/// entry.
+pub fn parse_list(list: &str) -> Result<Vec<Cidr>, InvalidCidr> {
+    let ranges = list
+        .split(',')
+        .map(str::parse)
+        .collect::<Result<Vec<Cidr>, _>>()?;
+    if ranges.is_empty() {
+        return Err(InvalidCidr(list.to_string()));
+    }
+    Ok(ranges)
+}
+
+/// The addresses in `ranges` that are in none of `removed`, as few blocks as
+/// that takes.
+pub fn subtract(ranges: &[Cidr], removed: &[Cidr]) -> Vec<Cidr> {
+    let mut out = Vec::new();
+    for range in ranges {
+        subtract_one(*range, removed, &mut out);
+    }
+    normalize(out)
+}
+
+fn subtract_one(range: Cidr, removed: &[Cidr], out: &mut Vec<Cidr>) {
+    if removed.iter().any(|r| r.contains(&range)) {
+        return;
+    }
+    if !removed.iter().any(|r| range.contains(r)) {
+        out.push(range);
+        return;
+    }
+    // Some removed block lies strictly inside, so `range` is not a single
+    // address and splits.
+    let (low, high) = range.halves().expect("a block holding a smaller one");
+    subtract_one(low, removed, out);
+    subtract_one(high, removed, out);
+}
+
+/// The addresses that are in both `a` and `b`.
+pub fn intersect(a: &[Cidr], b: &[Cidr]) -> Vec<Cidr> {
+    let mut out = Vec::new();
+    for x in a {
+        for y in b {
+            if x.contains(y) {
+                out.push(*y);
+            } else if y.contains(x) {
+                out.push(*x);
+            }
+        }
+    }
+    normalize(out)
+}
+
+/// Sorted, with blocks inside others dropped and sibling halves joined.
+pub fn normalize(mut ranges: Vec<Cidr>) -> Vec<Cidr> {
+    loop {
+        ranges.sort_by_key(|c| (c.addr.is_ipv6(), c.addr, c.prefix));
+        ranges.dedup();
+        let before = ranges.len();
+        let covered: Vec<Cidr> = ranges.clone();
+        ranges.retain(|c| !covered.iter().any(|o| o != c && o.contains(c)));
+
+        let mut merged = Vec::with_capacity(ranges.len());
+        let mut i = 0;
+        while i < ranges.len() {
+            let joined = ranges.get(i + 1).and_then(|next| {
+                let parent = Cidr::new(ranges[i].addr, ranges[i].prefix.checked_sub(1)?)?;
+                parent
+                    .halves()
+                    .filter(|halves| *halves == (ranges[i], *next))
+                    .map(|_| parent)
+            });
+            match joined {
+                Some(parent) => {
+                    merged.push(parent);
+                    i += 2;
+                }
+                None => {
+                    merged.push(ranges[i]);
+                    i += 1;
+                }
+            }
+        }
+        ranges = merged;
+        if ranges.len() == before {
+            return ranges;
+        }
+    }
+}
+
+fn max_prefix(addr: IpAddr) -> u8 {
+    if addr.is_ipv4() {
+        32
+    } else {
+        128
+    }
+}
+
+fn to_bits(addr: IpAddr) -> u128 {
+    match addr {
+        IpAddr::V4(v4) => u32::from(v4) as u128,
+        IpAddr::V6(v6) => u128::from(v6),
+    }
+}
+
+fn from_bits(like: IpAddr, bits: u128) -> IpAddr {
+    match like {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::from(bits as u32)),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::from(bits)),
+    }
+}
+
+fn mask(addr: IpAddr, prefix: u8) -> IpAddr {
+    let host_bits = u32::from(max_prefix(addr) - prefix);
+    let mask = u128::MAX.checked_shl(host_bits).unwrap_or(0);
+    from_bits(addr, to_bits(addr) & mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(s: &str) -> Vec<Cidr> {
+        parse_list(s).unwrap()
+    }
+
+    fn show(ranges: &[Cidr]) -> Vec<String> {
+        ranges.iter().map(Cidr::to_string).collect()
+    }
+
+    #[test]
+    fn parsing_clears_host_bits_and_rejects_bad_prefixes() {
+        assert_eq!(
+            "10.1.2.3/8".parse::<Cidr>().unwrap().to_string(),
+            "10.0.0.0/8"
+        );
+        assert_eq!(
+            "192.0.2.7".parse::<Cidr>().unwrap().to_string(),
+            "192.0.2.7/32"
+        );
+        assert_eq!(
+            "fd00::1/64".parse::<Cidr>().unwrap().to_string(),
+            "fd00::/64"
+        );
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!(parse_list("").is_err());
+        assert!(parse_list("10.0.0.0/8,").is_err());
+    }
+
+    #[test]
+    fn subtracting_a_host_from_everything_leaves_its_complement() {
+        let rest = subtract(&list("0.0.0.0/0"), &list("128.0.0.1"));
+        assert_eq!(rest.len(), 32);
+        assert!(rest
+            .iter()
+            .all(|r| !r.contains(&Cidr::host("128.0.0.1".parse().unwrap()))));
+        assert!(rest.contains(&"0.0.0.0/1".parse().unwrap()));
+        assert!(rest.contains(&"128.0.0.0/32".parse().unwrap()));
+        // Together the pieces cover all but one address.
+        let size: u64 = rest.iter().map(|r| 1u64 << (32 - r.prefix())).sum();
+        assert_eq!(size, (1u64 << 32) - 1);
+    }
+
+    #[test]
+    fn subtracting_private_ranges_from_the_default_route() {
+        let rest = subtract(
+            &list("0.0.0.0/0, ::/0"),
+            &list("10.0.0.0/8, 172.16.0.0/12, 192.168.0.0/16"),
+        );
+        for private in ["10.1.1.1", "172.20.0.1", "192.168.1.1"] {
+            let host = Cidr::host(private.parse().unwrap());
+            assert!(!rest.iter().any(|r| r.contains(&host)), "{}", private);
+        }
+        for public in ["8.8.8.8", "172.32.0.1", "192.169.0.1", "11.0.0.1"] {
+            let host = Cidr::host(public.parse().unwrap());
+            assert!(rest.iter().any(|r| r.contains(&host)), "{}", public);
+        }
+        assert!(rest.contains(&"::/0".parse().unwrap()), "IPv6 is untouched");
+    }
+
+    #[test]
+    fn subtracting_a_covering_or_disjoint_range() {
+        assert!(subtract(&list("10.1.0.0/16"), &list("10.0.0.0/8")).is_empty());
+        assert_eq!(
+            show(&subtract(&list("10.1.0.0/16"), &list("fd00::/8"))),
+            ["10.1.0.0/16"]
+        );
+    }
+
+    #[test]
+    fn intersection_keeps_the_narrower_block() {
+        let both = intersect(
+            &list("0.0.0.0/0, ::/0"),
+            &list("10.2.0.0/16, 203.0.113.5, fd00::/8"),
+        );
+        assert_eq!(show(&both), ["10.2.0.0/16", "203.0.113.5/32", "fd00::/8"]);
+        assert!(intersect(&list("10.0.0.0/8"), &list("192.168.0.0/16")).is_empty());
+    }
+
+    #[test]
+    fn normalize_joins_siblings_and_drops_covered_blocks() {
+        let joined = normalize(list("10.0.0.0/9, 10.128.0.0/9, 10.3.0.0/16, 10.0.0.0/8"));
+        assert_eq!(show(&joined), ["10.0.0.0/8"]);
+        let rejoined = normalize(subtract(&list("0.0.0.0/0"), &list("0.0.0.0/0")));
+        assert!(rejoined.is_empty());
+        let whole = normalize(list("0.0.0.0/1, 128.0.0.0/1"));
+        assert_eq!(show(&whole), ["0.0.0.0/0"]);
+    }
+}
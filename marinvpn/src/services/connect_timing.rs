@@ -0,0 +1,169 @@
+use std::cell::RefCell;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+/// The steps of a connect, from picking a server to the tunnel coming up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Asking the API for the best server, when none was picked.
+    FindServer,
+    /// Fetching the blind-signing key and getting a token signed.
+    BlindSign,
+    /// WireGuard and, with quantum resistance on, ML-KEM key generation.
+    Keys,
+    /// Proof of work, the config request and decapsulating the PQC secret.
+    Config,
+    /// Connectivity check and kill switch, before the tunnel is touched.
+    Checks,
+    /// Bringing the tunnel up, including any fallback transport.
+    Tunnel,
+}
+
+impl Phase {
+    pub fn label(self) -> &'static str {
+        match self {
+            Phase::FindServer => "server",
+            Phase::BlindSign => "blind-sign",
+            Phase::Keys => "keys",
+            Phase::Config => "config",
+            Phase::Checks => "checks",
+            Phase::Tunnel => "tunnel",
+        }
+    }
+}
+
+/// Where the time of one connect went.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConnectTiming {
+    pub total: Duration,
+    /// In the order each phase first ran. A multihop connect fetches both
+    /// configs side by side, so its phases add up both hops.
+    pub phases: Vec<(Phase, Duration)>,
+}
+
+impl ConnectTiming {
+    fn add(&mut self, phase: Phase, took: Duration) {
+        match self.phases.iter_mut().find(|(p, _)| *p == phase) {
+            Some((_, total)) => *total += took,
+            None => self.phases.push((phase, took)),
+        }
+    }
+
+    pub fn phase(&self, phase: Phase) -> Option<Duration> {
+        self.phases
+            .iter()
+            .find(|(p, _)| *p == phase)
+            .map(|(_, d)| *d)
+    }
+
+    /// Time spent outside every phase, e.g. in UI updates between them.
+    pub fn other(&self) -> Duration {
+        let phases: Duration = self.phases.iter().map(|(_, d)| *d).sum();
+        self.total.saturating_sub(phases)
+    }
+
+    /// E.g. `connect took 1620 ms (blind-sign 300, config 400, tunnel 900, other 20)`.
+    pub fn summary(&self) -> String {
+        let mut parts: Vec<String> = self
+            .phases
+            .iter()
+            .map(|(phase, took)| format!("{} {}", phase.label(), took.as_millis()))
+            .collect();
+        let other = self.other();
+        if !other.is_zero() {
+            parts.push(format!("other {}", other.as_millis()));
+        }
+        format!(
+            "connect took {} ms ({})",
+            self.total.as_millis(),
+            parts.join(", ")
+        )
+    }
+}
+
+tokio::task_local! {
+    static CURRENT: RefCell<ConnectTiming>;
+}
+
+/// Runs `connect` and returns how long it and each phase inside it took.
+pub async fn measure<T>(connect: impl Future<Output = T>) -> (T, ConnectTiming) {
+    let started = Instant::now();
+    let (out, mut timing) = CURRENT
+        .scope(RefCell::new(ConnectTiming::default()), async {
+            let out = connect.await;
+            (out, CURRENT.with(RefCell::take))
+        })
+        .await;
+    timing.total = started.elapsed();
+    (out, timing)
+}
+
+/// Runs `step` as `phase`, logging its duration and adding it to the
+/// connect being measured, if any.
+pub async fn timed<T>(phase: Phase, step: impl Future<Output = T>) -> T {
+    let started = Instant::now();
+    let out = step.await;
+    let took = started.elapsed();
+    tracing::debug!(
+        phase = phase.label(),
+        ms = took.as_millis() as u64,
+        "connect phase done"
+    );
+    let _ = CURRENT.try_with(|timing| timing.borrow_mut().add(phase, took));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_lists_phases_in_order_with_the_remainder() {
+        let ms = Duration::from_millis;
+        let mut timing = ConnectTiming {
+            total: ms(1620),
+            ..Default::default()
+        };
+        timing.add(Phase::BlindSign, ms(300));
+        timing.add(Phase::Config, ms(150));
+        timing.add(Phase::Tunnel, ms(900));
+        // A second hop's config adds to the first.
+        timing.add(Phase::Config, ms(250));
+
+        assert_eq!(timing.phase(Phase::Config), Some(ms(400)));
+        assert_eq!(timing.phase(Phase::FindServer), None);
+        assert_eq!(timing.other(), ms(20));
+        assert_eq!(
+            timing.summary(),
+            "connect took 1620 ms (blind-sign 300, config 400, tunnel 900, other 20)"
+        );
+    }
+
+    #[tokio::test]
+    async fn measured_phases_add_up_to_the_total() {
+        let nap = || tokio::time::sleep(Duration::from_millis(15));
+        let ((), timing) = measure(async {
+            timed(Phase::BlindSign, nap()).await;
+            nap().await;
+            timed(Phase::Config, nap()).await;
+            timed(Phase::Tunnel, nap()).await;
+        })
+        .await;
+
+        let phases: Vec<Phase> = timing.phases.iter().map(|(p, _)| *p).collect();
+        assert_eq!(phases, [Phase::BlindSign, Phase::Config, Phase::Tunnel]);
+        for (_, took) in &timing.phases {
+            assert!(*took >= Duration::from_millis(15));
+        }
+        let phase_sum: Duration = timing.phases.iter().map(|(_, d)| *d).sum();
+        assert_eq!(phase_sum + timing.other(), timing.total);
+        assert!(timing.other() >= Duration::from_millis(15));
+    }
+
+    #[tokio::test]
+    async fn steps_outside_a_measured_connect_are_not_kept() {
+        assert_eq!(timed(Phase::Keys, async { 7 }).await, 7);
+        let ((), timing) = measure(async {}).await;
+        assert!(timing.phases.is_empty());
+    }
+}
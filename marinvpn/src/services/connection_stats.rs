@@ -0,0 +1,223 @@
+use crate::models::ConnectionStatus;
+use crate::services::vpn::VpnEvent;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// Failure key for attempts that ended without an error, e.g. cancelled.
+pub const ABORTED: &str = "aborted";
+
+/// How connection attempts on this device have gone, for the user's own
+/// troubleshooting. Kept in the local config file and never sent anywhere.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ConnectionStats {
+    pub attempts: u64,
+    pub successes: u64,
+    /// Failed attempts by [`VpnError::kind`] of the first error seen, or
+    /// [`ABORTED`].
+    ///
+    /// [`VpnError::kind`]: crate::services::vpn::VpnError::kind
+    pub failures: BTreeMap<String, u64>,
+    /// Summed over successful attempts only.
+    pub total_connect_ms: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AttemptOutcome {
+    Connected(Duration),
+    Failed(&'static str),
+}
+
+impl ConnectionStats {
+    pub fn record(&mut self, outcome: &AttemptOutcome) {
+        self.attempts += 1;
+        match outcome {
+            AttemptOutcome::Connected(took) => {
+                self.successes += 1;
+                self.total_connect_ms = self
+                    .total_connect_ms
+                    .saturating_add(took.as_millis() as u64);
+            }
+            AttemptOutcome::Failed(kind) => {
+                *self.failures.entry(kind.to_string()).or_default() += 1;
+            }
+        }
+    }
+
+    pub fn failed(&self) -> u64 {
+        self.failures.values().sum()
+    }
+
+    pub fn average_connect_time(&self) -> Option<Duration> {
+        (self.successes > 0).then(|| Duration::from_millis(self.total_connect_ms / self.successes))
+    }
+}
+
+/// Follows VPN events and reports each attempt once it has either come up or
+/// gone back down. An attempt starts when the status turns `Connecting`.
+#[derive(Debug, Default)]
+pub struct AttemptTracker {
+    started: Option<Instant>,
+    error: Option<&'static str>,
+}
+
+impl AttemptTracker {
+    pub fn observe(&mut self, event: &VpnEvent, now: Instant) -> Option<AttemptOutcome> {
+        match event {
+            VpnEvent::StatusChanged(ConnectionStatus::Connecting) => {
+                if self.started.is_none() {
+                    self.started = Some(now);
+                    self.error = None;
+                }
+                None
+            }
+            VpnEvent::Error(e) if self.started.is_some() => {
+                self.error.get_or_insert(e.kind());
+                None
+            }
+            VpnEvent::StatusChanged(ConnectionStatus::Connected) => {
+                let started = self.started.take()?;
+                self.error = None;
+                Some(AttemptOutcome::Connected(now.duration_since(started)))
+            }
+            VpnEvent::StatusChanged(
+                ConnectionStatus::Disconnecting | ConnectionStatus::Disconnected,
+            ) => {
+                self.started.take()?;
+                Some(AttemptOutcome::Failed(self.error.take().unwrap_or(ABORTED)))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// What the app info page shows for a failure key.
+pub fn failure_label(kind: &str) -> &'static str {
+    match kind {
+        "config_missing" => "Missing configuration",
+        "network_unreachable" => "No internet connection",
+        "connection_failed" => "Connection failed",
+        "interface_error" => "Network interface error",
+        "permission_denied" | "not_root" => "Missing privileges",
+        "driver_missing" => "WireGuard not installed",
+        "firewall_error" => "Firewall error",
+        ABORTED => "Cancelled",
+        _ => "Other",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::vpn::VpnError;
+
+    fn status(s: ConnectionStatus) -> VpnEvent {
+        VpnEvent::StatusChanged(s)
+    }
+
+    /// Feeds `events` to a tracker, one second apart, and records every
+    /// attempt that finishes.
+    fn replay(stats: &mut ConnectionStats, events: Vec<VpnEvent>) {
+        let mut tracker = AttemptTracker::default();
+        let start = Instant::now();
+        for (i, event) in events.iter().enumerate() {
+            let now = start + Duration::from_secs(i as u64);
+            if let Some(outcome) = tracker.observe(event, now) {
+                stats.record(&outcome);
+            }
+        }
+    }
+
+    #[test]
+    fn successes_failures_and_cancellations_are_counted() {
+        let mut stats = ConnectionStats::default();
+        replay(
+            &mut stats,
+            vec![
+                // Up after 1s.
+                status(ConnectionStatus::Connecting),
+                status(ConnectionStatus::Connected),
+                status(ConnectionStatus::Disconnecting),
+                status(ConnectionStatus::Disconnected),
+                // Fails; only the first error counts.
+                status(ConnectionStatus::Connecting),
+                VpnEvent::Error(VpnError::ConnectionFailed("timeout".to_string())),
+                VpnEvent::Error(VpnError::FirewallError("rules".to_string())),
+                status(ConnectionStatus::Disconnected),
+                // Cancelled by the user.
+                status(ConnectionStatus::Connecting),
+                status(ConnectionStatus::Disconnecting),
+                status(ConnectionStatus::Disconnected),
+                // Up after 3s.
+                status(ConnectionStatus::Connecting),
+                VpnEvent::LocationChanged("Sweden".to_string()),
+                VpnEvent::TransportChanged(None),
+                status(ConnectionStatus::Connected),
+            ],
+        );
+
+        assert_eq!(stats.attempts, 4);
+        assert_eq!(stats.successes, 2);
+        assert_eq!(stats.failed(), 2);
+        assert_eq!(stats.failures["connection_failed"], 1);
+        assert_eq!(stats.failures[ABORTED], 1);
+        assert!(!stats.failures.contains_key("firewall_error"));
+        assert_eq!(stats.average_connect_time(), Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn errors_outside_an_attempt_are_not_counted() {
+        let mut stats = ConnectionStats::default();
+        replay(
+            &mut stats,
+            vec![
+                VpnEvent::Error(VpnError::NetworkUnreachable),
+                status(ConnectionStatus::Disconnected),
+                status(ConnectionStatus::Connecting),
+                status(ConnectionStatus::Connected),
+                // A dropped tunnel is not a failed attempt.
+                VpnEvent::Error(VpnError::InterfaceError("gone".to_string())),
+                status(ConnectionStatus::Disconnected),
+            ],
+        );
+
+        assert_eq!((stats.attempts, stats.successes, stats.failed()), (1, 1, 0));
+    }
+
+    #[test]
+    fn repeated_connecting_does_not_restart_the_clock() {
+        let mut stats = ConnectionStats::default();
+        replay(
+            &mut stats,
+            vec![
+                status(ConnectionStatus::Connecting),
+                status(ConnectionStatus::Connecting),
+                status(ConnectionStatus::Connected),
+            ],
+        );
+        assert_eq!(stats.attempts, 1);
+        assert_eq!(stats.average_connect_time(), Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn aggregates_accumulate_across_sessions() {
+        let mut stats = ConnectionStats::default();
+        assert_eq!(stats.average_connect_time(), None);
+        for _ in 0..3 {
+            stats.record(&AttemptOutcome::Failed("driver_missing"));
+        }
+        stats.record(&AttemptOutcome::Connected(Duration::from_millis(1500)));
+        stats.record(&AttemptOutcome::Connected(Duration::from_millis(500)));
+
+        assert_eq!(stats.attempts, 5);
+        assert_eq!(stats.failures["driver_missing"], 3);
+        assert_eq!(stats.average_connect_time(), Some(Duration::from_secs(1)));
+
+        // Survives a round trip through the config file.
+        let saved = serde_json::to_string(&stats).unwrap();
+        assert_eq!(
+            serde_json::from_str::<ConnectionStats>(&saved).unwrap(),
+            stats
+        );
+    }
+}
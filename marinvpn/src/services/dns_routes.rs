@@ -0,0 +1,277 @@
+//! Split DNS: which resolver answers for which domains, as systemd-resolved
+//! link domains on Linux and Name Resolution Policy Table rules on Windows.
+
+use crate::models::{DnsRoute, DnsRouteVia};
+use std::fmt;
+
+/// Marks the NRPT rules MarinVPN adds, so they can be found again.
+pub const NRPT_COMMENT: &str = "MarinVPN";
+
+const MAX_DOMAIN_LEN: usize = 253;
+const MAX_LABEL_LEN: usize = 63;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidDomain(pub String);
+
+impl fmt::Display for InvalidDomain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "not a domain name: {:?}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidDomain {}
+
+/// `domain` lowercased and without leading or trailing dots, if every label
+/// is letters, digits and inner hyphens. Single labels such as `corp` are
+/// allowed.
+pub fn parse_domain(domain: &str) -> Result<String, InvalidDomain> {
+    let invalid = || InvalidDomain(domain.to_string());
+    let name = domain.trim().trim_matches('.').to_lowercase();
+    if name.is_empty() || name.len() > MAX_DOMAIN_LEN {
+        return Err(invalid());
+    }
+    let valid_label = |label: &str| {
+        !label.is_empty()
+            && label.len() <= MAX_LABEL_LEN
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    if !name.split('.').all(valid_label) {
+        return Err(invalid());
+    }
+    Ok(name)
+}
+
+/// The routes whose domain is still valid, e.g. after a hand-edited
+/// settings file.
+fn valid(routes: &[DnsRoute]) -> impl Iterator<Item = (String, &DnsRoute)> {
+    routes
+        .iter()
+        .filter_map(|route| parse_domain(&route.domain).ok().map(|d| (d, route)))
+}
+
+/// `resolvectl domain` entries for routes through `via`: `~domain` only
+/// routes queries, a plain `domain` also becomes a search domain.
+fn link_domains(routes: &[DnsRoute], via: DnsRouteVia) -> Vec<String> {
+    valid(routes)
+        .filter(|(_, route)| route.via == via)
+        .map(|(domain, route)| {
+            if route.search {
+                domain
+            } else {
+                format!("~{}", domain)
+            }
+        })
+        .collect()
+}
+
+/// `resolvectl domain` arguments for the tunnel link. `~.` makes it answer
+/// for every name no other link claims.
+pub fn tunnel_link_domains(routes: &[DnsRoute], unmatched_local: bool) -> Vec<String> {
+    let mut domains = Vec::new();
+    if !unmatched_local {
+        domains.push("~.".to_string());
+    }
+    domains.extend(link_domains(routes, DnsRouteVia::Tunnel));
+    domains
+}
+
+/// `resolvectl domain` arguments added to the local network's link. A more
+/// specific domain there wins over the tunnel's `~.`.
+pub fn local_link_domains(routes: &[DnsRoute]) -> Vec<String> {
+    link_domains(routes, DnsRouteVia::Local)
+}
+
+/// Search domains in the order they were added.
+pub fn search_domains(routes: &[DnsRoute]) -> Vec<String> {
+    valid(routes)
+        .filter(|(_, route)| route.search)
+        .map(|(domain, _)| domain)
+        .collect()
+}
+
+/// The device of the main table's default route, in `ip route show default`
+/// output. wg-quick routes through its own table, so this is still the local
+/// network while connected.
+pub fn default_route_device(output: &str) -> Option<String> {
+    output.lines().find_map(|line| {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.first() != Some(&"default") {
+            return None;
+        }
+        let at = words.iter().position(|w| *w == "dev")?;
+        words.get(at + 1).map(|dev| dev.to_string())
+    })
+}
+
+/// The domains in `resolvectl domain <link>` output, e.g.
+/// `Link 3 (wlan0): home.lan ~example.com`.
+pub fn link_domains_from_status(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .next()
+        .and_then(|line| line.split_once("):"))
+        .map(|(_, domains)| domains.split_whitespace().map(String::from).collect())
+        .unwrap_or_default()
+}
+
+/// PowerShell commands adding an NRPT rule per route. Tunnel routes go to
+/// `tunnel_dns`, local ones to `local_dns`. Routes whose resolver list is
+/// empty are left out.
+pub fn windows_nrpt_rules(
+    routes: &[DnsRoute],
+    tunnel_dns: &[String],
+    local_dns: &[String],
+) -> Vec<String> {
+    valid(routes)
+        .filter_map(|(domain, route)| {
+            let servers = match route.via {
+                DnsRouteVia::Tunnel => tunnel_dns,
+                DnsRouteVia::Local => local_dns,
+            };
+            if servers.is_empty() {
+                return None;
+            }
+            let servers = servers
+                .iter()
+                .map(|s| format!("'{}'", s.replace('\'', "''")))
+                .collect::<Vec<_>>()
+                .join(",");
+            // `.corp.example` only matches names below it.
+            Some(format!(
+                "Add-DnsClientNrptRule -Namespace '{domain}','.{domain}' -NameServers {servers} -Comment '{NRPT_COMMENT}'"
+            ))
+        })
+        .collect()
+}
+
+/// Removes every NRPT rule [`windows_nrpt_rules`] added.
+pub fn windows_nrpt_cleanup() -> String {
+    format!(
+        "Get-DnsClientNrptRule | Where-Object {{ $_.Comment -eq '{NRPT_COMMENT}' }} | ForEach-Object {{ Remove-DnsClientNrptRule -Name $_.Name -Force }}"
+    )
+}
+
+/// Replaces the global DNS suffix search list with `suffixes`.
+pub fn windows_search_list(suffixes: &[String]) -> String {
+    let list = suffixes
+        .iter()
+        .map(|s| format!("'{}'", s.replace('\'', "''")))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!("Set-DnsClientGlobalSetting -SuffixSearchList @({list})")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(domain: &str, via: DnsRouteVia, search: bool) -> DnsRoute {
+        DnsRoute {
+            domain: domain.to_string(),
+            via,
+            search,
+        }
+    }
+
+    #[test]
+    fn domains_are_normalized_or_rejected() {
+        assert_eq!(parse_domain(" Corp.Example. ").unwrap(), "corp.example");
+        assert_eq!(parse_domain(".home.lan").unwrap(), "home.lan");
+        assert_eq!(parse_domain("corp").unwrap(), "corp");
+        assert_eq!(
+            parse_domain("xn--bcher-kva.example").unwrap(),
+            "xn--bcher-kva.example"
+        );
+        for bad in [
+            "",
+            ".",
+            "corp..example",
+            "-corp.example",
+            "corp-.example",
+            "corp example",
+            "*.example",
+            "corp_example.com",
+            &format!("{}.com", "a".repeat(64)),
+            &"a.".repeat(128),
+        ] {
+            assert!(parse_domain(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn without_routes_the_tunnel_answers_for_everything() {
+        assert_eq!(tunnel_link_domains(&[], false), ["~."]);
+        assert!(local_link_domains(&[]).is_empty());
+        assert!(tunnel_link_domains(&[], true).is_empty());
+    }
+
+    #[test]
+    fn routes_go_to_their_links_and_search_domains_lose_the_tilde() {
+        let routes = [
+            route("corp.example", DnsRouteVia::Tunnel, true),
+            route("git.corp.example", DnsRouteVia::Tunnel, false),
+            route("home.lan", DnsRouteVia::Local, false),
+            route("printer.local", DnsRouteVia::Local, true),
+            route("not valid", DnsRouteVia::Tunnel, true),
+        ];
+
+        assert_eq!(
+            tunnel_link_domains(&routes, false),
+            ["~.", "corp.example", "~git.corp.example"]
+        );
+        assert_eq!(
+            tunnel_link_domains(&routes, true),
+            ["corp.example", "~git.corp.example"]
+        );
+        assert_eq!(local_link_domains(&routes), ["~home.lan", "printer.local"]);
+        assert_eq!(search_domains(&routes), ["corp.example", "printer.local"]);
+    }
+
+    #[test]
+    fn local_link_is_read_from_ip_and_resolvectl() {
+        let routes = "default via 192.168.1.1 dev wlp3s0 proto dhcp src 192.168.1.20 metric 600\n\
+            192.168.1.0/24 dev wlp3s0 proto kernel scope link\n";
+        assert_eq!(default_route_device(routes).as_deref(), Some("wlp3s0"));
+        assert_eq!(default_route_device("10.0.0.0/8 dev eth0\n"), None);
+
+        assert_eq!(
+            link_domains_from_status("Link 3 (wlp3s0): home.lan ~example.com\n"),
+            ["home.lan", "~example.com"]
+        );
+        assert!(link_domains_from_status("Link 3 (wlp3s0):\n").is_empty());
+    }
+
+    #[test]
+    fn nrpt_rules_point_each_domain_at_its_resolver() {
+        let routes = [
+            route("corp.example", DnsRouteVia::Tunnel, false),
+            route("home.lan", DnsRouteVia::Local, false),
+        ];
+        let tunnel = ["10.64.0.1".to_string()];
+        let local = ["192.168.1.1".to_string(), "fe80::1".to_string()];
+
+        assert_eq!(
+            windows_nrpt_rules(&routes, &tunnel, &local),
+            [
+                "Add-DnsClientNrptRule -Namespace 'corp.example','.corp.example' -NameServers '10.64.0.1' -Comment 'MarinVPN'",
+                "Add-DnsClientNrptRule -Namespace 'home.lan','.home.lan' -NameServers '192.168.1.1','fe80::1' -Comment 'MarinVPN'",
+            ]
+        );
+        assert_eq!(
+            windows_nrpt_rules(&routes, &tunnel, &[]).len(),
+            1,
+            "no local resolver to send home.lan to"
+        );
+        assert!(windows_nrpt_cleanup().contains("$_.Comment -eq 'MarinVPN'"));
+        assert_eq!(
+            windows_search_list(&["corp.example".to_string(), "home.lan".to_string()]),
+            "Set-DnsClientGlobalSetting -SuffixSearchList @('corp.example','home.lan')"
+        );
+        assert_eq!(
+            windows_search_list(&[]),
+            "Set-DnsClientGlobalSetting -SuffixSearchList @()"
+        );
+    }
+}
@@ -0,0 +1,774 @@
+use crate::models::DohProvider;
+use crate::services::request_log;
+use serde_json::Value;
+use std::future::Future;
+use std::net::IpAddr;
+use std::time::Duration;
+use thiserror::Error;
+
+const ATTEMPTS_PER_PROVIDER: u32 = 3;
+const FIRST_RETRY_DELAY: Duration = Duration::from_millis(250);
+/// How long one provider gets, retries included, before the next is asked.
+const PROVIDER_TIMEOUT: Duration = Duration::from_secs(3);
+/// How long a whole lookup gets, however many providers are left. Relay
+/// lookups hold up the kill switch, and the system resolver is asked once
+/// this runs out.
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(4);
+/// Replaces the configured providers: a comma-separated list of built-in
+/// provider names (`cloudflare`, `google`, `quad9`) and `https` URLs.
+const PROVIDERS_ENV: &str = "MARIN_DOH_PROVIDERS";
+
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum DohError {
+    #[error("{provider} could not be reached: {message}")]
+    Network { provider: String, message: String },
+
+    #[error("{provider} answered with HTTP {status}")]
+    HttpStatus { provider: String, status: u16 },
+
+    #[error("{provider} sent an unreadable answer: {message}")]
+    Parse { provider: String, message: String },
+
+    #[error("{provider} has no address for {hostname}")]
+    NoAnswer { provider: String, hostname: String },
+
+    /// Only addresses no public API can live at, e.g. `0.0.0.0` or
+    /// `127.0.0.1`, which is how some censors answer blocked names.
+    #[error("{provider} answered {hostname} with unusable address {address}")]
+    Bogus {
+        provider: String,
+        hostname: String,
+        address: IpAddr,
+    },
+
+    #[error("No DoH provider configured")]
+    NoProviders,
+}
+
+impl DohError {
+    pub fn provider(&self) -> Option<&str> {
+        match self {
+            DohError::Network { provider, .. }
+            | DohError::HttpStatus { provider, .. }
+            | DohError::Parse { provider, .. }
+            | DohError::NoAnswer { provider, .. }
+            | DohError::Bogus { provider, .. } => Some(provider),
+            DohError::NoProviders => None,
+        }
+    }
+
+    /// Whether asking the same provider again could help. A provider that
+    /// answered with garbage or without a record will do so again.
+    fn is_transient(&self) -> bool {
+        match self {
+            DohError::Network { .. } => true,
+            DohError::HttpStatus { status, .. } => *status == 429 || *status >= 500,
+            _ => false,
+        }
+    }
+}
+
+/// The public addresses found for a host.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ResolvedHost {
+    pub v4: Vec<String>,
+    pub v6: Vec<String>,
+}
+
+/// Asks one provider for the A record of `hostname`.
+pub async fn query(
+    client: &reqwest::Client,
+    provider: &DohProvider,
+    hostname: &str,
+) -> Result<String, DohError> {
+    let body = fetch(client, provider, hostname, "A").await?;
+    parse_answer(&provider.name, hostname, &body)
+}
+
+/// Asks one provider for the A and AAAA records of `hostname` at once.
+/// Either is an answer, so IPv6-only hosts resolve too. When both fail, the
+/// A lookup's error is returned.
+pub async fn query_host(
+    client: &reqwest::Client,
+    provider: &DohProvider,
+    hostname: &str,
+) -> Result<ResolvedHost, DohError> {
+    let (a, aaaa) = tokio::join!(
+        fetch(client, provider, hostname, "A"),
+        fetch(client, provider, hostname, "AAAA"),
+    );
+    let v4 = a.and_then(|body| parse_addresses(&provider.name, hostname, &body, false));
+    let v6 = aaaa.and_then(|body| parse_addresses(&provider.name, hostname, &body, true));
+    let text = |ips: Vec<IpAddr>| ips.iter().map(ToString::to_string).collect();
+    match (v4, v6) {
+        (Err(e), Err(_)) => Err(e),
+        (v4, v6) => Ok(ResolvedHost {
+            v4: v4.map(text).unwrap_or_default(),
+            v6: v6.map(text).unwrap_or_default(),
+        }),
+    }
+}
+
+/// Fetches the raw JSON answer for `record_type` records of `hostname`.
+pub async fn fetch(
+    client: &reqwest::Client,
+    provider: &DohProvider,
+    hostname: &str,
+    record_type: &str,
+) -> Result<Vec<u8>, DohError> {
+    let network = |e: reqwest::Error| DohError::Network {
+        provider: provider.name.clone(),
+        message: e.to_string(),
+    };
+    let request = client
+        .get(&provider.url)
+        .header("Accept", "application/dns-json")
+        .query(&[("name", hostname), ("type", record_type)]);
+    let res = request_log::send(request).await.map_err(network)?;
+    if !res.status().is_success() {
+        return Err(DohError::HttpStatus {
+            provider: provider.name.clone(),
+            status: res.status().as_u16(),
+        });
+    }
+    Ok(res.bytes().await.map_err(network)?.to_vec())
+}
+
+/// The answer records of a JSON DoH response, or why there are none.
+pub fn answer_records(provider: &str, hostname: &str, body: &[u8]) -> Result<Vec<Value>, DohError> {
+    let parse = |message: String| DohError::Parse {
+        provider: provider.to_string(),
+        message,
+    };
+    let mut json: Value = serde_json::from_slice(body).map_err(|e| parse(e.to_string()))?;
+    let no_answer = || DohError::NoAnswer {
+        provider: provider.to_string(),
+        hostname: hostname.to_string(),
+    };
+    // Anything but NOERROR (NXDOMAIN, SERVFAIL, REFUSED...) means no
+    // usable answer, whatever else the body holds.
+    if json.get("Status").and_then(Value::as_u64).unwrap_or(0) != 0 {
+        return Err(no_answer());
+    }
+    match json.get_mut("Answer").map(Value::take) {
+        Some(Value::Array(answers)) => Ok(answers),
+        Some(_) => Err(parse("\"Answer\" is not a list".to_string())),
+        None => Err(no_answer()),
+    }
+}
+
+/// Picks the first public IPv4 address out of a JSON DoH response.
+pub fn parse_answer(provider: &str, hostname: &str, body: &[u8]) -> Result<String, DohError> {
+    parse_addresses(provider, hostname, body, false).map(|ips| ips[0].to_string())
+}
+
+/// Every public IPv4 address, or IPv6 with `v6`, in a JSON DoH response, in
+/// the order given. Never empty.
+fn parse_addresses(
+    provider: &str,
+    hostname: &str,
+    body: &[u8],
+    v6: bool,
+) -> Result<Vec<IpAddr>, DohError> {
+    let record_type = if v6 { 28 } else { 1 };
+    let answers = answer_records(provider, hostname, body)?;
+    let addresses: Vec<IpAddr> = answers
+        .iter()
+        .filter(|answer| {
+            answer
+                .get("type")
+                .and_then(Value::as_u64)
+                .is_none_or(|t| t == record_type)
+        })
+        .filter_map(|answer| answer.get("data")?.as_str()?.parse().ok())
+        .filter(|ip: &IpAddr| ip.is_ipv6() == v6)
+        .collect();
+    let public: Vec<IpAddr> = addresses.iter().copied().filter(is_public).collect();
+    match addresses.first() {
+        _ if !public.is_empty() => Ok(public),
+        Some(&address) => Err(DohError::Bogus {
+            provider: provider.to_string(),
+            hostname: hostname.to_string(),
+            address,
+        }),
+        None => Err(DohError::NoAnswer {
+            provider: provider.to_string(),
+            hostname: hostname.to_string(),
+        }),
+    }
+}
+
+fn is_public(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_multicast())
+        }
+        // fc00::/7 is unique local and fe80::/10 link-local.
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                || first & 0xfe00 == 0xfc00
+                || first & 0xffc0 == 0xfe80)
+        }
+    }
+}
+
+/// The providers to ask: the ones `MARIN_DOH_PROVIDERS` lists when it is
+/// set, otherwise `configured`. Applied where `settings.doh_providers` is
+/// read, not by [`resolve_with`], so providers passed in are used as given.
+pub fn active_providers(configured: &[DohProvider]) -> Vec<DohProvider> {
+    let Ok(spec) = std::env::var(PROVIDERS_ENV) else {
+        return configured.to_vec();
+    };
+    let listed = parse_providers(&spec);
+    if listed.is_empty() {
+        tracing::warn!("{} lists no usable provider, ignoring it", PROVIDERS_ENV);
+        return configured.to_vec();
+    }
+    listed
+}
+
+/// Reads a comma-separated provider list. Names of the built-in providers
+/// match without regard to case; an `https` URL is used as is and named
+/// after its host. Anything else is skipped.
+pub fn parse_providers(spec: &str) -> Vec<DohProvider> {
+    let builtin = DohProvider::defaults();
+    spec.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            if let Some(known) = builtin.iter().find(|p| p.name.eq_ignore_ascii_case(entry)) {
+                return Some(known.clone());
+            }
+            let host = entry
+                .strip_prefix("https://")
+                .and_then(|rest| rest.split(['/', '?', ':']).next())
+                .unwrap_or(entry);
+            let custom = DohProvider::new(host, entry);
+            if !custom.is_valid() {
+                tracing::warn!("Ignoring {} entry {}", PROVIDERS_ENV, entry);
+                return None;
+            }
+            Some(custom)
+        })
+        .collect()
+}
+
+/// Resolves `hostname` through `providers` in order, skipping any that are
+/// not plain `https` URLs. Transient failures are retried with a doubling
+/// delay, for up to three seconds per provider, before moving on, and the
+/// whole lookup stops after four seconds. `on_fallback` hears about every
+/// provider given up on while another is still left to try. When all fail,
+/// or time runs out, the last provider's error is returned.
+pub async fn resolve_with<T, F, Fut>(
+    providers: &[DohProvider],
+    hostname: &str,
+    query: F,
+    on_fallback: impl FnMut(&DohError),
+) -> Result<T, DohError>
+where
+    F: FnMut(DohProvider) -> Fut,
+    Fut: Future<Output = Result<T, DohError>>,
+{
+    resolve_with_delay(
+        providers,
+        hostname,
+        FIRST_RETRY_DELAY,
+        PROVIDER_TIMEOUT,
+        LOOKUP_TIMEOUT,
+        query,
+        on_fallback,
+    )
+    .await
+}
+
+async fn resolve_with_delay<T, F, Fut>(
+    providers: &[DohProvider],
+    hostname: &str,
+    first_delay: Duration,
+    provider_timeout: Duration,
+    lookup_timeout: Duration,
+    mut query: F,
+    mut on_fallback: impl FnMut(&DohError),
+) -> Result<T, DohError>
+where
+    F: FnMut(DohProvider) -> Fut,
+    Fut: Future<Output = Result<T, DohError>>,
+{
+    let providers: Vec<&DohProvider> = providers
+        .iter()
+        .filter(|p| {
+            let usable = p.is_valid();
+            if !usable {
+                tracing::warn!(
+                    "Ignoring DoH provider {} ({}): not an https URL",
+                    p.name,
+                    p.url
+                );
+            }
+            usable
+        })
+        .collect();
+    let deadline = tokio::time::Instant::now() + lookup_timeout;
+    let mut last_error = DohError::NoProviders;
+    for (index, &provider) in providers.iter().enumerate() {
+        let budget =
+            provider_timeout.min(deadline.saturating_duration_since(tokio::time::Instant::now()));
+        let attempts = async {
+            let mut delay = first_delay;
+            let mut attempt = 1;
+            loop {
+                match query(provider.clone()).await {
+                    Err(e) if e.is_transient() && attempt < ATTEMPTS_PER_PROVIDER => {
+                        tracing::warn!("DoH query failed ({}), retrying", e);
+                        attempt += 1;
+                        tokio::time::sleep(delay).await;
+                        delay *= 2;
+                    }
+                    result => break result,
+                }
+            }
+        };
+        let error = match tokio::time::timeout(budget, attempts).await {
+            Ok(Ok(answer)) => {
+                tracing::debug!("DoH: {} answered for {}", provider.name, hostname);
+                return Ok(answer);
+            }
+            Ok(Err(e)) => e,
+            Err(_) => DohError::Network {
+                provider: provider.name.clone(),
+                message: format!("no answer within {:?}", budget),
+            },
+        };
+        let out_of_time = tokio::time::Instant::now() >= deadline;
+        if index + 1 < providers.len() && !out_of_time {
+            on_fallback(&error);
+        }
+        last_error = error;
+        if out_of_time {
+            break;
+        }
+    }
+    Err(last_error)
+}
+
+/// A stand-in DoH server for tests.
+#[cfg(test)]
+pub(crate) mod mock {
+    use crate::models::DohProvider;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Answers a single HTTP request with `status` and `body`.
+    pub async fn serve_once(status: &'static str, body: &'static str) -> DohProvider {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/dns-query", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 1024];
+            let _ = socket.read(&mut request).await;
+            respond(&mut socket, status, body).await;
+        });
+        DohProvider::new("Local", &url)
+    }
+
+    /// Answers an A and an AAAA query, in either order, with `a` and `aaaa`.
+    pub async fn serve_records(a: &'static str, aaaa: &'static str) -> DohProvider {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/dns-query", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            for _ in 0..2 {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = [0u8; 1024];
+                let read = socket.read(&mut request).await.unwrap_or(0);
+                let asks_aaaa = String::from_utf8_lossy(&request[..read]).contains("type=AAAA");
+                respond(&mut socket, "200 OK", if asks_aaaa { aaaa } else { a }).await;
+            }
+        });
+        DohProvider::new("Local", &url)
+    }
+
+    async fn respond(socket: &mut tokio::net::TcpStream, status: &str, body: &str) {
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/dns-json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        socket.write_all(response.as_bytes()).await.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::{serve_once, serve_records};
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::sync::Mutex;
+
+    const HOST: &str = "api.marinvpn.example";
+
+    fn provider(name: &str) -> DohProvider {
+        DohProvider::new(name, &format!("https://{}.example/dns-query", name))
+    }
+
+    fn network_error(provider: &DohProvider) -> DohError {
+        DohError::Network {
+            provider: provider.name.clone(),
+            message: "connection reset".to_string(),
+        }
+    }
+
+    #[test]
+    fn picks_the_first_ipv4_answer() {
+        let body = br#"{"Status":0,"Answer":[
+            {"type":5,"data":"edge.example."},
+            {"type":28,"data":"2001:db8::1"},
+            {"type":1,"data":"192.0.2.7"},
+            {"type":1,"data":"192.0.2.8"}
+        ]}"#;
+        assert_eq!(parse_answer("Test", HOST, body).unwrap(), "192.0.2.7");
+    }
+
+    #[test]
+    fn unreadable_and_empty_answers_are_told_apart() {
+        assert!(matches!(
+            parse_answer("Test", HOST, b"<html>blocked</html>"),
+            Err(DohError::Parse { .. })
+        ));
+        assert!(matches!(
+            parse_answer("Test", HOST, br#"{"Answer":"192.0.2.7"}"#),
+            Err(DohError::Parse { .. })
+        ));
+        // NXDOMAIN comes back without an Answer section at all.
+        assert_eq!(
+            parse_answer("Test", HOST, br#"{"Status":3}"#),
+            Err(DohError::NoAnswer {
+                provider: "Test".to_string(),
+                hostname: HOST.to_string(),
+            })
+        );
+        assert!(matches!(
+            parse_answer("Test", HOST, br#"{"Answer":[{"type":5,"data":"1.2.3"}]}"#),
+            Err(DohError::NoAnswer { .. })
+        ));
+    }
+
+    #[test]
+    fn censored_and_failed_lookups_are_not_answers() {
+        let sinkholed = br#"{"Status":0,"Answer":[
+            {"type":1,"data":"0.0.0.0"},
+            {"type":1,"data":"10.10.34.35"}
+        ]}"#;
+        assert_eq!(
+            parse_answer("Test", HOST, sinkholed),
+            Err(DohError::Bogus {
+                provider: "Test".to_string(),
+                hostname: HOST.to_string(),
+                address: Ipv4Addr::UNSPECIFIED.into(),
+            })
+        );
+        let mixed = br#"{"Answer":[{"data":"127.0.0.1"},{"data":"203.0.113.9"}]}"#;
+        assert_eq!(parse_answer("Test", HOST, mixed).unwrap(), "203.0.113.9");
+
+        // SERVFAIL with a stray record is still a failure.
+        let servfail = br#"{"Status":2,"Answer":[{"type":1,"data":"203.0.113.9"}]}"#;
+        assert!(matches!(
+            parse_answer("Test", HOST, servfail),
+            Err(DohError::NoAnswer { .. })
+        ));
+    }
+
+    #[test]
+    fn aaaa_answers_skip_local_addresses() {
+        let body = br#"{"Status":0,"Answer":[
+            {"type":28,"data":"fe80::1"},
+            {"type":1,"data":"192.0.2.7"},
+            {"type":28,"data":"2001:db8::1"}
+        ]}"#;
+        assert_eq!(
+            parse_addresses("Test", HOST, body, true).unwrap(),
+            ["2001:db8::1".parse::<IpAddr>().unwrap()]
+        );
+        let loopback = br#"{"Answer":[{"type":28,"data":"::1"}]}"#;
+        assert!(matches!(
+            parse_addresses("Test", HOST, loopback, true),
+            Err(DohError::Bogus { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn hosts_resolve_with_either_address_family() {
+        let client = reqwest::Client::new();
+
+        let dual = serve_records(
+            r#"{"Answer":[{"type":1,"data":"198.51.100.4"}]}"#,
+            r#"{"Answer":[{"type":28,"data":"2001:db8::4"}]}"#,
+        )
+        .await;
+        assert_eq!(
+            query_host(&client, &dual, HOST).await.unwrap(),
+            ResolvedHost {
+                v4: vec!["198.51.100.4".to_string()],
+                v6: vec!["2001:db8::4".to_string()],
+            }
+        );
+
+        let v6_only = serve_records(
+            r#"{"Status":0}"#,
+            r#"{"Answer":[{"type":28,"data":"2001:db8::6"}]}"#,
+        )
+        .await;
+        let host = query_host(&client, &v6_only, HOST).await.unwrap();
+        assert!(host.v4.is_empty());
+        assert_eq!(host.v6, ["2001:db8::6"]);
+
+        let neither = serve_records(r#"{"Status":3}"#, r#"{"Status":3}"#).await;
+        assert!(matches!(
+            query_host(&client, &neither, HOST).await,
+            Err(DohError::NoAnswer { .. })
+        ));
+    }
+
+    #[test]
+    fn provider_lists_take_names_and_https_urls() {
+        let spec = " quad9, https://doh.example/dns-query,, bogus, http://plain.example ";
+        let providers = parse_providers(spec);
+        assert_eq!(
+            providers,
+            [
+                DohProvider::new("Quad9", "https://dns.quad9.net:5053/dns-query"),
+                DohProvider::new("doh.example", "https://doh.example/dns-query"),
+            ]
+        );
+        assert!(parse_providers("").is_empty());
+    }
+
+    #[tokio::test]
+    async fn provider_that_never_answers_is_given_up_on() {
+        let providers = [provider("first"), provider("second")];
+        let mut fallbacks = Vec::new();
+
+        let ip = resolve_with_delay(
+            &providers,
+            HOST,
+            Duration::ZERO,
+            Duration::from_millis(50),
+            LOOKUP_TIMEOUT,
+            |p| async move {
+                if p.name == "first" {
+                    std::future::pending().await
+                } else {
+                    Ok("192.0.2.1".to_string())
+                }
+            },
+            |e| fallbacks.push(e.clone()),
+        )
+        .await;
+
+        assert_eq!(ip.unwrap(), "192.0.2.1");
+        assert_eq!(
+            fallbacks,
+            [DohError::Network {
+                provider: "first".to_string(),
+                message: "no answer within 50ms".to_string(),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn the_whole_lookup_shares_one_deadline() {
+        let providers = [provider("first"), provider("second"), provider("third")];
+        let asked = Mutex::new(Vec::new());
+        let mut fallbacks = Vec::new();
+
+        let started = std::time::Instant::now();
+        let result: Result<String, _> = resolve_with_delay(
+            &providers,
+            HOST,
+            Duration::ZERO,
+            Duration::from_millis(200),
+            Duration::from_millis(300),
+            |p| {
+                asked.lock().unwrap().push(p.name.clone());
+                std::future::pending()
+            },
+            |e| fallbacks.push(e.provider().map(str::to_string)),
+        )
+        .await;
+
+        // The second provider only gets what is left, and the third none.
+        assert!(started.elapsed() < Duration::from_millis(500));
+        assert_eq!(result.unwrap_err().provider(), Some("second"));
+        assert_eq!(*asked.lock().unwrap(), ["first", "second"]);
+        assert_eq!(fallbacks, [Some("first".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn query_reports_each_failure_category() {
+        let client = reqwest::Client::new();
+
+        let ok = serve_once("200 OK", r#"{"Answer":[{"data":"198.51.100.4"}]}"#).await;
+        assert_eq!(query(&client, &ok, HOST).await.unwrap(), "198.51.100.4");
+
+        let refused = serve_once("503 Service Unavailable", "").await;
+        assert_eq!(
+            query(&client, &refused, HOST).await,
+            Err(DohError::HttpStatus {
+                provider: "Local".to_string(),
+                status: 503,
+            })
+        );
+
+        let garbled = serve_once("200 OK", "not json").await;
+        assert!(matches!(
+            query(&client, &garbled, HOST).await,
+            Err(DohError::Parse { .. })
+        ));
+
+        let closed = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}/dns-query", listener.local_addr().unwrap())
+        };
+        assert!(matches!(
+            query(&client, &DohProvider::new("Gone", &closed), HOST).await,
+            Err(DohError::Network { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn blocked_provider_is_retried_then_the_next_one_answers() {
+        let providers = [provider("first"), provider("second")];
+        let asked = Mutex::new(Vec::new());
+        let mut fallbacks = Vec::new();
+
+        let ip = resolve_with_delay(
+            &providers,
+            HOST,
+            Duration::ZERO,
+            PROVIDER_TIMEOUT,
+            LOOKUP_TIMEOUT,
+            |p| {
+                asked.lock().unwrap().push(p.name.clone());
+                let result = if p.name == "first" {
+                    Err(network_error(&p))
+                } else {
+                    Ok("192.0.2.1".to_string())
+                };
+                async move { result }
+            },
+            |e| fallbacks.push(e.clone()),
+        )
+        .await;
+
+        assert_eq!(ip.unwrap(), "192.0.2.1");
+        assert_eq!(
+            *asked.lock().unwrap(),
+            ["first", "first", "first", "second"]
+        );
+        assert_eq!(fallbacks, vec![network_error(&providers[0])]);
+    }
+
+    #[tokio::test]
+    async fn blocked_default_provider_falls_through_in_order() {
+        let mut providers = crate::models::SettingsState::default().doh_providers;
+        providers.insert(0, DohProvider::new("Plain", "http://doh.example/dns-query"));
+        let asked = Mutex::new(Vec::new());
+        let mut fallbacks = Vec::new();
+
+        let ip = resolve_with_delay(
+            &providers,
+            HOST,
+            Duration::ZERO,
+            PROVIDER_TIMEOUT,
+            LOOKUP_TIMEOUT,
+            |p| {
+                asked.lock().unwrap().push(p.name.clone());
+                let result = match p.name.as_str() {
+                    "Cloudflare" => Err(DohError::HttpStatus {
+                        provider: p.name.clone(),
+                        status: 403,
+                    }),
+                    _ => Ok("203.0.113.20".to_string()),
+                };
+                async move { result }
+            },
+            |e| fallbacks.push(e.provider().map(str::to_string)),
+        )
+        .await;
+
+        assert_eq!(ip.unwrap(), "203.0.113.20");
+        // The non-https entry is never asked, and Quad9 is not needed.
+        assert_eq!(*asked.lock().unwrap(), ["Cloudflare", "Google"]);
+        assert_eq!(fallbacks, [Some("Cloudflare".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn only_transient_failures_are_retried() {
+        let providers = [provider("first"), provider("second")];
+        let asked = Mutex::new(Vec::new());
+
+        let result = resolve_with_delay(
+            &providers,
+            HOST,
+            Duration::ZERO,
+            PROVIDER_TIMEOUT,
+            LOOKUP_TIMEOUT,
+            |p| {
+                asked.lock().unwrap().push(p.name.clone());
+                let result = match p.name.as_str() {
+                    "first" => parse_answer(&p.name, HOST, br#"{"Status":3}"#),
+                    _ => Err(DohError::HttpStatus {
+                        provider: p.name.clone(),
+                        status: 403,
+                    }),
+                };
+                async move { result }
+            },
+            |_| {},
+        )
+        .await;
+
+        assert_eq!(*asked.lock().unwrap(), ["first", "second"]);
+        assert_eq!(
+            result,
+            Err(DohError::HttpStatus {
+                provider: "second".to_string(),
+                status: 403,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn all_providers_failing_returns_the_last_error() {
+        let providers = [provider("first"), provider("second")];
+        let mut fallbacks = 0;
+        let result: Result<String, _> = resolve_with_delay(
+            &providers,
+            HOST,
+            Duration::ZERO,
+            PROVIDER_TIMEOUT,
+            LOOKUP_TIMEOUT,
+            |p| {
+                let error = network_error(&p);
+                async move { Err(error) }
+            },
+            |_| fallbacks += 1,
+        )
+        .await;
+
+        assert_eq!(result.unwrap_err().provider(), Some("second"));
+        assert_eq!(fallbacks, 1);
+        assert_eq!(
+            resolve_with(&[], HOST, |_| async { Ok(String::new()) }, |_| {}).await,
+            Err(DohError::NoProviders)
+        );
+    }
+}
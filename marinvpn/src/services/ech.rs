@@ -0,0 +1,435 @@
+use crate::models::DohProvider;
+use crate::services::doh::{self, DohError};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use rustls::client::{EchConfig, EchMode};
+use rustls::crypto::aws_lc_rs::{self, hpke::ALL_SUPPORTED_SUITES};
+use rustls::pki_types::EchConfigListBytes;
+use rustls::{ClientConfig, PeerIncompatible, RootCertStore};
+use serde_json::Value;
+use std::sync::Arc;
+
+/// DNS type of the HTTPS record (RFC 9460) that carries ECH configs.
+const HTTPS_RECORD_TYPE: u64 = 65;
+/// SvcParamKey of the `ech` parameter.
+const ECH_PARAM_KEY: u16 = 5;
+
+/// An `ECHConfigList` as published in the API host's HTTPS record, still in
+/// wire format.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EchConfigList(Vec<u8>);
+
+impl EchConfigList {
+    /// Checks the list's own length prefix; the configs inside are left to
+    /// the TLS stack.
+    pub fn from_bytes(bytes: Vec<u8>) -> Option<Self> {
+        let declared = u16::from_be_bytes([*bytes.first()?, *bytes.get(1)?]) as usize;
+        (declared > 0 && declared == bytes.len() - 2).then_some(Self(bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// How the next control-plane connection treats ECH.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EchPlan {
+    /// `settings.encrypted_client_hello` is off.
+    Disabled,
+    /// The API host publishes no ECH config; a normal handshake it is.
+    NotOffered,
+    Use(EchConfigList),
+}
+
+pub fn plan(enabled: bool, offered: Option<EchConfigList>) -> EchPlan {
+    match offered {
+        _ if !enabled => EchPlan::Disabled,
+        None => EchPlan::NotOffered,
+        Some(list) => EchPlan::Use(list),
+    }
+}
+
+/// TLS settings that send an Encrypted Client Hello built from `list`, so
+/// only the config's public name is readable on the wire. ECH needs TLS 1.3
+/// and HPKE, which is why this goes through rustls with the aws-lc crypto
+/// provider rather than the platform TLS backend. Servers are checked
+/// against the bundled web PKI roots.
+///
+/// The result is only fit for the host that published `list`. Fails when
+/// none of its configs uses an HPKE suite the provider supports.
+pub fn tls_config(list: &EchConfigList) -> Result<ClientConfig, rustls::Error> {
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    tls_config_with_roots(list, roots)
+}
+
+fn tls_config_with_roots(
+    list: &EchConfigList,
+    roots: RootCertStore,
+) -> Result<ClientConfig, rustls::Error> {
+    let ech = EchConfig::new(
+        EchConfigListBytes::from(list.as_bytes()),
+        ALL_SUPPORTED_SUITES,
+    )?;
+    let mut config = ClientConfig::builder_with_provider(Arc::new(aws_lc_rs::default_provider()))
+        .with_ech(EchMode::Enable(ech))?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// Whether `error`, or anything behind it, is the server turning the
+/// Encrypted Client Hello down. That happens once the config it was built
+/// from is rotated out, and only a fresh lookup or a normal handshake gets
+/// through after that.
+pub fn rejected(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut next = Some(error);
+    while let Some(e) = next {
+        if let Some(rustls::Error::PeerIncompatible(
+            PeerIncompatible::ServerRejectedEncryptedClientHello(_),
+        )) = e.downcast_ref::<rustls::Error>()
+        {
+            return true;
+        }
+        // An io::Error skips the error it wraps when asked for its source.
+        next = match e.downcast_ref::<std::io::Error>() {
+            Some(io) => io.get_ref().map(|inner| inner as _),
+            None => e.source(),
+        };
+    }
+    false
+}
+
+/// Looks up the ECH configs `hostname` publishes, through `providers` so the
+/// local resolver cannot strip them. `None` when the host offers none.
+pub async fn discover(
+    client: &reqwest::Client,
+    providers: &[DohProvider],
+    hostname: &str,
+) -> Result<Option<EchConfigList>, DohError> {
+    let lookup = doh::resolve_with(
+        providers,
+        hostname,
+        |provider| async move {
+            let body = doh::fetch(client, &provider, hostname, "HTTPS").await?;
+            parse_answer(&provider.name, hostname, &body)
+        },
+        |e| tracing::warn!("ECH lookup failed ({}), trying alternate", e),
+    )
+    .await;
+    match lookup {
+        Ok(list) => Ok(Some(list)),
+        Err(DohError::NoAnswer { .. }) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// The first ECH config list among the HTTPS records of a JSON DoH response.
+pub fn parse_answer(
+    provider: &str,
+    hostname: &str,
+    body: &[u8],
+) -> Result<EchConfigList, DohError> {
+    doh::answer_records(provider, hostname, body)?
+        .iter()
+        .filter(|record| record.get("type").and_then(Value::as_u64) == Some(HTTPS_RECORD_TYPE))
+        .filter_map(|record| record.get("data")?.as_str())
+        .find_map(parse_https_record)
+        .ok_or_else(|| DohError::NoAnswer {
+            provider: provider.to_string(),
+            hostname: hostname.to_string(),
+        })
+}
+
+/// Pulls the `ech` parameter out of one HTTPS record. Providers hand these
+/// out either in presentation format (`1 . alpn=h2 ech=...`) or in the
+/// generic `\# <len> <hex>` form of RFC 3597.
+pub fn parse_https_record(data: &str) -> Option<EchConfigList> {
+    match data.trim().strip_prefix("\\#") {
+        Some(generic) => parse_generic(generic),
+        None => parse_presentation(data),
+    }
+}
+
+fn parse_presentation(data: &str) -> Option<EchConfigList> {
+    let mut fields = data.split_whitespace();
+    // Priority 0 is AliasMode, which carries no parameters.
+    if fields.next()?.parse::<u16>().ok()? == 0 {
+        return None;
+    }
+    let _target = fields.next()?;
+    let encoded = fields.find_map(|param| param.strip_prefix("ech="))?;
+    let bytes = BASE64_STANDARD.decode(encoded.trim_matches('"')).ok()?;
+    EchConfigList::from_bytes(bytes)
+}
+
+fn parse_generic(generic: &str) -> Option<EchConfigList> {
+    let mut fields = generic.split_whitespace();
+    let length: usize = fields.next()?.parse().ok()?;
+    let rdata = hex::decode(fields.collect::<String>()).ok()?;
+    if rdata.len() != length {
+        return None;
+    }
+
+    let mut rest = rdata.as_slice();
+    let priority = take_u16(&mut rest)?;
+    if priority == 0 {
+        return None;
+    }
+    // Skip the uncompressed target name.
+    loop {
+        let (&label, tail) = rest.split_first()?;
+        rest = tail.get(label as usize..)?;
+        if label == 0 {
+            break;
+        }
+    }
+    while !rest.is_empty() {
+        let key = take_u16(&mut rest)?;
+        let len = take_u16(&mut rest)? as usize;
+        let value = rest.get(..len)?;
+        rest = &rest[len..];
+        if key == ECH_PARAM_KEY {
+            return EchConfigList::from_bytes(value.to_vec());
+        }
+    }
+    None
+}
+
+fn take_u16(rest: &mut &[u8]) -> Option<u16> {
+    let (bytes, tail) = rest.split_first_chunk::<2>()?;
+    *rest = tail;
+    Some(u16::from_be_bytes(*bytes))
+}
+
+/// A stand-in TLS server without ECH support, and clients for it.
+#[cfg(test)]
+pub(crate) mod mock {
+    use super::*;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+
+    /// Name the clients ask for. The server's self-signed certificate
+    /// covers it and the public name of [`usable_list`].
+    pub const HOST: &str = "api.marinvpn.example";
+    const CERT: &str =
+        "MIIBuDCCAV6gAwIBAgIUY9oh9prHx2hMF44h6VarefiWBrwwCgYIKoZIzj0EAwIwGTEXMBUGA1UE\
+AwwOcHVibGljLmV4YW1wbGUwIBcNMjYxMDE2MTgyNTQ1WhgPMjEyNjA5MjIxODI1NDVaMBkxFzAV\
+BgNVBAMMDnB1YmxpYy5leGFtcGxlMFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEv0o/XPQjeLFg\
+9srrRvoS+5OLipP84l9ejxLadhNsB8LgYPc3Gs89oe1adV1Ciy+39TV4CiYAIZ8wi7IbwXC7VaOB\
+gTB/MB0GA1UdDgQWBBSn0a23QxnmB801T8NuKy4WeKrSCTAfBgNVHSMEGDAWgBSn0a23QxnmB801\
+T8NuKy4WeKrSCTAvBgNVHREEKDAmgg5wdWJsaWMuZXhhbXBsZYIUYXBpLm1hcmludnBuLmV4YW1w\
+bGUwDAYDVR0TAQH/BAIwADAKBggqhkjOPQQDAgNIADBFAiEAyObOnHkwu2mZV2HgGMpFTXajEVk0\
+oQ/dK3c3ZcQtWkgCIHqzo9TJI54bol6mE8L2XTzAlNbf8CeDwrn3dSk3aa6R";
+    const KEY: &str =
+        "MIGHAgEAMBMGByqGSM49AgEGCCqGSM49AwEHBG0wawIBAQQglubeW3ofVN5TLb+BCFtJTt6RSfy8\
+4AcnD3Z5MRiLFHihRANCAAS/Sj9c9CN4sWD2yutG+hL7k4uKk/ziX16PEtp2E2wHwuBg9zcazz2h\
+7Vp1XUKLL7f1NXgKJgAhnzCLshvBcLtV";
+
+    /// A one-entry list with an X25519, HKDF-SHA256, AES-128-GCM config
+    /// whose public name is `public.example`.
+    pub fn usable_list() -> EchConfigList {
+        let public_name = b"public.example";
+        let mut contents = vec![0x01, 0x00, 0x20, 0x00, 0x20];
+        contents.extend([0x42; 32]);
+        contents.extend([0x00, 0x04, 0x00, 0x01, 0x00, 0x01, 0x00]);
+        contents.push(public_name.len() as u8);
+        contents.extend(public_name);
+        contents.extend([0x00, 0x00]);
+
+        let mut config = vec![0xfe, 0x0d];
+        config.extend((contents.len() as u16).to_be_bytes());
+        config.extend(contents);
+        let mut bytes = (config.len() as u16).to_be_bytes().to_vec();
+        bytes.extend(config);
+        EchConfigList::from_bytes(bytes).unwrap()
+    }
+
+    fn cert() -> CertificateDer<'static> {
+        CertificateDer::from(BASE64_STANDARD.decode(CERT).unwrap())
+    }
+
+    /// Serves `connections` connections over TLS 1.3 without ECH, which is
+    /// how a rotated-out ECH config looks to the client: the outer
+    /// ClientHello is answered for the public name and the inner one
+    /// ignored. A connection that completes the handshake gets `200 OK`.
+    pub fn serve_without_ech(connections: usize) -> SocketAddr {
+        let key = PrivatePkcs8KeyDer::from(BASE64_STANDARD.decode(KEY).unwrap());
+        let config =
+            rustls::ServerConfig::builder_with_provider(Arc::new(aws_lc_rs::default_provider()))
+                .with_protocol_versions(&[&rustls::version::TLS13])
+                .unwrap()
+                .with_no_client_auth()
+                .with_single_cert(vec![cert()], PrivateKeyDer::Pkcs8(key))
+                .unwrap();
+        let config = Arc::new(config);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for socket in listener.incoming().take(connections) {
+                let connection = rustls::ServerConnection::new(config.clone()).unwrap();
+                let mut tls = rustls::StreamOwned::new(connection, socket.unwrap());
+                let mut request = [0u8; 1024];
+                if tls.read(&mut request).is_err() {
+                    continue;
+                }
+                let _ = tls.write_all(
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok",
+                );
+                tls.conn.send_close_notify();
+                let _ = tls.flush();
+            }
+        });
+        addr
+    }
+
+    /// A client for [`serve_without_ech`] at `addr`, sending an Encrypted
+    /// Client Hello built from `ech` when given.
+    pub fn client(addr: SocketAddr, ech: Option<&EchConfigList>) -> reqwest::Client {
+        let mut roots = RootCertStore::empty();
+        roots.add(cert()).unwrap();
+        let tls = match ech {
+            Some(list) => tls_config_with_roots(list, roots).unwrap(),
+            None => ClientConfig::builder_with_provider(Arc::new(aws_lc_rs::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        };
+        reqwest::Client::builder()
+            .use_preconfigured_tls(tls)
+            .resolve(HOST, addr)
+            .build()
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::doh::mock::serve_once;
+    use mock::usable_list;
+
+    const HOST: &str = "api.marinvpn.example";
+    /// A one-entry list holding an empty draft-13 config.
+    const LIST: [u8; 6] = [0x00, 0x04, 0xfe, 0x0d, 0x00, 0x00];
+
+    fn list() -> EchConfigList {
+        EchConfigList::from_bytes(LIST.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn reads_ech_from_both_record_formats() {
+        assert_eq!(
+            parse_https_record("1 . alpn=h2,h3 ech=AAT+DQAA"),
+            Some(list())
+        );
+        assert_eq!(
+            parse_https_record("1 . alpn=\"h2\" ech=\"AAT+DQAA\" ipv4hint=192.0.2.1"),
+            Some(list())
+        );
+        assert_eq!(
+            parse_https_record(
+                "\\# 20 00 01 00 00 01 00 03 02 68 32 00 05 00 06 00 04 fe 0d 00 00"
+            ),
+            Some(list())
+        );
+        assert_eq!(
+            parse_https_record("\\# 20 0001000001000302683200050006 0004fe0d0000"),
+            Some(list())
+        );
+    }
+
+    #[test]
+    fn records_without_usable_ech_are_skipped() {
+        // No ech parameter.
+        assert_eq!(parse_https_record("1 . alpn=h2,h3"), None);
+        assert_eq!(
+            parse_https_record("\\# 11 00 01 00 00 01 00 03 02 68 32"),
+            None
+        );
+        // AliasMode, bad base64, and a list whose length prefix lies.
+        assert_eq!(parse_https_record("0 cdn.example."), None);
+        assert_eq!(parse_https_record("1 . ech=not*base64"), None);
+        assert_eq!(parse_https_record("1 . ech=AAX+DQAA"), None);
+        // Declared rdata length does not match.
+        assert_eq!(
+            parse_https_record("\\# 21 00 01 00 00 01 00 03 02 68 32"),
+            None
+        );
+    }
+
+    #[test]
+    fn plan_falls_back_cleanly() {
+        assert_eq!(plan(false, Some(list())), EchPlan::Disabled);
+        assert_eq!(plan(true, None), EchPlan::NotOffered);
+        assert_eq!(plan(true, Some(list())), EchPlan::Use(list()));
+    }
+
+    #[test]
+    fn tls_config_takes_a_config_with_a_supported_suite() {
+        let config = tls_config(&usable_list()).unwrap();
+        let name = rustls::pki_types::ServerName::try_from("api.marinvpn.example").unwrap();
+        assert!(rustls::ClientConnection::new(Arc::new(config), name).is_ok());
+
+        // The fixture's config has no contents, so there is nothing to use.
+        assert!(tls_config(&list()).is_err());
+    }
+
+    #[tokio::test]
+    async fn a_server_ignoring_ech_reads_as_a_rejection() {
+        let addr = mock::serve_without_ech(1);
+        let client = mock::client(addr, Some(&usable_list()));
+        let url = format!("https://{}:{}/api/v1/version", mock::HOST, addr.port());
+        let error = client.get(&url).send().await.unwrap_err();
+        assert!(rejected(&error), "{:?}", error);
+
+        // Other failures are not mistaken for one.
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = closed.local_addr().unwrap();
+        drop(closed);
+        let client = mock::client(addr, Some(&usable_list()));
+        let url = format!("https://{}:{}/", mock::HOST, addr.port());
+        let error = client.get(&url).send().await.unwrap_err();
+        assert!(!rejected(&error));
+    }
+
+    #[tokio::test]
+    async fn lookup_against_a_host_that_advertises_ech() {
+        let client = reqwest::Client::new();
+        let provider = serve_once(
+            "200 OK",
+            r#"{"Status":0,"Answer":[
+                {"name":"api.marinvpn.example.","type":5,"data":"edge.example."},
+                {"name":"edge.example.","type":65,"data":"1 . alpn=h2 ech=AAT+DQAA"}
+            ]}"#,
+        )
+        .await;
+        let body = doh::fetch(&client, &provider, HOST, "HTTPS").await.unwrap();
+        assert_eq!(parse_answer(&provider.name, HOST, &body).unwrap(), list());
+    }
+
+    #[tokio::test]
+    async fn lookup_against_a_host_without_ech() {
+        let client = reqwest::Client::new();
+        let provider = serve_once(
+            "200 OK",
+            r#"{"Status":0,"Answer":[{"type":65,"data":"1 . alpn=h2,h3"}]}"#,
+        )
+        .await;
+        let body = doh::fetch(&client, &provider, HOST, "HTTPS").await.unwrap();
+        assert!(matches!(
+            parse_answer(&provider.name, HOST, &body),
+            Err(DohError::NoAnswer { .. })
+        ));
+
+        // No HTTPS record at all reads the same.
+        assert!(matches!(
+            parse_answer("Test", HOST, br#"{"Status":0}"#),
+            Err(DohError::NoAnswer { .. })
+        ));
+    }
+}
@@ -0,0 +1,255 @@
+//! Checking that turning the kill switch off really removed its rules.
+//! Each removal command is best effort, and rules left behind by a crash
+//! pile up over restarts until they slow the firewall down or block
+//! traffic.
+
+use async_trait::async_trait;
+use tokio::process::Command;
+use tracing::warn;
+
+/// Runs firewall commands, so cleanup can be checked without touching the
+/// real firewall.
+#[async_trait]
+pub trait CommandRunner: Send + Sync {
+    /// Runs `command`, whose first entry is the program. Returns its
+    /// standard output, or `None` when it could not start or failed.
+    async fn output(&self, command: &[String]) -> Option<String>;
+}
+
+pub struct SystemCommands;
+
+#[async_trait]
+impl CommandRunner for SystemCommands {
+    async fn output(&self, command: &[String]) -> Option<String> {
+        let (program, args) = command.split_first()?;
+        let output = Command::new(program).args(args).output().await.ok()?;
+        output
+            .status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
+
+type ItemsFn = Box<dyn Fn(&str) -> Vec<String> + Send + Sync>;
+type RemoveFn = Box<dyn Fn(&str) -> Vec<Vec<String>> + Send + Sync>;
+
+/// One kind of rule the kill switch adds and how to find and remove it.
+pub struct LeftoverCheck {
+    /// Lists the firewall's current state. Failing counts as nothing left,
+    /// since a tool that is not installed holds no rules.
+    pub list: Vec<String>,
+    /// The leftovers in `list`'s output.
+    pub items: ItemsFn,
+    /// Commands that remove one leftover.
+    pub remove: RemoveFn,
+}
+
+/// How many times leftovers are removed before giving up on them.
+pub const CLEANUP_ATTEMPTS: usize = 3;
+
+/// Removes whatever `checks` still find, up to `attempts` times, and
+/// returns what was left after the last try. Anything left is logged.
+pub async fn remove_leftovers(
+    runner: &dyn CommandRunner,
+    checks: &[LeftoverCheck],
+    attempts: usize,
+) -> Vec<String> {
+    for _ in 0..attempts {
+        let found = find(runner, checks).await;
+        if found.is_empty() {
+            return Vec::new();
+        }
+        for (check, item) in found {
+            for command in (check.remove)(&item) {
+                let _ = runner.output(&command).await;
+            }
+        }
+    }
+
+    let left: Vec<String> = find(runner, checks)
+        .await
+        .into_iter()
+        .map(|(_, item)| item)
+        .collect();
+    for item in &left {
+        warn!("Kill switch rule could not be removed: {}", item);
+    }
+    left
+}
+
+async fn find<'a>(
+    runner: &dyn CommandRunner,
+    checks: &'a [LeftoverCheck],
+) -> Vec<(&'a LeftoverCheck, String)> {
+    let mut found = Vec::new();
+    for check in checks {
+        if let Some(out) = runner.output(&check.list).await {
+            found.extend((check.items)(&out).into_iter().map(|item| (check, item)));
+        }
+    }
+    found
+}
+
+/// The `MarinVPN*` rules the Windows kill switch and split tunneling add.
+pub fn windows_rules() -> LeftoverCheck {
+    LeftoverCheck {
+        list: [
+            "powershell",
+            "-NoProfile",
+            "-Command",
+            "Get-NetFirewallRule -DisplayName 'MarinVPN*' -ErrorAction SilentlyContinue | Select-Object -ExpandProperty DisplayName",
+        ]
+        .map(String::from)
+        .to_vec(),
+        items: Box::new(|out| {
+            out.lines()
+                .map(str::trim)
+                .filter(|name| name.starts_with("MarinVPN"))
+                .map(String::from)
+                .collect()
+        }),
+        remove: Box::new(|name| {
+            vec![vec![
+                "powershell".to_string(),
+                "-NoProfile".to_string(),
+                "-Command".to_string(),
+                format!(
+                    "Remove-NetFirewallRule -DisplayName '{}'",
+                    name.replace('\'', "''")
+                ),
+            ]]
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// A firewall holding named rules, each with how many removals it
+    /// survives. Listing prints the names one per line.
+    struct DryRun {
+        rules: Mutex<Vec<(String, usize)>>,
+        ran: Mutex<Vec<String>>,
+    }
+
+    impl DryRun {
+        fn new(rules: &[(&str, usize)]) -> Self {
+            Self {
+                rules: Mutex::new(rules.iter().map(|(r, n)| (r.to_string(), *n)).collect()),
+                ran: Mutex::new(Vec::new()),
+            }
+        }
+
+        fn removals(&self) -> Vec<String> {
+            self.ran
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|c| c.starts_with("remove"))
+                .cloned()
+                .collect()
+        }
+    }
+
+    #[async_trait]
+    impl CommandRunner for DryRun {
+        async fn output(&self, command: &[String]) -> Option<String> {
+            self.ran.lock().unwrap().push(command.join(" "));
+            let mut rules = self.rules.lock().unwrap();
+            match command[0].as_str() {
+                "list" => Some(rules.iter().map(|(r, _)| format!("{}\n", r)).collect()),
+                "remove" => {
+                    let at = rules.iter().position(|(r, _)| *r == command[1])?;
+                    if rules[at].1 == 0 {
+                        rules.remove(at);
+                    } else {
+                        rules[at].1 -= 1;
+                    }
+                    Some(String::new())
+                }
+                _ => None,
+            }
+        }
+    }
+
+    fn check() -> LeftoverCheck {
+        LeftoverCheck {
+            list: vec!["list".to_string()],
+            items: Box::new(|out| {
+                out.lines()
+                    .filter(|l| l.starts_with("MarinVPN"))
+                    .map(String::from)
+                    .collect()
+            }),
+            remove: Box::new(|item| vec![vec!["remove".to_string(), item.to_string()]]),
+        }
+    }
+
+    #[tokio::test]
+    async fn a_clean_firewall_needs_no_removals() {
+        let firewall = DryRun::new(&[("Other App", 0)]);
+        assert!(remove_leftovers(&firewall, &[check()], CLEANUP_ATTEMPTS)
+            .await
+            .is_empty());
+        assert_eq!(*firewall.ran.lock().unwrap(), ["list"]);
+    }
+
+    #[tokio::test]
+    async fn stragglers_are_retried_until_gone() {
+        let firewall = DryRun::new(&[
+            ("MarinVPN - Block LAN", 0),
+            ("Other App", 0),
+            ("MarinVPN - Allow Tunnel", 1),
+        ]);
+        let left = remove_leftovers(&firewall, &[check()], CLEANUP_ATTEMPTS).await;
+
+        assert!(left.is_empty());
+        assert_eq!(
+            firewall.removals(),
+            [
+                "remove MarinVPN - Block LAN",
+                "remove MarinVPN - Allow Tunnel",
+                "remove MarinVPN - Allow Tunnel",
+            ]
+        );
+        assert_eq!(firewall.rules.lock().unwrap().len(), 1, "others stay");
+    }
+
+    #[tokio::test]
+    async fn rules_that_will_not_go_are_reported() {
+        let firewall = DryRun::new(&[("MarinVPN - Stuck", usize::MAX), ("MarinVPN - Easy", 0)]);
+        let left = remove_leftovers(&firewall, &[check()], CLEANUP_ATTEMPTS).await;
+
+        assert_eq!(left, ["MarinVPN - Stuck"]);
+        assert_eq!(firewall.removals().len(), CLEANUP_ATTEMPTS + 1);
+    }
+
+    #[tokio::test]
+    async fn a_listing_that_fails_counts_as_clean() {
+        let firewall = DryRun::new(&[("MarinVPN - Block LAN", 0)]);
+        let missing_tool = LeftoverCheck {
+            list: vec!["not-installed".to_string()],
+            ..check()
+        };
+        assert!(
+            remove_leftovers(&firewall, &[missing_tool], CLEANUP_ATTEMPTS)
+                .await
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn windows_rules_are_found_by_display_name() {
+        let rules = windows_rules();
+        let items = (rules.items)(
+            "MarinVPN - Block LAN\r\nSomething Else\r\nMarinVPN Bypass - Tor's\r\n\r\n",
+        );
+        assert_eq!(items, ["MarinVPN - Block LAN", "MarinVPN Bypass - Tor's"]);
+        assert_eq!(
+            (rules.remove)("MarinVPN Bypass - Tor's")[0][3],
+            "Remove-NetFirewallRule -DisplayName 'MarinVPN Bypass - Tor''s'"
+        );
+    }
+}
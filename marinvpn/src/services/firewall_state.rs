@@ -0,0 +1,571 @@
+//! What the kill switch is enforcing right now, read back from the firewall
+//! instead of worked out from the settings it was built from. Rules that
+//! failed to apply, or that were left behind by an earlier run, show up here
+//! as they really are.
+
+use crate::services::firewall_cleanup::CommandRunner;
+use std::fmt;
+
+/// The part of the rule set that decides what can leave the machine.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FirewallState {
+    /// The firewall the rules were read from.
+    pub backend: &'static str,
+    /// Families in which everything no rule allows is dropped.
+    pub blocked_families: Vec<Family>,
+    pub allowed: Vec<RuleMatch>,
+    pub blocked: Vec<RuleMatch>,
+    /// Traffic split tunneling sends around the tunnel.
+    pub split_exceptions: Vec<RuleMatch>,
+}
+
+impl FirewallState {
+    fn new(backend: &'static str) -> Self {
+        Self {
+            backend,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocked_families.is_empty()
+            && self.allowed.is_empty()
+            && self.blocked.is_empty()
+            && self.split_exceptions.is_empty()
+    }
+
+    fn block_family(&mut self, family: Family) {
+        if !self.blocked_families.contains(&family) {
+            self.blocked_families.push(family);
+        }
+    }
+
+    /// Files a rule by its verdict. A drop of a whole address family counts
+    /// as blocking the family.
+    fn add(&mut self, rule: RuleMatch, allow: bool) {
+        if allow {
+            if rule.mark.is_some() || rule.program.is_some() {
+                self.split_exceptions.push(rule);
+            } else {
+                self.allowed.push(rule);
+            }
+        } else if let Some(family) = rule.whole_family() {
+            self.block_family(family);
+        } else {
+            self.blocked.push(rule);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Family {
+    Ipv4,
+    Ipv6,
+}
+
+impl fmt::Display for Family {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Family::Ipv4 => "IPv4",
+            Family::Ipv6 => "IPv6",
+        })
+    }
+}
+
+/// What one rule matches. Fields that are not set match anything.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RuleMatch {
+    pub protocol: Option<String>,
+    pub address: Option<String>,
+    pub port: Option<String>,
+    pub interface: Option<String>,
+    pub program: Option<String>,
+    pub mark: Option<String>,
+    pub cgroup: Option<String>,
+}
+
+impl RuleMatch {
+    fn whole_family(&self) -> Option<Family> {
+        let only_address = RuleMatch {
+            address: self.address.clone(),
+            ..Default::default()
+        };
+        if *self != only_address {
+            return None;
+        }
+        match self.address.as_deref() {
+            Some("0.0.0.0/0") => Some(Family::Ipv4),
+            Some("::/0") => Some(Family::Ipv6),
+            _ => None,
+        }
+    }
+}
+
+/// For example `udp to 198.51.100.1 port 51820` or `on marinvpn0`.
+impl fmt::Display for RuleMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts: Vec<String> = [
+            self.protocol.clone(),
+            self.address.as_ref().map(|a| format!("to {}", a)),
+            self.port.as_ref().map(|p| format!("port {}", p)),
+            self.interface.as_ref().map(|i| format!("on {}", i)),
+            self.program.as_ref().map(|p| format!("from {}", p)),
+            self.mark.as_ref().map(|m| format!("marked {}", m)),
+            self.cgroup.as_ref().map(|c| format!("in cgroup {}", c)),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        if parts.is_empty() {
+            f.write_str("all other traffic")
+        } else {
+            f.write_str(&parts.join(" "))
+        }
+    }
+}
+
+/// Reads the kill switch rules that are in force, or `None` when there are
+/// none. Listing the firewall needs the same rights as changing it.
+#[cfg(target_os = "linux")]
+pub async fn read(runner: &dyn CommandRunner) -> Option<FirewallState> {
+    use crate::services::{linux_firewall, split_tunnel};
+
+    let list_table = |table: &str| {
+        ["nft", "list", "table", "inet", table]
+            .map(String::from)
+            .to_vec()
+    };
+    let split = runner
+        .output(&list_table(split_tunnel::NFT_TABLE))
+        .await
+        .unwrap_or_default();
+    if let Some(killswitch) = runner.output(&list_table(linux_firewall::NFT_TABLE)).await {
+        return Some(parse_nft(&killswitch, &split));
+    }
+
+    let list_chain = |program: &str| {
+        [program, "-S", linux_firewall::IPT_CHAIN]
+            .map(String::from)
+            .to_vec()
+    };
+    let v4 = runner.output(&list_chain("iptables")).await;
+    let v6 = runner.output(&list_chain("ip6tables")).await;
+    if v4.is_none() && v6.is_none() {
+        return None;
+    }
+    Some(parse_iptables(
+        &v4.unwrap_or_default(),
+        &v6.unwrap_or_default(),
+    ))
+}
+
+/// Reads the kill switch rules that are in force, or `None` when there are
+/// none.
+#[cfg(target_os = "windows")]
+pub async fn read(runner: &dyn CommandRunner) -> Option<FirewallState> {
+    let policy = runner
+        .output(&["netsh", "advfirewall", "show", "allprofiles"].map(String::from))
+        .await
+        .unwrap_or_default();
+    let rules = runner
+        .output(&["powershell", "-NoProfile", "-Command", WINDOWS_LISTING].map(String::from))
+        .await
+        .unwrap_or_default();
+    let state = parse_windows(&policy, &rules);
+    (!state.is_empty()).then_some(state)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "windows")))]
+pub async fn read(_runner: &dyn CommandRunner) -> Option<FirewallState> {
+    None
+}
+
+/// Prints each `MarinVPN*` rule on one line, with its fields separated by
+/// tabs in the order [`parse_windows`] reads them.
+pub const WINDOWS_LISTING: &str = "Get-NetFirewallRule -DisplayName 'MarinVPN*' -Direction Outbound -ErrorAction SilentlyContinue | ForEach-Object { \
+    $a = $_ | Get-NetFirewallAddressFilter; \
+    $p = $_ | Get-NetFirewallPortFilter; \
+    $app = $_ | Get-NetFirewallApplicationFilter; \
+    $if = $_ | Get-NetFirewallInterfaceFilter; \
+    \"$($_.DisplayName)`t$($_.Action)`t$($p.Protocol)`t$($a.RemoteAddress -join ',')`t$($p.RemotePort -join ',')`t$($if.InterfaceAlias -join ',')`t$($app.Program)\" \
+}";
+
+/// Reads `nft list table` output for the kill switch table and, possibly
+/// empty, the split tunneling table.
+pub fn parse_nft(killswitch: &str, split: &str) -> FirewallState {
+    let mut state = FirewallState::new("nftables");
+    for line in nft_output_chain(killswitch) {
+        if line.starts_with("type ") {
+            if line.contains("policy drop") {
+                state.block_family(Family::Ipv4);
+                state.block_family(Family::Ipv6);
+            }
+            continue;
+        }
+        let rule = NftRule::parse(&line);
+        match rule.verdict.as_deref() {
+            Some("accept") => state.add(rule.matches, true),
+            Some("drop" | "reject") => state.add(rule.matches, false),
+            _ => {}
+        }
+    }
+    for line in nft_output_chain(split) {
+        let rule = NftRule::parse(&line);
+        if rule.sets_mark {
+            state.split_exceptions.push(rule.matches);
+        }
+    }
+    state
+}
+
+/// The lines of the `output` chain, without comments.
+fn nft_output_chain(listing: &str) -> Vec<String> {
+    let mut chain = None;
+    let mut lines = Vec::new();
+    for line in listing.lines() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if let Some(name) = line.strip_prefix("chain ") {
+            chain = name.split_whitespace().next();
+        } else if line == "}" {
+            chain = None;
+        } else if chain == Some("output") && !line.is_empty() {
+            lines.push(line.to_string());
+        }
+    }
+    lines
+}
+
+struct NftRule {
+    matches: RuleMatch,
+    verdict: Option<String>,
+    sets_mark: bool,
+}
+
+impl NftRule {
+    fn parse(line: &str) -> Self {
+        let tokens = nft_tokens(line);
+        let mut rule = NftRule {
+            matches: RuleMatch::default(),
+            verdict: None,
+            sets_mark: false,
+        };
+        let m = &mut rule.matches;
+        let value = |i: usize| tokens.get(i).cloned();
+        let mut i = 0;
+        while i < tokens.len() {
+            match tokens[i].as_str() {
+                "oifname" => {
+                    m.interface = value(i + 1);
+                    i += 2;
+                }
+                "ip" | "ip6" if value(i + 1).as_deref() == Some("daddr") => {
+                    m.address = value(i + 2);
+                    i += 3;
+                }
+                proto @ ("tcp" | "udp") => {
+                    m.protocol = Some(proto.to_string());
+                    i += 1;
+                }
+                "dport" => {
+                    m.port = value(i + 1);
+                    i += 2;
+                }
+                "icmpv6" => {
+                    m.protocol = Some("icmpv6".to_string());
+                    i += if value(i + 1).as_deref() == Some("type") {
+                        3
+                    } else {
+                        1
+                    };
+                }
+                "l4proto" => {
+                    m.protocol = value(i + 1);
+                    i += 2;
+                }
+                "mark" if value(i + 1).as_deref() == Some("set") => {
+                    rule.sets_mark = true;
+                    i += 3;
+                }
+                "mark" => {
+                    m.mark = value(i + 1);
+                    i += 2;
+                }
+                "cgroup" => {
+                    m.cgroup = value(i + 1);
+                    i += 2;
+                }
+                "sport" | "packets" | "bytes" => i += 2,
+                verdict @ ("accept" | "drop" | "reject") => {
+                    rule.verdict = Some(verdict.to_string());
+                    i += 1;
+                }
+                _ => i += 1,
+            }
+        }
+        rule
+    }
+}
+
+/// Words of an nft rule, with quotes removed and a `{ a, b }` set kept as
+/// one token.
+fn nft_tokens(line: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut set: Option<Vec<&str>> = None;
+    for word in line.split_whitespace() {
+        match (word, set.as_mut()) {
+            ("{", None) => set = Some(Vec::new()),
+            ("}", Some(items)) => {
+                tokens.push(items.join(", "));
+                set = None;
+            }
+            (item, Some(items)) => items.push(item.trim_end_matches(',')),
+            (word, None) => tokens.push(word.trim_matches('"').to_string()),
+        }
+    }
+    tokens
+}
+
+/// Reads `iptables -S` and `ip6tables -S` output for the kill switch chain.
+pub fn parse_iptables(v4: &str, v6: &str) -> FirewallState {
+    let mut state = FirewallState::new("iptables");
+    for (family, listing) in [(Family::Ipv4, v4), (Family::Ipv6, v6)] {
+        for line in listing.lines() {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            if tokens.first() != Some(&"-A") {
+                continue;
+            }
+            let mut m = RuleMatch::default();
+            let mut verdict = None;
+            let value = |i: usize| tokens.get(i).map(|s| s.to_string());
+            let mut i = 2;
+            while i < tokens.len() {
+                match tokens[i] {
+                    "-o" => m.interface = value(i + 1),
+                    "-d" => {
+                        m.address = value(i + 1).map(|a| {
+                            a.trim_end_matches("/32")
+                                .trim_end_matches("/128")
+                                .to_string()
+                        })
+                    }
+                    "-p" => {
+                        m.protocol = value(i + 1).map(|p| match p.as_str() {
+                            "ipv6-icmp" => "icmpv6".to_string(),
+                            _ => p,
+                        })
+                    }
+                    "--dport" => m.port = value(i + 1),
+                    "--mark" => m.mark = value(i + 1),
+                    "-j" => verdict = value(i + 1),
+                    _ => {
+                        i += 1;
+                        continue;
+                    }
+                }
+                i += 2;
+            }
+            match verdict.as_deref() {
+                Some("ACCEPT") => state.add(m, true),
+                Some("DROP" | "REJECT") if m == RuleMatch::default() => state.block_family(family),
+                Some("DROP" | "REJECT") => state.add(m, false),
+                _ => {}
+            }
+        }
+    }
+    state
+}
+
+/// Reads `netsh advfirewall show allprofiles` output and the rules printed
+/// by [`WINDOWS_LISTING`].
+pub fn parse_windows(policy: &str, rules: &str) -> FirewallState {
+    let mut state = FirewallState::new("Windows Firewall");
+    let policies: Vec<String> = policy
+        .lines()
+        .filter_map(|line| line.split_once("Firewall Policy"))
+        .map(|(_, value)| value.trim().to_lowercase())
+        .collect();
+    if !policies.is_empty() && policies.iter().all(|p| p.contains("blockoutbound")) {
+        state.block_family(Family::Ipv4);
+        state.block_family(Family::Ipv6);
+    }
+
+    for line in rules.lines() {
+        let fields: Vec<&str> = line.trim_end_matches('\r').split('\t').collect();
+        let [name, action, protocol, address, port, interface, program] = fields[..] else {
+            continue;
+        };
+        let field = |value: &str| {
+            let value = value.trim();
+            (!value.is_empty() && !value.eq_ignore_ascii_case("any")).then(|| value.to_string())
+        };
+        let m = RuleMatch {
+            protocol: field(protocol).map(|p| p.to_lowercase()),
+            address: field(address).map(|a| a.replace(',', ", ")),
+            port: field(port),
+            interface: field(interface),
+            program: field(program),
+            ..Default::default()
+        };
+        let allow = action.trim().eq_ignore_ascii_case("allow");
+        if allow && name.starts_with("MarinVPN - Bypass") {
+            state.split_exceptions.push(m);
+        } else {
+            state.add(m, allow);
+        }
+    }
+    state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shown(rules: &[RuleMatch]) -> Vec<String> {
+        rules.iter().map(RuleMatch::to_string).collect()
+    }
+
+    #[test]
+    fn nft_listing_is_read_back() {
+        let killswitch = r#"table inet marinvpn_killswitch {
+	chain output {
+		type filter hook output priority filter; policy drop;
+		oifname "lo" accept
+		ip daddr 198.51.100.1 udp dport 51820 accept
+		ip6 daddr 2001:db8::1 tcp dport 443 accept
+		udp sport 68 udp dport 67 accept
+		icmpv6 type { nd-router-solicit, nd-router-advert } accept
+		oifname "marinvpn0" accept
+		meta mark 0x00001000 accept
+		ip daddr { 10.0.0.0/8, 172.16.0.0/12, 192.168.0.0/16 } accept # handle 12
+		ip6 daddr ::/0 drop
+	}
+
+	chain input {
+		type filter hook input priority filter; policy accept;
+		iifname "eth0" drop
+	}
+}"#;
+        let split = r#"table inet marinvpn_split {
+	chain output {
+		type route hook output priority mangle; policy accept;
+		meta cgroup 4096 meta mark set 0x00001000
+	}
+}"#;
+        let state = parse_nft(killswitch, split);
+
+        assert_eq!(state.backend, "nftables");
+        assert_eq!(state.blocked_families, [Family::Ipv4, Family::Ipv6]);
+        assert_eq!(
+            shown(&state.allowed),
+            [
+                "on lo",
+                "udp to 198.51.100.1 port 51820",
+                "tcp to 2001:db8::1 port 443",
+                "udp port 67",
+                "icmpv6",
+                "on marinvpn0",
+                "to 10.0.0.0/8, 172.16.0.0/12, 192.168.0.0/16",
+            ]
+        );
+        assert!(state.blocked.is_empty(), "the input chain is not reported");
+        assert_eq!(
+            shown(&state.split_exceptions),
+            ["marked 0x00001000", "in cgroup 4096"]
+        );
+    }
+
+    #[test]
+    fn include_only_split_rules_mark_all_other_traffic() {
+        let split = "table inet marinvpn_split {\n\tchain output {\n\
+            \t\tmeta cgroup 8192 accept\n\
+            \t\tip daddr 203.0.113.0/24 accept\n\
+            \t\tmeta mark set 0x00001000\n\t}\n}\n";
+        let state = parse_nft("", split);
+        assert_eq!(shown(&state.split_exceptions), ["all other traffic"]);
+        assert!(state.allowed.is_empty());
+    }
+
+    #[test]
+    fn iptables_listing_is_read_back_per_family() {
+        let v4 = "-N MARINVPN_KILLSWITCH
+-A MARINVPN_KILLSWITCH -o lo -j ACCEPT
+-A MARINVPN_KILLSWITCH -d 198.51.100.1/32 -p udp -m udp --dport 51820 -j ACCEPT
+-A MARINVPN_KILLSWITCH -o marinvpn0 -j ACCEPT
+-A MARINVPN_KILLSWITCH -m mark --mark 0x1000 -j ACCEPT
+-A MARINVPN_KILLSWITCH -d 10.0.0.0/8 -p tcp -m tcp --dport 445 -j REJECT --reject-with icmp-port-unreachable
+-A MARINVPN_KILLSWITCH -j DROP
+";
+        let v6 = "-N MARINVPN_KILLSWITCH
+-A MARINVPN_KILLSWITCH -p ipv6-icmp -m icmp6 --icmpv6-type 133 -j ACCEPT
+";
+        let state = parse_iptables(v4, v6);
+
+        assert_eq!(state.backend, "iptables");
+        assert_eq!(
+            state.blocked_families,
+            [Family::Ipv4],
+            "the IPv6 chain has no drop"
+        );
+        assert_eq!(
+            shown(&state.allowed),
+            [
+                "on lo",
+                "udp to 198.51.100.1 port 51820",
+                "on marinvpn0",
+                "icmpv6",
+            ]
+        );
+        assert_eq!(shown(&state.blocked), ["tcp to 10.0.0.0/8 port 445"]);
+        assert_eq!(shown(&state.split_exceptions), ["marked 0x1000"]);
+    }
+
+    #[test]
+    fn windows_policy_and_rules_are_read_back() {
+        let policy = "Domain Profile Settings:
+----------------------------------------------------------------------
+State                                 ON
+Firewall Policy                       AllowInbound,BlockOutbound
+
+Private Profile Settings:
+Firewall Policy                       AllowInbound,BlockOutbound
+Ok.
+";
+        let rules = [
+            "MarinVPN - Allow Loopback\tAllow\tAny\t127.0.0.1,::1\tAny\tAny\tAny",
+            "MarinVPN - Allow Endpoint UDP:51820\tAllow\tUDP\t198.51.100.1\t51820\tAny\tAny",
+            "MarinVPN - Allow Tunnel marinvpn\tAllow\tAny\tAny\tAny\tmarinvpn\tAny",
+            "MarinVPN - Bypass IP 203.0.113.7\tAllow\tAny\t203.0.113.7\tAny\tAny\tAny",
+            "MarinVPN Bypass - Tor\tAllow\tAny\tAny\tAny\tAny\tC:\\Apps\\tor.exe",
+            "MarinVPN - Block IPv6 Ethernet\tBlock\tAny\t::/0\tAny\tEthernet\tAny",
+            "MarinVPN - Leak Protect DNS UDP Ethernet\tBlock\tUDP\tAny\t53\tEthernet\tAny\r",
+            "not a rule",
+        ]
+        .join("\n");
+        let state = parse_windows(policy, &rules);
+
+        assert_eq!(state.blocked_families, [Family::Ipv4, Family::Ipv6]);
+        assert_eq!(
+            shown(&state.allowed),
+            [
+                "to 127.0.0.1, ::1",
+                "udp to 198.51.100.1 port 51820",
+                "on marinvpn",
+            ]
+        );
+        assert_eq!(
+            shown(&state.blocked),
+            ["to ::/0 on Ethernet", "udp port 53 on Ethernet"]
+        );
+        assert_eq!(
+            shown(&state.split_exceptions),
+            ["to 203.0.113.7", "from C:\\Apps\\tor.exe"]
+        );
+    }
+
+    #[test]
+    fn an_allowing_windows_policy_with_no_rules_is_empty() {
+        let policy = "Firewall Policy                       BlockInbound,AllowOutbound\n";
+        assert!(parse_windows(policy, "").is_empty());
+        assert!(parse_windows("", "").blocked_families.is_empty());
+    }
+}
@@ -0,0 +1,196 @@
+use crate::error::AppError;
+use rsa::RsaPublicKey;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long a fetched server public key is trusted before asking again.
+pub const PUBLIC_KEY_TTL: Duration = Duration::from_secs(6 * 60 * 60);
+
+const FETCH_ATTEMPTS: u32 = 2;
+const FETCH_RETRY_DELAY: Duration = Duration::from_millis(300);
+
+struct CachedKey {
+    key: RsaPublicKey,
+    fetched_at: Instant,
+}
+
+/// Caches one of the server's RSA public keys. The keys are public, so the
+/// only risk in holding on to one is that the server rotates it; callers
+/// that see a signature fail to verify call [`PublicKeyCache::invalidate`]
+/// and fetch again.
+pub struct PublicKeyCache {
+    ttl: Duration,
+    entry: Mutex<Option<CachedKey>>,
+    version: Mutex<u64>,
+}
+
+impl PublicKeyCache {
+    pub const fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entry: Mutex::new(None),
+            version: Mutex::new(0),
+        }
+    }
+
+    /// Bumped every time a fetch returns a different key than the one held.
+    pub fn version(&self) -> u64 {
+        *self.version.lock().unwrap()
+    }
+
+    pub fn invalidate(&self) {
+        if let Some(entry) = self.entry.lock().unwrap().as_mut() {
+            entry.fetched_at = Instant::now() - self.ttl;
+        }
+    }
+
+    pub async fn get<F, Fut>(&self, fetch: F) -> Result<RsaPublicKey, AppError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<RsaPublicKey, AppError>>,
+    {
+        self.get_at(Instant::now(), fetch).await
+    }
+
+    async fn get_at<F, Fut>(&self, now: Instant, mut fetch: F) -> Result<RsaPublicKey, AppError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<RsaPublicKey, AppError>>,
+    {
+        if let Some(entry) = self.entry.lock().unwrap().as_ref() {
+            if now.saturating_duration_since(entry.fetched_at) < self.ttl {
+                return Ok(entry.key.clone());
+            }
+        }
+
+        let mut attempt = 1;
+        let key = loop {
+            match fetch().await {
+                Ok(key) => break key,
+                // Only transport failures are worth repeating; a captive
+                // portal or bad response will answer the same way again.
+                Err(AppError::Network(e)) if attempt < FETCH_ATTEMPTS => {
+                    tracing::warn!("Public key fetch failed ({}), retrying", e);
+                    attempt += 1;
+                    tokio::time::sleep(FETCH_RETRY_DELAY).await;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+
+        let mut entry = self.entry.lock().unwrap();
+        if entry.as_ref().is_some_and(|old| old.key != key) {
+            *self.version.lock().unwrap() += 1;
+            tracing::info!("Server public key rotated");
+        }
+        *entry = Some(CachedKey {
+            key: key.clone(),
+            fetched_at: now,
+        });
+        Ok(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use once_cell::sync::Lazy;
+    use rand::thread_rng;
+    use rsa::RsaPrivateKey;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static KEYS: Lazy<[RsaPublicKey; 2]> = Lazy::new(|| {
+        let mut rng = thread_rng();
+        [(); 2].map(|_| RsaPrivateKey::new(&mut rng, 512).unwrap().to_public_key())
+    });
+
+    /// Serves `KEYS[*current]` and counts how often it was asked.
+    struct Server {
+        current: AtomicUsize,
+        fetches: AtomicUsize,
+    }
+
+    impl Server {
+        fn new() -> Self {
+            Self {
+                current: AtomicUsize::new(0),
+                fetches: AtomicUsize::new(0),
+            }
+        }
+
+        async fn fetch(&self) -> Result<RsaPublicKey, AppError> {
+            self.fetches.fetch_add(1, Ordering::SeqCst);
+            Ok(KEYS[self.current.load(Ordering::SeqCst)].clone())
+        }
+
+        fn fetches(&self) -> usize {
+            self.fetches.load(Ordering::SeqCst)
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_cached_key_until_ttl() {
+        let cache = PublicKeyCache::new(Duration::from_secs(60));
+        let server = Server::new();
+        let start = Instant::now();
+
+        let first = cache.get_at(start, || server.fetch()).await.unwrap();
+        assert_eq!(first, KEYS[0]);
+        assert_eq!(server.fetches(), 1);
+
+        let hit = cache
+            .get_at(start + Duration::from_secs(59), || server.fetch())
+            .await
+            .unwrap();
+        assert_eq!(hit, KEYS[0]);
+        assert_eq!(server.fetches(), 1);
+
+        cache
+            .get_at(start + Duration::from_secs(60), || server.fetch())
+            .await
+            .unwrap();
+        assert_eq!(server.fetches(), 2);
+        assert_eq!(cache.version(), 0);
+    }
+
+    #[tokio::test]
+    async fn invalidate_picks_up_a_rotated_key() {
+        let cache = PublicKeyCache::new(Duration::from_secs(60));
+        let server = Server::new();
+
+        assert_eq!(cache.get(|| server.fetch()).await.unwrap(), KEYS[0]);
+        server.current.store(1, Ordering::SeqCst);
+        // Still within the TTL, so the stale key is served...
+        assert_eq!(cache.get(|| server.fetch()).await.unwrap(), KEYS[0]);
+
+        // ...until a verification failure invalidates it.
+        cache.invalidate();
+        assert_eq!(cache.get(|| server.fetch()).await.unwrap(), KEYS[1]);
+        assert_eq!(server.fetches(), 2);
+        assert_eq!(cache.version(), 1);
+
+        cache.invalidate();
+        cache.get(|| server.fetch()).await.unwrap();
+        assert_eq!(cache.version(), 1, "same key again is not a rotation");
+    }
+
+    #[tokio::test]
+    async fn failed_fetch_leaves_cache_empty() {
+        let cache = PublicKeyCache::new(Duration::from_secs(60));
+        let calls = AtomicUsize::new(0);
+
+        let result = cache
+            .get(|| async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err(AppError::CaptivePortal)
+            })
+            .await;
+        assert!(matches!(result, Err(AppError::CaptivePortal)));
+        assert_eq!(calls.load(Ordering::SeqCst), 1, "only network errors retry");
+
+        let server = Server::new();
+        assert_eq!(cache.get(|| server.fetch()).await.unwrap(), KEYS[0]);
+        assert_eq!(server.fetches(), 1);
+    }
+}
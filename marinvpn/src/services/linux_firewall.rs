@@ -0,0 +1,520 @@
+//! The Linux kill switch as commands for either nftables or, where `nft` is
+//! missing, iptables. Both backends let out the same traffic and drop the
+//! rest.
+
+use crate::services::firewall_cleanup::LeftoverCheck;
+use crate::services::split_tunnel::BYPASS_MARK;
+
+/// Private ranges local sharing lets through outside the tunnel.
+const LAN_RANGES: [&str; 3] = ["192.168.0.0/16", "10.0.0.0/8", "172.16.0.0/12"];
+/// Neighbour discovery, which IPv6 needs to keep a link up at all.
+const ICMPV6_ND: [&str; 4] = [
+    "router-solicitation",
+    "router-advertisement",
+    "neighbor-solicitation",
+    "neighbor-advertisement",
+];
+
+/// What the kill switch lets out. Everything else is dropped.
+pub struct KillSwitchRules<'a> {
+    /// Relay addresses, reachable on the `allow` ports. Empty while no relay
+    /// is known, e.g. for lockdown when disconnected.
+    pub relay_v4: &'a [&'a str],
+    pub relay_v6: &'a [&'a str],
+    pub allow: &'a [(&'static str, u16)],
+    pub tunnel_iface: &'a str,
+    /// DHCPv6 and neighbour discovery.
+    pub ipv6_support: bool,
+    /// Traffic carrying the split tunneling bypass mark.
+    pub split_tunneling: bool,
+    /// IPv4 LAN ranges.
+    pub local_sharing: bool,
+}
+
+/// A firewall the kill switch can be built on. Commands are argument lists
+/// whose first entry is the program to run.
+pub trait Firewall: Send + Sync {
+    fn name(&self) -> &'static str;
+    /// The programs the commands use, each with arguments that make it exit
+    /// straight away, to check it is installed.
+    fn tools(&self) -> &'static [(&'static str, &'static [&'static str])];
+    fn enable(&self, rules: &KillSwitchRules) -> Vec<Vec<String>>;
+    /// Removes everything `enable` added. Harmless when nothing was.
+    fn disable(&self) -> Vec<Vec<String>>;
+    /// Finds what `disable` failed to remove.
+    fn leftovers(&self) -> Vec<LeftoverCheck>;
+}
+
+/// Backends in order of preference.
+pub fn backends() -> [Box<dyn Firewall>; 2] {
+    [Box::new(Nftables), Box::new(Iptables)]
+}
+
+/// An `inet` table whose output chain drops by default.
+pub struct Nftables;
+
+pub const NFT_TABLE: &str = "marinvpn_killswitch";
+
+impl Firewall for Nftables {
+    fn name(&self) -> &'static str {
+        "nftables"
+    }
+
+    fn tools(&self) -> &'static [(&'static str, &'static [&'static str])] {
+        &[("nft", &["--version"])]
+    }
+
+    fn enable(&self, rules: &KillSwitchRules) -> Vec<Vec<String>> {
+        let rule =
+            |parts: &[&str]| command(&["nft", "add", "rule", "inet", NFT_TABLE, "output"], parts);
+        // Adding an existing table or chain is a no-op, but rules would be
+        // appended twice, so a table left by an earlier call is emptied.
+        let mut out = vec![
+            command(&["nft", "add", "table", "inet", NFT_TABLE], &[]),
+            command(&["nft", "flush", "table", "inet", NFT_TABLE], &[]),
+            command(
+                &["nft", "add", "chain", "inet", NFT_TABLE, "output"],
+                &[
+                    "{", "type", "filter", "hook", "output", "priority", "0;", "policy", "drop;",
+                    "}",
+                ],
+            ),
+            command(
+                &["nft", "add", "chain", "inet", NFT_TABLE, "input"],
+                &[
+                    "{", "type", "filter", "hook", "input", "priority", "0;", "policy", "accept;",
+                    "}",
+                ],
+            ),
+            rule(&["oifname", "lo", "accept"]),
+        ];
+        for (family, relays) in [("ip", rules.relay_v4), ("ip6", rules.relay_v6)] {
+            for addr in relays {
+                for (proto, port) in rules.allow {
+                    let port = port.to_string();
+                    out.push(rule(&[
+                        family, "daddr", addr, proto, "dport", &port, "accept",
+                    ]));
+                }
+            }
+        }
+        out.push(rule(&["udp", "sport", "68", "dport", "67", "accept"]));
+        if rules.ipv6_support {
+            out.push(rule(&["udp", "sport", "546", "dport", "547", "accept"]));
+            let types = format!("{{ {} }}", ICMPV6_ND.join(", "));
+            out.push(rule(&["icmpv6", "type", &types, "accept"]));
+        }
+        out.push(rule(&["oifname", rules.tunnel_iface, "accept"]));
+        if rules.split_tunneling {
+            out.push(rule(&["mark", BYPASS_MARK, "accept"]));
+        }
+        if rules.local_sharing {
+            let lan = format!("{{ {} }}", LAN_RANGES.join(", "));
+            out.push(rule(&["ip", "daddr", &lan, "accept"]));
+        }
+        out.push(rule(&["ip6", "daddr", "::/0", "drop"]));
+        out
+    }
+
+    fn disable(&self) -> Vec<Vec<String>> {
+        vec![command(&["nft", "delete", "table", "inet", NFT_TABLE], &[])]
+    }
+
+    fn leftovers(&self) -> Vec<LeftoverCheck> {
+        vec![LeftoverCheck {
+            list: command(&["nft", "list", "tables"], &[]),
+            items: Box::new(|out| {
+                out.lines()
+                    .map(str::trim)
+                    .filter(|line| line.split_whitespace().last() == Some(NFT_TABLE))
+                    .map(String::from)
+                    .collect()
+            }),
+            // `table inet marinvpn_killswitch` becomes `nft delete table ...`.
+            remove: Box::new(|table| {
+                vec![command(
+                    &["nft", "delete"],
+                    &table.split_whitespace().collect::<Vec<_>>(),
+                )]
+            }),
+        }]
+    }
+}
+
+/// A chain per family that the output chain jumps to first, ending in a
+/// drop.
+pub struct Iptables;
+
+pub const IPT_CHAIN: &str = "MARINVPN_KILLSWITCH";
+
+impl Iptables {
+    /// Creates `IPT_CHAIN` empty and hooked first into OUTPUT, whether or
+    /// not an earlier run left it behind.
+    fn chain_setup(program: &str) -> Vec<Vec<String>> {
+        vec![
+            command(&[program, "-N", IPT_CHAIN], &[]),
+            command(&[program, "-F", IPT_CHAIN], &[]),
+            command(&[program, "-D", "OUTPUT", "-j", IPT_CHAIN], &[]),
+            command(&[program, "-I", "OUTPUT", "1", "-j", IPT_CHAIN], &[]),
+        ]
+    }
+}
+
+impl Firewall for Iptables {
+    fn name(&self) -> &'static str {
+        "iptables"
+    }
+
+    fn tools(&self) -> &'static [(&'static str, &'static [&'static str])] {
+        &[("iptables", &["--version"]), ("ip6tables", &["--version"])]
+    }
+
+    fn enable(&self, rules: &KillSwitchRules) -> Vec<Vec<String>> {
+        let append = |program: &str, parts: &[&str]| command(&[program, "-A", IPT_CHAIN], parts);
+        let v4 = |parts: &[&str]| append("iptables", parts);
+        let v6 = |parts: &[&str]| append("ip6tables", parts);
+        let mut out = Iptables::chain_setup("iptables");
+        out.extend(Iptables::chain_setup("ip6tables"));
+
+        out.push(v4(&["-o", "lo", "-j", "ACCEPT"]));
+        out.push(v6(&["-o", "lo", "-j", "ACCEPT"]));
+        for (program, relays) in [("iptables", rules.relay_v4), ("ip6tables", rules.relay_v6)] {
+            for addr in relays {
+                for (proto, port) in rules.allow {
+                    let port = port.to_string();
+                    out.push(append(
+                        program,
+                        &["-d", addr, "-p", proto, "--dport", &port, "-j", "ACCEPT"],
+                    ));
+                }
+            }
+        }
+        out.push(v4(&[
+            "-p", "udp", "--sport", "68", "--dport", "67", "-j", "ACCEPT",
+        ]));
+        if rules.ipv6_support {
+            out.push(v6(&[
+                "-p", "udp", "--sport", "546", "--dport", "547", "-j", "ACCEPT",
+            ]));
+            for kind in ICMPV6_ND {
+                out.push(v6(&[
+                    "-p",
+                    "ipv6-icmp",
+                    "--icmpv6-type",
+                    kind,
+                    "-j",
+                    "ACCEPT",
+                ]));
+            }
+        }
+        out.push(v4(&["-o", rules.tunnel_iface, "-j", "ACCEPT"]));
+        out.push(v6(&["-o", rules.tunnel_iface, "-j", "ACCEPT"]));
+        if rules.split_tunneling {
+            out.push(v4(&["-m", "mark", "--mark", BYPASS_MARK, "-j", "ACCEPT"]));
+            out.push(v6(&["-m", "mark", "--mark", BYPASS_MARK, "-j", "ACCEPT"]));
+        }
+        if rules.local_sharing {
+            for range in LAN_RANGES {
+                out.push(v4(&["-d", range, "-j", "ACCEPT"]));
+            }
+        }
+        out.push(v4(&["-j", "DROP"]));
+        out.push(v6(&["-j", "DROP"]));
+        out
+    }
+
+    fn disable(&self) -> Vec<Vec<String>> {
+        ["iptables", "ip6tables"]
+            .into_iter()
+            .flat_map(|program| {
+                [
+                    command(&[program, "-D", "OUTPUT", "-j", IPT_CHAIN], &[]),
+                    command(&[program, "-F", IPT_CHAIN], &[]),
+                    command(&[program, "-X", IPT_CHAIN], &[]),
+                ]
+            })
+            .collect()
+    }
+
+    fn leftovers(&self) -> Vec<LeftoverCheck> {
+        ["iptables", "ip6tables"]
+            .into_iter()
+            .map(|program| LeftoverCheck {
+                list: command(&[program, "-S"], &[]),
+                items: Box::new(|out| {
+                    let hooked = out.lines().any(|line| line.contains(IPT_CHAIN));
+                    if hooked {
+                        vec![IPT_CHAIN.to_string()]
+                    } else {
+                        Vec::new()
+                    }
+                }),
+                remove: Box::new(move |chain| {
+                    vec![
+                        command(&[program, "-D", "OUTPUT", "-j", chain], &[]),
+                        command(&[program, "-F", chain], &[]),
+                        command(&[program, "-X", chain], &[]),
+                    ]
+                }),
+            })
+            .collect()
+    }
+}
+
+fn command(head: &[&str], tail: &[&str]) -> Vec<String> {
+    head.iter().chain(tail).map(|s| s.to_string()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn rules<'a>(relay_v4: &'a [&'a str], relay_v6: &'a [&'a str]) -> KillSwitchRules<'a> {
+        KillSwitchRules {
+            relay_v4,
+            relay_v6,
+            allow: &[("udp", 51820)],
+            tunnel_iface: "marinvpn0",
+            ipv6_support: false,
+            split_tunneling: false,
+            local_sharing: false,
+        }
+    }
+
+    fn lines(commands: Vec<Vec<String>>) -> Vec<String> {
+        commands.into_iter().map(|c| c.join(" ")).collect()
+    }
+
+    #[test]
+    fn nftables_drops_by_default_and_allows_relay_and_tunnel() {
+        let out = lines(Nftables.enable(&rules(&["198.51.100.1"], &["2001:db8::1"])));
+        assert_eq!(
+            out,
+            [
+                "nft add table inet marinvpn_killswitch",
+                "nft flush table inet marinvpn_killswitch",
+                "nft add chain inet marinvpn_killswitch output { type filter hook output priority 0; policy drop; }",
+                "nft add chain inet marinvpn_killswitch input { type filter hook input priority 0; policy accept; }",
+                "nft add rule inet marinvpn_killswitch output oifname lo accept",
+                "nft add rule inet marinvpn_killswitch output ip daddr 198.51.100.1 udp dport 51820 accept",
+                "nft add rule inet marinvpn_killswitch output ip6 daddr 2001:db8::1 udp dport 51820 accept",
+                "nft add rule inet marinvpn_killswitch output udp sport 68 dport 67 accept",
+                "nft add rule inet marinvpn_killswitch output oifname marinvpn0 accept",
+                "nft add rule inet marinvpn_killswitch output ip6 daddr ::/0 drop",
+            ]
+        );
+        assert_eq!(
+            lines(Nftables.disable()),
+            ["nft delete table inet marinvpn_killswitch"]
+        );
+    }
+
+    #[test]
+    fn iptables_mirrors_the_nftables_allow_list() {
+        let out = lines(Iptables.enable(&rules(&["198.51.100.1"], &["2001:db8::1"])));
+        assert_eq!(
+            out,
+            [
+                "iptables -N MARINVPN_KILLSWITCH",
+                "iptables -F MARINVPN_KILLSWITCH",
+                "iptables -D OUTPUT -j MARINVPN_KILLSWITCH",
+                "iptables -I OUTPUT 1 -j MARINVPN_KILLSWITCH",
+                "ip6tables -N MARINVPN_KILLSWITCH",
+                "ip6tables -F MARINVPN_KILLSWITCH",
+                "ip6tables -D OUTPUT -j MARINVPN_KILLSWITCH",
+                "ip6tables -I OUTPUT 1 -j MARINVPN_KILLSWITCH",
+                "iptables -A MARINVPN_KILLSWITCH -o lo -j ACCEPT",
+                "ip6tables -A MARINVPN_KILLSWITCH -o lo -j ACCEPT",
+                "iptables -A MARINVPN_KILLSWITCH -d 198.51.100.1 -p udp --dport 51820 -j ACCEPT",
+                "ip6tables -A MARINVPN_KILLSWITCH -d 2001:db8::1 -p udp --dport 51820 -j ACCEPT",
+                "iptables -A MARINVPN_KILLSWITCH -p udp --sport 68 --dport 67 -j ACCEPT",
+                "iptables -A MARINVPN_KILLSWITCH -o marinvpn0 -j ACCEPT",
+                "ip6tables -A MARINVPN_KILLSWITCH -o marinvpn0 -j ACCEPT",
+                "iptables -A MARINVPN_KILLSWITCH -j DROP",
+                "ip6tables -A MARINVPN_KILLSWITCH -j DROP",
+            ]
+        );
+        assert_eq!(
+            lines(Iptables.disable()),
+            [
+                "iptables -D OUTPUT -j MARINVPN_KILLSWITCH",
+                "iptables -F MARINVPN_KILLSWITCH",
+                "iptables -X MARINVPN_KILLSWITCH",
+                "ip6tables -D OUTPUT -j MARINVPN_KILLSWITCH",
+                "ip6tables -F MARINVPN_KILLSWITCH",
+                "ip6tables -X MARINVPN_KILLSWITCH",
+            ]
+        );
+    }
+
+    /// The rules each chain holds after running commands the way nft and
+    /// iptables would.
+    #[derive(Debug, Default, PartialEq)]
+    struct Ruleset(BTreeMap<String, Vec<String>>);
+
+    impl Ruleset {
+        fn run(&mut self, commands: Vec<Vec<String>>) {
+            for c in commands {
+                let c: Vec<&str> = c.iter().map(String::as_str).collect();
+                let chains = &mut self.0;
+                match c.as_slice() {
+                    ["nft", "add", "table", ..] => {}
+                    ["nft", "flush", "table", _, table] | ["nft", "delete", "table", _, table] => {
+                        let delete = c[1] == "delete";
+                        chains.retain(|name, rules| {
+                            if name.starts_with(&format!("{} ", table)) {
+                                rules.clear();
+                                !delete
+                            } else {
+                                true
+                            }
+                        });
+                    }
+                    ["nft", "add", "chain", _, table, chain, ..] => {
+                        chains.entry(format!("{} {}", table, chain)).or_default();
+                    }
+                    ["nft", "add", "rule", _, table, chain, rule @ ..] => chains
+                        .get_mut(&format!("{} {}", table, chain))
+                        .expect("rule added to a missing chain")
+                        .push(rule.join(" ")),
+                    [program, flag, chain, rest @ ..] => {
+                        let key = format!("{} {}", program, chain);
+                        match *flag {
+                            "-N" => {
+                                chains.entry(key).or_default();
+                            }
+                            "-F" => chains.entry(key).or_default().clear(),
+                            "-X" => {
+                                chains.remove(&key);
+                            }
+                            "-A" => chains.entry(key).or_default().push(rest.join(" ")),
+                            "-I" => chains
+                                .entry(key)
+                                .or_default()
+                                .insert(rest[0].parse::<usize>().unwrap() - 1, rest[1..].join(" ")),
+                            "-D" => {
+                                let rules = chains.entry(key).or_default();
+                                if let Some(at) = rules.iter().position(|r| *r == rest.join(" ")) {
+                                    rules.remove(at);
+                                }
+                            }
+                            other => panic!("unexpected flag {}", other),
+                        }
+                    }
+                    other => panic!("unexpected command {:?}", other),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn enabling_twice_leaves_one_rule_set() {
+        let mut all = rules(&["198.51.100.1"], &["2001:db8::1"]);
+        all.ipv6_support = true;
+        all.split_tunneling = true;
+        all.local_sharing = true;
+
+        for firewall in backends() {
+            let mut once = Ruleset::default();
+            once.run(firewall.enable(&all));
+            let mut twice = Ruleset::default();
+            twice.run(firewall.enable(&all));
+            twice.run(firewall.enable(&all));
+            assert_eq!(once, twice, "{}", firewall.name());
+            assert!(once
+                .0
+                .values()
+                .flatten()
+                .any(|r| r.contains("198.51.100.1")));
+
+            // A later call with fewer allowances replaces the earlier set.
+            twice.run(firewall.enable(&rules(&[], &[])));
+            let mut fewer = Ruleset::default();
+            fewer.run(firewall.enable(&rules(&[], &[])));
+            assert_eq!(twice, fewer, "{}", firewall.name());
+
+            twice.run(firewall.disable());
+            assert!(
+                twice.0.values().all(Vec::is_empty),
+                "{}: {:?}",
+                firewall.name(),
+                twice
+            );
+        }
+    }
+
+    #[test]
+    fn leftovers_are_found_in_each_backends_listing() {
+        let nft = &Nftables.leftovers()[0];
+        assert_eq!(nft.list, ["nft", "list", "tables"]);
+        let tables =
+            "table inet filter\ntable inet marinvpn_killswitch\ntable inet marinvpn_split\n";
+        assert_eq!((nft.items)(tables), ["table inet marinvpn_killswitch"]);
+        assert_eq!(
+            lines((nft.remove)("table inet marinvpn_killswitch")),
+            ["nft delete table inet marinvpn_killswitch"]
+        );
+
+        let ipt = Iptables.leftovers();
+        assert_eq!(ipt.len(), 2);
+        assert_eq!(ipt[1].list, ["ip6tables", "-S"]);
+        assert!((ipt[0].items)("-P OUTPUT ACCEPT\n").is_empty());
+        let rules = "-P OUTPUT ACCEPT\n-N MARINVPN_KILLSWITCH\n-A OUTPUT -j MARINVPN_KILLSWITCH\n";
+        assert_eq!((ipt[0].items)(rules), ["MARINVPN_KILLSWITCH"]);
+        assert_eq!(
+            lines((ipt[1].remove)("MARINVPN_KILLSWITCH")),
+            [
+                "ip6tables -D OUTPUT -j MARINVPN_KILLSWITCH",
+                "ip6tables -F MARINVPN_KILLSWITCH",
+                "ip6tables -X MARINVPN_KILLSWITCH",
+            ]
+        );
+    }
+
+    #[test]
+    fn optional_allowances_appear_in_both_backends() {
+        let mut all = rules(&[], &[]);
+        all.ipv6_support = true;
+        all.split_tunneling = true;
+        all.local_sharing = true;
+
+        let nft = lines(Nftables.enable(&all));
+        assert!(
+            !nft.iter().any(|l| l.contains("dport 51820")),
+            "no relay yet"
+        );
+        for expected in [
+            "output udp sport 546 dport 547 accept",
+            "output icmpv6 type { router-solicitation, router-advertisement, neighbor-solicitation, neighbor-advertisement } accept",
+            "output mark 0x1000 accept",
+            "output ip daddr { 192.168.0.0/16, 10.0.0.0/8, 172.16.0.0/12 } accept",
+        ] {
+            assert!(nft.iter().any(|l| l.ends_with(expected)), "{}", expected);
+        }
+
+        let ipt = lines(Iptables.enable(&all));
+        assert!(
+            !ipt.iter().any(|l| l.contains("--dport 51820")),
+            "no relay yet"
+        );
+        for expected in [
+            "ip6tables -A MARINVPN_KILLSWITCH -p udp --sport 546 --dport 547 -j ACCEPT",
+            "ip6tables -A MARINVPN_KILLSWITCH -p ipv6-icmp --icmpv6-type router-solicitation -j ACCEPT",
+            "ip6tables -A MARINVPN_KILLSWITCH -p ipv6-icmp --icmpv6-type neighbor-advertisement -j ACCEPT",
+            "iptables -A MARINVPN_KILLSWITCH -m mark --mark 0x1000 -j ACCEPT",
+            "ip6tables -A MARINVPN_KILLSWITCH -m mark --mark 0x1000 -j ACCEPT",
+            "iptables -A MARINVPN_KILLSWITCH -d 172.16.0.0/12 -j ACCEPT",
+        ] {
+            assert!(ipt.contains(&expected.to_string()), "{}", expected);
+        }
+        // The drops stay last, after every allowance.
+        assert_eq!(
+            ipt[ipt.len() - 2..],
+            [
+                "iptables -A MARINVPN_KILLSWITCH -j DROP",
+                "ip6tables -A MARINVPN_KILLSWITCH -j DROP",
+            ]
+        );
+    }
+}
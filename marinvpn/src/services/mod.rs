@@ -1,15 +1,41 @@
+pub mod api_proxy;
 pub mod apps;
 pub mod auth;
+pub mod autostart;
+pub mod cidr;
+pub mod connect_timing;
+pub mod connection_stats;
+pub mod dns_routes;
+pub mod doh;
+pub mod ech;
+pub mod firewall_cleanup;
+pub mod firewall_state;
+pub mod key_cache;
+pub mod linux_firewall;
+pub mod network_id;
+pub mod power;
+pub mod privacy_audit;
+pub mod qr;
+pub mod report_crypto;
+pub mod request_log;
+pub mod self_test;
 pub mod servers;
+pub mod split_tunnel;
+pub mod stealth_probe;
 pub mod vpn;
+#[cfg(test)]
+pub mod vpn_mock;
+pub mod wg_conf;
+pub mod wg_keys;
+pub mod wg_show;
 
 use crate::error::AppError;
-use crate::models::{CommonVpnServer, WireGuardConfig};
+use crate::models::{CommonVpnServer, ServerSettings, WireGuardConfig};
 use async_trait::async_trait;
-use marinvpn_common::DnsBlockingState;
+use std::sync::Arc;
 
 #[async_trait]
-pub trait AppService: Clone + Send + Sync + 'static {
+pub trait AppService: Send + Sync + 'static {
     async fn find_best_server(&self, country: Option<&str>) -> Result<CommonVpnServer, AppError>;
     async fn find_best_server_excluding(
         &self,
@@ -20,8 +46,7 @@ pub trait AppService: Clone + Send + Sync + 'static {
         &self,
         location: &str,
         token: &str,
-        dns_blocking: Option<DnsBlockingState>,
-        quantum_resistant: bool,
+        settings: &ServerSettings,
     ) -> Result<WireGuardConfig, AppError>;
     async fn get_servers(&self) -> Result<Vec<CommonVpnServer>, AppError>;
     async fn measure_latency(&self, endpoint: &str) -> Option<u32>;
@@ -48,11 +73,9 @@ impl AppService for ProductionAppService {
         &self,
         location: &str,
         token: &str,
-        dns_blocking: Option<DnsBlockingState>,
-        quantum_resistant: bool,
+        settings: &ServerSettings,
     ) -> Result<WireGuardConfig, AppError> {
-        auth::AuthService::get_anonymous_config(location, token, dns_blocking, quantum_resistant)
-            .await
+        auth::AuthService::get_anonymous_config(location, token, settings).await
     }
 
     async fn get_servers(&self) -> Result<Vec<CommonVpnServer>, AppError> {
@@ -63,3 +86,58 @@ impl AppService for ProductionAppService {
         servers::ServersService::measure_latency(endpoint).await
     }
 }
+
+/// Type-erased `AppService` so an implementation can be handed to
+/// `AppStateProvider` as a prop.
+#[derive(Clone)]
+pub struct SharedAppService(Arc<dyn AppService>);
+
+impl SharedAppService {
+    pub fn new(service: impl AppService) -> Self {
+        Self(Arc::new(service))
+    }
+}
+
+impl Default for SharedAppService {
+    fn default() -> Self {
+        Self::new(ProductionAppService)
+    }
+}
+
+impl PartialEq for SharedAppService {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+#[async_trait]
+impl AppService for SharedAppService {
+    async fn find_best_server(&self, country: Option<&str>) -> Result<CommonVpnServer, AppError> {
+        self.0.find_best_server(country).await
+    }
+
+    async fn find_best_server_excluding(
+        &self,
+        country: Option<&str>,
+        exclude: &[String],
+    ) -> Result<CommonVpnServer, AppError> {
+        self.0.find_best_server_excluding(country, exclude).await
+    }
+
+    async fn get_anonymous_config(
+        &self,
+        location: &str,
+        token: &str,
+        settings: &ServerSettings,
+    ) -> Result<WireGuardConfig, AppError> {
+        self.0.get_anonymous_config(location, token, settings).await
+    }
+
+    async fn get_servers(&self) -> Result<Vec<CommonVpnServer>, AppError> {
+        self.0.get_servers().await
+    }
+
+    async fn measure_latency(&self, endpoint: &str) -> Option<u32> {
+        self.0.measure_latency(endpoint).await
+    }
+}
@@ -0,0 +1,122 @@
+use sha2::{Digest, Sha256};
+use std::net::Ipv4Addr;
+
+/// An opaque id for the network this machine is on, derived from the default
+/// gateway. It is hashed so the settings file never records which routers
+/// the user has been behind. `None` when there is no default route or the
+/// platform is not supported yet.
+pub fn current() -> Option<String> {
+    #[cfg(target_os = "linux")]
+    {
+        let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+        let arp = std::fs::read_to_string("/proc/net/arp").unwrap_or_default();
+        from_tables(&routes, &arp)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        None
+    }
+}
+
+/// Builds the id from `/proc/net/route` and `/proc/net/arp`. The gateway's
+/// MAC tells apart the many networks that all use 192.168.1.1; without an
+/// ARP entry the interface and gateway address are used instead.
+fn from_tables(routes: &str, arp: &str) -> Option<String> {
+    let (iface, gateway) = default_gateway(routes)?;
+    let key = match gateway_mac(arp, gateway) {
+        Some(mac) => mac,
+        None => format!("{}/{}", iface, gateway),
+    };
+    Some(hex::encode(&Sha256::digest(key.as_bytes())[..8]))
+}
+
+/// The default route with the lowest metric, as `(interface, gateway)`.
+fn default_gateway(routes: &str) -> Option<(String, Ipv4Addr)> {
+    routes
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let f: Vec<&str> = line.split_whitespace().collect();
+            if f.len() < 8 || f[1] != "00000000" || f[7] != "00000000" {
+                return None;
+            }
+            // Stored in host (little-endian) byte order.
+            let gateway = u32::from_str_radix(f[2], 16).ok()?;
+            if gateway == 0 {
+                return None;
+            }
+            let metric: u32 = f[6].parse().ok()?;
+            Some((
+                metric,
+                f[0].to_string(),
+                Ipv4Addr::from(gateway.swap_bytes()),
+            ))
+        })
+        .min_by_key(|(metric, _, _)| *metric)
+        .map(|(_, iface, gateway)| (iface, gateway))
+}
+
+fn gateway_mac(arp: &str, gateway: Ipv4Addr) -> Option<String> {
+    let gateway = gateway.to_string();
+    arp.lines().skip(1).find_map(|line| {
+        let f: Vec<&str> = line.split_whitespace().collect();
+        let mac = *f.get(3)?;
+        (f[0] == gateway && mac != "00:00:00:00:00:00").then(|| mac.to_lowercase())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROUTES: &str = "\
+Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+eth0\t00000000\t0100A8C0\t0003\t0\t0\t100\t00000000\t0\t0\t0
+wlan0\t00000000\t0101A8C0\t0003\t0\t0\t600\t00000000\t0\t0\t0
+wlan0\t0001A8C0\t00000000\t0001\t0\t0\t600\t00FFFFFF\t0\t0\t0
+";
+
+    const ARP: &str = "\
+IP address       HW type     Flags       HW address            Mask     Device
+192.168.0.1      0x1         0x2         A4:91:B1:0C:22:7E     *        eth0
+192.168.1.1      0x1         0x0         00:00:00:00:00:00     *        wlan0
+";
+
+    #[test]
+    fn picks_the_default_route_with_the_lowest_metric() {
+        assert_eq!(
+            default_gateway(ROUTES),
+            Some(("eth0".to_string(), Ipv4Addr::new(192, 168, 0, 1)))
+        );
+        let subnet_only = ROUTES
+            .lines()
+            .filter(|l| !l.contains("\t0003\t"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert_eq!(default_gateway(&subnet_only), None);
+    }
+
+    #[test]
+    fn incomplete_arp_entries_are_ignored() {
+        assert_eq!(
+            gateway_mac(ARP, Ipv4Addr::new(192, 168, 0, 1)),
+            Some("a4:91:b1:0c:22:7e".to_string())
+        );
+        assert_eq!(gateway_mac(ARP, Ipv4Addr::new(192, 168, 1, 1)), None);
+    }
+
+    #[test]
+    fn id_is_stable_and_follows_the_gateway() {
+        let id = from_tables(ROUTES, ARP).unwrap();
+        assert_eq!(id.len(), 16);
+        assert!(!id.contains("a4:91"));
+        assert_eq!(from_tables(ROUTES, ARP), Some(id.clone()));
+
+        // Same address, different router.
+        let other_router = ARP.replace("A4:91:B1:0C:22:7E", "3C:22:FB:11:00:01");
+        assert_ne!(from_tables(ROUTES, &other_router), Some(id.clone()));
+        // Without the ARP entry the id falls back to the route itself.
+        assert_ne!(from_tables(ROUTES, ""), Some(id));
+        assert_eq!(from_tables("", ARP), None);
+    }
+}
@@ -0,0 +1,186 @@
+/// Where the machine is drawing power from right now.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PowerSource {
+    Mains,
+    Battery,
+    /// No battery was found, or the platform is not supported yet.
+    Unknown,
+}
+
+/// Reads the current power source. Cheap enough to call before every sync.
+pub async fn current() -> PowerSource {
+    #[cfg(target_os = "linux")]
+    {
+        from_supplies(&linux_supplies())
+    }
+    #[cfg(target_os = "macos")]
+    {
+        match tokio::process::Command::new("pmset")
+            .args(["-g", "batt"])
+            .output()
+            .await
+        {
+            Ok(out) => from_pmset(&String::from_utf8_lossy(&out.stdout)),
+            Err(_) => PowerSource::Unknown,
+        }
+    }
+    #[cfg(target_os = "windows")]
+    {
+        match tokio::process::Command::new("powershell")
+            .args([
+                "-NoProfile",
+                "-Command",
+                "(Get-CimInstance -ClassName Win32_Battery).BatteryStatus",
+            ])
+            .output()
+            .await
+        {
+            Ok(out) => from_battery_status(&String::from_utf8_lossy(&out.stdout)),
+            Err(_) => PowerSource::Unknown,
+        }
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+    {
+        PowerSource::Unknown
+    }
+}
+
+/// One entry under `/sys/class/power_supply`.
+#[derive(Debug, Default)]
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+struct Supply {
+    kind: String,
+    online: Option<bool>,
+    status: String,
+}
+
+#[cfg(target_os = "linux")]
+fn linux_supplies() -> Vec<Supply> {
+    let read = |dir: &std::path::Path, name: &str| {
+        std::fs::read_to_string(dir.join(name))
+            .map(|s| s.trim().to_string())
+            .unwrap_or_default()
+    };
+    let Ok(entries) = std::fs::read_dir("/sys/class/power_supply") else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .map(|entry| {
+            let dir = entry.path();
+            Supply {
+                kind: read(&dir, "type"),
+                online: match read(&dir, "online").as_str() {
+                    "1" => Some(true),
+                    "0" => Some(false),
+                    _ => None,
+                },
+                status: read(&dir, "status"),
+            }
+        })
+        .collect()
+}
+
+/// An adapter that is plugged in wins; otherwise a discharging battery means
+/// we are on battery. A battery that is charging or full implies mains power
+/// even when the adapter does not show up.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn from_supplies(supplies: &[Supply]) -> PowerSource {
+    let batteries: Vec<&Supply> = supplies.iter().filter(|s| s.kind == "Battery").collect();
+    if supplies
+        .iter()
+        .any(|s| s.kind != "Battery" && s.online == Some(true))
+    {
+        PowerSource::Mains
+    } else if batteries.iter().any(|b| b.status == "Discharging") {
+        PowerSource::Battery
+    } else if batteries.is_empty() {
+        PowerSource::Unknown
+    } else {
+        PowerSource::Mains
+    }
+}
+
+/// Reads the "Now drawing from" line of `pmset -g batt`.
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+fn from_pmset(output: &str) -> PowerSource {
+    let Some(line) = output.lines().find(|l| l.contains("drawing from")) else {
+        return PowerSource::Unknown;
+    };
+    if line.contains("'Battery Power'") {
+        PowerSource::Battery
+    } else if line.contains("'AC Power'") {
+        PowerSource::Mains
+    } else {
+        PowerSource::Unknown
+    }
+}
+
+/// `Win32_Battery.BatteryStatus`: 1 is discharging and 4/5 are low and
+/// critical, both only reported off mains. No output means no battery.
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+fn from_battery_status(output: &str) -> PowerSource {
+    match output.lines().map(str::trim).find(|l| !l.is_empty()) {
+        None => PowerSource::Unknown,
+        Some("1" | "4" | "5") => PowerSource::Battery,
+        Some(status) if status.parse::<u16>().is_ok() => PowerSource::Mains,
+        Some(_) => PowerSource::Unknown,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn supply(kind: &str, online: Option<bool>, status: &str) -> Supply {
+        Supply {
+            kind: kind.to_string(),
+            online,
+            status: status.to_string(),
+        }
+    }
+
+    #[test]
+    fn linux_supplies_tell_mains_from_battery() {
+        let unplugged = [
+            supply("Mains", Some(false), ""),
+            supply("Battery", None, "Discharging"),
+        ];
+        assert_eq!(from_supplies(&unplugged), PowerSource::Battery);
+
+        let plugged = [
+            supply("Mains", Some(true), ""),
+            supply("Battery", None, "Charging"),
+        ];
+        assert_eq!(from_supplies(&plugged), PowerSource::Mains);
+
+        // No adapter entry, but the battery is not draining.
+        let full = [supply("Battery", None, "Full")];
+        assert_eq!(from_supplies(&full), PowerSource::Mains);
+
+        // A desktop, or a peripheral's battery reporting on its own.
+        assert_eq!(from_supplies(&[]), PowerSource::Unknown);
+        let mouse = [supply("USB", Some(false), "")];
+        assert_eq!(from_supplies(&mouse), PowerSource::Unknown);
+    }
+
+    #[test]
+    fn pmset_output_is_parsed() {
+        let battery = "Now drawing from 'Battery Power'\n \
+            -InternalBattery-0 (id=4653155)\t87%; discharging; 6:12 remaining present: true\n";
+        assert_eq!(from_pmset(battery), PowerSource::Battery);
+        let ac = "Now drawing from 'AC Power'\n \
+            -InternalBattery-0 (id=4653155)\t100%; charged; 0:00 remaining present: true\n";
+        assert_eq!(from_pmset(ac), PowerSource::Mains);
+        assert_eq!(from_pmset(""), PowerSource::Unknown);
+    }
+
+    #[test]
+    fn windows_battery_status_is_parsed() {
+        assert_eq!(from_battery_status("1\r\n"), PowerSource::Battery);
+        assert_eq!(from_battery_status("4\r\n"), PowerSource::Battery);
+        assert_eq!(from_battery_status("2\r\n"), PowerSource::Mains);
+        assert_eq!(from_battery_status("6\r\n"), PowerSource::Mains);
+        assert_eq!(from_battery_status(""), PowerSource::Unknown);
+    }
+}
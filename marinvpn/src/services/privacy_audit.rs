@@ -0,0 +1,334 @@
+//! Checks outgoing requests for data that ties them to the user where the
+//! endpoint has no use for it: the account behind the anonymous config flow,
+//! a device name outside device management, a position finer than a city.
+//!
+//! Every request [`request_log::send`](super::request_log::send) makes is
+//! audited under test, and a finding fails the test. Setting
+//! `MARIN_PRIVACY_AUDIT` audits them at runtime too, logging findings as
+//! errors. The device attestation key is not flagged: the server checks it
+//! on every request.
+
+use marinvpn_common::account_number;
+use serde_json::Value;
+use std::fmt;
+
+/// Endpoints that act for an account, so may name it and carry its session.
+/// Every other request, including DNS-over-HTTPS lookups, must not.
+const ACCOUNT_ENDPOINTS: &[&str] = &[
+    "/account/login",
+    "/account/devices",
+    "/account/devices/remove",
+    "/account/sessions",
+    "/account/logout",
+    "/account/panic",
+    "/auth/issue-token",
+    "/auth/refresh",
+    "/vpn/config",
+    "/vpn/report",
+    "/vpn/panic",
+];
+
+/// Endpoints that name a device: logging in may kick one off the account,
+/// removal names the one to remove.
+const DEVICE_NAME_ENDPOINTS: &[&str] = &["/account/login", "/account/devices/remove"];
+
+/// Field and header names, lowercased and without `_` or `-`, so that
+/// `account_number`, `accountNumber` and `Account-Number` all match.
+const ACCOUNT_FIELDS: &[&str] = &["account", "accountnumber", "authorization", "cookie"];
+const DEVICE_NAME_FIELDS: &[&str] = &["devicename", "kickdevice", "hostname"];
+const GEO_FIELDS: &[&str] = &[
+    "lat",
+    "latitude",
+    "lon",
+    "lng",
+    "longitude",
+    "coordinates",
+    "gps",
+    "geolocation",
+];
+
+/// Something identifying in a request, with where it was found. The value
+/// itself is left out so a finding can be logged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Leak {
+    /// An account number or session on an endpoint that should not be
+    /// linkable to the account.
+    Account(String),
+    DeviceName(String),
+    PreciseLocation(String),
+}
+
+impl fmt::Display for Leak {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Account(at) => write!(f, "account data in {}", at),
+            Self::DeviceName(at) => write!(f, "a device name in {}", at),
+            Self::PreciseLocation(at) => write!(f, "a precise location in {}", at),
+        }
+    }
+}
+
+/// One named value of a request: a header, a query parameter or a field of
+/// a JSON body.
+struct Field {
+    at: String,
+    name: String,
+    value: String,
+}
+
+/// Everything identifying in `request` that its endpoint does not need.
+pub fn audit(request: &reqwest::Request) -> Vec<Leak> {
+    let path = request.url().path();
+    let for_account = ACCOUNT_ENDPOINTS.iter().any(|e| path.ends_with(e));
+    let names_device = DEVICE_NAME_ENDPOINTS.iter().any(|e| path.ends_with(e));
+
+    let mut leaks = Vec::new();
+    for field in fields(request) {
+        let name = normalized(&field.name);
+        if GEO_FIELDS.contains(&name.as_str()) {
+            leaks.push(Leak::PreciseLocation(field.at));
+        } else if !names_device && DEVICE_NAME_FIELDS.contains(&name.as_str()) {
+            leaks.push(Leak::DeviceName(field.at));
+        } else if !for_account
+            && (ACCOUNT_FIELDS.contains(&name.as_str()) || holds_account_number(&field.value))
+        {
+            leaks.push(Leak::Account(field.at));
+        }
+    }
+    leaks
+}
+
+/// Whether outgoing requests are audited.
+pub fn enabled() -> bool {
+    cfg!(test) || std::env::var_os("MARIN_PRIVACY_AUDIT").is_some()
+}
+
+/// Audits `request`. Under test a finding panics, otherwise it is logged.
+pub fn check(request: &reqwest::Request) {
+    let leaks = audit(request);
+    if leaks.is_empty() {
+        return;
+    }
+    let path = request.url().path();
+    if cfg!(test) {
+        panic!("{} {} leaks {:?}", request.method(), path, leaks);
+    }
+    for leak in leaks {
+        tracing::error!(
+            "Privacy audit: {} {} sends {}",
+            request.method(),
+            path,
+            leak
+        );
+    }
+}
+
+fn normalized(name: &str) -> String {
+    name.chars()
+        .filter(|c| !matches!(c, '_' | '-'))
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+fn fields(request: &reqwest::Request) -> Vec<Field> {
+    let mut fields: Vec<Field> = request
+        .headers()
+        .iter()
+        .map(|(name, value)| Field {
+            at: format!("header {}", name),
+            name: name.to_string(),
+            value: String::from_utf8_lossy(value.as_bytes()).into_owned(),
+        })
+        .collect();
+    fields.extend(request.url().query_pairs().map(|(name, value)| Field {
+        at: format!("query parameter {}", name),
+        name: name.into_owned(),
+        value: value.into_owned(),
+    }));
+
+    let body = request.body().and_then(|body| body.as_bytes());
+    match body.map(serde_json::from_slice::<Value>) {
+        Some(Ok(json)) => body_fields(&json, "", &mut fields),
+        Some(Err(_)) => fields.push(Field {
+            at: "body".to_string(),
+            name: String::new(),
+            value: String::from_utf8_lossy(body.unwrap_or_default()).into_owned(),
+        }),
+        None => {}
+    }
+    fields
+}
+
+/// Flattens `value` into fields named after their key. Array items keep the
+/// key of the array.
+fn body_fields(value: &Value, path: &str, fields: &mut Vec<Field>) {
+    let name = path.rsplit('.').next().unwrap_or_default();
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                body_fields(value, &path, fields);
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                body_fields(item, path, fields);
+            }
+        }
+        Value::Null => {}
+        leaf => fields.push(Field {
+            at: format!("body field {}", path),
+            name: name.to_string(),
+            value: match leaf {
+                Value::String(s) => s.clone(),
+                other => other.to_string(),
+            },
+        }),
+    }
+}
+
+/// Whether `value` has an account number in it, as typed or in groups
+/// separated by spaces or dashes.
+fn holds_account_number(value: &str) -> bool {
+    value
+        .split(|c: char| !(account_number::ALPHABET.contains(c) || c == ' ' || c == '-'))
+        .any(|run| account_number::validate(run).is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const API: &str = "https://api.marinvpn.example/api/v1";
+
+    fn post(path: &str, body: Value) -> reqwest::Request {
+        reqwest::Client::new()
+            .post(format!("{}{}", API, path))
+            .json(&body)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn anonymous_requests_may_not_name_the_account() {
+        let clean = post(
+            "/vpn/config-anonymous",
+            json!({ "location": "Sweden", "pub_key": "p3kFZ1ZQ9H4XGqQb8t5cQkM2x0p0vQ0Jm4oUuVb2Z2U=" }),
+        );
+        assert!(audit(&clean).is_empty());
+
+        let leaky = post(
+            "/vpn/config-anonymous",
+            json!({ "location": "Sweden", "account_number": "ABCDEFGHJKLMNPQR" }),
+        );
+        assert_eq!(
+            audit(&leaky),
+            [Leak::Account("body field account_number".to_string())]
+        );
+
+        let hidden = post(
+            "/vpn/config-anonymous",
+            json!({ "extra": { "note": "mine is ABCD-EFGH-JKLM-NPQR" } }),
+        );
+        assert_eq!(
+            audit(&hidden),
+            [Leak::Account("body field extra.note".to_string())]
+        );
+
+        let session = reqwest::Client::new()
+            .get(format!("{}/vpn/pow-challenge", API))
+            .bearer_auth("token")
+            .build()
+            .unwrap();
+        assert_eq!(
+            audit(&session),
+            [Leak::Account("header authorization".to_string())]
+        );
+    }
+
+    #[test]
+    fn account_endpoints_may_name_the_account() {
+        let login = post(
+            "/account/login",
+            json!({ "account_number": "ABCDEFGHJKLMNPQR", "kick_device": "brave tiger" }),
+        );
+        assert!(audit(&login).is_empty());
+
+        let config = post(
+            "/vpn/config",
+            json!({ "account_number": "ABCDEFGHJKLMNPQR" }),
+        );
+        assert!(audit(&config).is_empty());
+    }
+
+    #[test]
+    fn device_names_only_go_where_a_device_is_picked() {
+        let remove = post(
+            "/account/devices/remove",
+            json!({ "account_number": "ABCDEFGHJKLMNPQR", "device_name": "brave tiger" }),
+        );
+        assert!(audit(&remove).is_empty());
+
+        let report = post(
+            "/vpn/report",
+            json!({ "account_number": "ABCDEFGHJKLMNPQR", "os": { "hostname": "alices-laptop" } }),
+        );
+        assert_eq!(
+            audit(&report),
+            [Leak::DeviceName("body field os.hostname".to_string())]
+        );
+    }
+
+    #[test]
+    fn coordinates_are_flagged_everywhere() {
+        let request = post(
+            "/vpn/config",
+            json!({ "location": "Sweden", "position": { "Latitude": 59.33, "lng": 18.06 } }),
+        );
+        assert_eq!(
+            audit(&request),
+            [
+                Leak::PreciseLocation("body field position.Latitude".to_string()),
+                Leak::PreciseLocation("body field position.lng".to_string()),
+            ]
+        );
+
+        let query = reqwest::Client::new()
+            .get(format!("{}/vpn/servers", API))
+            .query(&[("lat", "59.33")])
+            .build()
+            .unwrap();
+        assert_eq!(
+            audit(&query),
+            [Leak::PreciseLocation("query parameter lat".to_string())]
+        );
+    }
+
+    #[test]
+    fn keys_and_hashes_are_not_mistaken_for_account_numbers() {
+        for value in [
+            "p3kFZ1ZQ9H4XGqQb8t5cQkM2x0p0vQ0Jm4oUuVb2Z2U=",
+            "ABCDEFGHJKLMNPQRS",
+            "ABCDEFGHJKLMNPQ",
+            "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08",
+            "Sweden",
+        ] {
+            assert!(!holds_account_number(value), "{}", value);
+        }
+        assert!(holds_account_number("ABCD EFGH JKLM NPQR"));
+    }
+
+    #[test]
+    fn leaks_are_reported_without_their_value() {
+        let leak = Leak::Account("body field account_number".to_string());
+        assert_eq!(
+            leak.to_string(),
+            "account data in body field account_number"
+        );
+    }
+}
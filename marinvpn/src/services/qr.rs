@@ -0,0 +1,426 @@
+//! Just enough of QR code encoding to show an account number: version 1
+//! (21x21 modules), error correction level M and alphanumeric mode, which
+//! holds up to 20 characters. The account number grouped in fours is 19.
+
+use marinvpn_common::account_number;
+
+const VERSION: usize = 1;
+const SIZE: usize = 17 + 4 * VERSION;
+const DATA_CODEWORDS: usize = 16;
+const EC_CODEWORDS: usize = 10;
+const ALPHANUMERIC: &str = "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ $%*+-./:";
+/// Format bits for level M; the mask number is added to them.
+const LEVEL_M: u32 = 0b00;
+
+/// A square of dark and light modules, without the quiet zone.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QrCode {
+    modules: Vec<bool>,
+}
+
+/// What the account QR code holds: the account number in its usual groups
+/// and nothing else, so a photo of the screen gives away no session. A
+/// phone shows it as text to type in, and the login field accepts it as
+/// pasted. `None` for anything that is not a valid account number.
+pub fn account_payload(account: &str) -> Option<String> {
+    account_number::validate(account)
+        .ok()
+        .map(|normalized| account_number::format_groups(&normalized))
+}
+
+pub fn account_code(account: &str) -> Option<QrCode> {
+    QrCode::encode(&account_payload(account)?)
+}
+
+impl QrCode {
+    /// Encodes `text` if it fits: at most 20 characters from the QR
+    /// alphanumeric set (digits, uppercase letters, space and `$%*+-./:`).
+    pub fn encode(text: &str) -> Option<Self> {
+        let data = data_codewords(text)?;
+        let mut codewords = data.clone();
+        codewords.extend(reed_solomon(&data, EC_CODEWORDS));
+
+        let mut base = Matrix::new();
+        base.draw_function_patterns();
+        base.draw_codewords(&codewords);
+
+        let best = (0..8)
+            .map(|mask| {
+                let mut candidate = base.clone();
+                candidate.apply_mask(mask);
+                candidate.draw_format_bits(mask);
+                candidate
+            })
+            .min_by_key(Matrix::penalty)?;
+        Some(Self {
+            modules: best.modules,
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        SIZE
+    }
+
+    pub fn is_dark(&self, x: usize, y: usize) -> bool {
+        x < SIZE && y < SIZE && self.modules[y * SIZE + x]
+    }
+
+    /// An SVG path drawing each dark module as a unit square, offset by
+    /// `border` modules of quiet zone.
+    pub fn svg_path(&self, border: usize) -> String {
+        let mut path = String::new();
+        for y in 0..SIZE {
+            for x in 0..SIZE {
+                if self.is_dark(x, y) {
+                    path.push_str(&format!("M{},{}h1v1h-1z", x + border, y + border));
+                }
+            }
+        }
+        path
+    }
+}
+
+/// Mode indicator, count, data, terminator and padding, as bytes.
+fn data_codewords(text: &str) -> Option<Vec<u8>> {
+    let values: Vec<u32> = text
+        .chars()
+        .map(|c| ALPHANUMERIC.find(c).map(|i| i as u32))
+        .collect::<Option<_>>()?;
+    let capacity = DATA_CODEWORDS * 8;
+
+    let mut bits = Bits::default();
+    bits.push(0b0010, 4);
+    bits.push(values.len() as u32, 9);
+    for pair in values.chunks(2) {
+        match pair {
+            [a, b] => bits.push(a * 45 + b, 11),
+            [a] => bits.push(*a, 6),
+            _ => unreachable!(),
+        }
+    }
+    if bits.len() > capacity {
+        return None;
+    }
+    bits.push(0, (capacity - bits.len()).min(4));
+    bits.push(0, (8 - bits.len() % 8) % 8);
+
+    let mut bytes = bits.into_bytes();
+    for pad in [0xEC, 0x11].into_iter().cycle() {
+        if bytes.len() == DATA_CODEWORDS {
+            break;
+        }
+        bytes.push(pad);
+    }
+    Some(bytes)
+}
+
+#[derive(Default)]
+struct Bits(Vec<bool>);
+
+impl Bits {
+    fn push(&mut self, value: u32, count: usize) {
+        for i in (0..count).rev() {
+            self.0.push((value >> i) & 1 == 1);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.0
+            .chunks(8)
+            .map(|byte| byte.iter().fold(0, |acc, &bit| (acc << 1) | bit as u8))
+            .collect()
+    }
+}
+
+/// Multiplication in GF(2^8) modulo the QR polynomial x^8+x^4+x^3+x^2+1.
+fn gf_mul(x: u8, y: u8) -> u8 {
+    let mut z: u32 = 0;
+    for i in (0..8).rev() {
+        z = (z << 1) ^ ((z >> 7) * 0x11D);
+        z ^= ((y as u32 >> i) & 1) * x as u32;
+    }
+    z as u8
+}
+
+/// The `degree` error correction codewords for `data`.
+fn reed_solomon(data: &[u8], degree: usize) -> Vec<u8> {
+    // Generator polynomial, highest coefficient (always 1) left out.
+    let mut divisor = vec![0u8; degree];
+    divisor[degree - 1] = 1;
+    let mut root = 1u8;
+    for _ in 0..degree {
+        for j in 0..degree {
+            divisor[j] = gf_mul(divisor[j], root);
+            if j + 1 < degree {
+                divisor[j] ^= divisor[j + 1];
+            }
+        }
+        root = gf_mul(root, 0x02);
+    }
+
+    let mut remainder = vec![0u8; degree];
+    for &byte in data {
+        let factor = byte ^ remainder.remove(0);
+        remainder.push(0);
+        for (r, &d) in remainder.iter_mut().zip(&divisor) {
+            *r ^= gf_mul(d, factor);
+        }
+    }
+    remainder
+}
+
+#[derive(Clone)]
+struct Matrix {
+    modules: Vec<bool>,
+    /// Finder, timing and format modules, which data and masks skip.
+    reserved: Vec<bool>,
+}
+
+impl Matrix {
+    fn new() -> Self {
+        Self {
+            modules: vec![false; SIZE * SIZE],
+            reserved: vec![false; SIZE * SIZE],
+        }
+    }
+
+    fn get(&self, x: usize, y: usize) -> bool {
+        self.modules[y * SIZE + x]
+    }
+
+    fn set_function(&mut self, x: usize, y: usize, dark: bool) {
+        self.modules[y * SIZE + x] = dark;
+        self.reserved[y * SIZE + x] = true;
+    }
+
+    fn draw_function_patterns(&mut self) {
+        for i in 0..SIZE {
+            self.set_function(6, i, i % 2 == 0);
+            self.set_function(i, 6, i % 2 == 0);
+        }
+        for (cx, cy) in [(3, 3), (SIZE - 4, 3), (3, SIZE - 4)] {
+            self.draw_finder(cx, cy);
+        }
+        // Reserve the format areas; the bits go in once the mask is known.
+        self.draw_format_bits(0);
+    }
+
+    /// A finder pattern centred on (cx, cy), with its light separator.
+    fn draw_finder(&mut self, cx: usize, cy: usize) {
+        for dy in -4i32..=4 {
+            for dx in -4i32..=4 {
+                let (x, y) = (cx as i32 + dx, cy as i32 + dy);
+                if !(0..SIZE as i32).contains(&x) || !(0..SIZE as i32).contains(&y) {
+                    continue;
+                }
+                let ring = dx.abs().max(dy.abs());
+                self.set_function(x as usize, y as usize, ring != 2 && ring != 4);
+            }
+        }
+    }
+
+    fn draw_format_bits(&mut self, mask: u32) {
+        let data = (LEVEL_M << 3) | mask;
+        let mut rem = data;
+        for _ in 0..10 {
+            rem = (rem << 1) ^ ((rem >> 9) * 0x537);
+        }
+        let bits = ((data << 10) | rem) ^ 0x5412;
+        let bit = |i: usize| (bits >> i) & 1 == 1;
+
+        // Around the top-left finder.
+        for i in 0..=5 {
+            self.set_function(8, i, bit(i));
+        }
+        self.set_function(8, 7, bit(6));
+        self.set_function(8, 8, bit(7));
+        self.set_function(7, 8, bit(8));
+        for i in 9..15 {
+            self.set_function(14 - i, 8, bit(i));
+        }
+        // The copy split between the other two finders.
+        for i in 0..8 {
+            self.set_function(SIZE - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set_function(8, SIZE - 15 + i, bit(i));
+        }
+        self.set_function(8, SIZE - 8, true);
+    }
+
+    /// Places the codewords in the zigzag from the bottom-right corner,
+    /// two columns at a time, skipping the vertical timing pattern.
+    fn draw_codewords(&mut self, codewords: &[u8]) {
+        let total = codewords.len() * 8;
+        let mut i = 0;
+        let mut right = SIZE - 1;
+        while right >= 1 {
+            if right == 6 {
+                right = 5;
+            }
+            let upward = (right + 1) & 2 == 0;
+            for vert in 0..SIZE {
+                let y = if upward { SIZE - 1 - vert } else { vert };
+                for x in [right, right - 1] {
+                    if !self.reserved[y * SIZE + x] && i < total {
+                        self.modules[y * SIZE + x] = (codewords[i / 8] >> (7 - i % 8)) & 1 == 1;
+                        i += 1;
+                    }
+                }
+            }
+            if right < 2 {
+                break;
+            }
+            right -= 2;
+        }
+    }
+
+    fn apply_mask(&mut self, mask: u32) {
+        for y in 0..SIZE {
+            for x in 0..SIZE {
+                let flip = match mask {
+                    0 => (x + y) % 2 == 0,
+                    1 => y % 2 == 0,
+                    2 => x % 3 == 0,
+                    3 => (x + y) % 3 == 0,
+                    4 => (x / 3 + y / 2) % 2 == 0,
+                    5 => x * y % 2 + x * y % 3 == 0,
+                    6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                    _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+                };
+                if flip && !self.reserved[y * SIZE + x] {
+                    self.modules[y * SIZE + x] ^= true;
+                }
+            }
+        }
+    }
+
+    /// The standard penalty score; the mask with the lowest one is used.
+    fn penalty(&self) -> u32 {
+        let mut score = 0;
+        let lines = (0..SIZE).flat_map(|i| {
+            [
+                (0..SIZE).map(|j| self.get(j, i)).collect::<Vec<_>>(),
+                (0..SIZE).map(|j| self.get(i, j)).collect::<Vec<_>>(),
+            ]
+        });
+        for line in lines {
+            // Runs of five or more of one colour.
+            for run in line.chunk_by(|a, b| a == b).map(<[bool]>::len) {
+                if run >= 5 {
+                    score += run as u32 - 2;
+                }
+            }
+            // Patterns that look like a finder.
+            const FINDER: [bool; 11] = [
+                true, false, true, true, true, false, true, false, false, false, false,
+            ];
+            for window in line.windows(11) {
+                if window == FINDER || window.iter().rev().eq(FINDER.iter()) {
+                    score += 40;
+                }
+            }
+        }
+        for y in 0..SIZE - 1 {
+            for x in 0..SIZE - 1 {
+                let c = self.get(x, y);
+                if c == self.get(x + 1, y) && c == self.get(x, y + 1) && c == self.get(x + 1, y + 1)
+                {
+                    score += 3;
+                }
+            }
+        }
+        let total = (SIZE * SIZE) as i64;
+        let dark = self.modules.iter().filter(|&&m| m).count() as i64;
+        let k = ((dark * 20 - total * 10).abs() + total - 1) / total - 1;
+        score + k as u32 * 10
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payload_is_only_the_grouped_account_number() {
+        assert_eq!(
+            account_payload(" abcd-e2gh jk7m npqr ").as_deref(),
+            Some("ABCD E2GH JK7M NPQR")
+        );
+        assert_eq!(
+            account_payload("ABCDE2GHJK7MNPQR").as_deref(),
+            Some("ABCD E2GH JK7M NPQR")
+        );
+        // Tokens and keys are not account numbers and are refused outright.
+        assert_eq!(account_payload("eyJhbGciOiJIUzI1NiJ9.e30.sig"), None);
+        assert_eq!(account_payload("ABCD E2GH"), None);
+        assert!(account_code("ABCDE2GHJK7MNPQR").is_some());
+    }
+
+    #[test]
+    fn hello_world_matches_the_reference_codewords() {
+        let data = data_codewords("HELLO WORLD").unwrap();
+        assert_eq!(
+            data,
+            [32, 91, 11, 120, 209, 114, 220, 77, 67, 64, 236, 17, 236, 17, 236, 17]
+        );
+        assert_eq!(
+            reed_solomon(&data, EC_CODEWORDS),
+            [196, 35, 39, 119, 235, 215, 231, 226, 93, 23]
+        );
+    }
+
+    #[test]
+    fn text_outside_version_one_is_refused() {
+        assert!(QrCode::encode("lowercase").is_none());
+        // Twenty characters fill it.
+        assert!(QrCode::encode("ABCD E2GH JK7M NPQRX").is_some());
+        assert!(QrCode::encode("ABCD E2GH JK7M NPQR X").is_none());
+    }
+
+    #[test]
+    fn format_bits_carry_level_m_and_the_chosen_mask() {
+        let mut matrix = Matrix::new();
+        matrix.draw_format_bits(0);
+        // Read back the copy beside the top-left finder, bit 14 first.
+        let coords = [
+            (0, 8),
+            (1, 8),
+            (2, 8),
+            (3, 8),
+            (4, 8),
+            (5, 8),
+            (7, 8),
+            (8, 8),
+            (8, 7),
+            (8, 5),
+            (8, 4),
+            (8, 3),
+            (8, 2),
+            (8, 1),
+            (8, 0),
+        ];
+        let read: String = coords
+            .iter()
+            .map(|&(x, y)| if matrix.get(x, y) { '1' } else { '0' })
+            .collect();
+        assert_eq!(read, "101010000010010");
+    }
+
+    #[test]
+    fn finders_sit_in_three_corners() {
+        let code = account_code("ABCDE2GHJK7MNPQR").unwrap();
+        assert_eq!(code.size(), 21);
+        for (x, y) in [(0, 0), (14, 0), (0, 14)] {
+            // Dark outer ring, light ring, dark centre.
+            assert!(code.is_dark(x, y));
+            assert!(!code.is_dark(x + 1, y + 1));
+            assert!(code.is_dark(x + 3, y + 3));
+        }
+        assert!(code.svg_path(4).starts_with("M4,4h1v1h-1z"));
+    }
+}
@@ -0,0 +1,216 @@
+use crate::error::AppError;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use rand::{CryptoRng, RngCore};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use rsa::traits::PublicKeyParts;
+use rsa::{Oaep, RsaPublicKey};
+use sha2::{Digest, Sha256};
+
+/// Marks a hybrid-encrypted report:
+/// `hybrid-v1.<rsa-oaep(aes key)>.<nonce>.<aes-gcm ciphertext>`, base64 parts.
+/// Chunked reports are base64 RSA-OAEP blocks joined by `|`.
+pub const HYBRID_PREFIX: &str = "hybrid-v1";
+const HYBRID_AAD: &[u8] = b"MARIN_VPN_REPORT_V1";
+const AES_KEY_LEN: usize = 32;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReportEncryption {
+    /// A single RSA block when the message fits, hybrid otherwise.
+    #[default]
+    Auto,
+    RsaChunks,
+    Hybrid,
+}
+
+/// Largest plaintext one RSA-OAEP(SHA-256) block can carry under `key`:
+/// the modulus size minus two digests and two framing bytes.
+pub fn max_oaep_chunk(key: &RsaPublicKey) -> Result<usize, AppError> {
+    let overhead = 2 * <Sha256 as Digest>::output_size() + 2;
+    key.size()
+        .checked_sub(overhead)
+        .filter(|n| *n > 0)
+        .ok_or_else(|| {
+            AppError::Crypto(format!(
+                "Support key of {} bits is too small for OAEP",
+                key.size() * 8
+            ))
+        })
+}
+
+pub fn encrypt_report<R: RngCore + CryptoRng>(
+    key: &RsaPublicKey,
+    message: &[u8],
+    scheme: ReportEncryption,
+    rng: &mut R,
+) -> Result<String, AppError> {
+    let max_chunk = max_oaep_chunk(key)?;
+    let scheme = match scheme {
+        ReportEncryption::Auto if message.len() <= max_chunk => ReportEncryption::RsaChunks,
+        ReportEncryption::Auto => ReportEncryption::Hybrid,
+        other => other,
+    };
+
+    match scheme {
+        ReportEncryption::Hybrid => encrypt_hybrid(key, message, rng),
+        _ => {
+            let mut chunks = Vec::new();
+            for chunk in message.chunks(max_chunk) {
+                chunks.push(BASE64_STANDARD.encode(rsa_encrypt(key, chunk, rng)?));
+            }
+            Ok(chunks.join("|"))
+        }
+    }
+}
+
+fn rsa_encrypt<R: RngCore + CryptoRng>(
+    key: &RsaPublicKey,
+    data: &[u8],
+    rng: &mut R,
+) -> Result<Vec<u8>, AppError> {
+    key.encrypt(rng, Oaep::new::<Sha256>(), data)
+        .map_err(|e| AppError::Crypto(format!("Encryption failed: {}", e)))
+}
+
+fn encrypt_hybrid<R: RngCore + CryptoRng>(
+    key: &RsaPublicKey,
+    message: &[u8],
+    rng: &mut R,
+) -> Result<String, AppError> {
+    let mut aes_key = [0u8; AES_KEY_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    rng.fill_bytes(&mut aes_key);
+    rng.fill_bytes(&mut nonce);
+
+    let sealing = UnboundKey::new(&AES_256_GCM, &aes_key)
+        .map(LessSafeKey::new)
+        .map_err(|_| AppError::Crypto("Invalid report key".to_string()));
+    let wrapped = rsa_encrypt(key, &aes_key, rng);
+    aes_key.fill(0);
+    let (sealing, wrapped) = (sealing?, wrapped?);
+
+    let mut ciphertext = message.to_vec();
+    sealing
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(HYBRID_AAD),
+            &mut ciphertext,
+        )
+        .map_err(|_| AppError::Crypto("Report encryption failed".to_string()))?;
+
+    Ok(format!(
+        "{}.{}.{}.{}",
+        HYBRID_PREFIX,
+        BASE64_STANDARD.encode(wrapped),
+        BASE64_STANDARD.encode(nonce),
+        BASE64_STANDARD.encode(ciphertext)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use once_cell::sync::Lazy;
+    use rand::thread_rng;
+    use rsa::RsaPrivateKey;
+
+    // Key generation is slow in debug builds, so round trips share one
+    // small key and larger sizes are checked on synthetic moduli.
+    static KEY: Lazy<RsaPrivateKey> =
+        Lazy::new(|| RsaPrivateKey::new(&mut thread_rng(), 1024).unwrap());
+
+    fn synthetic_key(bits: usize) -> RsaPublicKey {
+        let n = rsa::BigUint::from_bytes_be(&vec![0xff; bits / 8]);
+        RsaPublicKey::new(n, rsa::BigUint::from(65537u32)).unwrap()
+    }
+
+    /// What support tooling does with the server's private key.
+    fn decrypt(key: &RsaPrivateKey, report: &str) -> Vec<u8> {
+        let unwrap = |b64: &str| {
+            key.decrypt(Oaep::new::<Sha256>(), &BASE64_STANDARD.decode(b64).unwrap())
+                .unwrap()
+        };
+        let parts: Vec<&str> = report.split('.').collect();
+        if parts[0] != HYBRID_PREFIX {
+            return report.split('|').flat_map(unwrap).collect();
+        }
+
+        let aes_key = unwrap(parts[1]);
+        let nonce: [u8; NONCE_LEN] = BASE64_STANDARD
+            .decode(parts[2])
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let mut data = BASE64_STANDARD.decode(parts[3]).unwrap();
+        let opening = LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &aes_key).unwrap());
+        let plain = opening
+            .open_in_place(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(HYBRID_AAD),
+                &mut data,
+            )
+            .unwrap();
+        plain.to_vec()
+    }
+
+    #[test]
+    fn chunk_size_follows_key_size() {
+        assert_eq!(max_oaep_chunk(&KEY.to_public_key()).unwrap(), 62);
+        assert_eq!(max_oaep_chunk(&synthetic_key(2048)).unwrap(), 190);
+        // The server's support key.
+        assert_eq!(max_oaep_chunk(&synthetic_key(4096)).unwrap(), 446);
+    }
+
+    #[test]
+    fn undersized_key_is_rejected() {
+        let key = synthetic_key(512);
+        assert!(matches!(max_oaep_chunk(&key), Err(AppError::Crypto(_))));
+        assert!(encrypt_report(&key, b"hi", ReportEncryption::Auto, &mut thread_rng()).is_err());
+    }
+
+    #[test]
+    fn chunked_round_trip_uses_full_blocks() {
+        let key = &*KEY;
+        let message = vec![b'x'; 2 * 62 + 7];
+        let report = encrypt_report(
+            &key.to_public_key(),
+            &message,
+            ReportEncryption::RsaChunks,
+            &mut thread_rng(),
+        )
+        .unwrap();
+        assert_eq!(report.split('|').count(), 3);
+        assert_eq!(decrypt(key, &report), message);
+    }
+
+    #[test]
+    fn hybrid_round_trip() {
+        let key = &*KEY;
+        let message = "connection drops every few minutes on hotel wifi".repeat(40);
+        let report = encrypt_report(
+            &key.to_public_key(),
+            message.as_bytes(),
+            ReportEncryption::Hybrid,
+            &mut thread_rng(),
+        )
+        .unwrap();
+        assert!(report.starts_with(HYBRID_PREFIX));
+        assert!(!report.contains('|'));
+        assert_eq!(decrypt(key, &report), message.as_bytes());
+    }
+
+    #[test]
+    fn auto_picks_scheme_by_size() {
+        let key = &*KEY;
+        let public = key.to_public_key();
+        let mut rng = thread_rng();
+
+        let short = encrypt_report(&public, b"short", ReportEncryption::Auto, &mut rng).unwrap();
+        assert!(!short.starts_with(HYBRID_PREFIX));
+        assert_eq!(decrypt(key, &short), b"short");
+
+        let long = vec![b'y'; 63];
+        let report = encrypt_report(&public, &long, ReportEncryption::Auto, &mut rng).unwrap();
+        assert!(report.starts_with(HYBRID_PREFIX));
+        assert_eq!(decrypt(key, &report), long);
+    }
+}
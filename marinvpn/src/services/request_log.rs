@@ -0,0 +1,266 @@
+use crate::services::{auth, ech, privacy_audit};
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// How many requests the log keeps before the oldest drop off.
+pub const CAPACITY: usize = 200;
+
+static LOG: Lazy<Mutex<RequestLog>> = Lazy::new(|| Mutex::new(RequestLog::new(CAPACITY)));
+
+/// One control-plane request as the app info page lists it. Bodies and
+/// headers are never kept.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestRecord {
+    pub at: DateTime<Utc>,
+    pub method: String,
+    /// Host and path. The query string is dropped, since DoH lookups carry
+    /// the name being resolved there.
+    pub endpoint: String,
+    pub outcome: RequestOutcome,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RequestOutcome {
+    Status(u16),
+    TimedOut,
+    Unreachable,
+    Failed,
+}
+
+impl RequestOutcome {
+    fn of(result: &reqwest::Result<reqwest::Response>) -> Self {
+        match result {
+            Ok(res) => Self::Status(res.status().as_u16()),
+            Err(e) if e.is_timeout() => Self::TimedOut,
+            Err(e) if e.is_connect() => Self::Unreachable,
+            Err(_) => Self::Failed,
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        matches!(self, Self::Status(status) if (200..400).contains(status))
+    }
+
+    pub fn label(&self) -> String {
+        match self {
+            Self::Status(status) => status.to_string(),
+            Self::TimedOut => "Timed out".to_string(),
+            Self::Unreachable => "Unreachable".to_string(),
+            Self::Failed => "Failed".to_string(),
+        }
+    }
+}
+
+/// Newest-last ring of recorded requests.
+#[derive(Debug)]
+pub struct RequestLog {
+    records: VecDeque<RequestRecord>,
+    capacity: usize,
+}
+
+impl RequestLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            records: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, record: RequestRecord) {
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    pub fn records(&self) -> Vec<RequestRecord> {
+        self.records.iter().cloned().collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+}
+
+/// Everything recorded since start-up or the last [`clear`], oldest first.
+pub fn snapshot() -> Vec<RequestRecord> {
+    LOG.lock().unwrap().records()
+}
+
+pub fn clear() {
+    LOG.lock().unwrap().clear();
+}
+
+/// Sends `request` and records it. Control-plane requests go through here
+/// so that none is missing from the log, and none skips the privacy audit.
+/// A request the API host fails for rejecting its Encrypted Client Hello is
+/// sent once more without ECH.
+pub async fn send(request: reqwest::RequestBuilder) -> reqwest::Result<reqwest::Response> {
+    send_logged(&LOG, request, auth::drop_rejected_ech).await
+}
+
+async fn send_logged(
+    log: &Mutex<RequestLog>,
+    request: reqwest::RequestBuilder,
+    without_ech: impl FnOnce() -> reqwest::Client,
+) -> reqwest::Result<reqwest::Response> {
+    let (client, request) = request.build_split();
+    let request = request?;
+    if privacy_audit::enabled() {
+        privacy_audit::check(&request);
+    }
+
+    let retry = request.try_clone();
+    let result = execute_logged(log, &client, request).await;
+    match (result, retry) {
+        (Err(e), Some(retry)) if ech::rejected(&e) => {
+            tracing::warn!("API host rejected ECH, retrying without it");
+            execute_logged(log, &without_ech(), retry).await
+        }
+        (result, _) => result,
+    }
+}
+
+async fn execute_logged(
+    log: &Mutex<RequestLog>,
+    client: &reqwest::Client,
+    request: reqwest::Request,
+) -> reqwest::Result<reqwest::Response> {
+    let method = request.method().to_string();
+    let endpoint = endpoint(request.url());
+
+    let result = client.execute(request).await;
+    log.lock().unwrap().push(RequestRecord {
+        at: Utc::now(),
+        method,
+        endpoint,
+        outcome: RequestOutcome::of(&result),
+    });
+    result
+}
+
+fn endpoint(url: &reqwest::Url) -> String {
+    let host = url.host_str().unwrap_or_default();
+    match url.port() {
+        Some(port) => format!("{}:{}{}", host, port, url.path()),
+        None => format!("{}{}", host, url.path()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::doh::mock::serve_once;
+
+    fn record(endpoint: &str) -> RequestRecord {
+        RequestRecord {
+            at: Utc::now(),
+            method: "GET".to_string(),
+            endpoint: endpoint.to_string(),
+            outcome: RequestOutcome::Status(200),
+        }
+    }
+
+    #[test]
+    fn oldest_records_drop_off_at_capacity() {
+        let mut log = RequestLog::new(3);
+        for path in ["/a", "/b", "/c", "/d", "/e"] {
+            log.push(record(path));
+        }
+        let endpoints: Vec<_> = log.records().into_iter().map(|r| r.endpoint).collect();
+        assert_eq!(endpoints, vec!["/c", "/d", "/e"]);
+
+        log.clear();
+        assert!(log.records().is_empty());
+    }
+
+    #[tokio::test]
+    async fn records_method_endpoint_and_status_without_query() {
+        let log = Mutex::new(RequestLog::new(CAPACITY));
+        let client = reqwest::Client::new();
+        let server = serve_once("200 OK", "{}").await;
+        let url = reqwest::Url::parse(&server.url).unwrap();
+        let host = format!("127.0.0.1:{}", url.port().unwrap());
+
+        let before = Utc::now();
+        let request = client
+            .get(&server.url)
+            .query(&[("name", "api.marinvpn.example"), ("type", "A")]);
+        assert!(send_logged(&log, request, reqwest::Client::new)
+            .await
+            .is_ok());
+
+        let records = log.lock().unwrap().records();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].method, "GET");
+        assert_eq!(records[0].endpoint, format!("{}/dns-query", host));
+        assert_eq!(records[0].outcome, RequestOutcome::Status(200));
+        assert!(records[0].outcome.is_success());
+        assert!(records[0].at >= before);
+    }
+
+    #[tokio::test]
+    async fn records_error_statuses_and_unreachable_hosts() {
+        let log = Mutex::new(RequestLog::new(CAPACITY));
+        let client = reqwest::Client::new();
+        let server = serve_once("503 Service Unavailable", "").await;
+        let _ = send_logged(
+            &log,
+            client.post(&server.url).body("secret"),
+            reqwest::Client::new,
+        )
+        .await;
+
+        // Nothing listens on a port that was just released.
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api/v1/vpn/servers", closed.local_addr().unwrap());
+        drop(closed);
+        assert!(send_logged(&log, client.get(&url), reqwest::Client::new)
+            .await
+            .is_err());
+
+        let records = log.lock().unwrap().records();
+        assert_eq!(records[0].method, "POST");
+        assert_eq!(records[0].outcome, RequestOutcome::Status(503));
+        assert!(!records[0].outcome.is_success());
+        assert!(records[1].endpoint.ends_with("/api/v1/vpn/servers"));
+        assert_eq!(records[1].outcome, RequestOutcome::Unreachable);
+        assert_eq!(records[1].outcome.label(), "Unreachable");
+    }
+
+    #[tokio::test]
+    async fn requests_rejected_for_ech_are_retried_without_it() {
+        let log = Mutex::new(RequestLog::new(CAPACITY));
+        let addr = ech::mock::serve_without_ech(2);
+        let client = ech::mock::client(addr, Some(&ech::mock::usable_list()));
+        let url = format!("https://{}:{}/api/v1/version", ech::mock::HOST, addr.port());
+
+        let res = send_logged(&log, client.get(&url), || ech::mock::client(addr, None))
+            .await
+            .unwrap();
+        assert_eq!(res.text().await.unwrap(), "ok");
+        let outcomes: Vec<_> = log
+            .lock()
+            .unwrap()
+            .records()
+            .into_iter()
+            .map(|r| r.outcome)
+            .collect();
+        assert_eq!(
+            outcomes,
+            [RequestOutcome::Unreachable, RequestOutcome::Status(200)]
+        );
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "leaks")]
+    async fn leaky_requests_fail_the_audit_before_they_are_sent() {
+        let log = Mutex::new(RequestLog::new(CAPACITY));
+        let request = reqwest::Client::new()
+            .post("http://127.0.0.1:9/api/v1/vpn/config-anonymous")
+            .json(&serde_json::json!({ "account_number": "ABCDEFGHJKLMNPQR" }));
+        let _ = send_logged(&log, request, reqwest::Client::new).await;
+    }
+}
@@ -0,0 +1,273 @@
+//! A connection test that needs no account and no server, for checking the
+//! app before signing up. It runs the same connect and disconnect path as a
+//! real tunnel, on a [`WireGuardService::self_test`] service whose runner
+//! only simulates the interfaces, kill switch and DNS, against a bundled
+//! config for a relay that does not exist. Nothing is sent over the network.
+//!
+//! [`WireGuardService::self_test`]: crate::services::vpn::WireGuardService::self_test
+
+use crate::models::{ConnectionStatus, SettingsState, WireGuardConfig};
+use crate::services::vpn::{VpnEvent, VpnService};
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// Location the self-test tunnel is reported under, so its log lines cannot
+/// be mistaken for a real connection's.
+pub const SELF_TEST_LOCATION: &str = "Self-test (simulated)";
+
+/// How long each step gets. A simulated tunnel takes about a second.
+pub const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The bundled config. The endpoint and addresses are reserved for
+/// documentation (RFC 5737) and the keys are throwaway, so nothing could be
+/// reached with it even by a real backend.
+pub fn config() -> WireGuardConfig {
+    WireGuardConfig {
+        private_key: "GJsJF9WMcRVJ3g5wWa0P6Pc9BwLuaWWnSNG7hZaLwXI=".to_string(),
+        public_key: "bT74lpaVQ1I6+jcEXr77bz7gUPM6GsGWkQyK0pKilCo=".to_string(),
+        preshared_key: None,
+        endpoint: "192.0.2.1:51820".to_string(),
+        allowed_ips: "0.0.0.0/0".to_string(),
+        address: "10.64.0.2/32".to_string(),
+        dns: Some("10.64.0.1".to_string()),
+        pqc_handshake: None,
+        pqc_provider: None,
+        pqc_ciphertext: None,
+        obfuscation_key: None,
+        obfuscation_port: None,
+        obfuscation_cipher: None,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SelfTestStep {
+    /// Kill switch on, tunnel up, status reported as connected.
+    Connect,
+    /// Statistics arrive from the running tunnel.
+    Stats,
+    /// Tunnel down and the kill switch released (or kept, under lockdown).
+    Disconnect,
+}
+
+impl SelfTestStep {
+    pub fn label(self) -> &'static str {
+        match self {
+            SelfTestStep::Connect => "Connect",
+            SelfTestStep::Stats => "Tunnel statistics",
+            SelfTestStep::Disconnect => "Disconnect",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SelfTestResult {
+    pub step: SelfTestStep,
+    pub outcome: Result<(), String>,
+}
+
+/// What the login view shows while the self-test runs and after it is done.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SelfTest {
+    pub running: bool,
+    pub results: Vec<SelfTestResult>,
+}
+
+impl SelfTest {
+    /// Whether a finished run got through every step.
+    pub fn passed(&self) -> bool {
+        !self.running && self.results.len() == 3 && self.results.iter().all(|r| r.outcome.is_ok())
+    }
+}
+
+/// Connects `service` to the bundled config with `settings`, waits for
+/// statistics and disconnects again. Stops at the first step that fails,
+/// but always disconnects. `report` sees each result as soon as it is known.
+///
+/// `service` should come from [`WireGuardService::self_test`]; on any other
+/// service this would try to bring up a real tunnel.
+///
+/// [`WireGuardService::self_test`]: crate::services::vpn::WireGuardService::self_test
+pub async fn run(
+    service: &dyn VpnService,
+    settings: &SettingsState,
+    timeout: Duration,
+    mut report: impl FnMut(&SelfTestResult),
+) -> Vec<SelfTestResult> {
+    let mut results = Vec::with_capacity(3);
+    let mut record = |step, outcome| {
+        let result = SelfTestResult { step, outcome };
+        report(&result);
+        results.push(result);
+    };
+
+    let mut events = service.subscribe();
+    let connected = within(timeout, async {
+        service
+            .connect(
+                SELF_TEST_LOCATION.to_string(),
+                config(),
+                None,
+                settings.clone(),
+                None,
+            )
+            .await;
+        await_status(&mut events, ConnectionStatus::Connected).await
+    })
+    .await;
+    let connected_ok = connected.is_ok();
+    record(SelfTestStep::Connect, connected);
+
+    if connected_ok {
+        let stats = within(timeout, await_stats(&mut events)).await;
+        record(SelfTestStep::Stats, stats);
+    }
+
+    let mut events = service.subscribe();
+    let disconnected = within(timeout, async {
+        service.disconnect().await;
+        match service.get_status().await {
+            ConnectionStatus::Disconnected => Ok(()),
+            _ => await_status(&mut events, ConnectionStatus::Disconnected).await,
+        }
+    })
+    .await;
+    if connected_ok {
+        record(SelfTestStep::Disconnect, disconnected);
+    }
+    results
+}
+
+async fn within(
+    timeout: Duration,
+    step: impl std::future::Future<Output = Result<(), String>>,
+) -> Result<(), String> {
+    tokio::time::timeout(timeout, step)
+        .await
+        .unwrap_or_else(|_| Err(format!("No answer within {} seconds", timeout.as_secs())))
+}
+
+async fn await_status(
+    events: &mut broadcast::Receiver<VpnEvent>,
+    wanted: ConnectionStatus,
+) -> Result<(), String> {
+    loop {
+        match events.recv().await {
+            Ok(VpnEvent::StatusChanged(status)) if status == wanted => return Ok(()),
+            Ok(VpnEvent::Error(e)) => return Err(e.to_string()),
+            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+            Err(broadcast::error::RecvError::Closed) => {
+                return Err("VPN service stopped".to_string())
+            }
+        }
+    }
+}
+
+async fn await_stats(events: &mut broadcast::Receiver<VpnEvent>) -> Result<(), String> {
+    loop {
+        match events.recv().await {
+            Ok(VpnEvent::StatsUpdated(stats)) if stats.latest_handshake != 0 => return Ok(()),
+            Ok(VpnEvent::Error(e)) => return Err(e.to_string()),
+            Ok(VpnEvent::StatusChanged(ConnectionStatus::Disconnected)) => {
+                return Err("Tunnel closed before reporting statistics".to_string())
+            }
+            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+            Err(broadcast::error::RecvError::Closed) => {
+                return Err("VPN service stopped".to_string())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::vpn::{VpnError, WireGuardService};
+    use crate::services::vpn_mock::{MockCall, MockVpnService};
+
+    #[tokio::test]
+    async fn the_simulated_tunnel_connects_and_disconnects() {
+        let service = WireGuardService::self_test();
+        let mut reported = Vec::new();
+        let results = run(
+            &service,
+            &SettingsState::default(),
+            SELF_TEST_TIMEOUT,
+            |r| reported.push(r.step),
+        )
+        .await;
+
+        assert_eq!(
+            reported,
+            [
+                SelfTestStep::Connect,
+                SelfTestStep::Stats,
+                SelfTestStep::Disconnect
+            ]
+        );
+        assert!(results.iter().all(|r| r.outcome.is_ok()), "{:?}", results);
+        assert_eq!(service.get_status().await, ConnectionStatus::Disconnected);
+        let done = SelfTest {
+            running: false,
+            results,
+        };
+        assert!(done.passed());
+    }
+
+    #[tokio::test]
+    async fn connects_to_the_bundled_config_without_an_account() {
+        let vpn = MockVpnService::new();
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(None));
+        let record = seen.clone();
+        vpn.on_connect(move |settings| {
+            *record.lock().unwrap() = Some(settings.lockdown_mode);
+            vec![VpnEvent::StatusChanged(ConnectionStatus::Connected)]
+        });
+        let settings = SettingsState {
+            lockdown_mode: true,
+            ..SettingsState::default()
+        };
+
+        let timeout = Duration::from_millis(50);
+        run(&vpn, &settings, timeout, |_| {}).await;
+        assert_eq!(
+            vpn.calls(),
+            [
+                MockCall::Connect {
+                    entry: SELF_TEST_LOCATION.to_string(),
+                    exit: None,
+                },
+                MockCall::Disconnect,
+            ]
+        );
+        // The firewall settings are exercised as configured.
+        assert_eq!(*seen.lock().unwrap(), Some(true));
+    }
+
+    #[tokio::test]
+    async fn a_failed_connect_stops_the_run_but_still_disconnects() {
+        let vpn = MockVpnService::new();
+        vpn.on_connect(|_| {
+            vec![
+                VpnEvent::Error(VpnError::FirewallError("nft missing".to_string())),
+                VpnEvent::StatusChanged(ConnectionStatus::Disconnected),
+            ]
+        });
+
+        let results = run(&vpn, &SettingsState::default(), SELF_TEST_TIMEOUT, |_| {}).await;
+        assert_eq!(
+            results,
+            [SelfTestResult {
+                step: SelfTestStep::Connect,
+                outcome: Err("Firewall/Kill-switch error: nft missing".to_string()),
+            }]
+        );
+        assert_eq!(vpn.calls().last(), Some(&MockCall::Disconnect));
+    }
+
+    #[test]
+    fn the_bundled_config_points_nowhere() {
+        let config = config();
+        assert!(config.endpoint.starts_with("192.0.2."));
+        assert!(crate::services::wg_conf::validate_server_config(&config).is_ok());
+    }
+}
@@ -1,6 +1,8 @@
 use crate::error::AppError;
-use crate::models::CommonVpnServer;
+use crate::models::{CommonVpnServer, Location};
+use crate::services::request_log;
 use futures_util::stream::{FuturesUnordered, StreamExt};
+use marinvpn_common::version::CLIENT_VERSION_HEADER;
 use once_cell::sync::Lazy;
 use std::time::{Duration, Instant};
 
@@ -22,12 +24,10 @@ impl ServersService {
             return Ok(cache.0.clone());
         }
 
-        let client = reqwest::Client::new();
-        let res = client
+        let request = crate::services::auth::client()
             .get(format!("{}/vpn/servers", *API_BASE))
-            .send()
-            .await
-            .map_err(AppError::Network)?;
+            .header(CLIENT_VERSION_HEADER, env!("CARGO_PKG_VERSION"));
+        let res = request_log::send(request).await.map_err(AppError::Network)?;
 
         if !res.status().is_success() {
             return Err(AppError::Api {
@@ -65,24 +65,65 @@ impl ServersService {
         }
 
         let servers = Self::get_servers().await?;
-        let mut candidates: Vec<CommonVpnServer> = Vec::new();
-        for server in servers.into_iter() {
-            if country.map_or(true, |c| server.country == c) {
-                let location = format!("{}, {}", server.country, server.city);
-                if exclude_locations.iter().any(|ex| ex == &location) {
-                    continue;
-                }
-                candidates.push(server);
-            }
+        let in_country: Vec<CommonVpnServer> = servers
+            .into_iter()
+            .filter(|s| country.is_none_or(|c| s.country == c))
+            .collect();
+
+        let candidates = Self::exclude_servers(&in_country, exclude_locations, true);
+        if !candidates.is_empty() {
+            return Self::select_best_server_from_candidates(candidates).await;
         }
 
+        // Nothing outside the excluded locations; settle for a different
+        // machine in the same place rather than reusing an excluded server.
+        let candidates = Self::exclude_servers(&in_country, exclude_locations, false);
         if candidates.is_empty() {
-            return Self::find_best_server(country).await;
+            return Err(AppError::Vpn(
+                "No distinct server available outside the excluded ones".to_string(),
+            ));
         }
-
         Self::select_best_server_from_candidates(candidates).await
     }
 
+    /// Drops every server matching an exclusion. Exclusions may name a server
+    /// by endpoint (matched on host, so another port on the same box still
+    /// counts) or public key; "Country, City" locations are only honoured when
+    /// `by_location` is set.
+    pub fn exclude_servers(
+        servers: &[CommonVpnServer],
+        exclude: &[String],
+        by_location: bool,
+    ) -> Vec<CommonVpnServer> {
+        servers
+            .iter()
+            .filter(|server| {
+                let location = Location::from(*server).to_string();
+                !exclude.iter().any(|ex| {
+                    ex == &server.public_key
+                        || Self::same_host(ex, &server.endpoint)
+                        || (by_location && ex == &location)
+                })
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// True when two endpoints/keys describe the same relay.
+    pub fn is_same_server(
+        endpoint_a: &str,
+        public_key_a: &str,
+        endpoint_b: &str,
+        public_key_b: &str,
+    ) -> bool {
+        public_key_a == public_key_b || Self::same_host(endpoint_a, endpoint_b)
+    }
+
+    fn same_host(a: &str, b: &str) -> bool {
+        let (a, b) = (endpoint_host(a), endpoint_host(b));
+        !a.is_empty() && a.eq_ignore_ascii_case(b)
+    }
+
     async fn select_best_server_from_candidates(
         candidates: Vec<CommonVpnServer>,
     ) -> Result<CommonVpnServer, AppError> {
@@ -144,4 +185,102 @@ impl ServersService {
 
         None
     }
-}
\ No newline at end of file
+}
+
+fn endpoint_host(endpoint: &str) -> &str {
+    let trimmed = endpoint.trim();
+    if let Some(rest) = trimmed.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or(rest);
+    }
+    match trimmed.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') && port.parse::<u16>().is_ok() => host,
+        _ => trimmed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(country: &str, city: &str, endpoint: &str, key: &str) -> CommonVpnServer {
+        CommonVpnServer {
+            country: country.to_string(),
+            city: city.to_string(),
+            endpoint: endpoint.to_string(),
+            public_key: key.to_string(),
+            current_load: 10,
+            avg_latency: 20,
+        }
+    }
+
+    #[test]
+    fn exclusion_by_endpoint_drops_same_host_on_other_port() {
+        let servers = vec![
+            server("Sweden", "Stockholm", "198.51.100.1:51820", "a"),
+            server("Sweden", "Stockholm", "198.51.100.1:443", "b"),
+            server("Sweden", "Stockholm", "198.51.100.2:51820", "c"),
+        ];
+        let left =
+            ServersService::exclude_servers(&servers, &["198.51.100.1:51820".to_string()], true);
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].public_key, "c");
+    }
+
+    #[test]
+    fn exclusion_by_public_key_and_location() {
+        let servers = vec![
+            server("Sweden", "Stockholm", "198.51.100.1:51820", "a"),
+            server("Germany", "Berlin", "203.0.113.5:51820", "b"),
+            server("Germany", "Frankfurt", "203.0.113.6:51820", "c"),
+        ];
+        let exclude = vec!["Sweden, Stockholm".to_string(), "b".to_string()];
+        let left = ServersService::exclude_servers(&servers, &exclude, true);
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].city, "Frankfurt");
+    }
+
+    #[test]
+    fn same_location_fallback_still_excludes_entry_server() {
+        let servers = vec![
+            server("Sweden", "Stockholm", "198.51.100.1:51820", "a"),
+            server("Sweden", "Stockholm", "198.51.100.2:51820", "b"),
+        ];
+        let exclude = vec![
+            "Sweden, Stockholm".to_string(),
+            "198.51.100.1:51820".to_string(),
+            "a".to_string(),
+        ];
+        assert!(ServersService::exclude_servers(&servers, &exclude, true).is_empty());
+        let fallback = ServersService::exclude_servers(&servers, &exclude, false);
+        assert_eq!(fallback.len(), 1);
+        assert_eq!(fallback[0].public_key, "b");
+    }
+
+    #[test]
+    fn same_server_matches_on_key_or_host() {
+        assert!(ServersService::is_same_server(
+            "198.51.100.1:51820",
+            "a",
+            "198.51.100.1:53",
+            "b"
+        ));
+        assert!(ServersService::is_same_server(
+            "198.51.100.1:51820",
+            "a",
+            "198.51.100.9:51820",
+            "a"
+        ));
+        assert!(ServersService::is_same_server(
+            "[2001:db8::1]:51820",
+            "a",
+            "[2001:db8::1]:443",
+            "b"
+        ));
+        assert!(!ServersService::is_same_server(
+            "198.51.100.1:51820",
+            "a",
+            "198.51.100.2:51820",
+            "b"
+        ));
+    }
+}
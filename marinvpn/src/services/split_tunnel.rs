@@ -0,0 +1,317 @@
+//! Firewall and routing rules for per-app split tunneling, in both
+//! directions: listed apps go around the tunnel, or only listed apps go
+//! through it.
+
+use crate::models::{AppInfo, SplitTunnelMode};
+use std::path::Path;
+
+/// Packet mark for traffic that leaves outside the tunnel. A policy rule
+/// sends it to the main table ahead of wg-quick's rules, and the kill switch
+/// lets it through.
+pub const BYPASS_MARK: &str = "0x1000";
+/// net_cls class of apps that must use the tunnel in include-only mode.
+pub const TUNNEL_CLASSID: &str = "0x2000";
+/// nftables table that sets [`BYPASS_MARK`].
+pub const NFT_TABLE: &str = "marinvpn_split";
+/// Ahead of the rules wg-quick adds for a full tunnel.
+pub const RULE_PRIORITY: &str = "100";
+
+/// Where the listed apps' processes are moved, and the class that cgroup
+/// tags their sockets with.
+pub fn linux_cgroup(mode: SplitTunnelMode) -> (&'static str, &'static str) {
+    match mode {
+        SplitTunnelMode::Exclude => ("/sys/fs/cgroup/net_cls/marinvpn_bypass", BYPASS_MARK),
+        SplitTunnelMode::IncludeOnly => ("/sys/fs/cgroup/net_cls/marinvpn_tunnel", TUNNEL_CLASSID),
+    }
+}
+
+/// `nft` argument lists that create [`NFT_TABLE`] and mark bypass traffic.
+/// Excluding marks the listed apps. Include-only marks everything except the
+/// listed apps and the `routed` ranges, which still go through the tunnel.
+pub fn linux_mark_rules(mode: SplitTunnelMode, routed: &[String]) -> Vec<Vec<String>> {
+    let chain = |rule: &[&str]| -> Vec<String> {
+        ["add", "rule", "inet", NFT_TABLE, "output"]
+            .iter()
+            .chain(rule)
+            .map(|s| s.to_string())
+            .collect()
+    };
+    let mut rules = vec![
+        args(&["add", "table", "inet", NFT_TABLE]),
+        args(&["flush", "table", "inet", NFT_TABLE]),
+        args(&[
+            "add", "chain", "inet", NFT_TABLE, "output", "{", "type", "route", "hook", "output",
+            "priority", "mangle;", "policy", "accept;", "}",
+        ]),
+    ];
+    match mode {
+        SplitTunnelMode::Exclude => {
+            rules.push(chain(&[
+                "meta",
+                "cgroup",
+                BYPASS_MARK,
+                "meta",
+                "mark",
+                "set",
+                BYPASS_MARK,
+            ]));
+        }
+        SplitTunnelMode::IncludeOnly => {
+            rules.push(chain(&["meta", "cgroup", TUNNEL_CLASSID, "accept"]));
+            for range in routed {
+                let family = if range.contains(':') { "ip6" } else { "ip" };
+                rules.push(chain(&[family, "daddr", range, "accept"]));
+            }
+            rules.push(chain(&["meta", "mark", "set", BYPASS_MARK]));
+        }
+    }
+    rules
+}
+
+/// `ip rule` arguments that route [`BYPASS_MARK`] by the main table, once
+/// per family.
+pub fn linux_route_rules(action: &str) -> Vec<Vec<String>> {
+    ["-4", "-6"]
+        .iter()
+        .map(|family| {
+            args(&[
+                family,
+                "rule",
+                action,
+                "fwmark",
+                BYPASS_MARK,
+                "table",
+                "main",
+                "priority",
+                RULE_PRIORITY,
+            ])
+        })
+        .collect()
+}
+
+/// PowerShell commands that confine the listed apps. Excluding lets them out
+/// on any adapter past the kill switch. Include-only blocks them on every
+/// adapter but the tunnel, even where the kill switch would let them out.
+/// Windows has no per-app route, so there the other apps keep using the
+/// tunnel.
+pub fn windows_app_rules(mode: SplitTunnelMode, apps: &[AppInfo]) -> Vec<String> {
+    apps.iter()
+        .map(|app| {
+            let name = ps_quote(&app.name);
+            let path = ps_quote(&app.path);
+            match mode {
+                SplitTunnelMode::Exclude => format!(
+                    "New-NetFirewallRule -DisplayName 'MarinVPN Bypass - {name}' \
+                        -Direction Outbound -Program '{path}' -Action Allow -Profile Any \
+                        -InterfaceAlias '*' -EdgeTraversalPolicy Allow"
+                ),
+                SplitTunnelMode::IncludeOnly => format!(
+                    "Get-NetAdapter | Where-Object {{ $_.InterfaceDescription -notlike '*Wintun*' -and $_.InterfaceAlias -notlike 'marinvpn*' }} | ForEach-Object {{ \
+                        New-NetFirewallRule -DisplayName \"MarinVPN Tunnel Only - {name} $($_.InterfaceAlias)\" \
+                        -Direction Outbound -Program '{path}' -InterfaceAlias $_.InterfaceAlias -Action Block -Profile Any \
+                    }}"
+                ),
+            }
+        })
+        .collect()
+}
+
+/// PowerShell commands that remove every rule [`windows_app_rules`] adds.
+pub const WINDOWS_CLEAR_APP_RULES: [&str; 2] = [
+    "Remove-NetFirewallRule -DisplayName 'MarinVPN Bypass - *' -ErrorAction SilentlyContinue",
+    "Remove-NetFirewallRule -DisplayName 'MarinVPN Tunnel Only - *' -ErrorAction SilentlyContinue",
+];
+
+/// The listed apps whose executable is no longer at the saved path, e.g.
+/// after an uninstall or an update that moved it. Rules for them match
+/// nothing.
+pub fn missing_apps(apps: &[AppInfo]) -> Vec<AppInfo> {
+    apps.iter()
+        .filter(|app| !Path::new(&app.path).exists())
+        .cloned()
+        .collect()
+}
+
+/// The executable's file name without `.exe`, lowercased, so
+/// `C:\Program Files\Mozilla\Firefox.EXE` and `/usr/bin/firefox` match.
+pub fn image_name(path: &str) -> Option<String> {
+    let file = path.rsplit(['/', '\\']).next()?.to_lowercase();
+    let stem = file.strip_suffix(".exe").unwrap_or(&file);
+    (!stem.is_empty()).then(|| stem.to_string())
+}
+
+/// An app the user picked by its executable, named after the file without
+/// `.exe`.
+pub fn picked_app(path: &str) -> Option<AppInfo> {
+    let file = path.rsplit(['/', '\\']).next()?;
+    let name = match file.rsplit_once('.') {
+        Some((stem, ext)) if ext.eq_ignore_ascii_case("exe") => stem,
+        _ => file,
+    };
+    (!name.is_empty()).then(|| AppInfo {
+        name: name.to_string(),
+        path: path.to_string(),
+        icon: None,
+    })
+}
+
+/// An installed app with the same image name as `stale`, to offer as its
+/// new location.
+pub fn relocation<'a>(stale: &AppInfo, installed: &'a [AppInfo]) -> Option<&'a AppInfo> {
+    let name = image_name(&stale.path)?;
+    installed
+        .iter()
+        .find(|app| app.path != stale.path && image_name(&app.path).as_deref() == Some(&name))
+}
+
+fn args(parts: &[&str]) -> Vec<String> {
+    parts.iter().map(|s| s.to_string()).collect()
+}
+
+fn ps_quote(s: &str) -> String {
+    s.replace('\'', "''")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app(name: &str, path: &str) -> AppInfo {
+        AppInfo {
+            name: name.to_string(),
+            path: path.to_string(),
+            icon: None,
+        }
+    }
+
+    fn joined(rules: &[Vec<String>]) -> Vec<String> {
+        rules.iter().map(|r| r.join(" ")).collect()
+    }
+
+    #[test]
+    fn excluding_marks_only_the_bypass_cgroup() {
+        let rules = joined(&linux_mark_rules(
+            SplitTunnelMode::Exclude,
+            &["10.0.0.0/8".to_string()],
+        ));
+        assert_eq!(
+            rules,
+            [
+                "add table inet marinvpn_split",
+                "flush table inet marinvpn_split",
+                "add chain inet marinvpn_split output { type route hook output priority mangle; policy accept; }",
+                "add rule inet marinvpn_split output meta cgroup 0x1000 meta mark set 0x1000",
+            ]
+        );
+        assert_eq!(
+            linux_cgroup(SplitTunnelMode::Exclude),
+            ("/sys/fs/cgroup/net_cls/marinvpn_bypass", "0x1000")
+        );
+    }
+
+    #[test]
+    fn include_only_marks_everything_but_listed_apps_and_ranges() {
+        let rules = joined(&linux_mark_rules(
+            SplitTunnelMode::IncludeOnly,
+            &["203.0.113.0/24".to_string(), "2001:db8::/32".to_string()],
+        ));
+        assert_eq!(
+            rules[3..],
+            [
+                "add rule inet marinvpn_split output meta cgroup 0x2000 accept",
+                "add rule inet marinvpn_split output ip daddr 203.0.113.0/24 accept",
+                "add rule inet marinvpn_split output ip6 daddr 2001:db8::/32 accept",
+                "add rule inet marinvpn_split output meta mark set 0x1000",
+            ]
+        );
+        assert_eq!(
+            linux_cgroup(SplitTunnelMode::IncludeOnly),
+            ("/sys/fs/cgroup/net_cls/marinvpn_tunnel", "0x2000")
+        );
+    }
+
+    #[test]
+    fn bypass_mark_is_routed_by_the_main_table_for_both_families() {
+        assert_eq!(
+            joined(&linux_route_rules("add")),
+            [
+                "-4 rule add fwmark 0x1000 table main priority 100",
+                "-6 rule add fwmark 0x1000 table main priority 100",
+            ]
+        );
+    }
+
+    #[test]
+    fn windows_rules_allow_excluded_apps_and_confine_included_ones() {
+        let apps = [app("Tor's Browser", "C:\\Apps\\tor.exe")];
+
+        let exclude = windows_app_rules(SplitTunnelMode::Exclude, &apps);
+        assert_eq!(exclude.len(), 1);
+        assert!(exclude[0].contains("'MarinVPN Bypass - Tor''s Browser'"));
+        assert!(exclude[0].contains("-Program 'C:\\Apps\\tor.exe' -Action Allow"));
+
+        let include = windows_app_rules(SplitTunnelMode::IncludeOnly, &apps);
+        assert_eq!(include.len(), 1);
+        assert!(include[0].contains("-notlike '*Wintun*'"));
+        assert!(include[0].contains("-Program 'C:\\Apps\\tor.exe'"));
+        assert!(include[0].contains("-Action Block"));
+    }
+
+    #[test]
+    fn only_apps_whose_executable_is_gone_are_missing() {
+        let dir = std::env::temp_dir().join(format!("marinvpn-split-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let present = dir.join("present");
+        std::fs::write(&present, b"").unwrap();
+        let gone = dir.join("gone");
+
+        let apps = [
+            app("Present", present.to_str().unwrap()),
+            app("Gone", gone.to_str().unwrap()),
+        ];
+        let missing = missing_apps(&apps);
+        assert_eq!(missing, [apps[1].clone()]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(missing_apps(&apps).len(), 2);
+    }
+
+    #[test]
+    fn stale_apps_are_relocated_by_image_name() {
+        assert_eq!(
+            image_name("C:\\Program Files\\Mozilla\\Firefox.EXE").as_deref(),
+            Some("firefox")
+        );
+        assert_eq!(image_name("/usr/bin/firefox").as_deref(), Some("firefox"));
+        assert_eq!(image_name("C:\\Apps\\"), None);
+
+        let stale = app("Firefox", "C:\\Program Files (x86)\\Mozilla\\firefox.exe");
+        let installed = [
+            app("Thunderbird", "C:\\Program Files\\Mozilla\\thunderbird.exe"),
+            app("Firefox", "C:\\Program Files\\Mozilla\\Firefox.exe"),
+        ];
+        assert_eq!(relocation(&stale, &installed), Some(&installed[1]));
+        assert_eq!(relocation(&stale, &installed[..1]), None);
+        assert_eq!(relocation(&installed[1], &installed), None);
+    }
+
+    #[test]
+    fn picked_apps_are_named_after_the_executable() {
+        assert_eq!(
+            picked_app("C:\\Program Files\\Mozilla\\Firefox.EXE"),
+            Some(app("Firefox", "C:\\Program Files\\Mozilla\\Firefox.EXE"))
+        );
+        assert_eq!(
+            picked_app("/opt/tor-browser/start-tor-browser"),
+            Some(app(
+                "start-tor-browser",
+                "/opt/tor-browser/start-tor-browser"
+            ))
+        );
+        assert_eq!(
+            picked_app("/usr/bin/python3.11").map(|a| a.name),
+            Some("python3.11".to_string())
+        );
+        assert_eq!(picked_app("C:\\Apps\\"), None);
+    }
+}
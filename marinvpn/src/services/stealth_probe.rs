@@ -0,0 +1,305 @@
+use crate::models::{ConnectionStatus, SettingsState, StealthMode, WireGuardConfig};
+use crate::services::vpn::{VpnEvent, VpnService};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+/// Modes tried by [`probe_stealth_modes`], cheapest first. Automatic is left
+/// out because it is itself a cycle over LWO, QUIC and WebSocket.
+pub const PROBE_ORDER: [StealthMode; 7] = [
+    StealthMode::None,
+    StealthMode::WireGuard443,
+    StealthMode::WireGuardPort,
+    StealthMode::Lwo,
+    StealthMode::Quic,
+    StealthMode::Tcp,
+    StealthMode::Shadowsocks,
+];
+
+/// How long one mode gets to complete a handshake.
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// The relay every mode is tried against. Only the entry hop is probed;
+/// obfuscation never wraps the exit of a multihop pair.
+#[derive(Clone, Debug)]
+pub struct ProbeTarget {
+    pub location: String,
+    pub config: WireGuardConfig,
+    pub auth: Option<(String, String)>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProbeOutcome {
+    /// Time from starting the attempt until a handshake was reported.
+    Handshake(Duration),
+    Failed(String),
+    TimedOut,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProbeResult {
+    pub mode: StealthMode,
+    pub outcome: ProbeOutcome,
+}
+
+/// What the settings view shows while a probe runs and after it is done.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StealthProbe {
+    pub running: bool,
+    pub results: Vec<ProbeResult>,
+}
+
+/// The mode that reached a handshake fastest, if any did.
+pub fn fastest(results: &[ProbeResult]) -> Option<StealthMode> {
+    results
+        .iter()
+        .filter_map(|r| match r.outcome {
+            ProbeOutcome::Handshake(latency) => Some((latency, r.mode)),
+            _ => None,
+        })
+        .min_by_key(|(latency, _)| *latency)
+        .map(|(_, mode)| mode)
+}
+
+/// Connects to `target` once per mode in `modes` and tears the tunnel down
+/// again after each attempt, whatever its outcome, so no obfuscator or
+/// per-attempt firewall rule carries over into the next one. Disconnecting
+/// keeps the blocking firewall up when `settings` has lockdown on, and the
+/// configured lockdown state is re-applied once every mode has been tried.
+///
+/// `report` sees each result as soon as it is known. Returns `None` without
+/// touching anything when a tunnel is already up or coming up.
+pub async fn probe_stealth_modes(
+    service: &dyn VpnService,
+    target: &ProbeTarget,
+    settings: &SettingsState,
+    modes: &[StealthMode],
+    timeout: Duration,
+    mut report: impl FnMut(&ProbeResult),
+) -> Option<Vec<ProbeResult>> {
+    if service.get_status().await != ConnectionStatus::Disconnected {
+        return None;
+    }
+
+    let mut results = Vec::with_capacity(modes.len());
+    for &mode in modes {
+        let mut attempt = settings.clone();
+        attempt.stealth_mode = mode;
+
+        let mut events = service.subscribe();
+        let started = Instant::now();
+        let outcome = tokio::time::timeout(timeout, async {
+            service
+                .connect(
+                    target.location.clone(),
+                    target.config.clone(),
+                    None,
+                    attempt,
+                    target.auth.clone(),
+                )
+                .await;
+            await_handshake(&mut events).await
+        })
+        .await;
+        service.disconnect().await;
+
+        let outcome = match outcome {
+            Ok(Ok(())) => ProbeOutcome::Handshake(started.elapsed()),
+            Ok(Err(reason)) => ProbeOutcome::Failed(reason),
+            Err(_) => ProbeOutcome::TimedOut,
+        };
+        let result = ProbeResult { mode, outcome };
+        report(&result);
+        results.push(result);
+    }
+
+    let _ = service.apply_lockdown(settings).await;
+    Some(results)
+}
+
+async fn await_handshake(events: &mut broadcast::Receiver<VpnEvent>) -> Result<(), String> {
+    loop {
+        match events.recv().await {
+            Ok(VpnEvent::StatsUpdated(stats)) if stats.latest_handshake != 0 => return Ok(()),
+            Ok(VpnEvent::Error(e)) => return Err(e.to_string()),
+            Ok(VpnEvent::StatusChanged(ConnectionStatus::Disconnected)) => {
+                return Err("Tunnel closed before a handshake".to_string())
+            }
+            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+            Err(broadcast::error::RecvError::Closed) => {
+                return Err("VPN service stopped".to_string())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::vpn::{VpnError, VpnStats};
+    use crate::services::vpn_mock::{MockCall, MockVpnService};
+
+    fn target() -> ProbeTarget {
+        ProbeTarget {
+            location: "Sweden, Stockholm".to_string(),
+            config: WireGuardConfig {
+                private_key: "priv".to_string(),
+                public_key: "pub".to_string(),
+                preshared_key: None,
+                endpoint: "192.0.2.1:51820".to_string(),
+                allowed_ips: "0.0.0.0/0".to_string(),
+                address: "10.0.0.2/32".to_string(),
+                dns: None,
+                pqc_handshake: None,
+                pqc_provider: None,
+                pqc_ciphertext: None,
+                obfuscation_key: None,
+                obfuscation_port: None,
+                obfuscation_cipher: None,
+            },
+            auth: None,
+        }
+    }
+
+    fn handshake() -> VpnEvent {
+        VpnEvent::StatsUpdated(VpnStats {
+            download_speed: 0.0,
+            upload_speed: 0.0,
+            total_download: 0,
+            total_upload: 0,
+            latest_handshake: 1_700_000_000,
+        })
+    }
+
+    /// QUIC and TCP get through; plain UDP is refused and LWO comes up but
+    /// never completes a handshake.
+    fn censored_network() -> MockVpnService {
+        let vpn = MockVpnService::new();
+        vpn.on_connect(|settings| match settings.stealth_mode {
+            StealthMode::Quic | StealthMode::Tcp => vec![
+                VpnEvent::StatusChanged(ConnectionStatus::Connected),
+                handshake(),
+            ],
+            StealthMode::Lwo => vec![VpnEvent::StatusChanged(ConnectionStatus::Connected)],
+            _ => vec![
+                VpnEvent::Error(VpnError::ConnectionFailed("blocked".to_string())),
+                VpnEvent::StatusChanged(ConnectionStatus::Disconnected),
+            ],
+        });
+        vpn
+    }
+
+    async fn probe(
+        vpn: &MockVpnService,
+        settings: &SettingsState,
+        modes: &[StealthMode],
+    ) -> Option<Vec<ProbeResult>> {
+        let timeout = Duration::from_millis(50);
+        probe_stealth_modes(vpn, &target(), settings, modes, timeout, |_| {}).await
+    }
+
+    #[tokio::test]
+    async fn every_mode_is_tried_and_reported() {
+        let vpn = censored_network();
+        let modes = [StealthMode::None, StealthMode::Lwo, StealthMode::Quic];
+        let mut reported = Vec::new();
+        let results = probe_stealth_modes(
+            &vpn,
+            &target(),
+            &SettingsState::default(),
+            &modes,
+            Duration::from_millis(50),
+            |r| reported.push(r.mode),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(reported, modes);
+        assert_eq!(
+            results[0].outcome,
+            ProbeOutcome::Failed("Connection failed: blocked".to_string())
+        );
+        assert_eq!(results[1].outcome, ProbeOutcome::TimedOut);
+        assert!(matches!(results[2].outcome, ProbeOutcome::Handshake(_)));
+        assert_eq!(fastest(&results), Some(StealthMode::Quic));
+    }
+
+    #[tokio::test]
+    async fn each_attempt_is_torn_down_before_the_next() {
+        let vpn = censored_network();
+        let modes = [StealthMode::None, StealthMode::Lwo, StealthMode::Tcp];
+        probe(&vpn, &SettingsState::default(), &modes)
+            .await
+            .unwrap();
+
+        let connect = MockCall::Connect {
+            entry: "Sweden, Stockholm".to_string(),
+            exit: None,
+        };
+        assert_eq!(
+            vpn.calls(),
+            [
+                connect.clone(),
+                MockCall::Disconnect,
+                connect.clone(),
+                MockCall::Disconnect,
+                connect,
+                MockCall::Disconnect,
+                MockCall::ApplyLockdown,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn attempts_keep_the_lockdown_setting() {
+        let vpn = MockVpnService::new();
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let record = seen.clone();
+        vpn.on_connect(move |settings| {
+            record
+                .lock()
+                .unwrap()
+                .push((settings.stealth_mode, settings.lockdown_mode));
+            vec![handshake()]
+        });
+        let settings = SettingsState {
+            lockdown_mode: true,
+            ..SettingsState::default()
+        };
+
+        probe(&vpn, &settings, &[StealthMode::Quic, StealthMode::Tcp])
+            .await
+            .unwrap();
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [(StealthMode::Quic, true), (StealthMode::Tcp, true)]
+        );
+        assert_eq!(vpn.calls().last(), Some(&MockCall::ApplyLockdown));
+    }
+
+    #[tokio::test]
+    async fn refuses_to_run_over_a_live_tunnel() {
+        let vpn = censored_network();
+        vpn.emit(VpnEvent::StatusChanged(ConnectionStatus::Connected));
+
+        let results = probe(&vpn, &SettingsState::default(), &PROBE_ORDER).await;
+        assert_eq!(results, None);
+        assert!(vpn.calls().is_empty());
+    }
+
+    #[test]
+    fn fastest_ignores_failures() {
+        let result = |mode, outcome| ProbeResult { mode, outcome };
+        let ms = |millis| ProbeOutcome::Handshake(Duration::from_millis(millis));
+        let results = [
+            result(StealthMode::None, ProbeOutcome::TimedOut),
+            result(StealthMode::Tcp, ms(900)),
+            result(StealthMode::Quic, ms(300)),
+            result(
+                StealthMode::Lwo,
+                ProbeOutcome::Failed("blocked".to_string()),
+            ),
+        ];
+        assert_eq!(fastest(&results), Some(StealthMode::Quic));
+        assert_eq!(fastest(&results[..1]), None);
+    }
+}
@@ -1,7 +1,27 @@
-use crate::models::{ConnectionStatus, SettingsState, StealthMode, WireGuardConfig};
+use crate::models::{
+    ConnectionStatus, Location, LocationSelection, ReconnectPolicy, SettingsState, SplitTunnelMode,
+    StealthMode, WireGuardConfig,
+};
+use crate::services::cidr::{self, Cidr};
+use crate::services::connect_timing::{timed, Phase};
+#[cfg(any(target_os = "linux", target_os = "windows"))]
+use crate::services::dns_routes;
+use crate::services::doh;
+#[cfg(any(target_os = "linux", target_os = "windows"))]
+use crate::services::firewall_cleanup::{remove_leftovers, SystemCommands, CLEANUP_ATTEMPTS};
+#[cfg(target_os = "linux")]
+use crate::services::linux_firewall::{self, Firewall, KillSwitchRules};
+use crate::services::network_id;
+use crate::services::split_tunnel;
+use crate::services::wg_conf::{self, PeerSection, WgConfFile};
+use crate::services::wg_show::WgShow;
 use base64::Engine;
+use futures_util::FutureExt;
 use rand::Rng;
-use std::net::{SocketAddr, TcpStream};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::panic::AssertUnwindSafe;
 use std::sync::Arc;
 use std::time::Duration;
 use std::time::Instant;
@@ -21,7 +41,7 @@ pub struct VpnStats {
 
 #[derive(Clone, Debug)]
 pub enum VpnError {
-    ConfigMissing,
+    ConfigMissing(String),
     NetworkUnreachable,
     ConnectionFailed(String),
     InterfaceError(String),
@@ -31,10 +51,28 @@ pub enum VpnError {
     FirewallError(String),
 }
 
+impl VpnError {
+    /// Stable name of the variant, used as a key in [`ConnectionStats`].
+    ///
+    /// [`ConnectionStats`]: crate::services::connection_stats::ConnectionStats
+    pub fn kind(&self) -> &'static str {
+        match self {
+            VpnError::ConfigMissing(_) => "config_missing",
+            VpnError::NetworkUnreachable => "network_unreachable",
+            VpnError::ConnectionFailed(_) => "connection_failed",
+            VpnError::InterfaceError(_) => "interface_error",
+            VpnError::PermissionDenied => "permission_denied",
+            VpnError::DriverMissing => "driver_missing",
+            VpnError::NotRoot => "not_root",
+            VpnError::FirewallError(_) => "firewall_error",
+        }
+    }
+}
+
 impl std::fmt::Display for VpnError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            VpnError::ConfigMissing => write!(f, "Missing WireGuard configuration."),
+            VpnError::ConfigMissing(msg) => write!(f, "Incomplete configuration: {}", msg),
             VpnError::NetworkUnreachable => write!(f, "No internet connection detected."),
             VpnError::ConnectionFailed(msg) => write!(f, "Connection failed: {}", msg),
             VpnError::InterfaceError(msg) => write!(f, "Interface error: {}", msg),
@@ -55,6 +93,31 @@ pub enum VpnEvent {
     StatsUpdated(VpnStats),
     Error(VpnError),
     CaptivePortalActive(bool),
+    /// Where the entry hop leaves this machine; `None` once disconnected.
+    TransportChanged(Option<StealthPort>),
+    /// Automatic got no UDP handshake and moved to UDP-over-TCP. Carries the
+    /// network to remember this for, if it could be identified.
+    UdpBlocked(Option<String>),
+    /// The tunnel is about to be torn down and rebuilt without the user
+    /// asking. The usual status changes follow.
+    Recovering(RecoveryCause),
+    /// A self-healing reconnect brought the tunnel back. `new_server` is set
+    /// when failover had to move to another server.
+    Recovered {
+        cause: RecoveryCause,
+        new_server: Option<String>,
+    },
+}
+
+/// Why the tunnel was rebuilt without the user asking.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecoveryCause {
+    /// No handshake for longer than the reconnect policy allows.
+    StaleHandshake,
+    /// The host woke from sleep and the tunnel had not survived it.
+    Resume,
+    /// Health checks through the tunnel kept failing.
+    HealthCheck,
 }
 
 #[async_trait::async_trait]
@@ -73,6 +136,12 @@ pub trait VpnService: Send + Sync {
     async fn enable_captive_portal(&self, duration_secs: u64);
     async fn apply_lockdown(&self, settings: &SettingsState) -> Result<(), VpnError>;
     async fn disable_kill_switch(&self);
+    /// Moves a connected tunnel's entry hop to `mode` without reconnecting.
+    /// Does nothing while disconnected; the next connect reads the setting.
+    async fn switch_obfuscation(&self, mode: StealthMode) -> Result<(), VpnError>;
+    /// Puts the split tunneling apps in `settings` in place on a connected
+    /// tunnel. Does nothing while disconnected; the next connect reads them.
+    async fn update_split_apps(&self, settings: &SettingsState);
 }
 
 #[async_trait::async_trait]
@@ -82,11 +151,21 @@ trait WgRunner: Send + Sync {
         entry: &WireGuardConfig,
         exit: Option<&WireGuardConfig>,
         settings: &SettingsState,
-    ) -> Result<(), VpnError>;
+    ) -> Result<StealthPort, VpnError>;
     async fn down(&self) -> Result<(), VpnError>;
+    /// Replaces the running obfuscator with `settings.stealth_mode`'s and
+    /// points the entry interface at it, leaving the interfaces up.
+    async fn switch_stealth(
+        &self,
+        entry: &WireGuardConfig,
+        exit: Option<&WireGuardConfig>,
+        settings: &SettingsState,
+    ) -> Result<StealthPort, VpnError>;
     async fn get_stats(&self) -> Result<VpnStats, VpnError>;
-    async fn apply_app_bypass(&self, app_path: &str);
     async fn apply_bypass_route(&self, ip: &str);
+    /// Replaces the per-app split tunneling rules of a running tunnel with
+    /// those for `settings`, releasing apps that are no longer listed.
+    async fn apply_app_bypass(&self, tunnel: &WireGuardConfig, settings: &SettingsState);
     async fn apply_single_up(&self, iface: &str, conf: &str) -> Result<(), VpnError>;
     async fn apply_single_down(&self, iface: &str);
     async fn enable_kill_switch(
@@ -95,9 +174,782 @@ trait WgRunner: Send + Sync {
         settings: &SettingsState,
     ) -> Result<(), VpnError>;
     async fn disable_kill_switch(&self);
+    /// Finds the tunnel MTU towards `entry`'s relay for `up` to use, unless
+    /// `settings` fix one. Called before the kill switch goes on, since its
+    /// rules drop the ICMP the probe relies on.
+    async fn probe_mtu(&self, entry: &WireGuardConfig, settings: &SettingsState);
+    /// Whether a TCP connection to `port` on the relay behind `endpoint`
+    /// can be opened.
+    async fn tcp_reachable(&self, endpoint: &str, port: u16) -> bool;
 }
 
 const DEFAULT_WIREGUARD_PORT: u16 = 51820;
+/// Relay port for the transports that pass as HTTPS: QUIC, WebSocket and
+/// raw TCP.
+const STEALTH_HTTPS_PORT: u16 = 443;
+const STEALTH_DNS_PORT: u16 = 53;
+const SHADOWSOCKS_DEFAULT_PORT: u16 = 8388;
+const SHADOWSOCKS_DEFAULT_CIPHER: &str = "aes-256-gcm";
+/// AEAD ciphers `ss-local` (shadowsocks-libev) implements. A relay asking
+/// for anything else is refused before `ss-local` is spawned, since it
+/// would only exit straight away.
+const SHADOWSOCKS_CIPHERS: &[&str] = &[
+    "aes-128-gcm",
+    "aes-192-gcm",
+    "aes-256-gcm",
+    "chacha20-ietf-poly1305",
+    "xchacha20-ietf-poly1305",
+];
+const STALE_HANDSHAKE_SECS: u64 = 180;
+/// Still above WireGuard's two-minute rekey interval, so a tunnel carrying
+/// traffic never gets there.
+const AGGRESSIVE_STALE_HANDSHAKE_SECS: u64 = 150;
+const RESUME_POLL_SECS: u64 = 5;
+const RESUME_GAP_SECS: u64 = 15;
+/// How long Automatic waits for the first handshake over UDP before checking
+/// whether UDP is blocked.
+const UDP_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(8);
+/// How long a live obfuscation switch waits for the handshake over the new
+/// transport before reporting the tunnel connected anyway. The stale
+/// handshake check takes over from there.
+const SWITCH_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const HANDSHAKE_POLL: Duration = Duration::from_millis(250);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ResumeAction {
+    KeepTunnel,
+    Reconnect,
+}
+
+/// Decides what to do with the tunnel after the host wakes up. Peers drop
+/// their session state while we sleep, so anything without a recent handshake
+/// is rebuilt instead of waiting for the health monitor to notice. This one
+/// stays on the wall clock: `Instant` does not advance while suspended, so only
+/// wall time shows how long the peer went without us. A clock step mistaken
+/// for a resume costs at most one reconnect.
+fn resume_action(policy: ReconnectPolicy, latest_handshake: Option<u64>, now: u64) -> ResumeAction {
+    let Some(limit) = stale_handshake_limit(policy) else {
+        return ResumeAction::KeepTunnel;
+    };
+    match latest_handshake {
+        Some(ts) if ts > 0 && now.saturating_sub(ts) <= limit.as_secs() => ResumeAction::KeepTunnel,
+        _ => ResumeAction::Reconnect,
+    }
+}
+
+/// How old the handshake may get before the tunnel is rebuilt, or `None`
+/// when `policy` leaves it alone however old it gets.
+fn stale_handshake_limit(policy: ReconnectPolicy) -> Option<Duration> {
+    match policy {
+        ReconnectPolicy::Never => None,
+        ReconnectPolicy::OnFailure => Some(Duration::from_secs(STALE_HANDSHAKE_SECS)),
+        ReconnectPolicy::Aggressive => Some(Duration::from_secs(AGGRESSIVE_STALE_HANDSHAKE_SECS)),
+    }
+}
+
+/// How often the health monitor probes, and how many failed probes in a row
+/// make it fail over.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct HealthSchedule {
+    interval: Duration,
+    failures_before_failover: u32,
+}
+
+/// `None` when `policy` runs no health monitor at all.
+fn health_schedule(policy: ReconnectPolicy) -> Option<HealthSchedule> {
+    match policy {
+        ReconnectPolicy::Never => None,
+        ReconnectPolicy::OnFailure => Some(HealthSchedule {
+            interval: Duration::from_secs(30),
+            failures_before_failover: 3,
+        }),
+        ReconnectPolicy::Aggressive => Some(HealthSchedule {
+            interval: Duration::from_secs(10),
+            failures_before_failover: 2,
+        }),
+    }
+}
+
+/// Whether failover may move to another server rather than reconnect to the
+/// same one. A city the user picked is always kept; a country only gives
+/// way under `Aggressive`, and then to another server in that country.
+fn failover_switches_server(policy: ReconnectPolicy, selection: &LocationSelection) -> bool {
+    match (policy, selection) {
+        (ReconnectPolicy::Never, _) | (_, LocationSelection::Specific(_)) => false,
+        (_, LocationSelection::Automatic) => true,
+        (ReconnectPolicy::OnFailure, LocationSelection::BestIn(_)) => false,
+        (ReconnectPolicy::Aggressive, LocationSelection::BestIn(_)) => true,
+    }
+}
+
+/// Whether failover may move the tunnel `settings` describe to another
+/// server. A pinned server rules it out whatever the policy, so the exit IP
+/// stays the same.
+fn failover_may_switch(settings: &SettingsState) -> bool {
+    settings.pinned_server.is_none()
+        && failover_switches_server(settings.reconnect_policy, &settings.entry_location)
+}
+
+/// The server name and config failover reconnects with: the best server for
+/// the selection where [`failover_may_switch`] allows it, otherwise the ones
+/// the tunnel had.
+async fn failover_target(
+    settings: &SettingsState,
+    entry_name: String,
+    entry_config: WireGuardConfig,
+    auth: Option<&(String, String)>,
+) -> (String, WireGuardConfig) {
+    if !failover_may_switch(settings) {
+        return (entry_name, entry_config);
+    }
+    info!("Failover: Re-scanning for best available server...");
+    let Ok(new_server) = crate::services::servers::ServersService::find_best_server(
+        settings.entry_location.country(),
+    )
+    .await
+    else {
+        return (entry_name, entry_config);
+    };
+    info!(
+        "Failover: Found new candidate {}. Fetching fresh configuration...",
+        new_server.city
+    );
+
+    let mut final_config = entry_config;
+    if let Some((account, token)) = auth {
+        let location = Location::from(&new_server).to_string();
+        if let Ok(cfg) = crate::services::auth::AuthService::get_config(
+            account,
+            &location,
+            token,
+            &settings.server_settings(),
+            settings.key_reuse,
+        )
+        .await
+        {
+            final_config = cfg;
+        }
+    }
+    (new_server.city, final_config)
+}
+
+/// How long ago the tunnel's last handshake was, kept on the monotonic clock.
+///
+/// WireGuard reports handshakes as Unix timestamps, so comparing them with the
+/// wall clock on every poll turns a clock step into a false verdict: jump
+/// forward and a healthy tunnel looks stale, jump back and a dead one never
+/// does. The wall clock is read only when a new handshake appears; after that
+/// its age grows with `Instant`.
+#[derive(Debug, Default)]
+struct HandshakeAge {
+    seen: Option<(u64, Duration, Instant)>,
+    last_poll: Option<Instant>,
+}
+
+impl HandshakeAge {
+    /// Returns the handshake's age, or `None` before the first handshake.
+    fn observe(&mut self, latest_handshake: u64, wall_now: u64, now: Instant) -> Option<Duration> {
+        let last_poll = self.last_poll.replace(now);
+        if latest_handshake == 0 {
+            self.seen = None;
+            return None;
+        }
+
+        match self.seen {
+            Some((ts, age, at)) if ts == latest_handshake => Some(age + now.duration_since(at)),
+            _ => {
+                let mut age = Duration::from_secs(wall_now.saturating_sub(latest_handshake));
+                if let Some(last_poll) = last_poll {
+                    // It was not there at the last poll, whatever the wall
+                    // clock says now.
+                    age = age.min(now.duration_since(last_poll));
+                }
+                self.seen = Some((latest_handshake, age, now));
+                Some(age)
+            }
+        }
+    }
+}
+
+/// KiB/s between two readings of a byte counter. A counter that went
+/// backwards (the interface was recreated) counts as no traffic.
+fn transfer_rate(previous: u64, current: u64, elapsed: Duration) -> f64 {
+    let secs = elapsed.as_secs_f64();
+    if secs > 0.0 {
+        current.saturating_sub(previous) as f64 / secs / 1024.0
+    } else {
+        0.0
+    }
+}
+
+/// A poll that arrives much later on the wall clock than scheduled means the
+/// process was frozen, which in practice is a suspend/resume cycle.
+fn is_resume_gap(expected: Duration, observed: Duration) -> bool {
+    observed > expected + Duration::from_secs(RESUME_GAP_SECS)
+}
+
+/// Bytes added by one WireGuard hop: outer IPv6 header (40), UDP (8) and the
+/// WireGuard data header plus auth tag (32). IPv6 is assumed so the figure is
+/// safe for either address family.
+const WG_HOP_OVERHEAD: u32 = 80;
+const MIN_TUNNEL_MTU: u32 = 576;
+const MAX_TUNNEL_MTU: u32 = 1500 - WG_HOP_OVERHEAD;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct MultihopMtu {
+    entry: u32,
+    exit: u32,
+}
+
+/// Range the automatic tunnel MTU is searched in.
+const PROBE_MTU_MIN: u32 = 1200;
+const PROBE_MTU_MAX: u32 = MAX_TUNNEL_MTU;
+/// Used when probing gives no answer: survives most PPPoE and mobile links.
+const FALLBACK_TUNNEL_MTU: u32 = 1280;
+const MTU_PROBE_BUDGET: Duration = Duration::from_secs(3);
+const MTU_PROBE_TIMEOUT: Duration = Duration::from_millis(400);
+
+/// MTU the user set for the tunnel, or `None` when it is automatic. `0` and
+/// the stock 1420 both mean automatic.
+fn fixed_mtu(settings: &SettingsState) -> Option<u32> {
+    match settings.mtu {
+        0 | 1420 => None,
+        mtu => Some(mtu),
+    }
+}
+
+/// Binary search for the largest MTU in `low..=high` that `fits`, assuming
+/// every smaller one fits too. The top of the range is tried first, since
+/// most paths carry it. `None` when not even `low` fits.
+async fn search_mtu<F, Fut>(low: u32, high: u32, mut fits: F) -> Option<u32>
+where
+    F: FnMut(u32) -> Fut,
+    Fut: Future<Output = bool>,
+{
+    if fits(high).await {
+        return Some(high);
+    }
+    if !fits(low).await {
+        return None;
+    }
+    let (mut good, mut bad) = (low, high);
+    while bad - good > 1 {
+        let mid = good + (bad - good) / 2;
+        if fits(mid).await {
+            good = mid;
+        } else {
+            bad = mid;
+        }
+    }
+    Some(good)
+}
+
+/// Whether tunnel packets of `mtu` bytes reach `ip` unfragmented once
+/// wrapped: one ICMP echo of the wrapped size with Don't Fragment set.
+async fn path_fits(ip: IpAddr, mtu: u32) -> bool {
+    // The IP header plus the 8-byte echo header.
+    let headers = if ip.is_ipv4() { 28 } else { 48 };
+    let payload = (mtu + WG_HOP_OVERHEAD - headers).to_string();
+    let mut ping = Command::new("ping");
+    if cfg!(target_os = "windows") {
+        ping.args(["-n", "1", "-w", "1000", "-l", &payload]);
+        // Routers never fragment IPv6, and `-f` is refused for it.
+        if ip.is_ipv4() {
+            ping.arg("-f");
+        }
+    } else {
+        ping.args(["-c", "1", "-W", "1", "-M", "do", "-s", &payload]);
+    }
+    ping.arg(ip.to_string()).kill_on_drop(true);
+    matches!(
+        tokio::time::timeout(MTU_PROBE_TIMEOUT, ping.output()).await,
+        Ok(Ok(output)) if output.status.success()
+    )
+}
+
+/// The exit tunnel is carried inside the entry tunnel, so it loses one more
+/// hop of overhead on top of whatever the entry interface can carry.
+fn multihop_mtu(base: u32) -> Result<MultihopMtu, VpnError> {
+    let entry = base.min(MAX_TUNNEL_MTU);
+    let exit = entry.saturating_sub(WG_HOP_OVERHEAD);
+    if exit < MIN_TUNNEL_MTU {
+        return Err(VpnError::InterfaceError(format!(
+            "MTU {} is too small for multihop (exit tunnel would be {}, minimum is {})",
+            base, exit, MIN_TUNNEL_MTU
+        )));
+    }
+    Ok(MultihopMtu { entry, exit })
+}
+
+/// The resolvers the physical adapters used before the tunnel came up,
+/// without loopback placeholders.
+#[cfg(target_os = "windows")]
+fn local_dns_servers(snapshot: &[DnsSnapshot], tunnels: &[&str]) -> Vec<String> {
+    let mut servers: Vec<String> = Vec::new();
+    for entry in snapshot {
+        if tunnels.contains(&entry.interface_alias.as_str()) {
+            continue;
+        }
+        for server in &entry.server_addresses {
+            let loopback = server
+                .parse::<IpAddr>()
+                .map(|ip| ip.is_loopback())
+                .unwrap_or(true);
+            if !loopback && !servers.contains(server) {
+                servers.push(server.clone());
+            }
+        }
+    }
+    servers
+}
+
+/// The interface the user's traffic leaves through, which is where DNS must
+/// be set. With multihop that is the exit tunnel nested inside the entry
+/// one; the entry interface then only carries the exit tunnel's packets.
+fn egress_interface<'a>(entry: &'a str, exit: &'a str, multihop: bool) -> &'a str {
+    if multihop {
+        exit
+    } else {
+        entry
+    }
+}
+
+/// `endpoint` with its port replaced, for modes that only move WireGuard to
+/// a port the network is likely to let through.
+fn endpoint_on_port(endpoint: &str, port: u16) -> String {
+    let (host, _) = parse_endpoint_host_port(endpoint);
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// What `start_stealth` set up for the entry hop.
+struct StealthRoute {
+    /// Local endpoint WireGuard dials instead of the relay, if any.
+    endpoint: Option<String>,
+    port: StealthPort,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+}
+
+/// A port the entry hop may use to reach the relay.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StealthPort {
+    pub transport: Transport,
+    pub port: u16,
+}
+
+impl StealthPort {
+    fn udp(port: u16) -> Self {
+        Self {
+            transport: Transport::Udp,
+            port,
+        }
+    }
+
+    fn tcp(port: u16) -> Self {
+        Self {
+            transport: Transport::Tcp,
+            port,
+        }
+    }
+
+    fn protocol(&self) -> &'static str {
+        match self.transport {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp",
+        }
+    }
+}
+
+impl std::fmt::Display for StealthPort {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.protocol().to_uppercase(), self.port)
+    }
+}
+
+/// Every port `mode` may use, in the order they are tried, for a relay
+/// endpoint that names `relay_port` (`None` when it names none). The kill
+/// switch opens exactly these and the settings view lists them, so neither
+/// can drift from what the obfuscators dial.
+pub fn stealth_ports(mode: StealthMode, relay_port: Option<u16>) -> Vec<StealthPort> {
+    let wireguard = StealthPort::udp(relay_port.unwrap_or(DEFAULT_WIREGUARD_PORT));
+    match mode {
+        StealthMode::None | StealthMode::Lwo => vec![wireguard],
+        StealthMode::WireGuardPort => vec![StealthPort::udp(STEALTH_DNS_PORT)],
+        StealthMode::WireGuard443 => vec![StealthPort::udp(STEALTH_HTTPS_PORT)],
+        StealthMode::Quic => vec![StealthPort::udp(STEALTH_HTTPS_PORT)],
+        StealthMode::Tcp => vec![StealthPort::tcp(STEALTH_HTTPS_PORT)],
+        StealthMode::Shadowsocks => {
+            let port = relay_port.unwrap_or(SHADOWSOCKS_DEFAULT_PORT);
+            vec![StealthPort::udp(port), StealthPort::tcp(port)]
+        }
+        // LWO, then QUIC, then WebSocket; see `start_stealth`.
+        StealthMode::Automatic => vec![
+            wireguard,
+            StealthPort::udp(STEALTH_HTTPS_PORT),
+            StealthPort::tcp(STEALTH_HTTPS_PORT),
+        ],
+    }
+}
+
+/// The ports `mode` uses against a relay on the default WireGuard port, as
+/// shown before connecting.
+pub fn stealth_port_hint(mode: StealthMode) -> String {
+    let ports: Vec<String> = stealth_ports(mode, None)
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    ports.join(", ")
+}
+
+/// The port named in `endpoint`, if it names one.
+fn endpoint_port(endpoint: &str) -> Option<u16> {
+    endpoint
+        .contains(':')
+        .then(|| parse_endpoint_host_port(endpoint).1)
+}
+
+/// The relay port `mode` dials for `entry`, for [`stealth_ports`].
+/// Shadowsocks uses the relay's own obfuscation port when it has one.
+fn relay_port(entry: &WireGuardConfig, mode: StealthMode) -> Option<u16> {
+    match (mode, entry.obfuscation_port) {
+        (StealthMode::Shadowsocks, Some(port)) => Some(port),
+        _ => endpoint_port(&entry.endpoint),
+    }
+}
+
+/// `entry`'s endpoint on the port `mode` dials, which is what the kill
+/// switch has to let through.
+fn kill_switch_endpoint(entry: &WireGuardConfig, mode: StealthMode) -> String {
+    match relay_port(entry, mode) {
+        Some(port) => endpoint_on_port(&entry.endpoint, port),
+        None => entry.endpoint.clone(),
+    }
+}
+
+/// The Shadowsocks cipher `entry`'s relay expects, if `ss-local` has it.
+fn shadowsocks_cipher(entry: &WireGuardConfig) -> Result<&str, VpnError> {
+    match entry.obfuscation_cipher.as_deref() {
+        None => Ok(SHADOWSOCKS_DEFAULT_CIPHER),
+        Some(cipher) if SHADOWSOCKS_CIPHERS.contains(&cipher) => Ok(cipher),
+        Some(cipher) => Err(VpnError::ConfigMissing(format!(
+            "the relay uses the Shadowsocks cipher {}, which is not supported (supported: {})",
+            cipher,
+            SHADOWSOCKS_CIPHERS.join(", ")
+        ))),
+    }
+}
+
+/// The programs the kill switch drives on this platform, each with
+/// arguments that make it exit straight away. Linux picks a backend in
+/// [`linux_firewall`] instead.
+#[cfg(target_os = "windows")]
+const FIREWALL_TOOLS: &[(&str, &[&str])] = &[
+    ("netsh", &["advfirewall", "show", "currentprofile", "state"]),
+    ("powershell", &["-NoProfile", "-Command", "exit"]),
+];
+#[cfg(not(any(target_os = "linux", target_os = "windows")))]
+const FIREWALL_TOOLS: &[(&str, &[&str])] = &[];
+
+/// Fails on the first of `tools` that is not installed. Every firewall call
+/// ignores its outcome, so without this a missing tool would leave the kill
+/// switch reported on while nothing is blocked.
+async fn check_firewall_tools(tools: &[(&str, &[&str])]) -> Result<(), VpnError> {
+    for (program, args) in tools {
+        let started = Command::new(program)
+            .args(*args)
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()
+            .await;
+        if matches!(&started, Err(e) if e.kind() == std::io::ErrorKind::NotFound) {
+            return Err(missing_tool_error(program));
+        }
+    }
+    Ok(())
+}
+
+/// The first Linux firewall backend that is installed.
+#[cfg(target_os = "linux")]
+async fn linux_firewall() -> Result<Box<dyn Firewall>, VpnError> {
+    for firewall in linux_firewall::backends() {
+        if check_firewall_tools(firewall.tools()).await.is_ok() {
+            return Ok(firewall);
+        }
+    }
+    Err(VpnError::FirewallError(
+        "Neither nftables (nft) nor iptables is installed, so the kill switch cannot block traffic"
+            .to_string(),
+    ))
+}
+
+fn missing_tool_error(program: &str) -> VpnError {
+    let tool = match program {
+        "nft" => "nftables (nft)",
+        "powershell" => "PowerShell",
+        other => other,
+    };
+    VpnError::FirewallError(format!(
+        "{} is not installed, so the kill switch cannot block traffic",
+        tool
+    ))
+}
+
+/// `(protocol, port)` pairs the kill switch lets through to the entry relay.
+fn kill_switch_allow_rules(endpoint: &str, mode: StealthMode) -> Vec<(&'static str, u16)> {
+    stealth_ports(mode, endpoint_port(endpoint))
+        .iter()
+        .map(|p| (p.protocol(), p.port))
+        .collect()
+}
+
+/// Display names of the rules the Windows kill switch adds. Re-enabling it
+/// removes only these: the DoH blocks [`RealWgRunner::apply_dns`] adds share
+/// the `MarinVPN` prefix and have to outlive a re-applied kill switch.
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+const WINDOWS_KILL_SWITCH_RULES: &[&str] = &[
+    "MarinVPN - Allow Loopback",
+    "MarinVPN - Allow Endpoint *",
+    "MarinVPN - Allow ICMPv6 ND",
+    "MarinVPN - Allow DHCPv6",
+    "MarinVPN - Allow Tunnel *",
+    "MarinVPN - Bypass IP *",
+    "MarinVPN - Block IPv6 *",
+    "MarinVPN - Block LAN",
+    "MarinVPN - Leak Protect DNS *",
+];
+
+/// PowerShell that removes [`WINDOWS_KILL_SWITCH_RULES`].
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+fn windows_kill_switch_removal() -> String {
+    let names: Vec<String> = WINDOWS_KILL_SWITCH_RULES
+        .iter()
+        .map(|name| format!("'{}'", name))
+        .collect();
+    format!(
+        "Remove-NetFirewallRule -DisplayName {} -ErrorAction SilentlyContinue",
+        names.join(",")
+    )
+}
+
+/// Returns the entry config WireGuard should actually dial once stealth is up.
+fn with_stealth_endpoint(
+    entry: &WireGuardConfig,
+    stealth_endpoint: Option<String>,
+) -> WireGuardConfig {
+    let mut final_entry = entry.clone();
+    if let Some(ep) = stealth_endpoint {
+        final_entry.endpoint = ep;
+    }
+    final_entry
+}
+
+/// The AllowedIPs of the peer that carries the user's traffic: the server's
+/// list with split tunneling applied. Unparsable user entries are skipped.
+fn tunnel_allowed_ips(
+    config: &WireGuardConfig,
+    relay: &str,
+    settings: &SettingsState,
+) -> Vec<String> {
+    let Ok(server) = cidr::parse_list(&config.allowed_ips) else {
+        return vec![config.allowed_ips.clone()];
+    };
+    if !settings.split_tunneling {
+        return server.iter().map(Cidr::to_string).collect();
+    }
+
+    let mut routed = match settings.split_mode {
+        SplitTunnelMode::Exclude => cidr::subtract(&server, &parse_ranges(&settings.excluded_ips)),
+        // Listed apps need the whole tunnel; packet marks send the rest around it.
+        SplitTunnelMode::IncludeOnly if settings.tunnels_only_listed_apps() => server,
+        SplitTunnelMode::IncludeOnly => {
+            cidr::intersect(&server, &include_only_ranges(config, settings))
+        }
+    };
+
+    // Without a /0 for its family wg-quick adds the ranges as plain routes,
+    // and a relay inside one of them would be routed into its own tunnel.
+    if let Ok(ip) = parse_endpoint_host_port(relay).0.parse::<IpAddr>() {
+        let default_route = routed
+            .iter()
+            .any(|r| r.prefix() == 0 && r.addr().is_ipv4() == ip.is_ipv4());
+        if !default_route {
+            routed = cidr::subtract(&routed, &[Cidr::host(ip)]);
+        }
+    }
+    routed.iter().map(Cidr::to_string).collect()
+}
+
+/// What include-only mode routes through the tunnel for every app: the
+/// listed ranges, and the relay's resolver, which is only reachable there.
+fn include_only_ranges(config: &WireGuardConfig, settings: &SettingsState) -> Vec<Cidr> {
+    let mut wanted = parse_ranges(&settings.included_ips);
+    if !settings.uses_custom_dns() {
+        wanted.extend(
+            config
+                .dns
+                .iter()
+                .flat_map(|dns| dns.split(','))
+                .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+                .map(Cidr::host),
+        );
+    }
+    wanted
+}
+
+fn parse_ranges(list: &[String]) -> Vec<Cidr> {
+    list.iter().filter_map(|r| r.parse().ok()).collect()
+}
+
+/// Polls until the tunnel reports a handshake made at or after `since`
+/// (Unix seconds) or `timeout` runs out.
+async fn has_handshake_within(runner: &dyn WgRunner, since: u64, timeout: Duration) -> bool {
+    let started = Instant::now();
+    loop {
+        if let Ok(stats) = runner.get_stats().await {
+            if stats.latest_handshake != 0 && stats.latest_handshake >= since {
+                return true;
+            }
+        }
+        let elapsed = started.elapsed();
+        if elapsed >= timeout {
+            return false;
+        }
+        tokio::time::sleep(HANDSHAKE_POLL.min(timeout - elapsed)).await;
+    }
+}
+
+/// Automatic's last resort for networks that drop all UDP. When a tunnel it
+/// brought up over UDP gets no handshake within `timeout` but the relay
+/// answers on TCP 443, the tunnel is rebuilt on the TCP obfuscator. Returns
+/// the new port, or `None` if the tunnel was left as it was.
+async fn fall_back_to_tcp(
+    runner: &dyn WgRunner,
+    entry: &WireGuardConfig,
+    exit: Option<&WireGuardConfig>,
+    settings: &SettingsState,
+    port: StealthPort,
+    timeout: Duration,
+) -> Result<Option<StealthPort>, VpnError> {
+    if settings.stealth_mode != StealthMode::Automatic || port.transport != Transport::Udp {
+        return Ok(None);
+    }
+    if has_handshake_within(runner, 0, timeout).await {
+        return Ok(None);
+    }
+    let tcp_open = runner
+        .tcp_reachable(&entry.endpoint, STEALTH_HTTPS_PORT)
+        .await;
+    if !tcp_open {
+        warn!("No handshake over UDP and TCP 443 is unreachable; keeping the tunnel");
+        return Ok(None);
+    }
+
+    warn!("No handshake over UDP but TCP 443 is open; switching to UDP-over-TCP");
+    runner.down().await?;
+    let tcp = SettingsState {
+        stealth_mode: StealthMode::Tcp,
+        ..settings.clone()
+    };
+    runner.up(entry, exit, &tcp).await.map(Some)
+}
+
+/// Whether WireGuard interface `iface` exists.
+#[cfg_attr(not(any(target_os = "linux", target_os = "windows")), allow(dead_code))]
+async fn interface_up(iface: &str) -> bool {
+    Command::new("wg")
+        .args(["show", iface])
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .await
+        .is_ok_and(|status| status.success())
+}
+
+/// Gives the running `iface` the key and peers in `conf`, keeping its
+/// addresses, routes and DNS. `wg setconf` drops the old peer with its
+/// session, so the next packet starts a handshake at the new endpoint.
+/// `stripped` holds the keys-only copy `wg` reads and is removed after.
+#[cfg_attr(not(any(target_os = "linux", target_os = "windows")), allow(dead_code))]
+async fn set_conf_in_place(
+    iface: &str,
+    stripped: &std::path::Path,
+    conf: &str,
+) -> Result<(), VpnError> {
+    let mut options = fs::OpenOptions::new();
+    options.create(true).write(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let write = async {
+        use tokio::io::AsyncWriteExt;
+        let mut file = options.open(stripped).await?;
+        file.write_all(wg_conf::strip(conf).as_bytes()).await
+    };
+    write
+        .await
+        .map_err(|e| VpnError::InterfaceError(e.to_string()))?;
+
+    let output = Command::new("wg")
+        .arg("setconf")
+        .arg(iface)
+        .arg(stripped)
+        .output()
+        .await;
+    let _ = fs::remove_file(stripped).await;
+    let output = output.map_err(|_| VpnError::DriverMissing)?;
+    if !output.status.success() {
+        let err = String::from_utf8_lossy(&output.stderr);
+        return Err(VpnError::InterfaceError(err.trim().to_string()));
+    }
+    Ok(())
+}
+
+/// Obfuscation always wraps the outer (entry) hop. A multihop pair that points
+/// at the same relay would route the exit tunnel back into itself, so it is
+/// refused up front instead of failing somewhere inside the obfuscator.
+fn check_multihop_stealth(
+    entry: &WireGuardConfig,
+    exit: Option<&WireGuardConfig>,
+) -> Result<(), VpnError> {
+    let Some(exit) = exit else {
+        return Ok(());
+    };
+    let (entry_host, _) = parse_endpoint_host_port(&entry.endpoint);
+    let (exit_host, _) = parse_endpoint_host_port(&exit.endpoint);
+    if entry_host.eq_ignore_ascii_case(&exit_host) || entry.public_key == exit.public_key {
+        return Err(VpnError::ConnectionFailed(
+            "Multi-hop entry and exit must be different servers".to_string(),
+        ));
+    }
+    if is_loopback_host(&exit_host) {
+        return Err(VpnError::ConnectionFailed(
+            "Multi-hop exit cannot be a local endpoint".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn is_loopback_host(host: &str) -> bool {
+    host.eq_ignore_ascii_case("localhost")
+        || host
+            .parse::<std::net::IpAddr>()
+            .map(|ip| ip.is_loopback())
+            .unwrap_or(false)
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
 
 fn parse_endpoint_host_port(endpoint: &str) -> (String, u16) {
     let trimmed = endpoint.trim();
@@ -133,6 +985,38 @@ fn parse_endpoint_host_port(endpoint: &str) -> (String, u16) {
     (trimmed.to_string(), DEFAULT_WIREGUARD_PORT)
 }
 
+/// Listens for logind's `PrepareForSleep(false)` signal, which fires as soon as
+/// the machine wakes up. The returned child is killed when dropped.
+#[cfg(target_os = "linux")]
+fn spawn_logind_resume_listener(
+    resume_tx: tokio::sync::mpsc::Sender<()>,
+) -> Option<tokio::process::Child> {
+    use tokio::io::AsyncBufReadExt;
+
+    let mut child = Command::new("dbus-monitor")
+        .args([
+            "--system",
+            "type='signal',interface='org.freedesktop.login1.Manager',member='PrepareForSleep'",
+        ])
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .ok()?;
+
+    let stdout = child.stdout.take()?;
+    tokio::spawn(async move {
+        let mut lines = tokio::io::BufReader::new(stdout).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            if line.trim() == "boolean false" && resume_tx.send(()).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    Some(child)
+}
+
 #[derive(Clone)]
 struct ConnectionContext {
     entry_name: String,
@@ -149,6 +1033,9 @@ pub struct WireGuardService {
     current_status: Arc<Mutex<ConnectionStatus>>,
     runner: Arc<Box<dyn WgRunner>>,
     active_context: Arc<Mutex<Option<ConnectionContext>>>,
+    /// Set on the [`WireGuardService::self_test`] service, which must never
+    /// touch the network.
+    self_test: bool,
 }
 
 impl WireGuardService {
@@ -167,12 +1054,33 @@ impl WireGuardService {
             current_status: Arc::new(Mutex::new(ConnectionStatus::Disconnected)),
             runner: Arc::new(runner),
             active_context: Arc::new(Mutex::new(None)),
+            self_test: false,
+        }
+    }
+
+    /// A service for the local self-test: tunnels are simulated, and the
+    /// connectivity check and everything that watches a live tunnel over
+    /// the network (health checks, failover, resume, DAITA cover traffic)
+    /// are left out. It has its own state, so a self-test never disturbs
+    /// the app's real tunnel.
+    pub fn self_test() -> Self {
+        info!("Initializing VPN Service for the local self-test.");
+        let (tx, _) = broadcast::channel(100);
+        Self {
+            event_tx: tx,
+            current_status: Arc::new(Mutex::new(ConnectionStatus::Disconnected)),
+            runner: Arc::new(Box::new(SimulationRunner::new())),
+            active_context: Arc::new(Mutex::new(None)),
+            self_test: true,
         }
     }
 
     async fn set_status(&self, status: ConnectionStatus) {
         let mut lock = self.current_status.lock().await;
         *lock = status;
+        if status == ConnectionStatus::Disconnected {
+            let _ = self.event_tx.send(VpnEvent::TransportChanged(None));
+        }
         let _ = self.event_tx.send(VpnEvent::StatusChanged(status));
     }
 
@@ -184,6 +1092,9 @@ impl WireGuardService {
     }
 
     async fn check_connectivity(&self) -> Result<(), VpnError> {
+        if self.self_test {
+            return Ok(());
+        }
         let internet_check = tokio::task::spawn_blocking(|| {
             let targets = [([1, 1, 1, 1], 53), ([8, 8, 8, 8], 53)];
             for addr in targets {
@@ -210,14 +1121,23 @@ impl WireGuardService {
         let runner = self.runner.clone();
         let svc = self.clone();
 
-        if settings.daita_enabled {
-            self.start_daita_task(status_lock.clone(), self.active_context.clone());
+        // A simulated tunnel has nothing to heal and no relay to hide
+        // traffic towards.
+        let stale_limit =
+            stale_handshake_limit(settings.reconnect_policy).filter(|_| !self.self_test);
+        if !self.self_test {
+            if settings.daita_enabled {
+                self.start_daita_task(status_lock.clone(), self.active_context.clone());
+            }
+            if let Some(schedule) = health_schedule(settings.reconnect_policy) {
+                self.start_health_monitor(status_lock.clone(), schedule);
+            }
+            self.start_resume_watcher(status_lock.clone());
         }
 
-        self.start_health_monitor(status_lock.clone());
-
         tokio::spawn(async move {
             let mut interval = tokio::time::interval(std::time::Duration::from_secs(2));
+            let mut handshake_age = HandshakeAge::default();
             loop {
                 interval.tick().await;
                 if *status_lock.lock().await != ConnectionStatus::Connected {
@@ -226,32 +1146,12 @@ impl WireGuardService {
                 if let Ok(stats) = runner.get_stats().await {
                     let _ = tx.send(VpnEvent::StatsUpdated(stats.clone()));
 
-                    if stats.latest_handshake > 0 {
-                        let now = std::time::SystemTime::now()
-                            .duration_since(std::time::UNIX_EPOCH)
-                            .unwrap_or_default()
-                            .as_secs();
-                        if now.saturating_sub(stats.latest_handshake) > 180 {
-                            warn!("Handshake stale. Triggering self-healing...");
-                            let ctx_lock = svc.active_context.lock().await;
-                            if let Some(ctx) = ctx_lock.as_ref() {
-                                let entry_n = ctx.entry_name.clone();
-                                let entry_c = ctx.entry_config.clone();
-                                let exit = ctx.exit.clone();
-                                let sets = ctx.settings.clone();
-                                let auth = if let (Some(a), Some(t)) =
-                                    (&ctx.account_number, &ctx.auth_token)
-                                {
-                                    Some((a.clone(), t.clone()))
-                                } else {
-                                    None
-                                };
-                                drop(ctx_lock);
-
-                                svc.disconnect().await;
-                                svc.connect(entry_n, entry_c, exit, sets, auth).await;
-                                break;
-                            }
+                    let age =
+                        handshake_age.observe(stats.latest_handshake, unix_now(), Instant::now());
+                    if age.zip(stale_limit).is_some_and(|(age, limit)| age > limit) {
+                        warn!("Handshake stale. Triggering self-healing...");
+                        if svc.reconnect_active(RecoveryCause::StaleHandshake).await {
+                            break;
                         }
                     }
                 }
@@ -259,35 +1159,192 @@ impl WireGuardService {
         });
     }
 
-    fn start_daita_task(
+    /// Runs [`fall_back_to_tcp`] on a fresh tunnel. A switch is remembered
+    /// for `network` both here, so reconnects skip UDP, and in the UI, which
+    /// persists it.
+    async fn fall_back_if_udp_blocked(
         &self,
-        status_lock: Arc<Mutex<ConnectionStatus>>,
-        context_lock: Arc<Mutex<Option<ConnectionContext>>>,
-    ) {
+        entry: &WireGuardConfig,
+        exit: Option<&WireGuardConfig>,
+        settings: &SettingsState,
+        port: StealthPort,
+        network: Option<String>,
+    ) -> Result<StealthPort, VpnError> {
+        let runner = &**self.runner;
+        match fall_back_to_tcp(runner, entry, exit, settings, port, UDP_HANDSHAKE_TIMEOUT).await? {
+            Some(tcp) => {
+                if let Some(id) = network.as_deref() {
+                    if let Some(ctx) = self.active_context.lock().await.as_mut() {
+                        ctx.settings.remember_udp_blocked(id);
+                    }
+                }
+                let _ = self.event_tx.send(VpnEvent::UdpBlocked(network));
+                Ok(tcp)
+            }
+            None => Ok(port),
+        }
+    }
+
+    /// Tears the tunnel down and brings it back up with the stored context.
+    /// Returns `false` when there is no active context to reconnect with.
+    async fn reconnect_active(&self, cause: RecoveryCause) -> bool {
+        let ctx = match self.active_context.lock().await.clone() {
+            Some(ctx) => ctx,
+            None => return false,
+        };
+        let _ = self.event_tx.send(VpnEvent::Recovering(cause));
+        let auth = if let (Some(a), Some(t)) = (ctx.account_number, ctx.auth_token) {
+            Some((a, t))
+        } else {
+            None
+        };
+
+        self.disconnect().await;
+        self.connect(
+            ctx.entry_name,
+            ctx.entry_config,
+            ctx.exit,
+            ctx.settings,
+            auth,
+        )
+        .await;
+        self.finish_recovery(cause, None).await;
+        true
+    }
+
+    /// Reports a self-healing reconnect as done if the tunnel came back up.
+    /// When it did not, `connect` has already reported why.
+    async fn finish_recovery(&self, cause: RecoveryCause, new_server: Option<String>) {
+        if self.get_status().await == ConnectionStatus::Connected {
+            info!("Self-healing reconnect succeeded.");
+            let _ = self
+                .event_tx
+                .send(VpnEvent::Recovered { cause, new_server });
+        }
+    }
+
+    fn start_resume_watcher(&self, status_lock: Arc<Mutex<ConnectionStatus>>) {
+        let svc = self.clone();
+
         tokio::spawn(async move {
-            info!("DAITA: Defense Against AI-guided Traffic Analysis ACTIVE.");
-            info!("DAITA: Using multi-modal traffic masking (Browsing, Streaming, VOIP mimics).");
+            let (resume_tx, mut resume_rx) = tokio::sync::mpsc::channel::<()>(4);
+            #[cfg(target_os = "linux")]
+            let _logind = spawn_logind_resume_listener(resume_tx.clone());
+            #[cfg(not(target_os = "linux"))]
+            drop(resume_tx);
 
-            let fallback_targets = ["1.1.1.1:53", "8.8.8.8:53", "9.9.9.9:53"];
-            let socket = tokio::net::UdpSocket::bind("0.0.0.0:0").await.ok();
+            let poll = Duration::from_secs(RESUME_POLL_SECS);
+            let mut interval = tokio::time::interval(poll);
+            let mut last_tick = std::time::SystemTime::now();
 
             loop {
-                let (is_connected, endpoint) = {
-                    let status = *status_lock.lock().await;
-                    let ctx = context_lock.lock().await;
-                    let ep = ctx.as_ref().map(|c| c.entry_config.endpoint.clone());
-                    (status == ConnectionStatus::Connected, ep)
+                let resumed = tokio::select! {
+                    _ = interval.tick() => {
+                        let now = std::time::SystemTime::now();
+                        let observed = now.duration_since(last_tick).unwrap_or_default();
+                        last_tick = now;
+                        is_resume_gap(poll, observed)
+                    }
+                    Some(_) = resume_rx.recv() => true,
                 };
 
-                if !is_connected {
+                if *status_lock.lock().await != ConnectionStatus::Connected {
                     break;
                 }
+                if !resumed {
+                    continue;
+                }
+
+                info!("System resume detected. Re-validating tunnel...");
+                if svc.handle_resume().await {
+                    break;
+                }
+            }
+        });
+    }
+
+    /// Returns `true` when the tunnel was rebuilt and this watcher should exit.
+    async fn handle_resume(&self) -> bool {
+        let settings = match self.active_context.lock().await.as_ref() {
+            Some(ctx) => ctx.settings.clone(),
+            None => return false,
+        };
+
+        if settings.lockdown_mode {
+            // Some platforms flush firewall state across sleep; re-assert the
+            // fail-closed rules before touching the tunnel.
+            let (endpoint, rules) = self.lockdown_rules(&settings).await;
+            if let Err(e) = self.runner.enable_kill_switch(&endpoint, &rules).await {
+                warn!("Failed to re-apply lockdown after resume: {}", e);
+            }
+        }
+
+        let handshake = self
+            .runner
+            .get_stats()
+            .await
+            .ok()
+            .map(|s| s.latest_handshake);
+        match resume_action(settings.reconnect_policy, handshake, unix_now()) {
+            ResumeAction::KeepTunnel => {
+                info!("Tunnel survived resume; handshake is fresh.");
+                false
+            }
+            ResumeAction::Reconnect => {
+                warn!("Tunnel handshake stale after resume. Reconnecting...");
+                self.reconnect_active(RecoveryCause::Resume).await
+            }
+        }
+    }
+
+    /// The endpoint and settings to re-assert the kill switch with. Enabling
+    /// it replaces every rule it added before, so while a tunnel is up its
+    /// entry relay has to stay allowed, on the port of the transport the
+    /// tunnel actually runs on. Without one, nothing is let out.
+    async fn lockdown_rules(&self, settings: &SettingsState) -> (String, SettingsState) {
+        let mut rules = settings.clone();
+        if self.get_status().await == ConnectionStatus::Disconnected {
+            return ("0.0.0.0".to_string(), rules);
+        }
+        match self.active_context.lock().await.as_ref() {
+            Some(ctx) => {
+                rules.stealth_mode = ctx.settings.stealth_mode;
+                let endpoint = kill_switch_endpoint(&ctx.entry_config, rules.stealth_mode);
+                (endpoint, rules)
+            }
+            None => ("0.0.0.0".to_string(), rules),
+        }
+    }
+
+    fn start_daita_task(
+        &self,
+        status_lock: Arc<Mutex<ConnectionStatus>>,
+        context_lock: Arc<Mutex<Option<ConnectionContext>>>,
+    ) {
+        tokio::spawn(async move {
+            info!("DAITA: Defense Against AI-guided Traffic Analysis ACTIVE.");
+            info!("DAITA: Using multi-modal traffic masking (Browsing, Streaming, VOIP mimics).");
+
+            let fallback_targets = ["1.1.1.1:53", "8.8.8.8:53", "9.9.9.9:53"];
+            let socket = tokio::net::UdpSocket::bind("0.0.0.0:0").await.ok();
+
+            loop {
+                let (is_connected, endpoint) = {
+                    let status = *status_lock.lock().await;
+                    let ctx = context_lock.lock().await;
+                    let ep = ctx.as_ref().map(|c| c.entry_config.endpoint.clone());
+                    (status == ConnectionStatus::Connected, ep)
+                };
+
+                if !is_connected {
+                    break;
+                }
+
+                let target = endpoint.unwrap_or_else(|| {
+                    let mut rng = rand::thread_rng();
+                    fallback_targets[rng.gen_range(0..fallback_targets.len())].to_string()
+                });
 
-                let target = endpoint.unwrap_or_else(|| {
-                    let mut rng = rand::thread_rng();
-                    fallback_targets[rng.gen_range(0..fallback_targets.len())].to_string()
-                });
-
                 let (burst_count, base_delay, packet_size_range, jitter_range, mode) = {
                     let mut rng = rand::thread_rng();
                     let mode = rng.gen_range(0..100);
@@ -353,12 +1410,16 @@ impl WireGuardService {
             }
         });
     }
-    fn start_health_monitor(&self, status_lock: Arc<Mutex<ConnectionStatus>>) {
+    fn start_health_monitor(
+        &self,
+        status_lock: Arc<Mutex<ConnectionStatus>>,
+        schedule: HealthSchedule,
+    ) {
         let svc = self.clone();
         let tx = self.event_tx.clone();
 
         tokio::spawn(async move {
-            let mut interval = tokio::time::interval(Duration::from_secs(30));
+            let mut interval = tokio::time::interval(schedule.interval);
             let mut failure_count = 0;
 
             loop {
@@ -388,16 +1449,15 @@ impl WireGuardService {
 
                 if !health_check {
                     failure_count += 1;
-                    warn!("Tunnel health check failed ({}/3)", failure_count);
+                    warn!(
+                        "Tunnel health check failed ({}/{})",
+                        failure_count, schedule.failures_before_failover
+                    );
 
-                    if failure_count >= 3 {
+                    if failure_count >= schedule.failures_before_failover {
                         error!(
                             "Tunnel detected as 'Silent Dead'. Triggering emergency failover..."
                         );
-                        let _ = tx.send(VpnEvent::Error(VpnError::ConnectionFailed(
-                            "Silent network failure detected. Switching servers...".to_string(),
-                        )));
-
                         let ctx_lock = svc.active_context.lock().await;
                         if let Some(ctx) = ctx_lock.as_ref() {
                             let (en, ec, ex, st) = (
@@ -415,43 +1475,16 @@ impl WireGuardService {
                             };
                             drop(ctx_lock);
 
+                            let _ = tx.send(VpnEvent::Recovering(RecoveryCause::HealthCheck));
                             svc.disconnect().await;
                             tokio::time::sleep(Duration::from_secs(3)).await;
 
-                            if st.entry_location == "Automatic" {
-                                info!("Failover: Re-scanning for best available server...");
-                                if let Ok(new_server) =
-                                    crate::services::servers::ServersService::find_best_server(None)
-                                        .await
-                                {
-                                    info!("Failover: Found new candidate {}. Fetching fresh configuration...", new_server.city);
-
-                                    let mut final_config = ec;
-                                    if let Some((ref a, ref t)) = auth {
-                                        let location =
-                                            format!("{}, {}", new_server.country, new_server.city);
-                                        if let Ok(cfg) =
-                                            crate::services::auth::AuthService::get_config(
-                                                a,
-                                                &location,
-                                                t,
-                                                Some(st.dns_blocking.clone()),
-                                                st.quantum_resistant,
-                                            )
-                                            .await
-                                        {
-                                            final_config = cfg;
-                                        }
-                                    }
-
-                                    svc.connect(new_server.city, final_config, ex, st, auth)
-                                        .await;
-                                } else {
-                                    svc.connect(en, ec, ex, st, auth).await;
-                                }
-                            } else {
-                                svc.connect(en, ec, ex, st, auth).await;
-                            }
+                            let (name, config) =
+                                failover_target(&st, en.clone(), ec, auth.as_ref()).await;
+                            let switched_to = (name != en).then(|| name.clone());
+                            svc.connect(name, config, ex, st, auth).await;
+                            svc.finish_recovery(RecoveryCause::HealthCheck, switched_to)
+                                .await;
                         }
                         break;
                     }
@@ -478,7 +1511,7 @@ impl VpnService for WireGuardService {
         entry: String,
         entry_config: WireGuardConfig,
         exit: Option<(String, WireGuardConfig)>,
-        settings: SettingsState,
+        mut settings: SettingsState,
         auth: Option<(String, String)>,
     ) {
         {
@@ -488,6 +1521,10 @@ impl VpnService for WireGuardService {
             }
         }
 
+        // Read before the tunnel touches any routes.
+        let network = network_id::current();
+        settings.stealth_mode = settings.stealth_mode_on(network.as_deref());
+
         {
             let mut lock = self.active_context.lock().await;
             let (account_number, auth_token) = if let Some((a, t)) = auth {
@@ -519,32 +1556,46 @@ impl VpnService for WireGuardService {
             .send(VpnEvent::LocationChanged(display_location.clone()));
         info!("Initiating WireGuard connection: {}", display_location);
 
-        if let Err(e) = self.check_connectivity().await {
-            self.emit_error(e).await;
-            return;
-        }
-
-        let endpoint = exit
-            .as_ref()
-            .map(|(_, c)| &c.endpoint)
-            .unwrap_or(&entry_config.endpoint);
-        if let Err(e) = self.runner.enable_kill_switch(endpoint, &settings).await {
+        let checks = timed(Phase::Checks, async {
+            self.check_connectivity().await?;
+            self.runner.probe_mtu(&entry_config, &settings).await;
+            // The kill switch guards the physical interface, which only ever
+            // sees the entry hop (multihop nests the exit inside it).
+            self.runner
+                .enable_kill_switch(
+                    &kill_switch_endpoint(&entry_config, settings.stealth_mode),
+                    &settings,
+                )
+                .await
+        })
+        .await;
+        if let Err(e) = checks {
             self.emit_error(e).await;
             return;
         }
 
-        match self
-            .runner
-            .up(&entry_config, exit.as_ref().map(|(_, c)| c), &settings)
-            .await
-        {
-            Ok(_) => {
-                info!("Tunnel established successfully.");
+        let exit_config = exit.as_ref().map(|(_, c)| c);
+        let result = timed(Phase::Tunnel, async {
+            let port = self
+                .runner
+                .up(&entry_config, exit_config, &settings)
+                .await?;
+            self.fall_back_if_udp_blocked(&entry_config, exit_config, &settings, port, network)
+                .await
+        })
+        .await;
+        match result {
+            Ok(port) => {
+                info!("Tunnel established successfully via {}.", port);
+                let _ = self.event_tx.send(VpnEvent::TransportChanged(Some(port)));
                 self.set_status(ConnectionStatus::Connected).await;
                 self.start_stats_loop(settings);
             }
             Err(e) => {
                 error!("Failed to establish tunnel: {}", e);
+                // An obfuscator may already be running for a tunnel that
+                // never came up.
+                let _ = self.runner.down().await;
                 if !settings.lockdown_mode {
                     warn!("Cleaning up kill-switch after failed connection...");
                     self.runner.disable_kill_switch().await;
@@ -621,7 +1672,8 @@ impl VpnService for WireGuardService {
     async fn apply_lockdown(&self, settings: &SettingsState) -> Result<(), VpnError> {
         if settings.lockdown_mode {
             info!("Lockdown Mode enabled: enforcing persistent fail-closed firewall.");
-            self.runner.enable_kill_switch("0.0.0.0", settings).await?;
+            let (endpoint, settings) = self.lockdown_rules(settings).await;
+            self.runner.enable_kill_switch(&endpoint, &settings).await?;
         } else {
             let status = self.get_status().await;
             if status == ConnectionStatus::Disconnected {
@@ -639,6 +1691,165 @@ impl VpnService for WireGuardService {
     async fn disable_kill_switch(&self) {
         self.runner.disable_kill_switch().await;
     }
+
+    /// The status goes to `Connecting` and back to `Connected` once the
+    /// relay answers over the new transport. The session's background loops
+    /// keep running, since the tunnel itself stays up.
+    async fn switch_obfuscation(&self, mode: StealthMode) -> Result<(), VpnError> {
+        if self.get_status().await != ConnectionStatus::Connected {
+            return Ok(());
+        }
+        let mut context = self.active_context.lock().await;
+        let Some(ctx) = context.as_mut() else {
+            return Ok(());
+        };
+        let mut settings = SettingsState {
+            stealth_mode: mode,
+            ..ctx.settings.clone()
+        };
+        settings.stealth_mode = settings.stealth_mode_on(network_id::current().as_deref());
+        if settings.stealth_mode == ctx.settings.stealth_mode {
+            return Ok(());
+        }
+
+        info!(
+            "Switching obfuscation from {:?} to {:?}",
+            ctx.settings.stealth_mode, settings.stealth_mode
+        );
+        let _ = self
+            .event_tx
+            .send(VpnEvent::StatusChanged(ConnectionStatus::Connecting));
+        let since = unix_now();
+        let switched = async {
+            // Enabling replaces the old mode's allow rules, which cuts the
+            // old transport off from here on. Nothing leaks meanwhile, since
+            // everything else stays blocked, and the new mode's ports are
+            // open before its obfuscator sends the first packet.
+            self.runner
+                .enable_kill_switch(
+                    &kill_switch_endpoint(&ctx.entry_config, settings.stealth_mode),
+                    &settings,
+                )
+                .await?;
+            let exit = ctx.exit.as_ref().map(|(_, c)| c);
+            self.runner
+                .switch_stealth(&ctx.entry_config, exit, &settings)
+                .await
+        }
+        .await;
+        let port = match switched {
+            Ok(port) => port,
+            Err(e) => {
+                error!("Failed to switch obfuscation: {}", e);
+                *context = None;
+                drop(context);
+                let _ = self.runner.down().await;
+                if !settings.lockdown_mode {
+                    self.runner.disable_kill_switch().await;
+                }
+                self.emit_error(e.clone()).await;
+                return Err(e);
+            }
+        };
+        ctx.settings = settings;
+        drop(context);
+
+        if !has_handshake_within(&**self.runner, since, SWITCH_HANDSHAKE_TIMEOUT).await {
+            warn!("No handshake over {} yet after switching obfuscation", port);
+        }
+        info!("Obfuscation switched; tunnel now leaves via {}.", port);
+        let _ = self.event_tx.send(VpnEvent::TransportChanged(Some(port)));
+        let _ = self
+            .event_tx
+            .send(VpnEvent::StatusChanged(ConnectionStatus::Connected));
+        Ok(())
+    }
+
+    /// Ranges stay as the tunnel was connected with, since they are part of
+    /// its allowed IPs. For the same reason, an include-only list going from
+    /// empty to not (or back) waits for the next connect.
+    async fn update_split_apps(&self, settings: &SettingsState) {
+        if self.get_status().await != ConnectionStatus::Connected {
+            return;
+        }
+        let mut context = self.active_context.lock().await;
+        let Some(ctx) = context.as_mut() else {
+            return;
+        };
+        let updated = SettingsState {
+            split_tunneling: settings.split_tunneling,
+            split_mode: settings.split_mode,
+            excluded_apps: settings.excluded_apps.clone(),
+            included_apps: settings.included_apps.clone(),
+            ..ctx.settings.clone()
+        };
+        if updated.split_tunneling == ctx.settings.split_tunneling
+            && updated.split_mode == ctx.settings.split_mode
+            && updated.split_apps() == ctx.settings.split_apps()
+        {
+            return;
+        }
+        if updated.tunnels_only_listed_apps() != ctx.settings.tunnels_only_listed_apps() {
+            info!("Split tunneling app change applies on the next connect.");
+            return;
+        }
+
+        let tunnel = ctx.exit.as_ref().map_or(&ctx.entry_config, |(_, c)| c);
+        self.runner.apply_app_bypass(tunnel, &updated).await;
+        info!(
+            "Split tunneling now covers {} app(s).",
+            updated.split_apps().len()
+        );
+        ctx.settings = updated;
+    }
+}
+
+/// Windows profile policy while the kill switch is on.
+const LOCKDOWN_FIREWALL_POLICY: &str = "blockoutbound,allowinbound";
+/// What Windows ships with.
+const DEFAULT_FIREWALL_POLICY: &str = "blockinbound,allowoutbound";
+
+/// The policy to put back when the kill switch comes off. A lockdown kept
+/// across a restart is already in force when the new process snapshots the
+/// policy, and restoring that snapshot would leave the machine blocked.
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+fn restorable_firewall_policy(current: String) -> String {
+    if current == LOCKDOWN_FIREWALL_POLICY {
+        DEFAULT_FIREWALL_POLICY.to_string()
+    } else {
+        current
+    }
+}
+
+/// Runs `teardown`, then `cleanup` even if `teardown` panicked, so a broken
+/// interface or obfuscator cannot leave the system on MarinVPN's DNS with no
+/// tunnel behind it. A panic is raised again once `cleanup` is done.
+async fn with_cleanup(teardown: impl Future<Output = ()>, cleanup: impl Future<Output = ()>) {
+    let result = AssertUnwindSafe(teardown).catch_unwind().await;
+    cleanup.await;
+    if let Err(panic) = result {
+        std::panic::resume_unwind(panic);
+    }
+}
+
+/// Whether quitting should leave the fail-closed firewall in place. Only
+/// lockdown mode blocks traffic while disconnected, so the opt-in means
+/// nothing without it.
+pub fn keeps_firewall_on_exit(settings: &SettingsState) -> bool {
+    settings.lockdown_mode && settings.keep_lockdown_after_exit
+}
+
+/// Brings the tunnel down before the app exits and removes the firewall
+/// unless the user asked for it to outlive the app. A relaunch re-applies
+/// lockdown from the saved settings, so the rules left behind are picked up
+/// again rather than duplicated.
+pub async fn shut_down(service: &dyn VpnService, settings: &SettingsState) {
+    service.disconnect().await;
+    if keeps_firewall_on_exit(settings) {
+        info!("Quitting with lockdown kept: the firewall stays until the app removes it.");
+    } else {
+        service.disable_kill_switch().await;
+    }
 }
 
 impl Default for WireGuardService {
@@ -647,6 +1858,71 @@ impl Default for WireGuardService {
     }
 }
 
+/// Type-erased VPN backend handed to the UI, so the app can run against
+/// something other than the real WireGuard service (a mock in UI tests).
+#[derive(Clone)]
+pub struct SharedVpnService(Arc<dyn VpnService>);
+
+impl SharedVpnService {
+    pub fn new(service: impl VpnService + 'static) -> Self {
+        Self(Arc::new(service))
+    }
+}
+
+impl PartialEq for SharedVpnService {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+#[async_trait::async_trait]
+impl VpnService for SharedVpnService {
+    fn subscribe(&self) -> broadcast::Receiver<VpnEvent> {
+        self.0.subscribe()
+    }
+
+    async fn connect(
+        &self,
+        entry: String,
+        entry_config: WireGuardConfig,
+        exit: Option<(String, WireGuardConfig)>,
+        settings: SettingsState,
+        auth: Option<(String, String)>,
+    ) {
+        self.0
+            .connect(entry, entry_config, exit, settings, auth)
+            .await
+    }
+
+    async fn disconnect(&self) {
+        self.0.disconnect().await
+    }
+
+    async fn get_status(&self) -> ConnectionStatus {
+        self.0.get_status().await
+    }
+
+    async fn enable_captive_portal(&self, duration_secs: u64) {
+        self.0.enable_captive_portal(duration_secs).await
+    }
+
+    async fn apply_lockdown(&self, settings: &SettingsState) -> Result<(), VpnError> {
+        self.0.apply_lockdown(settings).await
+    }
+
+    async fn disable_kill_switch(&self) {
+        self.0.disable_kill_switch().await
+    }
+
+    async fn switch_obfuscation(&self, mode: StealthMode) -> Result<(), VpnError> {
+        self.0.switch_obfuscation(mode).await
+    }
+
+    async fn update_split_apps(&self, settings: &SettingsState) {
+        self.0.update_split_apps(settings).await
+    }
+}
+
 struct SimulationRunner {
     state: Mutex<SimulationState>,
 }
@@ -671,15 +1947,16 @@ impl SimulationRunner {
 impl WgRunner for SimulationRunner {
     async fn up(
         &self,
-        _entry: &WireGuardConfig,
+        entry: &WireGuardConfig,
         exit: Option<&WireGuardConfig>,
-        _settings: &SettingsState,
-    ) -> Result<(), VpnError> {
+        settings: &SettingsState,
+    ) -> Result<StealthPort, VpnError> {
         tokio::time::sleep(Duration::from_millis(800)).await;
         if exit.is_some() {
             tokio::time::sleep(Duration::from_millis(1000)).await;
         }
-        Ok(())
+        let mode = settings.stealth_mode;
+        Ok(stealth_ports(mode, relay_port(entry, mode))[0])
     }
 
     async fn down(&self) -> Result<(), VpnError> {
@@ -687,6 +1964,17 @@ impl WgRunner for SimulationRunner {
         Ok(())
     }
 
+    async fn switch_stealth(
+        &self,
+        entry: &WireGuardConfig,
+        _exit: Option<&WireGuardConfig>,
+        settings: &SettingsState,
+    ) -> Result<StealthPort, VpnError> {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let mode = settings.stealth_mode;
+        Ok(stealth_ports(mode, relay_port(entry, mode))[0])
+    }
+
     async fn get_stats(&self) -> Result<VpnStats, VpnError> {
         let (dl_speed, ul_speed) = {
             let mut rng = rand::thread_rng();
@@ -704,12 +1992,12 @@ impl WgRunner for SimulationRunner {
             upload_speed: ul_speed,
             total_download: state.total_download,
             total_upload: state.total_upload,
-            latest_handshake: 0,
+            latest_handshake: unix_now(),
         })
     }
 
-    async fn apply_app_bypass(&self, _app_path: &str) {}
     async fn apply_bypass_route(&self, _ip: &str) {}
+    async fn apply_app_bypass(&self, _tunnel: &WireGuardConfig, _settings: &SettingsState) {}
     async fn apply_single_up(&self, _iface: &str, _conf: &str) -> Result<(), VpnError> {
         Ok(())
     }
@@ -722,20 +2010,37 @@ impl WgRunner for SimulationRunner {
         Ok(())
     }
     async fn disable_kill_switch(&self) {}
+    async fn probe_mtu(&self, _entry: &WireGuardConfig, _settings: &SettingsState) {}
+    async fn tcp_reachable(&self, _endpoint: &str, _port: u16) -> bool {
+        true
+    }
 }
 
 struct RunnerState {
     last_stats: Option<VpnStats>,
     last_check: Option<Instant>,
     bypass_routes: Vec<String>,
+    /// Tunnel MTU found by probing, or the fallback when probing failed,
+    /// per relay endpoint.
+    probed_mtu: HashMap<String, u32>,
+    /// Whether kill switch rules are in place, which stops the MTU probe.
+    kill_switch_on: bool,
     #[cfg(target_os = "linux")]
     original_resolv_conf: Option<String>,
+    /// The interface resolvectl was given the tunnel DNS for.
+    #[cfg(target_os = "linux")]
+    systemd_dns_iface: Option<String>,
+    /// The local network's link and the domains it had before local DNS
+    /// routes were added to it.
     #[cfg(target_os = "linux")]
-    systemd_dns_applied: bool,
+    local_dns_link: Option<(String, Vec<String>)>,
     #[cfg(target_os = "windows")]
     original_firewall_policy: Option<String>,
     #[cfg(target_os = "windows")]
     original_dns_snapshot: Option<Vec<DnsSnapshot>>,
+    /// The global DNS suffix list before search domains were added to it.
+    #[cfg(target_os = "windows")]
+    original_suffix_search_list: Option<Vec<String>>,
 }
 
 #[cfg(target_os = "windows")]
@@ -747,7 +2052,7 @@ struct DnsSnapshot {
 
 #[async_trait::async_trait]
 trait Obfuscator: Send + Sync {
-    async fn start(&self, remote_endpoint: &str, key: Option<&str>) -> Result<String, VpnError>;
+    async fn start(&self, entry: &WireGuardConfig) -> Result<String, VpnError>;
     async fn stop(&self) -> Result<(), VpnError>;
 }
 
@@ -765,7 +2070,8 @@ impl WsObfuscator {
 
 #[async_trait::async_trait]
 impl Obfuscator for WsObfuscator {
-    async fn start(&self, remote_endpoint: &str, _key: Option<&str>) -> Result<String, VpnError> {
+    async fn start(&self, entry: &WireGuardConfig) -> Result<String, VpnError> {
+        let remote_endpoint = entry.endpoint.as_str();
         info!(
             "Starting WSTunnel (WebSocket) obfuscation for {}",
             remote_endpoint
@@ -780,7 +2086,7 @@ impl Obfuscator for WsObfuscator {
                 "-l",
                 &format!("udp://127.0.0.1:{}", local_port),
                 "-r",
-                &format!("wss://{}:443", remote_host),
+                &format!("wss://{}:{}", remote_host, STEALTH_HTTPS_PORT),
                 "--udp",
                 "--udp-timeout",
                 "60",
@@ -827,7 +2133,9 @@ impl SsObfuscator {
 
 #[async_trait::async_trait]
 impl Obfuscator for SsObfuscator {
-    async fn start(&self, remote_endpoint: &str, key: Option<&str>) -> Result<String, VpnError> {
+    async fn start(&self, entry: &WireGuardConfig) -> Result<String, VpnError> {
+        let remote_endpoint = entry.endpoint.as_str();
+        let key = entry.obfuscation_key.as_deref();
         info!(
             "Starting Shadowsocks (AEAD) obfuscation for {}",
             remote_endpoint
@@ -835,24 +2143,26 @@ impl Obfuscator for SsObfuscator {
 
         let local_port = 51821;
         let remote_host = remote_endpoint.split(':').next().unwrap_or(remote_endpoint);
-        let remote_port = remote_endpoint.split(':').nth(1).unwrap_or("8388");
+        let remote_port = relay_port(entry, StealthMode::Shadowsocks)
+            .unwrap_or(SHADOWSOCKS_DEFAULT_PORT)
+            .to_string();
         let password = key.ok_or_else(|| {
-            error!("Shadowsocks requires an obfuscation key but none was provided.");
-            VpnError::ConfigMissing
+            VpnError::ConfigMissing("Shadowsocks needs an obfuscation key".to_string())
         })?;
+        let cipher = shadowsocks_cipher(entry)?;
 
         let child = Command::new("ss-local")
             .args([
                 "-s",
                 remote_host,
                 "-p",
-                remote_port,
+                &remote_port,
                 "-l",
                 &local_port.to_string(),
                 "-k",
                 password,
                 "-m",
-                "aes-256-gcm",
+                cipher,
                 "-U",
             ])
             .stdout(std::process::Stdio::null())
@@ -897,7 +2207,8 @@ impl QuicObfuscator {
 
 #[async_trait::async_trait]
 impl Obfuscator for QuicObfuscator {
-    async fn start(&self, remote_endpoint: &str, _key: Option<&str>) -> Result<String, VpnError> {
+    async fn start(&self, entry: &WireGuardConfig) -> Result<String, VpnError> {
+        let remote_endpoint = entry.endpoint.as_str();
         info!("Starting QUIC (HTTP/3) obfuscation for {}", remote_endpoint);
 
         let local_port = 51822;
@@ -909,7 +2220,7 @@ impl Obfuscator for QuicObfuscator {
                 "-l",
                 &format!("127.0.0.1:{}", local_port),
                 "-r",
-                &format!("{}:443", remote_host),
+                &format!("{}:{}", remote_host, STEALTH_HTTPS_PORT),
                 "--cert-verify=false",
             ])
             .stdout(std::process::Stdio::null())
@@ -954,7 +2265,8 @@ impl TcpObfuscator {
 
 #[async_trait::async_trait]
 impl Obfuscator for TcpObfuscator {
-    async fn start(&self, remote_endpoint: &str, _key: Option<&str>) -> Result<String, VpnError> {
+    async fn start(&self, entry: &WireGuardConfig) -> Result<String, VpnError> {
+        let remote_endpoint = entry.endpoint.as_str();
         info!(
             "Starting raw UDP-over-TCP obfuscation for {}",
             remote_endpoint
@@ -969,7 +2281,7 @@ impl Obfuscator for TcpObfuscator {
                 "-l",
                 &format!("udp://127.0.0.1:{}", local_port),
                 "-r",
-                &format!("tcp://{}:443", remote_host),
+                &format!("tcp://{}:{}", remote_host, STEALTH_HTTPS_PORT),
                 "--udp",
             ])
             .stdout(std::process::Stdio::null())
@@ -1014,7 +2326,9 @@ impl LwoObfuscator {
 
 #[async_trait::async_trait]
 impl Obfuscator for LwoObfuscator {
-    async fn start(&self, remote_endpoint: &str, key: Option<&str>) -> Result<String, VpnError> {
+    async fn start(&self, entry: &WireGuardConfig) -> Result<String, VpnError> {
+        let remote_endpoint = entry.endpoint.as_str();
+        let key = entry.obfuscation_key.as_deref();
         info!(
             "Starting LWO (Lightweight WireGuard Obfuscation) for {}",
             remote_endpoint
@@ -1124,6 +2438,9 @@ struct RealWgRunner {
     quic_obfuscator: Arc<QuicObfuscator>,
     tcp_obfuscator: Arc<TcpObfuscator>,
     lwo_obfuscator: Arc<LwoObfuscator>,
+    /// Where DNS goes when systemd-resolved is not running.
+    #[cfg(target_os = "linux")]
+    resolv_conf: std::path::PathBuf,
 }
 
 impl RealWgRunner {
@@ -1140,116 +2457,395 @@ impl RealWgRunner {
                 last_stats: None,
                 last_check: None,
                 bypass_routes: Vec::new(),
+                probed_mtu: HashMap::new(),
+                kill_switch_on: false,
                 #[cfg(target_os = "linux")]
                 original_resolv_conf: None,
                 #[cfg(target_os = "linux")]
-                systemd_dns_applied: false,
+                systemd_dns_iface: None,
+                #[cfg(target_os = "linux")]
+                local_dns_link: None,
                 #[cfg(target_os = "windows")]
                 original_firewall_policy: None,
                 #[cfg(target_os = "windows")]
                 original_dns_snapshot: None,
+                #[cfg(target_os = "windows")]
+                original_suffix_search_list: None,
             }),
             ws_obfuscator: Arc::new(WsObfuscator::new()),
             ss_obfuscator: Arc::new(SsObfuscator::new()),
             quic_obfuscator: Arc::new(QuicObfuscator::new()),
             tcp_obfuscator: Arc::new(TcpObfuscator::new()),
             lwo_obfuscator: Arc::new(LwoObfuscator::new()),
+            #[cfg(target_os = "linux")]
+            resolv_conf: "/etc/resolv.conf".into(),
         }
     }
 
-    fn create_conf(
+    /// Starts the obfuscator for `mode` in front of `entry` and returns the
+    /// local endpoint WireGuard should dial instead, if any, along with the
+    /// port the traffic leaves on. Explicitly chosen transports fail the
+    /// connection rather than silently falling back to plain UDP.
+    async fn start_stealth(
         &self,
-        config: &WireGuardConfig,
-        settings: &SettingsState,
-        mtu_override: Option<u32>,
-    ) -> String {
-        let mtu = if let Some(m) = mtu_override {
-            m
-        } else if settings.mtu == 0 || settings.mtu == 1420 {
-            1280
-        } else {
-            settings.mtu
+        mode: StealthMode,
+        entry: &WireGuardConfig,
+    ) -> Result<StealthRoute, VpnError> {
+        let remote = entry.endpoint.as_str();
+        let ports = stealth_ports(mode, relay_port(entry, mode));
+        let route = |endpoint, port| StealthRoute { endpoint, port };
+
+        let explicit = match mode {
+            StealthMode::None => return Ok(route(None, ports[0])),
+            StealthMode::WireGuardPort => {
+                info!("Stealth Mode: WireGuard on Port 53 (DNS) simulation");
+                return Ok(route(
+                    Some(endpoint_on_port(remote, STEALTH_DNS_PORT)),
+                    ports[0],
+                ));
+            }
+            StealthMode::WireGuard443 => {
+                info!("Stealth Mode: WireGuard on UDP port 443");
+                return Ok(route(
+                    Some(endpoint_on_port(remote, STEALTH_HTTPS_PORT)),
+                    ports[0],
+                ));
+            }
+            StealthMode::Automatic => {
+                info!("Stealth Mode: AUTOMATIC discovery initiated...");
+                if let Ok(ep) = self.lwo_obfuscator.start(entry).await {
+                    info!("Auto-Stealth: Selected LWO");
+                    return Ok(route(Some(ep), ports[0]));
+                }
+                if let Ok(ep) = self.quic_obfuscator.start(entry).await {
+                    info!("Auto-Stealth: Selected QUIC");
+                    return Ok(route(Some(ep), ports[1]));
+                }
+                return match self.ws_obfuscator.start(entry).await {
+                    Ok(ep) => Ok(route(Some(ep), ports[2])),
+                    Err(_) => {
+                        warn!("Auto-Stealth: All methods failed, using standard UDP");
+                        Ok(route(None, ports[0]))
+                    }
+                };
+            }
+            StealthMode::Lwo => self.lwo_obfuscator.start(entry).await,
+            StealthMode::Quic => self.quic_obfuscator.start(entry).await,
+            StealthMode::Shadowsocks => self.ss_obfuscator.start(entry).await,
+            StealthMode::Tcp => self.tcp_obfuscator.start(entry).await,
         };
 
-        let mut peer_section = format!(
-            "[Peer]\nPublicKey = {}\nEndpoint = {}\nAllowedIPs = {}\nPersistentKeepalive = 25\n",
-            config.public_key, config.endpoint, config.allowed_ips
-        );
+        explicit
+            .map(|ep| route(Some(ep), ports[0]))
+            .map_err(|e| match e {
+                VpnError::ConfigMissing(_) => e,
+                e => VpnError::ConnectionFailed(format!("{:?} obfuscation failed: {}", mode, e)),
+            })
+    }
 
-        if let Some(ref psk) = config.preshared_key {
-            peer_section.push_str(&format!("PresharedKey = {}\n", psk));
+    async fn stop_obfuscators(&self) {
+        let obfuscators: [&dyn Obfuscator; 5] = [
+            &*self.ws_obfuscator,
+            &*self.ss_obfuscator,
+            &*self.quic_obfuscator,
+            &*self.tcp_obfuscator,
+            &*self.lwo_obfuscator,
+        ];
+        for obfuscator in obfuscators {
+            if let Err(e) = obfuscator.stop().await {
+                warn!("Failed to stop obfuscator: {}", e);
+            }
         }
+    }
 
-        format!(
-            "[Interface]\nPrivateKey = {}\nAddress = {}\nMTU = {}\n{}\n\n{}\n",
-            config.private_key,
-            config.address,
-            mtu,
-            config
-                .dns
-                .as_ref()
-                .map(|d| format!("DNS = {}", d))
-                .unwrap_or_default(),
-            peer_section
-        )
+    /// MTU of the entry tunnel: the one set in the settings, or else the
+    /// one `probe_mtu` found towards the entry relay. 1280 when it has not
+    /// probed that relay.
+    async fn tunnel_mtu(&self, entry: &WireGuardConfig, settings: &SettingsState) -> u32 {
+        match fixed_mtu(settings) {
+            Some(mtu) => mtu,
+            None => self
+                .state
+                .lock()
+                .await
+                .probed_mtu
+                .get(&entry.endpoint)
+                .copied()
+                .unwrap_or(FALLBACK_TUNNEL_MTU),
+        }
     }
 
-    async fn apply_dns(&self, dns: &Option<String>, settings: &SettingsState) {
-        let dns_servers = if settings.custom_dns && !settings.custom_dns_server.is_empty() {
-            settings.custom_dns_server.clone()
-        } else {
-            dns.clone()
-                .unwrap_or_else(|| "1.1.1.1, 8.8.8.8".to_string())
+    /// The entry interface's `.conf`, dialing `stealth_endpoint` in place of
+    /// the relay when set. With an exit hop the entry tunnel only carries
+    /// traffic to the exit relay.
+    async fn entry_conf(
+        &self,
+        entry: &WireGuardConfig,
+        stealth_endpoint: Option<String>,
+        exit: Option<&WireGuardConfig>,
+        settings: &SettingsState,
+        mtu: u32,
+    ) -> Result<String, VpnError> {
+        let final_entry = with_stealth_endpoint(entry, stealth_endpoint);
+        let Some(exit_cfg) = exit else {
+            return Ok(self.create_conf(&final_entry, &entry.endpoint, settings, mtu));
         };
+        let hop_mtu = multihop_mtu(mtu)?;
 
-        #[cfg(target_os = "linux")]
-        {
-            let servers: Vec<&str> = dns_servers
-                .split(',')
-                .map(|s| s.trim())
-                .filter(|s| !s.is_empty())
-                .collect();
+        let exit_host = exit_cfg
+            .endpoint
+            .split(':')
+            .next()
+            .unwrap_or(&exit_cfg.endpoint);
 
-            let resolvectl_check = Command::new("resolvectl").arg("--version").output();
-            let mut applied_with_systemd = false;
-            if resolvectl_check.is_ok() {
-                info!("Applying DNS via resolvectl (systemd-resolved)");
-                let dns_status = Command::new("resolvectl")
-                    .arg("dns")
-                    .arg(&self.iface_entry)
-                    .args(&servers)
-                    .status();
+        let exit_ip = match tokio::net::lookup_host(format!("{}:51820", exit_host)).await {
+            Ok(mut addrs) => addrs
+                .next()
+                .map(|a| a.ip().to_string())
+                .unwrap_or_else(|| exit_host.to_string()),
+            Err(_) => exit_host.to_string(),
+        };
 
-                if dns_status.map(|s| s.success()).unwrap_or(false) {
-                    applied_with_systemd = true;
-                } else if let Err(err) = dns_status {
-                    warn!("resolvectl dns command failed: {}", err);
-                }
+        let conf = WgConfFile::new(&final_entry.private_key, &final_entry.address)
+            .mtu(hop_mtu.entry)
+            .peer(
+                PeerSection::from_config(&final_entry)
+                    .allowed_ips([final_entry.address.clone(), format!("{}/32", exit_ip)]),
+            )
+            .render();
+        Ok(conf)
+    }
 
-                if let Ok(status) = Command::new("resolvectl")
-                    .arg("domain")
-                    .arg(&self.iface_entry)
-                    .arg("~.")
-                    .status()
-                {
-                    if !status.success() {
-                        warn!(
-                            "resolvectl domain adjustment failed: status={:?}",
+    /// `relay` is the server's own endpoint, which is not `config.endpoint`
+    /// when a local obfuscator sits in between.
+    fn create_conf(
+        &self,
+        config: &WireGuardConfig,
+        relay: &str,
+        settings: &SettingsState,
+        mtu: u32,
+    ) -> String {
+        let peer = PeerSection::from_config(config)
+            .allowed_ips(tunnel_allowed_ips(config, relay, settings));
+
+        WgConfFile::new(&config.private_key, &config.address)
+            .dns(config.dns.clone())
+            .mtu(mtu)
+            .peer(peer)
+            .render()
+    }
+
+    /// Moves the split tunneling apps into their net_cls cgroup and marks
+    /// whatever should leave outside the tunnel. Only processes already
+    /// running are moved; an app started later is not.
+    #[cfg(target_os = "linux")]
+    async fn apply_app_split(&self, tunnel: &WireGuardConfig, settings: &SettingsState) {
+        let apps = settings.split_apps();
+        if !settings.split_tunneling || apps.is_empty() {
+            return;
+        }
+        if !std::path::Path::new("/sys/fs/cgroup/net_cls").exists() {
+            warn!("net_cls cgroup not available; per-app split tunneling disabled.");
+            return;
+        }
+
+        let (cgroup_dir, classid) = split_tunnel::linux_cgroup(settings.split_mode);
+        let _ = fs::create_dir_all(cgroup_dir).await;
+        let _ = fs::write(format!("{}/net_cls.classid", cgroup_dir), classid).await;
+
+        // `ip rule add` does not check for an existing rule.
+        for action in ["del", "add"] {
+            for rule in split_tunnel::linux_route_rules(action) {
+                let _ = Command::new("ip").args(&rule).status().await;
+            }
+        }
+        let routed: Vec<String> = include_only_ranges(tunnel, settings)
+            .iter()
+            .map(Cidr::to_string)
+            .collect();
+        for rule in split_tunnel::linux_mark_rules(settings.split_mode, &routed) {
+            let _ = Command::new("nft").args(&rule).status().await;
+        }
+
+        for app in apps {
+            if !std::path::Path::new(&app.path).exists() {
+                warn!(
+                    "Split tunneling app {} no longer exists at {}",
+                    app.name, app.path
+                );
+                continue;
+            }
+            info!("Applying split tunneling cgroup to {}", app.path);
+            let pid_output = Command::new("sh")
+                .args([
+                    "-c",
+                    &format!(
+                        "for p in /proc/[0-9]*/exe; do if [ \"$(readlink -f \"$p\")\" = \"{}\" ]; then echo ${{p%/exe}} | awk -F/ '{{print $3}}'; fi; done",
+                        app.path.replace('"', "\\\"")
+                    ),
+                ])
+                .output()
+                .await
+                .ok();
+            let Some(output) = pid_output else {
+                warn!("Failed to locate process for split tunneling: {}", app.path);
+                continue;
+            };
+            let pids = String::from_utf8_lossy(&output.stdout);
+            if pids.trim().is_empty() {
+                warn!("No running process found for split tunneling: {}", app.path);
+                continue;
+            }
+            for pid in pids.split_whitespace() {
+                let _ = fs::write(format!("{}/cgroup.procs", cgroup_dir), pid).await;
+            }
+        }
+    }
+
+    /// Moves every process out of the split tunneling cgroups and back to the
+    /// net_cls root, so apps dropped from the list use the tunnel again.
+    #[cfg(target_os = "linux")]
+    async fn release_app_split(&self) {
+        for mode in [SplitTunnelMode::Exclude, SplitTunnelMode::IncludeOnly] {
+            let (cgroup_dir, _) = split_tunnel::linux_cgroup(mode);
+            let Ok(procs) = fs::read_to_string(format!("{}/cgroup.procs", cgroup_dir)).await else {
+                continue;
+            };
+            for pid in procs.split_whitespace() {
+                let _ = fs::write("/sys/fs/cgroup/net_cls/cgroup.procs", pid).await;
+            }
+        }
+    }
+
+    /// Adds the firewall rules for the split tunneling apps. An app no
+    /// longer at its saved path is looked for among running processes.
+    #[cfg(target_os = "windows")]
+    async fn apply_windows_app_rules(settings: &SettingsState) {
+        let mut apps = settings.split_apps().to_vec();
+        for app in &mut apps {
+            if std::path::Path::new(&app.path).exists() {
+                continue;
+            }
+            match Self::running_app_path(app).await {
+                Some(path) => {
+                    info!("Split tunneling app {} found running at {}", app.name, path);
+                    app.path = path;
+                }
+                None => warn!(
+                    "Split tunneling app {} no longer exists at {}",
+                    app.name, app.path
+                ),
+            }
+        }
+        for script in split_tunnel::windows_app_rules(settings.split_mode, &apps) {
+            let _ = Command::new("powershell")
+                .args(["-NoProfile", "-Command", &script])
+                .status()
+                .await;
+        }
+    }
+
+    /// Where a split tunneling app whose saved path is gone runs from now,
+    /// found by its image name among running processes.
+    #[cfg(target_os = "windows")]
+    async fn running_app_path(app: &crate::models::AppInfo) -> Option<String> {
+        let image = split_tunnel::image_name(&app.path)?;
+        let script = format!(
+            "Get-Process -Name '{}' -ErrorAction SilentlyContinue | Select-Object -First 1 -ExpandProperty Path",
+            image.replace('\'', "''")
+        );
+        let output = Command::new("powershell")
+            .args(["-NoProfile", "-Command", &script])
+            .output()
+            .await
+            .ok()?;
+        let path = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        (!path.is_empty()).then_some(path)
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn clear_app_split(&self) {
+        let _ = Command::new("nft")
+            .args(["delete", "table", "inet", split_tunnel::NFT_TABLE])
+            .status()
+            .await;
+        for rule in split_tunnel::linux_route_rules("del") {
+            let _ = Command::new("ip").args(&rule).status().await;
+        }
+    }
+
+    async fn apply_dns(&self, iface: &str, dns: &Option<String>, settings: &SettingsState) {
+        let dns_servers = if settings.uses_custom_dns() {
+            settings.custom_dns_server.clone()
+        } else {
+            dns.clone()
+                .unwrap_or_else(|| "1.1.1.1, 8.8.8.8".to_string())
+        };
+
+        #[cfg(target_os = "linux")]
+        {
+            let servers: Vec<&str> = dns_servers
+                .split(',')
+                .map(|s| s.trim())
+                .filter(|s| !s.is_empty())
+                .collect();
+
+            let resolvectl_check = Command::new("resolvectl").arg("--version").output();
+            let mut applied_with_systemd = false;
+            if resolvectl_check.is_ok() {
+                info!("Applying DNS via resolvectl (systemd-resolved)");
+                let dns_status = Command::new("resolvectl")
+                    .arg("dns")
+                    .arg(iface)
+                    .args(&servers)
+                    .status();
+
+                if dns_status.map(|s| s.success()).unwrap_or(false) {
+                    applied_with_systemd = true;
+                } else if let Err(err) = dns_status {
+                    warn!("resolvectl dns command failed: {}", err);
+                }
+
+                let domains = dns_routes::tunnel_link_domains(
+                    &settings.dns_routes,
+                    settings.dns_unmatched_local,
+                );
+                if let Ok(status) = Command::new("resolvectl")
+                    .arg("domain")
+                    .arg(iface)
+                    .args(&domains)
+                    .status()
+                    .await
+                {
+                    if !status.success() {
+                        warn!(
+                            "resolvectl domain adjustment failed: status={:?}",
                             status.code()
                         );
                     }
                 }
+                if settings.dns_unmatched_local {
+                    // Without `~.` the link would still get names no other
+                    // link claims.
+                    let _ = Command::new("resolvectl")
+                        .args(["default-route", iface, "false"])
+                        .status()
+                        .await;
+                }
+                if applied_with_systemd {
+                    self.apply_local_dns_routes(&settings.dns_routes).await;
+                }
             }
 
             if applied_with_systemd {
                 let mut state = self.state.lock().await;
-                state.systemd_dns_applied = true;
+                state.systemd_dns_iface = Some(iface.to_string());
                 state.original_resolv_conf = None;
-            } else if let Ok(content) = fs::read_to_string("/etc/resolv.conf") {
+            } else if let Ok(content) = fs::read_to_string(&self.resolv_conf).await {
                 {
                     let mut state = self.state.lock().await;
                     state.original_resolv_conf = Some(content);
-                    state.systemd_dns_applied = false;
+                    state.systemd_dns_iface = None;
                 }
 
                 let mut new_conf = String::new();
@@ -1257,10 +2853,17 @@ impl RealWgRunner {
                 for s in servers {
                     new_conf.push_str(&format!("nameserver {}\n", s));
                 }
-                let _ = fs::write("/etc/resolv.conf", new_conf);
+                let search = dns_routes::search_domains(&settings.dns_routes);
+                if !search.is_empty() {
+                    new_conf.push_str(&format!("search {}\n", search.join(" ")));
+                }
+                if !settings.dns_routes.is_empty() || settings.dns_unmatched_local {
+                    warn!("Routing DNS by domain needs systemd-resolved; all names go to the tunnel DNS");
+                }
+                let _ = fs::write(&self.resolv_conf, new_conf).await;
             } else {
                 let mut state = self.state.lock().await;
-                state.systemd_dns_applied = false;
+                state.systemd_dns_iface = None;
             }
         }
 
@@ -1268,17 +2871,19 @@ impl RealWgRunner {
         {
             {
                 let mut state = self.state.lock().await;
-                                if state.original_dns_snapshot.is_none() {
-                                    state.original_dns_snapshot = Self::capture_dns_snapshot().await;
-                                }
-                            }
-                            let first_dns = dns_servers.split(',').next().unwrap_or("1.1.1.1").trim();
-            info!(
-                "Applying Windows DNS: {} to interface {}",
-                first_dns, self.iface_entry
-            );
+                if state.original_dns_snapshot.is_none() {
+                    state.original_dns_snapshot = Self::capture_dns_snapshot().await;
+                }
+            }
+            self.apply_windows_dns_routes(&dns_servers, settings).await;
+            if settings.dns_unmatched_local {
+                info!("Leaving Windows DNS on the local resolvers; only routed domains use the tunnel");
+                return;
+            }
+            let first_dns = dns_servers.split(',').next().unwrap_or("1.1.1.1").trim();
+            info!("Applying Windows DNS: {} to interface {}", first_dns, iface);
 
-            let name_arg = format!("name={}", self.iface_entry);
+            let name_arg = format!("name={}", iface);
             let _ = Command::new("netsh")
                 .args([
                     "interface",
@@ -1306,44 +2911,202 @@ impl RealWgRunner {
             }
 
             let block_leaks = format!(
-                "$iface = '{}'; \
-                Get-NetAdapter | Where-Object {{ $_.InterfaceAlias -ne $iface -and $_.InterfaceAlias -ne 'marinvpn1' }} | ForEach-Object {{ \
+                "$entry = '{}'; $exit = '{}'; \
+                Get-NetAdapter | Where-Object {{ $_.InterfaceAlias -ne $entry -and $_.InterfaceAlias -ne $exit }} | ForEach-Object {{ \
                     $alias = $_.InterfaceAlias; \
                     netsh interface ipv4 set dnsservers name=$alias source=static address=127.0.0.1 validate=no; \
                     $doh_ips = @('1.1.1.1', '1.0.0.1', '8.8.8.8', '8.8.4.4', '9.9.9.9', '149.112.112.112'); \
                     foreach ($ip in $doh_ips) {{ \
                         New-NetFirewallRule -DisplayName \"MarinVPN - Block DoH $alias $ip\" -Direction Outbound -InterfaceAlias $alias -RemoteAddress $ip -RemotePort 443 -Protocol TCP -Action Block -Profile Any -Force; \
                     }} \
-                }}", self.iface_entry.replace("'", "''"));
+                }}", self.iface_entry.replace("'", "''"), self.iface_exit.replace("'", "''"));
             let _ = Command::new("powershell")
                 .args(["-NoProfile", "-Command", &block_leaks])
                 .status();
         }
     }
 
+    /// Points routed domains at their resolvers with NRPT rules, replacing
+    /// any from an earlier connect, and puts search domains ahead of the
+    /// global suffix list.
+    #[cfg(target_os = "windows")]
+    async fn apply_windows_dns_routes(&self, dns_servers: &str, settings: &SettingsState) {
+        let powershell = |script: String| async move {
+            let _ = Command::new("powershell")
+                .args(["-NoProfile", "-Command", &script])
+                .status()
+                .await;
+        };
+        powershell(dns_routes::windows_nrpt_cleanup()).await;
+
+        let tunnel_dns: Vec<String> = dns_servers
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(String::from)
+            .collect();
+        let local_dns = {
+            let state = self.state.lock().await;
+            let tunnels = [self.iface_entry.as_str(), self.iface_exit.as_str()];
+            state
+                .original_dns_snapshot
+                .as_deref()
+                .map(|snapshot| local_dns_servers(snapshot, &tunnels))
+                .unwrap_or_default()
+        };
+        let has_local_routes = settings
+            .dns_routes
+            .iter()
+            .any(|r| r.via == crate::models::DnsRouteVia::Local);
+        if has_local_routes && local_dns.is_empty() {
+            warn!("No local DNS server is known, so local DNS routes were not applied");
+        }
+        for rule in dns_routes::windows_nrpt_rules(&settings.dns_routes, &tunnel_dns, &local_dns) {
+            powershell(rule).await;
+        }
+
+        let search = dns_routes::search_domains(&settings.dns_routes);
+        if search.is_empty() {
+            return;
+        }
+        let original = {
+            let state = self.state.lock().await;
+            state.original_suffix_search_list.clone()
+        };
+        let original = match original {
+            Some(list) => list,
+            None => {
+                let output = Command::new("powershell")
+                    .args([
+                        "-NoProfile",
+                        "-Command",
+                        "(Get-DnsClientGlobalSetting).SuffixSearchList -join ','",
+                    ])
+                    .output()
+                    .await;
+                let list: Vec<String> = output
+                    .map(|o| {
+                        String::from_utf8_lossy(&o.stdout)
+                            .split(',')
+                            .map(str::trim)
+                            .filter(|s| !s.is_empty())
+                            .map(String::from)
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                self.state.lock().await.original_suffix_search_list = Some(list.clone());
+                list
+            }
+        };
+        let kept: Vec<String> = original
+            .into_iter()
+            .filter(|s| !search.contains(s))
+            .collect();
+        let suffixes = [search, kept].concat();
+        powershell(dns_routes::windows_search_list(&suffixes)).await;
+    }
+
+    /// Adds the local routes' domains to the link of the local network, next
+    /// to the domains it already has. More specific than the tunnel's `~.`,
+    /// they win for their names.
+    #[cfg(target_os = "linux")]
+    async fn apply_local_dns_routes(&self, routes: &[crate::models::DnsRoute]) {
+        let domains = dns_routes::local_link_domains(routes);
+        if domains.is_empty() {
+            return;
+        }
+        let default_route = Command::new("ip")
+            .args(["route", "show", "default"])
+            .output()
+            .await
+            .ok()
+            .and_then(|o| dns_routes::default_route_device(&String::from_utf8_lossy(&o.stdout)));
+        let Some(link) = default_route else {
+            warn!("No default route, so local DNS routes were not applied");
+            return;
+        };
+        let original = Command::new("resolvectl")
+            .args(["domain", &link])
+            .output()
+            .await
+            .map(|o| dns_routes::link_domains_from_status(&String::from_utf8_lossy(&o.stdout)))
+            .unwrap_or_default();
+
+        let status = Command::new("resolvectl")
+            .arg("domain")
+            .arg(&link)
+            .args(original.iter().chain(&domains))
+            .status()
+            .await;
+        if status.map(|s| s.success()).unwrap_or(false) {
+            self.state.lock().await.local_dns_link = Some((link, original));
+        } else {
+            warn!("Could not add local DNS routes to {}", link);
+        }
+    }
+
     async fn restore_dns(&self) {
         #[cfg(target_os = "linux")]
         {
+            let local_link = self.state.lock().await.local_dns_link.take();
+            if let Some((link, original)) = local_link {
+                // An empty argument clears the link's domains.
+                let original = if original.is_empty() {
+                    vec![String::new()]
+                } else {
+                    original
+                };
+                let _ = Command::new("resolvectl")
+                    .arg("domain")
+                    .arg(&link)
+                    .args(&original)
+                    .status()
+                    .await;
+            }
+
             let mut state = self.state.lock().await;
-            if state.systemd_dns_applied {
-                state.systemd_dns_applied = false;
+            if let Some(iface) = state.systemd_dns_iface.take() {
                 drop(state);
                 let _ = Command::new("resolvectl")
                     .arg("revert")
-                    .arg(&self.iface_entry)
-                    .status();
+                    .arg(&iface)
+                    .status()
+                    .await;
             } else if let Some(original) = state.original_resolv_conf.take() {
                 drop(state);
-                let _ = fs::write("/etc/resolv.conf", original);
+                if let Err(e) = fs::write(&self.resolv_conf, original).await {
+                    error!("Failed to restore {}: {}", self.resolv_conf.display(), e);
+                }
             }
         }
 
         #[cfg(target_os = "windows")]
         {
-            let snapshot = {
+            let (snapshot, suffixes) = {
                 let mut state = self.state.lock().await;
-                state.original_dns_snapshot.take()
+                (
+                    state.original_dns_snapshot.take(),
+                    state.original_suffix_search_list.take(),
+                )
             };
+            let _ = Command::new("powershell")
+                .args([
+                    "-NoProfile",
+                    "-Command",
+                    &dns_routes::windows_nrpt_cleanup(),
+                ])
+                .status()
+                .await;
+            if let Some(suffixes) = suffixes {
+                let _ = Command::new("powershell")
+                    .args([
+                        "-NoProfile",
+                        "-Command",
+                        &dns_routes::windows_search_list(&suffixes),
+                    ])
+                    .status()
+                    .await;
+            }
             if let Some(snapshot) = snapshot {
                 info!("Restoring Windows DNS from snapshot...");
                 Self::restore_dns_snapshot(&snapshot);
@@ -1393,7 +3156,15 @@ impl RealWgRunner {
         }
     }
 
-    async fn resolve_endpoint_ips(host: &str) -> (Vec<String>, Vec<String>) {
+    /// The relay's addresses, looked up over DoH so the local resolver does
+    /// not see which relay is about to be used. The providers are
+    /// `settings.doh_providers`, or those `MARIN_DOH_PROVIDERS` lists, and the
+    /// lookup gives up after one shared deadline. Only when it fails is the
+    /// system resolver asked.
+    async fn resolve_endpoint_ips(
+        host: &str,
+        settings: &SettingsState,
+    ) -> (Vec<String>, Vec<String>) {
         let host = host.trim();
         if host.parse::<std::net::IpAddr>().is_ok() {
             if host.contains(':') {
@@ -1401,6 +3172,14 @@ impl RealWgRunner {
             }
             return (vec![host.to_string()], Vec::new());
         }
+        let providers = doh::active_providers(&settings.doh_providers);
+        match crate::services::auth::AuthService::secure_resolve(host, &providers).await {
+            Ok(resolved) => return (resolved.v4, resolved.v6),
+            Err(e) => warn!(
+                "DoH lookup of {} failed ({}), asking the system resolver",
+                host, e
+            ),
+        }
         let mut v4 = Vec::new();
         let mut v6 = Vec::new();
         if let Ok(lookup) = tokio::net::lookup_host(format!("{}:0", host)).await {
@@ -1513,92 +3292,55 @@ impl WgRunner for RealWgRunner {
         entry: &WireGuardConfig,
         exit: Option<&WireGuardConfig>,
         settings: &SettingsState,
-    ) -> Result<(), VpnError> {
-        let mut final_entry = entry.clone();
-        let obfs_key = entry.obfuscation_key.as_deref();
-
-        match settings.stealth_mode {
-            StealthMode::Automatic => {
-                info!("Stealth Mode: AUTOMATIC discovery initiated...");
-                if let Ok(ep) = self.lwo_obfuscator.start(&entry.endpoint, obfs_key).await {
-                    final_entry.endpoint = ep;
-                    info!("Auto-Stealth: Selected LWO");
-                } else if let Ok(ep) = self.quic_obfuscator.start(&entry.endpoint, obfs_key).await {
-                    final_entry.endpoint = ep;
-                    info!("Auto-Stealth: Selected QUIC");
-                } else {
-                    match self.ws_obfuscator.start(&entry.endpoint, obfs_key).await {
-                        Ok(ep) => final_entry.endpoint = ep,
-                        Err(_) => warn!("Auto-Stealth: All methods failed, using standard UDP"),
-                    }
-                }
-            }
-            StealthMode::WireGuardPort => {
-                info!("Stealth Mode: WireGuard on Port 53 (DNS) simulation");
-                let host = entry.endpoint.split(':').next().unwrap_or(&entry.endpoint);
-                final_entry.endpoint = format!("{}:53", host);
-            }
-            StealthMode::None => {
-                // Standard WireGuard
-            }
-            _ => {
-                // Fallback for other methods
-            }
-        }
+    ) -> Result<StealthPort, VpnError> {
+        check_multihop_stealth(entry, exit)?;
 
-        let entry_conf = if let Some(exit_cfg) = exit {
-            let exit_host = exit_cfg
-                .endpoint
-                .split(':')
-                .next()
-                .unwrap_or(&exit_cfg.endpoint);
-
-            let exit_ip = match tokio::net::lookup_host(format!("{}:51820", exit_host)).await {
-                Ok(mut addrs) => addrs
-                    .next()
-                    .map(|a| a.ip().to_string())
-                    .unwrap_or_else(|| exit_host.to_string()),
-                Err(_) => exit_host.to_string(),
-            };
+        // Only the entry hop is visible on the local network; the exit tunnel
+        // rides inside it and is never handed to an obfuscator.
+        let stealth = self.start_stealth(settings.stealth_mode, entry).await?;
 
-            format!(
-                    "[Interface]\nPrivateKey = {}\nAddress = {}\nMTU = 1320\n\n[Peer]\nPublicKey = {}\nEndpoint = {}\nAllowedIPs = {}, {}/32\nPersistentKeepalive = 25\n",
-                    final_entry.private_key,
-                    final_entry.address,
-                    final_entry.public_key,
-                    final_entry.endpoint,
-                    final_entry.address,
-                    exit_ip
-                )
-        } else {
-            self.create_conf(&final_entry, settings, None)
+        let mtu = self.tunnel_mtu(entry, settings).await;
+        let hop_mtu = match exit {
+            Some(_) => Some(multihop_mtu(mtu)?),
+            None => None,
         };
 
+        let entry_conf = self
+            .entry_conf(entry, stealth.endpoint, exit, settings, mtu)
+            .await?;
         self.apply_single_up(&self.iface_entry, &entry_conf).await?;
 
-        if let Some(exit_cfg) = exit {
-            info!("Establishing nested exit tunnel with adjusted MTU...");
-            let exit_conf = self.create_conf(exit_cfg, settings, Some(1200));
+        if let (Some(exit_cfg), Some(hop_mtu)) = (exit, hop_mtu) {
+            info!(
+                "Establishing nested exit tunnel (entry MTU {}, exit MTU {})...",
+                hop_mtu.entry, hop_mtu.exit
+            );
+            let exit_conf = self.create_conf(exit_cfg, &exit_cfg.endpoint, settings, hop_mtu.exit);
             self.apply_single_up(&self.iface_exit, &exit_conf).await?;
         }
 
-        self.apply_dns(&exit.unwrap_or(entry).dns, settings).await;
+        let dns_iface = egress_interface(&self.iface_entry, &self.iface_exit, exit.is_some());
+        self.apply_dns(dns_iface, &exit.unwrap_or(entry).dns, settings)
+            .await;
+        #[cfg(target_os = "linux")]
+        self.apply_app_split(exit.unwrap_or(entry), settings).await;
 
-        Ok(())
+        Ok(stealth.port)
     }
 
     async fn down(&self) -> Result<(), VpnError> {
-        self.apply_single_down(&self.iface_exit).await;
-        self.apply_single_down(&self.iface_entry).await;
-
-        self.ws_obfuscator.stop().await.ok();
-        self.ss_obfuscator.stop().await.ok();
-        self.quic_obfuscator.stop().await.ok();
-        self.tcp_obfuscator.stop().await.ok();
-        self.lwo_obfuscator.stop().await.ok();
-
-        self.restore_dns().await;
-        self.clear_bypass_routes().await;
+        let teardown = async {
+            self.apply_single_down(&self.iface_exit).await;
+            self.apply_single_down(&self.iface_entry).await;
+            self.stop_obfuscators().await;
+        };
+        let cleanup = async {
+            self.restore_dns().await;
+            self.clear_bypass_routes().await;
+            #[cfg(target_os = "linux")]
+            self.clear_app_split().await;
+        };
+        with_cleanup(teardown, cleanup).await;
 
         let mut state = self.state.lock().await;
         state.last_stats = None;
@@ -1607,11 +3349,29 @@ impl WgRunner for RealWgRunner {
         Ok(())
     }
 
+    async fn switch_stealth(
+        &self,
+        entry: &WireGuardConfig,
+        exit: Option<&WireGuardConfig>,
+        settings: &SettingsState,
+    ) -> Result<StealthPort, VpnError> {
+        // Only one obfuscator runs at a time, and Automatic does not say
+        // which one it picked.
+        self.stop_obfuscators().await;
+        let stealth = self.start_stealth(settings.stealth_mode, entry).await?;
+        let mtu = self.tunnel_mtu(entry, settings).await;
+        let entry_conf = self
+            .entry_conf(entry, stealth.endpoint, exit, settings, mtu)
+            .await?;
+        self.apply_single_up(&self.iface_entry, &entry_conf).await?;
+        Ok(stealth.port)
+    }
+
     async fn get_stats(&self) -> Result<VpnStats, VpnError> {
         let output = Command::new("wg")
             .arg("show")
             .arg(&self.iface_entry)
-            .args(["transfer", "latest-handshake"])
+            .arg("dump")
             .output()
             .await
             .map_err(|_| VpnError::DriverMissing)?;
@@ -1626,40 +3386,25 @@ impl WgRunner for RealWgRunner {
             });
         }
 
-        let out_str = String::from_utf8_lossy(&output.stdout);
-        let lines: Vec<&str> = out_str.lines().collect();
-
-        let mut total_download = 0;
-        let mut total_upload = 0;
-        let mut latest_handshake = 0;
-
-        for line in lines {
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.len() >= 3 && !line.contains("handshake") {
-                total_download = parts[1].parse::<u64>().unwrap_or(0);
-                total_upload = parts[2].parse::<u64>().unwrap_or(0);
-            } else if line.contains("handshake") && parts.len() >= 2 {
-                latest_handshake = parts[1].parse::<u64>().unwrap_or(0);
-            }
-        }
+        let show = WgShow::parse(&String::from_utf8_lossy(&output.stdout))
+            .map_err(VpnError::InterfaceError)?;
+        let total_download = show.total_rx();
+        let total_upload = show.total_tx();
+        let latest_handshake = show.latest_handshake().unwrap_or(0);
 
         let now = Instant::now();
         let mut state = self.state.lock().await;
 
-        let (dl_speed, ul_speed) = if let (Some(last), Some(last_time)) =
-            (&state.last_stats, &state.last_check)
-        {
-            let dur = now.duration_since(*last_time).as_secs_f64();
-            if dur > 0.0 {
-                let dl = (total_download.saturating_sub(last.total_download)) as f64 / dur / 1024.0;
-                let ul = (total_upload.saturating_sub(last.total_upload)) as f64 / dur / 1024.0;
-                (dl, ul)
+        let (dl_speed, ul_speed) =
+            if let (Some(last), Some(last_time)) = (&state.last_stats, &state.last_check) {
+                let elapsed = now.duration_since(*last_time);
+                (
+                    transfer_rate(last.total_download, total_download, elapsed),
+                    transfer_rate(last.total_upload, total_upload, elapsed),
+                )
             } else {
                 (0.0, 0.0)
-            }
-        } else {
-            (0.0, 0.0)
-        };
+            };
 
         let stats = VpnStats {
             download_speed: dl_speed,
@@ -1675,88 +3420,19 @@ impl WgRunner for RealWgRunner {
         Ok(stats)
     }
 
-    async fn apply_app_bypass(&self, app_path: &str) {
-        #[cfg(target_os = "linux")]
+    async fn apply_bypass_route(&self, ip: &str) {
         {
-            info!("Applying cgroup bypass: {}", app_path);
-            let cgroup_dir = "/sys/fs/cgroup/net_cls/marinvpn_bypass";
-            if !std::path::Path::new("/sys/fs/cgroup/net_cls").exists() {
-                warn!("net_cls cgroup not available; app bypass disabled.");
-                return;
-            }
-
-            let commands = vec![
-                format!("mkdir -p {}", cgroup_dir),
-                format!("echo 0x1000 > {}/net_cls.classid", cgroup_dir),
-                "ip rule add fwmark 0x1000 table main".to_string(),
-            ];
-
-            for cmd in commands {
-                let _ = Command::new("sh").args(["-c", &cmd]).status();
+            let mut state = self.state.lock().await;
+            if !state.bypass_routes.iter().any(|r| r == ip) {
+                state.bypass_routes.push(ip.to_string());
             }
+        }
 
-            let pid_output = Command::new("sh")
-                .args([
-                    "-c",
-                    &format!(
-                        "for p in /proc/[0-9]*/exe; do if [ \"$(readlink -f \"$p\")\" = \"{}\" ]; then echo ${p%/exe} | awk -F/ '{print $3}'; fi; done",
-                        app_path.replace('"', "\\\"")
-                    ),
-                ])
-                .output()
-                .ok();
-            let Some(output) = pid_output else {
-                warn!("Failed to locate process for bypass: {}", app_path);
-                return;
-            };
-            let pids = String::from_utf8_lossy(&output.stdout);
-            if pids.trim().is_empty() {
-                warn!("No running process found for bypass: {}", app_path);
-                return;
-            }
-
-            for pid in pids.split_whitespace() {
-                let cmd = format!("echo {} > {}/cgroup.procs", pid, cgroup_dir);
-                let _ = Command::new("sh").args(["-c", &cmd]).status();
-            }
-        }
-
-        #[cfg(target_os = "windows")]
-        {
-            info!("Injecting WFP bypass: {}", app_path);
-
-            let app_name = std::path::Path::new(app_path)
-                .file_stem()
-                .and_then(|s| s.to_str())
-                .unwrap_or("MarinVPNBypass");
-
-            let script = format!(
-                "New-NetFirewallRule -DisplayName 'MarinVPN Bypass - {}' \
-                    -Direction Outbound -Program '{}' -Action Allow -Profile Any \
-                    -InterfaceAlias '*' -EdgeTraversalPolicy Allow",
-                app_name.replace("'", "''"),
-                app_path.replace("'", "''")
-            );
-
-            let _ = Command::new("powershell")
-                .args(["-NoProfile", "-Command", &script])
-                .status();
-        }
-    }
-
-    async fn apply_bypass_route(&self, ip: &str) {
-        {
-            let mut state = self.state.lock().await;
-            if !state.bypass_routes.iter().any(|r| r == ip) {
-                state.bypass_routes.push(ip.to_string());
-            }
-        }
-
-        #[cfg(target_os = "linux")]
-        {
-            let get_iface = "ip route | grep default | awk '{print $5}' | head -n1";
-            let iface = Command::new("sh")
-                .args(["-c", get_iface])
+        #[cfg(target_os = "linux")]
+        {
+            let get_iface = "ip route | grep default | awk '{print $5}' | head -n1";
+            let iface = Command::new("sh")
+                .args(["-c", get_iface])
                 .output()
                 .await
                 .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
@@ -1784,6 +3460,32 @@ impl WgRunner for RealWgRunner {
         }
     }
 
+    async fn apply_app_bypass(&self, tunnel: &WireGuardConfig, settings: &SettingsState) {
+        #[cfg(target_os = "linux")]
+        {
+            self.clear_app_split().await;
+            self.release_app_split().await;
+            self.apply_app_split(tunnel, settings).await;
+        }
+
+        #[cfg(target_os = "windows")]
+        {
+            let _ = tunnel;
+            for script in split_tunnel::WINDOWS_CLEAR_APP_RULES {
+                let _ = Command::new("powershell")
+                    .args(["-NoProfile", "-Command", script])
+                    .status()
+                    .await;
+            }
+            if settings.split_tunneling {
+                Self::apply_windows_app_rules(settings).await;
+            }
+        }
+
+        #[cfg(not(any(target_os = "linux", target_os = "windows")))]
+        let _ = (tunnel, settings);
+    }
+
     async fn apply_single_up(&self, iface: &str, conf: &str) -> Result<(), VpnError> {
         #[cfg(target_os = "linux")]
         {
@@ -1802,6 +3504,11 @@ impl WgRunner for RealWgRunner {
                 .await
                 .map_err(|e| VpnError::InterfaceError(e.to_string()))?;
 
+            if interface_up(iface).await {
+                let stripped = format!("/tmp/marinvpn_{}.setconf", iface);
+                return set_conf_in_place(iface, std::path::Path::new(&stripped), conf).await;
+            }
+
             let output = Command::new("wg-quick")
                 .arg("up")
                 .arg(&conf_path)
@@ -1829,6 +3536,11 @@ impl WgRunner for RealWgRunner {
                 .await
                 .map_err(|e| VpnError::InterfaceError(e.to_string()))?;
 
+            if interface_up(iface).await {
+                let stripped = config_dir.join(format!("{}.setconf", iface));
+                return set_conf_in_place(iface, &stripped, conf).await;
+            }
+
             let _ = Command::new("wireguard.exe")
                 .arg("/installmanagerservice")
                 .arg(&conf_path)
@@ -1872,12 +3584,21 @@ impl WgRunner for RealWgRunner {
         endpoint: &str,
         settings: &SettingsState,
     ) -> Result<(), VpnError> {
-        let (host, port) = parse_endpoint_host_port(endpoint);
+        #[cfg(target_os = "linux")]
+        let firewall = linux_firewall().await?;
+        #[cfg(not(target_os = "linux"))]
+        check_firewall_tools(FIREWALL_TOOLS).await?;
+
+        // Counted as on from here, since a failure below may leave some
+        // rules in place.
+        self.state.lock().await.kill_switch_on = true;
+
+        let (host, _) = parse_endpoint_host_port(endpoint);
         let host_str = host.as_str();
         let (resolved_v4, resolved_v6) = if host_str == "0.0.0.0" {
             (Vec::new(), Vec::new())
         } else {
-            Self::resolve_endpoint_ips(&host).await
+            Self::resolve_endpoint_ips(&host, settings).await
         };
         if host_str != "0.0.0.0" && resolved_v4.is_empty() && resolved_v6.is_empty() {
             return Err(VpnError::FirewallError(
@@ -1891,229 +3612,28 @@ impl WgRunner for RealWgRunner {
         };
         let v6_addrs: Vec<&str> = resolved_v6.iter().map(|s| s.as_str()).collect();
 
-        let mut allow_rules: Vec<(&'static str, u16)> = Vec::new();
-        match settings.stealth_mode {
-            StealthMode::Automatic => {
-                allow_rules.push(("udp", port));
-                allow_rules.push(("tcp", 443));
-                allow_rules.push(("udp", 443));
-            }
-            StealthMode::WireGuardPort => {
-                allow_rules.push(("udp", 53));
-            }
-            StealthMode::Lwo => {
-                allow_rules.push(("udp", port));
-            }
-            StealthMode::Quic => {
-                allow_rules.push(("udp", 443));
-            }
-            StealthMode::Tcp => {
-                allow_rules.push(("tcp", 443));
-            }
-            StealthMode::Shadowsocks => {
-                let ss_port = if endpoint.contains(':') { port } else { 8388 };
-                allow_rules.push(("tcp", ss_port));
-                allow_rules.push(("udp", ss_port));
-            }
-            StealthMode::None => {
-                allow_rules.push(("udp", port));
-            }
-        }
+        let allow_rules = kill_switch_allow_rules(endpoint, settings.stealth_mode);
 
         #[cfg(target_os = "linux")]
         {
-            info!("Enabling Linux Kill-switch using nftables...");
-
-            let run_nft = |args: &[&str]| Command::new("nft").args(args).status();
-
-            let _ = run_nft(&["add", "table", "inet", "marinvpn_killswitch"]);
-            let _ = run_nft(&[
-                "add",
-                "chain",
-                "inet",
-                "marinvpn_killswitch",
-                "output",
-                "{",
-                "type",
-                "filter",
-                "hook",
-                "output",
-                "priority",
-                "0;",
-                "policy",
-                "drop;",
-                "}",
-            ]);
-            let _ = run_nft(&[
-                "add",
-                "chain",
-                "inet",
-                "marinvpn_killswitch",
-                "input",
-                "{",
-                "type",
-                "filter",
-                "hook",
-                "input",
-                "priority",
-                "0;",
-                "policy",
-                "accept;",
-                "}",
-            ]);
-            let _ = run_nft(&[
-                "add",
-                "rule",
-                "inet",
-                "marinvpn_killswitch",
-                "output",
-                "oifname",
-                "lo",
-                "accept",
-            ]);
-            if v4_addrs.len() == 1 && v4_addrs[0] == "0.0.0.0" {
-                // no specific endpoint
-            } else {
-                for addr in &v4_addrs {
-                    for (proto, port) in &allow_rules {
-                        let port_str = port.to_string();
-                        let _ = run_nft(&[
-                            "add",
-                            "rule",
-                            "inet",
-                            "marinvpn_killswitch",
-                            "output",
-                            "ip",
-                            "daddr",
-                            addr,
-                            proto,
-                            "dport",
-                            &port_str,
-                            "accept",
-                        ]);
-                    }
-                }
-            }
-            if !v6_addrs.is_empty() {
-                for addr in &v6_addrs {
-                    for (proto, port) in &allow_rules {
-                        let port_str = port.to_string();
-                        let _ = run_nft(&[
-                            "add",
-                            "rule",
-                            "inet",
-                            "marinvpn_killswitch",
-                            "output",
-                            "ip6",
-                            "daddr",
-                            addr,
-                            proto,
-                            "dport",
-                            &port_str,
-                            "accept",
-                        ]);
-                    }
-                }
-            }
-
-            let _ = run_nft(&[
-                "add",
-                "rule",
-                "inet",
-                "marinvpn_killswitch",
-                "output",
-                "udp",
-                "sport",
-                "68",
-                "dport",
-                "67",
-                "accept",
-            ]);
-            if settings.ipv6_support {
-                let _ = run_nft(&[
-                    "add",
-                    "rule",
-                    "inet",
-                    "marinvpn_killswitch",
-                    "output",
-                    "udp",
-                    "sport",
-                    "546",
-                    "dport",
-                    "547",
-                    "accept",
-                ]);
-                let _ = run_nft(&[
-                    "add",
-                    "rule",
-                    "inet",
-                    "marinvpn_killswitch",
-                    "output",
-                    "icmpv6",
-                    "type",
-                    "{",
-                    "router-solicitation,",
-                    "router-advertisement,",
-                    "neighbor-solicitation,",
-                    "neighbor-advertisement",
-                    "}",
-                    "accept",
-                ]);
-            }
-
-            let _ = run_nft(&[
-                "add",
-                "rule",
-                "inet",
-                "marinvpn_killswitch",
-                "output",
-                "oifname",
-                &self.iface_entry,
-                "accept",
-            ]);
-
-            if settings.split_tunneling {
-                let _ = run_nft(&[
-                    "add",
-                    "rule",
-                    "inet",
-                    "marinvpn_killswitch",
-                    "output",
-                    "mark",
-                    "0x1000",
-                    "accept",
-                ]);
+            info!("Enabling Linux Kill-switch using {}...", firewall.name());
+            let relay_v4: Vec<&str> = v4_addrs
+                .iter()
+                .copied()
+                .filter(|addr| *addr != "0.0.0.0")
+                .collect();
+            let rules = KillSwitchRules {
+                relay_v4: &relay_v4,
+                relay_v6: &v6_addrs,
+                allow: &allow_rules,
+                tunnel_iface: &self.iface_entry,
+                ipv6_support: settings.ipv6_support,
+                split_tunneling: settings.split_tunneling,
+                local_sharing: settings.local_sharing,
+            };
+            for command in firewall.enable(&rules) {
+                let _ = Command::new(&command[0]).args(&command[1..]).status().await;
             }
-
-            if settings.local_sharing {
-                let _ = run_nft(&[
-                    "add",
-                    "rule",
-                    "inet",
-                    "marinvpn_killswitch",
-                    "output",
-                    "ip",
-                    "daddr",
-                    "{",
-                    "192.168.0.0/16,",
-                    "10.0.0.0/8,",
-                    "172.16.0.0/12",
-                    "}",
-                    "accept",
-                ]);
-            }
-
-            let _ = run_nft(&[
-                "add",
-                "rule",
-                "inet",
-                "marinvpn_killswitch",
-                "output",
-                "ip6",
-                "daddr",
-                "::/0",
-                "drop",
-            ]);
         }
 
         #[cfg(target_os = "windows")]
@@ -2123,20 +3643,33 @@ impl WgRunner for RealWgRunner {
             {
                 let mut state = self.state.lock().await;
                 if state.original_firewall_policy.is_none() {
-                    state.original_firewall_policy = Self::read_firewall_policy().await;
+                    state.original_firewall_policy = Self::read_firewall_policy()
+                        .await
+                        .map(restorable_firewall_policy);
                 }
                 if state.original_dns_snapshot.is_none() {
                     state.original_dns_snapshot = Self::capture_dns_snapshot().await;
                 }
             }
 
+            // Rules with the same name do not replace each other, so the
+            // previous call's are removed before adding them again.
+            let mut removals = vec![windows_kill_switch_removal()];
+            removals.extend(split_tunnel::WINDOWS_CLEAR_APP_RULES.map(String::from));
+            for script in &removals {
+                let _ = Command::new("powershell")
+                    .args(["-NoProfile", "-Command", script])
+                    .status()
+                    .await;
+            }
+
             let _ = Command::new("netsh")
                 .args([
                     "advfirewall",
                     "set",
                     "allprofiles",
                     "firewallpolicy",
-                    "blockoutbound,allowinbound",
+                    LOCKDOWN_FIREWALL_POLICY,
                 ])
                 .status();
 
@@ -2206,9 +3739,7 @@ impl WgRunner for RealWgRunner {
                         .status();
                     self.apply_bypass_route(ip).await;
                 }
-                for app in &settings.excluded_apps {
-                    self.apply_app_bypass(&app.path).await;
-                }
+                Self::apply_windows_app_rules(settings).await;
             }
 
             let block_v6 = "Get-NetAdapter | Where-Object { $_.InterfaceDescription -notlike '*Wintun*' -and $_.InterfaceAlias -notlike 'marinvpn*' } | ForEach-Object { \
@@ -2243,12 +3774,21 @@ impl WgRunner for RealWgRunner {
     }
 
     async fn disable_kill_switch(&self) {
+        self.state.lock().await.kill_switch_on = false;
+
         #[cfg(target_os = "linux")]
         {
-            info!("Disabling Linux Kill-switch (nftables)...");
-            let _ = Command::new("nft")
-                .args(["delete", "table", "inet", "marinvpn_killswitch"])
-                .status();
+            info!("Disabling Linux Kill-switch...");
+            // Either backend may have been used, by this run or one kept
+            // across a restart, and removing an absent one is a no-op.
+            let mut leftovers = Vec::new();
+            for firewall in linux_firewall::backends() {
+                for command in firewall.disable() {
+                    let _ = Command::new(&command[0]).args(&command[1..]).status().await;
+                }
+                leftovers.extend(firewall.leftovers());
+            }
+            remove_leftovers(&SystemCommands, &leftovers, CLEANUP_ATTEMPTS).await;
         }
 
         #[cfg(target_os = "windows")]
@@ -2289,17 +3829,1247 @@ impl WgRunner for RealWgRunner {
                 ])
                 .status();
 
-            let _ = Command::new("powershell")
-                .args([
-                    "-NoProfile",
-                    "-Command",
-                    "Remove-NetFirewallRule -DisplayName 'MarinVPN Bypass - *'",
-                ])
-                .status();
+            for script in split_tunnel::WINDOWS_CLEAR_APP_RULES {
+                let _ = Command::new("powershell")
+                    .args(["-NoProfile", "-Command", script])
+                    .status()
+                    .await;
+            }
+            remove_leftovers(
+                &SystemCommands,
+                &[crate::services::firewall_cleanup::windows_rules()],
+                CLEANUP_ATTEMPTS,
+            )
+            .await;
 
             self.restore_dns().await;
         }
 
         self.clear_bypass_routes().await;
     }
+
+    /// Probes the largest tunnel MTU the path to the relay carries
+    /// unfragmented and remembers it per endpoint. Falls back to 1280 when
+    /// the relay cannot be resolved or pinged within 3 seconds, and remembers
+    /// that too, so a relay that drops ICMP only costs the wait once. The
+    /// relay is resolved over DoH like the kill switch does, so the local
+    /// resolver does not learn it. Kill switch rules drop ICMP, so nothing
+    /// is probed while they are in place.
+    async fn probe_mtu(&self, entry: &WireGuardConfig, settings: &SettingsState) {
+        if fixed_mtu(settings).is_some() {
+            return;
+        }
+        {
+            let state = self.state.lock().await;
+            if state.probed_mtu.contains_key(&entry.endpoint) {
+                return;
+            }
+            if state.kill_switch_on || settings.lockdown_mode {
+                info!("Kill switch is on, not probing the tunnel MTU");
+                return;
+            }
+        }
+        let (host, _) = parse_endpoint_host_port(&entry.endpoint);
+        let probe = async {
+            let (v4, v6) = Self::resolve_endpoint_ips(&host, settings).await;
+            let ip: IpAddr = v4.iter().chain(&v6).find_map(|ip| ip.parse().ok())?;
+            search_mtu(PROBE_MTU_MIN, PROBE_MTU_MAX, |mtu| path_fits(ip, mtu)).await
+        };
+        let mtu = match tokio::time::timeout(MTU_PROBE_BUDGET, probe).await {
+            Ok(Some(mtu)) => {
+                info!("Probed tunnel MTU {} towards {}", mtu, host);
+                mtu
+            }
+            _ => {
+                warn!(
+                    "MTU probe towards {} failed, using {}",
+                    host, FALLBACK_TUNNEL_MTU
+                );
+                FALLBACK_TUNNEL_MTU
+            }
+        };
+        let mut state = self.state.lock().await;
+        state.probed_mtu.insert(entry.endpoint.clone(), mtu);
+    }
+
+    async fn tcp_reachable(&self, endpoint: &str, port: u16) -> bool {
+        let target = endpoint_on_port(endpoint, port);
+        let connect = tokio::net::TcpStream::connect(target);
+        matches!(
+            tokio::time::timeout(Duration::from_secs(3), connect).await,
+            Ok(Ok(_))
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::vpn_mock::{MockCall, MockVpnService};
+
+    #[test]
+    fn resume_keeps_tunnel_with_fresh_handshake() {
+        let now = 1_700_000_000;
+        assert_eq!(
+            resume_action(ReconnectPolicy::OnFailure, Some(now - 30), now),
+            ResumeAction::KeepTunnel
+        );
+    }
+
+    #[test]
+    fn resume_reconnects_with_stale_handshake() {
+        let now = 1_700_000_000;
+        assert_eq!(
+            resume_action(
+                ReconnectPolicy::OnFailure,
+                Some(now - STALE_HANDSHAKE_SECS - 1),
+                now
+            ),
+            ResumeAction::Reconnect
+        );
+    }
+
+    #[test]
+    fn resume_reconnects_without_handshake_or_stats() {
+        let now = 1_700_000_000;
+        for policy in [ReconnectPolicy::OnFailure, ReconnectPolicy::Aggressive] {
+            assert_eq!(resume_action(policy, Some(0), now), ResumeAction::Reconnect);
+            assert_eq!(resume_action(policy, None, now), ResumeAction::Reconnect);
+        }
+    }
+
+    #[test]
+    fn never_policy_leaves_the_tunnel_alone() {
+        let now = 1_700_000_000;
+        let policy = ReconnectPolicy::Never;
+        for handshake in [None, Some(0), Some(now - 3600)] {
+            assert_eq!(
+                resume_action(policy, handshake, now),
+                ResumeAction::KeepTunnel
+            );
+        }
+        assert_eq!(stale_handshake_limit(policy), None);
+        assert_eq!(health_schedule(policy), None);
+        assert!(!failover_switches_server(
+            policy,
+            &LocationSelection::Automatic
+        ));
+    }
+
+    #[test]
+    fn aggressive_policy_reacts_sooner_than_on_failure() {
+        let on_failure = health_schedule(ReconnectPolicy::OnFailure).unwrap();
+        let aggressive = health_schedule(ReconnectPolicy::Aggressive).unwrap();
+        assert!(aggressive.interval < on_failure.interval);
+        assert!(aggressive.failures_before_failover < on_failure.failures_before_failover);
+
+        // A handshake this old is fine by default but stale under Aggressive.
+        let now = 1_700_000_000;
+        let handshake = Some(now - AGGRESSIVE_STALE_HANDSHAKE_SECS - 1);
+        assert_eq!(
+            resume_action(ReconnectPolicy::OnFailure, handshake, now),
+            ResumeAction::KeepTunnel
+        );
+        assert_eq!(
+            resume_action(ReconnectPolicy::Aggressive, handshake, now),
+            ResumeAction::Reconnect
+        );
+    }
+
+    #[test]
+    fn failover_switches_servers_only_where_the_policy_allows() {
+        let automatic = LocationSelection::Automatic;
+        let country = LocationSelection::BestIn("Sweden".to_string());
+        let city = LocationSelection::Specific(Location::new("Sweden", "Stockholm"));
+
+        let on_failure = ReconnectPolicy::OnFailure;
+        assert!(failover_switches_server(on_failure, &automatic));
+        assert!(!failover_switches_server(on_failure, &country));
+        assert!(!failover_switches_server(on_failure, &city));
+
+        let aggressive = ReconnectPolicy::Aggressive;
+        assert!(failover_switches_server(aggressive, &automatic));
+        assert!(failover_switches_server(aggressive, &country));
+        assert!(!failover_switches_server(aggressive, &city));
+    }
+
+    #[test]
+    fn a_pinned_server_is_never_failed_over_from() {
+        let selections = [
+            LocationSelection::Automatic,
+            LocationSelection::BestIn("Sweden".to_string()),
+            LocationSelection::Specific(Location::new("Sweden", "Stockholm")),
+        ];
+        for policy in [
+            ReconnectPolicy::Never,
+            ReconnectPolicy::OnFailure,
+            ReconnectPolicy::Aggressive,
+        ] {
+            for selection in &selections {
+                let mut settings = SettingsState {
+                    reconnect_policy: policy,
+                    entry_location: selection.clone(),
+                    ..SettingsState::default()
+                };
+                assert_eq!(
+                    failover_may_switch(&settings),
+                    failover_switches_server(policy, selection)
+                );
+                settings.pinned_server = Some("Sweden, Stockholm".to_string());
+                assert!(!failover_may_switch(&settings), "{:?}", policy);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn failover_with_a_pinned_server_keeps_the_endpoint() {
+        let settings = SettingsState {
+            reconnect_policy: ReconnectPolicy::Aggressive,
+            entry_location: LocationSelection::Automatic,
+            pinned_server: Some("Sweden, Stockholm".to_string()),
+            ..SettingsState::default()
+        };
+        let auth = ("ABCDEFGHJKLMNPQR".to_string(), "token".to_string());
+        let (name, config) = failover_target(
+            &settings,
+            "Sweden, Stockholm".to_string(),
+            wg_config("se-sto.marinvpn.net:51820", "se_pub_key"),
+            Some(&auth),
+        )
+        .await;
+        assert_eq!(name, "Sweden, Stockholm");
+        assert_eq!(config.endpoint, "se-sto.marinvpn.net:51820");
+    }
+
+    fn exit_settings(lockdown_mode: bool, keep_lockdown_after_exit: bool) -> SettingsState {
+        SettingsState {
+            lockdown_mode,
+            keep_lockdown_after_exit,
+            ..SettingsState::default()
+        }
+    }
+
+    #[test]
+    fn firewall_outlives_the_app_only_with_lockdown_and_opt_in() {
+        assert!(keeps_firewall_on_exit(&exit_settings(true, true)));
+        assert!(!keeps_firewall_on_exit(&exit_settings(true, false)));
+        assert!(!keeps_firewall_on_exit(&exit_settings(false, true)));
+        assert!(!keeps_firewall_on_exit(&exit_settings(false, false)));
+    }
+
+    #[test]
+    fn kept_lockdown_policy_is_not_restored_after_relaunch() {
+        assert_eq!(
+            restorable_firewall_policy(LOCKDOWN_FIREWALL_POLICY.to_string()),
+            DEFAULT_FIREWALL_POLICY
+        );
+        assert_eq!(
+            restorable_firewall_policy("blockinbound,blockoutbound".to_string()),
+            "blockinbound,blockoutbound"
+        );
+    }
+
+    #[tokio::test]
+    async fn shut_down_removes_the_firewall_by_default() {
+        let service = MockVpnService::new();
+        shut_down(&service, &exit_settings(true, false)).await;
+        assert_eq!(
+            service.calls(),
+            [MockCall::Disconnect, MockCall::DisableKillSwitch]
+        );
+    }
+
+    #[tokio::test]
+    async fn shut_down_keeps_the_firewall_when_asked() {
+        let service = MockVpnService::new();
+        shut_down(&service, &exit_settings(true, true)).await;
+        assert_eq!(service.calls(), [MockCall::Disconnect]);
+    }
+
+    #[test]
+    fn handshake_age_ignores_a_forward_clock_jump() {
+        let start = Instant::now();
+        let mut age = HandshakeAge::default();
+        let wall = 1_700_000_000;
+        assert_eq!(
+            age.observe(wall - 5, wall, start),
+            Some(Duration::from_secs(5))
+        );
+
+        // NTP moves the clock an hour ahead between two polls.
+        let later = start + Duration::from_secs(2);
+        assert_eq!(
+            age.observe(wall - 5, wall + 3602, later),
+            Some(Duration::from_secs(7))
+        );
+
+        // A handshake stamped just before the jump but first seen after it
+        // is no older than the poll interval.
+        let next = later + Duration::from_secs(2);
+        assert_eq!(
+            age.observe(wall + 1, wall + 3604, next),
+            Some(Duration::from_secs(2))
+        );
+    }
+
+    #[test]
+    fn handshake_age_keeps_growing_after_a_backward_clock_jump() {
+        let start = Instant::now();
+        let mut age = HandshakeAge::default();
+        let wall = 1_700_000_000;
+        age.observe(wall - 5, wall, start);
+
+        // The clock is set back an hour and the peer stops answering.
+        let later = start + Duration::from_secs(STALE_HANDSHAKE_SECS);
+        let observed = age.observe(wall - 5, wall - 3600, later).unwrap();
+        assert!(observed > Duration::from_secs(STALE_HANDSHAKE_SECS));
+    }
+
+    #[test]
+    fn handshake_age_is_unknown_without_a_handshake() {
+        let mut age = HandshakeAge::default();
+        assert_eq!(age.observe(0, 1_700_000_000, Instant::now()), None);
+    }
+
+    #[test]
+    fn transfer_rate_never_goes_negative() {
+        let second = Duration::from_secs(1);
+        assert_eq!(transfer_rate(0, 2048, second), 2.0);
+        assert_eq!(transfer_rate(4096, 1024, second), 0.0);
+        assert_eq!(transfer_rate(0, 2048, Duration::ZERO), 0.0);
+    }
+
+    #[test]
+    fn dns_goes_to_the_exit_interface_only_with_multihop() {
+        assert_eq!(
+            egress_interface("marinvpn0", "marinvpn1", false),
+            "marinvpn0"
+        );
+        assert_eq!(
+            egress_interface("marinvpn0", "marinvpn1", true),
+            "marinvpn1"
+        );
+    }
+
+    #[test]
+    fn multihop_mtu_subtracts_one_hop_for_exit() {
+        assert_eq!(
+            multihop_mtu(1280).unwrap(),
+            MultihopMtu {
+                entry: 1280,
+                exit: 1200
+            }
+        );
+        assert_eq!(
+            multihop_mtu(1380).unwrap(),
+            MultihopMtu {
+                entry: 1380,
+                exit: 1300
+            }
+        );
+    }
+
+    #[test]
+    fn multihop_mtu_clamps_oversized_base() {
+        let mtu = multihop_mtu(9000).unwrap();
+        assert_eq!(mtu.entry, MAX_TUNNEL_MTU);
+        assert_eq!(mtu.exit, MAX_TUNNEL_MTU - WG_HOP_OVERHEAD);
+    }
+
+    #[test]
+    fn multihop_mtu_rejects_bases_that_leave_no_room() {
+        assert!(multihop_mtu(MIN_TUNNEL_MTU + WG_HOP_OVERHEAD).is_ok());
+        assert!(multihop_mtu(MIN_TUNNEL_MTU + WG_HOP_OVERHEAD - 1).is_err());
+        assert!(multihop_mtu(0).is_err());
+    }
+
+    #[test]
+    fn default_mtu_is_automatic() {
+        let mut settings = SettingsState::default();
+        assert_eq!(fixed_mtu(&settings), None);
+        settings.mtu = 0;
+        assert_eq!(fixed_mtu(&settings), None);
+        settings.mtu = 1360;
+        assert_eq!(fixed_mtu(&settings), Some(1360));
+    }
+
+    #[tokio::test]
+    async fn mtu_is_not_probed_behind_the_kill_switch() {
+        let runner = RealWgRunner::new();
+        runner.state.lock().await.kill_switch_on = true;
+        let entry = wg_config("192.0.2.1:51820", "entry");
+        let settings = SettingsState::default();
+
+        runner.probe_mtu(&entry, &settings).await;
+        assert!(runner.state.lock().await.probed_mtu.is_empty());
+        assert_eq!(
+            runner.tunnel_mtu(&entry, &settings).await,
+            FALLBACK_TUNNEL_MTU
+        );
+
+        // What an earlier probe found, fallback included, is reused.
+        let mut state = runner.state.lock().await;
+        state.probed_mtu.insert(entry.endpoint.clone(), 1380);
+        drop(state);
+        assert_eq!(runner.tunnel_mtu(&entry, &settings).await, 1380);
+    }
+
+    #[tokio::test]
+    async fn mtu_search_finds_the_largest_size_the_path_carries() {
+        for path in [1200, 1213, 1372, 1419, 1420] {
+            let mut probes = Vec::new();
+            let found = search_mtu(PROBE_MTU_MIN, PROBE_MTU_MAX, |mtu| {
+                probes.push(mtu);
+                async move { mtu <= path }
+            })
+            .await;
+            assert_eq!(found, Some(path));
+            assert!(probes.len() <= 10, "{} probes for {}", probes.len(), path);
+        }
+    }
+
+    #[tokio::test]
+    async fn mtu_search_gives_up_when_nothing_fits() {
+        let found = search_mtu(PROBE_MTU_MIN, PROBE_MTU_MAX, |_| async { false }).await;
+        assert_eq!(found, None);
+    }
+
+    fn wg_config(endpoint: &str, public_key: &str) -> WireGuardConfig {
+        WireGuardConfig {
+            private_key: "cHJpdmF0ZQ==".to_string(),
+            public_key: public_key.to_string(),
+            preshared_key: None,
+            endpoint: endpoint.to_string(),
+            allowed_ips: "0.0.0.0/0".to_string(),
+            address: "10.0.0.2/32".to_string(),
+            dns: None,
+            pqc_handshake: None,
+            pqc_provider: None,
+            pqc_ciphertext: None,
+            obfuscation_key: None,
+            obfuscation_port: None,
+            obfuscation_cipher: None,
+        }
+    }
+
+    fn routes_address(allowed: &[String], ip: &str) -> bool {
+        let host = Cidr::host(ip.parse().unwrap());
+        allowed
+            .iter()
+            .any(|r| r.parse::<Cidr>().unwrap().contains(&host))
+    }
+
+    #[test]
+    fn allowed_ips_are_the_servers_without_split_tunneling() {
+        let mut config = wg_config("198.51.100.1:51820", "relay");
+        config.allowed_ips = "0.0.0.0/0, ::/0".to_string();
+        let settings = SettingsState {
+            excluded_ips: vec!["10.0.0.0/8".to_string()],
+            ..SettingsState::default()
+        };
+        assert_eq!(
+            tunnel_allowed_ips(&config, &config.endpoint, &settings),
+            ["0.0.0.0/0", "::/0"]
+        );
+    }
+
+    #[test]
+    fn excluded_ranges_and_the_relay_are_left_out() {
+        let mut config = wg_config("198.51.100.1:51820", "relay");
+        config.allowed_ips = "0.0.0.0/0, ::/0".to_string();
+        let mut settings = SettingsState {
+            split_tunneling: true,
+            excluded_ips: vec!["192.168.0.0/16".to_string(), "not a range".to_string()],
+            ..SettingsState::default()
+        };
+
+        let allowed = tunnel_allowed_ips(&config, &config.endpoint, &settings);
+        assert!(!routes_address(&allowed, "192.168.1.10"));
+        assert!(
+            !routes_address(&allowed, "198.51.100.1"),
+            "relay would loop"
+        );
+        assert!(routes_address(&allowed, "8.8.8.8"));
+        assert!(allowed.contains(&"::/0".to_string()));
+
+        // Nothing excluded keeps the default route, which wg-quick handles
+        // with its own rule for the relay.
+        settings.excluded_ips.clear();
+        assert_eq!(
+            tunnel_allowed_ips(&config, &config.endpoint, &settings),
+            ["0.0.0.0/0", "::/0"]
+        );
+    }
+
+    #[test]
+    fn include_only_routes_the_listed_ranges_and_tunnel_dns() {
+        let mut config = wg_config("127.0.0.1:40000", "relay");
+        config.allowed_ips = "0.0.0.0/0, ::/0".to_string();
+        config.dns = Some("10.64.0.1".to_string());
+        let mut settings = SettingsState {
+            split_tunneling: true,
+            split_mode: SplitTunnelMode::IncludeOnly,
+            included_ips: vec!["203.0.113.0/24".to_string(), "2001:db8::/32".to_string()],
+            ..SettingsState::default()
+        };
+
+        let allowed = tunnel_allowed_ips(&config, "203.0.113.9:51820", &settings);
+        assert!(routes_address(&allowed, "203.0.113.20"));
+        assert!(routes_address(&allowed, "10.64.0.1"));
+        assert!(routes_address(&allowed, "2001:db8::1"));
+        assert!(!routes_address(&allowed, "8.8.8.8"));
+        assert!(
+            !routes_address(&allowed, "203.0.113.9"),
+            "the real relay, not the local obfuscator, is kept out"
+        );
+
+        settings.custom_dns = true;
+        let allowed = tunnel_allowed_ips(&config, "203.0.113.9:51820", &settings);
+        assert!(!routes_address(&allowed, "10.64.0.1"));
+    }
+
+    #[test]
+    fn include_only_apps_keep_the_whole_tunnel() {
+        let mut config = wg_config("203.0.113.9:51820", "entry");
+        config.allowed_ips = "0.0.0.0/0, ::/0".to_string();
+        config.dns = Some("10.64.0.1".to_string());
+        let settings = SettingsState {
+            split_tunneling: true,
+            split_mode: SplitTunnelMode::IncludeOnly,
+            included_ips: vec!["203.0.113.0/24".to_string()],
+            included_apps: vec![crate::models::AppInfo {
+                name: "Firefox".to_string(),
+                path: "/usr/lib/firefox/firefox".to_string(),
+                icon: None,
+            }],
+            ..SettingsState::default()
+        };
+        assert!(settings.tunnels_only_listed_apps());
+        assert_eq!(
+            tunnel_allowed_ips(&config, &config.endpoint, &settings),
+            ["0.0.0.0/0", "::/0"]
+        );
+        assert_eq!(
+            include_only_ranges(&config, &settings),
+            [
+                "203.0.113.0/24".parse().unwrap(),
+                "10.64.0.1".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn multihop_stealth_rewrites_only_the_entry_endpoint() {
+        let entry = wg_config("198.51.100.1:51820", "entry");
+        let exit = wg_config("203.0.113.7:51820", "exit");
+        assert!(check_multihop_stealth(&entry, Some(&exit)).is_ok());
+
+        let obfuscated = with_stealth_endpoint(&entry, Some("127.0.0.1:51822".to_string()));
+        assert_eq!(obfuscated.endpoint, "127.0.0.1:51822");
+        assert_eq!(obfuscated.public_key, entry.public_key);
+        assert_eq!(exit.endpoint, "203.0.113.7:51820");
+
+        let port53 = with_stealth_endpoint(
+            &entry,
+            Some(endpoint_on_port(&entry.endpoint, STEALTH_DNS_PORT)),
+        );
+        assert_eq!(port53.endpoint, "198.51.100.1:53");
+    }
+
+    #[test]
+    fn stealth_without_obfuscator_keeps_entry_endpoint() {
+        let entry = wg_config("198.51.100.1:51820", "entry");
+        assert_eq!(with_stealth_endpoint(&entry, None).endpoint, entry.endpoint);
+    }
+
+    #[test]
+    fn multihop_stealth_rejects_same_relay_or_local_exit() {
+        let entry = wg_config("198.51.100.1:51820", "entry");
+        let same_host = wg_config("198.51.100.1:443", "other");
+        let same_key = wg_config("203.0.113.7:51820", "entry");
+        let local = wg_config("127.0.0.1:51820", "exit");
+        assert!(check_multihop_stealth(&entry, Some(&same_host)).is_err());
+        assert!(check_multihop_stealth(&entry, Some(&same_key)).is_err());
+        assert!(check_multihop_stealth(&entry, Some(&local)).is_err());
+        assert!(check_multihop_stealth(&entry, None).is_ok());
+    }
+
+    /// A network that drops UDP: tunnels only complete a handshake when they
+    /// run over TCP.
+    struct UdpBlockedRunner {
+        udp_open: bool,
+        tcp_open: bool,
+        missing_firewall_tool: Option<&'static str>,
+        mode: std::sync::Mutex<Option<StealthMode>>,
+        calls: Arc<std::sync::Mutex<Vec<String>>>,
+        /// The endpoint of each kill switch enable.
+        kill_switch_endpoints: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    impl UdpBlockedRunner {
+        fn new(udp_open: bool, tcp_open: bool) -> Self {
+            Self {
+                udp_open,
+                tcp_open,
+                missing_firewall_tool: None,
+                mode: std::sync::Mutex::new(None),
+                calls: Arc::new(std::sync::Mutex::new(Vec::new())),
+                kill_switch_endpoints: Arc::new(std::sync::Mutex::new(Vec::new())),
+            }
+        }
+
+        fn calls(&self) -> Vec<String> {
+            self.calls.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl WgRunner for UdpBlockedRunner {
+        async fn up(
+            &self,
+            entry: &WireGuardConfig,
+            _exit: Option<&WireGuardConfig>,
+            settings: &SettingsState,
+        ) -> Result<StealthPort, VpnError> {
+            let mode = settings.stealth_mode;
+            self.calls.lock().unwrap().push(format!("up {:?}", mode));
+            *self.mode.lock().unwrap() = Some(mode);
+            Ok(stealth_ports(mode, relay_port(entry, mode))[0])
+        }
+        async fn down(&self) -> Result<(), VpnError> {
+            self.calls.lock().unwrap().push("down".to_string());
+            *self.mode.lock().unwrap() = None;
+            Ok(())
+        }
+        async fn switch_stealth(
+            &self,
+            entry: &WireGuardConfig,
+            _exit: Option<&WireGuardConfig>,
+            settings: &SettingsState,
+        ) -> Result<StealthPort, VpnError> {
+            let mode = settings.stealth_mode;
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("switch {:?}", mode));
+            *self.mode.lock().unwrap() = Some(mode);
+            Ok(stealth_ports(mode, relay_port(entry, mode))[0])
+        }
+        async fn get_stats(&self) -> Result<VpnStats, VpnError> {
+            let over_tcp = *self.mode.lock().unwrap() == Some(StealthMode::Tcp);
+            let gets_through = over_tcp || self.udp_open;
+            Ok(VpnStats {
+                download_speed: 0.0,
+                upload_speed: 0.0,
+                total_download: 0,
+                total_upload: 0,
+                latest_handshake: if gets_through { unix_now() } else { 0 },
+            })
+        }
+        async fn apply_bypass_route(&self, _ip: &str) {}
+        async fn apply_app_bypass(&self, _tunnel: &WireGuardConfig, settings: &SettingsState) {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("apps {}", settings.split_apps().len()));
+        }
+        async fn apply_single_up(&self, _iface: &str, _conf: &str) -> Result<(), VpnError> {
+            Ok(())
+        }
+        async fn apply_single_down(&self, _iface: &str) {}
+        async fn enable_kill_switch(
+            &self,
+            endpoint: &str,
+            settings: &SettingsState,
+        ) -> Result<(), VpnError> {
+            self.kill_switch_endpoints
+                .lock()
+                .unwrap()
+                .push(endpoint.to_string());
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("kill switch {:?}", settings.stealth_mode));
+            match self.missing_firewall_tool {
+                Some(tool) => Err(missing_tool_error(tool)),
+                None => Ok(()),
+            }
+        }
+        async fn disable_kill_switch(&self) {}
+        async fn probe_mtu(&self, _entry: &WireGuardConfig, _settings: &SettingsState) {
+            self.calls.lock().unwrap().push("probe mtu".to_string());
+        }
+        async fn tcp_reachable(&self, _endpoint: &str, port: u16) -> bool {
+            self.tcp_open && port == STEALTH_HTTPS_PORT
+        }
+    }
+
+    async fn automatic_attempt(runner: &UdpBlockedRunner, mode: StealthMode) -> Option<String> {
+        let entry = wg_config("192.0.2.1:51820", "entry-key");
+        let settings = SettingsState {
+            stealth_mode: mode,
+            ..SettingsState::default()
+        };
+        let port = runner.up(&entry, None, &settings).await.unwrap();
+        let timeout = Duration::from_millis(20);
+        fall_back_to_tcp(runner, &entry, None, &settings, port, timeout)
+            .await
+            .unwrap()
+            .map(|port| port.to_string())
+    }
+
+    #[tokio::test]
+    async fn automatic_moves_to_tcp_when_udp_is_blocked() {
+        let runner = UdpBlockedRunner::new(false, true);
+        let port = automatic_attempt(&runner, StealthMode::Automatic).await;
+        assert_eq!(port.as_deref(), Some("TCP 443"));
+        assert_eq!(runner.calls(), ["up Automatic", "down", "up Tcp"]);
+    }
+
+    #[tokio::test]
+    async fn tunnel_is_kept_when_tcp_is_blocked_too() {
+        let runner = UdpBlockedRunner::new(false, false);
+        let port = automatic_attempt(&runner, StealthMode::Automatic).await;
+        assert_eq!(port, None);
+        assert_eq!(runner.calls(), ["up Automatic"]);
+    }
+
+    #[tokio::test]
+    async fn working_udp_and_explicit_modes_are_left_alone() {
+        let runner = UdpBlockedRunner::new(true, true);
+        let port = automatic_attempt(&runner, StealthMode::Automatic).await;
+        assert_eq!(port, None);
+        assert_eq!(runner.calls(), ["up Automatic"]);
+
+        let runner = UdpBlockedRunner::new(false, true);
+        assert_eq!(automatic_attempt(&runner, StealthMode::None).await, None);
+        assert_eq!(runner.calls(), ["up None"]);
+    }
+
+    #[test]
+    fn endpoint_on_port_handles_ipv6() {
+        assert_eq!(
+            endpoint_on_port("[2001:db8::1]:51820", STEALTH_DNS_PORT),
+            "[2001:db8::1]:53"
+        );
+        assert_eq!(
+            endpoint_on_port("vpn.example.com", STEALTH_DNS_PORT),
+            "vpn.example.com:53"
+        );
+    }
+
+    #[test]
+    fn wireguard_443_moves_only_the_port() {
+        assert_eq!(
+            endpoint_on_port("192.0.2.1:51820", STEALTH_HTTPS_PORT),
+            "192.0.2.1:443"
+        );
+        assert_eq!(
+            endpoint_on_port("[2001:db8::1]:51820", STEALTH_HTTPS_PORT),
+            "[2001:db8::1]:443"
+        );
+        assert_eq!(ports(StealthMode::WireGuard443, Some(51820)), ["UDP 443"]);
+    }
+
+    /// A service connected to Stockholm with `mode`, its recorded runner
+    /// calls and its events.
+    fn connected_with(
+        runner: UdpBlockedRunner,
+        mode: StealthMode,
+    ) -> (
+        WireGuardService,
+        Arc<std::sync::Mutex<Vec<String>>>,
+        broadcast::Receiver<VpnEvent>,
+    ) {
+        let (event_tx, events) = broadcast::channel(64);
+        let calls = runner.calls.clone();
+        let service = WireGuardService {
+            event_tx,
+            current_status: Arc::new(Mutex::new(ConnectionStatus::Connected)),
+            runner: Arc::new(Box::new(runner)),
+            active_context: Arc::new(Mutex::new(Some(ConnectionContext {
+                entry_name: "Stockholm".to_string(),
+                entry_config: wg_config("198.51.100.1:51820", "entry"),
+                exit: None,
+                settings: SettingsState {
+                    stealth_mode: mode,
+                    ..SettingsState::default()
+                },
+                account_number: None,
+                auth_token: None,
+            }))),
+            self_test: false,
+        };
+        (service, calls, events)
+    }
+
+    fn drain(events: &mut broadcast::Receiver<VpnEvent>) -> Vec<String> {
+        let mut seen = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            seen.push(match event {
+                VpnEvent::StatusChanged(status) => format!("{:?}", status),
+                VpnEvent::TransportChanged(Some(port)) => port.to_string(),
+                other => format!("{:?}", other),
+            });
+        }
+        seen
+    }
+
+    #[tokio::test]
+    async fn obfuscation_is_switched_on_the_live_tunnel() {
+        let (service, calls, mut events) =
+            connected_with(UdpBlockedRunner::new(true, true), StealthMode::Lwo);
+
+        service.switch_obfuscation(StealthMode::Quic).await.unwrap();
+
+        // The kill switch opens the new ports before the obfuscators swap,
+        // and the tunnel is never taken down.
+        assert_eq!(*calls.lock().unwrap(), ["kill switch Quic", "switch Quic"]);
+        assert_eq!(drain(&mut events), ["Connecting", "UDP 443", "Connected"]);
+        assert_eq!(service.get_status().await, ConnectionStatus::Connected);
+        let context = service.active_context.lock().await;
+        assert_eq!(
+            context.as_ref().unwrap().settings.stealth_mode,
+            StealthMode::Quic
+        );
+    }
+
+    #[tokio::test]
+    async fn switching_to_the_same_mode_or_while_disconnected_does_nothing() {
+        let (service, calls, mut events) =
+            connected_with(UdpBlockedRunner::new(true, true), StealthMode::Quic);
+        service.switch_obfuscation(StealthMode::Quic).await.unwrap();
+
+        *service.current_status.lock().await = ConnectionStatus::Disconnected;
+        service.switch_obfuscation(StealthMode::Tcp).await.unwrap();
+
+        assert!(calls.lock().unwrap().is_empty());
+        assert!(drain(&mut events).is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_switch_tears_the_tunnel_down() {
+        let mut runner = UdpBlockedRunner::new(true, true);
+        runner.missing_firewall_tool = Some("nft");
+        let (service, calls, mut events) = connected_with(runner, StealthMode::Lwo);
+
+        let result = service.switch_obfuscation(StealthMode::Tcp).await;
+
+        assert!(matches!(result, Err(VpnError::FirewallError(_))));
+        assert_eq!(*calls.lock().unwrap(), ["kill switch Tcp", "down"]);
+        let seen = drain(&mut events);
+        assert_eq!(seen.first().map(String::as_str), Some("Connecting"));
+        assert_eq!(seen.last().map(String::as_str), Some("Disconnected"));
+        assert!(service.active_context.lock().await.is_none());
+    }
+
+    fn with_apps(paths: &[&str]) -> SettingsState {
+        SettingsState {
+            split_tunneling: true,
+            excluded_apps: paths
+                .iter()
+                .map(|path| crate::models::AppInfo {
+                    name: path.to_string(),
+                    path: path.to_string(),
+                    icon: None,
+                })
+                .collect(),
+            ..SettingsState::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn split_apps_are_updated_on_the_live_tunnel() {
+        let (service, calls, mut events) =
+            connected_with(UdpBlockedRunner::new(true, true), StealthMode::Lwo);
+
+        service
+            .update_split_apps(&with_apps(&["/usr/bin/steam", "/usr/bin/discord"]))
+            .await;
+        service
+            .update_split_apps(&with_apps(&["/usr/bin/steam"]))
+            .await;
+        service
+            .update_split_apps(&with_apps(&["/usr/bin/steam"]))
+            .await;
+
+        assert_eq!(*calls.lock().unwrap(), ["apps 2", "apps 1"]);
+        assert!(drain(&mut events).is_empty());
+        let context = service.active_context.lock().await;
+        let settings = &context.as_ref().unwrap().settings;
+        assert_eq!(settings.excluded_apps.len(), 1);
+        assert_eq!(settings.stealth_mode, StealthMode::Lwo);
+    }
+
+    #[tokio::test]
+    async fn split_apps_that_change_allowed_ips_wait_for_the_next_connect() {
+        let (service, calls, _events) =
+            connected_with(UdpBlockedRunner::new(true, true), StealthMode::Lwo);
+        let mut include_only = with_apps(&[]);
+        include_only.split_mode = SplitTunnelMode::IncludeOnly;
+        include_only.included_apps = with_apps(&["/usr/bin/firefox"]).excluded_apps;
+        service.update_split_apps(&include_only).await;
+
+        *service.current_status.lock().await = ConnectionStatus::Disconnected;
+        service
+            .update_split_apps(&with_apps(&["/usr/bin/steam"]))
+            .await;
+
+        assert!(calls.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn resume_without_a_handshake_is_announced_as_a_recovery() {
+        let (event_tx, mut events) = broadcast::channel(64);
+        let service = WireGuardService {
+            event_tx,
+            current_status: Arc::new(Mutex::new(ConnectionStatus::Connected)),
+            runner: Arc::new(Box::new(UdpBlockedRunner::new(false, false))),
+            active_context: Arc::new(Mutex::new(Some(ConnectionContext {
+                entry_name: "Stockholm".to_string(),
+                entry_config: wg_config("198.51.100.1:51820", "entry"),
+                exit: None,
+                settings: SettingsState::default(),
+                account_number: None,
+                auth_token: None,
+            }))),
+            self_test: false,
+        };
+
+        assert!(service.handle_resume().await);
+
+        let mut seen = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            seen.push(event);
+        }
+        assert!(
+            matches!(
+                seen.first(),
+                Some(VpnEvent::Recovering(RecoveryCause::Resume))
+            ),
+            "{:?}",
+            seen
+        );
+        // `connect` checks for internet access before bringing the tunnel
+        // up, so it only comes back where the test machine has some.
+        let came_back = seen
+            .iter()
+            .any(|e| matches!(e, VpnEvent::StatusChanged(ConnectionStatus::Connected)));
+        let recovered = seen.iter().any(|e| {
+            matches!(
+                e,
+                VpnEvent::Recovered {
+                    cause: RecoveryCause::Resume,
+                    new_server: None
+                }
+            )
+        });
+        assert_eq!(recovered, came_back, "{:?}", seen);
+    }
+
+    #[tokio::test]
+    async fn lockdown_reasserted_on_resume_keeps_the_relay_allowed() {
+        let runner = UdpBlockedRunner::new(true, true);
+        let endpoints = runner.kill_switch_endpoints.clone();
+        let (service, calls, _events) = connected_with(runner, StealthMode::Tcp);
+        if let Some(ctx) = service.active_context.lock().await.as_mut() {
+            ctx.settings.lockdown_mode = true;
+        }
+
+        // The handshake is fresh, so the tunnel is kept.
+        assert!(!service.handle_resume().await);
+        assert_eq!(*endpoints.lock().unwrap(), ["198.51.100.1:51820"]);
+        assert_eq!(*calls.lock().unwrap(), ["kill switch Tcp"]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn restoring_dns_writes_the_original_resolv_conf_back() {
+        let path = std::env::temp_dir().join(format!("marinvpn-resolv-{}", std::process::id()));
+        std::fs::write(&path, "# Generated by MarinVPN\nnameserver 10.64.0.1\n").unwrap();
+        let mut runner = RealWgRunner::new();
+        runner.resolv_conf = path.clone();
+        runner.state.lock().await.original_resolv_conf =
+            Some("nameserver 192.168.1.1\n".to_string());
+
+        runner.restore_dns().await;
+
+        let restored = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(restored, "nameserver 192.168.1.1\n");
+        assert!(runner.state.lock().await.original_resolv_conf.is_none());
+    }
+
+    #[tokio::test]
+    async fn dns_is_restored_when_bringing_the_interface_down_panics() {
+        let steps = std::sync::Mutex::new(Vec::new());
+        let teardown = async {
+            steps.lock().unwrap().push("interface down");
+            panic!("wg-quick went away");
+        };
+        let cleanup = async {
+            steps.lock().unwrap().push("restore dns");
+        };
+
+        let result = AssertUnwindSafe(with_cleanup(teardown, cleanup))
+            .catch_unwind()
+            .await;
+
+        assert!(result.is_err(), "the panic is not swallowed");
+        assert_eq!(*steps.lock().unwrap(), ["interface down", "restore dns"]);
+    }
+
+    #[tokio::test]
+    async fn cleanup_follows_a_teardown_that_went_fine() {
+        let steps = std::sync::Mutex::new(Vec::new());
+        with_cleanup(
+            async { steps.lock().unwrap().push("interface down") },
+            async { steps.lock().unwrap().push("restore dns") },
+        )
+        .await;
+        assert_eq!(*steps.lock().unwrap(), ["interface down", "restore dns"]);
+    }
+
+    #[tokio::test]
+    async fn firewall_tools_that_are_not_installed_are_named() {
+        #[cfg(unix)]
+        assert!(check_firewall_tools(&[("sh", &["-c", "exit 3"])])
+            .await
+            .is_ok());
+
+        let err = check_firewall_tools(&[("marinvpn-no-such-tool", &[])])
+            .await
+            .unwrap_err();
+        assert!(matches!(err, VpnError::FirewallError(_)));
+        assert!(err
+            .to_string()
+            .contains("marinvpn-no-such-tool is not installed"));
+        assert!(missing_tool_error("nft")
+            .to_string()
+            .contains("nftables (nft) is not installed"));
+    }
+
+    #[tokio::test]
+    async fn a_self_test_connects_and_disconnects_without_the_network() {
+        let (event_tx, mut events) = broadcast::channel(64);
+        let runner = UdpBlockedRunner::new(true, true);
+        let calls = runner.calls.clone();
+        let service = WireGuardService {
+            event_tx,
+            current_status: Arc::new(Mutex::new(ConnectionStatus::Disconnected)),
+            runner: Arc::new(Box::new(runner)),
+            active_context: Arc::new(Mutex::new(None)),
+            self_test: true,
+        };
+        // On a real tunnel both would send traffic to public hosts.
+        let settings = SettingsState {
+            stealth_mode: StealthMode::None,
+            daita_enabled: true,
+            reconnect_policy: ReconnectPolicy::Aggressive,
+            ..SettingsState::default()
+        };
+
+        let entry = wg_config("192.0.2.1:51820", "entry");
+        service
+            .connect("Self-test".to_string(), entry, None, settings, None)
+            .await;
+        assert_eq!(service.get_status().await, ConnectionStatus::Connected);
+        service.disconnect().await;
+
+        assert_eq!(
+            *calls.lock().unwrap(),
+            ["probe mtu", "kill switch None", "up None", "down"]
+        );
+        let mut statuses = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            if let VpnEvent::StatusChanged(status) = event {
+                statuses.push(status);
+            }
+        }
+        assert_eq!(
+            statuses,
+            [
+                ConnectionStatus::Connecting,
+                ConnectionStatus::Connected,
+                ConnectionStatus::Disconnecting,
+                ConnectionStatus::Disconnected,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn lockdown_reapplied_while_connected_keeps_the_relay_allowed() {
+        let runner = UdpBlockedRunner::new(true, true);
+        let endpoints = runner.kill_switch_endpoints.clone();
+        let (service, calls, _events) = connected_with(runner, StealthMode::WireGuard443);
+        // Any settings change re-applies lockdown, whatever the stored mode.
+        let settings = SettingsState {
+            lockdown_mode: true,
+            stealth_mode: StealthMode::None,
+            ..SettingsState::default()
+        };
+
+        service.apply_lockdown(&settings).await.unwrap();
+
+        let endpoint = endpoints.lock().unwrap()[0].clone();
+        assert_eq!(endpoint, "198.51.100.1:51820");
+        assert_eq!(*calls.lock().unwrap(), ["kill switch WireGuard443"]);
+        assert_eq!(
+            kill_switch_allow_rules(&endpoint, StealthMode::WireGuard443),
+            [("udp", 443)]
+        );
+
+        *service.current_status.lock().await = ConnectionStatus::Disconnected;
+        service.apply_lockdown(&settings).await.unwrap();
+        assert_eq!(endpoints.lock().unwrap()[1], "0.0.0.0");
+    }
+
+    #[test]
+    fn reenabling_the_windows_kill_switch_keeps_the_doh_blocks() {
+        let removal = windows_kill_switch_removal();
+        assert!(removal.starts_with("Remove-NetFirewallRule -DisplayName 'MarinVPN - Allow"));
+        assert!(!removal.contains("'MarinVPN*'"));
+        let doh_block = "MarinVPN - Block DoH Ethernet 1.1.1.1";
+        for pattern in WINDOWS_KILL_SWITCH_RULES {
+            assert!(
+                !doh_block.starts_with(pattern.trim_end_matches('*')),
+                "{}",
+                pattern
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn lockdown_fails_when_the_firewall_tool_is_missing() {
+        let (event_tx, _events) = broadcast::channel(8);
+        let mut runner = UdpBlockedRunner::new(true, true);
+        runner.missing_firewall_tool = Some("nft");
+        let service = WireGuardService {
+            event_tx,
+            current_status: Arc::new(Mutex::new(ConnectionStatus::Disconnected)),
+            runner: Arc::new(Box::new(runner)),
+            active_context: Arc::new(Mutex::new(None)),
+            self_test: false,
+        };
+        let settings = SettingsState {
+            lockdown_mode: true,
+            ..SettingsState::default()
+        };
+
+        let err = service.apply_lockdown(&settings).await.unwrap_err();
+        assert_eq!(err.kind(), "firewall_error");
+        assert!(err.to_string().contains("nftables (nft)"), "{}", err);
+    }
+
+    #[test]
+    fn kill_switch_opens_only_the_modes_ports() {
+        assert_eq!(
+            kill_switch_allow_rules("192.0.2.1:51820", StealthMode::WireGuard443),
+            [("udp", 443)]
+        );
+        assert_eq!(
+            kill_switch_allow_rules("192.0.2.1:4000", StealthMode::None),
+            [("udp", 4000)]
+        );
+        assert_eq!(
+            kill_switch_allow_rules("192.0.2.1", StealthMode::Shadowsocks),
+            [("udp", 8388), ("tcp", 8388)]
+        );
+    }
+
+    #[test]
+    fn shadowsocks_dials_the_relays_own_port() {
+        let mut entry = WireGuardConfig::default();
+        entry.endpoint = "192.0.2.1:51820".to_string();
+        entry.obfuscation_port = Some(8443);
+        assert_eq!(relay_port(&entry, StealthMode::Shadowsocks), Some(8443));
+        assert_eq!(relay_port(&entry, StealthMode::None), Some(51820));
+        assert_eq!(
+            kill_switch_allow_rules(
+                &kill_switch_endpoint(&entry, StealthMode::Shadowsocks),
+                StealthMode::Shadowsocks
+            ),
+            [("udp", 8443), ("tcp", 8443)]
+        );
+        assert_eq!(
+            kill_switch_endpoint(&entry, StealthMode::Automatic),
+            "192.0.2.1:51820"
+        );
+    }
+
+    #[test]
+    fn shadowsocks_ciphers_come_from_the_allowlist() {
+        let mut entry = WireGuardConfig::default();
+        assert_eq!(shadowsocks_cipher(&entry).unwrap(), "aes-256-gcm");
+        entry.obfuscation_cipher = Some("chacha20-ietf-poly1305".to_string());
+        assert_eq!(
+            shadowsocks_cipher(&entry).unwrap(),
+            "chacha20-ietf-poly1305"
+        );
+        entry.obfuscation_cipher = Some("rc4-md5".to_string());
+        let err = shadowsocks_cipher(&entry).unwrap_err();
+        assert_eq!(err.kind(), "config_missing");
+        assert!(err.to_string().contains("rc4-md5"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn unsupported_shadowsocks_cipher_fails_before_spawning() {
+        let mut entry = WireGuardConfig::default();
+        entry.endpoint = "192.0.2.1:51820".to_string();
+        entry.obfuscation_key = Some("b2JmdXNjYXRpb24=".to_string());
+        entry.obfuscation_cipher = Some("2022-blake3-aes-256-gcm".to_string());
+        let err = SsObfuscator::new().start(&entry).await.unwrap_err();
+        assert_eq!(err.kind(), "config_missing");
+    }
+
+    fn ports(mode: StealthMode, relay_port: Option<u16>) -> Vec<String> {
+        stealth_ports(mode, relay_port)
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn stealth_ports_match_what_each_mode_dials() {
+        assert_eq!(ports(StealthMode::None, Some(4000)), ["UDP 4000"]);
+        assert_eq!(ports(StealthMode::Lwo, None), ["UDP 51820"]);
+        assert_eq!(ports(StealthMode::WireGuardPort, Some(4000)), ["UDP 53"]);
+        assert_eq!(ports(StealthMode::Quic, Some(4000)), ["UDP 443"]);
+        assert_eq!(ports(StealthMode::Tcp, Some(4000)), ["TCP 443"]);
+        assert_eq!(
+            ports(StealthMode::Shadowsocks, None),
+            ["UDP 8388", "TCP 8388"]
+        );
+        assert_eq!(
+            ports(StealthMode::Shadowsocks, Some(4000)),
+            ["UDP 4000", "TCP 4000"]
+        );
+        assert_eq!(
+            ports(StealthMode::Automatic, Some(4000)),
+            ["UDP 4000", "UDP 443", "TCP 443"]
+        );
+    }
+
+    #[test]
+    fn port_hint_lists_every_port_for_the_default_relay() {
+        assert_eq!(stealth_port_hint(StealthMode::Quic), "UDP 443");
+        assert_eq!(
+            stealth_port_hint(StealthMode::Automatic),
+            "UDP 51820, UDP 443, TCP 443"
+        );
+    }
+
+    #[test]
+    fn endpoint_port_is_only_read_when_named() {
+        assert_eq!(endpoint_port("192.0.2.1:4000"), Some(4000));
+        assert_eq!(endpoint_port("[2001:db8::1]:4000"), Some(4000));
+        assert_eq!(endpoint_port("vpn.example.com"), None);
+    }
+
+    #[test]
+    fn resume_gap_ignores_normal_scheduling_jitter() {
+        let poll = Duration::from_secs(RESUME_POLL_SECS);
+        assert!(!is_resume_gap(poll, poll + Duration::from_secs(2)));
+        assert!(is_resume_gap(poll, Duration::from_secs(600)));
+    }
 }
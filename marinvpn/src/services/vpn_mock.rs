@@ -0,0 +1,145 @@
+use crate::models::{ConnectionStatus, SettingsState, StealthMode, WireGuardConfig};
+use crate::services::vpn::{VpnError, VpnEvent, VpnService};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+/// What the UI asked the backend to do, in order.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MockCall {
+    Connect { entry: String, exit: Option<String> },
+    Disconnect,
+    EnableCaptivePortal(u64),
+    ApplyLockdown,
+    DisableKillSwitch,
+    SwitchObfuscation(StealthMode),
+    /// The split tunneling apps listed when the update was sent.
+    UpdateSplitApps(Vec<String>),
+}
+
+type ConnectScript = Box<dyn Fn(&SettingsState) -> Vec<VpnEvent> + Send + Sync>;
+
+/// A `VpnService` with no side effects. Calls are only recorded; nothing
+/// changes until the test pushes events with [`MockVpnService::emit`], so
+/// every status transition the UI sees is one the test chose.
+#[derive(Clone)]
+pub struct MockVpnService {
+    tx: broadcast::Sender<VpnEvent>,
+    status: Arc<Mutex<ConnectionStatus>>,
+    calls: Arc<Mutex<Vec<MockCall>>>,
+    on_connect: Arc<Mutex<Option<ConnectScript>>>,
+}
+
+impl MockVpnService {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(32);
+        Self {
+            tx,
+            status: Arc::new(Mutex::new(ConnectionStatus::Disconnected)),
+            calls: Arc::new(Mutex::new(Vec::new())),
+            on_connect: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Makes every `connect` emit the events `script` returns for the
+    /// settings it was called with, for tests that drive whole attempts.
+    pub fn on_connect(
+        &self,
+        script: impl Fn(&SettingsState) -> Vec<VpnEvent> + Send + Sync + 'static,
+    ) {
+        *self.on_connect.lock().unwrap() = Some(Box::new(script));
+    }
+
+    pub fn emit(&self, event: VpnEvent) {
+        if let VpnEvent::StatusChanged(status) = &event {
+            *self.status.lock().unwrap() = *status;
+        }
+        let _ = self.tx.send(event);
+    }
+
+    pub fn calls(&self) -> Vec<MockCall> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    pub fn subscribers(&self) -> usize {
+        self.tx.receiver_count()
+    }
+
+    fn record(&self, call: MockCall) {
+        self.calls.lock().unwrap().push(call);
+    }
+}
+
+impl Default for MockVpnService {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PartialEq for MockVpnService {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.calls, &other.calls)
+    }
+}
+
+#[async_trait::async_trait]
+impl VpnService for MockVpnService {
+    fn subscribe(&self) -> broadcast::Receiver<VpnEvent> {
+        self.tx.subscribe()
+    }
+
+    async fn connect(
+        &self,
+        entry: String,
+        _entry_config: WireGuardConfig,
+        exit: Option<(String, WireGuardConfig)>,
+        settings: SettingsState,
+        _auth: Option<(String, String)>,
+    ) {
+        self.record(MockCall::Connect {
+            entry,
+            exit: exit.map(|(name, _)| name),
+        });
+        let events = match &*self.on_connect.lock().unwrap() {
+            Some(script) => script(&settings),
+            None => Vec::new(),
+        };
+        for event in events {
+            self.emit(event);
+        }
+    }
+
+    async fn disconnect(&self) {
+        self.record(MockCall::Disconnect);
+    }
+
+    async fn get_status(&self) -> ConnectionStatus {
+        *self.status.lock().unwrap()
+    }
+
+    async fn enable_captive_portal(&self, duration_secs: u64) {
+        self.record(MockCall::EnableCaptivePortal(duration_secs));
+    }
+
+    async fn apply_lockdown(&self, _settings: &SettingsState) -> Result<(), VpnError> {
+        self.record(MockCall::ApplyLockdown);
+        Ok(())
+    }
+
+    async fn disable_kill_switch(&self) {
+        self.record(MockCall::DisableKillSwitch);
+    }
+
+    async fn switch_obfuscation(&self, mode: StealthMode) -> Result<(), VpnError> {
+        self.record(MockCall::SwitchObfuscation(mode));
+        Ok(())
+    }
+
+    async fn update_split_apps(&self, settings: &SettingsState) {
+        let apps = settings
+            .split_apps()
+            .iter()
+            .map(|a| a.path.clone())
+            .collect();
+        self.record(MockCall::UpdateSplitApps(apps));
+    }
+}
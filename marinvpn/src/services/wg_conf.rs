@@ -0,0 +1,451 @@
+use crate::error::AppError;
+use crate::models::WireGuardConfig;
+use crate::services::cidr::{self, Cidr};
+use std::fmt::Write;
+use std::net::IpAddr;
+
+const DEFAULT_KEEPALIVE_SECS: u16 = 25;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InterfaceSection {
+    pub private_key: String,
+    pub address: String,
+    pub dns: Option<String>,
+    pub mtu: Option<u32>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerSection {
+    pub public_key: String,
+    pub preshared_key: Option<String>,
+    pub endpoint: Option<String>,
+    pub allowed_ips: Vec<String>,
+    pub persistent_keepalive: Option<u16>,
+}
+
+impl PeerSection {
+    pub fn new(public_key: impl Into<String>) -> Self {
+        Self {
+            public_key: public_key.into(),
+            preshared_key: None,
+            endpoint: None,
+            allowed_ips: Vec::new(),
+            persistent_keepalive: None,
+        }
+    }
+
+    /// The single server peer described by an API config.
+    pub fn from_config(config: &WireGuardConfig) -> Self {
+        Self::new(&config.public_key)
+            .preshared_key(config.preshared_key.clone())
+            .endpoint(&config.endpoint)
+            .allowed_ips([&config.allowed_ips])
+            .persistent_keepalive(DEFAULT_KEEPALIVE_SECS)
+    }
+
+    pub fn preshared_key(mut self, psk: Option<String>) -> Self {
+        self.preshared_key = psk.filter(|k| !k.trim().is_empty());
+        self
+    }
+
+    pub fn endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.endpoint = Some(endpoint.into());
+        self
+    }
+
+    /// Accepts either individual entries or the comma separated form the API
+    /// returns; both end up as one normalized list.
+    pub fn allowed_ips<I, S>(mut self, ips: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.allowed_ips = ips
+            .into_iter()
+            .flat_map(|s| {
+                s.as_ref()
+                    .split(',')
+                    .map(|p| p.trim().to_string())
+                    .filter(|p| !p.is_empty())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        self
+    }
+
+    pub fn persistent_keepalive(mut self, secs: u16) -> Self {
+        self.persistent_keepalive = Some(secs);
+        self
+    }
+}
+
+/// A wg-quick configuration file. Every `.conf` the client writes goes through
+/// [`WgConfFile::render`] so key ordering and optional lines stay consistent.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WgConfFile {
+    pub interface: InterfaceSection,
+    pub peers: Vec<PeerSection>,
+}
+
+impl WgConfFile {
+    pub fn new(private_key: impl Into<String>, address: impl Into<String>) -> Self {
+        Self {
+            interface: InterfaceSection {
+                private_key: private_key.into(),
+                address: address.into(),
+                dns: None,
+                mtu: None,
+            },
+            peers: Vec::new(),
+        }
+    }
+
+    /// Single interface with one peer, exactly as the server handed it out.
+    pub fn from_config(config: &WireGuardConfig) -> Self {
+        Self::new(&config.private_key, &config.address)
+            .dns(config.dns.clone())
+            .peer(PeerSection::from_config(config))
+    }
+
+    pub fn dns(mut self, dns: Option<String>) -> Self {
+        self.interface.dns = dns.filter(|d| !d.trim().is_empty());
+        self
+    }
+
+    pub fn mtu(mut self, mtu: u32) -> Self {
+        self.interface.mtu = Some(mtu);
+        self
+    }
+
+    pub fn peer(mut self, peer: PeerSection) -> Self {
+        self.peers.push(peer);
+        self
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let iface = &self.interface;
+
+        let _ = writeln!(out, "[Interface]");
+        let _ = writeln!(out, "PrivateKey = {}", iface.private_key);
+        let _ = writeln!(out, "Address = {}", iface.address);
+        if let Some(ref dns) = iface.dns {
+            let _ = writeln!(out, "DNS = {}", dns);
+        }
+        if let Some(mtu) = iface.mtu {
+            let _ = writeln!(out, "MTU = {}", mtu);
+        }
+
+        for peer in &self.peers {
+            let _ = writeln!(out);
+            let _ = writeln!(out, "[Peer]");
+            let _ = writeln!(out, "PublicKey = {}", peer.public_key);
+            if let Some(ref psk) = peer.preshared_key {
+                let _ = writeln!(out, "PresharedKey = {}", psk);
+            }
+            if let Some(ref endpoint) = peer.endpoint {
+                let _ = writeln!(out, "Endpoint = {}", endpoint);
+            }
+            if !peer.allowed_ips.is_empty() {
+                let _ = writeln!(out, "AllowedIPs = {}", peer.allowed_ips.join(", "));
+            }
+            if let Some(keepalive) = peer.persistent_keepalive {
+                let _ = writeln!(out, "PersistentKeepalive = {}", keepalive);
+            }
+        }
+
+        out
+    }
+}
+
+/// Interface keys only wg-quick understands. `wg setconf` refuses them.
+const WG_QUICK_KEYS: &[&str] = &[
+    "address",
+    "dns",
+    "mtu",
+    "table",
+    "preup",
+    "postup",
+    "predown",
+    "postdown",
+    "saveconfig",
+];
+
+/// `conf` without the wg-quick keys, as `wg-quick strip` prints it, for
+/// `wg setconf` to apply to an interface that is already up.
+pub fn strip(conf: &str) -> String {
+    conf.lines()
+        .filter(|line| {
+            let key = line.split_once('=').map_or("", |(key, _)| key);
+            !WG_QUICK_KEYS.contains(&key.trim().to_ascii_lowercase().as_str())
+        })
+        .fold(String::new(), |mut out, line| {
+            let _ = writeln!(out, "{}", line);
+            out
+        })
+}
+
+/// Checks the fields of an API config that go into the `.conf` as is, so a
+/// malformed one is reported by name rather than by `wg-quick`.
+pub fn validate_server_config(config: &WireGuardConfig) -> Result<(), AppError> {
+    let invalid = |field: &str, value: &str| {
+        AppError::Config(format!("Server sent an invalid {}: {:?}", field, value))
+    };
+    let routable = |range: &Cidr| {
+        let ip = range.addr();
+        !ip.is_unspecified() && !ip.is_loopback() && !ip.is_multicast()
+    };
+    if !cidr::parse_list(&config.address).is_ok_and(|list| list.iter().all(routable)) {
+        return Err(invalid("address", &config.address));
+    }
+    if !is_valid_endpoint(&config.endpoint) {
+        return Err(invalid("endpoint", &config.endpoint));
+    }
+    if cidr::parse_list(&config.allowed_ips).is_err() {
+        return Err(invalid("allowed_ips", &config.allowed_ips));
+    }
+    Ok(())
+}
+
+/// `host:port` with an IPv4 address or host name, or `[ipv6]:port`.
+fn is_valid_endpoint(endpoint: &str) -> bool {
+    let Some((host, port)) = endpoint.rsplit_once(':') else {
+        return false;
+    };
+    if !port.parse::<u16>().is_ok_and(|port| port != 0) {
+        return false;
+    }
+    let ip = match host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        Some(v6) => v6.parse::<std::net::Ipv6Addr>().ok().map(IpAddr::V6),
+        None if host.contains(':') => return false,
+        None => host.parse::<std::net::Ipv4Addr>().ok().map(IpAddr::V4),
+    };
+    match ip {
+        Some(ip) => !ip.is_unspecified() && !ip.is_multicast(),
+        None => is_host_name(host),
+    }
+}
+
+fn is_host_name(host: &str) -> bool {
+    !host.is_empty()
+        && host.len() <= 253
+        && !host.chars().all(|c| c.is_ascii_digit() || c == '.')
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(psk: Option<&str>) -> WireGuardConfig {
+        WireGuardConfig {
+            private_key: "cHJpdmF0ZQ==".to_string(),
+            public_key: "cHVibGlj".to_string(),
+            preshared_key: psk.map(str::to_string),
+            endpoint: "203.0.113.10:51820".to_string(),
+            allowed_ips: "0.0.0.0/0, ::/0".to_string(),
+            address: "10.0.0.2/32".to_string(),
+            dns: Some("10.64.0.1".to_string()),
+            pqc_handshake: None,
+            pqc_provider: None,
+            pqc_ciphertext: None,
+            obfuscation_key: None,
+            obfuscation_port: None,
+            obfuscation_cipher: None,
+        }
+    }
+
+    #[test]
+    fn single_hop_renders_known_good_fixture() {
+        let rendered = WgConfFile::from_config(&config(None)).mtu(1280).render();
+        let expected = "\
+[Interface]
+PrivateKey = cHJpdmF0ZQ==
+Address = 10.0.0.2/32
+DNS = 10.64.0.1
+MTU = 1280
+
+[Peer]
+PublicKey = cHVibGlj
+Endpoint = 203.0.113.10:51820
+AllowedIPs = 0.0.0.0/0, ::/0
+PersistentKeepalive = 25
+";
+        assert_eq!(rendered, expected);
+    }
+
+    #[test]
+    fn pqc_psk_is_rendered_inside_peer_section() {
+        let rendered = WgConfFile::from_config(&config(Some("cHNr")))
+            .mtu(1280)
+            .render();
+        let expected = "\
+[Interface]
+PrivateKey = cHJpdmF0ZQ==
+Address = 10.0.0.2/32
+DNS = 10.64.0.1
+MTU = 1280
+
+[Peer]
+PublicKey = cHVibGlj
+PresharedKey = cHNr
+Endpoint = 203.0.113.10:51820
+AllowedIPs = 0.0.0.0/0, ::/0
+PersistentKeepalive = 25
+";
+        assert_eq!(rendered, expected);
+    }
+
+    #[test]
+    fn multihop_entry_renders_without_dns_and_keeps_psk() {
+        let entry = config(Some("cHNr"));
+        let conf = WgConfFile::new(&entry.private_key, &entry.address)
+            .mtu(1320)
+            .peer(PeerSection::from_config(&entry).allowed_ips(["10.0.0.2/32", "198.51.100.7/32"]));
+        let expected = "\
+[Interface]
+PrivateKey = cHJpdmF0ZQ==
+Address = 10.0.0.2/32
+MTU = 1320
+
+[Peer]
+PublicKey = cHVibGlj
+PresharedKey = cHNr
+Endpoint = 203.0.113.10:51820
+AllowedIPs = 10.0.0.2/32, 198.51.100.7/32
+PersistentKeepalive = 25
+";
+        assert_eq!(conf.render(), expected);
+    }
+
+    #[test]
+    fn multiple_peers_are_separated_by_blank_lines() {
+        let rendered = WgConfFile::new("a2V5", "10.0.0.3/32")
+            .peer(PeerSection::new("cGVlcjE=").allowed_ips(["10.1.0.0/16"]))
+            .peer(
+                PeerSection::new("cGVlcjI=")
+                    .endpoint("198.51.100.7:51820")
+                    .allowed_ips(["10.2.0.0/16"]),
+            )
+            .render();
+        let expected = "\
+[Interface]
+PrivateKey = a2V5
+Address = 10.0.0.3/32
+
+[Peer]
+PublicKey = cGVlcjE=
+AllowedIPs = 10.1.0.0/16
+
+[Peer]
+PublicKey = cGVlcjI=
+Endpoint = 198.51.100.7:51820
+AllowedIPs = 10.2.0.0/16
+";
+        assert_eq!(rendered, expected);
+    }
+
+    #[test]
+    fn strip_keeps_only_what_wg_setconf_takes() {
+        let conf = WgConfFile::new("priv", "10.64.0.2/32")
+            .dns(Some("10.64.0.1".to_string()))
+            .mtu(1280)
+            .peer(
+                PeerSection::new("pub")
+                    .endpoint("127.0.0.1:51821")
+                    .persistent_keepalive(25),
+            )
+            .render();
+        assert_eq!(
+            strip(&conf),
+            "[Interface]\nPrivateKey = priv\n\n[Peer]\nPublicKey = pub\n\
+             Endpoint = 127.0.0.1:51821\nPersistentKeepalive = 25\n"
+        );
+    }
+
+    #[test]
+    fn api_config_fields_are_accepted() {
+        assert!(validate_server_config(&config(None)).is_ok());
+
+        let mut dual_stack = config(None);
+        dual_stack.address = "10.0.0.2/32, fd00::2/128".to_string();
+        dual_stack.endpoint = "[2001:db8::10]:51820".to_string();
+        assert!(validate_server_config(&dual_stack).is_ok());
+
+        let mut named = config(None);
+        named.endpoint = "se-sto-01.relays.example:51820".to_string();
+        named.allowed_ips = "10.64.0.1".to_string();
+        assert!(validate_server_config(&named).is_ok());
+    }
+
+    fn rejected_field(change: impl FnOnce(&mut WireGuardConfig)) -> String {
+        let mut config = config(None);
+        change(&mut config);
+        match validate_server_config(&config) {
+            Err(AppError::Config(message)) => message,
+            other => panic!("expected a config error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn malformed_address_is_named() {
+        for address in [
+            "",
+            "10.0.0",
+            "10.0.0.2/33",
+            "fd00::2/129",
+            "0.0.0.0/32",
+            "10.0.0.2/32,",
+        ] {
+            let message = rejected_field(|c| c.address = address.to_string());
+            assert!(
+                message.contains("invalid address"),
+                "{}: {}",
+                address,
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_endpoint_is_named() {
+        for endpoint in [
+            "",
+            "203.0.113.10",
+            "203.0.113.10:0",
+            "203.0.113.10:70000",
+            "2001:db8::10:51820",
+            "[2001:db8::10]",
+            "0.0.0.0:51820",
+            "203.0.113:51820",
+            "bad host:51820",
+        ] {
+            let message = rejected_field(|c| c.endpoint = endpoint.to_string());
+            assert!(
+                message.contains("invalid endpoint"),
+                "{}: {}",
+                endpoint,
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_allowed_ips_are_named() {
+        for allowed_ips in ["", "0.0.0.0/0; ::/0", "0.0.0.0/-1", "everything"] {
+            let message = rejected_field(|c| c.allowed_ips = allowed_ips.to_string());
+            assert!(
+                message.contains("invalid allowed_ips"),
+                "{}: {}",
+                allowed_ips,
+                message
+            );
+        }
+    }
+}
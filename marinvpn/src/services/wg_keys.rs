@@ -0,0 +1,119 @@
+use crate::models::KeyReuse;
+use boringtun::x25519::StaticSecret;
+use rand::thread_rng;
+use std::sync::Mutex;
+use std::time::Instant;
+
+struct CachedPair {
+    location: String,
+    secret: StaticSecret,
+    created_at: Instant,
+}
+
+/// WireGuard private keys per location, for [`KeyReuse::Within`]. A key is
+/// reused until its window has passed since it was made, not since it was
+/// last used, so a client that keeps reconnecting still rotates keys.
+pub struct KeyPairCache {
+    entries: Mutex<Vec<CachedPair>>,
+}
+
+impl KeyPairCache {
+    pub const fn new() -> Self {
+        Self {
+            entries: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// The key to request a config for `location` with. With
+    /// [`KeyReuse::Fresh`] this is always a new key and every held key is
+    /// dropped.
+    pub fn get(&self, location: &str, reuse: KeyReuse) -> StaticSecret {
+        self.get_at(Instant::now(), location, reuse)
+    }
+
+    /// Drops the key held for `location`, e.g. after the server refused it.
+    pub fn forget(&self, location: &str) {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|entry| entry.location != location);
+    }
+
+    fn get_at(&self, now: Instant, location: &str, reuse: KeyReuse) -> StaticSecret {
+        let mut entries = self.entries.lock().unwrap();
+        let Some(window) = reuse.window() else {
+            entries.clear();
+            return StaticSecret::random_from_rng(thread_rng());
+        };
+
+        entries.retain(|entry| now.saturating_duration_since(entry.created_at) < window);
+        if let Some(entry) = entries.iter().find(|entry| entry.location == location) {
+            return entry.secret.clone();
+        }
+        let secret = StaticSecret::random_from_rng(thread_rng());
+        entries.push(CachedPair {
+            location: location.to_string(),
+            secret: secret.clone(),
+            created_at: now,
+        });
+        secret
+    }
+}
+
+impl Default for KeyPairCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use boringtun::x25519::PublicKey;
+    use std::time::Duration;
+
+    fn public(secret: &StaticSecret) -> [u8; 32] {
+        PublicKey::from(secret).to_bytes()
+    }
+
+    #[test]
+    fn fresh_is_the_default_and_never_repeats() {
+        assert_eq!(KeyReuse::default(), KeyReuse::Fresh);
+        let cache = KeyPairCache::new();
+        let first = cache.get("Sweden, Stockholm", KeyReuse::default());
+        let second = cache.get("Sweden, Stockholm", KeyReuse::default());
+        assert_ne!(public(&first), public(&second));
+    }
+
+    #[test]
+    fn reuses_a_location_key_within_the_window() {
+        let cache = KeyPairCache::new();
+        let reuse = KeyReuse::Within(600);
+        let start = Instant::now();
+
+        let first = cache.get_at(start, "Sweden, Stockholm", reuse);
+        let again = cache.get_at(start + Duration::from_secs(599), "Sweden, Stockholm", reuse);
+        assert_eq!(public(&first), public(&again));
+
+        let elsewhere = cache.get_at(start, "Germany, Frankfurt", reuse);
+        assert_ne!(public(&first), public(&elsewhere));
+
+        let expired = cache.get_at(start + Duration::from_secs(600), "Sweden, Stockholm", reuse);
+        assert_ne!(public(&first), public(&expired));
+    }
+
+    #[test]
+    fn forgetting_or_switching_to_fresh_drops_held_keys() {
+        let cache = KeyPairCache::new();
+        let reuse = KeyReuse::Within(600);
+
+        let first = cache.get("Sweden, Stockholm", reuse);
+        cache.forget("Sweden, Stockholm");
+        let second = cache.get("Sweden, Stockholm", reuse);
+        assert_ne!(public(&first), public(&second));
+
+        cache.get("Sweden, Stockholm", KeyReuse::Fresh);
+        let third = cache.get("Sweden, Stockholm", reuse);
+        assert_ne!(public(&second), public(&third));
+    }
+}
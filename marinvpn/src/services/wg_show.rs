@@ -0,0 +1,212 @@
+/// Parsed output of `wg show <iface> dump`.
+///
+/// The dump format is wg's scripting interface: one tab-separated line for
+/// the interface, then one per peer, with `(none)`, `off` and `0` standing in
+/// for unset values. It is stable across wg versions, unlike the
+/// human-readable output.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WgShow {
+    pub interface: InterfaceDump,
+    pub peers: Vec<PeerDump>,
+}
+
+/// The interface line. Its private key is dropped during parsing so it is
+/// never held outside the kernel and the config file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InterfaceDump {
+    pub public_key: String,
+    pub listen_port: u16,
+    pub fwmark: Option<u32>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerDump {
+    pub public_key: String,
+    pub endpoint: Option<String>,
+    pub allowed_ips: Vec<String>,
+    /// Unix time of the last handshake; `None` before the first one.
+    pub latest_handshake: Option<u64>,
+    pub transfer_rx: u64,
+    pub transfer_tx: u64,
+    pub persistent_keepalive: Option<u16>,
+}
+
+impl WgShow {
+    pub fn parse(dump: &str) -> Result<Self, String> {
+        let mut lines = dump.lines().filter(|l| !l.trim().is_empty());
+        let interface = match lines.next() {
+            Some(line) => parse_interface(line)?,
+            None => return Err("wg dump is empty".to_string()),
+        };
+        let peers = lines.map(parse_peer).collect::<Result<_, _>>()?;
+        Ok(Self { interface, peers })
+    }
+
+    /// Bytes received from all peers.
+    pub fn total_rx(&self) -> u64 {
+        self.peers
+            .iter()
+            .fold(0, |sum, p| sum.saturating_add(p.transfer_rx))
+    }
+
+    /// Bytes sent to all peers.
+    pub fn total_tx(&self) -> u64 {
+        self.peers
+            .iter()
+            .fold(0, |sum, p| sum.saturating_add(p.transfer_tx))
+    }
+
+    /// The most recent handshake with any peer.
+    pub fn latest_handshake(&self) -> Option<u64> {
+        self.peers.iter().filter_map(|p| p.latest_handshake).max()
+    }
+}
+
+fn fields<'a>(line: &'a str, kind: &str, count: usize) -> Result<Vec<&'a str>, String> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    if fields.len() != count {
+        return Err(format!(
+            "wg dump {} line has {} fields, expected {}",
+            kind,
+            fields.len(),
+            count
+        ));
+    }
+    Ok(fields)
+}
+
+fn number<T: std::str::FromStr>(field: &str, name: &str) -> Result<T, String> {
+    field
+        .parse()
+        .map_err(|_| format!("invalid {} in wg dump: {:?}", name, field))
+}
+
+fn optional(field: &str) -> Option<String> {
+    (field != "(none)").then(|| field.to_string())
+}
+
+fn parse_interface(line: &str) -> Result<InterfaceDump, String> {
+    let f = fields(line, "interface", 4)?;
+    let fwmark = match f[3] {
+        "off" => None,
+        mark => Some(match mark.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16)
+                .map_err(|_| format!("invalid fwmark in wg dump: {:?}", mark))?,
+            None => number(mark, "fwmark")?,
+        }),
+    };
+    Ok(InterfaceDump {
+        public_key: f[1].to_string(),
+        listen_port: number(f[2], "listen port")?,
+        fwmark,
+    })
+}
+
+fn parse_peer(line: &str) -> Result<PeerDump, String> {
+    let f = fields(line, "peer", 8)?;
+    let allowed_ips = match f[3] {
+        "(none)" => Vec::new(),
+        ips => ips.split(',').map(str::to_string).collect(),
+    };
+    let latest_handshake = match number(f[4], "latest handshake")? {
+        0 => None,
+        ts => Some(ts),
+    };
+    let persistent_keepalive = match f[7] {
+        "off" => None,
+        secs => Some(number(secs, "persistent keepalive")?),
+    };
+    Ok(PeerDump {
+        public_key: f[0].to_string(),
+        endpoint: optional(f[2]),
+        allowed_ips,
+        latest_handshake,
+        transfer_rx: number(f[5], "transfer rx")?,
+        transfer_tx: number(f[6], "transfer tx")?,
+        persistent_keepalive,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `wg show wg0 dump` for a multihop entry interface after a few minutes.
+    const MULTI_PEER: &str = "\
+yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=\tHIgo9xNzJMWLKASShiTqIybxZ0U3wGLiUeJ1PKf8ykw=\t51820\t0xca6c
+xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=\t(none)\t192.95.5.67:1234\t10.192.122.3/32,10.192.124.0/24\t1700000100\t1048576\t524288\toff
+TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0=\tFpCyhws9cxwWoV4xELtfJvjJN+zQVRPISllRWgeopVE=\t[2001:db8::1]:51820\t0.0.0.0/0,::/0\t1700000040\t2048\t1024\t25
+";
+
+    /// A peer that is configured but has never completed a handshake.
+    const NEVER_CONNECTED: &str = "\
+yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=\tHIgo9xNzJMWLKASShiTqIybxZ0U3wGLiUeJ1PKf8ykw=\t41414\toff
+xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=\t(none)\t(none)\t(none)\t0\t0\t0\toff
+";
+
+    #[test]
+    fn parses_interface_and_peers() {
+        let show = WgShow::parse(MULTI_PEER).unwrap();
+        assert_eq!(
+            show.interface,
+            InterfaceDump {
+                public_key: "HIgo9xNzJMWLKASShiTqIybxZ0U3wGLiUeJ1PKf8ykw=".to_string(),
+                listen_port: 51820,
+                fwmark: Some(0xca6c),
+            }
+        );
+        assert_eq!(show.peers.len(), 2);
+        assert_eq!(
+            show.peers[1],
+            PeerDump {
+                public_key: "TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0=".to_string(),
+                endpoint: Some("[2001:db8::1]:51820".to_string()),
+                allowed_ips: vec!["0.0.0.0/0".to_string(), "::/0".to_string()],
+                latest_handshake: Some(1_700_000_040),
+                transfer_rx: 2048,
+                transfer_tx: 1024,
+                persistent_keepalive: Some(25),
+            }
+        );
+        assert_eq!(show.peers[0].persistent_keepalive, None);
+    }
+
+    #[test]
+    fn totals_cover_every_peer() {
+        let show = WgShow::parse(MULTI_PEER).unwrap();
+        assert_eq!(show.total_rx(), 1_050_624);
+        assert_eq!(show.total_tx(), 525_312);
+        assert_eq!(show.latest_handshake(), Some(1_700_000_100));
+    }
+
+    #[test]
+    fn unset_values_are_none() {
+        let show = WgShow::parse(NEVER_CONNECTED).unwrap();
+        assert_eq!(show.interface.fwmark, None);
+        let peer = &show.peers[0];
+        assert_eq!(peer.endpoint, None);
+        assert!(peer.allowed_ips.is_empty());
+        assert_eq!(peer.latest_handshake, None);
+        assert_eq!(show.latest_handshake(), None);
+        assert_eq!((show.total_rx(), show.total_tx()), (0, 0));
+    }
+
+    #[test]
+    fn interface_without_peers_is_valid() {
+        let first_line = MULTI_PEER.lines().next().unwrap();
+        let show = WgShow::parse(first_line).unwrap();
+        assert!(show.peers.is_empty());
+        assert_eq!(show.latest_handshake(), None);
+    }
+
+    #[test]
+    fn malformed_dumps_are_rejected() {
+        assert!(WgShow::parse("").is_err());
+        // The human-readable format is not accepted by mistake.
+        assert!(WgShow::parse("interface: wg0\n  public key: abc=\n").is_err());
+        let short_peer = format!("{}peer=\t(none)\t(none)\n", NEVER_CONNECTED);
+        assert!(WgShow::parse(&short_peer).is_err());
+        let bad_counter = NEVER_CONNECTED.replace("\t0\t0\t0\t", "\t0\tlots\t0\t");
+        assert!(WgShow::parse(&bad_counter).is_err());
+    }
+}
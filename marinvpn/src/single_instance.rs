@@ -0,0 +1,175 @@
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{info, warn};
+
+const LOCK_FILENAME: &str = "marinvpn.lock";
+const PORT_FILENAME: &str = "marinvpn.port";
+const SHOW_REQUEST: &[u8] = b"show\n";
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Held by the instance that owns the config and the tunnel. The OS lock on
+/// the file goes away with the process, so a crash never leaves a lock that
+/// blocks the next launch.
+#[derive(Debug)]
+pub struct InstanceLock {
+    _file: File,
+    listener: TcpListener,
+}
+
+#[derive(Debug)]
+pub enum Acquired {
+    Primary(InstanceLock),
+    /// Another instance is running. `notified` says whether it took the
+    /// request to show its window.
+    Secondary {
+        notified: bool,
+    },
+}
+
+/// The directory holding the lock: the one the config file it protects is in.
+pub fn lock_dir() -> PathBuf {
+    let config = crate::storage::get_config_path();
+    config.parent().map(Path::to_path_buf).unwrap_or_default()
+}
+
+/// Takes the single-instance lock in `dir`, or asks the instance holding it
+/// to come to the front. The holder writes the loopback port it listens on
+/// to a file beside the lock, since Windows does not let anyone else read a
+/// locked file. A port left there by a crashed instance is overwritten.
+pub fn acquire(dir: &Path) -> io::Result<Acquired> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dir.join(LOCK_FILENAME))?;
+    let port_file = dir.join(PORT_FILENAME);
+
+    match file.try_lock() {
+        Ok(()) => {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+            std::fs::write(&port_file, listener.local_addr()?.port().to_string())?;
+            Ok(Acquired::Primary(InstanceLock {
+                _file: file,
+                listener,
+            }))
+        }
+        Err(TryLockError::WouldBlock) => {
+            let notified = std::fs::read_to_string(&port_file)
+                .ok()
+                .and_then(|port| port.trim().parse::<u16>().ok())
+                .is_some_and(|port| request_show(port).is_ok());
+            Ok(Acquired::Secondary { notified })
+        }
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
+
+fn request_show(port: u16) -> io::Result<()> {
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    let mut stream = TcpStream::connect_timeout(&addr, NOTIFY_TIMEOUT)?;
+    stream.set_write_timeout(Some(NOTIFY_TIMEOUT))?;
+    stream.write_all(SHOW_REQUEST)
+}
+
+impl InstanceLock {
+    /// Calls `on_show` whenever a later launch asks for the window, for as
+    /// long as the process runs. The lock is held until then as well.
+    pub fn listen(self, on_show: impl Fn() + Send + 'static) {
+        std::thread::spawn(move || {
+            let InstanceLock { _file, listener } = self;
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { continue };
+                let _ = stream.set_read_timeout(Some(NOTIFY_TIMEOUT));
+                let mut request = [0u8; SHOW_REQUEST.len()];
+                if stream.read_exact(&mut request).is_ok() && request == SHOW_REQUEST {
+                    info!("Another launch asked for the window");
+                    on_show();
+                }
+            }
+            warn!("Single-instance listener stopped");
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    fn temp_dir(tag: &str) -> PathBuf {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("marinvpn-{}-{}", tag, nanos));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn second_launch_brings_the_first_forward() {
+        let dir = temp_dir("contention");
+        let Acquired::Primary(lock) = acquire(&dir).unwrap() else {
+            panic!("first launch should own the lock");
+        };
+        let (shown, show_requests) = mpsc::channel();
+        lock.listen(move || shown.send(()).unwrap());
+
+        assert!(matches!(
+            acquire(&dir).unwrap(),
+            Acquired::Secondary { notified: true }
+        ));
+        show_requests.recv_timeout(Duration::from_secs(2)).unwrap();
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn lock_left_by_a_crashed_instance_is_taken_over() {
+        let dir = temp_dir("stale");
+        std::fs::write(dir.join(LOCK_FILENAME), "").unwrap();
+        std::fs::write(dir.join(PORT_FILENAME), "1").unwrap();
+
+        let Acquired::Primary(lock) = acquire(&dir).unwrap() else {
+            panic!("an unlocked file is stale");
+        };
+        let port = lock.listener.local_addr().unwrap().port();
+        assert_eq!(
+            std::fs::read_to_string(dir.join(PORT_FILENAME)).unwrap(),
+            port.to_string()
+        );
+
+        // Released when the holder goes away.
+        drop(lock);
+        assert!(matches!(acquire(&dir).unwrap(), Acquired::Primary(_)));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn unresponsive_holder_is_reported() {
+        let dir = temp_dir("hung");
+        let holder = File::create(dir.join(LOCK_FILENAME)).unwrap();
+        holder.try_lock().unwrap();
+
+        // Locked, but no port written yet.
+        assert!(matches!(
+            acquire(&dir).unwrap(),
+            Acquired::Secondary { notified: false }
+        ));
+
+        // A port nobody listens on.
+        let closed = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = closed.local_addr().unwrap().port();
+        drop(closed);
+        std::fs::write(dir.join(PORT_FILENAME), port.to_string()).unwrap();
+        assert!(matches!(
+            acquire(&dir).unwrap(),
+            Acquired::Secondary { notified: false }
+        ));
+
+        drop(holder);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -1,11 +1,20 @@
+use crate::components::toast::{ToastManager, ToastType};
 use crate::hooks::use_account::use_account;
-use crate::hooks::use_connection::use_connection;
+use crate::hooks::use_connection::{use_connection, VpnState};
+use crate::hooks::use_persist::{use_persist, PERSIST_DELAY};
 use crate::hooks::use_servers::use_servers;
-use crate::models::{ConnectionStatus, Region, SettingsState, VpnAction};
+use crate::hooks::wait_until_online;
+use crate::models::{ConnectionStatus, Region, SettingsState, SingleHopOffer, VpnAction};
+use crate::services::auth::{client_too_old, AuthService};
+use crate::services::autostart;
+use crate::services::connect_timing::ConnectTiming;
+use crate::services::connection_stats::ConnectionStats;
+use crate::services::stealth_probe::StealthProbe;
+use crate::services::vpn::{SharedVpnService, StealthPort};
+use crate::services::SharedAppService;
 use crate::storage::load_config;
 use dioxus::prelude::*;
 use std::collections::HashSet;
-use std::time::Duration;
 
 #[derive(Clone, Copy)]
 pub struct ConnectionState {
@@ -22,45 +31,124 @@ pub struct ConnectionState {
     pub scroll_to: Signal<Option<String>>,
     pub download_speed: Signal<f64>,
     pub upload_speed: Signal<f64>,
+    pub active_port: Signal<Option<StealthPort>>,
+    pub stealth_probe: Signal<StealthProbe>,
+    pub connection_stats: Signal<ConnectionStats>,
+    pub last_connect: Signal<Option<ConnectTiming>>,
+    pub single_hop_offer: Signal<Option<SingleHopOffer>>,
     pub device_name: Signal<String>,
     pub vpn_action: Coroutine<VpnAction>,
+    pub exit_ready: Signal<bool>,
 }
 
 #[component]
-pub fn AppStateProvider(children: Element) -> Element {
+pub fn AppStateProvider(
+    children: Element,
+    /// Replaces the WireGuard backend, e.g. with a mock in UI tests.
+    #[props(default)]
+    vpn_service: Option<SharedVpnService>,
+    /// Replaces the server/API client used to pick and configure servers.
+    #[props(default)]
+    app_service: Option<SharedAppService>,
+) -> Element {
     let config = use_hook(load_config);
 
+    // Settings (still here for now)
+    let settings = use_signal(|| config.get_settings());
+
+    // Background network tasks hold while this is set; see below.
+    let mut offline = use_signal(|| settings.peek().offline_mode);
+
     // Account Hook
-    let account_state = use_account(&config);
+    let account_state = use_account(&config, offline.into());
 
     // Servers Hook
-    let regions = use_servers();
+    let battery_saver = use_memo(move || settings().battery_saver);
+    let regions = use_servers(app_service.clone(), offline.into(), battery_saver.into());
 
-    // Settings (still here for now)
-    let settings = use_signal(|| config.get_settings());
     let favorites = use_signal(|| config.favorites.clone().unwrap_or_default());
     let scroll_to = use_signal(|| None);
 
     // Connection Hook (depends on Account and Settings)
-    let vpn_state = use_connection(account_state, settings);
+    let vpn_state = use_connection(account_state, settings, vpn_service, app_service);
+    let mut connection_stats = vpn_state.connection_stats;
+    use_hook(|| connection_stats.set(config.connection_stats.clone().unwrap_or_default()));
+    use_startup(settings, account_state.account_number, vpn_state);
 
-    // Persistence Effects
+    // Offline mode only covers the disconnected state: connecting is the
+    // user's explicit go-ahead, and the loops resume until the next disconnect.
+    let status = vpn_state.status;
     use_effect(move || {
+        let hold = settings().offline_mode && status() == ConnectionStatus::Disconnected;
+        if *offline.peek() != hold {
+            offline.set(hold);
+        }
+    });
+
+    // Persistence Effects
+    use_persist(move || settings(), PERSIST_DELAY, crate::storage::save_settings);
+
+    // Memoised so unrelated settings changes do not rebuild the API client.
+    // The ECH lookup runs once the proxy is in place, so it goes through it.
+    let api_route = use_memo(move || {
         let s = settings();
+        let doh_providers = crate::services::doh::active_providers(&s.doh_providers);
+        (s.api_proxy, s.encrypted_client_hello, doh_providers)
+    });
+    let mut toasts = use_context::<ToastManager>();
+    use_effect(move || {
+        let (proxy, encrypted_client_hello, doh_providers) = api_route();
         spawn(async move {
-            tokio::time::sleep(Duration::from_secs(1)).await;
-            let _ = tokio::task::spawn_blocking(move || crate::storage::save_settings(s)).await;
+            if let Err(e) = AuthService::set_api_proxy(&proxy).await {
+                tracing::warn!("API proxy: {}", e);
+                toasts.show(&format!("API proxy: {}", e), ToastType::Error);
+            }
+            AuthService::set_encrypted_client_hello(encrypted_client_hello, &doh_providers).await;
         });
     });
 
+    // Checked at launch too, so an entry removed or pointed elsewhere outside
+    // the app is put back. If it cannot be added the switch goes back off.
+    let launch_on_startup = use_memo(move || settings().launch_on_startup);
+    let mut settings_autostart = settings;
     use_effect(move || {
-        let f = favorites.read().clone();
+        let enabled = launch_on_startup();
         spawn(async move {
-            tokio::time::sleep(Duration::from_secs(1)).await;
-            let _ = tokio::task::spawn_blocking(move || crate::storage::save_favorites(f)).await;
+            if let Err(e) = autostart::sync(enabled).await {
+                tracing::warn!("Launch at startup: {}", e);
+                toasts.show(&e.to_string(), ToastType::Error);
+                if enabled {
+                    settings_autostart.with_mut(|s| s.launch_on_startup = false);
+                }
+            }
         });
     });
 
+    // Asked once per launch. A server that has dropped support for this
+    // build still answers, so the user hears why before things fail.
+    use_future(move || async move {
+        wait_until_online(offline.into()).await;
+        match AuthService::get_server_version().await {
+            Ok(server) if client_too_old(&server) => {
+                let message = format!(
+                    "This version of MarinVPN is no longer supported. Update to {} or later.",
+                    server.min_client_version
+                );
+                tracing::warn!("{}", message);
+                toasts.show(&message, ToastType::Error);
+            }
+            Ok(_) => {}
+            Err(e) => tracing::debug!("Server version check failed: {}", e),
+        }
+    });
+
+    use_persist(move || favorites(), PERSIST_DELAY, crate::storage::save_favorites);
+    use_persist(
+        move || connection_stats(),
+        PERSIST_DELAY,
+        crate::storage::save_connection_stats,
+    );
+
     use_context_provider(|| ConnectionState {
         status: vpn_state.status,
         current_location: vpn_state.current_location,
@@ -75,11 +163,36 @@ pub fn AppStateProvider(children: Element) -> Element {
         scroll_to,
         download_speed: vpn_state.download_speed,
         upload_speed: vpn_state.upload_speed,
+        active_port: vpn_state.active_port,
+        stealth_probe: vpn_state.stealth_probe,
+        connection_stats,
+        last_connect: vpn_state.last_connect,
+        single_hop_offer: vpn_state.single_hop_offer,
         device_name: account_state.device_name,
         vpn_action: vpn_state.vpn_action,
+        exit_ready: vpn_state.exit_ready,
     });
 
     rsx! {
         {children}
     }
-}
\ No newline at end of file
+}
+
+/// Carries out the startup behavior, decided once from the settings the app
+/// launched with. It waits for a signed-in account and for the lockdown
+/// firewall, so a startup connect never races the rules it has to replace.
+pub fn use_startup(
+    settings: Signal<SettingsState>,
+    account_number: Signal<Option<String>>,
+    vpn_state: VpnState,
+) {
+    let mut startup_action = use_signal(|| settings.peek().startup_action());
+    use_effect(move || {
+        let signed_in = account_number().is_some_and(|number| !number.is_empty());
+        if signed_in && (vpn_state.lockdown_applied)() {
+            if let Some(action) = startup_action.take() {
+                vpn_state.vpn_action.send(action);
+            }
+        }
+    });
+}
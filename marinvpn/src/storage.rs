@@ -1,11 +1,13 @@
 use crate::models::SettingsState;
+use crate::services::connection_stats::ConnectionStats;
 use directories::ProjectDirs;
 use keyring::Entry;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 use std::fs;
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
 use tracing::{error, info};
 
@@ -13,6 +15,8 @@ const KEYRING_SERVICE: &str = "marinvpn";
 const CONFIG_FILENAME: &str = "marinvpn_config.json";
 const DEVICE_KEYRING_KEY: &str = "device_attestation_key";
 const REFRESH_TOKEN_KEY: &str = "refresh_token";
+const BACKUP_SUFFIX: &str = ".bak";
+const TEMP_SUFFIX: &str = ".tmp";
 
 static CONFIG_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
 
@@ -28,11 +32,15 @@ pub struct AppConfig {
     pub refresh_token: Option<String>,
     pub account_expiry: Option<i64>,
     pub device_name: Option<String>,
+    pub connection_stats: Option<ConnectionStats>,
 }
 
 impl AppConfig {
     pub fn get_settings(&self) -> SettingsState {
-        self.settings.clone().unwrap_or_default()
+        self.settings
+            .clone()
+            .map(SettingsState::normalized)
+            .unwrap_or_default()
     }
 }
 
@@ -50,6 +58,11 @@ pub fn get_config_path() -> PathBuf {
         .join(CONFIG_FILENAME)
 }
 
+/// Where the previous good config is kept, next to [`get_config_path`].
+pub fn get_backup_path() -> PathBuf {
+    sibling(&get_config_path(), BACKUP_SUFFIX)
+}
+
 fn get_account_entry() -> Result<Entry, keyring::Error> {
     Entry::new(KEYRING_SERVICE, "account_number")
 }
@@ -68,8 +81,8 @@ fn get_device_key_entry() -> Result<Entry, keyring::Error> {
 
 fn load_config_inner() -> AppConfig {
     let path = get_config_path();
-    let mut config = match fs::read_to_string(&path) {
-        Ok(contents) => {
+    let mut config = match read_config_file(&path) {
+        Some(contents) => {
             let legacy_account = match serde_json::from_str::<serde_json::Value>(&contents) {
                 Ok(v) => v
                     .get("account_number")
@@ -98,11 +111,7 @@ fn load_config_inner() -> AppConfig {
                 }
             }
         }
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => AppConfig::default(),
-        Err(e) => {
-            error!("Failed to read config at {:?}: {}", path, e);
-            AppConfig::default()
-        }
+        None => AppConfig::default(),
     };
 
     if let Ok(entry) = get_account_entry() {
@@ -160,10 +169,71 @@ fn save_config_inner(config: &AppConfig) -> std::io::Result<()> {
     let json = serde_json::to_string_pretty(config)
         .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
 
-    fs::write(&path, json)?;
+    write_config_file(&path, &json)
+}
+
+/// The config file's contents, or the backup's when the file is missing or
+/// no longer parses (e.g. it was cut short by a crash).
+fn read_config_file(path: &Path) -> Option<String> {
+    match fs::read_to_string(path) {
+        Ok(contents) if serde_json::from_str::<AppConfig>(&contents).is_ok() => {
+            return Some(contents)
+        }
+        Ok(_) => error!("Config at {:?} is corrupt, trying the backup", path),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => error!("Failed to read config at {:?}: {}", path, e),
+    }
+
+    let backup = sibling(path, BACKUP_SUFFIX);
+    let contents = fs::read_to_string(&backup).ok()?;
+    if serde_json::from_str::<AppConfig>(&contents).is_err() {
+        error!("Config backup at {:?} is corrupt as well", backup);
+        return None;
+    }
+    info!("Restored config from {:?}", backup);
+    Some(contents)
+}
+
+/// Replaces the config at `path` so that a crash at any point leaves either
+/// the old file or the new one in place. The new file is renamed straight
+/// over the old one; a copy of the old one is made the backup first, unless
+/// it is corrupt, in which case the backup it would replace is kept.
+fn write_config_file(path: &Path, json: &str) -> std::io::Result<()> {
+    if let Ok(previous) = fs::read_to_string(path) {
+        if serde_json::from_str::<AppConfig>(&previous).is_ok() {
+            write_synced(&sibling(path, BACKUP_SUFFIX), &previous)?;
+        }
+    }
+    write_synced(path, json)
+}
+
+/// Writes `contents` to a temporary sibling, flushes it to disk and renames
+/// it over `path`, then flushes the directory so the rename itself survives
+/// a power cut.
+fn write_synced(path: &Path, contents: &str) -> std::io::Result<()> {
+    let temp = sibling(path, TEMP_SUFFIX);
+    {
+        let mut file = fs::File::create(&temp)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+    }
+    fs::rename(&temp, path)?;
+
+    // Windows cannot open a directory as a file; its rename is already
+    // written through by the time it returns.
+    #[cfg(unix)]
+    if let Some(dir) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::File::open(dir)?.sync_all()?;
+    }
     Ok(())
 }
 
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
 pub fn save_config(config: &AppConfig) -> std::io::Result<()> {
     let _guard = CONFIG_LOCK.lock().unwrap();
     save_config_inner(config)
@@ -183,6 +253,13 @@ pub fn save_favorites(favorites: HashSet<String>) -> std::io::Result<()> {
     save_config_inner(&config)
 }
 
+pub fn save_connection_stats(stats: ConnectionStats) -> std::io::Result<()> {
+    let _guard = CONFIG_LOCK.lock().unwrap();
+    let mut config = load_config_inner();
+    config.connection_stats = Some(stats);
+    save_config_inner(&config)
+}
+
 pub fn save_auth_info(
     account_number: Option<String>,
     auth_token: Option<String>,
@@ -222,3 +299,42 @@ pub fn save_device_attestation_key(key: &str) {
         let _ = entry.set_password(key);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replacing_the_config_keeps_the_old_one_as_backup() {
+        let dir = std::env::temp_dir().join(format!("marinvpn-storage-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.json");
+        let first = serde_json::to_string(&AppConfig::default()).unwrap();
+        let second = serde_json::to_string(&AppConfig {
+            account_number: Some("1234".to_string()),
+            ..AppConfig::default()
+        })
+        .unwrap();
+
+        write_config_file(&path, &first).unwrap();
+        write_config_file(&path, &second).unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), second);
+        assert_eq!(
+            fs::read_to_string(sibling(&path, BACKUP_SUFFIX)).unwrap(),
+            first
+        );
+        assert!(!sibling(&path, TEMP_SUFFIX).exists());
+        assert!(!sibling(&sibling(&path, BACKUP_SUFFIX), TEMP_SUFFIX).exists());
+
+        // A corrupt live file is not allowed to overwrite a good backup.
+        fs::write(&path, "{").unwrap();
+        write_config_file(&path, &second).unwrap();
+        assert_eq!(
+            fs::read_to_string(sibling(&path, BACKUP_SUFFIX)).unwrap(),
+            first
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,5 +1,8 @@
 use crate::components::toast::{use_toast, ToastType};
+use crate::components::ui::modal::Modal;
 use crate::icons::*;
+use crate::services::auth::AuthService;
+use crate::services::qr;
 use crate::state::ConnectionState;
 use crate::Route;
 use dioxus::prelude::*;
@@ -12,6 +15,15 @@ pub fn Account() -> Element {
     let i18n = crate::hooks::use_i18n();
     let account = (state.account_number)().unwrap_or_default();
     let mut show_account = use_signal(|| false);
+    let mut show_qr = use_signal(|| false);
+    let mut confirm_wipe = use_signal(|| false);
+    let mut is_wiping = use_signal(|| false);
+    let mut is_logging_out = use_signal(|| false);
+
+    let sessions = use_resource(move || async move {
+        let token = (state.auth_token)().unwrap_or_default();
+        AuthService::get_sessions(&token).await.ok()
+    });
 
     let expiry_text = use_memo(move || {
         if let Some(expiry) = (state.account_expiry)() {
@@ -46,6 +58,19 @@ pub fn Account() -> Element {
                         }
                     }
 
+                    if let Some(Some(sessions)) = &*sessions.read() {
+                        div { class: "px-1",
+                            h4 { class: "text-[10px] font-bold text-muted-foreground uppercase tracking-widest mb-2",
+                                {i18n.tr("active_sessions")}
+                            }
+                            div { class: "flex items-center justify-between py-1",
+                                span { class: "text-sm font-bold text-foreground",
+                                    "{sessions.active_sessions} / {sessions.max_sessions}"
+                                }
+                            }
+                        }
+                    }
+
                     // Account Number
                     div { class: "px-1",
                         h4 { class: "text-[10px] font-bold text-muted-foreground uppercase tracking-widest mb-2",
@@ -74,6 +99,13 @@ pub fn Account() -> Element {
                                     },
                                     Copy { size: 20 }
                                 }
+                                button {
+                                    class: "w-12 flex items-center justify-center hover:bg-accent rounded-lg text-muted-foreground hover:text-foreground transition-all focus:outline-none",
+                                    style: "height: 48px !important; min-height: 48px !important; flex-shrink: 0 !important;",
+                                    title: "Show as QR code",
+                                    onclick: move |_| show_qr.set(true),
+                                    QrCode { size: 20 }
+                                }
                             }
                         }
                     }
@@ -113,15 +145,115 @@ pub fn Account() -> Element {
                 button {
                     class: "w-full flex items-center justify-center bg-destructive/10 hover:bg-destructive/20 text-destructive rounded-lg border border-destructive/20 text-xs font-bold transition-all active:scale-[0.98]",
                     style: "height: 48px !important; min-height: 48px !important; flex-shrink: 0 !important;",
+                    disabled: is_logging_out(),
                     onclick: move |_| {
-                        state.account_number.set(None);
-                        state.auth_token.set(None);
-                        state.refresh_token.set(None);
-                        state.account_expiry.set(None);
-                        nav.replace(Route::Dashboard {});
+                        is_logging_out.set(true);
+                        let token = (state.auth_token)().unwrap_or_default();
+                        spawn(async move {
+                            match AuthService::logout(&token).await {
+                                Ok(_) => toast.show(i18n.tr("logged_out"), ToastType::Info),
+                                Err(_) => toast.show(i18n.tr("logged_out_offline"), ToastType::Error),
+                            }
+                            state.account_number.set(None);
+                            state.auth_token.set(None);
+                            state.refresh_token.set(None);
+                            state.account_expiry.set(None);
+                            is_logging_out.set(false);
+                            nav.replace(Route::Dashboard {});
+                        });
                     },
+                    if is_logging_out() {
+                        RefreshCw { class: "animate-spin mr-2", size: 14 }
+                    }
                     {i18n.tr("log_out")}
                 }
+                if confirm_wipe() {
+                    div { class: "p-3 rounded-lg border border-destructive/40 bg-destructive/5 space-y-3",
+                        p { class: "text-[11px] text-destructive font-medium", {i18n.tr("emergency_wipe_desc")} }
+                        div { class: "flex gap-2",
+                            button {
+                                class: "flex-1 flex items-center justify-center bg-card hover:bg-accent/40 border border-border text-foreground rounded-lg text-xs font-bold transition-all active:scale-[0.98]",
+                                style: "height: 40px !important; min-height: 40px !important;",
+                                disabled: is_wiping(),
+                                onclick: move |_| confirm_wipe.set(false),
+                                {i18n.tr("cancel")}
+                            }
+                            button {
+                                class: "flex-1 flex items-center justify-center gap-2 bg-destructive hover:brightness-110 text-destructive-foreground rounded-lg text-xs font-bold transition-all active:scale-[0.98] disabled:opacity-50",
+                                style: "height: 40px !important; min-height: 40px !important;",
+                                disabled: is_wiping(),
+                                onclick: move |_| {
+                                    is_wiping.set(true);
+                                    let acc = (state.account_number)().unwrap_or_default();
+                                    let token = (state.auth_token)().unwrap_or_default();
+                                    spawn(async move {
+                                        match AuthService::panic_account(&acc, &token).await {
+                                            Ok(res) => {
+                                                toast.show(&format!("Wiped {} devices", res.devices_removed), ToastType::Success);
+                                                state.account_number.set(None);
+                                                state.auth_token.set(None);
+                                                state.refresh_token.set(None);
+                                                state.account_expiry.set(None);
+                                                nav.replace(Route::Dashboard {});
+                                            }
+                                            Err(e) => {
+                                                toast.show(&e.user_friendly_message(), ToastType::Error);
+                                                confirm_wipe.set(false);
+                                            }
+                                        }
+                                        is_wiping.set(false);
+                                    });
+                                },
+                                if is_wiping() {
+                                    RefreshCw { class: "animate-spin", size: 14 }
+                                }
+                                {i18n.tr("wipe_now")}
+                            }
+                        }
+                    }
+                } else {
+                    button {
+                        class: "w-full flex items-center justify-center text-destructive/80 hover:text-destructive text-[10px] font-bold uppercase tracking-widest transition-all focus:outline-none",
+                        style: "height: 32px !important; min-height: 32px !important; flex-shrink: 0 !important;",
+                        onclick: move |_| confirm_wipe.set(true),
+                        {i18n.tr("emergency_wipe")}
+                    }
+                }
+            }
+
+            if show_qr() {
+                Modal {
+                    title: "Set up another device".to_string(),
+                    onclose: move |_| show_qr.set(false),
+                    children: match qr::account_code(&account) {
+                        Some(code) => {
+                            // Four modules of quiet zone on each side.
+                            let side = code.size() + 8;
+                            rsx! {
+                                div { class: "flex flex-col items-center gap-3",
+                                    svg {
+                                        class: "w-48 h-48 rounded-lg",
+                                        view_box: "0 0 {side} {side}",
+                                        shape_rendering: "crispEdges",
+                                        rect { width: "{side}", height: "{side}", fill: "#ffffff" }
+                                        path { d: code.svg_path(4), fill: "#000000" }
+                                    }
+                                    span { class: "text-sm font-bold text-foreground font-mono",
+                                        {qr::account_payload(&account).unwrap_or_default()}
+                                    }
+                                    p { class: "text-[11px] text-muted-foreground text-center",
+                                        "Scan this on your other device, or type the number into its login screen. Anyone who sees it can use your account."
+                                    }
+                                }
+                            }
+                        }
+                        None => rsx! {
+                            p { class: "text-xs text-muted-foreground",
+                                "This account number cannot be shown as a QR code."
+                            }
+                        },
+                    },
+                }
             }
         }
     }
@@ -1,4 +1,10 @@
 use crate::icons::*;
+use crate::hooks::use_request_log::use_request_log;
+use crate::services::connect_timing::ConnectTiming;
+use crate::services::connection_stats::{failure_label, ConnectionStats};
+use crate::services::firewall_cleanup::SystemCommands;
+use crate::services::firewall_state::{self, RuleMatch};
+use crate::services::request_log;
 use dioxus::prelude::*;
 
 #[component]
@@ -52,6 +58,12 @@ pub fn AppInfo() -> Element {
                     }
                 }
 
+                ConnectionStatsCard { stats: state.connection_stats, last_connect: state.last_connect }
+
+                RequestLogCard {}
+
+                FirewallCard {}
+
                 div {
                     h4 { class: "text-[10px] font-bold text-muted-foreground uppercase tracking-widest mb-3 ml-1", {i18n.tr("whats_new")} }
                     div { class: "space-y-4",
@@ -81,6 +93,183 @@ pub fn AppInfo() -> Element {
     }
 }
 
+/// Connection attempt counters. They are stored on this device only.
+#[component]
+fn ConnectionStatsCard(
+    stats: Signal<ConnectionStats>,
+    last_connect: Signal<Option<ConnectTiming>>,
+) -> Element {
+    let current = stats();
+    let average = match current.average_connect_time() {
+        Some(avg) => format!("{:.1}s", avg.as_secs_f64()),
+        None => "–".to_string(),
+    };
+
+    rsx! {
+        div { class: "bg-card rounded-2xl p-5 border border-border shadow-sm",
+            div { class: "flex justify-between items-baseline mb-1",
+                h4 { class: "font-bold text-lg text-foreground", "Connection history" }
+                button {
+                    class: "text-[10px] font-bold text-primary uppercase hover:underline disabled:opacity-50",
+                    disabled: current.attempts == 0,
+                    onclick: move |_| stats.set(ConnectionStats::default()),
+                    "Reset"
+                }
+            }
+            p { class: "text-[11px] text-muted-foreground font-medium mb-4 leading-relaxed",
+                "Kept on this device only and never sent to MarinVPN."
+            }
+            div { class: "grid grid-cols-2 gap-3 text-xs font-medium text-foreground",
+                span { "Attempts" }
+                span { class: "text-right font-mono", "{current.attempts}" }
+                span { "Connected" }
+                span { class: "text-right font-mono", "{current.successes}" }
+                span { "Failed" }
+                span { class: "text-right font-mono", "{current.failed()}" }
+                span { "Average connect time" }
+                span { class: "text-right font-mono", "{average}" }
+            }
+            if !current.failures.is_empty() {
+                ul { class: "mt-4 space-y-2 border-t border-border pt-3",
+                    for (kind, count) in current.failures.iter() {
+                        li { class: "flex justify-between text-[11px] text-muted-foreground font-medium",
+                            span { "{failure_label(kind)}" }
+                            span { class: "font-mono", "{count}" }
+                        }
+                    }
+                }
+            }
+            if let Some(timing) = last_connect() {
+                div { class: "mt-4 border-t border-border pt-3",
+                    p { class: "text-[10px] font-bold text-muted-foreground uppercase tracking-widest mb-1",
+                        "Last connect"
+                    }
+                    p { class: "text-[11px] text-muted-foreground font-mono break-words",
+                        "{timing.summary()}"
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Every request the app has sent to the API or a DoH provider since it
+/// started, newest first.
+#[component]
+fn RequestLogCard() -> Element {
+    let mut records = use_request_log();
+    let current = records();
+
+    rsx! {
+        div { class: "bg-card rounded-2xl p-5 border border-border shadow-sm",
+            div { class: "flex justify-between items-baseline mb-1",
+                h4 { class: "font-bold text-lg text-foreground", "Network activity" }
+                button {
+                    class: "text-[10px] font-bold text-primary uppercase hover:underline disabled:opacity-50",
+                    disabled: current.is_empty(),
+                    onclick: move |_| {
+                        request_log::clear();
+                        records.set(Vec::new());
+                    },
+                    "Clear"
+                }
+            }
+            p { class: "text-[11px] text-muted-foreground font-medium mb-4 leading-relaxed",
+                "Requests this app has made since it started. Only the address and result are kept, never what was sent."
+            }
+            if current.is_empty() {
+                p { class: "text-xs text-muted-foreground font-medium", "No requests yet." }
+            } else {
+                ul { class: "max-h-64 overflow-y-auto custom-scrollbar space-y-2",
+                    for record in current.iter().rev() {
+                        li { class: "flex items-center gap-3 text-[11px] font-medium text-foreground",
+                            span { class: "font-mono text-muted-foreground shrink-0",
+                                {record.at.with_timezone(&chrono::Local).format("%H:%M:%S").to_string()}
+                            }
+                            span { class: "font-mono font-bold shrink-0", "{record.method}" }
+                            span { class: "font-mono truncate flex-1", title: "{record.endpoint}", "{record.endpoint}" }
+                            span {
+                                class: if record.outcome.is_success() { "font-mono text-muted-foreground shrink-0" } else { "font-mono text-destructive shrink-0" },
+                                {record.outcome.label()}
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The kill switch rules as the firewall reports them, read when the page
+/// opens and on refresh.
+#[component]
+fn FirewallCard() -> Element {
+    let mut state = use_resource(|| firewall_state::read(&SystemCommands));
+    let current = state.read().clone();
+
+    rsx! {
+        div { class: "bg-card rounded-2xl p-5 border border-border shadow-sm",
+            div { class: "flex justify-between items-baseline mb-1",
+                h4 { class: "font-bold text-lg text-foreground", "Firewall" }
+                button {
+                    class: "text-[10px] font-bold text-primary uppercase hover:underline",
+                    onclick: move |_| state.restart(),
+                    "Refresh"
+                }
+            }
+            p { class: "text-[11px] text-muted-foreground font-medium mb-4 leading-relaxed",
+                "What the kill switch is blocking right now, as read from the system firewall."
+            }
+            match current {
+                None => rsx! {
+                    p { class: "text-xs text-muted-foreground font-medium", "Reading the firewall..." }
+                },
+                Some(None) => rsx! {
+                    p { class: "text-xs text-muted-foreground font-medium", "No kill switch rules are in place." }
+                },
+                Some(Some(firewall)) => {
+                    let families = firewall
+                        .blocked_families
+                        .iter()
+                        .map(|f| f.to_string())
+                        .collect::<Vec<_>>()
+                        .join(" and ");
+                    rsx! {
+                        div { class: "grid grid-cols-2 gap-3 text-xs font-medium text-foreground mb-2",
+                            span { "Firewall" }
+                            span { class: "text-right font-mono", "{firewall.backend}" }
+                            span { "Blocks everything else" }
+                            span { class: "text-right font-mono",
+                                if families.is_empty() { "No" } else { "{families}" }
+                            }
+                        }
+                        FirewallRuleList { title: "Allowed", rules: firewall.allowed }
+                        FirewallRuleList { title: "Blocked", rules: firewall.blocked }
+                        FirewallRuleList { title: "Outside the tunnel", rules: firewall.split_exceptions }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[component]
+fn FirewallRuleList(title: &'static str, rules: Vec<RuleMatch>) -> Element {
+    if rules.is_empty() {
+        return rsx! {};
+    }
+    rsx! {
+        div { class: "mt-3 border-t border-border pt-3",
+            p { class: "text-[10px] font-bold text-muted-foreground uppercase tracking-widest mb-1", "{title}" }
+            ul { class: "space-y-1",
+                for rule in rules.iter() {
+                    li { class: "text-[11px] text-muted-foreground font-mono break-words", "{rule}" }
+                }
+            }
+        }
+    }
+}
+
 #[component]
 fn ChangeLogItem(version: &'static str, date: &'static str, changes: Vec<&'static str>) -> Element {
     rsx! {
@@ -1,23 +1,34 @@
+use crate::components::ui::modal::Modal;
 use crate::components::DashboardMap;
-use crate::models::ConnectionStatus;
+use crate::hooks::use_controls_enabled;
+use crate::icons::CircleAlert;
+use crate::models::{ConnectionStatus, Hop, VpnAction};
 use crate::state::ConnectionState;
 use dioxus::prelude::*;
 
 #[component]
 pub fn Dashboard() -> Element {
-    let state = use_context::<ConnectionState>();
+    let mut state = use_context::<ConnectionState>();
     let status = (state.status)();
     let download_speed = (state.download_speed)();
     let upload_speed = (state.upload_speed)();
 
     let location_text = (state.current_location)();
-    let location = crate::models::LocationInfo::from_string(&location_text);
+    let country = crate::models::Location::parse(&location_text)
+        .map(|l| l.country)
+        .unwrap_or_default();
 
     let regions = state.regions.read();
+    let enabled = use_controls_enabled();
 
     rsx! {
         div { class: "relative w-full flex-1 bg-background overflow-hidden flex flex-col",
-            DashboardMap { regions: regions.clone(), country: location.country, status }
+            DashboardMap { regions: regions.clone(), country, status }
+
+            // Holds back map clicks while a connect or disconnect runs.
+            if !enabled {
+                div { class: "absolute inset-0 bg-background/20 cursor-wait" }
+            }
 
             if status == ConnectionStatus::Connected {
                 div { class: "absolute top-4 left-4 flex flex-col gap-2 pointer-events-none",
@@ -37,6 +48,46 @@ pub fn Dashboard() -> Element {
                     }
                 }
             }
+
+            if let Some(offer) = (state.single_hop_offer)() {
+                Modal {
+                    title: "Multihop unavailable".to_string(),
+                    onclose: move |_| state.single_hop_offer.set(None),
+                    children: rsx! {
+                        div { class: "flex items-start gap-3 mb-4",
+                            div { class: "w-9 h-9 shrink-0 rounded-full bg-destructive/10 flex items-center justify-center",
+                                CircleAlert { size: 20, class: Some("text-destructive".to_string()) }
+                            }
+                            div { class: "text-xs text-muted-foreground leading-relaxed",
+                                p { class: "mb-2",
+                                    {
+                                        let hop = match offer.failed {
+                                            Hop::Entry => "entry",
+                                            Hop::Exit => "exit",
+                                        };
+                                        format!("The {} server could not be reached. {}", hop, offer.reason)
+                                    }
+                                }
+                                p {
+                                    "You can connect through {offer.location} alone instead. A single server will then see both your IP address and the sites you visit."
+                                }
+                            }
+                        }
+                        div { class: "flex flex-col gap-2",
+                            button {
+                                class: "w-full h-11 bg-destructive text-destructive-foreground font-bold rounded-xl hover:opacity-90 transition-all active:scale-95",
+                                onclick: move |_| state.vpn_action.send(VpnAction::AcceptSingleHop),
+                                "Connect single-hop"
+                            }
+                            button {
+                                class: "w-full h-10 border border-border text-foreground font-semibold rounded-xl hover:bg-accent/40 transition-all active:scale-95",
+                                onclick: move |_| state.single_hop_offer.set(None),
+                                "Cancel"
+                            }
+                        }
+                    }
+                }
+            }
         }
     }
 }
@@ -1,4 +1,5 @@
 use crate::components::toast::{use_toast, ToastType};
+use crate::device_list::DeviceList;
 use crate::icons::{RefreshCw, X};
 use crate::services::auth::AuthService;
 use crate::state::ConnectionState;
@@ -20,14 +21,21 @@ pub fn Devices() -> Element {
             async move { AuthService::get_devices(&acc, &token).await }
         })
     };
+    let mut device_list = use_signal(DeviceList::default);
+
+    use_effect(move || {
+        if let Some(Ok(devices)) = &*devices_resource.value().read() {
+            device_list.write().refresh(devices.clone());
+        }
+    });
 
     rsx! {
         div { class: "h-full w-full flex flex-col bg-background",
             div { class: "flex-1 overflow-y-auto custom-scrollbar",
                 div { class: "space-y-3 pb-24",
                     match &*devices_resource.value().read() {
-                        Some(Ok(devices)) => rsx! {
-                            for device in devices {
+                        Some(Ok(_)) => rsx! {
+                            for device in device_list.read().visible() {
                                 {
                                     let name = device.name.clone();
                                     let name_for_action = name.clone();
@@ -66,13 +74,24 @@ pub fn Devices() -> Element {
                                                             let acc = acc_for_remove.clone();
                                                             let token = token_for_remove.clone();
                                                             let dev_name = name_for_action.clone();
+                                                            if !device_list.write().begin_remove(&dev_name) {
+                                                                return;
+                                                            }
                                                             spawn(async move {
                                                                 match AuthService::remove_device(&acc, &dev_name, &token).await {
+                                                                    Ok(res) if res.removed => {
+                                                                        device_list.write().confirm_remove(&dev_name);
+                                                                        toast.show(&format!("Removed {}", dev_name), ToastType::Success);
+                                                                    }
                                                                     Ok(_) => {
+                                                                        device_list.write().confirm_remove(&dev_name);
                                                                         devices_resource.restart();
-                                                                        toast.show(&format!("Removed {}", dev_name), ToastType::Success);
+                                                                        toast.show(&format!("{} was already removed", dev_name), ToastType::Info);
+                                                                    }
+                                                                    Err(e) => {
+                                                                        device_list.write().rollback_remove(&dev_name);
+                                                                        toast.show(&format!("Could not remove {}: {}", dev_name, e.user_friendly_message()), ToastType::Error);
                                                                     }
-                                                                    Err(e) => toast.show(&e.user_friendly_message(), ToastType::Error),
                                                                 }
                                                             });
                                                         },
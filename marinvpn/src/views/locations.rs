@@ -1,11 +1,15 @@
 use crate::components::toast::{use_toast, ToastType};
 use crate::hooks::use_vpn_client;
 use crate::icons::*;
+use crate::location_index::LocationIndex;
 use crate::models::City;
-use crate::models::ConnectionStatus;
+use crate::models::{ConnectionStatus, Location};
 use crate::state::ConnectionState;
 use crate::Route;
 use dioxus::prelude::*;
+use std::time::Duration;
+
+const SEARCH_DEBOUNCE: Duration = Duration::from_millis(150);
 
 #[component]
 pub fn Locations() -> Element {
@@ -13,33 +17,25 @@ pub fn Locations() -> Element {
     let mut current_tab = use_signal(|| "All");
 
     let mut expanded_country = use_signal(|| Option::<String>::None);
+    let mut search_input = use_signal(String::new);
     let mut search_query = use_signal(String::new);
 
+    use_effect(move || {
+        let input = search_input();
+        spawn(async move {
+            tokio::time::sleep(SEARCH_DEBOUNCE).await;
+            if *search_input.peek() == input {
+                search_query.set(input);
+            }
+        });
+    });
+
+    let index = use_memo(move || LocationIndex::build(&state.regions.read()));
     let filtered_regions = use_memo(move || {
-        let regions_val = state.regions.read();
-        let query = search_query().to_lowercase();
         let favs = state.favorites.read();
-        let show_favs = current_tab() == "Favorites";
-
-        regions_val
-            .iter()
-            .filter(|region| {
-                let matches_query = query.is_empty() || region.name.to_lowercase().contains(&query);
-                if !matches_query {
-                    return false;
-                }
-
-                if show_favs {
-                    region
-                        .cities
-                        .iter()
-                        .any(|c| favs.contains(&format!("{}, {}", region.name, c.name)))
-                } else {
-                    true
-                }
-            })
-            .cloned()
-            .collect::<Vec<_>>()
+        index
+            .read()
+            .search(&search_query(), &favs, current_tab() == "Favorites")
     });
 
     rsx! {
@@ -56,8 +52,8 @@ pub fn Locations() -> Element {
                         input {
                             class: "w-full bg-card border border-border rounded-xl pl-10 pr-4 py-2 text-sm text-foreground placeholder-muted-foreground focus:outline-none focus:ring-2 focus:ring-primary/20 transition-all shadow-sm",
                             placeholder: "Search locations...",
-                            value: "{search_query}",
-                            oninput: move |e| search_query.set(e.value()),
+                            value: "{search_input}",
+                            oninput: move |e| search_input.set(e.value()),
                         }
                     }
                     button {
@@ -112,7 +108,7 @@ pub fn Locations() -> Element {
                                     }
                                 }
 
-                                if expanded_country() == Some(region.name.clone()) || current_tab() == "Favorites" {
+                                if expanded_country() == Some(region.name.clone()) || current_tab() == "Favorites" || !search_query().is_empty() {
                                     div { class: "bg-background/40 border-t border-border/50 divide-y divide-border/30",
                                         for city in region.cities {
                                             LocationItem {
@@ -140,8 +136,11 @@ fn LocationItem(city: City, region_name: String, current_tab: String) -> Element
     let mut toast = use_toast();
     let settings = state.settings.read();
 
-    let location_name = format!("{}, {}", region_name, city.name);
+    let location = Location::new(&region_name, &city.name);
+    let location_name = location.to_string();
     let is_fav = state.favorites.read().contains(&location_name);
+    let is_pinned = settings.is_pinned(&location);
+    let mut pin_settings = state.settings;
     let current_loc = (state.current_location)();
     let status = (state.status)();
     let is_active_location = current_loc == location_name;
@@ -150,26 +149,29 @@ fn LocationItem(city: City, region_name: String, current_tab: String) -> Element
         return rsx! {};
     }
 
-    let location_name_connect = location_name.clone();
     let location_name_fav = location_name.clone();
+    let pin_location = location.clone();
     let city_name1 = city.name.clone();
     let city_name2 = city.name.clone();
 
     rsx! {
         div { class: "px-4 py-3 pl-14 hover:bg-accent/20 flex items-center justify-between group transition-colors",
             button {
-                class: "flex items-center gap-3 flex-1 text-left focus:outline-none",
+                class: "flex items-center gap-3 flex-1 text-left focus:outline-none disabled:opacity-50",
+                // A server the last sync no longer listed can still be the
+                // one in use, which leaves disconnecting possible.
+                disabled: !city.available && !is_active_location,
                 onclick: move |_| {
                     if is_active_location && status == ConnectionStatus::Connected {
                         vpn.disconnect();
                     } else {
-                        vpn.connect(location_name_connect.clone());
+                        vpn.connect(location.clone().into());
                         nav.push(Route::Dashboard {});
                     }
                 },
                 div {
                     class: "w-2 h-2 rounded-full shadow-[0_0_8px_currentColor] transition-colors",
-                    class: if is_active_location && status == ConnectionStatus::Connected { "text-primary bg-primary animate-pulse" } else if city.load < 50 { "text-status-success bg-current" } else if city.load < 80 { "text-status-warning bg-current" } else { "text-status-error bg-current" },
+                    class: if is_active_location && status == ConnectionStatus::Connected { "text-primary bg-primary animate-pulse" } else if !city.available { "text-muted-foreground bg-current" } else if city.load < 50 { "text-status-success bg-current" } else if city.load < 80 { "text-status-warning bg-current" } else { "text-status-error bg-current" },
                 }
                 div {
                     div {
@@ -178,7 +180,11 @@ fn LocationItem(city: City, region_name: String, current_tab: String) -> Element
                         "{city.name}"
                     }
                     div { class: "text-[11px] text-muted-foreground font-mono",
-                        "{city.ping}ms • {city.load}% load"
+                        if city.available {
+                            "{city.ping}ms • {city.load}% load"
+                        } else {
+                            "Unavailable"
+                        }
                     }
                 }
             }
@@ -211,6 +217,21 @@ fn LocationItem(city: City, region_name: String, current_tab: String) -> Element
                     }
                 }
 
+                button {
+                    class: "p-2 hover:bg-accent rounded-lg transition-all",
+                    class: if is_pinned { "text-primary" } else { "text-muted-foreground opacity-0 group-hover:opacity-100 focus:opacity-100" },
+                    title: if is_pinned { "Unpin server" } else { "Pin server: stay on it instead of switching automatically" },
+                    onclick: move |e| {
+                        e.stop_propagation();
+                        pin_settings.with_mut(|s| s.toggle_pin(&pin_location));
+                    },
+                    if is_pinned {
+                        Pin { size: 16, fill: Some("currentColor".to_string()) }
+                    } else {
+                        Pin { size: 16 }
+                    }
+                }
+
                 button {
                     class: "p-2 hover:bg-accent rounded-lg transition-all",
                     class: if is_fav { "text-status-warning" } else { "text-muted-foreground opacity-0 group-hover:opacity-100 focus:opacity-100" },
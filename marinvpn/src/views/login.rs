@@ -2,13 +2,57 @@ use crate::components::toast::ToastManager;
 use crate::components::toast::ToastType;
 use crate::components::ui::Modal;
 use crate::hooks::use_i18n;
-use crate::services::auth::AuthService;
+use crate::services::auth::{AuthService, LoginOutcome, LoginSession};
+use crate::services::self_test::{self, SelfTest, SelfTestStep, SELF_TEST_TIMEOUT};
+use crate::services::vpn::WireGuardService;
 use crate::state::ConnectionState;
 use dioxus::prelude::*;
+use marinvpn_common::account_number::{self, AccountNumberError};
+use tokio::sync::oneshot;
+
+fn sign_in(mut state: ConnectionState, session: LoginSession) {
+    let account_number = session.account.account_number.clone();
+    state.account_number.set(Some(account_number));
+    state.auth_token.set(Some(session.auth_token));
+    state.refresh_token.set(Some(session.refresh_token));
+    state.account_expiry.set(Some(session.account.expiry_date));
+    state.device_name.set(session.device_name);
+}
+
+/// Shown under the account number field as it is filled in.
+fn input_hint(input: &str) -> Option<(String, bool)> {
+    match account_number::validate(input) {
+        _ if input.is_empty() => None,
+        Ok(_) => Some(("Looks good".to_string(), false)),
+        Err(AccountNumberError::InvalidCharacter(c)) => {
+            Some((format!("\"{}\" never appears in account numbers", c), true))
+        }
+        Err(AccountNumberError::TooShort { missing }) => {
+            Some((format!("{} more characters", missing), false))
+        }
+        Err(AccountNumberError::TooLong) => Some(("Too many characters".to_string(), true)),
+    }
+}
+
+/// What to tell the user about a login that did not go through.
+fn refusal_message(outcome: LoginOutcome) -> String {
+    match outcome {
+        LoginOutcome::Expired => "This account has expired. Add time to it to log in.".to_string(),
+        LoginOutcome::Invalid => "No account has this number.".to_string(),
+        LoginOutcome::RateLimited => {
+            "Too many login attempts. Please wait and try again.".to_string()
+        }
+        LoginOutcome::DeviceLimit { message, .. } => {
+            message.unwrap_or_else(|| "Device limit reached".to_string())
+        }
+        LoginOutcome::Refused(message) => message,
+        LoginOutcome::Success(_) => String::new(),
+    }
+}
 
 #[component]
 pub fn Login() -> Element {
-    let mut state = use_context::<ConnectionState>();
+    let state = use_context::<ConnectionState>();
     let mut toasts = use_context::<ToastManager>();
     let i18n = use_i18n();
     let branding = state.settings.read();
@@ -19,51 +63,64 @@ pub fn Login() -> Element {
     let mut is_loading = use_signal(|| false);
     let mut device_limit = use_signal(|| None as Option<Vec<crate::models::Device>>);
     let mut limit_error = use_signal(|| None as Option<String>);
+    // Answers the login flow waiting on the device picker: the device to
+    // kick, or `None` when the picker is closed.
+    let mut kick_choice = use_signal(|| None as Option<oneshot::Sender<Option<String>>>);
+    let mut choose_kick = move |device: Option<String>| {
+        device_limit.set(None);
+        limit_error.set(None);
+        if let Some(choice) = kick_choice.write().take() {
+            let _ = choice.send(device);
+        }
+    };
+    // Saved from an earlier login on this machine; its old entry may be
+    // what holds the slot now.
+    let this_device = (state.device_name)();
+    // Set while the self-test dialog is open.
+    let mut self_test = use_signal(|| None as Option<SelfTest>);
 
     let on_login = move |_| {
-        let acc_num = account_input().replace(" ", "").to_uppercase();
-        if acc_num.len() < 16 {
-            toasts.show("Invalid account number format", ToastType::Error);
+        let Ok(acc_num) = account_number::validate(&account_input()) else {
+            toasts.show(i18n.tr("invalid_account"), ToastType::Error);
             return;
-        }
+        };
 
         spawn(async move {
             is_loading.set(true);
-            match AuthService::login(&acc_num, None).await {
-                Ok(resp) => {
-                    if resp.success {
-                        if let (Some(info), Some(device), Some(token), Some(refresh)) = (
-                            resp.account_info,
-                            resp.current_device,
-                            resp.auth_token,
-                            resp.refresh_token,
-                        ) {
-                            state.account_number.set(Some(info.account_number.clone()));
-                            state.auth_token.set(Some(token));
-                            state.refresh_token.set(Some(refresh));
-                            state.account_expiry.set(Some(info.expiry_date));
-                            state.device_name.set(device);
-                            toasts.show("Logged in successfully", ToastType::Success);
-                            navigator().push(crate::Route::Dashboard {});
-                        } else {
-                            toasts.show("Invalid login response", ToastType::Error);
-                        }
-                    } else if let Some(devs) = resp.devices {
-                        device_limit.set(Some(devs));
-                        limit_error.set(resp.error);
-                    } else {
-                        toasts.show(
-                            &resp.error.unwrap_or_else(|| "Login failed".to_string()),
-                            ToastType::Error,
-                        );
-                    }
+            let outcome = AuthService::login_with_kick(&acc_num, move |devices, message| {
+                let (choice, chosen) = oneshot::channel();
+                kick_choice.set(Some(choice));
+                device_limit.set(Some(devices));
+                limit_error.set(message);
+                async move { chosen.await.ok().flatten() }
+            })
+            .await;
+            match outcome {
+                Ok(LoginOutcome::Success(session)) => {
+                    sign_in(state, session);
+                    toasts.show("Logged in successfully", ToastType::Success);
+                    navigator().push(crate::Route::Dashboard {});
                 }
+                // The device picker was closed.
+                Ok(LoginOutcome::DeviceLimit { .. }) => {}
+                Ok(refused) => toasts.show(&refusal_message(refused), ToastType::Error),
                 Err(e) => toasts.show(&e.user_friendly_message(), ToastType::Error),
             }
             is_loading.set(false);
         });
     };
 
+    // Password managers and notes paste with all sorts of separators.
+    let on_paste = move |_| {
+        spawn(async move {
+            let read = document::eval("return await navigator.clipboard.readText();");
+            match read.join::<String>().await {
+                Ok(text) => account_input.set(account_number::format_groups(&text)),
+                Err(_) => toasts.show("Could not read the clipboard", ToastType::Error),
+            }
+        });
+    };
+
     let on_generate = move |_| {
         spawn(async move {
             is_loading.set(true);
@@ -78,6 +135,28 @@ pub fn Login() -> Element {
         });
     };
 
+    // Runs on a service of its own, so it works before login and never
+    // touches the network or a real tunnel.
+    let on_self_test = move |_| {
+        let settings = state.settings.peek().clone();
+        self_test.set(Some(SelfTest {
+            running: true,
+            results: Vec::new(),
+        }));
+        spawn(async move {
+            let service = WireGuardService::self_test();
+            self_test::run(&service, &settings, SELF_TEST_TIMEOUT, |result| {
+                if let Some(test) = self_test.write().as_mut() {
+                    test.results.push(result.clone());
+                }
+            })
+            .await;
+            if let Some(test) = self_test.write().as_mut() {
+                test.running = false;
+            }
+        });
+    };
+
     rsx! {
         div { class: "flex-1 flex flex-col items-center justify-center p-8 bg-background relative overflow-hidden",
             div { class: "absolute -top-24 -right-24 w-64 h-64 bg-primary/10 rounded-full blur-3xl" }
@@ -98,22 +177,43 @@ pub fn Login() -> Element {
 
                 div { class: "space-y-4",
                     div { class: "space-y-2",
-                        label { class: "text-sm font-medium leading-none peer-disabled:cursor-not-allowed peer-disabled:opacity-70",
-                            {i18n.tr("account_number")}
+                        div { class: "flex items-center justify-between",
+                            label {
+                                r#for: "account-number",
+                                class: "text-sm font-medium leading-none peer-disabled:cursor-not-allowed peer-disabled:opacity-70",
+                                {i18n.tr("account_number")}
+                            }
+                            button {
+                                class: "text-xs font-semibold text-primary hover:underline disabled:opacity-50",
+                                onclick: on_paste,
+                                disabled: is_loading(),
+                                "Paste"
+                            }
                         }
                         input {
-                            class: "flex h-12 w-full rounded-xl border border-input bg-background px-4 py-2 text-lg ring-offset-background file:border-0 file:bg-transparent file:text-sm file:font-medium placeholder:text-muted-foreground focus-visible:outline-none focus-visible:ring-2 focus-visible:ring-primary focus-visible:ring-offset-2 disabled:cursor-not-allowed disabled:opacity-50 transition-all",
-                            placeholder: "ABCD EFGH JKLM NOPQ",
+                            id: "account-number",
+                            name: "username",
+                            autocomplete: "username",
+                            autocapitalize: "characters",
+                            spellcheck: "false",
+                            class: "flex h-12 w-full rounded-xl border border-input bg-background px-4 py-2 text-lg font-mono tracking-wider ring-offset-background file:border-0 file:bg-transparent file:text-sm file:font-medium placeholder:text-muted-foreground focus-visible:outline-none focus-visible:ring-2 focus-visible:ring-primary focus-visible:ring-offset-2 disabled:cursor-not-allowed disabled:opacity-50 transition-all",
+                            placeholder: "ABCD EFGH JKLM NPQR",
                             value: "{account_input}",
-                            oninput: move |e| account_input.set(e.value()),
+                            oninput: move |e| account_input.set(account_number::format_groups(&e.value())),
                             disabled: is_loading(),
                         }
+                        if let Some((hint, is_error)) = input_hint(&account_input()) {
+                            p {
+                                class: if is_error { "text-xs text-destructive" } else { "text-xs text-muted-foreground" },
+                                "{hint}"
+                            }
+                        }
                     }
 
                     button {
                         class: "inline-flex items-center justify-center rounded-xl text-sm font-medium ring-offset-background transition-all focus-visible:outline-none focus-visible:ring-2 focus-visible:ring-ring focus-visible:ring-offset-2 disabled:pointer-events-none disabled:opacity-50 bg-primary text-primary-foreground hover:bg-primary/90 h-12 px-4 py-2 w-full text-base",
                         onclick: on_login,
-                        disabled: is_loading() || account_input().is_empty(),
+                        disabled: is_loading() || account_number::validate(&account_input()).is_err(),
                         if is_loading() {
                             div { class: "mr-2 h-4 w-4 animate-spin rounded-full border-2 border-current border-t-transparent" }
                         }
@@ -140,16 +240,62 @@ pub fn Login() -> Element {
                 p { class: "px-8 text-center text-sm text-muted-foreground leading-relaxed",
                     {i18n.tr("login_footer")}
                 }
+
+                div { class: "text-center",
+                    button {
+                        class: "text-xs font-semibold text-muted-foreground hover:text-primary hover:underline disabled:opacity-50",
+                        onclick: on_self_test,
+                        disabled: self_test().is_some_and(|test| test.running),
+                        "Test the app without an account"
+                    }
+                }
+            }
+        }
+
+        if let Some(test) = self_test() {
+            Modal {
+                title: "Self-test".to_string(),
+                onclose: move |_| self_test.set(None),
+                children: rsx! {
+                    div { class: "text-xs text-muted-foreground mb-3",
+                        "Simulates a tunnel on this device to check the app's connect and disconnect path. No real connection is made and nothing is sent over the network."
+                    }
+                    div { class: "space-y-1",
+                        for step in [SelfTestStep::Connect, SelfTestStep::Stats, SelfTestStep::Disconnect] {
+                            {
+                                let result = test.results.iter().find(|r| r.step == step);
+                                let (label, class) = match result.map(|r| &r.outcome) {
+                                    Some(Ok(())) => ("Passed".to_string(), "text-primary"),
+                                    Some(Err(reason)) => (reason.clone(), "text-destructive"),
+                                    None if test.running => ("...".to_string(), "opacity-50"),
+                                    None => ("Skipped".to_string(), "opacity-50"),
+                                };
+                                rsx! {
+                                    div { class: "flex items-center justify-between gap-2 text-[10px] font-mono",
+                                        span { "{step.label()}" }
+                                        span { class: "{class} text-right", "{label}" }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    if !test.running {
+                        div { class: "mt-3 text-xs font-semibold",
+                            if test.passed() {
+                                "The app's tunnel handling works on this device."
+                            } else {
+                                "The self-test failed. Include the error above when contacting support."
+                            }
+                        }
+                    }
+                }
             }
         }
 
         if let Some(devices) = device_limit() {
             Modal {
                 title: "Device limit reached".to_string(),
-                onclose: move |_| {
-                    device_limit.set(None);
-                    limit_error.set(None);
-                },
+                onclose: move |_| choose_kick(None),
                 children: rsx! {
                     div { class: "text-xs text-muted-foreground mb-3",
                         {limit_error().unwrap_or_else(|| "Remove a device to continue.".to_string())}
@@ -158,11 +304,14 @@ pub fn Login() -> Element {
                         for device in devices {
                             {
                                 let dev_name = device.name.clone();
-                                let acc = account_input();
+                                let is_this_device = dev_name == this_device;
                                 rsx! {
                                     div { class: "flex items-center justify-between gap-2 border border-border rounded-xl px-3 py-2",
                                         div { class: "text-xs",
                                             div { class: "font-semibold capitalize text-foreground", "{dev_name}" }
+                                            if is_this_device {
+                                                div { class: "text-[10px] font-bold text-primary", "This device, from an earlier login" }
+                                            }
                                             {
                                                 let date_str = device.created_date.clone();
                                                 rsx! { div { class: "text-[10px] text-muted-foreground", "Created {date_str}" } }
@@ -170,43 +319,9 @@ pub fn Login() -> Element {
                                         }
                                         button {
                                             class: "h-9 px-3 text-[10px] font-bold rounded-lg bg-destructive text-destructive-foreground hover:opacity-90 transition-all",
-                                            onclick: move |_| {
-                                                let acc_num = acc.replace(" ", "").to_uppercase();
-                                                let dev = dev_name.clone();
-                                                spawn(async move {
-                                                    match AuthService::login(&acc_num, Some(dev)).await {
-                                                        Ok(resp) => {
-                                                            if resp.success {
-                                                                if let (Some(info), Some(device), Some(token), Some(refresh)) = (
-                                                                    resp.account_info,
-                                                                    resp.current_device,
-                                                                    resp.auth_token,
-                                                                    resp.refresh_token,
-                                                                ) {
-                                                                    state.account_number.set(Some(info.account_number.clone()));
-                                                                    state.auth_token.set(Some(token));
-                                                                    state.refresh_token.set(Some(refresh));
-                                                                    state.account_expiry.set(Some(info.expiry_date));
-                                                                    state.device_name.set(device);
-                                                                    toasts.show("Logged in successfully", ToastType::Success);
-                                                                    device_limit.set(None);
-                                                                    limit_error.set(None);
-                                                                    navigator().push(crate::Route::Dashboard {});
-                                                                } else {
-                                                                    toasts.show("Invalid login response", ToastType::Error);
-                                                                }
-                                                            } else {
-                                                                toasts.show(
-                                                                    &resp.error.unwrap_or_else(|| "Login failed".to_string()),
-                                                                    ToastType::Error,
-                                                                );
-                                                            }
-                                                        }
-                                                        Err(e) => toasts.show(&e.user_friendly_message(), ToastType::Error),
-                                                    }
-                                                });
-                                            },
-                                            "Kick & Continue"
+                                            onclick: move |_| choose_kick(Some(dev_name.clone())),
+                                            // Its entry is replaced by this login, not lost.
+                                            if is_this_device { "Replace & Continue" } else { "Kick & Continue" }
                                         }
                                     }
                                 }
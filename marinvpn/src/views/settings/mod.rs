@@ -6,10 +6,20 @@ pub use ui::UiSettings;
 pub use view::Settings;
 pub use vpn::VpnSettings;
 
-use crate::components::SettingRow;
-use crate::icons::CircleCheck;
+use crate::components::{SettingDescription, SettingRow, SettingSelectRow, SettingTitle};
+use crate::icons::{CircleCheck, X};
+use crate::models::{
+    AppInfo, ConnectionStatus, Location, LocationSelection, SplitTunnelMode, StealthMode,
+};
+use crate::models::VpnAction;
+use crate::services::apps::AppScanner;
+use crate::services::cidr::Cidr;
+use crate::services::split_tunnel;
+use crate::services::stealth_probe::ProbeOutcome;
+use crate::services::vpn::stealth_port_hint;
 use crate::state::ConnectionState;
 use dioxus::prelude::*;
+use rfd::FileDialog;
 
 use crate::hooks::use_scroll_handler;
 
@@ -92,7 +102,7 @@ pub fn MultihopSettings() -> Element {
                             for region in regions.iter().cloned() {
                                 for city in region.cities.iter().cloned() {
                                     {
-                                        let loc = format!("{}, {}", region.name, city.name);
+                                        let loc = LocationSelection::from(Location::new(&region.name, &city.name));
                                         let loc2 = loc.clone();
                                         let is_active = s.entry_location == loc;
                                         rsx! {
@@ -124,7 +134,7 @@ pub fn MultihopSettings() -> Element {
                             for region in regions.iter().cloned() {
                                 for city in region.cities.iter().cloned() {
                                     {
-                                        let loc = format!("{}, {}", region.name, city.name);
+                                        let loc = LocationSelection::from(Location::new(&region.name, &city.name));
                                         let loc2 = loc.clone();
                                         let is_active = s.exit_location == loc;
                                         rsx! {
@@ -157,16 +167,267 @@ pub fn MultihopSettings() -> Element {
 
 #[component]
 pub fn SplitTunnelingSettings() -> Element {
+    let mut state = use_context::<ConnectionState>();
+    let (enabled, mode, ranges, listed_apps) = {
+        let s = state.settings.read();
+        let ranges = match s.split_mode {
+            SplitTunnelMode::Exclude => s.excluded_ips.clone(),
+            SplitTunnelMode::IncludeOnly => s.included_ips.clone(),
+        };
+        let listed_apps: Vec<String> = s.split_apps().iter().map(|a| a.path.clone()).collect();
+        (s.split_tunneling, s.split_mode, ranges, listed_apps)
+    };
+    let installed_apps = use_resource(AppScanner::scan_installed_apps);
+    // Apps get uninstalled or moved by updates while the page is open too.
+    let mut recheck = use_signal(|| 0u32);
+    use_future(move || async move {
+        loop {
+            tokio::time::sleep(std::time::Duration::from_secs(30)).await;
+            recheck += 1;
+        }
+    });
+    let missing_apps = use_memo(move || {
+        recheck();
+        split_tunnel::missing_apps(state.settings.read().split_apps())
+    });
+    let apps_description = match mode {
+        SplitTunnelMode::Exclude => "Checked apps connect directly instead of through the tunnel.",
+        SplitTunnelMode::IncludeOnly => {
+            "Only checked apps use the tunnel and everything else connects directly. On Windows the other apps still use the tunnel, but checked apps can never leave outside it."
+        }
+    };
+    // Apps added by hand that no installed app stands for. Missing ones are
+    // listed above with a way to find them again.
+    let picked_apps: Vec<AppInfo> = {
+        let installed = installed_apps.read();
+        let missing = missing_apps();
+        state
+            .settings
+            .read()
+            .split_apps()
+            .iter()
+            .filter(|app| !missing.iter().any(|m| m.path == app.path))
+            .filter(|app| {
+                installed
+                    .as_ref()
+                    .is_none_or(|apps| !apps.iter().any(|a| a.path == app.path))
+            })
+            .cloned()
+            .collect()
+    };
+    let mut new_range = use_signal(String::new);
+    let mut range_error = use_signal(|| None::<String>);
+
+    let mut add_range = move || {
+        let input = new_range();
+        if input.trim().is_empty() {
+            return;
+        }
+        match input.parse::<Cidr>() {
+            Ok(range) => {
+                let range = range.to_string();
+                state.settings.with_mut(|s| {
+                    let ranges = s.split_ranges_mut();
+                    if !ranges.contains(&range) {
+                        ranges.push(range);
+                    }
+                });
+                new_range.set(String::new());
+                range_error.set(None);
+            }
+            Err(e) => range_error.set(Some(e.to_string())),
+        }
+    };
+
     rsx! {
         div { class: "h-full w-full overflow-y-auto custom-scrollbar",
             div { class: "pb-24 -mx-4 divide-y divide-border/30",
-                div { class: "p-6 text-center text-muted-foreground text-xs", "No apps excluded" }
-                for app in ["Chrome", "Discord", "Spotify", "Steam"] {
-                    div {
-                        class: "px-4 flex items-center justify-between hover:bg-accent/20 transition-colors border-b border-border/50 last:border-0 shrink-0",
-                        style: "height: 48px !important; min-height: 48px !important;",
-                        span { class: "text-sm font-medium", "{app}" }
-                        div { class: "w-4 h-4 rounded border border-border group-hover:border-primary transition-colors" }
+                div { class: "flex flex-col",
+                    SettingRow {
+                        id: "split-tunneling",
+                        label: "Split tunneling".to_string(),
+                        checked: enabled,
+                        onclick: move |_| {
+                            state.settings.with_mut(|s| s.split_tunneling = !s.split_tunneling);
+                        },
+                    }
+                    SettingSelectRow {
+                        label: "Exclude what is listed".to_string(),
+                        selected: mode == SplitTunnelMode::Exclude,
+                        onclick: move |_| {
+                            state.settings.with_mut(|s| s.split_mode = SplitTunnelMode::Exclude);
+                        },
+                    }
+                    SettingSelectRow {
+                        label: "Only tunnel what is listed".to_string(),
+                        selected: mode == SplitTunnelMode::IncludeOnly,
+                        onclick: move |_| {
+                            state.settings.with_mut(|s| s.split_mode = SplitTunnelMode::IncludeOnly);
+                        },
+                    }
+                    SettingDescription {
+                        text: "Ranges are IP addresses or CIDR blocks such as 192.168.0.0/16. Changes to ranges apply on the next connect.".to_string(),
+                    }
+                }
+
+                div { class: "flex flex-col",
+                    for range in ranges {
+                        div {
+                            key: "{range}",
+                            class: "px-4 flex items-center justify-between border-b border-border/50 last:border-0 shrink-0",
+                            style: "height: 48px !important; min-height: 48px !important;",
+                            span { class: "text-sm font-mono", "{range}" }
+                            button {
+                                class: "text-muted-foreground hover:text-destructive transition-colors p-1",
+                                onclick: {
+                                    let range = range.clone();
+                                    move |_| {
+                                        let range = range.clone();
+                                        state.settings.with_mut(|s| s.split_ranges_mut().retain(|r| *r != range));
+                                    }
+                                },
+                                X { size: 14 }
+                            }
+                        }
+                    }
+                    div { class: "px-4 py-2 flex gap-2",
+                        input {
+                            class: "flex-1 bg-accent/20 border border-border rounded-xl px-3 py-2 text-xs font-mono focus:outline-none focus:ring-2 focus:ring-primary/20 transition-all",
+                            value: "{new_range}",
+                            placeholder: "e.g. 10.0.0.0/8",
+                            oninput: move |e| new_range.set(e.value()),
+                            onkeydown: move |e| {
+                                if e.key() == Key::Enter {
+                                    add_range();
+                                }
+                            },
+                        }
+                        button {
+                            class: "px-3 rounded-xl bg-primary text-primary-foreground text-xs font-bold disabled:opacity-50",
+                            disabled: new_range().trim().is_empty(),
+                            onclick: move |_| add_range(),
+                            "Add"
+                        }
+                    }
+                    if let Some(error) = range_error() {
+                        p { class: "px-4 pb-2 text-[11px] text-destructive", "{error}" }
+                    }
+                }
+
+                div { class: "flex flex-col",
+                    SettingTitle { label: "Apps".to_string() }
+                    SettingDescription { text: apps_description.to_string() }
+                    for app in missing_apps() {
+                        div {
+                            key: "{app.path}",
+                            class: "px-4 py-2 flex items-center justify-between gap-2 border-b border-border/50 bg-destructive/5 shrink-0",
+                            div { class: "min-w-0",
+                                p { class: "text-sm font-medium", "{app.name}" }
+                                p { class: "text-[11px] text-destructive truncate", "Not found at {app.path}" }
+                            }
+                            div { class: "flex items-center gap-2 shrink-0",
+                                if let Some(found) = installed_apps
+                                    .read()
+                                    .as_ref()
+                                    .and_then(|installed| split_tunnel::relocation(&app, installed).cloned())
+                                {
+                                    button {
+                                        class: "px-3 py-1 rounded-xl bg-primary text-primary-foreground text-xs font-bold",
+                                        title: "{found.path}",
+                                        onclick: {
+                                            let stale = app.path.clone();
+                                            move |_| {
+                                                let (stale, path) = (stale.clone(), found.path.clone());
+                                                state.settings.with_mut(|s| relocate_app(s.split_apps_mut(), &stale, path));
+                                            }
+                                        },
+                                        "Use installed"
+                                    }
+                                }
+                                button {
+                                    class: "px-3 py-1 rounded-xl bg-accent/30 hover:bg-accent border border-border text-xs font-bold",
+                                    onclick: {
+                                        let stale = app.path.clone();
+                                        move |_| {
+                                            if let Some(path) = FileDialog::new().pick_file() {
+                                                let path = path.to_string_lossy().to_string();
+                                                state.settings.with_mut(|s| relocate_app(s.split_apps_mut(), &stale, path));
+                                            }
+                                        }
+                                    },
+                                    "Re-locate"
+                                }
+                                button {
+                                    class: "text-muted-foreground hover:text-destructive transition-colors p-1",
+                                    onclick: {
+                                        let stale = app.path.clone();
+                                        move |_| {
+                                            state.settings.with_mut(|s| s.split_apps_mut().retain(|a| a.path != stale));
+                                        }
+                                    },
+                                    X { size: 14 }
+                                }
+                            }
+                        }
+                    }
+                    for app in picked_apps {
+                        SettingRow {
+                            key: "{app.path}",
+                            label: app.name.clone(),
+                            checked: true,
+                            onclick: move |_| {
+                                let path = app.path.clone();
+                                state.settings.with_mut(|s| s.split_apps_mut().retain(|a| a.path != path));
+                            },
+                        }
+                    }
+                    div { class: "px-4 py-2",
+                        button {
+                            class: "w-full bg-accent/30 hover:bg-accent border border-border rounded-xl text-xs font-bold py-2 transition-all active:scale-95",
+                            onclick: move |_| {
+                                let Some(app) = FileDialog::new()
+                                    .pick_file()
+                                    .and_then(|path| split_tunnel::picked_app(&path.to_string_lossy()))
+                                else {
+                                    return;
+                                };
+                                state.settings.with_mut(|s| {
+                                    let apps = s.split_apps_mut();
+                                    if !apps.iter().any(|a| a.path == app.path) {
+                                        apps.push(app);
+                                    }
+                                });
+                            },
+                            "Add application"
+                        }
+                    }
+                    match &*installed_apps.read() {
+                        None => rsx! {
+                            div { class: "p-6 text-center text-muted-foreground text-xs", "Looking for installed apps..." }
+                        },
+                        Some(apps) if apps.is_empty() => rsx! {
+                            div { class: "p-6 text-center text-muted-foreground text-xs", "No installed apps found" }
+                        },
+                        Some(apps) => rsx! {
+                            for app in apps.clone() {
+                                SettingRow {
+                                    key: "{app.path}",
+                                    label: app.name.clone(),
+                                    checked: listed_apps.contains(&app.path),
+                                    onclick: move |_| {
+                                        let app = app.clone();
+                                        state.settings.with_mut(|s| {
+                                            let apps = s.split_apps_mut();
+                                            if apps.iter().any(|a| a.path == app.path) {
+                                                apps.retain(|a| a.path != app.path);
+                                            } else {
+                                                apps.push(app);
+                                            }
+                                        });
+                                    },
+                                }
+                            }
+                        },
                     }
                 }
             }
@@ -174,10 +435,47 @@ pub fn SplitTunnelingSettings() -> Element {
     }
 }
 
+/// Points the listed app at `stale` to `path`, dropping it instead if `path`
+/// is already listed.
+fn relocate_app(apps: &mut Vec<AppInfo>, stale: &str, path: String) {
+    if apps.iter().any(|a| a.path == path) {
+        apps.retain(|a| a.path != stale);
+    } else if let Some(app) = apps.iter_mut().find(|a| a.path == stale) {
+        app.path = path;
+    }
+}
+
+fn stealth_mode_label(mode: StealthMode) -> &'static str {
+    match mode {
+        StealthMode::Automatic => "Automatic",
+        StealthMode::WireGuardPort => "WireGuard Port",
+        StealthMode::WireGuard443 => "WireGuard 443",
+        StealthMode::Lwo => "LWO",
+        StealthMode::Quic => "QUIC",
+        StealthMode::Shadowsocks => "Shadowsocks",
+        StealthMode::Tcp => "UDP-over-TCP",
+        StealthMode::None => "None",
+    }
+}
+
+fn probe_outcome_label(outcome: &ProbeOutcome) -> String {
+    match outcome {
+        ProbeOutcome::Handshake(latency) => format!("{} ms", latency.as_millis()),
+        ProbeOutcome::Failed(_) => "Failed".to_string(),
+        ProbeOutcome::TimedOut => "Timed out".to_string(),
+    }
+}
+
 #[component]
 pub fn AntiCensorshipSettings() -> Element {
     let mut state = use_context::<ConnectionState>();
     let s = state.settings.read();
+    let active_port = match (state.status)() {
+        ConnectionStatus::Connected => (state.active_port)(),
+        _ => None,
+    };
+    let probe = (state.stealth_probe)();
+    let can_probe = !probe.running && (state.status)() == ConnectionStatus::Disconnected;
 
     rsx! {
         div { class: "h-full w-full overflow-y-auto custom-scrollbar",
@@ -190,6 +488,9 @@ pub fn AntiCensorshipSettings() -> Element {
 
                 div { class: "p-4 bg-accent/5",
                     h4 { class: "text-[10px] font-bold text-muted-foreground uppercase tracking-widest mb-3", "Stealth Protocol" }
+                    if let Some(port) = active_port {
+                        p { class: "text-[10px] text-primary font-mono mb-3", "Active: {port}" }
+                    }
 
                     div { class: "space-y-1",
                         // Automatic Mode
@@ -202,6 +503,7 @@ pub fn AntiCensorshipSettings() -> Element {
                             div { class: "flex flex-col",
                                 span { class: "text-xs font-bold", "Automatic" }
                                 span { class: "text-[10px] opacity-70", "Intelligently select the best protocol for your network" }
+                                span { class: "text-[10px] font-mono opacity-50", {stealth_port_hint(StealthMode::Automatic)} }
                             }
                             if s.stealth_mode == crate::models::StealthMode::Automatic {
                                 CircleCheck { size: 14 }
@@ -217,13 +519,31 @@ pub fn AntiCensorshipSettings() -> Element {
                             },
                             div { class: "flex flex-col",
                                 span { class: "text-xs font-bold", "WireGuard Port" }
-                                span { class: "text-[10px] opacity-70", "Standard UDP but using common ports (e.g. 53, 123)" }
+                                span { class: "text-[10px] opacity-70", "Standard WireGuard UDP on the DNS port" }
+                                span { class: "text-[10px] font-mono opacity-50", {stealth_port_hint(StealthMode::WireGuardPort)} }
                             }
                             if s.stealth_mode == crate::models::StealthMode::WireGuardPort {
                                 CircleCheck { size: 14 }
                             }
                         }
 
+                        // WireGuard 443 Mode
+                        div {
+                            class: "px-3 py-2 rounded-xl flex items-center justify-between cursor-pointer transition-colors",
+                            class: if s.stealth_mode == crate::models::StealthMode::WireGuard443 { "bg-primary/10 text-primary" } else { "hover:bg-accent/40 text-foreground" },
+                            onclick: move |_| {
+                                state.settings.with_mut(|s| s.stealth_mode = crate::models::StealthMode::WireGuard443);
+                            },
+                            div { class: "flex flex-col",
+                                span { class: "text-xs font-bold", "WireGuard 443" }
+                                span { class: "text-[10px] opacity-70", "Standard WireGuard UDP on the HTTPS/QUIC port" }
+                                span { class: "text-[10px] font-mono opacity-50", {stealth_port_hint(StealthMode::WireGuard443)} }
+                            }
+                            if s.stealth_mode == crate::models::StealthMode::WireGuard443 {
+                                CircleCheck { size: 14 }
+                            }
+                        }
+
                         // LWO Mode
                         div {
                             class: "px-3 py-2 rounded-xl flex items-center justify-between cursor-pointer transition-colors",
@@ -234,6 +554,7 @@ pub fn AntiCensorshipSettings() -> Element {
                             div { class: "flex flex-col",
                                 span { class: "text-xs font-bold", "LWO (Lightweight)" }
                                 span { class: "text-[10px] opacity-70", "Obfuscated WireGuard headers with minimal overhead" }
+                                span { class: "text-[10px] font-mono opacity-50", {stealth_port_hint(StealthMode::Lwo)} }
                             }
                             if s.stealth_mode == crate::models::StealthMode::Lwo {
                                 CircleCheck { size: 14 }
@@ -250,6 +571,7 @@ pub fn AntiCensorshipSettings() -> Element {
                             div { class: "flex flex-col",
                                 span { class: "text-xs font-bold", "QUIC (HTTP/3)" }
                                 span { class: "text-[10px] opacity-70", "High-performance UDP-over-QUIC" }
+                                span { class: "text-[10px] font-mono opacity-50", {stealth_port_hint(StealthMode::Quic)} }
                             }
                             if s.stealth_mode == crate::models::StealthMode::Quic {
                                 CircleCheck { size: 14 }
@@ -266,6 +588,7 @@ pub fn AntiCensorshipSettings() -> Element {
                             div { class: "flex flex-col",
                                 span { class: "text-xs font-bold", "Shadowsocks (AEAD)" }
                                 span { class: "text-[10px] opacity-70", "Industry standard for circumvention" }
+                                span { class: "text-[10px] font-mono opacity-50", {stealth_port_hint(StealthMode::Shadowsocks)} }
                             }
                             if s.stealth_mode == crate::models::StealthMode::Shadowsocks {
                                 CircleCheck { size: 14 }
@@ -282,6 +605,7 @@ pub fn AntiCensorshipSettings() -> Element {
                             div { class: "flex flex-col",
                                 span { class: "text-xs font-bold", "UDP-over-TCP" }
                                 span { class: "text-[10px] opacity-70", "Raw TCP encapsulation via WSTunnel" }
+                                span { class: "text-[10px] font-mono opacity-50", {stealth_port_hint(StealthMode::Tcp)} }
                             }
                             if s.stealth_mode == crate::models::StealthMode::Tcp {
                                 CircleCheck { size: 14 }
@@ -298,6 +622,7 @@ pub fn AntiCensorshipSettings() -> Element {
                             div { class: "flex flex-col",
                                 span { class: "text-xs font-bold", "None" }
                                 span { class: "text-[10px] opacity-70", "Standard WireGuard UDP" }
+                                span { class: "text-[10px] font-mono opacity-50", {stealth_port_hint(StealthMode::None)} }
                             }
                             if s.stealth_mode == crate::models::StealthMode::None {
                                 CircleCheck { size: 14 }
@@ -306,6 +631,27 @@ pub fn AntiCensorshipSettings() -> Element {
                     }
                 }
 
+                div { class: "p-4 space-y-3",
+                    button {
+                        class: "w-full px-3 py-2 rounded-xl text-xs font-bold bg-primary/10 text-primary hover:bg-primary/20 transition-colors disabled:opacity-50",
+                        disabled: !can_probe,
+                        onclick: move |_| state.vpn_action.send(VpnAction::FindStealthMode),
+                        if probe.running { "Testing protocols..." } else { "Find a working protocol" }
+                    }
+                    p { class: "text-[10px] text-muted-foreground",
+                        "Briefly connects with each protocol to the selected server and switches to the fastest one that works. Disconnect first."
+                    }
+                    for result in probe.results.iter() {
+                        div { class: "flex items-center justify-between text-[10px] font-mono",
+                            span { "{stealth_mode_label(result.mode)}" }
+                            span {
+                                class: if matches!(result.outcome, ProbeOutcome::Handshake(_)) { "text-primary" } else { "opacity-50" },
+                                "{probe_outcome_label(&result.outcome)}"
+                            }
+                        }
+                    }
+                }
+
                 div { class: "p-4",
                      p { class: "text-[10px] text-muted-foreground italic",
                         "Note: Using obfuscation protocols may slightly increase latency and reduce throughput due to encryption overhead."
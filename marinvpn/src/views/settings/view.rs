@@ -1,15 +1,15 @@
 use crate::components::MenuRow;
 use crate::icons::*;
+use crate::models::VpnAction;
 use crate::state::ConnectionState;
 use crate::Route;
-use dioxus::desktop::use_window;
 use dioxus::prelude::*;
 
 #[component]
 pub fn Settings() -> Element {
     let state = use_context::<ConnectionState>();
     let nav = use_navigator();
-    let window = use_window();
+    let mut quitting = use_signal(|| false);
 
     use_effect(move || {
         if let Some(target) = (state.scroll_to)() {
@@ -77,6 +77,7 @@ pub fn Settings() -> Element {
                     }
                     MenuRow {
                         label: "Split tunneling".to_string(),
+                        value: state.settings.read().split_tunneling_summary(),
                         icon: rsx! {
                             FlaskConical { size: 18 }
                         },
@@ -107,8 +108,10 @@ pub fn Settings() -> Element {
                         button {
                             class: "w-full flex items-center justify-center bg-destructive/10 hover:bg-destructive/20 text-destructive rounded-xl border border-destructive/20 transition-all font-bold shadow-sm active:scale-95 text-xs shrink-0",
                             style: "height: 48px !important; min-height: 48px !important;",
+                            disabled: quitting(),
                             onclick: move |_| {
-                                window.close();
+                                quitting.set(true);
+                                state.vpn_action.send(VpnAction::Quit);
                             },
                             "Disconnect & Exit"
                         }
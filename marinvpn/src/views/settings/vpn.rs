@@ -1,22 +1,41 @@
 use crate::components::*;
-use crate::icons::CircleAlert;
-use crate::models::IpVersion;
+use crate::icons::{CircleAlert, X};
+use crate::models::{DnsRoute, DnsRouteVia, IpVersion, KeyReuse, ReconnectPolicy, StartupBehavior};
+use crate::services::dns_routes;
 use crate::state::ConnectionState;
 use dioxus::prelude::*;
 
+const STARTUP_OPTIONS: [(&str, StartupBehavior); 4] = [
+    ("Don't connect", StartupBehavior::DoNothing),
+    ("Last connection", StartupBehavior::ConnectLast),
+    ("Fastest server", StartupBehavior::ConnectFastest),
+    ("Selected location", StartupBehavior::ConnectConfigured),
+];
+
+const KEY_REUSE_OPTIONS: [(&str, KeyReuse); 3] = [
+    ("Never", KeyReuse::Fresh),
+    ("For 10 minutes", KeyReuse::Within(10 * 60)),
+    ("For 1 hour", KeyReuse::Within(60 * 60)),
+];
+
 #[component]
 pub fn VpnSettings(dns_expanded: Signal<bool>) -> Element {
     let mut state = use_context::<ConnectionState>();
     let nav = use_navigator();
     let settings_guard = state.settings.read();
     let launch_on_startup = settings_guard.launch_on_startup;
-    let auto_connect = settings_guard.auto_connect;
+    let startup_behavior = settings_guard.startup_behavior;
+    let offline_mode = settings_guard.offline_mode;
+    let battery_saver = settings_guard.battery_saver;
+    let reconnect_policy = settings_guard.reconnect_policy;
+    let key_reuse = settings_guard.key_reuse;
     let local_sharing = settings_guard.local_sharing;
     let dns_blocking = settings_guard.dns_blocking.clone();
     let custom_dns = settings_guard.custom_dns;
     let custom_dns_server = settings_guard.custom_dns_server.clone();
     let ipv6_support = settings_guard.ipv6_support;
     let lockdown_mode = settings_guard.lockdown_mode;
+    let keep_lockdown_after_exit = settings_guard.keep_lockdown_after_exit;
     let stealth_mode = settings_guard.stealth_mode;
     let quantum_resistant = settings_guard.quantum_resistant;
     let ip_version = settings_guard.ip_version;
@@ -186,16 +205,101 @@ pub fn VpnSettings(dns_expanded: Signal<bool>) -> Element {
                         state.settings.with_mut(|s| s.launch_on_startup = !s.launch_on_startup);
                     },
                 }
-            // Auto-connect
+            // Startup behavior
+            div { class: "flex flex-col",
+                SettingTitle { label: i18n.tr("auto_connect").to_string() }
+                for (label, option) in STARTUP_OPTIONS {
+                    SettingSelectRow {
+                        label: label.to_string(),
+                        selected: startup_behavior == option,
+                        onclick: move |_| {
+                            state.settings.with_mut(|s| s.set_startup_behavior(option));
+                        },
+                    }
+                }
+                SettingDescription {
+                    text: "What to connect to when the app launches. Last connection only reconnects if the VPN was still connected when the app closed, and goes back to the same servers.".to_string(),
+                }
+                SettingGap { height: 20, class: Some("!border-t-0".to_string()) }
+            }
+
+            // Offline mode
             div { class: "flex flex-col",
                 SettingRow {
-                    label: i18n.tr("auto_connect").to_string(),
-                    checked: auto_connect,
+                    id: "offline-mode",
+                    label: "Offline mode".to_string(),
+                    checked: offline_mode,
                     onclick: move |_| {
-                        state.settings.with_mut(|s| s.auto_connect = !s.auto_connect);
+                        state.settings.with_mut(|s| s.offline_mode = !s.offline_mode);
                     },
                 }
-                SettingDescription { text: i18n.tr("desc_auto_connect").to_string() }
+                SettingDescription {
+                    text: "While disconnected, stop syncing the server list and account tokens and measuring server latency. Nothing goes out until you connect.".to_string(),
+                }
+                SettingGap { height: 20, class: Some("!border-t-0".to_string()) }
+            }
+
+            // Battery saver
+            div { class: "flex flex-col",
+                SettingRow {
+                    id: "battery-saver",
+                    label: "Battery saver".to_string(),
+                    checked: battery_saver,
+                    onclick: move |_| {
+                        state.settings.with_mut(|s| s.battery_saver = !s.battery_saver);
+                    },
+                }
+                SettingDescription {
+                    text: "While running on battery, measure server latency about once every ten minutes instead of every minute. Latency updates resume as soon as you plug in.".to_string(),
+                }
+                SettingGap { height: 20, class: Some("!border-t-0".to_string()) }
+            }
+
+            // Reconnect policy
+            div { class: "flex flex-col",
+                SettingTitle { label: "Automatic reconnect".to_string() }
+                SettingSelectRow {
+                    label: "Never".to_string(),
+                    selected: reconnect_policy == ReconnectPolicy::Never,
+                    onclick: move |_| {
+                        state.settings.with_mut(|s| s.reconnect_policy = ReconnectPolicy::Never);
+                    },
+                }
+                SettingSelectRow {
+                    label: "On failure".to_string(),
+                    selected: reconnect_policy == ReconnectPolicy::OnFailure,
+                    onclick: move |_| {
+                        state.settings.with_mut(|s| s.reconnect_policy = ReconnectPolicy::OnFailure);
+                    },
+                }
+                SettingSelectRow {
+                    label: "Aggressive".to_string(),
+                    selected: reconnect_policy == ReconnectPolicy::Aggressive,
+                    onclick: move |_| {
+                        state.settings.with_mut(|s| s.reconnect_policy = ReconnectPolicy::Aggressive);
+                    },
+                }
+                SettingDescription {
+                    text: "On failure rebuilds a tunnel that stops responding and moves to another server when Automatic is selected. Aggressive checks more often and also moves within a chosen country. Never leaves reconnecting to you.".to_string(),
+                }
+                SettingGap { height: 20, class: Some("!border-t-0".to_string()) }
+            }
+
+            // WireGuard key reuse
+            div { class: "flex flex-col",
+                SettingTitle { label: "Reuse key on reconnect".to_string() }
+                for (label, option) in KEY_REUSE_OPTIONS {
+                    SettingSelectRow {
+                        label: label.to_string(),
+                        selected: key_reuse == option,
+                        onclick: move |_| {
+                            state.settings.with_mut(|s| s.key_reuse = option);
+                        },
+                    }
+                }
+                SettingDescription {
+                    text: "A new key for every connection keeps sessions apart. Reusing one lets an automatic reconnect to the same location keep its tunnel address. Connections you start yourself always use a new key.".to_string(),
+                }
                 SettingGap { height: 20, class: Some("!border-t-0".to_string()) }
             }
 
@@ -231,28 +335,28 @@ pub fn VpnSettings(dns_expanded: Signal<bool>) -> Element {
                             label: i18n.tr("ads").to_string(),
                             checked: dns_blocking.ads,
                             onclick: move |_| {
-                                state.settings.with_mut(|s| s.dns_blocking.ads = !s.dns_blocking.ads);
+                                state.settings.with_mut(|s| s.update_dns_blocking(|b| b.ads = !b.ads));
                             },
                         }
                         SettingRow {
                             label: i18n.tr("trackers").to_string(),
                             checked: dns_blocking.trackers,
                             onclick: move |_| {
-                                state.settings.with_mut(|s| s.dns_blocking.trackers = !s.dns_blocking.trackers);
+                                state.settings.with_mut(|s| s.update_dns_blocking(|b| b.trackers = !b.trackers));
                             },
                         }
                         SettingRow {
                             label: i18n.tr("malware").to_string(),
                             checked: dns_blocking.malware,
                             onclick: move |_| {
-                                state.settings.with_mut(|s| s.dns_blocking.malware = !s.dns_blocking.malware);
+                                state.settings.with_mut(|s| s.update_dns_blocking(|b| b.malware = !b.malware));
                             },
                         }
                         SettingRow {
                             label: i18n.tr("gambling").to_string(),
                             checked: dns_blocking.gambling,
                             onclick: move |_| {
-                                state.settings.with_mut(|s| s.dns_blocking.gambling = !s.dns_blocking.gambling);
+                                state.settings.with_mut(|s| s.update_dns_blocking(|b| b.gambling = !b.gambling));
                             },
                         }
                         SettingRow {
@@ -261,7 +365,7 @@ pub fn VpnSettings(dns_expanded: Signal<bool>) -> Element {
                             onclick: move |_| {
                                 state
                                     .settings
-                                    .with_mut(|s| s.dns_blocking.adult_content = !s.dns_blocking.adult_content);
+                                    .with_mut(|s| s.update_dns_blocking(|b| b.adult_content = !b.adult_content));
                             },
                         }
                         SettingRow {
@@ -270,7 +374,7 @@ pub fn VpnSettings(dns_expanded: Signal<bool>) -> Element {
                             onclick: move |_| {
                                 state
                                     .settings
-                                    .with_mut(|s| s.dns_blocking.social_media = !s.dns_blocking.social_media);
+                                    .with_mut(|s| s.update_dns_blocking(|b| b.social_media = !b.social_media));
                             },
                         }
                     }
@@ -280,7 +384,7 @@ pub fn VpnSettings(dns_expanded: Signal<bool>) -> Element {
                     label: "Use custom DNS server".to_string(),
                     checked: custom_dns,
                     onclick: move |_| {
-                        state.settings.with_mut(|s| s.custom_dns = !s.custom_dns);
+                        state.settings.with_mut(|s| s.set_custom_dns(!s.custom_dns));
                     },
                 }
                 if custom_dns {
@@ -296,7 +400,8 @@ pub fn VpnSettings(dns_expanded: Signal<bool>) -> Element {
                         }
                     }
                 }
-                SettingDescription { text: i18n.tr("desc_dns_blocking").to_string() }
+                SettingDescription { text: i18n.tr("desc_custom_dns_exclusive").to_string() }
+                DnsRoutes {}
                 SettingGap { height: 20, class: Some("!border-t-0".to_string()) }
             }
 
@@ -334,11 +439,27 @@ pub fn VpnSettings(dns_expanded: Signal<bool>) -> Element {
                     },
                 }
                 SettingDescription {
-                    text: "Blocks the Internet after you click on Disconnect or Quit. Always requires a VPN connection to reach the Internet."
+                    text: "Blocks the Internet after you click on Disconnect. Always requires a VPN connection to reach the Internet."
                         .to_string(),
                 }
                 SettingGap { height: 17, class: Some("!border-t-0".to_string()) }
             }
+            if lockdown_mode {
+                div { class: "flex flex-col",
+                    SettingRow {
+                        id: "keep-lockdown-after-exit",
+                        label: i18n.tr("keep_lockdown_after_exit").to_string(),
+                        checked: keep_lockdown_after_exit,
+                        onclick: move |_| {
+                            state
+                                .settings
+                                .with_mut(|s| s.keep_lockdown_after_exit = !s.keep_lockdown_after_exit);
+                        },
+                    }
+                    SettingDescription { text: i18n.tr("desc_keep_lockdown_after_exit").to_string() }
+                    SettingGap { height: 17, class: Some("!border-t-0".to_string()) }
+                }
+            }
             // Anti-censorship
             div { class: "flex flex-col",
                 SettingAction {
@@ -347,6 +468,7 @@ pub fn VpnSettings(dns_expanded: Signal<bool>) -> Element {
                         match stealth_mode {
                             crate::models::StealthMode::Automatic => "Automatic".to_string(),
                             crate::models::StealthMode::WireGuardPort => "Port 53".to_string(),
+                            crate::models::StealthMode::WireGuard443 => "Port 443".to_string(),
                             crate::models::StealthMode::Lwo => "LWO".to_string(),
                             crate::models::StealthMode::Quic => "QUIC".to_string(),
                             crate::models::StealthMode::Shadowsocks => "Shadowsocks".to_string(),
@@ -436,3 +558,124 @@ pub fn VpnSettings(dns_expanded: Signal<bool>) -> Element {
         }
     }
 }
+
+/// Domains answered by the tunnel's DNS or the local network's resolver,
+/// whichever the rest of DNS uses.
+#[component]
+fn DnsRoutes() -> Element {
+    let mut state = use_context::<ConnectionState>();
+    let settings = state.settings.read();
+    let routes = settings.dns_routes.clone();
+    let unmatched_local = settings.dns_unmatched_local;
+    drop(settings);
+    let mut new_domain = use_signal(String::new);
+    let mut domain_error = use_signal(|| None::<String>);
+
+    let mut add_domain = move || {
+        let input = new_domain();
+        if input.trim().is_empty() {
+            return;
+        }
+        match dns_routes::parse_domain(&input) {
+            Ok(domain) => {
+                state.settings.with_mut(|s| {
+                    if !s.dns_routes.iter().any(|r| r.domain == domain) {
+                        s.dns_routes.push(DnsRoute {
+                            domain,
+                            via: DnsRouteVia::Tunnel,
+                            search: false,
+                        });
+                    }
+                });
+                new_domain.set(String::new());
+                domain_error.set(None);
+            }
+            Err(e) => domain_error.set(Some(e.to_string())),
+        }
+    };
+    let mut edit_route = move |domain: &str, change: fn(&mut DnsRoute)| {
+        state.settings.with_mut(|s| {
+            if let Some(route) = s.dns_routes.iter_mut().find(|r| r.domain == domain) {
+                change(route);
+            }
+        });
+    };
+
+    rsx! {
+        SettingTitle { label: "DNS routes".to_string() }
+        SettingDescription {
+            text: "Names under these domains go to the resolver shown, tunnel or local network. Search domains also complete short names such as intranet. With the kill switch on, a local resolver is only reachable with local network sharing. Changes apply on the next connect.".to_string(),
+        }
+        SettingRow {
+            label: "Resolve other names locally".to_string(),
+            checked: unmatched_local,
+            onclick: move |_| {
+                state.settings.with_mut(|s| s.dns_unmatched_local = !s.dns_unmatched_local);
+            },
+        }
+        for route in routes {
+            div {
+                key: "{route.domain}",
+                class: "px-4 flex items-center justify-between gap-2 border-b border-border/50 last:border-0 shrink-0",
+                style: "height: 48px !important; min-height: 48px !important;",
+                span { class: "text-sm font-mono truncate flex-1", title: "{route.domain}", "{route.domain}" }
+                button {
+                    class: "px-2 py-1 rounded-lg bg-accent/40 text-[10px] font-bold uppercase",
+                    onclick: {
+                        let domain = route.domain.clone();
+                        move |_| {
+                            edit_route(&domain, |r| {
+                                r.via = match r.via {
+                                    DnsRouteVia::Tunnel => DnsRouteVia::Local,
+                                    DnsRouteVia::Local => DnsRouteVia::Tunnel,
+                                }
+                            })
+                        }
+                    },
+                    if route.via == DnsRouteVia::Tunnel { "Tunnel" } else { "Local" }
+                }
+                button {
+                    class: if route.search { "px-2 py-1 rounded-lg bg-primary text-primary-foreground text-[10px] font-bold uppercase" } else { "px-2 py-1 rounded-lg bg-accent/40 text-muted-foreground text-[10px] font-bold uppercase" },
+                    onclick: {
+                        let domain = route.domain.clone();
+                        move |_| edit_route(&domain, |r| r.search = !r.search)
+                    },
+                    "Search"
+                }
+                button {
+                    class: "text-muted-foreground hover:text-destructive transition-colors p-1",
+                    onclick: {
+                        let domain = route.domain.clone();
+                        move |_| {
+                            let domain = domain.clone();
+                            state.settings.with_mut(|s| s.dns_routes.retain(|r| r.domain != domain));
+                        }
+                    },
+                    X { size: 14 }
+                }
+            }
+        }
+        div { class: "px-4 py-2 flex gap-2",
+            input {
+                class: "flex-1 bg-accent/20 border border-border rounded-xl px-3 py-2 text-xs font-mono focus:outline-none focus:ring-2 focus:ring-primary/20 transition-all",
+                value: "{new_domain}",
+                placeholder: "e.g. corp.example.com",
+                oninput: move |e| new_domain.set(e.value()),
+                onkeydown: move |e| {
+                    if e.key() == Key::Enter {
+                        add_domain();
+                    }
+                },
+            }
+            button {
+                class: "px-3 rounded-xl bg-primary text-primary-foreground text-xs font-bold disabled:opacity-50",
+                disabled: new_domain().trim().is_empty(),
+                onclick: move |_| add_domain(),
+                "Add"
+            }
+        }
+        if let Some(error) = domain_error() {
+            p { class: "px-4 pb-2 text-[11px] text-destructive", "{error}" }
+        }
+    }
+}
@@ -43,11 +43,16 @@ pub fn Support() -> Element {
                                         let msg = report_text();
                                         spawn(async move {
                                             match AuthService::report_problem(&acc, &msg, &token).await {
-                                                Ok(_) => {
-                                                    toast.show("Report sent successfully", ToastType::Success);
+                                                Ok(res) if res.accepted => {
+                                                    let message = match res.reference {
+                                                        Some(reference) => format!("Report sent (reference {})", reference),
+                                                        None => "Report sent successfully".to_string(),
+                                                    };
+                                                    toast.show(&message, ToastType::Success);
                                                     show_report_modal.set(false);
                                                     report_text.set(String::new());
                                                 }
+                                                Ok(_) => toast.show("The report was not accepted, please try again later", ToastType::Error),
                                                 Err(e) => toast.show(&e.user_friendly_message(), ToastType::Error),
                                             }
                                             is_submitting.set(false);
@@ -4,6 +4,7 @@ use dioxus::desktop::{use_window, use_wry_event_handler, WindowEvent};
 use dioxus::prelude::*;
 use image::GenericImageView;
 use std::io::Cursor;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex, OnceLock};
 use std::time::{Duration, Instant};
 use tracing::error;
@@ -17,6 +18,14 @@ pub static TRAY_UPDATE_SENDER: OnceLock<tokio::sync::mpsc::UnboundedSender<Strin
 pub static TRAY_ICON_SENDER: OnceLock<tokio::sync::mpsc::UnboundedSender<Option<String>>> =
     OnceLock::new();
 
+/// Set from the single-instance listener thread and picked up by the tray
+/// loop, which owns the window.
+static SHOW_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+pub fn request_show() {
+    SHOW_REQUESTED.store(true, Ordering::Relaxed);
+}
+
 pub fn update_tray_tooltip(tooltip: &str) {
     if let Some(sender) = TRAY_UPDATE_SENDER.get() {
         let _ = sender.send(tooltip.to_string());
@@ -123,6 +132,11 @@ pub fn use_tray_management() {
                     }
                 }
 
+                if SHOW_REQUESTED.swap(false, Ordering::Relaxed) {
+                    window_coroutine.window.set_visible(true);
+                    window_coroutine.set_focus();
+                }
+
                 while let Ok(tooltip) = rx.try_recv() {
                     let _ = tray.set_tooltip(Some(tooltip));
                 }
@@ -1,5 +1,5 @@
 use keyring::Entry;
-use marinvpn::storage::{load_config, save_config, AppConfig};
+use marinvpn::storage::{get_backup_path, get_config_path, load_config, save_config, AppConfig};
 use serial_test::serial;
 use std::fs;
 
@@ -9,6 +9,7 @@ const TEST_USER: &str = "active_user";
 fn cleanup() {
     let path = marinvpn::storage::get_config_path();
     let _ = fs::remove_file(path);
+    let _ = fs::remove_file(marinvpn::storage::get_backup_path());
 
     if let Ok(entry) = Entry::new(TEST_SERVICE, TEST_USER) {
         let _ = entry.delete_password();
@@ -28,6 +29,7 @@ fn test_save_and_load_config() {
         device_name: Some("Test Device".to_string()),
         favorites: None,
         settings: None,
+        connection_stats: None,
     };
 
     save_config(&config).expect("Failed to save config");
@@ -89,3 +91,71 @@ fn test_migration_from_legacy_json() {
 
     cleanup();
 }
+
+fn named(device_name: &str) -> AppConfig {
+    AppConfig {
+        device_name: Some(device_name.to_string()),
+        ..AppConfig::default()
+    }
+}
+
+#[test]
+#[serial]
+fn save_keeps_the_previous_config_as_backup() {
+    cleanup();
+
+    save_config(&named("First")).expect("Failed to save config");
+    assert!(!get_backup_path().exists(), "nothing to back up yet");
+    save_config(&named("Second")).expect("Failed to save config");
+
+    let backup = fs::read_to_string(get_backup_path()).expect("Failed to read backup");
+    assert!(backup.contains("First"));
+    assert_eq!(load_config().device_name.as_deref(), Some("Second"));
+
+    let mut temp = get_config_path().into_os_string();
+    temp.push(".tmp");
+    assert!(
+        !std::path::Path::new(&temp).exists(),
+        "temp file left behind"
+    );
+
+    cleanup();
+}
+
+#[test]
+#[serial]
+fn truncated_config_falls_back_to_backup() {
+    cleanup();
+
+    save_config(&named("Good")).expect("Failed to save config");
+    save_config(&named("Newer")).expect("Failed to save config");
+
+    // What a crash halfway through an in-place write leaves behind.
+    let path = get_config_path();
+    let full = fs::read_to_string(&path).expect("Failed to read config");
+    fs::write(&path, &full[..full.len() / 2]).expect("Failed to truncate config");
+    assert_eq!(load_config().device_name.as_deref(), Some("Good"));
+
+    // The next save does not let the corrupt file replace the good backup.
+    save_config(&named("Recovered")).expect("Failed to save config");
+    assert_eq!(load_config().device_name.as_deref(), Some("Recovered"));
+    let backup = fs::read_to_string(get_backup_path()).expect("Failed to read backup");
+    assert!(backup.contains("Good"));
+
+    cleanup();
+}
+
+#[test]
+#[serial]
+fn missing_config_is_restored_from_backup() {
+    cleanup();
+
+    // A crash between moving the old file aside and moving the new one in.
+    save_config(&named("Kept")).expect("Failed to save config");
+    save_config(&named("Lost")).expect("Failed to save config");
+    fs::remove_file(get_config_path()).expect("Failed to remove config");
+
+    assert_eq!(load_config().device_name.as_deref(), Some("Kept"));
+
+    cleanup();
+}